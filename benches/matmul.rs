@@ -0,0 +1,64 @@
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use femto_gpt::tensor::{set_matmul_backend, sgemm, MatMulBackend, Tensor, TensorOps};
+
+// Matrix shapes `(m, n, p)` multiplied while training the default model (64 tokens, 64
+// embedding-degree, 4 heads and a 500-token vocabulary)
+const SHAPES: [(usize, usize, usize); 5] = [
+    (64, 64, 16),
+    (64, 16, 64),
+    (64, 64, 64),
+    (64, 64, 256),
+    (64, 64, 500),
+];
+
+// The plain triple loop that was used before the blocked SIMD kernels
+fn naive(a: &[f32], b: &[f32], m: usize, n: usize, p: usize) -> Vec<f32> {
+    let mut result = vec![0.; m * p];
+    for i in 0..m {
+        for k in 0..n {
+            for j in 0..p {
+                result[i * p + j] += a[i * n + k] * b[k * p + j];
+            }
+        }
+    }
+    result
+}
+
+fn bench_matmul(c: &mut Criterion) {
+    let mut rng = rand::thread_rng();
+    let mut group = c.benchmark_group("matmul");
+    for (m, n, p) in SHAPES {
+        let a = Tensor::<f32>::rand(&mut rng, &[m, n]);
+        let b = Tensor::<f32>::rand(&mut rng, &[n, p]);
+        let shape = format!("{}x{}x{}", m, n, p);
+        group.bench_with_input(BenchmarkId::new("naive", &shape), &(), |bench, _| {
+            bench.iter(|| naive(black_box(a.blob()), black_box(b.blob()), m, n, p))
+        });
+        for (name, backend) in [
+            ("simd", MatMulBackend::Native),
+            ("blas", MatMulBackend::Blas),
+        ] {
+            if set_matmul_backend(backend).is_err() {
+                continue; // Not compiled in
+            }
+            group.bench_with_input(BenchmarkId::new(name, &shape), &(), |bench, _| {
+                bench.iter(|| {
+                    let mut result = vec![0.; m * p];
+                    sgemm(
+                        black_box(a.blob()),
+                        black_box(b.blob()),
+                        &mut result,
+                        m,
+                        n,
+                        p,
+                    );
+                    result
+                })
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_matmul);
+criterion_main!(benches);
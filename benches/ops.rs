@@ -0,0 +1,116 @@
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use femto_gpt::funcs::{Function, LayerNorm, MatMul, Softmax};
+use femto_gpt::gpt::GptBuilder;
+use femto_gpt::graph::{CpuGraph, Graph};
+use femto_gpt::optimizer::AdamW;
+use femto_gpt::tensor::{GeneralTensor, Tensor, TensorOps};
+use rand::Rng;
+
+// Dimensions of the default model: 64 tokens, 64 embedding-degree, 4 heads of 16 and a
+// 500-token vocabulary
+const TOKENS: usize = 64;
+const EMBEDDING: usize = 64;
+const HEAD_SIZE: usize = 16;
+const VOCAB: usize = 500;
+
+// Shapes `(m, n, p)` of the multiplications of the default model: projections to the queries,
+// keys and values, attention scores and weighted values, output projection of the heads,
+// feed-forward layers and vocabulary projection
+const MATMULS: [(usize, usize, usize); 6] = [
+    (TOKENS, EMBEDDING, HEAD_SIZE),
+    (TOKENS, HEAD_SIZE, TOKENS),
+    (TOKENS, TOKENS, HEAD_SIZE),
+    (TOKENS, EMBEDDING, EMBEDDING),
+    (TOKENS, EMBEDDING, 4 * EMBEDDING),
+    (TOKENS, EMBEDDING, VOCAB),
+];
+
+// Times the forward and backward passes of `f` on `inps`
+fn bench_function(
+    c: &mut Criterion,
+    name: &str,
+    shape: &str,
+    mut f: Box<dyn Function>,
+    inps: &[GeneralTensor],
+) {
+    let inps = inps.iter().collect::<Vec<_>>();
+    let out = f.run(&inps, true).unwrap();
+    let out_grad = Tensor::<f32>::rand(&mut rand::thread_rng(), out.shape());
+    let mut group = c.benchmark_group(name);
+    group.bench_with_input(BenchmarkId::new("forward", shape), &(), |bench, _| {
+        bench.iter(|| f.run(black_box(&inps), true).unwrap())
+    });
+    group.bench_with_input(BenchmarkId::new("backward", shape), &(), |bench, _| {
+        bench.iter(|| f.grad(black_box(&inps), black_box(&out_grad)).unwrap())
+    });
+    group.finish();
+}
+
+fn rand_tensor(shape: &[usize]) -> GeneralTensor {
+    GeneralTensor::Float(Tensor::<f32>::rand(&mut rand::thread_rng(), shape))
+}
+
+fn bench_ops(c: &mut Criterion) {
+    for (m, n, p) in MATMULS {
+        bench_function(
+            c,
+            "MatMul",
+            &format!("{}x{}x{}", m, n, p),
+            MatMul::new(),
+            &[rand_tensor(&[m, n]), rand_tensor(&[n, p])],
+        );
+    }
+    // The attention scores of a head
+    bench_function(
+        c,
+        "Softmax",
+        &format!("{}x{}", TOKENS, TOKENS),
+        Softmax::new(),
+        &[rand_tensor(&[TOKENS, TOKENS])],
+    );
+    bench_function(
+        c,
+        "LayerNorm",
+        &format!("{}x{}", TOKENS, EMBEDDING),
+        LayerNorm::new(),
+        &[
+            rand_tensor(&[TOKENS, EMBEDDING]),
+            rand_tensor(&[EMBEDDING]),
+            rand_tensor(&[EMBEDDING]),
+        ],
+    );
+}
+
+// A forward pass, backward pass and optimizer step of the default model on a window, i.e. what
+// each worker of `GPT::train_cpu` does per sequence of a batch
+fn bench_training_step(c: &mut Criterion) {
+    let mut rng = rand::thread_rng();
+    let mut gpt = GptBuilder::new()
+        .vocab_size(VOCAB)
+        .build_with_rng(&mut rng, CpuGraph::new())
+        .unwrap();
+    let mut window = || {
+        Tensor::raw(
+            &[1, TOKENS],
+            (0..TOKENS).map(|_| rng.gen_range(0..VOCAB)).collect(),
+        )
+        .unwrap()
+    };
+    let (xs, ys) = (window(), window());
+    let optimizer = AdamW::new();
+    let mut group = c.benchmark_group("training");
+    group.sample_size(10);
+    group.bench_function("step", |bench| {
+        bench.iter(|| {
+            let loss = gpt
+                .forward_backward(black_box(&xs), black_box(&ys))
+                .unwrap();
+            gpt.graph_mut().optimize(&optimizer, 0.).unwrap();
+            loss
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_ops, bench_training_step);
+criterion_main!(benches);
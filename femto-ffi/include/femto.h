@@ -0,0 +1,93 @@
+#ifndef FEMTO_H
+#define FEMTO_H
+
+/* Generated by cbindgen from src/lib.rs, do not edit. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+// A model and its tokenizer.
+typedef struct FemtoModel FemtoModel;
+
+// Dimensions of a model, which should match the ones of the checkpoints it loads. Zeros keep the
+// defaults of `GptBuilder`.
+typedef struct FemtoConfig {
+  size_t embedding_degree;
+  size_t context;
+  size_t layers;
+  size_t heads;
+} FemtoConfig;
+
+// Called with every generated piece of text (NUL-terminated, valid until the call returns) and
+// the `user_data` passed to `femto_generate`. Pieces are made of whole UTF-8 characters: the
+// text of a token ending in the middle of one is held back until the next tokens complete it.
+typedef void (*FemtoTokenCallback)(const char *piece, void *user_data);
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+// The error of the last failed call on this thread, or an empty string. Owned by the library,
+// valid until the next failing call on this thread.
+const char *femto_last_error(void);
+
+// Creates a model with random weights and the vocabulary of a SentencePiece `.vocab` file.
+// `config` may be NULL for the default dimensions. Returns NULL on failure.
+//
+// # Safety
+// `vocab_path` must be a NUL-terminated string, `config` NULL or a valid `FemtoConfig`.
+struct FemtoModel *femto_model_new(const char *vocab_path, const struct FemtoConfig *config);
+
+// Creates a model from a bundle written by `femto export-bundle`, with its tokenizer, dimensions
+// and weights. Returns NULL on failure.
+//
+// # Safety
+// `bundle_path` must be a NUL-terminated string.
+struct FemtoModel *femto_model_open(const char *bundle_path);
+
+// Destroys a model created by `femto_model_new` or `femto_model_open`. NULL is ignored.
+//
+// # Safety
+// `model` must be NULL or a model that was not destroyed yet.
+void femto_model_free(struct FemtoModel *model);
+
+// Loads the weights of a checkpoint (Or of a `.safetensors` file) written by femto.
+//
+// # Safety
+// `model` must be a valid model, `checkpoint_path` a NUL-terminated string.
+int femto_model_load(struct FemtoModel *model, const char *checkpoint_path);
+
+// Generates `count` tokens following `prompt`, passing the text of each one to `callback` as
+// soon as it's sampled. The `temperature`, in (0, 1], is how likely less probable tokens are to
+// be picked.
+//
+// # Safety
+// `model` must be a valid model, `prompt` a NUL-terminated string.
+int femto_generate(struct FemtoModel *model,
+                   const char *prompt,
+                   size_t count,
+                   float temperature,
+                   FemtoTokenCallback callback,
+                   void *user_data);
+
+// Like `femto_generate`, sampling with `seed`: the same model, prompt, count, temperature and
+// seed always generate the same text.
+//
+// # Safety
+// `model` must be a valid model, `prompt` a NUL-terminated string.
+int femto_generate_seeded(struct FemtoModel *model,
+                          const char *prompt,
+                          size_t count,
+                          float temperature,
+                          uint64_t seed,
+                          FemtoTokenCallback callback,
+                          void *user_data);
+
+#ifdef __cplusplus
+} // extern "C"
+#endif // __cplusplus
+
+#endif /* FEMTO_H */
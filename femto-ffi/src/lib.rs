@@ -0,0 +1,447 @@
+// C ABI of femto: models are created from a SentencePiece vocabulary and load their weights from a
+// checkpoint, or are opened from a bundle, and generate text token by token through a callback.
+// See `include/femto.h`.
+//
+// Functions returning an `int` return 0 on success and -1 on failure, after which
+// `femto_last_error` describes what went wrong. Models run on the CPU.
+
+use femto_gpt::bundle::load_bundle;
+use femto_gpt::checkpoint::load_training_state;
+use femto_gpt::error::FemtoError;
+use femto_gpt::gpt::{GptBuilder, InferParams, GPT};
+use femto_gpt::graph::CpuGraph;
+use femto_gpt::tokenizer::{SentencePieceTokenizer, StreamDecoder, Tokenizer};
+use std::any::Any;
+use std::cell::{Cell, RefCell};
+use std::ffi::{c_char, c_int, c_void, CStr, CString};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::ptr;
+
+thread_local! {
+    static LAST_ERROR: RefCell<CString> = RefCell::new(CString::default());
+}
+
+fn set_last_error(message: String) {
+    // Interior NULs would truncate the message, not make it invalid
+    let message = CString::new(message.replace('\0', " ")).unwrap_or_default();
+    LAST_ERROR.with(|e| *e.borrow_mut() = message);
+}
+
+// Runs `f`, turning its error into a -1 and the message of `femto_last_error`. Panics are caught
+// and reported the same way, since unwinding into C is undefined behavior
+fn status(f: impl FnOnce() -> Result<(), FemtoError>) -> c_int {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(())) => 0,
+        Ok(Err(e)) => {
+            set_last_error(e.to_string());
+            -1
+        }
+        Err(payload) => {
+            set_last_error(format!("femto panicked: {}", panic_message(&payload)));
+            -1
+        }
+    }
+}
+
+fn panic_message(payload: &Box<dyn Any + Send>) -> &str {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message
+    } else {
+        "unknown panic"
+    }
+}
+
+unsafe fn path<'a>(s: *const c_char) -> Result<&'a Path, FemtoError> {
+    if s.is_null() {
+        return Err(FemtoError::Config("path is NULL".into()));
+    }
+    CStr::from_ptr(s)
+        .to_str()
+        .map(Path::new)
+        .map_err(|e| FemtoError::Config(format!("path is not valid UTF-8: {}", e)))
+}
+
+/// Dimensions of a model, which should match the ones of the checkpoints it loads. Zeros keep the
+/// defaults of `GptBuilder`.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct FemtoConfig {
+    pub embedding_degree: usize,
+    pub context: usize,
+    pub layers: usize,
+    pub heads: usize,
+}
+
+/// A model and its tokenizer.
+pub struct FemtoModel {
+    gpt: GPT<CpuGraph>,
+    tokenizer: Box<dyn Tokenizer>,
+}
+
+/// Called with every generated piece of text (NUL-terminated, valid until the call returns) and
+/// the `user_data` passed to `femto_generate`. Pieces are made of whole UTF-8 characters: the
+/// text of a token ending in the middle of one is held back until the next tokens complete it.
+pub type FemtoTokenCallback = extern "C" fn(piece: *const c_char, user_data: *mut c_void);
+
+/// The error of the last failed call on this thread, or an empty string. Owned by the library,
+/// valid until the next failing call on this thread.
+#[no_mangle]
+pub extern "C" fn femto_last_error() -> *const c_char {
+    panic::catch_unwind(|| LAST_ERROR.with(|e| e.borrow().as_ptr()))
+        .unwrap_or(<&CStr>::default().as_ptr())
+}
+
+/// Creates a model with random weights and the vocabulary of a SentencePiece `.vocab` file.
+/// `config` may be NULL for the default dimensions. Returns NULL on failure.
+///
+/// # Safety
+/// `vocab_path` must be a NUL-terminated string, `config` NULL or a valid `FemtoConfig`.
+#[no_mangle]
+pub unsafe extern "C" fn femto_model_new(
+    vocab_path: *const c_char,
+    config: *const FemtoConfig,
+) -> *mut FemtoModel {
+    let config = config.as_ref().copied().unwrap_or_default();
+    let mut model = None;
+    let result = status(|| {
+        let vocab = path(vocab_path)?;
+        let tokenizer =
+            SentencePieceTokenizer::load(vocab).map_err(FemtoError::tokenizer(vocab))?;
+        let mut builder = GptBuilder::new().vocab_size(tokenizer.vocab_size());
+        if config.embedding_degree > 0 {
+            builder = builder.embedding_degree(config.embedding_degree);
+        }
+        if config.context > 0 {
+            builder = builder.context(config.context);
+        }
+        if config.layers > 0 {
+            builder = builder.layers(config.layers);
+        }
+        if config.heads > 0 {
+            builder = builder.heads(config.heads);
+        }
+        let mut gpt = builder.build(CpuGraph::new())?;
+        gpt.set_training(false);
+        model = Some(FemtoModel {
+            gpt,
+            tokenizer: Box::new(tokenizer),
+        });
+        Ok(())
+    });
+    match model {
+        Some(model) if result == 0 => Box::into_raw(Box::new(model)),
+        _ => ptr::null_mut(),
+    }
+}
+
+/// Creates a model from a bundle written by `femto export-bundle`, with its tokenizer, dimensions
+/// and weights. Returns NULL on failure.
+///
+/// # Safety
+/// `bundle_path` must be a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn femto_model_open(bundle_path: *const c_char) -> *mut FemtoModel {
+    let mut model = None;
+    let result = status(|| {
+        let path = path(bundle_path)?;
+        let bundle = load_bundle(path)?.ok_or_else(|| {
+            FemtoError::Config(format!(
+                "{} is a checkpoint without a tokenizer, not a bundle",
+                path.display()
+            ))
+        })?;
+        let tokenizer = bundle
+            .tokenizer
+            .load()
+            .map_err(FemtoError::checkpoint(path))?;
+        let mut gpt = bundle
+            .config
+            .apply(GptBuilder::new())
+            .build(CpuGraph::new())?;
+        gpt.set_training_state(bundle.state, false)?;
+        gpt.set_training(false);
+        model = Some(FemtoModel { gpt, tokenizer });
+        Ok(())
+    });
+    match model {
+        Some(model) if result == 0 => Box::into_raw(Box::new(model)),
+        _ => ptr::null_mut(),
+    }
+}
+
+/// Destroys a model created by `femto_model_new` or `femto_model_open`. NULL is ignored.
+///
+/// # Safety
+/// `model` must be NULL or a model that was not destroyed yet.
+#[no_mangle]
+pub unsafe extern "C" fn femto_model_free(model: *mut FemtoModel) {
+    if !model.is_null() {
+        // Nothing to report a panic to
+        let _ = panic::catch_unwind(AssertUnwindSafe(|| drop(Box::from_raw(model))));
+    }
+}
+
+/// Loads the weights of a checkpoint (Or of a `.safetensors` file) written by femto.
+///
+/// # Safety
+/// `model` must be a valid model, `checkpoint_path` a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn femto_model_load(
+    model: *mut FemtoModel,
+    checkpoint_path: *const c_char,
+) -> c_int {
+    status(|| {
+        let model = model
+            .as_mut()
+            .ok_or_else(|| FemtoError::Config("model is NULL".into()))?;
+        let state = load_training_state(path(checkpoint_path)?)?;
+        model.gpt.set_training_state(state, false)?;
+        Ok(())
+    })
+}
+
+/// Generates `count` tokens following `prompt`, passing the text of each one to `callback` as
+/// soon as it's sampled. The `temperature`, in (0, 1], is how likely less probable tokens are to
+/// be picked.
+///
+/// # Safety
+/// `model` must be a valid model, `prompt` a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn femto_generate(
+    model: *mut FemtoModel,
+    prompt: *const c_char,
+    count: usize,
+    temperature: f32,
+    callback: FemtoTokenCallback,
+    user_data: *mut c_void,
+) -> c_int {
+    generate(model, prompt, count, temperature, None, callback, user_data)
+}
+
+/// Like `femto_generate`, sampling with `seed`: the same model, prompt, count, temperature and
+/// seed always generate the same text.
+///
+/// # Safety
+/// `model` must be a valid model, `prompt` a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn femto_generate_seeded(
+    model: *mut FemtoModel,
+    prompt: *const c_char,
+    count: usize,
+    temperature: f32,
+    seed: u64,
+    callback: FemtoTokenCallback,
+    user_data: *mut c_void,
+) -> c_int {
+    generate(
+        model,
+        prompt,
+        count,
+        temperature,
+        Some(seed),
+        callback,
+        user_data,
+    )
+}
+
+unsafe fn generate(
+    model: *mut FemtoModel,
+    prompt: *const c_char,
+    count: usize,
+    temperature: f32,
+    seed: Option<u64>,
+    callback: FemtoTokenCallback,
+    user_data: *mut c_void,
+) -> c_int {
+    status(|| {
+        let model = model
+            .as_mut()
+            .ok_or_else(|| FemtoError::Config("model is NULL".into()))?;
+        if prompt.is_null() {
+            return Err(FemtoError::Config("prompt is NULL".into()));
+        }
+        if !(temperature > 0. && temperature <= 1.) {
+            return Err(FemtoError::Config(format!(
+                "temperature should be in (0, 1], got {}",
+                temperature
+            )));
+        }
+        let prompt = CStr::from_ptr(prompt).to_string_lossy();
+        let tokens = model.tokenizer.tokenize(&prompt);
+        // `infer` reports the prompt tokens too
+        let skipped = Cell::new(tokens.len());
+        let tokenizer = model.tokenizer.as_ref();
+        let decoder = RefCell::new(StreamDecoder::new());
+        let emit = |piece: String| {
+            let piece = CString::new(piece.replace('\0', "")).unwrap_or_default();
+            callback(piece.as_ptr(), user_data);
+        };
+        model.gpt.infer(
+            &mut rand::thread_rng(),
+            &tokens,
+            &InferParams::new()
+                .count(count)
+                .temperature(temperature)
+                .seed(seed),
+            |token| {
+                if skipped.get() > 0 {
+                    skipped.set(skipped.get() - 1);
+                    return;
+                }
+                if let Some(piece) = decoder.borrow_mut().push(tokenizer, token) {
+                    emit(piece);
+                }
+            },
+        )?;
+        // The end of a character the generation stopped in the middle of
+        if let Some(piece) = decoder.into_inner().finish(tokenizer) {
+            emit(piece);
+        }
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use femto_gpt::bundle::{save_bundle, Bundle, BundleConfig, BundledTokenizer};
+
+    // The header is committed, so that building doesn't write into the sources. After changes of
+    // the ABI, `FEMTO_UPDATE_HEADER=1 cargo test -p femto-ffi` regenerates it
+    #[test]
+    fn test_header() {
+        let crate_dir = env!("CARGO_MANIFEST_DIR");
+        let mut header = Vec::new();
+        cbindgen::generate(crate_dir)
+            .expect("Unable to generate the C header")
+            .write(&mut header);
+        let path = Path::new(crate_dir).join("include/femto.h");
+        if std::env::var_os("FEMTO_UPDATE_HEADER").is_some() {
+            std::fs::write(&path, &header).unwrap();
+        }
+        assert!(
+            std::fs::read(&path).unwrap() == header,
+            "include/femto.h is out of date, run `FEMTO_UPDATE_HEADER=1 cargo test -p femto-ffi`"
+        );
+    }
+
+    extern "C" fn collect(piece: *const c_char, user_data: *mut c_void) {
+        let out = unsafe { &mut *(user_data as *mut String) };
+        out.push_str(unsafe { CStr::from_ptr(piece) }.to_str().unwrap());
+    }
+
+    #[test]
+    fn test_generate() {
+        let dir = std::env::temp_dir().join(format!("femto-ffi-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let vocab = dir.join("test.vocab");
+        std::fs::write(&vocab, "a\t-1\nb\t-1\nc\t-1\n").unwrap();
+        let vocab = CString::new(vocab.to_str().unwrap()).unwrap();
+        let config = FemtoConfig {
+            embedding_degree: 8,
+            context: 8,
+            layers: 1,
+            heads: 2,
+        };
+
+        unsafe {
+            let model = femto_model_new(vocab.as_ptr(), &config);
+            assert!(!model.is_null());
+
+            let mut out = String::new();
+            let prompt = CString::new("ab").unwrap();
+            let status = femto_generate(
+                model,
+                prompt.as_ptr(),
+                5,
+                0.5,
+                collect,
+                &mut out as *mut String as *mut c_void,
+            );
+            assert_eq!(status, 0);
+            assert_eq!(out.chars().count(), 5);
+            assert!(out.chars().all(|c| "abc".contains(c)));
+
+            // Seeded generations repeat themselves
+            let seeded: Vec<String> = (0..2)
+                .map(|_| {
+                    let mut out = String::new();
+                    let status = femto_generate_seeded(
+                        model,
+                        prompt.as_ptr(),
+                        20,
+                        1.,
+                        42,
+                        collect,
+                        &mut out as *mut String as *mut c_void,
+                    );
+                    assert_eq!(status, 0);
+                    out
+                })
+                .collect();
+            assert_eq!(seeded[0].chars().count(), 20);
+            assert_eq!(seeded[0], seeded[1]);
+
+            // A bundle of the model generates the same text
+            let bundle_path = dir.join("test.femto");
+            let state = (*model).gpt.get_training_state().unwrap();
+            save_bundle(
+                &bundle_path,
+                &Bundle {
+                    config: BundleConfig::of(&state, config.context).unwrap(),
+                    state,
+                    tokenizer: BundledTokenizer::SentencePiece(
+                        std::fs::read_to_string(dir.join("test.vocab")).unwrap(),
+                    ),
+                },
+            )
+            .unwrap();
+            let bundle_path = CString::new(bundle_path.to_str().unwrap()).unwrap();
+            let bundled = femto_model_open(bundle_path.as_ptr());
+            assert!(!bundled.is_null());
+            let mut out = String::new();
+            let status = femto_generate_seeded(
+                bundled,
+                prompt.as_ptr(),
+                20,
+                1.,
+                42,
+                collect,
+                &mut out as *mut String as *mut c_void,
+            );
+            assert_eq!(status, 0);
+            assert_eq!(out, seeded[0]);
+            femto_model_free(bundled);
+
+            let missing = CString::new(dir.join("missing.dat").to_str().unwrap()).unwrap();
+            assert_eq!(femto_model_load(model, missing.as_ptr()), -1);
+            let error = CStr::from_ptr(femto_last_error()).to_str().unwrap();
+            assert!(error.contains("missing.dat"));
+
+            let status = femto_generate(
+                model,
+                prompt.as_ptr(),
+                5,
+                1.5,
+                collect,
+                &mut out as *mut String as *mut c_void,
+            );
+            assert_eq!(status, -1);
+            let error = CStr::from_ptr(femto_last_error()).to_str().unwrap();
+            assert!(error.contains("(0, 1]"));
+
+            femto_model_free(model);
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+
+        // Panics don't unwind into the caller
+        assert_eq!(status(|| panic!("boom")), -1);
+        unsafe {
+            let error = CStr::from_ptr(femto_last_error()).to_str().unwrap();
+            assert!(error.contains("boom"));
+        }
+    }
+}
@@ -0,0 +1,230 @@
+// Self-contained models. A bundle is a checkpoint whose metadata also holds the tokenizer of the
+// model (The contents of its file) and the dimensions it was built with, as JSON, so that nothing
+// has to be shipped along with it. Being checkpoints, bundles load wherever checkpoints do.
+
+#[cfg(feature = "fs")]
+use crate::checkpoint::write_atomic;
+use crate::checkpoint::{decompress, Checkpoint, CheckpointError, CHECKPOINT_MAGIC};
+#[cfg(feature = "fs")]
+use crate::error::FemtoError;
+use crate::export::ModelShape;
+use crate::gpt::{Architecture, GptBuilder, TrainingState};
+use crate::tensor::TensorOps;
+use crate::tokenizer::{SentencePieceTokenizer, Tokenizer};
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "fs")]
+use std::fs;
+use std::io::Write;
+#[cfg(feature = "fs")]
+use std::path::Path;
+
+pub const BUNDLE_CONFIG_KEY: &str = "bundle.config";
+pub const BUNDLE_TOKENIZER_KEY: &str = "bundle.tokenizer";
+// `sentencepiece` or `huggingface`
+const BUNDLE_TOKENIZER_KIND_KEY: &str = "bundle.tokenizer.kind";
+
+/// Dimensions of a bundled model, see `GptBuilder`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BundleConfig {
+    pub architecture: Architecture,
+    pub vocab_size: usize,
+    pub embedding_degree: usize,
+    pub context: usize,
+    pub layers: usize,
+    pub heads: usize,
+    pub head_size: usize,
+}
+
+impl BundleConfig {
+    /// Dimensions of the model of `state`, GPT-2 ones being recognized by their learned
+    /// positional embeddings, which fix their context. Others run with a context of `context`.
+    pub fn of(state: &TrainingState, context: usize) -> Result<Self, CheckpointError> {
+        let shape =
+            ModelShape::of(state).map_err(|e| CheckpointError::InvalidFormat(e.to_string()))?;
+        let (architecture, context) = match state.tensors.get("pos_embedding") {
+            Some(pos) => (Architecture::Gpt2, pos.shape()[0]),
+            None => (Architecture::Femto, context),
+        };
+        Ok(Self {
+            architecture,
+            vocab_size: shape.vocab_size,
+            embedding_degree: shape.embedding_degree,
+            context,
+            layers: shape.num_layers,
+            heads: shape.num_heads,
+            head_size: shape.head_size,
+        })
+    }
+
+    /// Gives `builder` the dimensions of the bundled model.
+    pub fn apply<'a>(&self, builder: GptBuilder<'a>) -> GptBuilder<'a> {
+        builder
+            .architecture(self.architecture)
+            .vocab_size(self.vocab_size)
+            .embedding_degree(self.embedding_degree)
+            .context(self.context)
+            .layers(self.layers)
+            .heads(self.heads)
+            .head_size(self.head_size)
+    }
+}
+
+/// Tokenizer of a bundled model, as the contents of its file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BundledTokenizer {
+    /// A SentencePiece `.vocab` file
+    SentencePiece(String),
+    /// A Hugging Face `tokenizer.json` file
+    HuggingFace(String),
+}
+
+impl BundledTokenizer {
+    pub fn load(&self) -> Result<Box<dyn Tokenizer>, CheckpointError> {
+        match self {
+            Self::SentencePiece(_) => Ok(Box::new(self.sentencepiece()?)),
+            #[cfg(feature = "huggingface")]
+            Self::HuggingFace(json) => {
+                crate::tokenizer::HuggingFaceTokenizer::from_bytes(json.as_bytes())
+                    .map(|t| Box::new(t) as Box<dyn Tokenizer>)
+                    .map_err(|e| {
+                        CheckpointError::InvalidFormat(format!("invalid tokenizer: {}", e))
+                    })
+            }
+            #[cfg(not(feature = "huggingface"))]
+            Self::HuggingFace(_) => Err(CheckpointError::InvalidFormat(
+                "Hugging Face tokenizers need the `huggingface` feature".into(),
+            )),
+        }
+    }
+
+    /// The SentencePiece tokenizer, for the APIs that need its pieces and scores.
+    pub fn sentencepiece(&self) -> Result<SentencePieceTokenizer, CheckpointError> {
+        match self {
+            Self::SentencePiece(vocab) => SentencePieceTokenizer::from_reader(vocab.as_bytes())
+                .map_err(|e| CheckpointError::InvalidFormat(format!("invalid vocab: {}", e))),
+            Self::HuggingFace(_) => Err(CheckpointError::InvalidFormat(
+                "the bundled tokenizer isn't a SentencePiece one".into(),
+            )),
+        }
+    }
+}
+
+/// A model along with its tokenizer and dimensions.
+#[derive(Debug, Clone)]
+pub struct Bundle {
+    pub state: TrainingState,
+    pub tokenizer: BundledTokenizer,
+    pub config: BundleConfig,
+}
+
+impl Bundle {
+    pub fn write<W: Write>(&self, out: &mut W) -> Result<(), CheckpointError> {
+        let mut checkpoint = Checkpoint::from_training_state(&self.state);
+        let (kind, contents) = match &self.tokenizer {
+            BundledTokenizer::SentencePiece(vocab) => ("sentencepiece", vocab),
+            BundledTokenizer::HuggingFace(json) => ("huggingface", json),
+        };
+        let config = serde_json::to_string(&self.config)
+            .map_err(|e| CheckpointError::InvalidFormat(e.to_string()))?;
+        checkpoint
+            .metadata
+            .insert(BUNDLE_TOKENIZER_KIND_KEY.into(), kind.into());
+        checkpoint
+            .metadata
+            .insert(BUNDLE_TOKENIZER_KEY.into(), contents.clone());
+        checkpoint.metadata.insert(BUNDLE_CONFIG_KEY.into(), config);
+        checkpoint.write(out)
+    }
+
+    /// Reads a bundle, `None` for the checkpoints (Or other files) that aren't bundles.
+    pub fn read(bytes: &[u8]) -> Result<Option<Self>, CheckpointError> {
+        let bytes = &decompress(bytes)?[..];
+        if !bytes.starts_with(CHECKPOINT_MAGIC) {
+            return Ok(None);
+        }
+        let mut checkpoint = Checkpoint::read(bytes)?;
+        let Some(config) = checkpoint.metadata.remove(BUNDLE_CONFIG_KEY) else {
+            return Ok(None);
+        };
+        let invalid = CheckpointError::InvalidFormat;
+        let config = serde_json::from_str(&config)
+            .map_err(|e| invalid(format!("invalid bundle config: {}", e)))?;
+        let contents = checkpoint
+            .metadata
+            .remove(BUNDLE_TOKENIZER_KEY)
+            .ok_or_else(|| invalid("the bundle has no tokenizer".into()))?;
+        let tokenizer = match checkpoint
+            .metadata
+            .remove(BUNDLE_TOKENIZER_KIND_KEY)
+            .as_deref()
+        {
+            Some("sentencepiece") => BundledTokenizer::SentencePiece(contents),
+            Some("huggingface") => BundledTokenizer::HuggingFace(contents),
+            kind => return Err(invalid(format!("unknown tokenizer kind {:?}", kind))),
+        };
+        Ok(Some(Self {
+            state: checkpoint.into_training_state()?,
+            tokenizer,
+            config,
+        }))
+    }
+}
+
+/// Loads the bundle at `path`, `None` if it's a checkpoint without a tokenizer (Or another file).
+#[cfg(feature = "fs")]
+pub fn load_bundle(path: &Path) -> Result<Option<Bundle>, FemtoError> {
+    let bytes = fs::read(path).map_err(FemtoError::io(path))?;
+    Bundle::read(&bytes).map_err(FemtoError::checkpoint(path))
+}
+
+/// Saves a bundle, which is loaded like any checkpoint.
+#[cfg(feature = "fs")]
+pub fn save_bundle(path: &Path, bundle: &Bundle) -> Result<(), FemtoError> {
+    let mut bytes = Vec::new();
+    bundle
+        .write(&mut bytes)
+        .map_err(FemtoError::checkpoint(path))?;
+    write_atomic(path, &bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::checkpoint::{read_training_state, write_training_state};
+    use crate::graph::CpuGraph;
+
+    #[test]
+    fn test_bundle_roundtrip() {
+        let gpt = GptBuilder::new()
+            .vocab_size(3)
+            .embedding_degree(8)
+            .context(4)
+            .layers(1)
+            .heads(2)
+            .build(CpuGraph::new())
+            .unwrap();
+        let state = gpt.get_training_state().unwrap();
+        let bundle = Bundle {
+            config: BundleConfig::of(&state, 4).unwrap(),
+            state,
+            tokenizer: BundledTokenizer::SentencePiece("a\t-1\nb\t-1\nc\t-1\n".into()),
+        };
+        assert_eq!(bundle.config.architecture, Architecture::Femto);
+        assert_eq!(bundle.config.heads, 2);
+
+        let mut bytes = Vec::new();
+        bundle.write(&mut bytes).unwrap();
+        let read = Bundle::read(&bytes).unwrap().unwrap();
+        assert_eq!(read.config, bundle.config);
+        assert_eq!(read.tokenizer, bundle.tokenizer);
+        assert_eq!(read.tokenizer.load().unwrap().vocab_size(), 3);
+        assert_eq!(read.state.tensors.len(), bundle.state.tensors.len());
+
+        // It's a checkpoint too, while plain checkpoints aren't bundles
+        let state = read_training_state(&bytes).unwrap();
+        assert_eq!(state.tensors.len(), bundle.state.tensors.len());
+        let mut plain = Vec::new();
+        write_training_state(&mut plain, &state).unwrap();
+        assert!(Bundle::read(&plain).unwrap().is_none());
+    }
+}
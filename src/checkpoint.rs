@@ -0,0 +1,653 @@
+// Versioned checkpoint container. A checkpoint starts with 8 magic bytes and a little-endian u32
+// format version, followed by the (u64) length of a JSON header and the header itself, which
+// holds a free-form metadata map and the dtype, shape and data offset of every named tensor. The
+// raw little-endian tensor data comes last.
+//
+// Readers ignore header fields they don't know about, so new information can be added without
+// bumping the version. Breaking changes bump `CHECKPOINT_VERSION` and add a migration step, so
+// older checkpoints (Including the plain bincode-encoded `TrainingState`s written before this
+// format existed) keep loading.
+//
+// Checkpoints may be compressed as a whole with zstd, which readers detect by the magic bytes of
+// its frames and undo before anything else.
+
+#[cfg(feature = "fs")]
+use crate::error::FemtoError;
+#[cfg(feature = "fs")]
+use crate::export::read_safetensors;
+use crate::gpt::{Progress, Provenance, TrainingState};
+use crate::optimizer::OptimizerState;
+use crate::sampler::SamplerState;
+use crate::tensor::{Tensor, TensorError, TensorOps};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+#[cfg(feature = "fs")]
+use std::fs;
+use std::io::Write;
+#[cfg(feature = "fs")]
+use std::path::Path;
+use std::time::Duration;
+use thiserror::Error;
+
+pub const CHECKPOINT_MAGIC: &[u8; 8] = b"FEMTOCKP";
+pub const CHECKPOINT_VERSION: u32 = 1;
+pub const ZSTD_MAGIC: &[u8; 4] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+// Tensors of the optimizer state are stored under this prefix, its step count as metadata
+const OPTIMIZER_PREFIX: &str = "optimizer.";
+const OPTIMIZER_STEP_KEY: &str = "optimizer.step";
+// Position of the epoch-based sampling of the training windows, when used
+const SAMPLER_SEED_KEY: &str = "sampler.seed";
+const SAMPLER_EPOCH_KEY: &str = "sampler.epoch";
+const SAMPLER_CURSOR_KEY: &str = "sampler.cursor";
+// Tokens and seconds trained on, which training budgets count
+const PROGRESS_TOKENS_KEY: &str = "progress.tokens";
+const PROGRESS_SECONDS_KEY: &str = "progress.seconds";
+// Hashes of the dataset and tokenizer trained on, and the last training loss
+const PROVENANCE_DATASET_KEY: &str = "provenance.dataset";
+const PROVENANCE_TOKENIZER_KEY: &str = "provenance.tokenizer";
+const PROVENANCE_LOSS_KEY: &str = "provenance.loss";
+
+#[derive(Error, Debug)]
+pub enum CheckpointError {
+    #[error("io error: {0}")]
+    IoError(#[from] std::io::Error),
+    #[error("tensor error: {0}")]
+    TensorError(#[from] TensorError),
+    #[error("invalid checkpoint: {0}")]
+    InvalidFormat(String),
+    #[error("checkpoint version {0} is newer than the supported version {CHECKPOINT_VERSION}")]
+    UnsupportedVersion(u32),
+    #[error("invalid legacy checkpoint: {0}")]
+    LegacyError(#[from] bincode::Error),
+    /// The `index`-th of the checkpoints being combined doesn't have the tensors of the first one
+    #[error("checkpoint of another model: {message}")]
+    Mismatch { index: usize, message: String },
+    #[error("compressed checkpoints need the `compression` feature")]
+    CompressionUnsupported,
+}
+
+#[derive(Serialize, Deserialize)]
+struct TensorInfo {
+    name: String,
+    dtype: String,
+    shape: Vec<usize>,
+    offset: usize,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Header {
+    #[serde(default)]
+    metadata: BTreeMap<String, String>,
+    tensors: Vec<TensorInfo>,
+}
+
+/// Named tensors along with string metadata, as stored in a checkpoint file.
+#[derive(Debug, Clone, Default)]
+pub struct Checkpoint {
+    pub metadata: BTreeMap<String, String>,
+    pub tensors: BTreeMap<String, Tensor<f32>>,
+}
+
+impl Checkpoint {
+    pub fn from_training_state(state: &TrainingState) -> Self {
+        let mut tensors = state
+            .tensors
+            .iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect::<BTreeMap<_, _>>();
+        for (k, v) in state.optimizer.state.iter() {
+            tensors.insert(format!("{}{}", OPTIMIZER_PREFIX, k), v.clone());
+        }
+        let mut metadata = BTreeMap::new();
+        metadata.insert(
+            "femto.version".to_string(),
+            env!("CARGO_PKG_VERSION").to_string(),
+        );
+        metadata.insert(
+            OPTIMIZER_STEP_KEY.to_string(),
+            state.optimizer.step.to_string(),
+        );
+        if let Some(sampler) = state.sampler {
+            metadata.insert(SAMPLER_SEED_KEY.into(), sampler.seed.to_string());
+            metadata.insert(SAMPLER_EPOCH_KEY.into(), sampler.epoch.to_string());
+            metadata.insert(SAMPLER_CURSOR_KEY.into(), sampler.cursor.to_string());
+        }
+        if state.progress != Progress::default() {
+            metadata.insert(
+                PROGRESS_TOKENS_KEY.into(),
+                state.progress.tokens.to_string(),
+            );
+            metadata.insert(
+                PROGRESS_SECONDS_KEY.into(),
+                state.progress.elapsed.as_secs_f64().to_string(),
+            );
+        }
+        let provenance = &state.provenance;
+        if let Some(dataset) = &provenance.dataset {
+            metadata.insert(PROVENANCE_DATASET_KEY.into(), dataset.clone());
+        }
+        if let Some(tokenizer) = &provenance.tokenizer {
+            metadata.insert(PROVENANCE_TOKENIZER_KEY.into(), tokenizer.clone());
+        }
+        if let Some(loss) = provenance.loss {
+            metadata.insert(PROVENANCE_LOSS_KEY.into(), loss.to_string());
+        }
+        Self { metadata, tensors }
+    }
+
+    pub fn into_training_state(self) -> Result<TrainingState, CheckpointError> {
+        let step = match self.metadata.get(OPTIMIZER_STEP_KEY) {
+            Some(step) => step
+                .parse()
+                .map_err(|_| CheckpointError::InvalidFormat("invalid optimizer step".into()))?,
+            None => 0,
+        };
+        let mut state = TrainingState {
+            tensors: Default::default(),
+            optimizer: OptimizerState {
+                step,
+                ..Default::default()
+            },
+            sampler: sampler_state(&self.metadata)?,
+            progress: progress(&self.metadata)?,
+            provenance: provenance(&self.metadata)?,
+        };
+        for (k, v) in self.tensors {
+            match k.strip_prefix(OPTIMIZER_PREFIX) {
+                Some(k) => state.optimizer.state.insert(k.to_string(), v),
+                None => state.tensors.insert(k, v),
+            };
+        }
+        Ok(state)
+    }
+
+    pub fn write<W: Write>(&self, out: &mut W) -> Result<(), CheckpointError> {
+        let mut offset = 0;
+        let mut tensors = Vec::with_capacity(self.tensors.len());
+        for (name, t) in self.tensors.iter() {
+            tensors.push(TensorInfo {
+                name: name.clone(),
+                dtype: "F32".into(),
+                shape: t.shape().to_vec(),
+                offset,
+            });
+            offset += t.size() * 4;
+        }
+        let header = serde_json::to_vec(&Header {
+            metadata: self.metadata.clone(),
+            tensors,
+        })
+        .map_err(|e| CheckpointError::InvalidFormat(e.to_string()))?;
+
+        out.write_all(CHECKPOINT_MAGIC)?;
+        out.write_all(&CHECKPOINT_VERSION.to_le_bytes())?;
+        out.write_all(&(header.len() as u64).to_le_bytes())?;
+        out.write_all(&header)?;
+        for t in self.tensors.values() {
+            let bytes = t
+                .blob()
+                .iter()
+                .flat_map(|f| f.to_le_bytes())
+                .collect::<Vec<_>>();
+            out.write_all(&bytes)?;
+        }
+        Ok(())
+    }
+
+    pub fn read(bytes: &[u8]) -> Result<Self, CheckpointError> {
+        let bytes = &decompress(bytes)?[..];
+        if !bytes.starts_with(CHECKPOINT_MAGIC) {
+            // Version 0: bincode-encoded `TrainingState`
+            let state: TrainingState = bincode::deserialize(bytes)?;
+            return Ok(Self::from_training_state(&state));
+        }
+        let invalid = |msg: &str| CheckpointError::InvalidFormat(msg.into());
+        if bytes.len() < 20 {
+            return Err(invalid("file is too small"));
+        }
+        let version = u32::from_le_bytes(bytes[8..12].try_into().unwrap());
+        if version > CHECKPOINT_VERSION {
+            return Err(CheckpointError::UnsupportedVersion(version));
+        }
+        let header_len = u64::from_le_bytes(bytes[12..20].try_into().unwrap()) as usize;
+        let data_start = 20usize
+            .checked_add(header_len)
+            .filter(|end| *end <= bytes.len())
+            .ok_or_else(|| invalid("header is out of bounds"))?;
+        let header: Header = serde_json::from_slice(&bytes[20..data_start])
+            .map_err(|e| CheckpointError::InvalidFormat(e.to_string()))?;
+        let data = &bytes[data_start..];
+
+        let mut tensors = BTreeMap::new();
+        for info in header.tensors {
+            if info.dtype != "F32" {
+                return Err(invalid(&format!(
+                    "unsupported dtype {} of {}",
+                    info.dtype, info.name
+                )));
+            }
+            let out_of_bounds = || invalid(&format!("data of {} is out of bounds", info.name));
+            let end = info
+                .shape
+                .iter()
+                .try_fold(4usize, |bytes, dim| bytes.checked_mul(*dim))
+                .and_then(|bytes| info.offset.checked_add(bytes))
+                .ok_or_else(out_of_bounds)?;
+            let raw = data.get(info.offset..end).ok_or_else(out_of_bounds)?;
+            let blob = raw
+                .chunks_exact(4)
+                .map(|b| f32::from_le_bytes(b.try_into().unwrap()))
+                .collect();
+            tensors.insert(info.name, Tensor::raw(&info.shape, blob)?);
+        }
+        migrate(
+            version,
+            Self {
+                metadata: header.metadata,
+                tensors,
+            },
+        )
+    }
+}
+
+fn sampler_state(
+    metadata: &BTreeMap<String, String>,
+) -> Result<Option<SamplerState>, CheckpointError> {
+    let field = |key: &str| {
+        metadata
+            .get(key)
+            .map(|v| {
+                v.parse::<u64>()
+                    .map_err(|_| CheckpointError::InvalidFormat(format!("invalid {}", key)))
+            })
+            .transpose()
+    };
+    Ok(
+        match (
+            field(SAMPLER_SEED_KEY)?,
+            field(SAMPLER_EPOCH_KEY)?,
+            field(SAMPLER_CURSOR_KEY)?,
+        ) {
+            (Some(seed), Some(epoch), Some(cursor)) => Some(SamplerState {
+                seed,
+                epoch: epoch as usize,
+                cursor: cursor as usize,
+            }),
+            _ => None,
+        },
+    )
+}
+
+fn progress(metadata: &BTreeMap<String, String>) -> Result<Progress, CheckpointError> {
+    let invalid = |key: &str| CheckpointError::InvalidFormat(format!("invalid {}", key));
+    let tokens = match metadata.get(PROGRESS_TOKENS_KEY) {
+        Some(tokens) => tokens.parse().map_err(|_| invalid(PROGRESS_TOKENS_KEY))?,
+        None => 0,
+    };
+    let elapsed = match metadata.get(PROGRESS_SECONDS_KEY) {
+        Some(seconds) => seconds
+            .parse::<f64>()
+            .ok()
+            .and_then(|s| Duration::try_from_secs_f64(s).ok())
+            .ok_or_else(|| invalid(PROGRESS_SECONDS_KEY))?,
+        None => Duration::ZERO,
+    };
+    Ok(Progress { tokens, elapsed })
+}
+
+fn provenance(metadata: &BTreeMap<String, String>) -> Result<Provenance, CheckpointError> {
+    let loss = match metadata.get(PROVENANCE_LOSS_KEY) {
+        Some(loss) => Some(loss.parse().map_err(|_| {
+            CheckpointError::InvalidFormat(format!("invalid {}", PROVENANCE_LOSS_KEY))
+        })?),
+        None => None,
+    };
+    Ok(Provenance {
+        dataset: metadata.get(PROVENANCE_DATASET_KEY).cloned(),
+        tokenizer: metadata.get(PROVENANCE_TOKENIZER_KEY).cloned(),
+        loss,
+    })
+}
+
+/// 64-bit FNV-1a, unlike `DefaultHasher` its output is stable across Rust releases (e.g. for the
+/// hashes of `Provenance`).
+pub fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |h, b| {
+        (h ^ *b as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+// Brings a checkpoint of an older format version up to date, one version at a time
+fn migrate(version: u32, checkpoint: Checkpoint) -> Result<Checkpoint, CheckpointError> {
+    match version {
+        CHECKPOINT_VERSION => Ok(checkpoint),
+        v => Err(CheckpointError::UnsupportedVersion(v)),
+    }
+}
+
+/// Compresses the bytes of a checkpoint with zstd at `level` (1 to 22, higher ones being smaller
+/// and slower), they are decompressed when read.
+pub fn compress(bytes: &[u8], level: i32) -> Result<Vec<u8>, CheckpointError> {
+    #[cfg(feature = "compression")]
+    return Ok(zstd::encode_all(bytes, level)?);
+    #[cfg(not(feature = "compression"))]
+    {
+        let _ = (bytes, level);
+        Err(CheckpointError::CompressionUnsupported)
+    }
+}
+
+/// The bytes of a checkpoint, decompressed if they were compressed by `compress`.
+pub fn decompress(bytes: &[u8]) -> Result<Cow<'_, [u8]>, CheckpointError> {
+    if !bytes.starts_with(ZSTD_MAGIC) {
+        return Ok(Cow::Borrowed(bytes));
+    }
+    #[cfg(feature = "compression")]
+    return Ok(Cow::Owned(zstd::decode_all(bytes)?));
+    #[cfg(not(feature = "compression"))]
+    Err(CheckpointError::CompressionUnsupported)
+}
+
+/// Format version of a checkpoint, 0 for the legacy bincode-encoded ones. The rest of the file
+/// isn't checked.
+pub fn checkpoint_version(bytes: &[u8]) -> u32 {
+    match bytes.get(8..12) {
+        Some(version) if bytes.starts_with(CHECKPOINT_MAGIC) => {
+            u32::from_le_bytes(version.try_into().unwrap())
+        }
+        _ => 0,
+    }
+}
+
+/// Weighted average of the weights of checkpoints of the same model ("Model soup"), weights are
+/// normalized to sum to 1. The optimizer state isn't kept.
+pub fn average_training_states(
+    states: &[TrainingState],
+    weights: &[f32],
+) -> Result<TrainingState, CheckpointError> {
+    let total = weights.iter().sum::<f32>();
+    let mut average = TrainingState {
+        tensors: Default::default(),
+        optimizer: Default::default(),
+        sampler: None,
+        progress: Default::default(),
+        provenance: Default::default(),
+    };
+    let first = match states.first() {
+        Some(first) => first,
+        None => return Ok(average),
+    };
+    for (index, state) in states.iter().enumerate() {
+        let mismatch = |message: String| CheckpointError::Mismatch { index, message };
+        if let Some(name) = state
+            .tensors
+            .keys()
+            .find(|n| !first.tensors.contains_key(*n))
+        {
+            return Err(mismatch(format!("unexpected tensor `{}`", name)));
+        }
+        for (name, t) in first.tensors.iter() {
+            let other = state
+                .tensors
+                .get(name)
+                .ok_or_else(|| mismatch(format!("tensor `{}` is missing", name)))?;
+            if other.shape() != t.shape() {
+                return Err(mismatch(format!(
+                    "tensor `{}` has shape {:?} instead of {:?}",
+                    name,
+                    other.shape(),
+                    t.shape()
+                )));
+            }
+        }
+    }
+    for (name, t) in first.tensors.iter() {
+        let mut blob = vec![0.; t.size()];
+        for (state, weight) in states.iter().zip(weights) {
+            for (sum, v) in blob.iter_mut().zip(state.tensors[name].blob()) {
+                *sum += v * weight / total;
+            }
+        }
+        average
+            .tensors
+            .insert(name.clone(), Tensor::raw(t.shape(), blob)?);
+    }
+    Ok(average)
+}
+
+/// Writes a training state (model weights and optimizer state) as a versioned checkpoint.
+pub fn write_training_state<W: Write>(
+    out: &mut W,
+    state: &TrainingState,
+) -> Result<(), CheckpointError> {
+    Checkpoint::from_training_state(state).write(out)
+}
+
+/// Reads a training state from a checkpoint of any supported version.
+pub fn read_training_state(bytes: &[u8]) -> Result<TrainingState, CheckpointError> {
+    Checkpoint::read(bytes)?.into_training_state()
+}
+
+/// Loads a training state from a checkpoint file, or only the weights of a `.safetensors` one.
+#[cfg(feature = "fs")]
+pub fn load_training_state(path: &Path) -> Result<TrainingState, FemtoError> {
+    let bytes = fs::read(path).map_err(FemtoError::io(path))?;
+    if path.extension().is_some_and(|ext| ext == "safetensors") {
+        let tensors = read_safetensors(&bytes)
+            .map_err(|e| CheckpointError::InvalidFormat(e.to_string()))
+            .map_err(FemtoError::checkpoint(path))?;
+        Ok(TrainingState {
+            tensors,
+            optimizer: Default::default(),
+            sampler: None,
+            progress: Default::default(),
+            provenance: Default::default(),
+        })
+    } else {
+        read_training_state(&bytes).map_err(FemtoError::checkpoint(path))
+    }
+}
+
+/// Saves a training state as a checkpoint file.
+#[cfg(feature = "fs")]
+pub fn save_training_state(path: &Path, state: &TrainingState) -> Result<(), FemtoError> {
+    save_compressed_training_state(path, state, None)
+}
+
+/// Saves a training state as a checkpoint file, compressed at `level` if any (See `compress`).
+#[cfg(feature = "fs")]
+pub fn save_compressed_training_state(
+    path: &Path,
+    state: &TrainingState,
+    level: Option<i32>,
+) -> Result<(), FemtoError> {
+    let mut bytes = Vec::new();
+    write_training_state(&mut bytes, state).map_err(FemtoError::checkpoint(path))?;
+    if let Some(level) = level {
+        bytes = compress(&bytes, level).map_err(FemtoError::checkpoint(path))?;
+    }
+    write_atomic(path, &bytes)
+}
+
+/// Writes `bytes` to `path`, creating its directory if needed. They go to a temporary file next
+/// to it first, renamed over it once complete, so that a failed or interrupted save never leaves
+/// a truncated file behind, nor loses the previous one.
+#[cfg(feature = "fs")]
+pub fn write_atomic(path: &Path, bytes: &[u8]) -> Result<(), FemtoError> {
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        fs::create_dir_all(dir).map_err(FemtoError::io(dir))?;
+    }
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = std::path::PathBuf::from(tmp);
+    let result = fs::File::create(&tmp)
+        .and_then(|mut file| {
+            file.write_all(bytes)?;
+            file.sync_all()
+        })
+        // Replaces an existing file on Windows too
+        .and_then(|_| fs::rename(&tmp, path));
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    result.map_err(FemtoError::io(path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn random_state() -> TrainingState {
+        let mut rng = rand::thread_rng();
+        let mut state = TrainingState {
+            tensors: Default::default(),
+            optimizer: Default::default(),
+            sampler: None,
+            progress: Default::default(),
+            provenance: Default::default(),
+        };
+        state
+            .tensors
+            .insert("a".into(), Tensor::<f32>::rand(&mut rng, &[3, 5]));
+        state
+            .tensors
+            .insert("b".into(), Tensor::<f32>::rand(&mut rng, &[7]));
+        state
+            .optimizer
+            .state
+            .insert("a_m".into(), Tensor::<f32>::rand(&mut rng, &[3, 5]));
+        state.optimizer.step = 123;
+        state
+    }
+
+    fn assert_same(a: &TrainingState, b: &TrainingState) {
+        assert_eq!(a.optimizer.step, b.optimizer.step);
+        assert_eq!(a.tensors.len(), b.tensors.len());
+        assert_eq!(a.optimizer.state.len(), b.optimizer.state.len());
+        for (name, t) in a.tensors.iter().chain(a.optimizer.state.iter()) {
+            let other = b
+                .tensors
+                .get(name)
+                .or_else(|| b.optimizer.state.get(name))
+                .unwrap();
+            assert_eq!(t.shape(), other.shape());
+            assert_eq!(t.blob(), other.blob());
+        }
+    }
+
+    #[cfg(feature = "fs")]
+    #[test]
+    fn test_atomic_save() {
+        let dir = std::env::temp_dir().join(format!("femto-save-{}", std::process::id()));
+        let path = dir.join("runs").join("model.dat");
+        let state = random_state();
+        save_training_state(&path, &state).unwrap();
+        save_training_state(&path, &state).unwrap();
+        assert_same(&state, &load_training_state(&path).unwrap());
+        // Only the checkpoint is left, the temporary file was renamed over it
+        let files = fs::read_dir(dir.join("runs")).unwrap().count();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(files, 1);
+
+        // Failures name the path
+        let file = std::env::temp_dir().join(format!("femto-file-{}", std::process::id()));
+        fs::write(&file, b"").unwrap();
+        let err = save_training_state(&file.join("model.dat"), &state).unwrap_err();
+        fs::remove_file(&file).unwrap();
+        assert!(err.to_string().contains(&*file.to_string_lossy()));
+    }
+
+    #[test]
+    fn test_checkpoint_roundtrip() {
+        let mut state = random_state();
+        state.sampler = Some(SamplerState {
+            seed: 42,
+            epoch: 3,
+            cursor: 17,
+        });
+        state.progress = Progress {
+            tokens: 50_000_000,
+            elapsed: Duration::from_millis(21_600_250),
+        };
+        state.provenance = Provenance {
+            dataset: Some(format!("{:016x}", fnv1a(b"dataset"))),
+            tokenizer: Some(format!("{:016x}", fnv1a(b"tokenizer"))),
+            loss: Some(1.234),
+        };
+        let mut bytes = Vec::new();
+        write_training_state(&mut bytes, &state).unwrap();
+        assert!(bytes.starts_with(CHECKPOINT_MAGIC));
+        let read = read_training_state(&bytes).unwrap();
+        assert_same(&state, &read);
+        assert_eq!(read.sampler, state.sampler);
+        assert_eq!(read.progress, state.progress);
+        assert_eq!(read.provenance, state.provenance);
+        assert_eq!(checkpoint_version(&bytes), CHECKPOINT_VERSION);
+    }
+
+    #[test]
+    fn test_legacy_checkpoint() {
+        let state = random_state();
+        let bytes = bincode::serialize(&state).unwrap();
+        assert_same(&state, &read_training_state(&bytes).unwrap());
+        assert_eq!(checkpoint_version(&bytes), 0);
+    }
+
+    #[test]
+    fn test_average_training_states() {
+        let a = random_state();
+        let mut b = random_state();
+        let average = average_training_states(&[a.clone(), b.clone()], &[3., 1.]).unwrap();
+        let expected = a.tensors["b"].blob()[0] * 0.75 + b.tensors["b"].blob()[0] * 0.25;
+        assert!((average.tensors["b"].blob()[0] - expected).abs() < 1e-6);
+        assert!(average.optimizer.state.is_empty());
+
+        b.tensors.insert("b".into(), Tensor::zeros(&[8]));
+        assert!(matches!(
+            average_training_states(&[a, b], &[1., 1.]),
+            Err(CheckpointError::Mismatch { index: 1, .. })
+        ));
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn test_compressed_checkpoint() {
+        let state = random_state();
+        let mut bytes = Vec::new();
+        write_training_state(&mut bytes, &state).unwrap();
+        let compressed = compress(&bytes, 3).unwrap();
+        assert!(compressed.starts_with(ZSTD_MAGIC));
+        assert_same(&state, &read_training_state(&compressed).unwrap());
+        assert_eq!(&decompress(&compressed).unwrap()[..], &bytes[..]);
+        assert!(matches!(decompress(&bytes).unwrap(), Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn test_newer_version_is_rejected() {
+        let mut bytes = Vec::new();
+        write_training_state(&mut bytes, &random_state()).unwrap();
+        bytes[8..12].copy_from_slice(&(CHECKPOINT_VERSION + 1).to_le_bytes());
+        assert!(matches!(
+            read_training_state(&bytes),
+            Err(CheckpointError::UnsupportedVersion(_))
+        ));
+    }
+
+    #[test]
+    fn test_huge_tensor_is_rejected() {
+        let header = format!(
+            r#"{{"tensors":[{{"name":"a","dtype":"F32","shape":[{},2],"offset":0}}]}}"#,
+            usize::MAX
+        );
+        let mut bytes = CHECKPOINT_MAGIC.to_vec();
+        bytes.extend(CHECKPOINT_VERSION.to_le_bytes());
+        bytes.extend((header.len() as u64).to_le_bytes());
+        bytes.extend(header.as_bytes());
+        assert!(matches!(
+            read_training_state(&bytes),
+            Err(CheckpointError::InvalidFormat(_))
+        ));
+    }
+}
@@ -0,0 +1,337 @@
+// Text classification on top of a trained model. The model stays frozen: texts are embedded (See
+// `GPT::embed`) and a linear head, mapping embeddings to the logits of the labels, is trained on
+// them with a softmax cross-entropy loss. Heads are saved as checkpoints of their own, the labels
+// and pooling being part of their metadata.
+
+#[cfg(feature = "fs")]
+use crate::checkpoint::write_atomic;
+use crate::checkpoint::{decompress, Checkpoint, CheckpointError};
+#[cfg(feature = "fs")]
+use crate::error::FemtoError;
+use crate::gpt::Pooling;
+use crate::optimizer::{Optimizer, OptimizerState};
+use crate::tensor::{Tensor, TensorError, TensorOps};
+use serde::Deserialize;
+use std::collections::HashMap;
+#[cfg(feature = "fs")]
+use std::fs;
+use std::io::Write;
+#[cfg(feature = "fs")]
+use std::path::Path;
+
+const CLASSIFIER_LABELS_KEY: &str = "classifier.labels";
+const CLASSIFIER_POOLING_KEY: &str = "classifier.pooling";
+const WEIGHTS: &str = "classifier.weights";
+const BIAS: &str = "classifier.bias";
+
+/// A text and its label, e.g. a line of a `text,label` CSV file.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct LabeledText {
+    pub text: String,
+    pub label: String,
+}
+
+/// Reads `text,label` CSV rows, quoted fields holding commas, newlines or doubled quotes. A
+/// `text,label` header is skipped.
+pub fn parse_labeled_csv(csv: &str) -> Result<Vec<LabeledText>, String> {
+    let mut rows = Vec::new();
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let (mut quoted, mut line) = (false, 1);
+    let mut chars = csv.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            '"' if quoted => quoted = false,
+            '"' if field.is_empty() => quoted = true,
+            ',' if !quoted => fields.push(std::mem::take(&mut field)),
+            '\r' if !quoted && chars.peek() == Some(&'\n') => {}
+            '\n' if !quoted => {
+                fields.push(std::mem::take(&mut field));
+                rows.push((line, std::mem::take(&mut fields)));
+                line += 1;
+            }
+            c => {
+                if c == '\n' {
+                    line += 1;
+                }
+                field.push(c);
+            }
+        }
+    }
+    if quoted {
+        return Err(format!("line {}: unterminated quote", line));
+    }
+    if !field.is_empty() || !fields.is_empty() {
+        fields.push(field);
+        rows.push((line, fields));
+    }
+    let mut examples = Vec::new();
+    for (i, (line, fields)) in rows.into_iter().enumerate() {
+        match &fields[..] {
+            [field] if field.trim().is_empty() => {}
+            [text, label] if i == 0 && text == "text" && label == "label" => {}
+            [text, label] => examples.push(LabeledText {
+                text: text.clone(),
+                label: label.trim().to_string(),
+            }),
+            _ => {
+                return Err(format!(
+                    "line {}: expected 2 fields (`text,label`), got {}",
+                    line,
+                    fields.len()
+                ))
+            }
+        }
+    }
+    Ok(examples)
+}
+
+/// Reads `{"text": ..., "label": ...}` lines.
+pub fn parse_labeled_jsonl(jsonl: &str) -> Result<Vec<LabeledText>, String> {
+    jsonl
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| serde_json::from_str(line).map_err(|e| format!("line {}: {}", i + 1, e)))
+        .collect()
+}
+
+/// A linear classification head over the embeddings of a model (`embedding_degree` values,
+/// pooled with `pooling`), `weights` being `[labels, embedding_degree]`.
+#[derive(Debug, Clone)]
+pub struct Classifier {
+    pub labels: Vec<String>,
+    pub pooling: Pooling,
+    pub weights: Tensor<f32>,
+    pub bias: Tensor<f32>,
+}
+
+impl Classifier {
+    pub fn new(labels: Vec<String>, embedding_degree: usize, pooling: Pooling) -> Self {
+        let num_labels = labels.len();
+        Self {
+            labels,
+            pooling,
+            weights: Tensor::zeros(&[num_labels, embedding_degree]),
+            bias: Tensor::zeros(&[num_labels]),
+        }
+    }
+
+    pub fn embedding_degree(&self) -> usize {
+        self.weights.shape()[1]
+    }
+
+    pub fn logits(&self, embedding: &[f32]) -> Vec<f32> {
+        self.weights
+            .blob()
+            .chunks(self.embedding_degree())
+            .zip(self.bias.blob())
+            .map(|(row, b)| row.iter().zip(embedding).map(|(w, x)| w * x).sum::<f32>() + b)
+            .collect()
+    }
+
+    /// The probabilities of the labels for a text of this embedding.
+    pub fn probabilities(&self, embedding: &[f32]) -> Vec<f32> {
+        let logits = self.logits(embedding);
+        let max = logits.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+        let exps = logits.iter().map(|l| (l - max).exp()).collect::<Vec<_>>();
+        let sum = exps.iter().sum::<f32>();
+        exps.iter().map(|e| e / sum).collect()
+    }
+
+    /// The most likely label (Its index) and its probability.
+    pub fn classify(&self, embedding: &[f32]) -> (usize, f32) {
+        self.probabilities(embedding)
+            .into_iter()
+            .enumerate()
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+            .unwrap_or_default()
+    }
+
+    /// Share of the `(embedding, label)` examples classified correctly.
+    pub fn accuracy(&self, examples: &[(Vec<f32>, usize)]) -> f32 {
+        let correct = examples
+            .iter()
+            .filter(|(embedding, label)| self.classify(embedding).0 == *label)
+            .count();
+        correct as f32 / examples.len().max(1) as f32
+    }
+
+    /// A step of `optimizer` on all the `(embedding, label)` examples, returning their average
+    /// loss before it.
+    pub fn fit_step<O: Optimizer>(
+        &mut self,
+        examples: &[(Vec<f32>, usize)],
+        optimizer: &O,
+        optimizer_state: &mut OptimizerState,
+        learning_rate: f32,
+    ) -> Result<f32, TensorError> {
+        let degree = self.embedding_degree();
+        let mut weights_grad = vec![0.; self.weights.size()];
+        let mut bias_grad = vec![0.; self.bias.size()];
+        let mut loss = 0.;
+        let scale = 1. / examples.len().max(1) as f32;
+        for (embedding, label) in examples.iter() {
+            let mut probs = self.probabilities(embedding);
+            loss -= probs[*label].max(f32::MIN_POSITIVE).ln() * scale;
+            // Gradient of the cross-entropy with respect to the logits
+            probs[*label] -= 1.;
+            for (i, p) in probs.iter().enumerate() {
+                bias_grad[i] += p * scale;
+                for (g, x) in weights_grad[i * degree..(i + 1) * degree]
+                    .iter_mut()
+                    .zip(embedding)
+                {
+                    *g += p * x * scale;
+                }
+            }
+        }
+        let weights_grad = Tensor::raw(self.weights.shape(), weights_grad)?;
+        let bias_grad = Tensor::raw(self.bias.shape(), bias_grad)?;
+        let params = HashMap::from([
+            (WEIGHTS.to_string(), (&mut self.weights, &weights_grad)),
+            (BIAS.to_string(), (&mut self.bias, &bias_grad)),
+        ]);
+        optimizer.step(params, optimizer_state, learning_rate)?;
+        Ok(loss)
+    }
+
+    pub fn write<W: Write>(&self, out: &mut W) -> Result<(), CheckpointError> {
+        let labels = serde_json::to_string(&self.labels)
+            .map_err(|e| CheckpointError::InvalidFormat(e.to_string()))?;
+        let pooling = match self.pooling {
+            Pooling::Mean => "mean",
+            Pooling::Last => "last",
+        };
+        Checkpoint {
+            metadata: [
+                (CLASSIFIER_LABELS_KEY.to_string(), labels),
+                (CLASSIFIER_POOLING_KEY.to_string(), pooling.to_string()),
+            ]
+            .into(),
+            tensors: [
+                (WEIGHTS.to_string(), self.weights.clone()),
+                (BIAS.to_string(), self.bias.clone()),
+            ]
+            .into(),
+        }
+        .write(out)
+    }
+
+    pub fn read(bytes: &[u8]) -> Result<Self, CheckpointError> {
+        let mut checkpoint = Checkpoint::read(&decompress(bytes)?)?;
+        let invalid = CheckpointError::InvalidFormat;
+        let labels = checkpoint
+            .metadata
+            .get(CLASSIFIER_LABELS_KEY)
+            .ok_or_else(|| invalid("not a classifier, it has no labels".into()))?;
+        let labels: Vec<String> = serde_json::from_str(labels)
+            .map_err(|e| invalid(format!("invalid classifier labels: {}", e)))?;
+        let pooling = checkpoint
+            .metadata
+            .get(CLASSIFIER_POOLING_KEY)
+            .map(|p| p.parse())
+            .transpose()
+            .map_err(invalid)?
+            .unwrap_or_default();
+        let mut tensor = |name: &str| {
+            checkpoint
+                .tensors
+                .remove(name)
+                .ok_or_else(|| invalid(format!("the classifier has no `{}`", name)))
+        };
+        let (weights, bias) = (tensor(WEIGHTS)?, tensor(BIAS)?);
+        if weights.dim() != 2
+            || weights.shape()[0] != labels.len()
+            || bias.shape() != [labels.len()]
+        {
+            return Err(invalid(format!(
+                "classifier weights of shape {:?} for {} labels",
+                weights.shape(),
+                labels.len()
+            )));
+        }
+        Ok(Self {
+            labels,
+            pooling,
+            weights,
+            bias,
+        })
+    }
+}
+
+#[cfg(feature = "fs")]
+pub fn load_classifier(path: &Path) -> Result<Classifier, FemtoError> {
+    let bytes = fs::read(path).map_err(FemtoError::io(path))?;
+    Classifier::read(&bytes).map_err(FemtoError::checkpoint(path))
+}
+
+#[cfg(feature = "fs")]
+pub fn save_classifier(path: &Path, classifier: &Classifier) -> Result<(), FemtoError> {
+    let mut bytes = Vec::new();
+    classifier
+        .write(&mut bytes)
+        .map_err(FemtoError::checkpoint(path))?;
+    write_atomic(path, &bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::optimizer::AdamW;
+
+    #[test]
+    fn test_labeled_texts() {
+        let csv = "text,label\nhello world,greeting\n\"bye, \"\"friend\"\"\nsee you\",farewell\r\n";
+        let examples = parse_labeled_csv(csv).unwrap();
+        assert_eq!(examples.len(), 2);
+        assert_eq!(examples[0].label, "greeting");
+        assert_eq!(examples[1].text, "bye, \"friend\"\nsee you");
+        assert_eq!(examples[1].label, "farewell");
+        assert!(parse_labeled_csv("a,b,c\n").is_err());
+        assert!(parse_labeled_csv("\"a,b\n").is_err());
+
+        let jsonl = "{\"text\": \"hello\", \"label\": \"greeting\"}\n\n";
+        assert_eq!(parse_labeled_jsonl(jsonl).unwrap()[0].text, "hello");
+    }
+
+    #[test]
+    fn test_classifier() {
+        // Three clusters of 2-dimensional embeddings
+        let centers = [[1., 0.], [-1., 0.5], [0., -1.]];
+        let examples = (0..30)
+            .map(|i| {
+                let [x, y] = centers[i % 3];
+                let jitter = (i as f32 * 0.37).sin() * 0.2;
+                (vec![x + jitter, y - jitter], i % 3)
+            })
+            .collect::<Vec<_>>();
+        let labels = ["a", "b", "c"].map(String::from).to_vec();
+        let mut classifier = Classifier::new(labels, 2, Pooling::Last);
+        let (optimizer, mut state) = (AdamW::new(), OptimizerState::default());
+        let first = classifier
+            .fit_step(&examples, &optimizer, &mut state, 0.05)
+            .unwrap();
+        assert!((first - 3f32.ln()).abs() < 1e-5);
+        let mut loss = first;
+        for _ in 0..200 {
+            loss = classifier
+                .fit_step(&examples, &optimizer, &mut state, 0.05)
+                .unwrap();
+        }
+        assert!(loss < first / 4.);
+        assert_eq!(classifier.accuracy(&examples), 1.);
+
+        let mut bytes = Vec::new();
+        classifier.write(&mut bytes).unwrap();
+        let read = Classifier::read(&bytes).unwrap();
+        assert_eq!(read.labels, classifier.labels);
+        assert_eq!(read.pooling, Pooling::Last);
+        assert_eq!(read.classify(&[0., -1.]), classifier.classify(&[0., -1.]));
+        assert_eq!(read.classify(&[0., -1.]).0, 2);
+    }
+}
@@ -0,0 +1,31 @@
+// Constrained decoding: at every step of `GPT::infer_batch`, the tokens whose text can't extend
+// the output into a valid one are masked out before sampling (See `InferParams::constraint`).
+
+mod regex;
+pub use regex::*;
+
+use crate::tokenizer::Tokenizer;
+use std::fmt::Debug;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum ConstraintError {
+    #[error("invalid pattern at character {position}: {message}")]
+    InvalidPattern { position: usize, message: String },
+}
+
+/// A set of valid outputs, checked token by token.
+pub trait Constraint: Debug + Send + Sync {
+    /// Whether each token of the vocabulary may follow the `generated` ones.
+    fn allowed(&self, generated: &[usize]) -> Vec<bool>;
+    /// Whether the `generated` tokens form a valid output, which ends the generation when no
+    /// token is allowed anymore.
+    fn is_complete(&self, generated: &[usize]) -> bool;
+}
+
+/// The text of every token of a tokenizer, indexed by token id.
+pub fn token_pieces<T: Tokenizer + ?Sized>(tokenizer: &T) -> Vec<String> {
+    (0..tokenizer.vocab_size())
+        .map(|t| tokenizer.untokenize(&[t]))
+        .collect()
+}
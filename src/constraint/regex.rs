@@ -0,0 +1,448 @@
+// Regular expressions compiled to Thompson NFAs, which are simulated over the text of the
+// generated tokens. Patterns match the whole output and support literals, `.`, classes (`[a-z]`,
+// `[^"]`), the `\d`, `\w` and `\s` escapes (And their negations), groups, alternations and the
+// `*`, `+`, `?` and `{m,n}` quantifiers.
+
+use super::{Constraint, ConstraintError};
+
+// Bound of `{m,n}` quantifiers, every repetition adds a copy of its expression to the automaton
+const MAX_REPEAT: usize = 1000;
+
+#[derive(Debug, Clone)]
+struct CharClass {
+    ranges: Vec<(char, char)>,
+    negated: bool,
+}
+
+impl CharClass {
+    fn single(c: char) -> Self {
+        Self {
+            ranges: vec![(c, c)],
+            negated: false,
+        }
+    }
+    fn matches(&self, c: char) -> bool {
+        self.ranges.iter().any(|(a, b)| (*a..=*b).contains(&c)) != self.negated
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Ast {
+    Empty,
+    Class(CharClass),
+    Concat(Vec<Ast>),
+    Alt(Vec<Ast>),
+    Repeat(Box<Ast>, usize, Option<usize>),
+}
+
+struct Parser<'a> {
+    chars: &'a [char],
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn error<T>(&self, message: &str) -> Result<T, ConstraintError> {
+        Err(ConstraintError::InvalidPattern {
+            position: self.pos,
+            message: message.into(),
+        })
+    }
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek();
+        self.pos += 1;
+        c
+    }
+    fn eat(&mut self, c: char) -> bool {
+        let found = self.peek() == Some(c);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn alt(&mut self) -> Result<Ast, ConstraintError> {
+        let mut branches = vec![self.concat()?];
+        while self.eat('|') {
+            branches.push(self.concat()?);
+        }
+        Ok(if branches.len() == 1 {
+            branches.remove(0)
+        } else {
+            Ast::Alt(branches)
+        })
+    }
+
+    fn concat(&mut self) -> Result<Ast, ConstraintError> {
+        let mut items = Vec::new();
+        while !matches!(self.peek(), None | Some('|') | Some(')')) {
+            items.push(self.repeat()?);
+        }
+        Ok(match items.len() {
+            0 => Ast::Empty,
+            1 => items.remove(0),
+            _ => Ast::Concat(items),
+        })
+    }
+
+    fn repeat(&mut self) -> Result<Ast, ConstraintError> {
+        let mut ast = self.atom()?;
+        loop {
+            let (min, max) = match self.peek() {
+                Some('*') => (0, None),
+                Some('+') => (1, None),
+                Some('?') => (0, Some(1)),
+                Some('{') => {
+                    self.pos += 1;
+                    let min = self.number()?;
+                    let max = if self.eat(',') {
+                        (self.peek() != Some('}'))
+                            .then(|| self.number())
+                            .transpose()?
+                    } else {
+                        Some(min)
+                    };
+                    if self.peek() != Some('}') {
+                        return self.error("expected `}`");
+                    }
+                    if max.is_some_and(|max| max < min) || max.unwrap_or(min) > MAX_REPEAT {
+                        return self.error("invalid repetition bounds");
+                    }
+                    (min, max)
+                }
+                _ => return Ok(ast),
+            };
+            self.pos += 1;
+            ast = Ast::Repeat(Box::new(ast), min, max);
+        }
+    }
+
+    fn number(&mut self) -> Result<usize, ConstraintError> {
+        let start = self.pos;
+        while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        match self.chars[start..self.pos]
+            .iter()
+            .collect::<String>()
+            .parse()
+        {
+            Ok(n) => Ok(n),
+            Err(_) => self.error("expected a number"),
+        }
+    }
+
+    fn atom(&mut self) -> Result<Ast, ConstraintError> {
+        match self.next() {
+            Some('(') => {
+                let ast = self.alt()?;
+                if !self.eat(')') {
+                    return self.error("expected `)`");
+                }
+                Ok(ast)
+            }
+            Some('[') => self.class().map(Ast::Class),
+            Some('.') => Ok(Ast::Class(CharClass {
+                ranges: vec![('\n', '\n')],
+                negated: true,
+            })),
+            Some('\\') => self.escape().map(Ast::Class),
+            Some('*' | '+' | '?' | '{') => {
+                self.pos -= 1;
+                self.error("nothing to repeat")
+            }
+            Some(c) => Ok(Ast::Class(CharClass::single(c))),
+            None => self.error("unexpected end of pattern"),
+        }
+    }
+
+    fn escape(&mut self) -> Result<CharClass, ConstraintError> {
+        let class = |ranges: &[(char, char)], negated| CharClass {
+            ranges: ranges.to_vec(),
+            negated,
+        };
+        const DIGITS: &[(char, char)] = &[('0', '9')];
+        const WORDS: &[(char, char)] = &[('a', 'z'), ('A', 'Z'), ('0', '9'), ('_', '_')];
+        const SPACES: &[(char, char)] = &[(' ', ' '), ('\t', '\r')];
+        Ok(match self.next() {
+            Some('d') => class(DIGITS, false),
+            Some('D') => class(DIGITS, true),
+            Some('w') => class(WORDS, false),
+            Some('W') => class(WORDS, true),
+            Some('s') => class(SPACES, false),
+            Some('S') => class(SPACES, true),
+            Some('n') => CharClass::single('\n'),
+            Some('t') => CharClass::single('\t'),
+            Some('r') => CharClass::single('\r'),
+            Some(c) => CharClass::single(c),
+            None => return self.error("unexpected end of pattern"),
+        })
+    }
+
+    fn class(&mut self) -> Result<CharClass, ConstraintError> {
+        let negated = self.eat('^');
+        let mut ranges = Vec::new();
+        let mut first = true;
+        loop {
+            let start = match self.next() {
+                Some(']') if !first => break,
+                Some('\\') => {
+                    let escaped = self.escape()?;
+                    // Negated escapes can't be merged in the ranges of the class
+                    if escaped.negated {
+                        return self.error("negated escapes aren't supported in classes");
+                    }
+                    ranges.extend(escaped.ranges);
+                    first = false;
+                    continue;
+                }
+                Some(c) => c,
+                None => return self.error("expected `]`"),
+            };
+            first = false;
+            if self.peek() == Some('-') && self.chars.get(self.pos + 1).is_some_and(|c| *c != ']') {
+                self.pos += 1;
+                let end = self.next().unwrap();
+                if end < start {
+                    return self.error("invalid class range");
+                }
+                ranges.push((start, end));
+            } else {
+                ranges.push((start, start));
+            }
+        }
+        Ok(CharClass { ranges, negated })
+    }
+}
+
+#[derive(Debug, Default)]
+struct State {
+    edges: Vec<(CharClass, usize)>,
+    epsilons: Vec<usize>,
+}
+
+#[derive(Debug)]
+struct Nfa {
+    states: Vec<State>,
+    start: usize,
+    accept: usize,
+}
+
+impl Nfa {
+    fn add(&mut self) -> usize {
+        self.states.push(State::default());
+        self.states.len() - 1
+    }
+
+    // Adds the states of `ast`, returning its entry and exit
+    fn compile(&mut self, ast: &Ast) -> (usize, usize) {
+        match ast {
+            Ast::Empty => {
+                let s = self.add();
+                (s, s)
+            }
+            Ast::Class(class) => {
+                let (s, e) = (self.add(), self.add());
+                self.states[s].edges.push((class.clone(), e));
+                (s, e)
+            }
+            Ast::Concat(items) => {
+                let (start, mut end) = self.compile(&items[0]);
+                for item in items[1..].iter() {
+                    let (s, e) = self.compile(item);
+                    self.states[end].epsilons.push(s);
+                    end = e;
+                }
+                (start, end)
+            }
+            Ast::Alt(branches) => {
+                let (s, e) = (self.add(), self.add());
+                for branch in branches.iter() {
+                    let (bs, be) = self.compile(branch);
+                    self.states[s].epsilons.push(bs);
+                    self.states[be].epsilons.push(e);
+                }
+                (s, e)
+            }
+            Ast::Repeat(inner, min, max) => {
+                let start = self.add();
+                let mut end = start;
+                for _ in 0..*min {
+                    let (s, e) = self.compile(inner);
+                    self.states[end].epsilons.push(s);
+                    end = e;
+                }
+                match max {
+                    // Loop back to the start of another repetition
+                    None => {
+                        let (s, e) = self.compile(inner);
+                        self.states[end].epsilons.push(s);
+                        self.states[e].epsilons.push(end);
+                    }
+                    // Optional repetitions, each one may skip the remaining ones
+                    Some(max) => {
+                        let exit = self.add();
+                        for _ in *min..*max {
+                            let (s, e) = self.compile(inner);
+                            self.states[end].epsilons.push(s);
+                            self.states[end].epsilons.push(exit);
+                            end = e;
+                        }
+                        self.states[end].epsilons.push(exit);
+                        end = exit;
+                    }
+                }
+                (start, end)
+            }
+        }
+    }
+
+    // Adds the states reachable from `set` without consuming characters
+    fn close(&self, set: &mut Vec<usize>) {
+        let mut stack = set.clone();
+        while let Some(s) = stack.pop() {
+            for next in self.states[s].epsilons.iter() {
+                if !set.contains(next) {
+                    set.push(*next);
+                    stack.push(*next);
+                }
+            }
+        }
+    }
+
+    fn step(&self, set: &[usize], c: char) -> Vec<usize> {
+        let mut next = Vec::new();
+        for s in set.iter() {
+            for (class, to) in self.states[*s].edges.iter() {
+                if class.matches(c) && !next.contains(to) {
+                    next.push(*to);
+                }
+            }
+        }
+        self.close(&mut next);
+        next
+    }
+
+    fn run(&self, set: &[usize], text: impl Iterator<Item = char>) -> Vec<usize> {
+        let mut set = set.to_vec();
+        for c in text {
+            if set.is_empty() {
+                break;
+            }
+            set = self.step(&set, c);
+        }
+        set
+    }
+}
+
+/// Constrains the text of the generated tokens to match a regular expression.
+#[derive(Debug)]
+pub struct RegexConstraint {
+    nfa: Nfa,
+    initial: Vec<usize>,
+    pieces: Vec<Vec<char>>,
+}
+
+impl RegexConstraint {
+    /// Compiles `pattern` for a vocabulary whose tokens have the texts of `pieces` (See
+    /// `token_pieces`).
+    pub fn new(pattern: &str, pieces: &[String]) -> Result<Self, ConstraintError> {
+        let chars = pattern.chars().collect::<Vec<_>>();
+        let mut parser = Parser {
+            chars: &chars,
+            pos: 0,
+        };
+        let ast = parser.alt()?;
+        if parser.peek().is_some() {
+            return parser.error("unmatched `)`");
+        }
+        let mut nfa = Nfa {
+            states: Vec::new(),
+            start: 0,
+            accept: 0,
+        };
+        (nfa.start, nfa.accept) = nfa.compile(&ast);
+        let mut initial = vec![nfa.start];
+        nfa.close(&mut initial);
+        Ok(Self {
+            nfa,
+            initial,
+            pieces: pieces.iter().map(|p| p.chars().collect()).collect(),
+        })
+    }
+
+    fn state(&self, generated: &[usize]) -> Vec<usize> {
+        self.nfa.run(
+            &self.initial,
+            generated
+                .iter()
+                .flat_map(|t| self.pieces.get(*t).into_iter().flatten().copied()),
+        )
+    }
+
+    /// Whether the whole `text` matches the pattern.
+    pub fn is_match(&self, text: &str) -> bool {
+        self.nfa
+            .run(&self.initial, text.chars())
+            .contains(&self.nfa.accept)
+    }
+}
+
+impl Constraint for RegexConstraint {
+    fn allowed(&self, generated: &[usize]) -> Vec<bool> {
+        let state = self.state(generated);
+        self.pieces
+            .iter()
+            .map(|piece| {
+                // Empty tokens would never move the generation forward
+                !state.is_empty()
+                    && !piece.is_empty()
+                    && !self.nfa.run(&state, piece.iter().copied()).is_empty()
+            })
+            .collect()
+    }
+    fn is_complete(&self, generated: &[usize]) -> bool {
+        self.state(generated).contains(&self.nfa.accept)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_regex() {
+        let re = |p| RegexConstraint::new(p, &[]).unwrap();
+        assert!(re("ab|cd").is_match("cd"));
+        assert!(!re("ab|cd").is_match("abcd"));
+        assert!(re(r"\d{2,3}-[a-c]+").is_match("123-abca"));
+        assert!(!re(r"\d{2,3}-[a-c]+").is_match("1234-a"));
+        assert!(re(r#"\{"name": "[^"]*"\}"#).is_match(r#"{"name": "femto"}"#));
+        assert!(re("(x?y)*").is_match(""));
+        assert!(re("(x?y)*").is_match("yxyy"));
+        assert!(re("a.c").is_match("a-c"));
+        for invalid in ["(ab", "ab)", "*a", "[a-", "a{3,2}"] {
+            assert!(RegexConstraint::new(invalid, &[]).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_allowed_tokens() {
+        let pieces = ["a", "b", "ab", "", "1", "12"].map(String::from);
+        let re = RegexConstraint::new("a+b1?", &pieces).unwrap();
+        assert_eq!(
+            re.allowed(&[]),
+            vec![true, false, true, false, false, false]
+        );
+        assert_eq!(
+            re.allowed(&[2]),
+            vec![false, false, false, false, true, false]
+        );
+        assert!(!re.is_complete(&[0]));
+        assert!(re.is_complete(&[0, 2]));
+        assert!(re.is_complete(&[2, 4]));
+        assert!(re.allowed(&[2, 4]).iter().all(|a| !a));
+    }
+}
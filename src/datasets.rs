@@ -0,0 +1,174 @@
+// Small standard corpora to train a first model on, downloaded by `femto fetch-dataset`. Their
+// contents are checked against the SHA-256 digests pinned here, so that a moved or altered file
+// doesn't silently train a different model. Downloading needs the `download` feature.
+
+#[cfg(feature = "download")]
+use sha2::{Digest, Sha256};
+#[cfg(feature = "download")]
+use std::io::Read;
+use std::str::FromStr;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum DownloadError {
+    #[error("cannot download {url}: {message}")]
+    Request { url: String, message: String },
+    #[error("{url} has the SHA-256 {actual}, expected {expected}")]
+    Checksum {
+        url: String,
+        expected: String,
+        actual: String,
+    },
+    #[error("no SHA-256 is pinned for {url} (It has {actual}), it can't be checked")]
+    Unpinned { url: String, actual: String },
+    #[error("{0} isn't UTF-8 text")]
+    NotText(String),
+}
+
+/// A corpus `fetch-dataset` knows of.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExampleDataset {
+    /// The works of Shakespeare of char-rnn (1.1 MB), as shipped as `dataset.txt`
+    TinyShakespeare,
+    /// The validation split of WikiText-2 (1.1 MB), word-level tokenized with `<unk>` markers
+    WikiText2Tiny,
+}
+
+impl ExampleDataset {
+    pub const ALL: [Self; 2] = [Self::TinyShakespeare, Self::WikiText2Tiny];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::TinyShakespeare => "tinyshakespeare",
+            Self::WikiText2Tiny => "wikitext2-tiny",
+        }
+    }
+
+    pub fn url(&self) -> &'static str {
+        match self {
+            Self::TinyShakespeare => {
+                "https://raw.githubusercontent.com/keyvank/femtoGPT/main/dataset.txt"
+            }
+            Self::WikiText2Tiny => {
+                "https://raw.githubusercontent.com/pytorch/examples/main/word_language_model/data/wikitext-2/valid.txt"
+            }
+        }
+    }
+
+    /// Digest of the contents, `None` for the corpora whose digest isn't pinned yet, which can't
+    /// be fetched: `fetch` fails with the digest of what it downloaded, to pin here.
+    pub fn sha256(&self) -> Option<&'static str> {
+        match self {
+            Self::TinyShakespeare => {
+                Some("434c0554a8c4c53dc17e56a0abb0f30b88f83cbceb0289cb897db68c25e89eba")
+            }
+            Self::WikiText2Tiny => None,
+        }
+    }
+
+    /// Downloads the corpus and checks its digest.
+    #[cfg(feature = "download")]
+    pub fn fetch(&self) -> Result<String, DownloadError> {
+        let url = self.url();
+        let request = |message: String| DownloadError::Request {
+            url: url.into(),
+            message,
+        };
+        let response = ureq::get(url)
+            .set("User-Agent", concat!("femto/", env!("CARGO_PKG_VERSION")))
+            .call()
+            .map_err(|e| {
+                request(match e {
+                    ureq::Error::Status(status, _) => format!("status {}", status),
+                    ureq::Error::Transport(e) => e
+                        .message()
+                        .map_or_else(|| e.kind().to_string(), String::from),
+                })
+            })?;
+        let mut bytes = Vec::new();
+        response
+            .into_reader()
+            .read_to_end(&mut bytes)
+            .map_err(|e| request(e.to_string()))?;
+        verify(url, &bytes, self.sha256())?;
+        String::from_utf8(bytes).map_err(|_| DownloadError::NotText(url.into()))
+    }
+}
+
+impl FromStr for ExampleDataset {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|d| d.name() == s)
+            .ok_or_else(|| {
+                format!(
+                    "expected `tinyshakespeare` or `wikitext2-tiny`, got `{}`",
+                    s
+                )
+            })
+    }
+}
+
+#[cfg(feature = "download")]
+pub fn sha256_hex(bytes: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(bytes))
+}
+
+// Checks the digest of the `bytes` downloaded from `url`, unchecked files being refused
+#[cfg(feature = "download")]
+fn verify(url: &str, bytes: &[u8], expected: Option<&str>) -> Result<(), DownloadError> {
+    let actual = sha256_hex(bytes);
+    let Some(expected) = expected else {
+        return Err(DownloadError::Unpinned {
+            url: url.into(),
+            actual,
+        });
+    };
+    if actual != expected {
+        return Err(DownloadError::Checksum {
+            url: url.into(),
+            expected: expected.into(),
+            actual,
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_names() {
+        for dataset in ExampleDataset::ALL {
+            assert_eq!(dataset.name().parse::<ExampleDataset>(), Ok(dataset));
+        }
+        assert!("shakespeare".parse::<ExampleDataset>().is_err());
+    }
+
+    #[cfg(feature = "download")]
+    #[test]
+    fn test_verify() {
+        // The one of the repository
+        assert_eq!(
+            ExampleDataset::TinyShakespeare.sha256(),
+            Some(sha256_hex(include_bytes!("../dataset.txt")).as_str())
+        );
+
+        let digest = sha256_hex(b"abc");
+        assert_eq!(
+            digest,
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert!(verify("url", b"abc", Some(&digest)).is_ok());
+        assert!(matches!(
+            verify("url", b"abc", None),
+            Err(DownloadError::Unpinned { .. })
+        ));
+        assert!(matches!(
+            verify("url", b"abd", Some(&digest)),
+            Err(DownloadError::Checksum { .. })
+        ));
+    }
+}
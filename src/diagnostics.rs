@@ -0,0 +1,168 @@
+// Diagnostics of the quality of generated texts, to measure the effects of sampling settings: how
+// varied the generations are (Across them and within each one), and how much freedom the sampling
+// had (See `GPT::infer_entropies`). Texts are compared by their tokens.
+
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+
+/// Statistics of a set of generations, see `GenerationStats::new`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct GenerationStats {
+    pub generations: usize,
+    /// Average number of generated tokens
+    pub mean_length: f32,
+    /// Shares of the distinct unigrams, bigrams and trigrams among all the ones of the
+    /// generations (distinct-1, distinct-2 and distinct-3), low values meaning the generations
+    /// keep using the same words
+    pub distinct: [f32; 3],
+    /// Average share of the trigrams of a generation already seen earlier in it (See
+    /// `repetition_rate`)
+    pub repetition_rate: f32,
+    /// Average BLEU of each generation against the other ones, high values meaning the
+    /// generations look alike. Needs 2 generations at least
+    pub self_bleu: Option<f32>,
+    /// Average entropy, in nats, of the distributions the tokens were sampled from
+    pub entropy: Option<f32>,
+}
+
+impl GenerationStats {
+    /// The statistics of the generated tokens of `generations` (Without their prompts), and of
+    /// the `entropies` of the distributions their tokens were sampled from, if known.
+    pub fn new(generations: &[Vec<usize>], entropies: &[f32]) -> Self {
+        let count = generations.len().max(1) as f32;
+        let references = generations.iter().map(|g| &g[..]).collect::<Vec<_>>();
+        Self {
+            generations: generations.len(),
+            mean_length: generations.iter().map(|g| g.len()).sum::<usize>() as f32 / count,
+            distinct: [1, 2, 3].map(|n| distinct_n(generations, n)),
+            repetition_rate: generations.iter().map(|g| repetition_rate(g)).sum::<f32>() / count,
+            self_bleu: (generations.len() >= 2).then(|| {
+                (0..references.len())
+                    .map(|i| {
+                        let others = [&references[..i], &references[i + 1..]].concat();
+                        bleu(references[i], &others)
+                    })
+                    .sum::<f32>()
+                    / count
+            }),
+            entropy: (!entropies.is_empty())
+                .then(|| entropies.iter().sum::<f32>() / entropies.len() as f32),
+        }
+    }
+}
+
+impl std::fmt::Display for GenerationStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let optional = |v: Option<f32>| v.map_or("-".into(), |v| format!("{:.4}", v));
+        writeln!(f, "Generations:     {}", self.generations)?;
+        writeln!(f, "Mean length:     {:.1} tokens", self.mean_length)?;
+        writeln!(
+            f,
+            "Distinct-1/2/3:  {:.4} / {:.4} / {:.4}",
+            self.distinct[0], self.distinct[1], self.distinct[2]
+        )?;
+        writeln!(f, "Repetition rate: {:.4}", self.repetition_rate)?;
+        writeln!(f, "Self-BLEU:       {}", optional(self.self_bleu))?;
+        writeln!(f, "Entropy:         {} nats", optional(self.entropy))
+    }
+}
+
+/// Share of the distinct `n`-grams among all the ones of `generations`, 0 without any.
+pub fn distinct_n(generations: &[Vec<usize>], n: usize) -> f32 {
+    let ngrams = generations
+        .iter()
+        .flat_map(|g| g.windows(n))
+        .collect::<Vec<_>>();
+    let distinct = ngrams.iter().collect::<HashSet<_>>().len();
+    distinct as f32 / ngrams.len().max(1) as f32
+}
+
+/// Share of the trigrams of `tokens` that already appear earlier in them, 0 for texts that never
+/// repeat themselves.
+pub fn repetition_rate(tokens: &[usize]) -> f32 {
+    let trigrams = tokens.windows(3).collect::<Vec<_>>();
+    let mut seen = HashSet::new();
+    let repeated = trigrams.iter().filter(|t| !seen.insert(**t)).count();
+    repeated as f32 / trigrams.len().max(1) as f32
+}
+
+// Occurrences of each `n`-gram of `tokens`
+fn ngram_counts(tokens: &[usize], n: usize) -> HashMap<&[usize], usize> {
+    let mut counts = HashMap::new();
+    for ngram in tokens.windows(n) {
+        *counts.entry(ngram).or_default() += 1;
+    }
+    counts
+}
+
+/// BLEU-4 of `candidate` against `references`: the geometric mean of its n-gram precisions (An
+/// n-gram counting at most as many times as in one of the references), times a penalty for
+/// candidates shorter than the reference closest in length. The precisions of bigrams and longer
+/// n-grams add one to their counts (BLEU+1), so that short candidates don't score 0.
+pub fn bleu(candidate: &[usize], references: &[&[usize]]) -> f32 {
+    if candidate.is_empty() || references.is_empty() {
+        return 0.;
+    }
+    let mut log_precision = 0.;
+    for n in 1..=4 {
+        let mut max_counts = HashMap::new();
+        for reference in references {
+            for (ngram, count) in ngram_counts(reference, n) {
+                let max = max_counts.entry(ngram).or_insert(0);
+                *max = count.max(*max);
+            }
+        }
+        let counts = ngram_counts(candidate, n);
+        let matches = counts
+            .iter()
+            .map(|(ngram, count)| (*count).min(max_counts.get(ngram).copied().unwrap_or(0)))
+            .sum::<usize>();
+        let total = candidate.len().saturating_sub(n - 1);
+        let precision = match n {
+            1 => matches as f32 / total as f32,
+            _ => (matches + 1) as f32 / (total + 1) as f32,
+        };
+        if precision == 0. {
+            return 0.;
+        }
+        log_precision += precision.ln() / 4.;
+    }
+    let closest = references
+        .iter()
+        .map(|r| r.len())
+        .min_by_key(|len| (len.abs_diff(candidate.len()), *len))
+        .unwrap();
+    let brevity = match candidate.len() < closest {
+        true => (1. - closest as f32 / candidate.len() as f32).exp(),
+        false => 1.,
+    };
+    brevity * log_precision.exp()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generation_stats() {
+        assert_eq!(repetition_rate(&[1, 2, 3, 4, 5]), 0.);
+        // 1 2 3, 2 3 1, 3 1 2 and 1 2 3 again
+        assert_eq!(repetition_rate(&[1, 2, 3, 1, 2, 3]), 0.25);
+        assert_eq!(distinct_n(&[vec![1, 2], vec![2, 1]], 1), 0.5);
+        assert_eq!(distinct_n(&[vec![1, 2], vec![2, 1]], 2), 1.);
+
+        let text = vec![1, 2, 3, 4, 5, 6];
+        assert!((bleu(&text, &[&text]) - 1.).abs() < 1e-6);
+        assert!(bleu(&text, &[&[7, 8, 9, 10, 11, 12]]) == 0.);
+        // Half of the words, in another order
+        let partial = bleu(&text, &[&[3, 1, 5, 8, 9, 10]]);
+        assert!(partial > 0. && partial < 0.5);
+
+        let alike = GenerationStats::new(&[text.clone(), text.clone()], &[]);
+        let varied = GenerationStats::new(&[text, vec![7, 8, 9, 10, 11, 12]], &[0.5, 1.5]);
+        assert!((alike.self_bleu.unwrap() - 1.).abs() < 1e-6);
+        assert_eq!(varied.self_bleu, Some(0.));
+        assert!(alike.distinct[0] < varied.distinct[0]);
+        assert_eq!((alike.entropy, varied.entropy), (None, Some(1.)));
+    }
+}
@@ -0,0 +1,159 @@
+// Errors of the entry points working with files (Loading tokenizers, checkpoints and configs) and
+// of whole training runs. Lower-level APIs keep their own error types, which convert into it.
+
+use crate::checkpoint::CheckpointError;
+use crate::constraint::ConstraintError;
+use crate::datasets::DownloadError;
+use crate::export::ExportError;
+use crate::gpt::GptError;
+use crate::graph::GraphError;
+#[cfg(feature = "hub")]
+use crate::hub::HubError;
+use crate::tensor::TensorError;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum FemtoError {
+    #[error("cannot access {}: {source}", path.display())]
+    Io {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("cannot load the tokenizer {}: {message}", path.display())]
+    Tokenizer { path: PathBuf, message: String },
+    #[error(transparent)]
+    Graph(#[from] GraphError),
+    #[error(transparent)]
+    Gpt(GptError),
+    #[error("cannot read the checkpoint {}: {source}", path.display())]
+    Checkpoint {
+        path: PathBuf,
+        source: CheckpointError,
+    },
+    #[error("cannot export the model: {0}")]
+    Export(#[from] ExportError),
+    #[error(transparent)]
+    Constraint(#[from] ConstraintError),
+    #[error("invalid configuration: {0}")]
+    Config(String),
+    #[cfg(feature = "hub")]
+    #[error("hub request failed: {0}")]
+    Hub(#[from] HubError),
+    #[error(transparent)]
+    Download(#[from] DownloadError),
+}
+
+impl From<TensorError> for FemtoError {
+    fn from(e: TensorError) -> Self {
+        Self::Graph(e.into())
+    }
+}
+
+impl From<GptError> for FemtoError {
+    fn from(e: GptError) -> Self {
+        match e {
+            GptError::Graph(e) => Self::Graph(e),
+            e => Self::Gpt(e),
+        }
+    }
+}
+
+impl FemtoError {
+    /// Wraps the io errors of accessing `path`, e.g.
+    /// `fs::read(path).map_err(FemtoError::io(path))`.
+    pub fn io(path: &Path) -> impl FnOnce(std::io::Error) -> Self + '_ {
+        move |source| Self::Io {
+            path: path.into(),
+            source,
+        }
+    }
+
+    pub fn checkpoint(path: &Path) -> impl FnOnce(CheckpointError) -> Self + '_ {
+        move |source| Self::Checkpoint {
+            path: path.into(),
+            source,
+        }
+    }
+
+    /// Wraps the errors of loading the tokenizer at `path`, telling missing files apart from
+    /// invalid ones.
+    pub fn tokenizer<E: Into<Box<dyn std::error::Error + Send + Sync>>>(
+        path: &Path,
+    ) -> impl FnOnce(E) -> Self + '_ {
+        move |e| match e.into().downcast::<std::io::Error>() {
+            Ok(e) if e.kind() == std::io::ErrorKind::NotFound => Self::io(path)(*e),
+            Ok(e) => Self::Tokenizer {
+                path: path.into(),
+                message: e.to_string(),
+            },
+            Err(e) => Self::Tokenizer {
+                path: path.into(),
+                message: e.to_string(),
+            },
+        }
+    }
+
+    /// A suggestion of how to fix the error, for users of the command line.
+    pub fn hint(&self) -> Option<&'static str> {
+        match self {
+            Self::Io { source, .. } if source.kind() == std::io::ErrorKind::NotFound => {
+                Some("check the path, or pass another one (See `--help`)")
+            }
+            Self::Io { .. } => None,
+            Self::Tokenizer { .. } => Some(
+                "`--vocab` expects a SentencePiece `.vocab` file, `--hf-tokenizer` a HuggingFace \
+                 `tokenizer.json`",
+            ),
+            Self::Checkpoint {
+                source: CheckpointError::Mismatch { .. },
+                ..
+            } => Some("only checkpoints of the same architecture and vocabulary can be combined"),
+            Self::Checkpoint { .. } => Some(
+                "the file may be truncated, or written by a newer version of femto; \
+                 `.safetensors` files are read by their extension",
+            ),
+            Self::Graph(GraphError::NumericalAnomaly { .. }) => {
+                Some("try a lower learning rate, or a higher precision (See `--precision`)")
+            }
+            Self::Gpt(GptError::PromptTooLong(..)) => {
+                Some("shorten the prompt, or truncate it (See `--context-overflow`)")
+            }
+            Self::Graph(_) | Self::Gpt(_) | Self::Export(_) => None,
+            Self::Constraint(_) => Some(
+                "patterns support literals, `.`, `[...]` classes, `\\d`, `\\w`, `\\s`, groups, `|` \
+                 and the `*`, `+`, `?` and `{m,n}` quantifiers",
+            ),
+            Self::Config(_) => Some("the file passed to `--config` should be valid JSON"),
+            #[cfg(feature = "hub")]
+            Self::Hub(HubError::MissingToken) => {
+                Some("set `HF_TOKEN`, or log in with `huggingface-cli login`")
+            }
+            #[cfg(feature = "hub")]
+            Self::Hub(e) if e.is_unauthorized() => Some(
+                "check the repository exists and that the token (`HF_TOKEN`) can access it",
+            ),
+            #[cfg(feature = "hub")]
+            Self::Hub(_) => None,
+            Self::Download(DownloadError::Checksum { .. }) => Some(
+                "the file was altered or moved upstream, or the download was corrupted; try again",
+            ),
+            Self::Download(_) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_io_error() {
+        let path = Path::new("no_such_dir/vocab_file.vocab");
+        let err = std::fs::read(path)
+            .map_err(FemtoError::io(path))
+            .unwrap_err();
+        assert!(err.to_string().contains("no_such_dir/vocab_file.vocab"));
+        assert!(err.hint().is_some());
+    }
+}
@@ -0,0 +1,330 @@
+// Writer for GGUF (https://github.com/ggerganov/ggml/blob/master/docs/gguf.md), the file format
+// of llama.cpp. Models are written as its `gpt2` architecture, with the GGUF names of its tensors
+// and metadata keys, and their SentencePiece vocabulary as its `llama` tokenizer. Matrices of
+// linear layers are stored transposed (`[out, in]`), as expected by ggml.
+//
+// Only models built with `Architecture::Gpt2` map onto it: the default architecture normalizes
+// after the residual connections and has fixed positional encodings, which no architecture of
+// llama.cpp does.
+
+use super::*;
+use std::io::Write;
+
+const GGUF_MAGIC: &[u8; 4] = b"GGUF";
+const GGUF_VERSION: u32 = 3;
+const GGUF_ALIGNMENT: usize = 32;
+const GGML_TYPE_F32: u32 = 0;
+const LAYER_NORM_EPSILON: f32 = 1e-5;
+// Types of the tokens of the vocabulary
+const TOKEN_NORMAL: i32 = 1;
+const TOKEN_UNKNOWN: i32 = 2;
+
+enum Value<'a> {
+    Bool(bool),
+    U32(u32),
+    F32(f32),
+    Str(&'a str),
+    StrArray(&'a [String]),
+    F32Array(&'a [f32]),
+    I32Array(&'a [i32]),
+}
+
+// GGUF metadata value types
+const TYPE_U32: u32 = 4;
+const TYPE_BOOL: u32 = 7;
+const TYPE_I32: u32 = 5;
+const TYPE_F32: u32 = 6;
+const TYPE_STRING: u32 = 8;
+const TYPE_ARRAY: u32 = 9;
+
+fn write_str<W: Write>(out: &mut W, s: &str) -> std::io::Result<()> {
+    out.write_all(&(s.len() as u64).to_le_bytes())?;
+    out.write_all(s.as_bytes())
+}
+
+fn write_kv<W: Write>(out: &mut W, key: &str, value: &Value) -> std::io::Result<()> {
+    write_str(out, key)?;
+    match value {
+        Value::Bool(v) => {
+            out.write_all(&TYPE_BOOL.to_le_bytes())?;
+            out.write_all(&[*v as u8])?;
+        }
+        Value::U32(v) => {
+            out.write_all(&TYPE_U32.to_le_bytes())?;
+            out.write_all(&v.to_le_bytes())?;
+        }
+        Value::F32(v) => {
+            out.write_all(&TYPE_F32.to_le_bytes())?;
+            out.write_all(&v.to_le_bytes())?;
+        }
+        Value::Str(v) => {
+            out.write_all(&TYPE_STRING.to_le_bytes())?;
+            write_str(out, v)?;
+        }
+        Value::StrArray(vs) => {
+            out.write_all(&TYPE_ARRAY.to_le_bytes())?;
+            out.write_all(&TYPE_STRING.to_le_bytes())?;
+            out.write_all(&(vs.len() as u64).to_le_bytes())?;
+            for v in vs.iter() {
+                write_str(out, v)?;
+            }
+        }
+        Value::F32Array(vs) => {
+            out.write_all(&TYPE_ARRAY.to_le_bytes())?;
+            out.write_all(&TYPE_F32.to_le_bytes())?;
+            out.write_all(&(vs.len() as u64).to_le_bytes())?;
+            for v in vs.iter() {
+                out.write_all(&v.to_le_bytes())?;
+            }
+        }
+        Value::I32Array(vs) => {
+            out.write_all(&TYPE_ARRAY.to_le_bytes())?;
+            out.write_all(&TYPE_I32.to_le_bytes())?;
+            out.write_all(&(vs.len() as u64).to_le_bytes())?;
+            for v in vs.iter() {
+                out.write_all(&v.to_le_bytes())?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn padding(offset: usize) -> usize {
+    (GGUF_ALIGNMENT - offset % GGUF_ALIGNMENT) % GGUF_ALIGNMENT
+}
+
+/// Maps the tensors of a femto model built with `Architecture::Gpt2` to the ones of llama.cpp's
+/// GPT-2. The per-head key/query/value matrices of a layer are fused into a single `attn_qkv`
+/// matrix, queries first.
+///
+/// femto computes attention scores as `k * q^T`, so its `k` matrices play the role of the
+/// conventional queries, and its `q` matrices the one of the keys.
+pub fn gguf_tensors(state: &TrainingState) -> Result<Vec<(String, Tensor<f32>)>, ExportError> {
+    let shape = ModelShape::of(state)?;
+    if !state.tensors.contains_key("pos_embedding") {
+        return Err(ExportError::Unsupported(
+            "only models built with the `gpt2` architecture can be exported to GGUF".into(),
+        ));
+    }
+    if shape.num_heads * shape.head_size != shape.embedding_degree {
+        return Err(ExportError::Unsupported(format!(
+            "{} heads of size {} don't add up to the embedding degree {}",
+            shape.num_heads, shape.head_size, shape.embedding_degree
+        )));
+    }
+    // llama.cpp's GPT-2 has an unbiased output head, like GPT-2 itself
+    if get_tensor(state, "head_map_bias")?
+        .blob()
+        .iter()
+        .any(|b| *b != 0.)
+    {
+        return Err(ExportError::Unsupported(
+            "the output head has a bias, which llama.cpp's GPT-2 doesn't".into(),
+        ));
+    }
+    let get = |name: &str| get_tensor(state, name).cloned();
+    let linear = |name: &str| -> Result<Tensor<f32>, ExportError> {
+        Ok(get_tensor(state, name)?.transpose()?)
+    };
+
+    let mut tensors = vec![
+        ("token_embd.weight".to_string(), get("token_embedding")?),
+        ("position_embd.weight".to_string(), get("pos_embedding")?),
+    ];
+    for l in 0..shape.num_layers {
+        let blk = |name: &str| format!("blk.{}.{}", l, name);
+        tensors.push((blk("attn_norm.weight"), get(&format!("norm_{}_coeff", l))?));
+        tensors.push((blk("attn_norm.bias"), get(&format!("norm_{}_bias", l))?));
+        let mut qkv = Vec::new();
+        let mut qkv_bias = Vec::new();
+        for kind in ["k", "q", "v"] {
+            for h in 0..shape.num_heads {
+                let head = format!("head_{}_{}_{}", l, h, kind);
+                qkv.extend_from_slice(linear(&head)?.blob());
+                qkv_bias.extend_from_slice(get(&format!("{}_bias", head))?.blob());
+            }
+        }
+        tensors.push((
+            blk("attn_qkv.weight"),
+            Tensor::raw(&[3 * shape.embedding_degree, shape.embedding_degree], qkv)?,
+        ));
+        tensors.push((
+            blk("attn_qkv.bias"),
+            Tensor::raw(&[3 * shape.embedding_degree], qkv_bias)?,
+        ));
+        tensors.push((
+            blk("attn_output.weight"),
+            linear(&format!("proj_{}_weights", l))?,
+        ));
+        tensors.push((blk("attn_output.bias"), get(&format!("proj_{}_bias", l))?));
+        tensors.push((
+            blk("ffn_norm.weight"),
+            get(&format!("atten_norm_{}_coeff", l))?,
+        ));
+        tensors.push((
+            blk("ffn_norm.bias"),
+            get(&format!("atten_norm_{}_bias", l))?,
+        ));
+        tensors.push((
+            blk("ffn_up.weight"),
+            linear(&format!("feedforward1_{}_weights", l))?,
+        ));
+        tensors.push((
+            blk("ffn_up.bias"),
+            get(&format!("feedforward1_{}_bias", l))?,
+        ));
+        tensors.push((
+            blk("ffn_down.weight"),
+            linear(&format!("feedforward2_{}_weights", l))?,
+        ));
+        tensors.push((
+            blk("ffn_down.bias"),
+            get(&format!("feedforward2_{}_bias", l))?,
+        ));
+    }
+    tensors.push(("output_norm.weight".into(), get("head_norm_coeff")?));
+    tensors.push(("output_norm.bias".into(), get("head_norm_bias")?));
+    tensors.push(("output.weight".into(), linear("head_map_weights")?));
+    Ok(tensors)
+}
+
+/// Writes a model built with `Architecture::Gpt2`, along with its architecture and SentencePiece
+/// vocabulary, as a GGUF file llama.cpp can run.
+pub fn write_gguf<W: Write>(
+    out: &mut W,
+    state: &TrainingState,
+    vocab: &[String],
+    scores: &[f32],
+) -> Result<(), ExportError> {
+    let shape = ModelShape::of(state)?;
+    let tensors = gguf_tensors(state)?;
+    // Learned positional embeddings fix the context length
+    let num_tokens = get_tensor(state, "pos_embedding")?.shape()[0];
+    // femto's SentencePiece vocabularies have no special token but the unknown one, the first
+    let token_types = (0..vocab.len())
+        .map(|t| if t == 0 { TOKEN_UNKNOWN } else { TOKEN_NORMAL })
+        .collect::<Vec<_>>();
+
+    let metadata = [
+        ("general.architecture", Value::Str("gpt2")),
+        ("general.name", Value::Str("femtoGPT")),
+        ("general.alignment", Value::U32(GGUF_ALIGNMENT as u32)),
+        ("gpt2.context_length", Value::U32(num_tokens as u32)),
+        (
+            "gpt2.embedding_length",
+            Value::U32(shape.embedding_degree as u32),
+        ),
+        ("gpt2.block_count", Value::U32(shape.num_layers as u32)),
+        (
+            "gpt2.feed_forward_length",
+            Value::U32(4 * shape.embedding_degree as u32),
+        ),
+        (
+            "gpt2.attention.head_count",
+            Value::U32(shape.num_heads as u32),
+        ),
+        (
+            "gpt2.attention.layer_norm_epsilon",
+            Value::F32(LAYER_NORM_EPSILON),
+        ),
+        ("tokenizer.ggml.model", Value::Str("llama")),
+        ("tokenizer.ggml.tokens", Value::StrArray(vocab)),
+        ("tokenizer.ggml.scores", Value::F32Array(scores)),
+        ("tokenizer.ggml.token_type", Value::I32Array(&token_types)),
+        // Without beginning and end of text tokens, the unknown one stands for them, so that
+        // llama.cpp neither prepends nor stops at its default ones (Normal tokens 1 and 2)
+        ("tokenizer.ggml.unknown_token_id", Value::U32(0)),
+        ("tokenizer.ggml.bos_token_id", Value::U32(0)),
+        ("tokenizer.ggml.eos_token_id", Value::U32(0)),
+        ("tokenizer.ggml.add_bos_token", Value::Bool(false)),
+    ];
+
+    let mut header = Vec::new();
+    header.write_all(GGUF_MAGIC)?;
+    header.write_all(&GGUF_VERSION.to_le_bytes())?;
+    header.write_all(&(tensors.len() as u64).to_le_bytes())?;
+    header.write_all(&(metadata.len() as u64).to_le_bytes())?;
+    for (k, v) in metadata.iter() {
+        write_kv(&mut header, k, v)?;
+    }
+
+    // Tensor infos, dimensions are listed from the fastest-varying one (Reverse of femto's order)
+    let mut offset = 0;
+    for (name, t) in tensors.iter() {
+        write_str(&mut header, name)?;
+        header.write_all(&(t.dim() as u32).to_le_bytes())?;
+        for d in t.shape().iter().rev() {
+            header.write_all(&(*d as u64).to_le_bytes())?;
+        }
+        header.write_all(&GGML_TYPE_F32.to_le_bytes())?;
+        header.write_all(&(offset as u64).to_le_bytes())?;
+        offset += t.size() * 4;
+        offset += padding(offset);
+    }
+    // Tensor data starts at an aligned offset of the file
+    out.write_all(&header)?;
+    out.write_all(&vec![0u8; padding(header.len())])?;
+
+    for (_, t) in tensors.iter() {
+        let bytes = t
+            .blob()
+            .iter()
+            .flat_map(|f| f.to_le_bytes())
+            .collect::<Vec<_>>();
+        out.write_all(&bytes)?;
+        out.write_all(&vec![0u8; padding(bytes.len())])?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gpt::{Architecture, GptBuilder};
+    use crate::graph::CpuGraph;
+
+    fn state(architecture: Architecture) -> TrainingState {
+        GptBuilder::new()
+            .architecture(architecture)
+            .vocab_size(3)
+            .embedding_degree(8)
+            .context(4)
+            .layers(2)
+            .heads(2)
+            .build(CpuGraph::new())
+            .unwrap()
+            .get_training_state()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_gguf_tensors() {
+        assert!(matches!(
+            gguf_tensors(&state(Architecture::Femto)),
+            Err(ExportError::Unsupported(_))
+        ));
+
+        let state = state(Architecture::Gpt2);
+        let tensors = gguf_tensors(&state).unwrap();
+        // Embeddings, 12 tensors per layer, the output normalization and head
+        assert_eq!(tensors.len(), 2 + 2 * 12 + 3);
+        let qkv = &tensors
+            .iter()
+            .find(|(n, _)| n == "blk.1.attn_qkv.weight")
+            .unwrap()
+            .1;
+        assert_eq!(qkv.shape(), &[24, 8]);
+        // The first rows are the queries of the first head, femto's `k`
+        let k = get_tensor(&state, "head_1_0_k")
+            .unwrap()
+            .transpose()
+            .unwrap();
+        assert_eq!(&qkv.blob()[..k.size()], k.blob());
+
+        let vocab = ["<unk>", "a", "b"].map(String::from);
+        let mut bytes = Vec::new();
+        write_gguf(&mut bytes, &state, &vocab, &[0.; 3]).unwrap();
+        assert_eq!(&bytes[..4], GGUF_MAGIC);
+        assert_eq!(bytes.len() % GGUF_ALIGNMENT, 0);
+    }
+}
@@ -0,0 +1,195 @@
+// Converter from OpenAI's GPT-2 checkpoints to femto models built with `Architecture::Gpt2`.
+// Both the Hugging Face naming (`h.0.attn.c_attn.weight`, optionally prefixed by `transformer.`)
+// and the naming of the original TensorFlow release (`model/h0/attn/c_attn/w`) are accepted.
+//
+// GPT-2 stores linear layers as `[in, out]` matrices, just like femto, so only the fused
+// query/key/value projections need to be split per head. Note that femto computes the attention
+// scores as `k * q^T`, softmaxing over the columns, so GPT-2's queries become femto's `k` and its
+// keys become femto's `q`.
+
+use super::*;
+use std::collections::HashMap;
+
+// Renames a tensor of the TensorFlow checkpoint to its Hugging Face counterpart
+fn hf_name(name: &str) -> String {
+    let name = name.strip_prefix("transformer.").unwrap_or(name);
+    let Some(name) = name.strip_prefix("model/") else {
+        return name.to_string();
+    };
+    let mut parts = name
+        .split('/')
+        .map(|part| match part {
+            "w" | "g" => "weight".to_string(),
+            "b" => "bias".to_string(),
+            _ => match part.strip_prefix('h') {
+                Some(l) if !l.is_empty() && l.chars().all(|c| c.is_ascii_digit()) => {
+                    format!("h.{}", l)
+                }
+                _ => part.to_string(),
+            },
+        })
+        .collect::<Vec<_>>();
+    // Embeddings are stored without a `/w` suffix
+    if parts.len() == 1 {
+        parts.push("weight".into());
+    }
+    parts.join(".")
+}
+
+// Drops the leading unit dimensions the TensorFlow checkpoint keeps on its matrices
+fn squeeze(t: Tensor<f32>) -> Result<Tensor<f32>, TensorError> {
+    let shape = t.shape().to_vec();
+    let first = shape
+        .iter()
+        .position(|d| *d != 1)
+        .unwrap_or(shape.len() - 1);
+    if first == 0 {
+        Ok(t)
+    } else {
+        Tensor::raw(&shape[first..], t.blob().to_vec())
+    }
+}
+
+// Takes `len` columns of a `[rows, cols]` matrix (or `len` values of a vector) starting at `start`
+fn columns(t: &Tensor<f32>, start: usize, len: usize) -> Result<Tensor<f32>, TensorError> {
+    let cols = t.shape()[t.dim() - 1];
+    let rows = t.size() / cols;
+    let blob = t
+        .blob()
+        .chunks(cols)
+        .flat_map(|row| row[start..start + len].iter().cloned())
+        .collect::<Vec<_>>();
+    let shape = if t.dim() == 1 {
+        vec![len]
+    } else {
+        vec![rows, len]
+    };
+    Tensor::raw(&shape, blob)
+}
+
+/// Maps the tensors of a GPT-2 checkpoint onto a femto model built with `Architecture::Gpt2`,
+/// `num_heads` heads and a context of `num_tokens` tokens (At most 1024, the size of GPT-2's
+/// positional embeddings). The output head shares the token embeddings, as in GPT-2.
+pub fn gpt2_training_state(
+    tensors: HashMap<String, Tensor<f32>>,
+    num_heads: usize,
+    num_tokens: usize,
+) -> Result<TrainingState, ExportError> {
+    let tensors = tensors
+        .into_iter()
+        .map(|(name, t)| Ok((hf_name(&name), squeeze(t)?)))
+        .collect::<Result<HashMap<_, _>, ExportError>>()?;
+    let get = |name: &str| -> Result<&Tensor<f32>, ExportError> {
+        tensors
+            .get(name)
+            .ok_or_else(|| ExportError::TensorNotFound(name.into()))
+    };
+
+    let wte = get("wte.weight")?;
+    let wpe = get("wpe.weight")?;
+    let embedding_degree = wte.shape()[1];
+    if embedding_degree % num_heads != 0 {
+        return Err(ExportError::InvalidFormat(format!(
+            "embedding degree {} is not divisible by {} heads",
+            embedding_degree, num_heads
+        )));
+    }
+    if num_tokens > wpe.shape()[0] {
+        return Err(ExportError::InvalidFormat(format!(
+            "context of {} tokens is longer than the {} positional embeddings",
+            num_tokens,
+            wpe.shape()[0]
+        )));
+    }
+    let head_size = embedding_degree / num_heads;
+    let num_layers = (0..)
+        .take_while(|l| tensors.contains_key(&format!("h.{}.ln_1.weight", l)))
+        .count();
+
+    let mut out = HashMap::new();
+    out.insert("token_embedding".to_string(), wte.clone());
+    out.insert(
+        "pos_embedding".to_string(),
+        Tensor::raw(
+            &[num_tokens, embedding_degree],
+            wpe.blob()[..num_tokens * embedding_degree].to_vec(),
+        )?,
+    );
+    for l in 0..num_layers {
+        let h = |name: &str| get(&format!("h.{}.{}", l, name));
+        out.insert(format!("norm_{}_coeff", l), h("ln_1.weight")?.clone());
+        out.insert(format!("norm_{}_bias", l), h("ln_1.bias")?.clone());
+
+        let c_attn = h("attn.c_attn.weight")?;
+        let c_attn_bias = h("attn.c_attn.bias")?;
+        // Columns of `c_attn` are GPT-2's queries, keys and values, in this order
+        for (i, kind) in ["k", "q", "v"].into_iter().enumerate() {
+            for head in 0..num_heads {
+                let start = i * embedding_degree + head * head_size;
+                out.insert(
+                    format!("head_{}_{}_{}", l, head, kind),
+                    columns(c_attn, start, head_size)?,
+                );
+                out.insert(
+                    format!("head_{}_{}_{}_bias", l, head, kind),
+                    columns(c_attn_bias, start, head_size)?,
+                );
+            }
+        }
+
+        out.insert(
+            format!("proj_{}_weights", l),
+            h("attn.c_proj.weight")?.clone(),
+        );
+        out.insert(format!("proj_{}_bias", l), h("attn.c_proj.bias")?.clone());
+        out.insert(format!("atten_norm_{}_coeff", l), h("ln_2.weight")?.clone());
+        out.insert(format!("atten_norm_{}_bias", l), h("ln_2.bias")?.clone());
+        out.insert(
+            format!("feedforward1_{}_weights", l),
+            h("mlp.c_fc.weight")?.clone(),
+        );
+        out.insert(
+            format!("feedforward1_{}_bias", l),
+            h("mlp.c_fc.bias")?.clone(),
+        );
+        out.insert(
+            format!("feedforward2_{}_weights", l),
+            h("mlp.c_proj.weight")?.clone(),
+        );
+        out.insert(
+            format!("feedforward2_{}_bias", l),
+            h("mlp.c_proj.bias")?.clone(),
+        );
+    }
+    out.insert("head_norm_coeff".to_string(), get("ln_f.weight")?.clone());
+    out.insert("head_norm_bias".to_string(), get("ln_f.bias")?.clone());
+    out.insert("head_map_weights".to_string(), wte.transpose()?);
+    out.insert(
+        "head_map_bias".to_string(),
+        Tensor::zeros(&[wte.shape()[0]]),
+    );
+
+    Ok(TrainingState {
+        tensors: out,
+        optimizer: Default::default(),
+        sampler: None,
+        progress: Default::default(),
+        provenance: Default::default(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hf_name() {
+        assert_eq!(hf_name("model/wte"), "wte.weight");
+        assert_eq!(
+            hf_name("model/h11/attn/c_attn/w"),
+            "h.11.attn.c_attn.weight"
+        );
+        assert_eq!(hf_name("model/ln_f/g"), "ln_f.weight");
+        assert_eq!(hf_name("transformer.h.0.ln_1.bias"), "h.0.ln_1.bias");
+    }
+}
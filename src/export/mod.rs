@@ -0,0 +1,80 @@
+mod gguf;
+mod gpt2;
+mod npz;
+mod safetensors;
+pub use gguf::*;
+pub use gpt2::*;
+pub use npz::*;
+pub use safetensors::*;
+
+use crate::gpt::TrainingState;
+use crate::tensor::{Tensor, TensorError, TensorOps};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum ExportError {
+    #[error("io error: {0}")]
+    IoError(#[from] std::io::Error),
+    #[error("tensor error: {0}")]
+    TensorError(#[from] TensorError),
+    #[error("tensor {0} not found in the model")]
+    TensorNotFound(String),
+    #[error("invalid file: {0}")]
+    InvalidFormat(String),
+    #[error("model can't be exported: {0}")]
+    Unsupported(String),
+}
+
+/// File formats trained models can be exported to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    Gguf,
+    Safetensors,
+}
+
+impl std::str::FromStr for ExportFormat {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "gguf" => Ok(ExportFormat::Gguf),
+            "safetensors" => Ok(ExportFormat::Safetensors),
+            _ => Err(format!("expected `gguf` or `safetensors`, got `{}`", s)),
+        }
+    }
+}
+
+fn get_tensor<'a>(state: &'a TrainingState, name: &str) -> Result<&'a Tensor<f32>, ExportError> {
+    state
+        .tensors
+        .get(name)
+        .ok_or_else(|| ExportError::TensorNotFound(name.into()))
+}
+
+/// Architecture of a model, recovered from the names and shapes of its tensors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ModelShape {
+    pub vocab_size: usize,
+    pub embedding_degree: usize,
+    pub num_layers: usize,
+    pub num_heads: usize,
+    pub head_size: usize,
+}
+
+impl ModelShape {
+    pub fn of(state: &TrainingState) -> Result<Self, ExportError> {
+        let embedding = get_tensor(state, "token_embedding")?;
+        let num_layers = (0..)
+            .take_while(|l| state.tensors.contains_key(&format!("norm_{}_coeff", l)))
+            .count();
+        let num_heads = (0..)
+            .take_while(|h| state.tensors.contains_key(&format!("head_0_{}_k", h)))
+            .count();
+        Ok(Self {
+            vocab_size: embedding.shape()[0],
+            embedding_degree: embedding.shape()[1],
+            num_layers,
+            num_heads,
+            head_size: get_tensor(state, "head_0_0_k")?.shape()[1],
+        })
+    }
+}
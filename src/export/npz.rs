@@ -0,0 +1,156 @@
+// Reader for NumPy's npz archives (https://numpy.org/doc/stable/reference/generated/numpy.savez.html):
+// a zip file holding one `.npy` file per array, each with a small Python-literal header giving
+// the dtype and the shape of the array. Single arrays can be written as `.npy` files.
+
+use super::*;
+use half::f16;
+use std::collections::HashMap;
+use std::io::{Cursor, Read, Write};
+
+const NPY_MAGIC: &[u8; 6] = b"\x93NUMPY";
+
+// Extracts the raw text of a field from a header like
+// `{'descr': '<f4', 'fortran_order': False, 'shape': (768, 2304), }`
+fn header_field<'a>(header: &'a str, key: &str) -> Option<&'a str> {
+    let start = header.find(&format!("'{}':", key))? + key.len() + 3;
+    let value = header[start..].trim_start();
+    let end = if value.starts_with('(') {
+        value.find(')')? + 1
+    } else {
+        value.find(',').unwrap_or(value.len())
+    };
+    Some(value[..end].trim())
+}
+
+fn read_npy(name: &str, bytes: &[u8]) -> Result<Tensor<f32>, ExportError> {
+    let invalid = |msg: &str| ExportError::InvalidFormat(format!("{}: {}", name, msg));
+    if bytes.len() < 10 || &bytes[..6] != NPY_MAGIC {
+        return Err(invalid("not a npy file"));
+    }
+    // Version 1 uses a 2-byte header length, later versions a 4-byte one
+    let (header_len, header_start) = if bytes[6] == 1 {
+        (u16::from_le_bytes([bytes[8], bytes[9]]) as usize, 10)
+    } else {
+        if bytes.len() < 12 {
+            return Err(invalid("file is too small"));
+        }
+        (
+            u32::from_le_bytes(bytes[8..12].try_into().unwrap()) as usize,
+            12,
+        )
+    };
+    let data_start = header_start + header_len;
+    if data_start > bytes.len() {
+        return Err(invalid("header is out of bounds"));
+    }
+    let header = std::str::from_utf8(&bytes[header_start..data_start])
+        .map_err(|_| invalid("header is not utf-8"))?;
+
+    if header_field(header, "fortran_order") != Some("False") {
+        return Err(invalid("fortran-ordered arrays are not supported"));
+    }
+    let shape = header_field(header, "shape")
+        .ok_or_else(|| invalid("missing shape"))?
+        .trim_matches(|c| c == '(' || c == ')')
+        .split(',')
+        .map(str::trim)
+        .filter(|d| !d.is_empty())
+        .map(|d| d.parse::<usize>().map_err(|_| invalid("invalid shape")))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let raw = &bytes[data_start..];
+    let blob: Vec<f32> = match header_field(header, "descr").map(|d| d.trim_matches('\'')) {
+        Some("<f8") => raw
+            .chunks_exact(8)
+            .map(|b| f64::from_le_bytes(b.try_into().unwrap()) as f32)
+            .collect(),
+        Some("<f4") => raw
+            .chunks_exact(4)
+            .map(|b| f32::from_le_bytes(b.try_into().unwrap()))
+            .collect(),
+        Some("<f2") => raw
+            .chunks_exact(2)
+            .map(|b| f16::from_le_bytes(b.try_into().unwrap()).to_f32())
+            .collect(),
+        descr => {
+            return Err(invalid(&format!("unsupported dtype {:?}", descr)));
+        }
+    };
+    Ok(Tensor::raw(&shape, blob)?)
+}
+
+/// Writes `tensor` as a version 1 `.npy` file of little-endian f32 values, e.g. for
+/// `numpy.load`.
+pub fn write_npy<W: Write>(out: &mut W, tensor: &Tensor<f32>) -> Result<(), ExportError> {
+    let shape = tensor
+        .shape()
+        .iter()
+        .map(|d| format!("{},", d))
+        .collect::<String>();
+    let mut header = format!(
+        "{{'descr': '<f4', 'fortran_order': False, 'shape': ({}), }}",
+        shape
+    );
+    // The data is aligned to 64 bytes, the header ends with a newline
+    let unpadded = NPY_MAGIC.len() + 4 + header.len() + 1;
+    header.push_str(&" ".repeat((64 - unpadded % 64) % 64));
+    header.push('\n');
+    out.write_all(NPY_MAGIC)?;
+    out.write_all(&[1, 0])?;
+    out.write_all(&(header.len() as u16).to_le_bytes())?;
+    out.write_all(header.as_bytes())?;
+    for v in tensor.blob() {
+        out.write_all(&v.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+/// Reads the floating-point (f64, f32 and f16) arrays of a npz archive as f32 tensors, named
+/// after their entries without the `.npy` extension.
+pub fn read_npz(bytes: &[u8]) -> Result<HashMap<String, Tensor<f32>>, ExportError> {
+    let mut archive = zip::ZipArchive::new(Cursor::new(bytes))
+        .map_err(|e| ExportError::InvalidFormat(e.to_string()))?;
+    let mut tensors = HashMap::new();
+    for i in 0..archive.len() {
+        let mut file = archive
+            .by_index(i)
+            .map_err(|e| ExportError::InvalidFormat(e.to_string()))?;
+        let name = file.name().trim_end_matches(".npy").to_string();
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)?;
+        let tensor = read_npy(&name, &data)?;
+        tensors.insert(name, tensor);
+    }
+    Ok(tensors)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_npy() {
+        let header = "{'descr': '<f4', 'fortran_order': False, 'shape': (2, 3), }";
+        let mut bytes = NPY_MAGIC.to_vec();
+        bytes.extend_from_slice(&[1, 0]);
+        bytes.extend_from_slice(&(header.len() as u16).to_le_bytes());
+        bytes.extend_from_slice(header.as_bytes());
+        for v in 0..6 {
+            bytes.extend_from_slice(&(v as f32).to_le_bytes());
+        }
+        let t = read_npy("t", &bytes).unwrap();
+        assert_eq!(t.shape(), &[2, 3]);
+        assert_eq!(t.blob(), &[0., 1., 2., 3., 4., 5.]);
+    }
+
+    #[test]
+    fn test_write_npy() {
+        let t = Tensor::raw(&[2, 1, 3], vec![0., 1., 2., 3., 4., 5.]).unwrap();
+        let mut bytes = Vec::new();
+        write_npy(&mut bytes, &t).unwrap();
+        assert_eq!((bytes.len() - 6 * 4) % 64, 0);
+        let read = read_npy("t", &bytes).unwrap();
+        assert_eq!(read.shape(), t.shape());
+        assert_eq!(read.blob(), t.blob());
+    }
+}
@@ -0,0 +1,134 @@
+// Reader/writer for safetensors (https://github.com/huggingface/safetensors): an 8-byte header
+// length, a JSON header describing the dtype, shape and byte range of every tensor, followed by
+// the raw little-endian tensor data.
+
+use super::*;
+use half::{bf16, f16};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::io::Write;
+
+#[derive(Serialize, Deserialize)]
+struct TensorInfo {
+    dtype: String,
+    shape: Vec<usize>,
+    data_offsets: [usize; 2],
+}
+
+/// Writes f32 tensors, under their femto names, as a safetensors file.
+pub fn write_safetensors<W: Write>(
+    out: &mut W,
+    tensors: &HashMap<String, Tensor<f32>>,
+    metadata: &HashMap<String, String>,
+) -> Result<(), ExportError> {
+    // Sorted, so that exporting the same model twice gives identical files
+    let tensors = tensors.iter().collect::<BTreeMap<_, _>>();
+
+    let mut header = serde_json::Map::new();
+    if !metadata.is_empty() {
+        header.insert(
+            "__metadata__".into(),
+            serde_json::to_value(metadata).unwrap(),
+        );
+    }
+    let mut offset = 0;
+    for (name, t) in tensors.iter() {
+        let info = TensorInfo {
+            dtype: "F32".into(),
+            shape: t.shape().to_vec(),
+            data_offsets: [offset, offset + t.size() * 4],
+        };
+        offset += t.size() * 4;
+        header.insert(name.to_string(), serde_json::to_value(info).unwrap());
+    }
+    let mut header = serde_json::to_vec(&header).unwrap();
+    // The data section is kept 8-byte aligned by padding the header with spaces
+    header.resize(header.len() + (8 - header.len() % 8) % 8, b' ');
+
+    out.write_all(&(header.len() as u64).to_le_bytes())?;
+    out.write_all(&header)?;
+    for t in tensors.values() {
+        let bytes = t
+            .blob()
+            .iter()
+            .flat_map(|f| f.to_le_bytes())
+            .collect::<Vec<_>>();
+        out.write_all(&bytes)?;
+    }
+    Ok(())
+}
+
+/// Reads the tensors of a safetensors file, converting floating-point ones (F64, F32, F16 and
+/// BF16) to f32.
+pub fn read_safetensors(bytes: &[u8]) -> Result<HashMap<String, Tensor<f32>>, ExportError> {
+    let invalid = |msg: &str| ExportError::InvalidFormat(msg.into());
+    if bytes.len() < 8 {
+        return Err(invalid("file is too small"));
+    }
+    let header_len = u64::from_le_bytes(bytes[..8].try_into().unwrap()) as usize;
+    let data_start = 8usize
+        .checked_add(header_len)
+        .filter(|end| *end <= bytes.len())
+        .ok_or_else(|| invalid("header is out of bounds"))?;
+    let header: HashMap<String, serde_json::Value> =
+        serde_json::from_slice(&bytes[8..data_start]).map_err(|e| invalid(&e.to_string()))?;
+    let data = &bytes[data_start..];
+
+    let mut tensors = HashMap::new();
+    for (name, info) in header {
+        if name == "__metadata__" {
+            continue;
+        }
+        let info: TensorInfo = serde_json::from_value(info).map_err(|e| invalid(&e.to_string()))?;
+        let [begin, end] = info.data_offsets;
+        if begin > end || end > data.len() {
+            return Err(invalid(&format!("data of {} is out of bounds", name)));
+        }
+        let raw = &data[begin..end];
+        let blob: Vec<f32> = match info.dtype.as_str() {
+            "F64" => raw
+                .chunks_exact(8)
+                .map(|b| f64::from_le_bytes(b.try_into().unwrap()) as f32)
+                .collect(),
+            "F32" => raw
+                .chunks_exact(4)
+                .map(|b| f32::from_le_bytes(b.try_into().unwrap()))
+                .collect(),
+            "F16" => raw
+                .chunks_exact(2)
+                .map(|b| f16::from_le_bytes(b.try_into().unwrap()).to_f32())
+                .collect(),
+            "BF16" => raw
+                .chunks_exact(2)
+                .map(|b| bf16::from_le_bytes(b.try_into().unwrap()).to_f32())
+                .collect(),
+            dtype => {
+                return Err(invalid(&format!("unsupported dtype {} of {}", dtype, name)));
+            }
+        };
+        tensors.insert(name, Tensor::raw(&info.shape, blob)?);
+    }
+    Ok(tensors)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_safetensors_roundtrip() {
+        let mut rng = rand::thread_rng();
+        let mut tensors = HashMap::new();
+        tensors.insert("a".to_string(), Tensor::<f32>::rand(&mut rng, &[3, 5]));
+        tensors.insert("b".to_string(), Tensor::<f32>::rand(&mut rng, &[7]));
+        let mut bytes = Vec::new();
+        write_safetensors(&mut bytes, &tensors, &HashMap::new()).unwrap();
+        assert_eq!(u64::from_le_bytes(bytes[..8].try_into().unwrap()) % 8, 0);
+        let read = read_safetensors(&bytes).unwrap();
+        assert_eq!(read.len(), 2);
+        for (name, t) in tensors.iter() {
+            assert_eq!(read[name].shape(), t.shape());
+            assert_eq!(read[name].blob(), t.blob());
+        }
+    }
+}
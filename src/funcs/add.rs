@@ -1,4 +1,4 @@
-use super::Function;
+use super::{Elementwise, Function};
 use crate::tensor::*;
 
 #[cfg(feature = "gpu")]
@@ -26,12 +26,15 @@ impl Function for Add {
     ) -> Result<Vec<Tensor<f32>>, TensorError> {
         Ok(vec![out_grad.clone(), out_grad.clone()])
     }
+    fn elementwise(&self) -> Option<Elementwise> {
+        Some(Elementwise::Add)
+    }
     fn clone_box(&self) -> Box<dyn Function> {
         Box::new(self.clone())
     }
 
     #[cfg(feature = "gpu")]
-    fn gpu_impl(&self, out_id: TensorId, inps: &[Vec<usize>]) -> GpuFunction {
-        gpu::add::gpu_impl(out_id, inps)
+    fn gpu_impl(&self, out_id: TensorId, inps: &[Vec<usize>]) -> Option<GpuFunction> {
+        Some(gpu::add::gpu_impl(out_id, inps))
     }
 }
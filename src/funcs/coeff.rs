@@ -1,4 +1,4 @@
-use super::Function;
+use super::{Elementwise, Function};
 use crate::tensor::*;
 
 #[cfg(feature = "gpu")]
@@ -28,12 +28,15 @@ impl Function for Coeff {
     ) -> Result<Vec<Tensor<f32>>, TensorError> {
         Ok(vec![out_grad.map_values(|d| d * self.coeff)])
     }
+    fn elementwise(&self) -> Option<Elementwise> {
+        Some(Elementwise::Coeff(self.coeff))
+    }
     fn clone_box(&self) -> Box<dyn Function> {
         Box::new(self.clone())
     }
 
     #[cfg(feature = "gpu")]
-    fn gpu_impl(&self, out_id: TensorId, inps: &[Vec<usize>]) -> GpuFunction {
-        gpu::coeff::gpu_impl(out_id, inps, self.coeff)
+    fn gpu_impl(&self, out_id: TensorId, inps: &[Vec<usize>]) -> Option<GpuFunction> {
+        Some(gpu::coeff::gpu_impl(out_id, inps, self.coeff))
     }
 }
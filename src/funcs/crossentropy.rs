@@ -8,15 +8,61 @@ use std::sync::Arc;
 
 #[derive(Debug, Clone)]
 pub struct CrossEntropy {
-    exp_output: Arc<Tensor<f32>>,
+    // Logarithm of the softmax normalizer of each row, the probabilities are recomputed from it
+    log_z: Arc<Tensor<f32>>,
+    label_smoothing: f32,
+    z_loss: f32,
 }
 impl CrossEntropy {
-    pub fn new() -> Box<dyn Function> {
+    // With label smoothing, the target distribution becomes `(1 - eps) * onehot + eps / classes`
+    // The z-loss adds `z_loss * log(Z)^2` (Z being the softmax normalizer) which keeps the logits
+    // from drifting away from zero.
+    // Positions whose target is out of the vocabulary (See `sampler::IGNORED_TARGET`) have a zero
+    // loss and gradient.
+    // An optional third input, of the shape of the targets, weights the loss of every position.
+    pub fn new(label_smoothing: f32, z_loss: f32) -> Box<dyn Function> {
         Box::new(Self {
-            exp_output: Arc::new(Tensor::scalar(0.)),
+            log_z: Arc::new(Tensor::scalar(0.)),
+            label_smoothing,
+            z_loss,
         })
     }
+
+    // Unweighted loss of a row whose target is `t`
+    fn row_loss(&self, o: &[f32], t: usize, log_z: f32) -> f32 {
+        let (eps, z_loss) = (self.label_smoothing, self.z_loss);
+        if t >= o.len() {
+            return 0.;
+        }
+        let mut loss = log_z - (1. - eps) * o[t];
+        if eps > 0. {
+            loss -= eps / o.len() as f32 * o.iter().sum::<f32>();
+        }
+        if z_loss > 0. {
+            loss += z_loss * log_z * log_z;
+        }
+        loss
+    }
+
+    // The per-position weights of the loss, if given
+    fn weights<'a>(
+        inps: &[&'a GeneralTensor],
+        target: &Tensor<usize>,
+    ) -> Result<Option<&'a Tensor<f32>>, TensorError> {
+        let weights = inps.get(2).map(|w| w.as_float()).transpose()?;
+        if weights.is_some_and(|w| w.shape() != target.shape()) {
+            return Err(TensorError::UnexpectedShape);
+        }
+        Ok(weights)
+    }
+}
+
+// `log(sum(exp(o)))`, shifted by the largest logit so that the exponentials can't overflow
+fn log_sum_exp(o: &[f32]) -> f32 {
+    let max = o.iter().fold(f32::NEG_INFINITY, |a, b| a.max(*b));
+    max + o.iter().map(|f| (f - max).exp()).sum::<f32>().ln()
 }
+
 impl Function for CrossEntropy {
     fn run(
         &mut self,
@@ -25,20 +71,24 @@ impl Function for CrossEntropy {
     ) -> Result<Tensor<f32>, TensorError> {
         let inp = inps[0].as_float()?;
         let target = inps[1].as_usize()?;
+        let weights = Self::weights(inps, target)?;
 
-        self.exp_output = Arc::new(inp.map(1, |o| Ok(o.map_values(|f| f.exp())))?);
+        let rows = inp.keep_right(1)?;
+        let rows = rows.inners();
+        self.log_z = Arc::new(Tensor::raw(
+            target.shape(),
+            rows.iter().map(|o| log_sum_exp(o.blob())).collect(),
+        )?);
 
         Tensor::raw(
             target.shape(),
-            inp.keep_right(1)?
-                .inners()
-                .iter()
+            rows.iter()
                 .zip(target.blob().iter())
-                .zip(self.exp_output.keep_right(1)?.inners().iter())
-                .map(|((o, t), o_exps)| {
-                    let sum = o_exps.blob().iter().sum::<f32>();
-                    let loss = sum.ln() - o.blob()[*t];
-                    loss
+                .zip(self.log_z.blob().iter())
+                .enumerate()
+                .map(|(i, ((o, t), log_z))| {
+                    let loss = self.row_loss(o.blob(), *t, *log_z);
+                    weights.map_or(loss, |w| loss * w.blob()[i])
                 })
                 .collect(),
         )
@@ -50,45 +100,156 @@ impl Function for CrossEntropy {
     ) -> Result<Vec<Tensor<f32>>, TensorError> {
         let inp = inps[0].as_float()?;
         let target = inps[1].as_usize()?;
+        let weights = Self::weights(inps, target)?;
 
-        let classes = inp.shape()[inp.dim() - 1];
+        let eps = self.label_smoothing;
+        let smooth = eps / inp.shape()[inp.dim() - 1] as f32;
+        let z_loss = self.z_loss;
+        let rows = inp.keep_right(1)?;
+        let rows = rows.inners();
 
-        Ok(vec![Tensor::raw(
+        // The softmax probabilities minus the target distribution, the z-loss scales the former
+        let mut grads = vec![Tensor::raw(
             inp.shape(),
-            self.exp_output
-                .keep_right(1)?
-                .inners()
-                .iter()
+            rows.iter()
                 .zip(target.blob().iter())
+                .zip(self.log_z.blob().iter())
                 .zip(out_grad.blob().iter())
-                .map(|((o_exps, t), g)| {
-                    let o_exps = o_exps.blob();
-                    let sum = o_exps.iter().sum::<f32>();
-                    let sum_inv = 1. / sum;
-
-                    let grad = (0..classes)
-                        .map(|c| {
-                            let val = o_exps[c];
+                .enumerate()
+                .flat_map(|(i, (((o, t), log_z), g))| {
+                    let z_coeff = 1. + 2. * z_loss * log_z;
+                    let g = if *t < o.size() { *g } else { 0. };
+                    let g = weights.map_or(g, |w| g * w.blob()[i]);
+                    o.blob()
+                        .iter()
+                        .enumerate()
+                        .map(|(c, o)| {
+                            let p = (o - log_z).exp() * z_coeff;
                             (if *t == c {
-                                val * sum_inv - 1.0
+                                p - (1.0 - eps) - smooth
                             } else {
-                                val * sum_inv
+                                p - smooth
                             }) * g
                         })
-                        .collect::<Vec<_>>();
-
-                    grad
+                        .collect::<Vec<_>>()
                 })
-                .flatten()
                 .collect(),
-        )?])
+        )?];
+        grads.push(Tensor::scalar(0.));
+        // The weights get the unweighted loss of their position
+        if weights.is_some() {
+            grads.push(Tensor::raw(
+                target.shape(),
+                rows.iter()
+                    .zip(target.blob().iter())
+                    .zip(self.log_z.blob().iter())
+                    .zip(out_grad.blob().iter())
+                    .map(|(((o, t), log_z), g)| self.row_loss(o.blob(), *t, *log_z) * g)
+                    .collect(),
+            )?);
+        }
+        Ok(grads)
     }
     fn clone_box(&self) -> Box<dyn Function> {
         Box::new(self.clone())
     }
 
     #[cfg(feature = "gpu")]
-    fn gpu_impl(&self, out_id: TensorId, inps: &[Vec<usize>]) -> GpuFunction {
-        gpu::crossentropy::gpu_impl(out_id, inps)
+    fn gpu_impl(&self, out_id: TensorId, inps: &[Vec<usize>]) -> Option<GpuFunction> {
+        Some(gpu::crossentropy::gpu_impl(
+            out_id,
+            inps,
+            self.label_smoothing,
+            self.z_loss,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_smoothed_z_loss_derivative() {
+        const EPSILON: f32 = 1e-2;
+
+        let logits = vec![0.3, -1.2, 0.8, 0.1, -0.4];
+        let target = GeneralTensor::Usize(Tensor::raw(&[1], vec![2]).unwrap());
+        let loss = |logits: &[f32]| {
+            let inp = GeneralTensor::Float(Tensor::raw(&[1, 5], logits.to_vec()).unwrap());
+            CrossEntropy::new(0.1, 0.01)
+                .run(&[&inp, &target], true)
+                .unwrap()
+                .blob()[0]
+        };
+
+        let mut f = CrossEntropy::new(0.1, 0.01);
+        let inp = GeneralTensor::Float(Tensor::raw(&[1, 5], logits.clone()).unwrap());
+        f.run(&[&inp, &target], true).unwrap();
+        let symbolic = f
+            .grad(&[&inp, &target], &Tensor::constant(&[1], 1.))
+            .unwrap();
+
+        for c in 0..logits.len() {
+            let mut plus = logits.clone();
+            let mut minus = logits.clone();
+            plus[c] += EPSILON;
+            minus[c] -= EPSILON;
+            let numeric = (loss(&plus) - loss(&minus)) / EPSILON / 2.;
+            assert!((numeric - symbolic[0].blob()[c]).abs() < 1e-3);
+        }
+    }
+
+    #[test]
+    fn test_ignored_target() {
+        let inp = GeneralTensor::Float(Tensor::raw(&[2, 3], vec![1., 2., 3., 1., 2., 3.]).unwrap());
+        let target = GeneralTensor::Usize(Tensor::raw(&[2], vec![0, usize::MAX]).unwrap());
+        let mut f = CrossEntropy::new(0.1, 0.01);
+        let loss = f.run(&[&inp, &target], true).unwrap();
+        assert!(loss.blob()[0] > 0.);
+        assert_eq!(loss.blob()[1], 0.);
+        let grad = f
+            .grad(&[&inp, &target], &Tensor::constant(&[2], 1.))
+            .unwrap();
+        assert_eq!(&grad[0].blob()[3..], &[0., 0., 0.]);
+    }
+
+    #[test]
+    fn test_weights() {
+        let inp = GeneralTensor::Float(Tensor::raw(&[2, 3], vec![1., 2., 3., 3., 2., 1.]).unwrap());
+        let target = GeneralTensor::Usize(Tensor::raw(&[2], vec![0, 1]).unwrap());
+        let weights = GeneralTensor::Float(Tensor::raw(&[2], vec![0.5, 0.]).unwrap());
+        let mut f = CrossEntropy::new(0., 0.);
+        let loss = f.run(&[&inp, &target], true).unwrap();
+        let weighted = f.run(&[&inp, &target, &weights], true).unwrap();
+        assert!((weighted.blob()[0] - 0.5 * loss.blob()[0]).abs() < 1e-6);
+        assert_eq!(weighted.blob()[1], 0.);
+        let grads = f
+            .grad(&[&inp, &target, &weights], &Tensor::constant(&[2], 1.))
+            .unwrap();
+        assert_eq!(&grads[0].blob()[3..], &[0., 0., 0.]);
+        assert_eq!(grads[2].blob(), loss.blob());
+    }
+
+    #[test]
+    fn test_large_logits() {
+        // exp(1000) overflows, the loss and gradient only depend on the differences
+        let inp = GeneralTensor::Float(Tensor::raw(&[1, 3], vec![1000., 999., 998.]).unwrap());
+        let shifted = GeneralTensor::Float(Tensor::raw(&[1, 3], vec![2., 1., 0.]).unwrap());
+        let target = GeneralTensor::Usize(Tensor::raw(&[1], vec![1]).unwrap());
+        let run = |inp: &GeneralTensor| {
+            let mut f = CrossEntropy::new(0.0, 0.0);
+            let loss = f.run(&[inp, &target], true).unwrap();
+            let grad = f
+                .grad(&[inp, &target], &Tensor::constant(&[1], 1.))
+                .unwrap();
+            (loss.blob()[0], grad[0].blob().to_vec())
+        };
+        let (loss, grad) = run(&inp);
+        let (expected_loss, expected_grad) = run(&shifted);
+        assert!((loss - expected_loss).abs() < 1e-3);
+        for (g, e) in grad.iter().zip(expected_grad.iter()) {
+            assert!((g - e).abs() < 1e-5);
+        }
     }
 }
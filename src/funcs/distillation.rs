@@ -0,0 +1,196 @@
+use super::Function;
+use crate::tensor::*;
+
+#[cfg(feature = "gpu")]
+use super::{gpu, GpuFunction, TensorId};
+
+/// Knowledge distillation loss. Inputs are the logits of the student, the expected tokens and
+/// the logits of the teacher, the loss of each row being
+/// `(1 - alpha) * CE + alpha * T^2 * KL(softmax(teacher / T) || softmax(student / T))`, CE being
+/// the cross-entropy of the student. Higher temperatures `T` soften the distributions, letting
+/// the student learn how the teacher ranks the unlikely tokens too (The `T^2` factor keeps the
+/// gradients of the KL term at the scale of the cross-entropy's). As with `CrossEntropy`,
+/// positions whose target is out of the vocabulary (See `sampler::IGNORED_TARGET`) have a zero
+/// loss and gradient.
+#[derive(Debug, Clone)]
+pub struct Distillation {
+    temperature: f32,
+    alpha: f32,
+}
+impl Distillation {
+    pub fn new(temperature: f32, alpha: f32) -> Box<dyn Function> {
+        Box::new(Self { temperature, alpha })
+    }
+}
+
+// `log(sum(exp(o / t)))`, shifted by the largest logit so that the exponentials can't overflow
+fn log_sum_exp(o: &[f32], t: f32) -> f32 {
+    let max = o.iter().fold(f32::NEG_INFINITY, |a, b| a.max(*b)) / t;
+    max + o.iter().map(|f| (f / t - max).exp()).sum::<f32>().ln()
+}
+
+// Terms of the loss of a row shared by the forward and backward passes
+struct Row {
+    // Softmax normalizers of the student's logits, and of both logits at the temperature
+    log_z: f32,
+    log_z_student: f32,
+    log_z_teacher: f32,
+    kl: f32,
+}
+
+impl Row {
+    fn new(student: &[f32], teacher: &[f32], t: f32) -> Self {
+        let log_z_student = log_sum_exp(student, t);
+        let log_z_teacher = log_sum_exp(teacher, t);
+        let kl = student
+            .iter()
+            .zip(teacher)
+            .map(|(o, s)| {
+                let log_p = s / t - log_z_teacher;
+                log_p.exp() * (log_p - (o / t - log_z_student))
+            })
+            .sum();
+        Self {
+            log_z: log_sum_exp(student, 1.),
+            log_z_student,
+            log_z_teacher,
+            kl,
+        }
+    }
+}
+
+impl Function for Distillation {
+    fn run(
+        &mut self,
+        inps: &[&GeneralTensor],
+        _training: bool,
+    ) -> Result<Tensor<f32>, TensorError> {
+        let inp = inps[0].as_float()?;
+        let target = inps[1].as_usize()?;
+        let teacher = inps[2].as_float()?;
+        if teacher.shape() != inp.shape() {
+            return Err(TensorError::UnexpectedShape);
+        }
+        let (t, alpha) = (self.temperature, self.alpha);
+        Tensor::raw(
+            target.shape(),
+            inp.keep_right(1)?
+                .inners()
+                .iter()
+                .zip(teacher.keep_right(1)?.inners().iter())
+                .zip(target.blob().iter())
+                .map(|((o, s), target)| {
+                    let (o, s) = (o.blob(), s.blob());
+                    if *target >= o.len() {
+                        return 0.;
+                    }
+                    let row = Row::new(o, s, t);
+                    (1. - alpha) * (row.log_z - o[*target]) + alpha * t * t * row.kl
+                })
+                .collect(),
+        )
+    }
+    fn grad(
+        &self,
+        inps: &[&GeneralTensor],
+        out_grad: &Tensor<f32>,
+    ) -> Result<Vec<Tensor<f32>>, TensorError> {
+        let inp = inps[0].as_float()?;
+        let target = inps[1].as_usize()?;
+        let teacher = inps[2].as_float()?;
+        let (t, alpha) = (self.temperature, self.alpha);
+
+        let mut student_grad = Vec::with_capacity(inp.size());
+        let mut teacher_grad = Vec::with_capacity(inp.size());
+        for (((o, s), target), g) in inp
+            .keep_right(1)?
+            .inners()
+            .iter()
+            .zip(teacher.keep_right(1)?.inners().iter())
+            .zip(target.blob().iter())
+            .zip(out_grad.blob().iter())
+        {
+            let (o, s) = (o.blob(), s.blob());
+            if *target >= o.len() {
+                student_grad.extend(std::iter::repeat_n(0., o.len()));
+                teacher_grad.extend(std::iter::repeat_n(0., o.len()));
+                continue;
+            }
+            let row = Row::new(o, s, t);
+            for (c, (o, s)) in o.iter().zip(s).enumerate() {
+                let log_p = s / t - row.log_z_teacher;
+                let log_q = o / t - row.log_z_student;
+                let p = log_p.exp();
+                let onehot = if c == *target { 1. } else { 0. };
+                student_grad.push(
+                    ((1. - alpha) * ((o - row.log_z).exp() - onehot)
+                        + alpha * t * (log_q.exp() - p))
+                        * g,
+                );
+                teacher_grad.push(alpha * t * p * (log_p - log_q - row.kl) * g);
+            }
+        }
+        Ok(vec![
+            Tensor::raw(inp.shape(), student_grad)?,
+            Tensor::scalar(0.),
+            Tensor::raw(teacher.shape(), teacher_grad)?,
+        ])
+    }
+    fn clone_box(&self) -> Box<dyn Function> {
+        Box::new(self.clone())
+    }
+
+    #[cfg(feature = "gpu")]
+    fn gpu_impl(&self, out_id: TensorId, inps: &[Vec<usize>]) -> Option<GpuFunction> {
+        Some(gpu::distillation::gpu_impl(
+            out_id,
+            inps,
+            self.temperature,
+            self.alpha,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::funcs::CrossEntropy;
+
+    #[test]
+    fn test_distillation() {
+        let logits =
+            GeneralTensor::Float(Tensor::raw(&[2, 3], vec![0.3, -1.2, 0.8, 2., 1., 0.]).unwrap());
+        let target = GeneralTensor::Usize(Tensor::raw(&[2], vec![2, 0]).unwrap());
+        let teacher =
+            GeneralTensor::Float(Tensor::raw(&[2, 3], vec![1., 0., 2., 0.5, 3., -1.]).unwrap());
+        let inps = [&logits, &target, &teacher];
+
+        // Without the KL term, it's the plain cross-entropy
+        let ce = CrossEntropy::new(0., 0.).run(&inps[..2], true).unwrap();
+        let loss = Distillation::new(2., 0.).run(&inps, true).unwrap();
+        for (l, ce) in loss.blob().iter().zip(ce.blob()) {
+            assert!((l - ce).abs() < 1e-6);
+        }
+
+        // A student matching its teacher has nothing to learn from it
+        let same = [&teacher, &target, &teacher];
+        let loss = Distillation::new(2., 1.).run(&same, true).unwrap();
+        assert!(loss.blob().iter().all(|l| l.abs() < 1e-5));
+        let grads = Distillation::new(2., 1.)
+            .grad(&same, &Tensor::constant(&[2], 1.))
+            .unwrap();
+        assert!(grads[0].blob().iter().all(|g| g.abs() < 1e-5));
+
+        // Ignored positions don't learn anything, from the targets or the teacher
+        let ignored = GeneralTensor::Usize(Tensor::raw(&[2], vec![2, usize::MAX]).unwrap());
+        let inps = [&logits, &ignored, &teacher];
+        let loss = Distillation::new(2., 0.5).run(&inps, true).unwrap();
+        assert!(loss.blob()[0] > 0.);
+        assert_eq!(loss.blob()[1], 0.);
+        let grads = Distillation::new(2., 0.5)
+            .grad(&inps, &Tensor::constant(&[2], 1.))
+            .unwrap();
+        assert!(grads[0].blob()[3..].iter().all(|g| *g == 0.));
+        assert!(grads[2].blob()[3..].iter().all(|g| *g == 0.));
+    }
+}
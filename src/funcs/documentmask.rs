@@ -0,0 +1,128 @@
+use super::Function;
+use crate::tensor::*;
+
+#[cfg(feature = "gpu")]
+use super::{gpu, GpuFunction, TensorId};
+
+/// A `TrilMask` that also masks the scores between tokens of different documents. The second
+/// input gives the document index of each of the `n` tokens of every sequence (See
+/// `sampler::document_indices`).
+#[derive(Debug, Clone)]
+pub struct DocumentMask {
+    n: usize,
+    causal: bool,
+}
+impl DocumentMask {
+    pub fn new(n: usize) -> Box<dyn Function> {
+        Box::new(Self { n, causal: true })
+    }
+    /// Only masks the scores between tokens of different documents, tokens attend to the later
+    /// ones of their document too.
+    pub fn bidirectional(n: usize) -> Box<dyn Function> {
+        Box::new(Self { n, causal: false })
+    }
+
+    // Whether token `i` may attend to token `j`, `docs` being the documents of a sequence
+    fn visible(&self, docs: &[usize], i: usize, j: usize) -> bool {
+        (j <= i || !self.causal) && docs[i] == docs[j]
+    }
+}
+
+impl Function for DocumentMask {
+    fn run(
+        &mut self,
+        inps: &[&GeneralTensor],
+        _training: bool,
+    ) -> Result<Tensor<f32>, TensorError> {
+        let inp = inps[0].as_float()?;
+        let docs = inps[1].as_usize()?;
+        if inp.size() != docs.size() * self.n {
+            return Err(TensorError::UnexpectedShape);
+        }
+        let mut dat = Vec::with_capacity(inp.size());
+        for (t, docs) in inp
+            .blob()
+            .chunks(self.n * self.n)
+            .zip(docs.blob().chunks(self.n))
+        {
+            for i in 0..self.n {
+                for j in 0..self.n {
+                    dat.push(if self.visible(docs, i, j) {
+                        t[i * self.n + j]
+                    } else {
+                        f32::NEG_INFINITY
+                    });
+                }
+            }
+        }
+        Tensor::raw(inp.shape(), dat)
+    }
+    fn grad(
+        &self,
+        inps: &[&GeneralTensor],
+        out_grad: &Tensor<f32>,
+    ) -> Result<Vec<Tensor<f32>>, TensorError> {
+        let docs = inps[1].as_usize()?;
+        let mut dat = Vec::with_capacity(out_grad.size());
+        for (t, docs) in out_grad
+            .blob()
+            .chunks(self.n * self.n)
+            .zip(docs.blob().chunks(self.n))
+        {
+            for i in 0..self.n {
+                for j in 0..self.n {
+                    dat.push(if self.visible(docs, i, j) {
+                        t[i * self.n + j]
+                    } else {
+                        0.
+                    });
+                }
+            }
+        }
+        Ok(vec![
+            Tensor::raw(out_grad.shape(), dat)?,
+            Tensor::scalar(0.),
+        ])
+    }
+    fn clone_box(&self) -> Box<dyn Function> {
+        Box::new(self.clone())
+    }
+
+    #[cfg(feature = "gpu")]
+    fn gpu_impl(&self, out_id: TensorId, inps: &[Vec<usize>]) -> Option<GpuFunction> {
+        Some(gpu::documentmask::gpu_impl(
+            out_id,
+            inps,
+            self.n,
+            self.causal,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_document_mask() {
+        let scores = GeneralTensor::Float(Tensor::raw(&[3, 3], vec![1.; 9]).unwrap());
+        let docs = GeneralTensor::Usize(Tensor::raw(&[3], vec![0, 0, 1]).unwrap());
+        let mut mask = DocumentMask { n: 3, causal: true };
+        let out = mask.run(&[&scores, &docs], true).unwrap();
+        let inf = f32::NEG_INFINITY;
+        assert_eq!(out.blob(), &[1., inf, inf, 1., 1., inf, inf, inf, 1.]);
+
+        let grads = mask.grad(&[&scores, &docs], &scores.as_float().unwrap().clone());
+        assert_eq!(
+            grads.unwrap()[0].blob(),
+            &[1., 0., 0., 1., 1., 0., 0., 0., 1.]
+        );
+
+        let mut mask = DocumentMask {
+            n: 3,
+            causal: false,
+        };
+        let out = mask.run(&[&scores, &docs], true).unwrap();
+        assert_eq!(out.blob(), &[1., 1., inf, 1., 1., inf, inf, inf, 1.]);
+    }
+}
@@ -0,0 +1,68 @@
+use super::Function;
+use crate::tensor::*;
+
+#[cfg(feature = "gpu")]
+use super::{gpu, GpuFunction, TensorId};
+
+/// Dot products of the rows (Along the last dimension) of two tensors of the same shape. The
+/// gradient of the first input being the second one, backpropagating from it starts the backward
+/// pass of a graph with a gradient computed out of it (e.g. by `InfoNce`, over a batch of
+/// sequences), given as the second input.
+#[derive(Debug, Clone)]
+pub struct Dot;
+impl Dot {
+    pub fn new() -> Box<dyn Function> {
+        Box::new(Self {})
+    }
+}
+
+impl Function for Dot {
+    fn run(
+        &mut self,
+        inps: &[&GeneralTensor],
+        _training: bool,
+    ) -> Result<Tensor<f32>, TensorError> {
+        let a = inps[0].as_float()?;
+        let b = inps[1].as_float()?;
+        if a.shape() != b.shape() || a.dim() == 0 {
+            return Err(TensorError::UnexpectedShape);
+        }
+        let degree = a.shape()[a.dim() - 1];
+        Tensor::raw(
+            &a.shape()[..a.dim() - 1],
+            a.blob()
+                .chunks(degree)
+                .zip(b.blob().chunks(degree))
+                .map(|(a, b)| a.iter().zip(b).map(|(a, b)| a * b).sum())
+                .collect(),
+        )
+    }
+    fn grad(
+        &self,
+        inps: &[&GeneralTensor],
+        out_grad: &Tensor<f32>,
+    ) -> Result<Vec<Tensor<f32>>, TensorError> {
+        let a = inps[0].as_float()?;
+        let b = inps[1].as_float()?;
+        let degree = a.shape()[a.dim() - 1];
+        let scaled = |t: &Tensor<f32>| {
+            Tensor::raw(
+                t.shape(),
+                t.blob()
+                    .chunks(degree)
+                    .zip(out_grad.blob())
+                    .flat_map(|(row, g)| row.iter().map(move |v| v * g))
+                    .collect(),
+            )
+        };
+        Ok(vec![scaled(b)?, scaled(a)?])
+    }
+    fn clone_box(&self) -> Box<dyn Function> {
+        Box::new(self.clone())
+    }
+
+    #[cfg(feature = "gpu")]
+    fn gpu_impl(&self, out_id: TensorId, inps: &[Vec<usize>]) -> Option<GpuFunction> {
+        Some(gpu::dot::gpu_impl(out_id, inps))
+    }
+}
@@ -1,4 +1,4 @@
-use super::Function;
+use super::{Elementwise, Function};
 use crate::tensor::*;
 
 #[cfg(feature = "gpu")]
@@ -40,12 +40,15 @@ impl Function for Dropout {
     ) -> Result<Vec<Tensor<f32>>, TensorError> {
         Ok(vec![(out_grad * &self.mask.view())?])
     }
+    fn elementwise(&self) -> Option<Elementwise> {
+        Some(Elementwise::Dropout(self.rate))
+    }
     fn clone_box(&self) -> Box<dyn Function> {
         Box::new(self.clone())
     }
 
     #[cfg(feature = "gpu")]
-    fn gpu_impl(&self, out_id: TensorId, inps: &[Vec<usize>]) -> GpuFunction {
-        gpu::dropout::gpu_impl(out_id, inps, self.rate)
+    fn gpu_impl(&self, out_id: TensorId, inps: &[Vec<usize>]) -> Option<GpuFunction> {
+        Some(gpu::dropout::gpu_impl(out_id, inps, self.rate))
     }
 }
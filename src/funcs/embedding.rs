@@ -4,6 +4,8 @@ use crate::tensor::*;
 #[cfg(feature = "gpu")]
 use super::{gpu, GpuFunction, TensorId};
 
+/// Looks up the rows of a table (Second input) by index (First input), the gradient of the table
+/// is scatter-added back to the looked up rows.
 #[derive(Debug, Clone)]
 pub struct Embedding;
 impl Embedding {
@@ -22,7 +24,16 @@ impl Function for Embedding {
         if emb.dim() != 2 {
             return Err(TensorError::UnexpectedShape);
         }
-        inp.map(0, |s| Ok(emb.get(s.scalar()?)?.into()))
+        // Gathers the rows of the table
+        let degree = emb.shape()[1];
+        let mut out = Vec::with_capacity(inp.size() * degree);
+        for &tok in inp.blob().iter() {
+            if tok >= emb.len() {
+                return Err(TensorError::InvalidIndex);
+            }
+            out.extend_from_slice(&emb.blob()[tok * degree..(tok + 1) * degree]);
+        }
+        Tensor::raw(&[inp.shape(), &[degree]].concat(), out)
     }
     fn grad(
         &self,
@@ -31,13 +42,15 @@ impl Function for Embedding {
     ) -> Result<Vec<Tensor<f32>>, TensorError> {
         let inp = inps[0].as_usize()?;
         let mut grad = Tensor::<f32>::zeros(inps[1].as_float()?.shape());
-        for (ch, embed) in inp
-            .blob()
-            .iter()
-            .zip(out_grad.keep_right(1)?.inners().iter())
-        {
-            let mut row = grad.get_mut(*ch)?;
-            row.set((&row.view() + embed)?)?;
+        let degree = grad.shape()[1];
+        // Scatter-adds the gradients of the gathered rows
+        for (&tok, row_grad) in inp.blob().iter().zip(out_grad.blob().chunks(degree)) {
+            for (g, d) in grad.blob_mut()[tok * degree..(tok + 1) * degree]
+                .iter_mut()
+                .zip(row_grad.iter())
+            {
+                *g += d;
+            }
         }
         Ok(vec![Tensor::scalar(0.), grad])
     }
@@ -46,7 +59,34 @@ impl Function for Embedding {
     }
 
     #[cfg(feature = "gpu")]
-    fn gpu_impl(&self, out_id: TensorId, inps: &[Vec<usize>]) -> GpuFunction {
-        gpu::embedding::gpu_impl(out_id, inps)
+    fn gpu_impl(&self, out_id: TensorId, inps: &[Vec<usize>]) -> Option<GpuFunction> {
+        Some(gpu::embedding::gpu_impl(out_id, inps))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gather_scatter() {
+        let table =
+            GeneralTensor::Float(Tensor::raw(&[3, 2], vec![0., 1., 2., 3., 4., 5.]).unwrap());
+        let inp = GeneralTensor::Usize(Tensor::raw(&[3], vec![2, 0, 2]).unwrap());
+        let mut emb = Embedding {};
+        let out = emb.run(&[&inp, &table], true).unwrap();
+        assert_eq!(out.shape(), &[3, 2]);
+        assert_eq!(out.blob(), &[4., 5., 0., 1., 4., 5.]);
+
+        // Repeated tokens accumulate their gradients
+        let out_grad = Tensor::raw(&[3, 2], vec![1., 2., 3., 4., 5., 6.]).unwrap();
+        let grads = emb.grad(&[&inp, &table], &out_grad).unwrap();
+        assert_eq!(grads[1].blob(), &[3., 4., 0., 0., 6., 8.]);
+
+        let inp = GeneralTensor::Usize(Tensor::raw(&[1], vec![3]).unwrap());
+        assert!(matches!(
+            emb.run(&[&inp, &table], true),
+            Err(TensorError::InvalidIndex)
+        ));
     }
 }
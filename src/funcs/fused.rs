@@ -0,0 +1,220 @@
+use super::gelu::{gelu, gelu_prime};
+use super::Function;
+use crate::tensor::*;
+
+#[cfg(feature = "gpu")]
+use super::{gpu, GpuFunction, TensorId};
+use std::sync::Arc;
+
+/// Operations that apply to every element independently, which can be fused together.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Elementwise {
+    /// Adds another tensor, broadcasted the way `Add` does
+    Add,
+    Coeff(f32),
+    Gelu,
+    Relu,
+    Dropout(f32),
+}
+
+impl Elementwise {
+    fn name(&self) -> &'static str {
+        match self {
+            Elementwise::Add => "Add",
+            Elementwise::Coeff(_) => "Coeff",
+            Elementwise::Gelu => "Gelu",
+            Elementwise::Relu => "Relu",
+            Elementwise::Dropout(_) => "Dropout",
+        }
+    }
+}
+
+/// A chain of elementwise operations computed in a single pass over its input. The first input
+/// goes through the chain, the following ones are the operands of its `Add` operations, in order.
+#[derive(Debug, Clone)]
+pub struct Fused {
+    ops: Vec<Elementwise>,
+    // The masks of the `Dropout` operations of the last training pass, empty in inference mode
+    masks: Vec<Arc<Tensor<f32>>>,
+}
+
+impl Fused {
+    pub fn new(ops: Vec<Elementwise>) -> Box<dyn Function> {
+        Box::new(Self {
+            ops,
+            masks: Vec::new(),
+        })
+    }
+
+    // Applies the chain to the `index`-th element, recording the input of each operation
+    fn eval(
+        &self,
+        index: usize,
+        mut x: f32,
+        operands: &[&Tensor<f32>],
+        mut trace: Option<&mut Vec<f32>>,
+    ) -> f32 {
+        let (mut operand, mut mask) = (0, 0);
+        for op in self.ops.iter() {
+            if let Some(trace) = trace.as_mut() {
+                trace.push(x);
+            }
+            x = match op {
+                Elementwise::Add => {
+                    let b = operands[operand].blob();
+                    operand += 1;
+                    x + b[index % b.len()]
+                }
+                Elementwise::Coeff(c) => x * c,
+                Elementwise::Gelu => gelu(x),
+                Elementwise::Relu => {
+                    if x > 0. {
+                        x
+                    } else {
+                        0.01 * x
+                    }
+                }
+                Elementwise::Dropout(_) => {
+                    mask += 1;
+                    self.masks.get(mask - 1).map_or(x, |m| x * m.blob()[index])
+                }
+            };
+        }
+        x
+    }
+}
+
+impl Function for Fused {
+    fn op_name(&self) -> String {
+        self.ops
+            .iter()
+            .map(|op| op.name())
+            .collect::<Vec<_>>()
+            .join("+")
+    }
+    fn run(&mut self, inps: &[&GeneralTensor], training: bool) -> Result<Tensor<f32>, TensorError> {
+        let inp = inps[0].as_float()?;
+        let operands = inps[1..]
+            .iter()
+            .map(|t| t.as_float())
+            .collect::<Result<Vec<_>, TensorError>>()?;
+        if operands.iter().any(|b| inp.size() % b.size() != 0) {
+            return Err(TensorError::UnexpectedShape);
+        }
+        self.masks.clear();
+        if training {
+            let mut rng = rand::thread_rng();
+            for op in self.ops.iter() {
+                if let Elementwise::Dropout(rate) = op {
+                    let rnd = Tensor::<f32>::rand_range(&mut rng, 0., 1.0, inp.shape());
+                    let scale = 1. / (1. - rate);
+                    self.masks.push(Arc::new(
+                        rnd.map_values(|v| if v > *rate { scale } else { 0. }),
+                    ));
+                }
+            }
+        }
+        Tensor::raw(
+            inp.shape(),
+            inp.blob()
+                .iter()
+                .enumerate()
+                .map(|(i, x)| self.eval(i, *x, &operands, None))
+                .collect(),
+        )
+    }
+    fn grad(
+        &self,
+        inps: &[&GeneralTensor],
+        out_grad: &Tensor<f32>,
+    ) -> Result<Vec<Tensor<f32>>, TensorError> {
+        let inp = inps[0].as_float()?;
+        let operands = inps[1..]
+            .iter()
+            .map(|t| t.as_float())
+            .collect::<Result<Vec<_>, TensorError>>()?;
+        let mut inp_grad = Vec::with_capacity(inp.size());
+        let mut operand_grads = operands
+            .iter()
+            .map(|b| vec![0.; b.size()])
+            .collect::<Vec<_>>();
+        let mut trace = Vec::with_capacity(self.ops.len());
+        for (i, (x, g)) in inp.blob().iter().zip(out_grad.blob().iter()).enumerate() {
+            trace.clear();
+            self.eval(i, *x, &operands, Some(&mut trace));
+            let (mut g, mut operand, mut mask) = (*g, operand_grads.len(), self.masks.len());
+            for (op, x) in self.ops.iter().zip(trace.iter()).rev() {
+                match op {
+                    Elementwise::Add => {
+                        operand -= 1;
+                        let b_grad = &mut operand_grads[operand];
+                        let len = b_grad.len();
+                        b_grad[i % len] += g;
+                    }
+                    Elementwise::Coeff(c) => g *= c,
+                    Elementwise::Gelu => g *= gelu_prime(*x),
+                    Elementwise::Relu => {
+                        if *x <= 0. {
+                            g *= 0.01;
+                        }
+                    }
+                    Elementwise::Dropout(_) => {
+                        if mask > 0 {
+                            mask -= 1;
+                            g *= self.masks[mask].blob()[i];
+                        }
+                    }
+                }
+            }
+            inp_grad.push(g);
+        }
+        let mut grads = vec![Tensor::raw(inp.shape(), inp_grad)?];
+        for (b, b_grad) in operands.iter().zip(operand_grads) {
+            grads.push(Tensor::raw(b.shape(), b_grad)?);
+        }
+        Ok(grads)
+    }
+    fn clone_box(&self) -> Box<dyn Function> {
+        Box::new(self.clone())
+    }
+
+    #[cfg(feature = "gpu")]
+    fn gpu_impl(&self, out_id: TensorId, inps: &[Vec<usize>]) -> Option<GpuFunction> {
+        Some(gpu::fused::gpu_impl(out_id, inps, &self.ops))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::funcs::{Add, Coeff, Gelu, Relu};
+
+    #[test]
+    fn test_fused_matches_unfused() {
+        let mut rng = rand::thread_rng();
+        let x = GeneralTensor::Float(Tensor::<f32>::rand_range(&mut rng, -1., 1., &[3, 4]));
+        let bias = GeneralTensor::Float(Tensor::<f32>::rand_range(&mut rng, -1., 1., &[4]));
+        let residual = GeneralTensor::Float(Tensor::<f32>::rand_range(&mut rng, -1., 1., &[3, 4]));
+
+        let a = GeneralTensor::Float(Add::new().run(&[&x, &bias], false).unwrap());
+        let b = GeneralTensor::Float(Gelu::new().run(&[&a], false).unwrap());
+        let c = GeneralTensor::Float(Coeff::new(0.5).run(&[&b], false).unwrap());
+        let d = GeneralTensor::Float(Relu::new().run(&[&c], false).unwrap());
+        let expected = Add::new().run(&[&d, &residual], false).unwrap();
+
+        let mut fused = Fused::new(vec![
+            Elementwise::Add,
+            Elementwise::Gelu,
+            Elementwise::Coeff(0.5),
+            Elementwise::Relu,
+            Elementwise::Add,
+            Elementwise::Dropout(0.5),
+        ]);
+        assert_eq!(fused.op_name(), "Add+Gelu+Coeff+Relu+Add+Dropout");
+        let out = fused.run(&[&x, &bias, &residual], false).unwrap();
+        assert_eq!(out.shape(), expected.shape());
+        for (o, e) in out.blob().iter().zip(expected.blob().iter()) {
+            assert!((o - e).abs() < 1e-6);
+        }
+    }
+}
@@ -1,4 +1,4 @@
-use super::Function;
+use super::{Elementwise, Function};
 use crate::tensor::*;
 
 #[cfg(feature = "gpu")]
@@ -7,11 +7,11 @@ use super::{gpu, GpuFunction, TensorId};
 const SQRT_2_OVER_PI: f32 = 0.7978845608;
 const GELU_CONST: f32 = 0.044715;
 
-fn gelu(x: f32) -> f32 {
+pub(super) fn gelu(x: f32) -> f32 {
     0.5 * x * ((SQRT_2_OVER_PI * (x + GELU_CONST * x.powi(3))).tanh() + 1.)
 }
 
-fn gelu_prime(x: f32) -> f32 {
+pub(super) fn gelu_prime(x: f32) -> f32 {
     let x2 = x * x;
     let x3 = x2 * x;
     let v = SQRT_2_OVER_PI * x + SQRT_2_OVER_PI * GELU_CONST * x3;
@@ -43,13 +43,16 @@ impl Function for Gelu {
         let der = inps[0].as_float()?.map_values(gelu_prime);
         Ok(vec![(&der * out_grad)?])
     }
+    fn elementwise(&self) -> Option<Elementwise> {
+        Some(Elementwise::Gelu)
+    }
     fn clone_box(&self) -> Box<dyn Function> {
         Box::new(self.clone())
     }
 
     #[cfg(feature = "gpu")]
-    fn gpu_impl(&self, out_id: TensorId, inps: &[Vec<usize>]) -> GpuFunction {
-        gpu::gelu::gpu_impl(out_id, inps)
+    fn gpu_impl(&self, out_id: TensorId, inps: &[Vec<usize>]) -> Option<GpuFunction> {
+        Some(gpu::gelu::gpu_impl(out_id, inps))
     }
 }
 
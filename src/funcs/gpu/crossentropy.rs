@@ -1,64 +1,108 @@
 use super::*;
 
-pub fn gpu_impl(out_id: TensorId, inps: &[Vec<usize>]) -> GpuFunction {
+pub fn gpu_impl(
+    out_id: TensorId,
+    inps: &[Vec<usize>],
+    label_smoothing: f32,
+    z_loss: f32,
+) -> GpuFunction {
     let works = inps[1].iter().fold(1, |a, b| a * b);
     let classes = inps[0].last().unwrap();
+    let target_coeff = 1.0 - label_smoothing;
+    let smooth = label_smoothing / *classes as f32;
 
+    // The optional weights of the positions scale their loss, and get their unweighted loss as
+    // gradient
+    let weighted = inps.len() == 3;
+    let (weights_arg, weights_grad_arg, weight) = if weighted {
+        (
+            ",\n                        __global float* weights",
+            ",\n                        __global float* weights,\n                        __global float* weights_grad",
+            " * weights[id]",
+        )
+    } else {
+        ("", "", "")
+    };
+    let weights_grad = if weighted {
+        format!(
+            "if(c == 0) {{
+                float logits_sum = 0.0;
+                for(uint i = 0; i < {classes}; i++) {{
+                    logits_sum += inp[i];
+                }}
+                weights_grad[id] += (log_z - {target_coeff} * inp[*expected]
+                    - {smooth} * logits_sum + {z_loss} * log_z * log_z) * *out_grad;
+            }}"
+        )
+    } else {
+        String::new()
+    };
+
+    // Only the log-sum-exp of each row is kept, shifted by the largest logit so that the
+    // exponentials can't overflow
     let forward_source_code = format!(
         "__kernel void calc_{out_id}(
                         __global float* out,
-                        __global float* exps_buff,
-                        __global float* sum_buff,
+                        __global float* log_z_buff,
                         __global float* inp,
-                        __global ulong* expected) {{
+                        __global ulong* expected{weights_arg}) {{
         uint id = get_global_id(0);
         out += id;
         expected += id;
         inp += {classes} * id;
-        sum_buff += id;
-        exps_buff += {classes} * id;
+        log_z_buff += id;
         if(id < {works}) {{
+            float max = -INFINITY;
+            float logits_sum = 0.0;
+            for(uint i = 0; i < {classes}; i++) {{
+                max = fmax(max, inp[i]);
+                logits_sum += inp[i];
+            }}
             float sum = 0.0;
             for(uint i = 0; i < {classes}; i++) {{
-                exps_buff[i] = exp(inp[i]);
-                sum += exps_buff[i];
+                sum += exp(inp[i] - max);
             }}
-            *sum_buff = sum;
-            *out = log(sum) - inp[*expected];
+            float log_z = max + log(sum);
+            *log_z_buff = log_z;
+            if(*expected >= {classes}) {{
+                *out = 0.0;
+                return;
+            }}
+            *out = (log_z - {target_coeff} * inp[*expected] - {smooth} * logits_sum
+                + {z_loss} * log_z * log_z){weight};
         }}
     }}"
     );
 
+    // The softmax probabilities, recomputed from the logits, minus the target distribution
     let backward_source_code = format!(
         "__kernel void grad_{out_id}(
                         __global float* out,
                         __global float* out_grad,
-                        __global float* exps_buff,
-                        __global float* sum_buff,
+                        __global float* log_z_buff,
                         __global float* inp,
                         __global float* inp_grad,
                         __global ulong* expected,
-                        __global float* expected_grad) {{
+                        __global float* expected_grad{weights_grad_arg}) {{
         uint wid = get_global_id(0);
         uint id = wid / {classes};
         uint c = wid % {classes};
-        sum_buff += id;
-        exps_buff += {classes} * id;
+        log_z_buff += id;
         inp_grad += {classes} * id;
         out_grad += id;
         out += id;
         expected += id;
         inp += {classes} * id;
-        if(wid < {works} * {classes}) {{
-            float val = exps_buff[c];
-            float sum_inv = 1.0 / *sum_buff;
-
-            float grad = val * sum_inv;
+        if(wid < {works} * {classes} && *expected < {classes}) {{
+            float log_z = *log_z_buff;
+            float z_coeff = 1.0 + 2.0 * {z_loss} * log_z;
+            float grad = exp(inp[c] - log_z) * z_coeff - {smooth};
             if(c == *expected) {{
-                grad = grad - 1.0;
+                grad = grad - {target_coeff};
             }}
-            grad *= *out_grad;
+            grad *= *out_grad{weight};
             inp_grad[c] += grad;
+            {weights_grad}
         }}
     }}"
     );
@@ -76,9 +120,6 @@ pub fn gpu_impl(out_id: TensorId, inps: &[Vec<usize>]) -> GpuFunction {
             local_work_size: 32,
             global_work_size: works * classes,
         }],
-        shared_buffers: vec![
-            SharedBuffer::Float(works * classes),
-            SharedBuffer::Float(works),
-        ],
+        shared_buffers: vec![SharedBuffer::Float(works)],
     }
 }
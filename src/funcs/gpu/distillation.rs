@@ -0,0 +1,118 @@
+use super::*;
+
+pub fn gpu_impl(
+    out_id: TensorId,
+    inps: &[Vec<usize>],
+    temperature: f32,
+    alpha: f32,
+) -> GpuFunction {
+    let works = inps[1].iter().fold(1, |a, b| a * b);
+    let classes = inps[0].last().unwrap();
+    let t = temperature;
+    let ce_coeff = 1.0 - alpha;
+    let kl_coeff = alpha * t * t;
+    let grad_coeff = alpha * t;
+
+    // Every row keeps the softmax normalizers of the student's logits (Plain and at the
+    // temperature) and of the teacher's, and its KL divergence, for the backward pass
+    let forward_source_code = format!(
+        "__kernel void calc_{out_id}(
+                        __global float* out,
+                        __global float* row_buff,
+                        __global float* inp,
+                        __global ulong* expected,
+                        __global float* teacher) {{
+        uint id = get_global_id(0);
+        out += id;
+        expected += id;
+        inp += {classes} * id;
+        teacher += {classes} * id;
+        row_buff += 4 * id;
+        if(id < {works}) {{
+            if(*expected >= {classes}) {{
+                *out = 0.0;
+                return;
+            }}
+            float max = -INFINITY;
+            float teacher_max = -INFINITY;
+            for(uint i = 0; i < {classes}; i++) {{
+                max = fmax(max, inp[i]);
+                teacher_max = fmax(teacher_max, teacher[i]);
+            }}
+            float sum = 0.0;
+            float student_sum = 0.0;
+            float teacher_sum = 0.0;
+            for(uint i = 0; i < {classes}; i++) {{
+                sum += exp(inp[i] - max);
+                student_sum += exp((inp[i] - max) / {t});
+                teacher_sum += exp((teacher[i] - teacher_max) / {t});
+            }}
+            float log_z = max + log(sum);
+            float log_z_student = max / {t} + log(student_sum);
+            float log_z_teacher = teacher_max / {t} + log(teacher_sum);
+            float kl = 0.0;
+            for(uint i = 0; i < {classes}; i++) {{
+                float log_p = teacher[i] / {t} - log_z_teacher;
+                kl += exp(log_p) * (log_p - (inp[i] / {t} - log_z_student));
+            }}
+            row_buff[0] = log_z;
+            row_buff[1] = log_z_student;
+            row_buff[2] = log_z_teacher;
+            row_buff[3] = kl;
+            *out = {ce_coeff} * (log_z - inp[*expected]) + {kl_coeff} * kl;
+        }}
+    }}"
+    );
+
+    let backward_source_code = format!(
+        "__kernel void grad_{out_id}(
+                        __global float* out,
+                        __global float* out_grad,
+                        __global float* row_buff,
+                        __global float* inp,
+                        __global float* inp_grad,
+                        __global ulong* expected,
+                        __global float* expected_grad,
+                        __global float* teacher,
+                        __global float* teacher_grad) {{
+        uint wid = get_global_id(0);
+        uint id = wid / {classes};
+        uint c = wid % {classes};
+        row_buff += 4 * id;
+        inp_grad += {classes} * id;
+        teacher_grad += {classes} * id;
+        out_grad += id;
+        expected += id;
+        inp += {classes} * id;
+        teacher += {classes} * id;
+        if(wid < {works} * {classes} && *expected < {classes}) {{
+            float log_p = teacher[c] / {t} - row_buff[2];
+            float log_q = inp[c] / {t} - row_buff[1];
+            float p = exp(log_p);
+            float grad = exp(inp[c] - row_buff[0]);
+            if(c == *expected) {{
+                grad = grad - 1.0;
+            }}
+            grad = {ce_coeff} * grad + {grad_coeff} * (exp(log_q) - p);
+            inp_grad[c] += grad * *out_grad;
+            teacher_grad[c] += {grad_coeff} * p * (log_p - log_q - row_buff[3]) * *out_grad;
+        }}
+    }}"
+    );
+
+    GpuFunction {
+        forward_funcs: vec![KernelCall {
+            source_code: forward_source_code,
+            kernel_name: format!("calc_{}", out_id),
+            local_work_size: 32,
+            global_work_size: works,
+        }],
+        backward_funcs: vec![KernelCall {
+            source_code: backward_source_code,
+            kernel_name: format!("grad_{}", out_id),
+            local_work_size: 32,
+            global_work_size: works * classes,
+        }],
+        shared_buffers: vec![SharedBuffer::Float(works * 4)],
+    }
+}
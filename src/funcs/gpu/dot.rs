@@ -0,0 +1,56 @@
+use super::*;
+
+pub fn gpu_impl(out_id: TensorId, inps: &[Vec<usize>]) -> GpuFunction {
+    let works = inps[0].iter().fold(1, |a, b| a * b);
+    let degree = inps[0][inps[0].len() - 1];
+    let rows = works / degree;
+
+    let forward_source_code = format!(
+        "__kernel void calc_{out_id}(
+                        __global float* out,
+                        __global float* a,
+                        __global float* b) {{
+        uint id = get_global_id(0);
+        if(id < {rows}) {{
+            float sum = 0.0;
+            for(uint i = 0; i < {degree}; i++) {{
+                sum += a[id * {degree} + i] * b[id * {degree} + i];
+            }}
+            out[id] = sum;
+        }}
+    }}"
+    );
+
+    let backward_source_code = format!(
+        "__kernel void grad_{out_id}(
+                        __global float* out,
+                        __global float* out_grad,
+                        __global float* a,
+                        __global float* a_grad,
+                        __global float* b,
+                        __global float* b_grad) {{
+        uint id = get_global_id(0);
+        if(id < {works}) {{
+            float g = out_grad[id / {degree}];
+            a_grad[id] += g * b[id];
+            b_grad[id] += g * a[id];
+        }}
+    }}"
+    );
+
+    GpuFunction {
+        forward_funcs: vec![KernelCall {
+            source_code: forward_source_code,
+            kernel_name: format!("calc_{}", out_id),
+            local_work_size: 32,
+            global_work_size: rows,
+        }],
+        backward_funcs: vec![KernelCall {
+            source_code: backward_source_code,
+            kernel_name: format!("grad_{}", out_id),
+            local_work_size: 32,
+            global_work_size: works,
+        }],
+        shared_buffers: vec![],
+    }
+}
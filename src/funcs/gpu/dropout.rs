@@ -1,30 +1,25 @@
 use super::*;
 
+// Elements are dropped when the uniform number of their counter `(id, out_id, 0, rng_pass)` is
+// below the rate, the backward pass regenerates the same mask
 pub fn gpu_impl(out_id: TensorId, inps: &[Vec<usize>], rate: f32) -> GpuFunction {
     let works = inps[0].iter().fold(1, |a, b| a * b);
-    const M: usize = 2147483647;
-    let threshold = (rate as f64) * (M as f64);
     let gain = 1.0 / (1.0 - rate);
 
     let forward_source_code = format!(
         "__kernel void calc_{out_id}(
                         __global float* out,
-                        __global ulong* seeds,
+                        ulong rng_key,
+                        uint rng_pass,
                         __global float* a) {{
         uint id = get_global_id(0);
-        ulong A = 16807;
-        ulong M = {M};
-
         if(id < {works}) {{
-            if(seeds[id] == 0) {{
-                seeds[id] = id;
-            }}
-            seeds[id] = (seeds[id] * A) % M;
-
-            if(seeds[id] < {threshold}) {{
+            if(rng_pass == 0) {{
+                out[id] = a[id];
+            }} else if(philox_uniform((uint4)(id, {out_id}u, 0u, rng_pass), rng_key) < {rate}) {{
                 out[id] = 0.0;
             }} else {{
-                out[id] = a[id];
+                out[id] = a[id] * {gain};
             }}
         }}
     }}"
@@ -34,13 +29,15 @@ pub fn gpu_impl(out_id: TensorId, inps: &[Vec<usize>], rate: f32) -> GpuFunction
         "__kernel void grad_{out_id}(
                         __global float* out,
                         __global float* out_grad,
-                        __global ulong* seeds,
+                        ulong rng_key,
+                        uint rng_pass,
                         __global float* a,
                         __global float* a_grad) {{
         uint id = get_global_id(0);
         if(id < {works}) {{
-            bool dropped = seeds[id] < {threshold};
-            if(!dropped) {{
+            if(rng_pass == 0) {{
+                a_grad[id] += out_grad[id];
+            }} else if(philox_uniform((uint4)(id, {out_id}u, 0u, rng_pass), rng_key) >= {rate}) {{
                 a_grad[id] += out_grad[id] * {gain};
             }}
         }}
@@ -60,6 +57,6 @@ pub fn gpu_impl(out_id: TensorId, inps: &[Vec<usize>], rate: f32) -> GpuFunction
             local_work_size: 32,
             global_work_size: works,
         }],
-        shared_buffers: vec![SharedBuffer::Usize(works)],
+        shared_buffers: vec![SharedBuffer::Random],
     }
 }
@@ -4,22 +4,21 @@ pub fn gpu_impl(out_id: TensorId, inps: &[Vec<usize>]) -> GpuFunction {
     let works = inps[0].iter().fold(1, |a, b| a * b);
     let degree = inps[1][1];
 
+    // One work-item per output element, gathering it from its row of the table
     let forward_source_code = format!(
         "__kernel void calc_{out_id}(
                         __global float* out,
                         __global ulong* inp,
                         __global float* emb) {{
         uint id = get_global_id(0);
-        out += {degree} * id;
-        emb += {degree} * inp[id];
-        if(id < {works}) {{
-            for(uint i = 0; i < {degree}; i++) {{
-                out[i] = emb[i];
-            }}
+        if(id < {works} * {degree}) {{
+            out[id] = emb[{degree} * inp[id / {degree}] + id % {degree}];
         }}
     }}"
     );
 
+    // One work-item per column of the table, scatter-adding the gradients of the tokens in order,
+    // so that no two work-items write the same element
     let backward_source_code = format!(
         "__kernel void grad_{out_id}(
                         __global float* out,
@@ -42,7 +41,7 @@ pub fn gpu_impl(out_id: TensorId, inps: &[Vec<usize>]) -> GpuFunction {
             source_code: forward_source_code,
             kernel_name: format!("calc_{}", out_id),
             local_work_size: 32,
-            global_work_size: works,
+            global_work_size: works * degree,
         }],
         backward_funcs: vec![KernelCall {
             source_code: backward_source_code,
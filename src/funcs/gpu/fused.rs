@@ -0,0 +1,214 @@
+use super::*;
+use crate::funcs::Elementwise;
+
+// Whether the `k`-th operation, a dropout, keeps the element `id` in training mode (See
+// `dropout::gpu_impl`)
+fn kept(k: usize, out_id: TensorId, rate: f32) -> String {
+    format!("philox_uniform((uint4)(id, {out_id}u, {k}u, rng_pass), rng_key) >= {rate}")
+}
+
+// Code applying the `k`-th operation of the chain to `x`
+fn apply(k: usize, op: &Elementwise, operand: usize, sizes: &[usize], out_id: TensorId) -> String {
+    match op {
+        Elementwise::Add => format!("x = x + inp_{operand}[id % {}];", sizes[operand]),
+        Elementwise::Coeff(c) => format!("x = x * {c};"),
+        Elementwise::Gelu => {
+            "x = 0.5 * x * (tanh(0.7978845608 * (x + 0.044715 * x * x * x)) + 1.);".into()
+        }
+        Elementwise::Relu => "x = x > 0. ? x : x * 0.01;".into(),
+        Elementwise::Dropout(rate) => format!(
+            "x = rng_pass == 0 ? x : {} ? x * {} : 0.;",
+            kept(k, out_id, *rate),
+            1.0 / (1.0 - rate)
+        ),
+    }
+}
+
+// Code multiplying `g` by the derivative of the `k`-th operation, at its input `s{k}`
+fn derive(k: usize, op: &Elementwise, out_id: TensorId) -> String {
+    match op {
+        Elementwise::Add => String::new(),
+        Elementwise::Coeff(c) => format!("g = g * {c};"),
+        Elementwise::Gelu => format!(
+            "float v = 0.7978845608 * s{k} + 0.7978845608 * 0.044715 * s{k} * s{k} * s{k};
+            float v_prime = 0.7978845608 + 3. * 0.7978845608 * 0.044715 * s{k} * s{k};
+            float cosh_v = cosh(v);
+            g = g * 0.5 * (1. + tanh(v) + s{k} * v_prime / (cosh_v * cosh_v));"
+        ),
+        Elementwise::Relu => format!("g = s{k} > 0. ? g : g * 0.01;"),
+        Elementwise::Dropout(rate) => format!(
+            "g = rng_pass == 0 ? g : {} ? g * {} : 0.;",
+            kept(k, out_id, *rate),
+            1.0 / (1.0 - rate)
+        ),
+    }
+}
+
+pub fn gpu_impl(out_id: TensorId, inps: &[Vec<usize>], ops: &[Elementwise]) -> GpuFunction {
+    let sizes = inps
+        .iter()
+        .map(|s| s.iter().fold(1, |a, b| a * b))
+        .collect::<Vec<_>>();
+    let works = sizes[0];
+
+    // Operand index of each `Add`
+    let mut operands = Vec::new();
+    let mut operand = 0;
+    for op in ops.iter() {
+        if *op == Elementwise::Add {
+            operand += 1;
+        }
+        operands.push(operand);
+    }
+    // Dropouts share the random arguments, each one with its own counters
+    let random = ops.iter().any(|op| matches!(op, Elementwise::Dropout(_)));
+    let (random_params, random_args) = if random {
+        ("ulong rng_key, uint rng_pass,", "rng_key, rng_pass,")
+    } else {
+        ("", "")
+    };
+    let inps_params = (0..inps.len())
+        .map(|j| format!("__global float* inp_{j}"))
+        .collect::<Vec<_>>()
+        .join(",");
+    let inps_args = (0..inps.len())
+        .map(|j| format!("inp_{j}"))
+        .collect::<Vec<_>>()
+        .join(",");
+    let inps_grads_params = (0..inps.len())
+        .map(|j| format!("__global float* inp_{j}, __global float* inp_{j}_grad"))
+        .collect::<Vec<_>>()
+        .join(",");
+
+    let forward_stages = ops
+        .iter()
+        .enumerate()
+        .map(|(k, op)| apply(k, op, operands[k], &sizes, out_id))
+        .collect::<Vec<_>>()
+        .join("\n");
+    let forward_source_code = format!(
+        "__kernel void calc_{out_id}(
+                        __global float* out,
+                        {random_params}
+                        {inps_params}) {{
+        uint id = get_global_id(0);
+        if(id < {works}) {{
+            float x = inp_0[id];
+            {forward_stages}
+            out[id] = x;
+        }}
+    }}"
+    );
+
+    // Gradient with respect to the input of the `from`-th operation, recomputing the chain
+    let traced_stages = ops
+        .iter()
+        .enumerate()
+        .map(|(k, op)| {
+            format!(
+                "float s{k} = x; {}",
+                apply(k, op, operands[k], &sizes, out_id)
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+    let backward_stages = ops
+        .iter()
+        .enumerate()
+        .rev()
+        .map(|(k, op)| format!("if({k} >= from) {{ {} }}", derive(k, op, out_id)))
+        .collect::<Vec<_>>()
+        .join("\n");
+    let helper = format!(
+        "float grad_at_{out_id}(uint id, uint from,
+                        __global float* out_grad,
+                        {random_params}
+                        {inps_params}) {{
+        float x = inp_0[id];
+        {traced_stages}
+        float g = out_grad[id];
+        {backward_stages}
+        return g;
+    }}"
+    );
+
+    // Operands as large as the input are handled along with it, broadcasted ones need a sum
+    let mut full_size_grads = String::new();
+    let mut backward_funcs = Vec::new();
+    for (k, op) in ops.iter().enumerate() {
+        if *op != Elementwise::Add {
+            continue;
+        }
+        let j = operands[k];
+        let size = sizes[j];
+        if size == works {
+            full_size_grads += &format!(
+                "inp_{j}_grad[id] += grad_at_{out_id}(id, {}, out_grad, {random_args} {inps_args});",
+                k + 1
+            );
+            continue;
+        }
+        let repeats = works / size;
+        backward_funcs.push(KernelCall {
+            source_code: format!(
+                "__kernel void grad_{out_id}_{j}(
+                        __global float* out,
+                        __global float* out_grad,
+                        {random_params}
+                        {inps_grads_params}) {{
+                uint id = get_global_id(0);
+                if(id < {size}) {{
+                    float sum = 0.0;
+                    for(uint i = 0; i < {repeats}; i++) {{
+                        sum += grad_at_{out_id}(i * {size} + id, {}, out_grad, {random_args} {inps_args});
+                    }}
+                    inp_{j}_grad[id] += sum;
+                }}
+            }}",
+                k + 1
+            ),
+            kernel_name: format!("grad_{}_{}", out_id, j),
+            local_work_size: 32,
+            global_work_size: size,
+        });
+    }
+    let backward_source_code = format!(
+        "{helper}
+        __kernel void grad_{out_id}(
+                        __global float* out,
+                        __global float* out_grad,
+                        {random_params}
+                        {inps_grads_params}) {{
+        uint id = get_global_id(0);
+        if(id < {works}) {{
+            inp_0_grad[id] += grad_at_{out_id}(id, 0, out_grad, {random_args} {inps_args});
+            {full_size_grads}
+        }}
+    }}"
+    );
+    // The first kernel defines the helper the others use
+    backward_funcs.insert(
+        0,
+        KernelCall {
+            source_code: backward_source_code,
+            kernel_name: format!("grad_{}", out_id),
+            local_work_size: 32,
+            global_work_size: works,
+        },
+    );
+
+    GpuFunction {
+        forward_funcs: vec![KernelCall {
+            source_code: forward_source_code,
+            kernel_name: format!("calc_{}", out_id),
+            local_work_size: 32,
+            global_work_size: works,
+        }],
+        backward_funcs,
+        shared_buffers: if random {
+            vec![SharedBuffer::Random]
+        } else {
+            Vec::new()
+        },
+    }
+}
@@ -4,6 +4,10 @@ pub fn gpu_impl(out_id: TensorId, inps: &[Vec<usize>]) -> GpuFunction {
     let n = inps[0][inps[0].len() - 1];
     let works = inps[0][..inps[0].len() - 1].iter().fold(1, |a, b| a * b);
 
+    let rows = row_count(works);
+
+    // Mean and variance in a single pass: every work-item runs Welford's algorithm over its
+    // elements, the partial results are then merged across the work-group (Chan et al.)
     let forward_source_code = format!(
         "__kernel void calc_{out_id}(
                         __global float* out,
@@ -13,31 +17,60 @@ pub fn gpu_impl(out_id: TensorId, inps: &[Vec<usize>]) -> GpuFunction {
                         __global float* a,
                         __global float* coeff,
                         __global float* bias) {{
-        uint id = get_global_id(0);
-        if(id < {works}) {{
-            a += id * {n};
-            out += id * {n};
-            float size_inv = 1./{n};
+        __local float counts[{MAX_ROW_GROUP}];
+        __local float avgs[{MAX_ROW_GROUP}];
+        __local float m2s[{MAX_ROW_GROUP}];
+        uint lid = get_local_id(0);
+        uint size = get_local_size(0);
+        uint rows = {rows};
+        for(uint row = get_group_id(0); row < rows; row += get_num_groups(0)) {{
+            __global float* x = a + row * {n};
+            __global float* y = out + row * {n};
+            float count = 0.;
             float avg = 0.;
-            for(uint i = 0; i < {n}; i++) {{
-                avg += a[i];
+            float m2 = 0.;
+            for(uint i = lid; i < {n}; i += size) {{
+                count += 1.;
+                float delta = x[i] - avg;
+                avg += delta / count;
+                m2 += delta * (x[i] - avg);
             }}
-            avg *= size_inv;
-            avg_buff[id] = avg;
-            float var = 0.;
-            for(uint i = 0; i < {n}; i++) {{
-                var += (a[i] - avg) * (a[i] - avg);
+            counts[lid] = count;
+            avgs[lid] = avg;
+            m2s[lid] = m2;
+            barrier(CLK_LOCAL_MEM_FENCE);
+            for(uint s = size / 2; s > 0; s >>= 1) {{
+                if(lid < s && counts[lid + s] > 0.) {{
+                    float other_count = counts[lid + s];
+                    float total = count + other_count;
+                    float delta = avgs[lid + s] - avg;
+                    avg += delta * other_count / total;
+                    m2 += m2s[lid + s] + delta * delta * count * other_count / total;
+                    count = total;
+                    counts[lid] = count;
+                    avgs[lid] = avg;
+                    m2s[lid] = m2;
+                }}
+                barrier(CLK_LOCAL_MEM_FENCE);
+            }}
+            avg = avgs[0];
+            float var = m2s[0] / {n};
+            if(lid == 0) {{
+                avg_buff[row] = avg;
+                sigma2_buff[row] = var;
             }}
-            var *= size_inv;
-            sigma2_buff[id] = var;
             float var_inv = 1. / sqrt(var + 1e-5);
-            for(uint i = 0; i < {n}; i++) {{
-                out[i] = (a[i] - avg) * var_inv * coeff[i] + bias[i];
+            for(uint i = lid; i < {n}; i += size) {{
+                y[i] = (x[i] - avg) * var_inv * coeff[i] + bias[i];
             }}
+            barrier(CLK_LOCAL_MEM_FENCE);
         }}
     }}"
     );
 
+    // With `x_hat` the normalized inputs and `dy = out_grad * coeff`, the gradient of the inputs
+    // is `(dy - mean(dy) - x_hat * mean(dy * x_hat)) / sigma`. The products `x_hat * out_grad`
+    // are kept for the gradient of `coeff`, which sums them over the rows.
     let backward_source_code_part_1 = format!(
         "__kernel void grad_{out_id}_0(
                         __global float* out,
@@ -51,42 +84,45 @@ pub fn gpu_impl(out_id: TensorId, inps: &[Vec<usize>]) -> GpuFunction {
                         __global float* coeff_grad,
                         __global float* bias,
                         __global float* bias_grad) {{
-        uint wid = get_global_id(0);
-        uint id = wid / {n};
-        uint i = wid % {n};
-
-        out += id * {n};
-        out_grad += id * {n};
-        inp += id * {n};
-        inp_grad += id * {n};
-        coeff_grad_temp += id * {n};
-
-        if(wid < {works} * {n}) {{
-            for(uint ii = 0; ii < {n}; ii++) {{
-                coeff_grad_temp[ii] = (out[ii] - bias[ii]) * out_grad[ii] / coeff[ii];
+        __local float dy_sums[{MAX_ROW_GROUP}];
+        __local float dy_x_hat_sums[{MAX_ROW_GROUP}];
+        uint lid = get_local_id(0);
+        uint size = get_local_size(0);
+        uint rows = {rows};
+        for(uint row = get_group_id(0); row < rows; row += get_num_groups(0)) {{
+            __global float* x = inp + row * {n};
+            __global float* x_grad = inp_grad + row * {n};
+            __global float* g = out_grad + row * {n};
+            __global float* temp = coeff_grad_temp + row * {n};
+            float avg = avg_buff[row];
+            float sigma_inv = 1. / sqrt(sigma2_buff[row] + 1e-5);
+            float dy_sum = 0.;
+            float dy_x_hat_sum = 0.;
+            for(uint i = lid; i < {n}; i += size) {{
+                float x_hat = (x[i] - avg) * sigma_inv;
+                float dy = g[i] * coeff[i];
+                dy_sum += dy;
+                dy_x_hat_sum += dy * x_hat;
+                temp[i] = x_hat * g[i];
             }}
-
-            float n_inv = 1.0 / {n};
-            float avg = avg_buff[id];
-            float sigma2 = sigma2_buff[id];
-            sigma2 += 0.00001;
-            float sigma2_inv = 1.0 / sigma2;
-            float sigma = sqrt(sigma2);
-            float sigma_inv = 1. / sigma;
-
-            float a = inp[i];
-            float sum = 0.0;
-            float n_inv_sigma = n_inv * sigma;
-            float n_inv_sigma_inv = n_inv * sigma_inv;
-            for(uint j = 0; j < {n}; j++) {{
-                if(i == j) {{
-                    sum += (sigma - n_inv_sigma - (a - avg) * (a - avg) * n_inv_sigma_inv) * sigma2_inv * out_grad[j] * coeff[j];
-                }} else {{
-                    float b = inp[j];
-                    sum += (-n_inv_sigma - (b - avg) * (a - avg) * n_inv_sigma_inv) * sigma2_inv * out_grad[j] * coeff[j];
+            dy_sums[lid] = dy_sum;
+            dy_x_hat_sums[lid] = dy_x_hat_sum;
+            barrier(CLK_LOCAL_MEM_FENCE);
+            for(uint s = size / 2; s > 0; s >>= 1) {{
+                if(lid < s) {{
+                    dy_sums[lid] += dy_sums[lid + s];
+                    dy_x_hat_sums[lid] += dy_x_hat_sums[lid + s];
                 }}
+                barrier(CLK_LOCAL_MEM_FENCE);
             }}
-            inp_grad[i] += sum;
+            float dy_avg = dy_sums[0] / {n};
+            float dy_x_hat_avg = dy_x_hat_sums[0] / {n};
+            for(uint i = lid; i < {n}; i += size) {{
+                float x_hat = (x[i] - avg) * sigma_inv;
+                float dy = g[i] * coeff[i];
+                x_grad[i] += (dy - dy_avg - x_hat * dy_x_hat_avg) * sigma_inv;
+            }}
+            barrier(CLK_LOCAL_MEM_FENCE);
         }}
     }}"
     );
@@ -122,15 +158,15 @@ pub fn gpu_impl(out_id: TensorId, inps: &[Vec<usize>]) -> GpuFunction {
         forward_funcs: vec![KernelCall {
             source_code: forward_source_code,
             kernel_name: format!("calc_{}", out_id),
-            local_work_size: 32,
-            global_work_size: works,
+            local_work_size: ROW_GROUP,
+            global_work_size: works * ROW_GROUP,
         }],
         backward_funcs: vec![
             KernelCall {
                 source_code: backward_source_code_part_1,
                 kernel_name: format!("grad_{}_0", out_id),
-                local_work_size: 32,
-                global_work_size: works * n,
+                local_work_size: ROW_GROUP,
+                global_work_size: works * ROW_GROUP,
             },
             KernelCall {
                 source_code: backward_source_code_part_2,
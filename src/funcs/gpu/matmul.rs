@@ -1,13 +1,44 @@
 use super::*;
 
-pub fn gpu_impl(out_id: TensorId, inps: &[Vec<usize>]) -> GpuFunction {
+pub fn gpu_impl(
+    out_id: TensorId,
+    inps: &[Vec<usize>],
+    transpose_a: bool,
+    transpose_b: bool,
+) -> GpuFunction {
     let a_mats = inps[0][..inps[0].len() - 2].iter().fold(1, |a, b| a * b);
     let b_mats = inps[1][..inps[1].len() - 2].iter().fold(1, |a, b| a * b);
     assert!(b_mats <= a_mats);
-    assert_eq!(inps[0][inps[0].len() - 1], inps[1][inps[1].len() - 2]);
-    let m = inps[0][inps[0].len() - 2];
-    let n = inps[0][inps[0].len() - 1];
-    let p = inps[1][inps[1].len() - 1];
+    // Shapes of the last two dimensions as they are stored
+    let (a_rows, a_cols) = (inps[0][inps[0].len() - 2], inps[0][inps[0].len() - 1]);
+    let (b_rows, b_cols) = (inps[1][inps[1].len() - 2], inps[1][inps[1].len() - 1]);
+    let (m, n) = if transpose_a {
+        (a_cols, a_rows)
+    } else {
+        (a_rows, a_cols)
+    };
+    let (n_b, p) = if transpose_b {
+        (b_cols, b_rows)
+    } else {
+        (b_rows, b_cols)
+    };
+    assert_eq!(n, n_b);
+    // Offsets of the element at row `i`, column `k` of the (Transposed) `a`, and row `k`, column
+    // `j` of the (Transposed) `b`
+    let a_at = |i: &str, k: &str| {
+        if transpose_a {
+            format!("{k} * {m} + {i}")
+        } else {
+            format!("{i} * {n} + {k}")
+        }
+    };
+    let b_at = |k: &str, j: &str| {
+        if transpose_b {
+            format!("{j} * {n} + {k}")
+        } else {
+            format!("{k} * {p} + {j}")
+        }
+    };
     let mp = m * p;
     let mn = m * n;
     let np = n * p;
@@ -33,11 +64,13 @@ pub fn gpu_impl(out_id: TensorId, inps: &[Vec<usize>]) -> GpuFunction {
             b += {n} * {p} * id_b;
             float sum = 0.0;
             for(uint k = 0; k < {n}; k++) {{
-                sum += a[i * {n} + k] * b[{p} * k + j];
+                sum += a[{}] * b[{}];
             }}
             out[ij] = sum;
         }}
-    }}"
+    }}",
+        a_at("i", "k"),
+        b_at("k", "j")
     );
 
     let works_1 = mats * mn;
@@ -63,11 +96,13 @@ pub fn gpu_impl(out_id: TensorId, inps: &[Vec<usize>]) -> GpuFunction {
             b += {np} * id_b;
             float sum = 0.0;
             for(uint j = 0; j < {p}; j++) {{
-                sum += out_grad[i * {p} + j] * b[k * {p} + j];
+                sum += out_grad[i * {p} + j] * b[{}];
             }}
-            a_grad[ik] += sum;
+            a_grad[{}] += sum;
         }}
-    }}"
+    }}",
+        b_at("k", "j"),
+        a_at("i", "k")
     );
 
     let works_2 = mats * np;
@@ -87,18 +122,19 @@ pub fn gpu_impl(out_id: TensorId, inps: &[Vec<usize>]) -> GpuFunction {
         uint k = kj / {p};
         uint j = kj % {p};
 
-        if(id < {works_2}) {{
+        if(wid < {works_2}) {{
             out_grad += {mp} * id;
             a += {mn} * id_a;
             grad_buff += {np} * id;
-            grad_buff[kj] = 0.0;
             float sum = 0.0;
             for(uint i = 0; i < {m}; i++) {{
-                sum += a[i * {n} + k] * out_grad[i * {p} + j];
+                sum += a[{}] * out_grad[i * {p} + j];
             }}
-            grad_buff[kj] += sum;
+            grad_buff[{}] = sum;
         }}
-    }}"
+    }}",
+        a_at("i", "k"),
+        b_at("k", "j")
     );
 
     let inp1_mats = mats / b_mats;
@@ -2,11 +2,16 @@ pub mod add;
 pub mod cat;
 pub mod coeff;
 pub mod crossentropy;
+pub mod distillation;
+pub mod documentmask;
+pub mod dot;
 pub mod dropout;
 pub mod embedding;
+pub mod fused;
 pub mod gelu;
 pub mod layer_norm;
 pub mod matmul;
+pub mod quantized_matmul;
 pub mod relu;
 pub mod softmax;
 pub mod transpose;
@@ -17,8 +22,50 @@ use crate::graph::TensorId;
 pub enum SharedBuffer {
     Float(usize),
     Usize(usize),
+    // Buffers initialized with constant data, e.g. quantized weights
+    Constant(Vec<f32>),
+    ConstantBytes(Vec<u8>),
+    // Not a buffer: two scalar arguments `ulong rng_key, uint rng_pass` for `philox_uniform`. The
+    // key is the graph's seed and the pass counter changes on every training forward pass, it's
+    // 0 in inference mode.
+    Random,
 }
 
+// Philox4x32-10 (Salmon et al., "Parallel random numbers: as easy as 1, 2, 3"), included once in
+// the program of a graph. Returns a uniform number in [0, 1) for each counter and key, so random
+// masks can be regenerated by backward passes instead of being stored.
+pub const PHILOX_SOURCE: &str = "
+float philox_uniform(uint4 ctr, ulong key) {
+    uint2 k = (uint2)((uint)key, (uint)(key >> 32));
+    for(uint r = 0; r < 10; r++) {
+        uint hi0 = mul_hi(0xD2511F53u, ctr.x);
+        uint lo0 = 0xD2511F53u * ctr.x;
+        uint hi1 = mul_hi(0xCD9E8D57u, ctr.z);
+        uint lo1 = 0xCD9E8D57u * ctr.z;
+        ctr = (uint4)(hi1 ^ ctr.y ^ k.x, lo1, hi0 ^ ctr.w ^ k.y, lo0);
+        k += (uint2)(0x9E3779B9u, 0xBB67AE85u);
+    }
+    return (ctr.x >> 8) * (1.0f / 16777216.0f);
+}
+";
+
+// Kernels reducing the rows of a tensor (See `softmax` and `layer_norm`) run a work-group per row,
+// striding over rows when there are fewer groups. They accept any power-of-two work-group size up
+// to `MAX_ROW_GROUP` (The sizes the autotuner tries), their global work size is `ROW_GROUP` times
+// the number of rows.
+pub const ROW_GROUP: usize = 64;
+pub const MAX_ROW_GROUP: usize = 256;
+
+// Number of rows a row kernel processes, which is less than `works` when the graph shrinks the
+// global work size (e.g. in inference mode)
+fn row_count(works: usize) -> String {
+    format!("min((uint){works}, (uint)(get_global_size(0) / {ROW_GROUP}))")
+}
+
+/// The kernels of an operation, all compiled into the program of the graph. Forward kernels take
+/// the output buffer, the shared buffers and then the inputs, backward kernels the output and its
+/// gradient, the shared buffers and then each input followed by its gradient (Which they add to).
+/// Kernel names must be unique in the graph, e.g. suffixed by the id of the output.
 #[derive(Clone, Debug)]
 pub struct GpuFunction {
     pub shared_buffers: Vec<SharedBuffer>,
@@ -26,6 +73,7 @@ pub struct GpuFunction {
     pub backward_funcs: Vec<KernelCall>,
 }
 
+/// A kernel and the work sizes it's enqueued with (The graph may tune the local size).
 #[derive(Clone, Debug)]
 pub struct KernelCall {
     pub source_code: String,
@@ -0,0 +1,94 @@
+use super::*;
+use crate::tensor::{QuantizedTensor, Q4_BLOCK_SIZE};
+
+pub fn gpu_impl(out_id: TensorId, inps: &[Vec<usize>], weights: &QuantizedTensor) -> GpuFunction {
+    let n = weights.shape()[0];
+    let p = weights.shape()[1];
+    assert_eq!(inps[0][inps[0].len() - 1], n);
+    let rows = inps[0].iter().fold(1, |a, b| a * b) / n;
+
+    // Expressions for reading `W[k][j]` out of the `q` and `scales` buffers
+    let (weight, shared_buffers) = match weights {
+        QuantizedTensor::Q8(w) => (
+            format!("scales[k] * q[k * {p} + j]"),
+            vec![
+                SharedBuffer::ConstantBytes(w.data().iter().map(|v| *v as u8).collect()),
+                SharedBuffer::Constant(w.scales().to_vec()),
+            ],
+        ),
+        QuantizedTensor::Q4(w) => {
+            let blocks = p.div_ceil(Q4_BLOCK_SIZE);
+            let row_bytes = blocks * Q4_BLOCK_SIZE / 2;
+            (
+                format!(
+                    "scales[k * {blocks} + j / {Q4_BLOCK_SIZE}] * \
+                    ((float)((((uchar)q[k * {row_bytes} + j / 2]) >> ((j % 2) * 4)) & 15) - 8.0)"
+                ),
+                vec![
+                    SharedBuffer::ConstantBytes(w.data().to_vec()),
+                    SharedBuffer::Constant(w.scales().iter().map(|s| s.to_f32()).collect()),
+                ],
+            )
+        }
+    };
+
+    let works_forward = rows * p;
+    let forward_source_code = format!(
+        "__kernel void calc_{out_id}(
+                        __global float* out,
+                        __global char* q,
+                        __global float* scales,
+                        __global float* a) {{
+        uint id = get_global_id(0);
+        uint i = id / {p};
+        uint j = id % {p};
+        if(id < {works_forward}) {{
+            a += {n} * i;
+            float sum = 0.0;
+            for(uint k = 0; k < {n}; k++) {{
+                sum += a[k] * {weight};
+            }}
+            out[id] = sum;
+        }}
+    }}"
+    );
+
+    let works_backward = rows * n;
+    let backward_source_code = format!(
+        "__kernel void grad_{out_id}(
+                        __global float* out,
+                        __global float* out_grad,
+                        __global char* q,
+                        __global float* scales,
+                        __global float* a,
+                        __global float* a_grad) {{
+        uint id = get_global_id(0);
+        uint i = id / {n};
+        uint k = id % {n};
+        if(id < {works_backward}) {{
+            out_grad += {p} * i;
+            float sum = 0.0;
+            for(uint j = 0; j < {p}; j++) {{
+                sum += out_grad[j] * {weight};
+            }}
+            a_grad[id] += sum;
+        }}
+    }}"
+    );
+
+    GpuFunction {
+        shared_buffers,
+        forward_funcs: vec![KernelCall {
+            source_code: forward_source_code,
+            kernel_name: format!("calc_{}", out_id),
+            local_work_size: 32,
+            global_work_size: works_forward,
+        }],
+        backward_funcs: vec![KernelCall {
+            source_code: backward_source_code,
+            kernel_name: format!("grad_{}", out_id),
+            local_work_size: 32,
+            global_work_size: works_backward,
+        }],
+    }
+}
@@ -3,56 +3,94 @@ use super::*;
 pub fn gpu_impl(out_id: TensorId, inps: &[Vec<usize>]) -> GpuFunction {
     let n = inps[0][inps[0].len() - 1];
     let works = inps[0][..inps[0].len() - 1].iter().fold(1, |a, b| a * b);
+    let rows = row_count(works);
 
+    // Online softmax: every work-item keeps the maximum of its elements and the sum of their
+    // exponentials relative to it, which are then merged across the work-group. Masked (-inf)
+    // inputs are skipped, so that they never produce `exp(-inf - -inf)`.
     let forward_source_code = format!(
         "__kernel void calc_{out_id}(
                         __global float* out,
                         __global float* a) {{
-        uint id = get_global_id(0);
-        if(id < {works}) {{
-            a += id * {n};
-            out += id * {n};
-            float mx = a[0];
-            for(uint i = 1; i < {n}; i++) {{
-                if(a[i] > mx) {{
-                    mx = a[i];
+        __local float maxs[{MAX_ROW_GROUP}];
+        __local float sums[{MAX_ROW_GROUP}];
+        uint lid = get_local_id(0);
+        uint size = get_local_size(0);
+        uint rows = {rows};
+        for(uint row = get_group_id(0); row < rows; row += get_num_groups(0)) {{
+            __global float* x = a + row * {n};
+            __global float* y = out + row * {n};
+            float mx = -INFINITY;
+            float sum = 0.;
+            for(uint i = lid; i < {n}; i += size) {{
+                float v = x[i];
+                if(v > mx) {{
+                    sum = sum * exp(mx - v) + 1.;
+                    mx = v;
+                }} else if(v > -INFINITY) {{
+                    sum += exp(v - mx);
                 }}
             }}
-            float sum = 0.;
-            for(uint i = 0; i < {n}; i++) {{
-                sum += exp(a[i] - mx);
+            maxs[lid] = mx;
+            sums[lid] = sum;
+            barrier(CLK_LOCAL_MEM_FENCE);
+            for(uint s = size / 2; s > 0; s >>= 1) {{
+                if(lid < s) {{
+                    float other_mx = maxs[lid + s];
+                    float other_sum = sums[lid + s];
+                    if(other_mx > mx) {{
+                        sum = sum * exp(mx - other_mx) + other_sum;
+                        mx = other_mx;
+                    }} else if(other_mx > -INFINITY) {{
+                        sum += other_sum * exp(other_mx - mx);
+                    }}
+                    maxs[lid] = mx;
+                    sums[lid] = sum;
+                }}
+                barrier(CLK_LOCAL_MEM_FENCE);
             }}
-            for(uint i = 0; i < {n}; i++) {{
-                out[i] = exp(a[i] - mx) / sum;
+            mx = maxs[0];
+            sum = sums[0];
+            for(uint i = lid; i < {n}; i += size) {{
+                y[i] = exp(x[i] - mx) / sum;
             }}
+            barrier(CLK_LOCAL_MEM_FENCE);
         }}
     }}"
     );
 
+    // The gradient of each input is `y_i * (g_i - sum_j(y_j * g_j))`
     let backward_source_code = format!(
         "__kernel void grad_{out_id}(
                         __global float* out,
                         __global float* out_grad,
                         __global float* a,
                         __global float* a_grad) {{
-        uint wid = get_global_id(0);
-        uint id = wid / {n};
-        uint i = wid % {n};
-        if(wid < {works} * {n}) {{
-            out += id * {n};
-            out_grad += id * {n};
-            a_grad += id * {n};
-            float si = out[i];
-            float sum = 0.0;
-            for(uint j = 0; j < {n}; j++) {{
-                if(i == j) {{
-                    sum += si * (1. - si) * out_grad[j];
-                }} else {{
-                    float sj = out[j];
-                    sum += -si * sj * out_grad[j];
+        __local float dots[{MAX_ROW_GROUP}];
+        uint lid = get_local_id(0);
+        uint size = get_local_size(0);
+        uint rows = {rows};
+        for(uint row = get_group_id(0); row < rows; row += get_num_groups(0)) {{
+            __global float* y = out + row * {n};
+            __global float* g = out_grad + row * {n};
+            __global float* x_grad = a_grad + row * {n};
+            float dot = 0.;
+            for(uint i = lid; i < {n}; i += size) {{
+                dot += y[i] * g[i];
+            }}
+            dots[lid] = dot;
+            barrier(CLK_LOCAL_MEM_FENCE);
+            for(uint s = size / 2; s > 0; s >>= 1) {{
+                if(lid < s) {{
+                    dots[lid] += dots[lid + s];
                 }}
+                barrier(CLK_LOCAL_MEM_FENCE);
+            }}
+            dot = dots[0];
+            for(uint i = lid; i < {n}; i += size) {{
+                x_grad[i] += y[i] * (g[i] - dot);
             }}
-            a_grad[i] += sum;
+            barrier(CLK_LOCAL_MEM_FENCE);
         }}
     }}"
     );
@@ -61,14 +99,14 @@ pub fn gpu_impl(out_id: TensorId, inps: &[Vec<usize>]) -> GpuFunction {
         forward_funcs: vec![KernelCall {
             source_code: forward_source_code,
             kernel_name: format!("calc_{}", out_id),
-            local_work_size: 32,
-            global_work_size: works,
+            local_work_size: ROW_GROUP,
+            global_work_size: works * ROW_GROUP,
         }],
         backward_funcs: vec![KernelCall {
             source_code: backward_source_code,
             kernel_name: format!("grad_{}", out_id),
-            local_work_size: 32,
-            global_work_size: works * n,
+            local_work_size: ROW_GROUP,
+            global_work_size: works * ROW_GROUP,
         }],
         shared_buffers: vec![],
     }
@@ -0,0 +1,178 @@
+use super::Function;
+use crate::tensor::*;
+
+/// Contrastive loss of sentence embeddings (InfoNCE, as in SimCSE). Inputs are the embeddings of
+/// `n` anchors and of their positives, two `[n, degree]` tensors, the `i`-th positive going with
+/// the `i`-th anchor. The others are its in-batch negatives: the loss of each anchor is the
+/// cross-entropy of picking its positive among all of them, by their cosine similarities divided
+/// by the `temperature`. Lower temperatures push the negatives further apart.
+#[derive(Debug, Clone)]
+pub struct InfoNce {
+    temperature: f32,
+}
+impl InfoNce {
+    pub fn new(temperature: f32) -> Box<dyn Function> {
+        Box::new(Self { temperature })
+    }
+}
+
+// The rows of `t`, scaled to unit lengths, and their lengths
+fn normalize(t: &Tensor<f32>) -> Result<(Vec<Vec<f32>>, Vec<f32>), TensorError> {
+    if t.dim() != 2 {
+        return Err(TensorError::UnexpectedShape);
+    }
+    let mut rows = Vec::with_capacity(t.shape()[0]);
+    let mut norms = Vec::with_capacity(t.shape()[0]);
+    for row in t.blob().chunks(t.shape()[1]) {
+        let norm = row.iter().map(|v| v * v).sum::<f32>().sqrt().max(1e-6);
+        rows.push(row.iter().map(|v| v / norm).collect());
+        norms.push(norm);
+    }
+    Ok((rows, norms))
+}
+
+// Gradient of a row given the one of its normalized version `x`, `norm` being its length
+fn denormalize_grad(x: &[f32], norm: f32, grad: &[f32]) -> Vec<f32> {
+    let dot = x.iter().zip(grad).map(|(x, g)| x * g).sum::<f32>();
+    x.iter()
+        .zip(grad)
+        .map(|(x, g)| (g - x * dot) / norm)
+        .collect()
+}
+
+// The normalized inputs of the loss, shared by the forward and backward passes
+struct Batch {
+    anchors: Vec<Vec<f32>>,
+    anchor_norms: Vec<f32>,
+    positives: Vec<Vec<f32>>,
+    positive_norms: Vec<f32>,
+    // Similarity of every anchor to every positive, divided by the temperature
+    sims: Vec<Vec<f32>>,
+}
+
+impl Batch {
+    fn new(inps: &[&GeneralTensor], temperature: f32) -> Result<Self, TensorError> {
+        let anchors = inps[0].as_float()?;
+        let positives = inps[1].as_float()?;
+        if anchors.shape() != positives.shape() {
+            return Err(TensorError::UnexpectedShape);
+        }
+        let (anchors, anchor_norms) = normalize(anchors)?;
+        let (positives, positive_norms) = normalize(positives)?;
+        let sims = anchors
+            .iter()
+            .map(|a| {
+                positives
+                    .iter()
+                    .map(|p| a.iter().zip(p).map(|(a, p)| a * p).sum::<f32>() / temperature)
+                    .collect()
+            })
+            .collect();
+        Ok(Self {
+            anchors,
+            anchor_norms,
+            positives,
+            positive_norms,
+            sims,
+        })
+    }
+}
+
+// `log(sum(exp(s)))`, shifted by the largest similarity so that the exponentials can't overflow
+fn log_sum_exp(s: &[f32]) -> f32 {
+    let max = s.iter().fold(f32::NEG_INFINITY, |a, b| a.max(*b));
+    max + s.iter().map(|s| (s - max).exp()).sum::<f32>().ln()
+}
+
+impl Function for InfoNce {
+    fn run(
+        &mut self,
+        inps: &[&GeneralTensor],
+        _training: bool,
+    ) -> Result<Tensor<f32>, TensorError> {
+        let batch = Batch::new(inps, self.temperature)?;
+        Tensor::raw(
+            &[batch.sims.len()],
+            batch
+                .sims
+                .iter()
+                .enumerate()
+                .map(|(i, row)| log_sum_exp(row) - row[i])
+                .collect(),
+        )
+    }
+    fn grad(
+        &self,
+        inps: &[&GeneralTensor],
+        out_grad: &Tensor<f32>,
+    ) -> Result<Vec<Tensor<f32>>, TensorError> {
+        let batch = Batch::new(inps, self.temperature)?;
+        let (n, degree) = (batch.anchors.len(), inps[0].as_float()?.shape()[1]);
+        let mut anchor_grads = vec![vec![0.; degree]; n];
+        let mut positive_grads = vec![vec![0.; degree]; n];
+        for (i, (row, g)) in batch.sims.iter().zip(out_grad.blob()).enumerate() {
+            let log_z = log_sum_exp(row);
+            for (j, s) in row.iter().enumerate() {
+                let onehot = if i == j { 1. } else { 0. };
+                let d = ((s - log_z).exp() - onehot) * g / self.temperature;
+                for k in 0..degree {
+                    anchor_grads[i][k] += d * batch.positives[j][k];
+                    positive_grads[j][k] += d * batch.anchors[i][k];
+                }
+            }
+        }
+        let grad = |rows: &[Vec<f32>], norms: &[f32], grads: &[Vec<f32>]| {
+            Tensor::raw(
+                &[n, degree],
+                rows.iter()
+                    .zip(norms)
+                    .zip(grads)
+                    .flat_map(|((x, norm), g)| denormalize_grad(x, *norm, g))
+                    .collect(),
+            )
+        };
+        Ok(vec![
+            grad(&batch.anchors, &batch.anchor_norms, &anchor_grads)?,
+            grad(&batch.positives, &batch.positive_norms, &positive_grads)?,
+        ])
+    }
+    fn clone_box(&self) -> Box<dyn Function> {
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_infonce() {
+        let anchors =
+            GeneralTensor::Float(Tensor::raw(&[3, 2], vec![1., 0., 0., 1., -1., 0.]).unwrap());
+        // The positives point the way of their anchors, whatever their lengths
+        let positives =
+            GeneralTensor::Float(Tensor::raw(&[3, 2], vec![2., 0., 0., 0.5, -3., 0.]).unwrap());
+        let aligned = InfoNce::new(0.1)
+            .run(&[&anchors, &positives], false)
+            .unwrap();
+        // Swapping two positives makes their anchors closer to negatives
+        let swapped =
+            GeneralTensor::Float(Tensor::raw(&[3, 2], vec![0., 0.5, 2., 0., -3., 0.]).unwrap());
+        let misaligned = InfoNce::new(0.1).run(&[&anchors, &swapped], false).unwrap();
+        assert!(aligned.blob().iter().all(|l| *l < 1e-3));
+        assert!(misaligned.blob()[..2].iter().all(|l| *l > 1.));
+        assert!(misaligned.blob()[2] < 1e-3);
+
+        // Only the directions matter, the gradients are orthogonal to the embeddings
+        let grads = InfoNce::new(0.5)
+            .grad(&[&anchors, &swapped], &Tensor::constant(&[3], 1.))
+            .unwrap();
+        for (grad, inp) in grads.iter().zip([&anchors, &swapped]) {
+            let inp = inp.as_float().unwrap();
+            for (g, x) in grad.blob().chunks(2).zip(inp.blob().chunks(2)) {
+                assert!((g[0] * x[0] + g[1] * x[1]).abs() < 1e-5);
+            }
+        }
+        assert!(grads[0].blob().iter().any(|g| g.abs() > 1e-3));
+    }
+}
@@ -3,46 +3,94 @@ use crate::tensor::*;
 
 #[cfg(feature = "gpu")]
 use super::{gpu, GpuFunction, TensorId};
+use std::borrow::Cow;
 
+/// Matrix multiplication of the last two dimensions of its inputs, either of which may be read
+/// transposed (Instead of being transposed by a separate `Transpose`).
 #[derive(Debug, Clone)]
-pub struct MatMul;
+pub struct MatMul {
+    transpose_a: bool,
+    transpose_b: bool,
+}
 impl MatMul {
     pub fn new() -> Box<dyn Function> {
-        Box::new(Self {})
+        Self::transposed(false, false)
+    }
+    pub fn transposed(transpose_a: bool, transpose_b: bool) -> Box<dyn Function> {
+        Box::new(Self {
+            transpose_a,
+            transpose_b,
+        })
     }
 }
+
+// The operand as it's multiplied
+fn oriented(t: &Tensor<f32>, transposed: bool) -> Result<Cow<'_, Tensor<f32>>, TensorError> {
+    Ok(if transposed {
+        Cow::Owned(t.transpose()?)
+    } else {
+        Cow::Borrowed(t)
+    })
+}
+
 impl Function for MatMul {
+    fn op_name(&self) -> String {
+        match (self.transpose_a, self.transpose_b) {
+            (false, false) => "MatMul",
+            (true, false) => "MatMul(a^T)",
+            (false, true) => "MatMul(b^T)",
+            (true, true) => "MatMul(a^T, b^T)",
+        }
+        .into()
+    }
     fn run(
         &mut self,
         inps: &[&GeneralTensor],
         _training: bool,
     ) -> Result<Tensor<f32>, TensorError> {
-        let inps = inps
-            .iter()
-            .map(|t| t.as_float())
-            .collect::<Result<Vec<_>, TensorError>>()?;
-        inps[0] ^ inps[1]
+        let a = oriented(inps[0].as_float()?, self.transpose_a)?;
+        let b = oriented(inps[1].as_float()?, self.transpose_b)?;
+        &*a ^ &*b
     }
     fn grad(
         &self,
         inps: &[&GeneralTensor],
         out_grad: &Tensor<f32>,
     ) -> Result<Vec<Tensor<f32>>, TensorError> {
-        let inps = inps
-            .iter()
-            .map(|t| t.as_float())
-            .collect::<Result<Vec<_>, TensorError>>()?;
+        let a = oriented(inps[0].as_float()?, self.transpose_a)?;
+        let b = oriented(inps[1].as_float()?, self.transpose_b)?;
+        let a_grad = (out_grad ^ &b.transpose()?)?;
+        let b_grad = (&a.transpose()? ^ out_grad)?;
         Ok(vec![
-            (out_grad ^ &inps[1].transpose()?)?,
-            (&inps[0].transpose()? ^ out_grad)?,
+            if self.transpose_a {
+                a_grad.transpose()?
+            } else {
+                a_grad
+            },
+            if self.transpose_b {
+                b_grad.transpose()?
+            } else {
+                b_grad
+            },
         ])
     }
+    fn with_transposed_inputs(&self, transposed: &[bool]) -> Option<Box<dyn Function>> {
+        Some(Self::transposed(
+            self.transpose_a ^ transposed[0],
+            self.transpose_b ^ transposed[1],
+        ))
+    }
     fn clone_box(&self) -> Box<dyn Function> {
         Box::new(self.clone())
     }
 
     #[cfg(feature = "gpu")]
-    fn gpu_impl(&self, out_id: TensorId, inps: &[Vec<usize>]) -> GpuFunction {
-        gpu::matmul::gpu_impl(out_id, inps)
+    fn gpu_impl(&self, out_id: TensorId, inps: &[Vec<usize>]) -> Option<GpuFunction> {
+        Some(gpu::matmul::gpu_impl(
+            out_id,
+            inps,
+            self.transpose_a,
+            self.transpose_b,
+        ))
     }
 }
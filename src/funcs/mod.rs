@@ -5,17 +5,23 @@ use crate::graph::TensorId;
 mod gpu;
 
 #[cfg(feature = "gpu")]
-pub use gpu::{GpuFunction, KernelCall, SharedBuffer};
+pub use gpu::{GpuFunction, KernelCall, SharedBuffer, PHILOX_SOURCE};
 
 mod add;
 mod cat;
 mod coeff;
 mod crossentropy;
+mod distillation;
+mod documentmask;
+mod dot;
 mod dropout;
 mod embedding;
+mod fused;
 mod gelu;
+mod infonce;
 mod layer_norm;
 mod matmul;
+mod quantized_matmul;
 mod relu;
 mod softmax;
 mod transpose;
@@ -25,11 +31,17 @@ pub use add::*;
 pub use cat::*;
 pub use coeff::*;
 pub use crossentropy::*;
+pub use distillation::*;
+pub use documentmask::*;
+pub use dot::*;
 pub use dropout::*;
 pub use embedding::*;
+pub use fused::*;
 pub use gelu::*;
+pub use infonce::*;
 pub use layer_norm::*;
 pub use matmul::*;
+pub use quantized_matmul::*;
 pub use relu::*;
 pub use softmax::*;
 pub use transpose::*;
@@ -37,15 +49,45 @@ pub use trilmask::*;
 
 use super::tensor::*;
 
+/// An operation of a graph (See `Graph::call`). Besides the operations of this module, crates can
+/// implement their own: `run` computes the output from the inputs, `grad` the gradients of the
+/// float inputs from the gradient of the output (One per input, usize inputs get a placeholder),
+/// and `gpu_impl` the OpenCL kernels doing both on GPUs, if any. `graph::gradcheck` compares
+/// `grad` with numerical gradients.
 pub trait Function: std::fmt::Debug {
     fn clone_box(&self) -> Box<dyn Function>;
+    /// Name of the operation (e.g. `MatMul`), used when inspecting graphs.
+    fn op_name(&self) -> String {
+        format!("{:?}", self)
+            .split(|c: char| !c.is_alphanumeric() && c != '_')
+            .next()
+            .unwrap_or_default()
+            .to_string()
+    }
     fn run(&mut self, inps: &[&GeneralTensor], training: bool) -> Result<Tensor<f32>, TensorError>;
     fn grad(
         &self,
         inps: &[&GeneralTensor],
         out_grad: &Tensor<f32>,
     ) -> Result<Vec<Tensor<f32>>, TensorError>;
+    /// The operation as a stage of a `Fused` chain, None if it doesn't apply elementwise.
+    fn elementwise(&self) -> Option<Elementwise> {
+        None
+    }
+    /// Whether the operation only swaps the last two dimensions of its input.
+    fn is_transpose(&self) -> bool {
+        false
+    }
+    /// The operation reading the flagged inputs transposed, None if it can't (See
+    /// `Graph::fold_transposes`).
+    fn with_transposed_inputs(&self, _transposed: &[bool]) -> Option<Box<dyn Function>> {
+        None
+    }
 
+    /// Kernels computing the output of tensor `out_id` and the gradients of its inputs (See
+    /// `GpuFunction`), None if the operation only runs on CPUs.
     #[cfg(feature = "gpu")]
-    fn gpu_impl(&self, _out_id: TensorId, _inp_shapes: &[Vec<usize>]) -> GpuFunction;
+    fn gpu_impl(&self, _out_id: TensorId, _inp_shapes: &[Vec<usize>]) -> Option<GpuFunction> {
+        None
+    }
 }
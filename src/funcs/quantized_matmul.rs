@@ -0,0 +1,42 @@
+use super::Function;
+use crate::tensor::*;
+use std::sync::Arc;
+
+#[cfg(feature = "gpu")]
+use super::{gpu, GpuFunction, TensorId};
+
+// Multiplies its input by a constant quantized weight matrix. The weights live inside the
+// computation instead of the graph, so they are never updated by the optimizer.
+#[derive(Debug, Clone)]
+pub struct QuantizedMatMul {
+    weights: Arc<QuantizedTensor>,
+}
+impl QuantizedMatMul {
+    pub fn new(weights: Arc<QuantizedTensor>) -> Box<dyn Function> {
+        Box::new(Self { weights })
+    }
+}
+impl Function for QuantizedMatMul {
+    fn run(
+        &mut self,
+        inps: &[&GeneralTensor],
+        _training: bool,
+    ) -> Result<Tensor<f32>, TensorError> {
+        self.weights.matmul(inps[0].as_float()?)
+    }
+    fn grad(
+        &self,
+        _inps: &[&GeneralTensor],
+        out_grad: &Tensor<f32>,
+    ) -> Result<Vec<Tensor<f32>>, TensorError> {
+        Ok(vec![self.weights.matmul_transposed(out_grad)?])
+    }
+    fn clone_box(&self) -> Box<dyn Function> {
+        Box::new(self.clone())
+    }
+
+    #[cfg(feature = "gpu")]
+    fn gpu_impl(&self, out_id: TensorId, inps: &[Vec<usize>]) -> Option<GpuFunction> {
+        Some(gpu::quantized_matmul::gpu_impl(out_id, inps, &self.weights))
+    }
+}
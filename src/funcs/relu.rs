@@ -1,4 +1,4 @@
-use super::Function;
+use super::{Elementwise, Function};
 use crate::tensor::*;
 
 #[cfg(feature = "gpu")]
@@ -35,12 +35,15 @@ impl Function for Relu {
         let der = inps[0].map_values(|f| if f > 0. { 1. } else { 0.01 });
         Ok(vec![(&der * out_grad)?])
     }
+    fn elementwise(&self) -> Option<Elementwise> {
+        Some(Elementwise::Relu)
+    }
     fn clone_box(&self) -> Box<dyn Function> {
         Box::new(self.clone())
     }
 
     #[cfg(feature = "gpu")]
-    fn gpu_impl(&self, out_id: TensorId, inps: &[Vec<usize>]) -> GpuFunction {
-        gpu::relu::gpu_impl(out_id, inps)
+    fn gpu_impl(&self, out_id: TensorId, inps: &[Vec<usize>]) -> Option<GpuFunction> {
+        Some(gpu::relu::gpu_impl(out_id, inps))
     }
 }
@@ -31,12 +31,15 @@ impl Function for Transpose {
     ) -> Result<Vec<Tensor<f32>>, TensorError> {
         Ok(vec![out_grad.transpose()?])
     }
+    fn is_transpose(&self) -> bool {
+        true
+    }
     fn clone_box(&self) -> Box<dyn Function> {
         Box::new(self.clone())
     }
 
     #[cfg(feature = "gpu")]
-    fn gpu_impl(&self, out_id: TensorId, inps: &[Vec<usize>]) -> GpuFunction {
-        gpu::transpose::gpu_impl(out_id, inps)
+    fn gpu_impl(&self, out_id: TensorId, inps: &[Vec<usize>]) -> Option<GpuFunction> {
+        Some(gpu::transpose::gpu_impl(out_id, inps))
     }
 }
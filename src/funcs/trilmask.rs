@@ -56,7 +56,7 @@ impl Function for TrilMask {
     }
 
     #[cfg(feature = "gpu")]
-    fn gpu_impl(&self, out_id: TensorId, inps: &[Vec<usize>]) -> GpuFunction {
-        gpu::trilmask::gpu_impl(out_id, inps, self.n)
+    fn gpu_impl(&self, out_id: TensorId, inps: &[Vec<usize>]) -> Option<GpuFunction> {
+        Some(gpu::trilmask::gpu_impl(out_id, inps, self.n))
     }
 }
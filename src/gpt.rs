@@ -1,534 +1,4646 @@
+use crate::constraint::Constraint;
+use crate::diagnostics::repetition_rate;
 use crate::funcs::*;
-use crate::graph::{Graph, GraphError, TensorId};
-use crate::optimizer::{Optimizer, OptimizerState};
-use crate::tensor::{GeneralTensor, Tensor, TensorError, TensorOps};
-use rand::Rng;
+use crate::graph::{AnyGraph, Graph, GraphError, Profile, TensorId};
+use crate::optimizer::{LossScaler, Optimizer, OptimizerState};
+use crate::sampler::{
+    document_indices, isolate_padding, sample_dataset, Batch, Corpus, EpochSampler, Masking,
+    SamplerState, Sampling, IGNORED_TARGET,
+};
+use crate::tensor::{
+    GeneralTensor, Precision, QuantFormat, QuantizedTensor, Tensor, TensorError, TensorOps,
+};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::time::Instant;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use tracing::{debug, info, info_span, warn};
+
+/// Errors of building, running and training a `GPT`: the ones of its graph, and of the settings
+/// and prompts it's given.
+#[derive(Error, Debug)]
+pub enum GptError {
+    #[error(transparent)]
+    Graph(#[from] GraphError),
+    #[error("model has no lora adapters!")]
+    LoraNotEnabled,
+    #[error("invalid model configuration: {0}")]
+    InvalidConfig(String),
+    #[error("no tensor is named {0} (see `femto graph-dump`)")]
+    UnknownTensor(String),
+    #[error("prompt {0} is empty")]
+    EmptyPrompt(usize),
+    #[error("prompt {0} has {1} tokens, more than the context of {2} (see `ContextOverflow`)")]
+    PromptTooLong(usize, usize, usize),
+    #[error("every token is banned or not allowed")]
+    NoAllowedTokens,
+}
+
+impl From<TensorError> for GptError {
+    fn from(e: TensorError) -> Self {
+        Self::Graph(e.into())
+    }
+}
+
+impl GptError {
+    /// Whether a device ran out of memory (See `GraphError::is_out_of_memory`).
+    pub fn is_out_of_memory(&self) -> bool {
+        matches!(self, Self::Graph(e) if e.is_out_of_memory())
+    }
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TrainingState {
     pub tensors: HashMap<String, Tensor<f32>>,
     pub optimizer: OptimizerState,
+    /// Position of the epoch-based sampling of the training windows, if used (Not part of the
+    /// legacy bincode format)
+    #[serde(skip)]
+    pub sampler: Option<SamplerState>,
+    /// Tokens and time trained on so far (Not part of the legacy bincode format either)
+    #[serde(skip)]
+    pub progress: Progress,
+    /// What the model was trained on and how well (Not part of the legacy bincode format)
+    #[serde(skip)]
+    pub provenance: Provenance,
 }
 
-pub struct GPT<G: Graph> {
-    graph: G,
-    num_tokens: usize,
-    token_input: TensorId,
-    pos_input: TensorId,
-    output: TensorId,
-    expected_output: TensorId,
-    loss: TensorId,
-    pos_input_fixed: Tensor<f32>,
+/// Inference-only weights, where the matrices of linear layers are quantized (8 or 4 bits) and
+/// the remaining (small) tensors are kept in f32.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QuantizedState {
+    pub tensors: HashMap<String, Tensor<f32>>,
+    pub quantized: HashMap<String, QuantizedTensor>,
 }
 
-fn sample_dataset<R: Rng>(
-    dataset: &[usize],
-    batch_size: usize,
-    context_size: usize,
-    rng: &mut R,
-) -> (Tensor<usize>, Tensor<usize>) {
-    let mut xs: Vec<usize> = Vec::with_capacity(batch_size * context_size);
-    let mut ys: Vec<usize> = Vec::with_capacity(batch_size * context_size);
-    for _i in 0..batch_size {
-        let start: usize = rng.gen_range(0..dataset.len());
-        let all = dataset
-            .iter()
-            .cycle()
-            .skip(start)
-            .take(context_size + 1)
-            .cloned()
-            .collect::<Vec<_>>();
-        xs.extend(&all[0..context_size]);
-        ys.extend(&all[1..context_size + 1]);
+/// Layout of the transformer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Architecture {
+    /// Fixed sinusoidal positional encodings, normalization after the residual connections
+    #[default]
+    Femto,
+    /// GPT-2 compatible: learned positional embeddings, normalization before the attention and
+    /// feed-forward blocks (Pre-LN), and biased key/query/value projections
+    Gpt2,
+}
+
+impl std::str::FromStr for Architecture {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "femto" => Ok(Architecture::Femto),
+            "gpt2" => Ok(Architecture::Gpt2),
+            _ => Err(format!("expected `femto` or `gpt2`, got `{}`", s)),
+        }
     }
+}
 
-    (
-        Tensor::raw(&[batch_size, context_size], xs).unwrap(),
-        Tensor::raw(&[batch_size, context_size], ys).unwrap(),
-    )
+/// Decides how far the backward pass travels from the loss.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BackwardScope {
+    /// Backpropagate through the whole graph
+    #[default]
+    Full,
+    /// Truncated backpropagation: only the output head and the last n transformer layers get
+    /// gradients, earlier layers stay frozen
+    LastLayers(usize),
+    /// Skip computations that do not lead to a trainable parameter
+    ParamsOnly,
 }
 
-fn select<R: Rng, T: TensorOps<f32>>(
-    rng: &mut R,
-    t: &T,
-    temperature: f32,
-) -> Result<usize, TensorError> {
-    let t = Softmax::new().run(
-        &[&GeneralTensor::Float(Tensor::<f32>::raw(
-            t.shape(),
-            t.blob().to_vec(),
-        )?)],
-        false,
-    )?;
-    let mut ts = t.blob().iter().cloned().enumerate().collect::<Vec<_>>();
-    ts.sort_by_key(|(_, b)| (b * 1000.) as usize);
-    let dice = rng.gen_range(0.0..temperature);
-    let mut accum = 0.;
-    for (id, t) in ts.iter().rev() {
-        accum += t;
-        if dice < accum {
-            return Ok(*id);
+impl std::str::FromStr for BackwardScope {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "full" => Ok(BackwardScope::Full),
+            "params-only" => Ok(BackwardScope::ParamsOnly),
+            _ => s
+                .strip_prefix("last-layers:")
+                .and_then(|n| n.parse::<usize>().ok())
+                .map(BackwardScope::LastLayers)
+                .ok_or(format!(
+                    "expected `full`, `params-only` or `last-layers:<n>`, got `{}`",
+                    s
+                )),
         }
     }
-    panic!();
 }
 
-fn pos_encode_inter(num_tokens: usize, embedding_size: usize) -> Tensor<f32> {
-    let mut raw_new = Vec::new();
-    let cols = embedding_size;
-    let rows = num_tokens;
-    for row in 0..rows {
-        for col in 0..cols {
-            let k = row as f32;
-            let i = (col / 2) as f32;
-            let factor = 10000f32.powf(2f32 * i / embedding_size as f32);
+/// How `GPT::embed` reduces the hidden states of the tokens of a text to a single vector.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Pooling {
+    /// Average over all the tokens
+    #[default]
+    Mean,
+    /// The state of the last token, the only one that attends to the whole text
+    Last,
+}
 
-            let pos = if col % 2 == 0 {
-                (k / factor).sin()
-            } else {
-                (k / factor).cos()
-            };
+impl Pooling {
+    // Reduces the hidden states of the tokens of a text
+    fn pool(&self, mut states: Vec<Vec<f32>>) -> Vec<f32> {
+        match self {
+            Pooling::Mean => {
+                let mut sum = vec![0.; states[0].len()];
+                for state in states.iter() {
+                    for (s, v) in sum.iter_mut().zip(state) {
+                        *s += v;
+                    }
+                }
+                sum.iter().map(|s| s / states.len() as f32).collect()
+            }
+            Pooling::Last => states.pop().unwrap(),
+        }
+    }
 
-            raw_new.push(pos);
+    // How much the state of each of `len` tokens weighs in the pooled vector
+    fn weights(&self, len: usize) -> Vec<f32> {
+        match self {
+            Pooling::Mean => vec![1. / len as f32; len],
+            Pooling::Last => (0..len)
+                .map(|i| if i + 1 == len { 1. } else { 0. })
+                .collect(),
         }
     }
+}
 
-    Tensor::raw(&[rows, cols], raw_new).unwrap()
+impl std::str::FromStr for Pooling {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "mean" => Ok(Pooling::Mean),
+            "last" => Ok(Pooling::Last),
+            _ => Err(format!("expected `mean` or `last`, got `{}`", s)),
+        }
+    }
 }
 
-impl<G: Graph> GPT<G> {
-    pub fn new<R: Rng>(
+/// What inference does with prompts longer than the context of the model. Generations longer
+/// than the context always slide it, the model seeing the last `num_tokens` tokens.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ContextOverflow {
+    /// Fail with `GptError::PromptTooLong`
+    #[default]
+    Error,
+    /// Drop the first tokens of the prompt
+    TruncateLeft,
+    /// Drop the last tokens of the prompt
+    TruncateRight,
+    /// Keep the whole prompt, the model only seeing its last tokens
+    Sliding,
+}
+
+impl std::str::FromStr for ContextOverflow {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "error" => Ok(ContextOverflow::Error),
+            "truncate-left" => Ok(ContextOverflow::TruncateLeft),
+            "truncate-right" => Ok(ContextOverflow::TruncateRight),
+            "sliding" => Ok(ContextOverflow::Sliding),
+            _ => Err(format!(
+                "expected `error`, `truncate-left`, `truncate-right` or `sliding`, got `{}`",
+                s
+            )),
+        }
+    }
+}
+
+/// Configuration of the low-rank adapters injected into the attention projections. The
+/// adapter output is scaled by `alpha / rank`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LoraConfig {
+    pub rank: usize,
+    pub alpha: f32,
+}
+
+impl LoraConfig {
+    fn scale(&self) -> f32 {
+        self.alpha / self.rank as f32
+    }
+}
+
+/// Configuration of knowledge distillation from a teacher model (See `GPT::set_teacher` and
+/// `Distillation`): the loss blends the cross-entropy (With weight `1 - alpha`) with the KL
+/// divergence from the teacher's distribution, both softened by `temperature`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DistillConfig {
+    pub temperature: f32,
+    pub alpha: f32,
+}
+
+/// Configuration of the contrastive training of sentence embeddings (See
+/// `GPT::train_contrastive` and `InfoNce`): the embeddings `GPT::embed` gives with `pooling` are
+/// compared by their cosine similarities divided by `temperature`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ContrastiveConfig {
+    pub temperature: f32,
+    pub pooling: Pooling,
+}
+
+impl Default for ContrastiveConfig {
+    fn default() -> Self {
+        Self {
+            temperature: 0.05,
+            pooling: Pooling::Mean,
+        }
+    }
+}
+
+/// Configuration of direct preference optimization (See `GPT::train_dpo`): `beta` scales the
+/// log-probability ratios of the completions between the model and its reference, higher values
+/// keeping the model closer to the reference.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DpoConfig {
+    pub beta: f32,
+}
+
+impl Default for DpoConfig {
+    fn default() -> Self {
+        Self { beta: 0.1 }
+    }
+}
+
+/// An example of `GPT::train_dpo`: the tokens of a prompt, and of two completions of it, the
+/// `chosen` one being preferred over the `rejected` one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Preference {
+    pub prompt: Vec<usize>,
+    pub chosen: Vec<usize>,
+    pub rejected: Vec<usize>,
+}
+
+/// Settings of a `GPT`, which `GPT::new` builds. Defaults to the model of the `femto` command
+/// line: 4 layers of 4 heads, 64-dimensional embeddings and a 64-token context. Only the
+/// vocabulary size has to be set.
+#[derive(Debug, Clone)]
+pub struct GptBuilder<'a> {
+    batch_size: Option<usize>,
+    vocab_size: usize,
+    embedding_degree: usize,
+    num_tokens: usize,
+    num_layers: usize,
+    num_heads: usize,
+    head_size: Option<usize>,
+    dropout: f32,
+    label_smoothing: f32,
+    z_loss: f32,
+    class_weights: Option<Vec<f32>>,
+    architecture: Architecture,
+    document_separator: Option<usize>,
+    pad_token: Option<usize>,
+    masked_lm: Option<Masking>,
+    distillation: Option<DistillConfig>,
+    contrastive: Option<ContrastiveConfig>,
+    dpo: Option<DpoConfig>,
+    lora: Option<LoraConfig>,
+    quantized: Option<&'a QuantizedState>,
+}
+
+impl<'a> GptBuilder<'a> {
+    pub fn new() -> Self {
+        Self {
+            batch_size: None,
+            vocab_size: 0,
+            embedding_degree: 64,
+            num_tokens: 64,
+            num_layers: 4,
+            num_heads: 4,
+            head_size: None,
+            dropout: 0.0,
+            label_smoothing: 0.0,
+            z_loss: 0.0,
+            class_weights: None,
+            architecture: Architecture::Femto,
+            document_separator: None,
+            pad_token: None,
+            masked_lm: None,
+            distillation: None,
+            contrastive: None,
+            dpo: None,
+            lora: None,
+            quantized: None,
+        }
+    }
+    /// Number of sequences per batch, which GPU graphs need to pre-allocate. CPU graphs process
+    /// single sequences (None, the default), see `GPT::new`.
+    pub fn batch_size(mut self, batch_size: impl Into<Option<usize>>) -> Self {
+        self.batch_size = batch_size.into();
+        self
+    }
+    pub fn vocab_size(mut self, vocab_size: usize) -> Self {
+        self.vocab_size = vocab_size;
+        self
+    }
+    pub fn embedding_degree(mut self, embedding_degree: usize) -> Self {
+        self.embedding_degree = embedding_degree;
+        self
+    }
+    /// Number of tokens the model sees at once.
+    pub fn context(mut self, num_tokens: usize) -> Self {
+        self.num_tokens = num_tokens;
+        self
+    }
+    pub fn layers(mut self, num_layers: usize) -> Self {
+        self.num_layers = num_layers;
+        self
+    }
+    pub fn heads(mut self, num_heads: usize) -> Self {
+        self.num_heads = num_heads;
+        self
+    }
+    /// Defaults to the embedding degree divided by the number of heads.
+    pub fn head_size(mut self, head_size: usize) -> Self {
+        self.head_size = Some(head_size);
+        self
+    }
+    pub fn dropout(mut self, dropout: f32) -> Self {
+        self.dropout = dropout;
+        self
+    }
+    pub fn label_smoothing(mut self, label_smoothing: f32) -> Self {
+        self.label_smoothing = label_smoothing;
+        self
+    }
+    pub fn z_loss(mut self, z_loss: f32) -> Self {
+        self.z_loss = z_loss;
+        self
+    }
+    /// Weights of the loss of the positions whose target is each token of the vocabulary, e.g. to
+    /// rebalance rare tokens, or to leave some out of the loss with a zero weight.
+    pub fn class_weights(mut self, class_weights: impl Into<Option<Vec<f32>>>) -> Self {
+        self.class_weights = class_weights.into();
+        self
+    }
+    pub fn architecture(mut self, architecture: Architecture) -> Self {
+        self.architecture = architecture;
+        self
+    }
+    /// Token separating the documents of the dataset, tokens then only attend to the ones of
+    /// their own document, so that windows packing several short documents don't mix them.
+    pub fn document_separator(mut self, separator: impl Into<Option<usize>>) -> Self {
+        self.document_separator = separator.into();
+        self
+    }
+    /// Token padding the windows of documents shorter than the context (See `sampler::Padded`),
+    /// which the other tokens then don't attend to.
+    pub fn pad_token(mut self, pad: impl Into<Option<usize>>) -> Self {
+        self.pad_token = pad.into();
+        self
+    }
+    /// Makes a bidirectional masked language model (Like BERT) instead of a causal one: tokens
+    /// attend to the whole window, and the training loops predict the tokens `masking` hides
+    /// rather than the next ones. Such models can't generate text.
+    pub fn masked_lm(mut self, masking: impl Into<Option<Masking>>) -> Self {
+        self.masked_lm = masking.into();
+        self
+    }
+    /// Trains on the logits of a teacher model too, given by `GPT::set_teacher`. The loss then
+    /// ignores the label smoothing, z-loss and class weights settings.
+    pub fn distillation(mut self, distillation: impl Into<Option<DistillConfig>>) -> Self {
+        self.distillation = distillation.into();
+        self
+    }
+    /// Lets `GPT::train_contrastive` fine-tune the embeddings of the model, adding the inputs it
+    /// needs to the graph.
+    pub fn contrastive(mut self, contrastive: impl Into<Option<ContrastiveConfig>>) -> Self {
+        self.contrastive = contrastive.into();
+        self
+    }
+    /// Lets `GPT::train_dpo` train the model on preferences, adding the weights of the loss of
+    /// every token to the graph. The loss then ignores the label smoothing, z-loss and class
+    /// weights settings.
+    pub fn dpo(mut self, dpo: impl Into<Option<DpoConfig>>) -> Self {
+        self.dpo = dpo.into();
+        self
+    }
+    pub fn lora(mut self, lora: impl Into<Option<LoraConfig>>) -> Self {
+        self.lora = lora.into();
+        self
+    }
+    pub fn quantized(mut self, quantized: impl Into<Option<&'a QuantizedState>>) -> Self {
+        self.quantized = quantized.into();
+        self
+    }
+    pub fn build<G: Graph>(&self, graph: G) -> Result<GPT<G>, GptError> {
+        self.build_with_rng(&mut rand::thread_rng(), graph)
+    }
+    /// Builds the model, initializing its parameters with `rng`.
+    pub fn build_with_rng<R: Rng, G: Graph>(
+        &self,
         rng: &mut R,
-        mut g: G,
-        batch_size: Option<usize>,
-        vocab_size: usize,
-        embedding_degree: usize,
-        num_tokens: usize,
-        num_layers: usize,
-        num_heads: usize,
-        head_size: usize,
-        dropout: f32,
-    ) -> Result<Self, GraphError> {
-        // Mapping each token to a `embedding_degree` dimension space through a lookup table
-        let token_embedding = g.alloc(
-            Tensor::<f32>::rand(rng, &[vocab_size, embedding_degree]),
-            true,
-            "token_embedding".into(),
-        )?;
+        graph: G,
+    ) -> Result<GPT<G>, GptError> {
+        GPT::new(rng, graph, self)
+    }
 
-        // Token inputs. We will get `num_tokens` tokens as inputs and will have `num_tokens`
-        // outputs.
-        // In the case of CPU training, it's much more efficient to parallelize over instances
-        // in a single batch. (I.e. it's not very efficient to parallelize a matrix multiplication
-        // operation on CPUs. Better approach is to process a 32-instanced batch on a 32-core CPU,
-        // where each instance runs on its own core, without parallelizing operations)
-        // That's why we DO NOT specify a `batch_size` when training on a CPU.
-        let token_input = g.alloc_usize(
-            Tensor::<usize>::zeros(&if let Some(batch_size) = batch_size {
-                vec![batch_size, num_tokens]
-            } else {
-                vec![num_tokens]
-            }),
-            "token_input".into(),
-        )?;
+    // The head size, defaulting to the embedding degree divided by the number of heads
+    fn resolved_head_size(&self) -> Result<usize, GptError> {
+        match self.head_size {
+            Some(head_size) => Ok(head_size),
+            None if self.num_heads > 0 && self.embedding_degree.is_multiple_of(self.num_heads) => {
+                Ok(self.embedding_degree / self.num_heads)
+            }
+            None => Err(GptError::InvalidConfig(format!(
+                "embedding degree {} is not divisible into {} heads",
+                self.embedding_degree, self.num_heads
+            ))),
+        }
+    }
 
-        let expected_output = g.alloc_usize(
-            Tensor::<usize>::zeros(&if let Some(batch_size) = batch_size {
-                vec![batch_size, num_tokens]
-            } else {
-                vec![num_tokens]
-            }),
-            "expected_output".into(),
-        )?;
+    /// Estimates the memory training the model with `optimizer` takes, without building it. LoRA
+    /// adapters aren't counted.
+    pub fn estimate<O: Optimizer>(&self, optimizer: &O) -> Result<MemoryEstimate, GptError> {
+        if self.vocab_size == 0 {
+            return Err(GptError::InvalidConfig("vocab size is not set".into()));
+        }
+        let head_size = self.resolved_head_size()?;
+        let is_gpt2 = self.architecture == Architecture::Gpt2;
+        let (vocab, emb, tokens, heads) = (
+            self.vocab_size,
+            self.embedding_degree,
+            self.num_tokens,
+            self.num_heads,
+        );
 
-        // Map the token index into a `embedding_degree` dimension vector through the `token_embedding`
-        // lookup table.
-        let embedded_token_input = g.call(Embedding::new(), &[token_input, token_embedding])?;
+        // Shapes of the parameters, in the order `GPT::new` allocates them
+        let mut shapes = vec![vec![vocab, emb]];
+        if is_gpt2 {
+            shapes.push(vec![tokens, emb]);
+        }
+        for _ in 0..self.num_layers {
+            shapes.extend([vec![emb], vec![emb]]);
+            for _ in 0..heads * 3 {
+                shapes.push(vec![emb, head_size]);
+                if is_gpt2 {
+                    shapes.push(vec![head_size]);
+                }
+            }
+            shapes.extend([vec![emb], vec![heads * head_size, emb]]);
+            shapes.extend([vec![emb], vec![emb]]);
+            shapes.extend([vec![4 * emb], vec![emb, 4 * emb]]);
+            shapes.extend([vec![emb], vec![4 * emb, emb]]);
+        }
+        shapes.extend([vec![emb], vec![emb], vec![vocab], vec![emb, vocab]]);
 
-        // Map token positions into `embedding_degree` dimension vectors.
-        let pos_input = g.alloc(
-            Tensor::<f32>::rand(rng, &[num_tokens, embedding_degree]),
-            false,
-            "pos_input".into(),
-        )?;
+        // Tensors computed per sequence, once elementwise chains are fused (See `GPT::new`): the
+        // keys, queries and values of each head (And their biases), the scores before and after
+        // scaling, masking, softmax and dropout, and the attention, then the concatenated heads
+        // and six embedding-sized and two feed-forward-sized tensors
+        let masks = self.masked_lm.is_none() || self.document_separator.is_some();
+        let projections = if is_gpt2 { 6 } else { 3 };
+        let scores = if masks { 5 } else { 4 };
+        let per_head = (projections + 1) * tokens * head_size + scores * tokens * tokens;
+        let per_layer = heads * per_head + tokens * heads * head_size + 14 * tokens * emb;
+        // The embeddings, the final normalization, the logits (Before and after their bias) and
+        // the loss of each token (And its scaling for masked language models)
+        let losses = if self.masked_lm.is_some() { 2 } else { 1 };
+        let activations =
+            self.num_layers * per_layer + 3 * tokens * emb + 2 * tokens * vocab + losses * tokens;
 
-        // Positional+Token information will both reside in a single `embedding_degree` dimension
-        // vector.
-        let inp = g.call(Add::new(), &[embedded_token_input, pos_input])?;
+        Ok(MemoryEstimate {
+            params: shapes.iter().map(|s| s.iter().product::<usize>()).sum(),
+            activations,
+            optimizer_bytes: shapes.iter().map(|s| optimizer.state_size(s)).sum(),
+        })
+    }
+}
 
-        let mut curr_inp = inp;
-        for l in 0..num_layers {
-            // Normalize input before applying multi-head attention
-            let norm_coeff = g.alloc(
-                Tensor::<f32>::rand(rng, &[embedding_degree]),
-                true,
-                format!("norm_{}_coeff", l),
-            )?;
-            let norm_bias = g.alloc(
-                Tensor::<f32>::zeros(&[embedding_degree]),
-                true,
-                format!("norm_{}_bias", l),
-            )?;
-            let norm_inp = g.call(LayerNorm::new(), &[curr_inp, norm_coeff, norm_bias])?;
+impl Default for GptBuilder<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Linear warmup to `base`, then linear decay down to `min`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LrSchedule {
+    pub base: f32,
+    pub min: f32,
+    pub warmup_steps: usize,
+    pub decay_steps: usize,
+}
+
+impl LrSchedule {
+    /// Learning rate of the given optimizer step.
+    pub fn at(&self, step: usize) -> f32 {
+        if step < self.warmup_steps {
+            (self.base / self.warmup_steps as f32) * step as f32
+        } else {
+            // Fancy LR tuning, thanks to https://github.com/cutoken!
+            f32::max(
+                self.min,
+                self.base
+                    - (self.base - self.min) * (step - self.warmup_steps) as f32
+                        / self.decay_steps as f32,
+            )
+        }
+    }
+}
+
+impl Default for LrSchedule {
+    fn default() -> Self {
+        Self {
+            base: 0.001,
+            min: 0.00001,
+            warmup_steps: 100,
+            decay_steps: 50000,
+        }
+    }
+}
+
+/// Settings of a training run (See `GPT::fit`), defaulting to those of the `femto` command line.
+#[derive(Debug, Clone)]
+pub struct TrainConfig {
+    pub num_batches: usize,
+    pub batch_size: usize,
+    pub backward_scope: BackwardScope,
+    /// Data-parallel workers of CPU graphs, 0 uses one per core (See `GPT::train_cpu`)
+    pub num_workers: usize,
+    pub learning_rate: LrSchedule,
+}
+
+impl Default for TrainConfig {
+    fn default() -> Self {
+        Self {
+            num_batches: 100000,
+            batch_size: 32,
+            backward_scope: BackwardScope::Full,
+            num_workers: 0,
+            learning_rate: LrSchedule::default(),
+        }
+    }
+}
+
+// Input of the document index of every token, in models masking attention across documents or
+// to the padding (Which gets documents of its own)
+#[derive(Debug, Clone, Copy)]
+struct Documents {
+    input: TensorId,
+    separator: Option<usize>,
+    pad: Option<usize>,
+}
+
+// Input of the weight of the loss of every token, in models weighting the loss by target
+#[derive(Debug, Clone)]
+struct LossWeights {
+    input: TensorId,
+    class_weights: Vec<f32>,
+}
+
+// Loads the weights of the loss of the targets `ys`, ignored targets weighing nothing
+fn load_loss_weights<G: Graph>(
+    graph: &mut G,
+    loss_weights: Option<&LossWeights>,
+    ys: &Tensor<usize>,
+) -> Result<(), GraphError> {
+    if let Some(loss_weights) = loss_weights {
+        let weights = ys
+            .blob()
+            .iter()
+            .map(|y| loss_weights.class_weights.get(*y).copied().unwrap_or(0.))
+            .collect();
+        graph.load(loss_weights.input, &Tensor::raw(ys.shape(), weights)?)?;
+    }
+    Ok(())
+}
+
+// Loads the documents of the tokens `xs` in models masking attention across documents, for the
+// next forward pass when `stage` is set (See `Graph::stage_usize`)
+fn load_documents<G: Graph>(
+    graph: &mut G,
+    documents: Option<Documents>,
+    xs: &Tensor<usize>,
+    stage: bool,
+) -> Result<(), GraphError> {
+    if let Some(documents) = documents {
+        let mut docs = match documents.separator {
+            Some(separator) => document_indices(xs, separator),
+            None => Tensor::zeros(xs.shape()),
+        };
+        if let Some(pad) = documents.pad {
+            docs = isolate_padding(xs, &docs, pad);
+        }
+        if stage {
+            graph.stage_usize(documents.input, &docs)?;
+        } else {
+            graph.load_usize(documents.input, &docs)?;
+        }
+    }
+    Ok(())
+}
+
+// Masking of the training windows of masked language models
+#[derive(Debug, Clone, Copy)]
+struct MaskedLm {
+    masking: Masking,
+    vocab_size: usize,
+    pad: Option<usize>,
+}
+
+// Input of the logits of the teacher, in models trained by distillation
+#[derive(Debug, Clone, Copy)]
+struct TeacherInput {
+    input: TensorId,
+    vocab_size: usize,
+}
+
+// Input of the gradient of the hidden states, in models trained contrastively, and the product
+// of both the backward passes start from
+#[derive(Debug, Clone, Copy)]
+struct ContrastiveInput {
+    config: ContrastiveConfig,
+    grad: TensorId,
+    probe: TensorId,
+    embedding_degree: usize,
+}
+
+/// A stage of a curriculum growing the context length: `num_batches` batches of windows of
+/// `context` tokens, trained on a model of that context sharing the parameters of the full one
+/// (Whose shapes don't depend on the context, unless positional embeddings are learned).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ContextStage {
+    pub context: usize,
+    pub num_batches: usize,
+}
+
+impl ContextStage {
+    /// The stages left after `step` optimizer steps, along with the number of batches left in
+    /// each of them.
+    pub fn remaining(stages: &[ContextStage], step: usize) -> Vec<ContextStage> {
+        let mut end = 0;
+        stages
+            .iter()
+            .filter_map(|stage| {
+                end += stage.num_batches;
+                (step < end).then(|| ContextStage {
+                    context: stage.context,
+                    num_batches: end - step.max(end - stage.num_batches),
+                })
+            })
+            .collect()
+    }
+}
+
+impl std::str::FromStr for ContextStage {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split_once(':')
+            .and_then(|(context, num_batches)| {
+                Some(ContextStage {
+                    context: context.parse().ok()?,
+                    num_batches: num_batches.parse().ok()?,
+                })
+            })
+            .ok_or(format!("expected `<context>:<batches>`, got `{}`", s))
+    }
+}
+
+// A frozen weight matrix `W` and its trainable low-rank update `A * B`
+#[derive(Debug, Clone)]
+struct LoraAdapter {
+    weights: TensorId,
+    a: TensorId,
+    b: TensorId,
+}
+
+/// Gradient statistics of a training step, see `GPT::set_grad_stats`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GradStats {
+    /// Optimizer step the gradients were applied at
+    pub step: usize,
+    /// L2 norm of the gradients of all the parameters
+    pub grad_norm: f32,
+    /// Gradient noise scale `tr(Σ) / |G|^2` (https://arxiv.org/abs/1812.06162), around which
+    /// larger batches stop paying off, smoothed over the steps measured so far. Only estimated
+    /// by the data-parallel loops, from the gradients of the shares of each batch.
+    pub noise_scale: Option<f32>,
+}
+
+// Smoothed estimates of `|G|^2` and `tr(Σ)`, from the squared norms of gradients over batches of
+// two sizes (See Appendix A of https://arxiv.org/abs/1812.06162). Estimates of single steps are
+// too noisy to be used as is.
+#[derive(Debug, Clone, Copy, Default)]
+struct NoiseScale {
+    ema: Option<(f32, f32)>,
+}
+
+impl NoiseScale {
+    const DECAY: f32 = 0.9;
+
+    fn update(&mut self, small: f32, b_small: f32, big: f32, b_big: f32) -> Option<f32> {
+        if b_big <= b_small {
+            return None;
+        }
+        let g2 = (b_big * big - b_small * small) / (b_big - b_small);
+        let trace = (small - big) / (1. / b_small - 1. / b_big);
+        let (g2, trace) = match self.ema {
+            Some((ema_g2, ema_trace)) => (
+                Self::DECAY * ema_g2 + (1. - Self::DECAY) * g2,
+                Self::DECAY * ema_trace + (1. - Self::DECAY) * trace,
+            ),
+            None => (g2, trace),
+        };
+        self.ema = Some((g2, trace));
+        Some(trace / g2)
+    }
+}
+
+fn squared_norm<'a>(grads: impl IntoIterator<Item = &'a Tensor<f32>>) -> f32 {
+    grads
+        .into_iter()
+        .map(|g| g.blob().iter().map(|f| f * f).sum::<f32>())
+        .sum()
+}
+
+/// Tokens and time a model was trained on, across the runs that resumed from its checkpoints.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Progress {
+    pub tokens: u64,
+    pub elapsed: Duration,
+}
+
+/// Data a model was trained on, as hashes of the files (See `checkpoint::fnv1a`), and the loss
+/// of its last training step.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Provenance {
+    pub dataset: Option<String>,
+    pub tokenizer: Option<String>,
+    pub loss: Option<f32>,
+}
+
+/// Limits of training on top of the number of batches of the training loops, see
+/// `GPT::set_budget`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Budget {
+    pub tokens: Option<u64>,
+    pub duration: Option<Duration>,
+}
+
+impl Budget {
+    pub fn is_spent(&self, progress: &Progress) -> bool {
+        self.tokens.is_some_and(|tokens| progress.tokens >= tokens)
+            || self
+                .duration
+                .is_some_and(|duration| progress.elapsed >= duration)
+    }
+}
+
+/// Settings of the loss spike guard of the training loops, see `GPT::set_spike_guard`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SpikeGuard {
+    /// Losses above this many times their moving average are spikes, as are NaN/Inf ones
+    pub threshold: f32,
+    /// Multiplier of the learning rate after a rollback
+    pub lr_factor: f32,
+    /// Number of optimizer steps the learning rate stays reduced for
+    pub cooldown: usize,
+}
+
+impl Default for SpikeGuard {
+    fn default() -> Self {
+        Self {
+            threshold: 2.,
+            lr_factor: 0.5,
+            cooldown: 100,
+        }
+    }
+}
+
+// State of the spike guard over a training run
+#[derive(Debug, Clone, Default)]
+struct SpikeMonitor {
+    // Moving average of the loss, and the number of steps it's made of
+    loss_ema: Option<(f32, usize)>,
+    // Last state whose losses were fine
+    last_good: Option<TrainingState>,
+    cooldown: usize,
+}
+
+impl SpikeMonitor {
+    const DECAY: f32 = 0.9;
+    // Steps averaged before losses can be spikes
+    const WARMUP: usize = 10;
+}
+
+/// Average timings of the training steps of `GPT::bench`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BenchReport {
+    pub forward: Duration,
+    pub backward: Duration,
+    pub optimizer: Duration,
+    /// Tokens of the batches trained on per second
+    pub tokens_per_sec: f32,
+}
+
+impl std::fmt::Display for BenchReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let ms = |d: Duration| d.as_secs_f64() * 1000.;
+        writeln!(f, "Forward:   {:>10.2}ms", ms(self.forward))?;
+        writeln!(f, "Backward:  {:>10.2}ms", ms(self.backward))?;
+        writeln!(f, "Optimizer: {:>10.2}ms", ms(self.optimizer))?;
+        writeln!(
+            f,
+            "Step:      {:>10.2}ms ({:.0} tokens/s)",
+            ms(self.forward + self.backward + self.optimizer),
+            self.tokens_per_sec
+        )
+    }
+}
+
+/// Memory taken by the training of a model, see `GptBuilder::estimate`. Every tensor is in f32,
+/// whatever the precision, and has a gradient of its size.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryEstimate {
+    pub params: usize,
+    /// Number of elements of the tensors a forward pass computes over a sequence
+    pub activations: usize,
+    /// State of the optimizer, e.g. the moments of `AdamW`
+    pub optimizer_bytes: usize,
+}
+
+impl MemoryEstimate {
+    pub fn param_bytes(&self) -> usize {
+        self.params * std::mem::size_of::<f32>()
+    }
+    /// Activations of `sequences` sequences, along with their gradients.
+    pub fn activation_bytes(&self, sequences: usize) -> usize {
+        2 * sequences * self.activations * std::mem::size_of::<f32>()
+    }
+    /// RAM taken by `GPT::train_cpu` with `workers` workers. The model and each of the replicas
+    /// of the workers hold their parameters and the activations of a sequence, with gradients,
+    /// and the workers accumulate the gradients of the parameters.
+    pub fn cpu_bytes(&self, workers: usize) -> usize {
+        (workers + 1) * (2 * self.param_bytes() + self.activation_bytes(1))
+            + workers * self.param_bytes()
+            + self.optimizer_bytes
+    }
+    /// Device memory taken by training on a GPU with batches of `batch_size` sequences. An upper
+    /// bound, since the gradients of the activations share buffers (See `plan_grad_buffers`).
+    pub fn gpu_bytes(&self, batch_size: usize) -> usize {
+        2 * self.param_bytes() + self.activation_bytes(batch_size) + self.optimizer_bytes
+    }
+}
+
+pub struct GPT<G: Graph> {
+    graph: G,
+    // Number of sequences GPU graphs are allocated for, CPU graphs take batches of any size
+    batch_size: Option<usize>,
+    num_tokens: usize,
+    layer_starts: Vec<usize>,
+    lora: Option<LoraConfig>,
+    lora_adapters: Vec<LoraAdapter>,
+    linear_weights: Vec<TensorId>,
+    frozen: Vec<TensorId>,
+    loss_scaler: Option<LossScaler>,
+    // Position of the epoch-based sampling of training windows, random windows are sampled when
+    // not set (See `set_sampling`)
+    sampler: Option<SamplerState>,
+    token_input: TensorId,
+    documents: Option<Documents>,
+    masked_lm: Option<MaskedLm>,
+    teacher_input: Option<TeacherInput>,
+    contrastive: Option<ContrastiveInput>,
+    dpo: Option<DpoConfig>,
+    loss_weights: Option<LossWeights>,
+    // The model distilled into this one, see `set_teacher`
+    teacher: Option<Box<GPT<G>>>,
+    // The model `train_dpo` measures the log-probabilities against, see `set_reference`
+    reference: Option<Box<GPT<G>>>,
+    pos_input: TensorId,
+    // Normalized output of the last layer, before the vocabulary projection
+    hidden: TensorId,
+    // Attention weights of each head of each layer
+    attention: Vec<Vec<TensorId>>,
+    output: TensorId,
+    expected_output: TensorId,
+    loss: TensorId,
+    // Sinusoidal positional encodings, learned positional embeddings are regular parameters
+    pos_input_fixed: Option<Tensor<f32>>,
+    // Whether forward passes apply dropout, see `set_training`
+    training: bool,
+    // Steps between gradient statistics, and the last ones (See `set_grad_stats`)
+    grad_stats_every: Option<usize>,
+    grad_stats: Option<GradStats>,
+    noise_scale: NoiseScale,
+    spike_guard: Option<SpikeGuard>,
+    spikes: SpikeMonitor,
+    progress: Progress,
+    provenance: Provenance,
+    budget: Budget,
+    // Whether the passes are timed, and the timings of the replicas of data-parallel training
+    profiling: bool,
+    profile: Profile,
+}
+
+/// Settings of `GPT::infer` and `GPT::infer_batch`, e.g.
+/// `InferParams::new().count(50).top_k(40).stop(vec![newline])`.
+#[derive(Debug, Clone)]
+pub struct InferParams {
+    /// Maximum number of tokens generated after each prompt
+    pub count: usize,
+    /// How likely less probable tokens are to be picked, in (0, 1]
+    pub temperature: f32,
+    /// Temperatures of the first generated tokens, by stages of `(tokens, temperature)` following
+    /// each other, e.g. `[(10, 1.0)]` for more varied openings. `temperature` applies after them
+    pub temperature_schedule: Vec<(usize, f32)>,
+    /// Only the `top_k` most likely tokens may be sampled
+    pub top_k: Option<usize>,
+    /// Only the most likely tokens whose probabilities add up to `top_p` may be sampled (Nucleus
+    /// sampling)
+    pub top_p: Option<f32>,
+    /// Divides the positive logits of the tokens already in the sequence (Prompt included) and
+    /// multiplies the negative ones, values above 1 discouraging repetitions
+    pub repetition_penalty: f32,
+    /// Sequences of tokens ending the generation once generated, which are kept in the results
+    pub stop: Vec<Vec<usize>>,
+    /// Seed of the sampling, which then ignores the random generator it's given so that the
+    /// results are reproducible
+    pub seed: Option<u64>,
+    /// How prompts longer than the context are handled
+    pub context_overflow: ContextOverflow,
+    /// Added to the logits of tokens before sampling, e.g. `-f32::INFINITY` bans a token
+    pub logit_bias: HashMap<usize, f32>,
+    /// The only tokens that may be generated, all of them when `None`
+    pub allowed_tokens: Option<HashSet<usize>>,
+    /// Valid outputs (Not including the prompts), e.g. a `RegexConstraint`. Sequences end early
+    /// once complete, when they can't be extended anymore
+    pub constraint: Option<Arc<dyn Constraint>>,
+    /// Classifier-free guidance: the logits become `u + guidance_scale * (c - u)`, `c` being the
+    /// ones following the prompt and `u` the ones following the `negative_prompt` instead, so
+    /// that values above 1 steer the generation towards the prompt and away from the negative
+    /// one. Every token then takes two passes of the model, 1 disables it
+    pub guidance_scale: f32,
+    /// Replaces the prompt in the unconditional passes of the guidance, which only see the last
+    /// token of the prompt when it's empty
+    pub negative_prompt: Vec<usize>,
+    /// Token healing, given the text of every token (See `constraint::token_pieces`): the last
+    /// token of each prompt is dropped, and the first generated token has to start with its
+    /// text. A prompt ending in the middle of a word is split differently from the same text
+    /// continued, which the model hardly saw in training, healing lets it pick the token it
+    /// would have seen. Constraints see the text of that token whole.
+    pub token_healing: Option<Arc<Vec<String>>>,
+}
+
+impl Default for InferParams {
+    fn default() -> Self {
+        Self {
+            count: 100,
+            temperature: 0.5,
+            temperature_schedule: Vec::new(),
+            top_k: None,
+            top_p: None,
+            repetition_penalty: 1.0,
+            stop: Vec::new(),
+            seed: None,
+            context_overflow: ContextOverflow::Error,
+            logit_bias: HashMap::new(),
+            allowed_tokens: None,
+            constraint: None,
+            guidance_scale: 1.0,
+            negative_prompt: Vec::new(),
+            token_healing: None,
+        }
+    }
+}
+
+impl InferParams {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn count(mut self, count: usize) -> Self {
+        self.count = count;
+        self
+    }
+    pub fn temperature(mut self, temperature: f32) -> Self {
+        self.temperature = temperature;
+        self
+    }
+    /// Samples the next `tokens` tokens, after the stages already added, at `temperature`, in
+    /// (0, 1] as the one of the other tokens.
+    pub fn temperature_for(mut self, tokens: usize, temperature: f32) -> Self {
+        self.temperature_schedule.push((tokens, temperature));
+        self
+    }
+    pub fn top_k(mut self, top_k: impl Into<Option<usize>>) -> Self {
+        self.top_k = top_k.into();
+        self
+    }
+    pub fn top_p(mut self, top_p: impl Into<Option<f32>>) -> Self {
+        self.top_p = top_p.into();
+        self
+    }
+    pub fn repetition_penalty(mut self, repetition_penalty: f32) -> Self {
+        self.repetition_penalty = repetition_penalty;
+        self
+    }
+    /// Adds a sequence ending the generation.
+    pub fn stop(mut self, stop: Vec<usize>) -> Self {
+        self.stop.push(stop);
+        self
+    }
+    pub fn seed(mut self, seed: impl Into<Option<u64>>) -> Self {
+        self.seed = seed.into();
+        self
+    }
+    pub fn context_overflow(mut self, context_overflow: ContextOverflow) -> Self {
+        self.context_overflow = context_overflow;
+        self
+    }
+    pub fn logit_bias(mut self, token: usize, bias: f32) -> Self {
+        self.logit_bias.insert(token, bias);
+        self
+    }
+    pub fn allowed_tokens(mut self, allowed_tokens: impl Into<Option<HashSet<usize>>>) -> Self {
+        self.allowed_tokens = allowed_tokens.into();
+        self
+    }
+    pub fn constraint(mut self, constraint: impl Into<Option<Arc<dyn Constraint>>>) -> Self {
+        self.constraint = constraint.into();
+        self
+    }
+    pub fn guidance_scale(mut self, guidance_scale: f32) -> Self {
+        self.guidance_scale = guidance_scale;
+        self
+    }
+    pub fn negative_prompt(mut self, negative_prompt: Vec<usize>) -> Self {
+        self.negative_prompt = negative_prompt;
+        self
+    }
+    pub fn token_healing(mut self, pieces: impl Into<Option<Arc<Vec<String>>>>) -> Self {
+        self.token_healing = pieces.into();
+        self
+    }
+
+    // Temperature of the token following `generated` ones
+    fn temperature_at(&self, generated: usize) -> f32 {
+        let mut end = 0;
+        for (tokens, temperature) in self.temperature_schedule.iter() {
+            end += tokens;
+            if generated < end {
+                return *temperature;
+            }
+        }
+        self.temperature
+    }
+
+    // Entropy of the distribution the token following `generated` ones is sampled from, given its
+    // logits before the temperature and the truncations
+    fn sampling_entropy(&self, logits: &[f32], generated: usize) -> f32 {
+        let mut truncated = logits.to_vec();
+        self.truncate(&mut truncated);
+        let mut probs = log_softmax(&truncated)
+            .into_iter()
+            .map(f32::exp)
+            .collect::<Vec<_>>();
+        probs.sort_by(|a, b| b.total_cmp(a));
+        // `select` draws a point among the first `temperature` of the cumulated probabilities,
+        // picking the token it falls on
+        let temperature = self.temperature_at(generated);
+        let (mut start, mut entropy) = (0., 0.);
+        for p in probs {
+            let q = ((start + p).min(temperature) - start).max(0.) / temperature;
+            if q > 0. {
+                entropy -= q * q.ln();
+            }
+            start += p;
+        }
+        entropy
+    }
+
+    // The part of the `i`-th prompt that is continued, given a context of `num_tokens` tokens
+    fn fit_prompt<'a>(
+        &self,
+        i: usize,
+        prompt: &'a [usize],
+        num_tokens: usize,
+    ) -> Result<&'a [usize], GptError> {
+        if prompt.len() <= num_tokens {
+            return Ok(prompt);
+        }
+        match self.context_overflow {
+            ContextOverflow::Error => Err(GptError::PromptTooLong(i, prompt.len(), num_tokens)),
+            ContextOverflow::TruncateLeft => Ok(&prompt[prompt.len() - num_tokens..]),
+            ContextOverflow::TruncateRight => Ok(&prompt[..num_tokens]),
+            ContextOverflow::Sliding => Ok(prompt),
+        }
+    }
+
+    // The part of a prompt that is continued, and its last token when token healing drops it
+    // (Unless it's the only one, or one without a text among the pieces)
+    fn heal_prompt<'a>(&self, prompt: &'a [usize]) -> (&'a [usize], Option<usize>) {
+        match (&self.token_healing, prompt.split_last()) {
+            (Some(pieces), Some((last, kept))) if !kept.is_empty() && *last < pieces.len() => {
+                (kept, Some(*last))
+            }
+            _ => (prompt, None),
+        }
+    }
+
+    // Masks the tokens whose text doesn't start with the one of the token dropped from a prompt
+    // (Tokens without a text among the pieces included)
+    fn heal(&self, logits: &mut [f32], dropped: usize) {
+        let Some(pieces) = &self.token_healing else {
+            return;
+        };
+        let Some(text) = pieces.get(dropped) else {
+            return;
+        };
+        for (i, logit) in logits.iter_mut().enumerate() {
+            if !pieces
+                .get(i)
+                .is_some_and(|piece| piece.starts_with(text.as_str()))
+            {
+                *logit = f32::NEG_INFINITY;
+            }
+        }
+    }
+
+    // Whether the `generated` tokens end with a stop sequence
+    fn stopped(&self, generated: &[usize]) -> bool {
+        self.stop
+            .iter()
+            .any(|stop| !stop.is_empty() && generated.ends_with(stop))
+    }
+
+    // Penalizes the repetitions of the tokens of `seq` in the logits of the token following it
+    fn penalize(&self, logits: &mut [f32], seq: &[usize]) {
+        if self.repetition_penalty == 1.0 {
+            return;
+        }
+        for token in seq.iter().collect::<HashSet<_>>() {
+            if let Some(logit) = logits.get_mut(*token) {
+                *logit = if *logit > 0. {
+                    *logit / self.repetition_penalty
+                } else {
+                    *logit * self.repetition_penalty
+                };
+            }
+        }
+    }
+
+    // Masks the tokens that top-k and nucleus sampling leave out, the most likely one is always
+    // kept
+    fn truncate(&self, logits: &mut [f32]) {
+        let mut order = (0..logits.len()).collect::<Vec<_>>();
+        order.sort_by(|a, b| logits[*b].total_cmp(&logits[*a]));
+        let mut keep = self.top_k.unwrap_or(logits.len());
+        if let Some(top_p) = self.top_p {
+            let log_probs = log_softmax(logits);
+            let mut sum = 0.;
+            let nucleus = order.iter().position(|token| {
+                sum += log_probs[*token].exp();
+                sum >= top_p
+            });
+            keep = keep.min(nucleus.map_or(logits.len(), |i| i + 1));
+        }
+        for token in order.iter().skip(keep.max(1)) {
+            logits[*token] = f32::NEG_INFINITY;
+        }
+    }
+
+    // Biases and masks the logits of the token following the `generated` ones
+    fn constrain(&self, logits: &mut [f32], generated: &[usize]) {
+        for (token, bias) in self.logit_bias.iter() {
+            if let Some(logit) = logits.get_mut(*token) {
+                *logit += bias;
+            }
+        }
+        if let Some(allowed) = &self.allowed_tokens {
+            for (token, logit) in logits.iter_mut().enumerate() {
+                if !allowed.contains(&token) {
+                    *logit = f32::NEG_INFINITY;
+                }
+            }
+        }
+        if let Some(constraint) = &self.constraint {
+            for (logit, allowed) in logits.iter_mut().zip(constraint.allowed(generated)) {
+                if !allowed {
+                    *logit = f32::NEG_INFINITY;
+                }
+            }
+        }
+    }
+}
+
+/// A token of the sequences returned by `GPT::infer_logprobs`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TokenLogprob {
+    pub token: usize,
+    /// Log-probability of the token given the ones before it, `None` for the first token of a
+    /// prompt, which nothing predicts
+    pub log_prob: Option<f32>,
+    /// The most likely tokens at this position with their log-probabilities, most likely first
+    pub top: Vec<(usize, f32)>,
+}
+
+impl TokenLogprob {
+    fn new(token: usize, logits: &[f32], top_n: usize) -> Self {
+        let log_probs = log_softmax(logits);
+        let mut top = log_probs
+            .iter()
+            .cloned()
+            .enumerate()
+            .filter(|(_, l)| *l > f32::NEG_INFINITY)
+            .collect::<Vec<_>>();
+        top.sort_by(|a, b| b.1.total_cmp(&a.1));
+        top.truncate(top_n);
+        Self {
+            token,
+            log_prob: Some(log_probs[token]),
+            top,
+        }
+    }
+}
+
+fn log_softmax(logits: &[f32]) -> Vec<f32> {
+    let max = logits.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+    let log_sum = logits.iter().map(|l| (l - max).exp()).sum::<f32>().ln() + max;
+    logits.iter().map(|l| l - log_sum).collect()
+}
+
+/// Settings of `GPT::beam_search`.
+#[derive(Debug, Clone, Copy)]
+pub struct BeamParams {
+    /// Number of sequences kept at every step
+    pub beam_size: usize,
+    /// Exponent of the lengths dividing the log-probabilities of sequences, higher values favor
+    /// longer ones (Which only matters when a constraint ends sequences early)
+    pub length_penalty: f32,
+}
+
+impl Default for BeamParams {
+    fn default() -> Self {
+        Self {
+            beam_size: 4,
+            length_penalty: 1.0,
+        }
+    }
+}
+
+/// How `GPT::best_of` picks among the continuations of a prompt, the highest score winning.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Rerank {
+    /// Average log-probability of the generated tokens, under the logits they were sampled from
+    #[default]
+    LogProb,
+    /// Share of the trigrams of the generated tokens seen earlier in them (See
+    /// `repetition_rate`), negated
+    Repetition,
+}
+
+impl Rerank {
+    // Score of the `generated` tokens, given their log-probabilities
+    fn score(&self, generated: &[usize], log_probs: &[f32]) -> f32 {
+        match self {
+            Rerank::LogProb => log_probs.iter().sum::<f32>() / log_probs.len().max(1) as f32,
+            Rerank::Repetition => -repetition_rate(generated),
+        }
+    }
+}
+
+impl std::str::FromStr for Rerank {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "logprob" => Ok(Rerank::LogProb),
+            "repetition" => Ok(Rerank::Repetition),
+            _ => Err(format!("expected `logprob` or `repetition`, got `{}`", s)),
+        }
+    }
+}
+
+// Windows of the next `batch_size` sequences of a training loop, by epochs when `sampler` is set
+// and masked for masked language models
+fn next_batch<C: Corpus + ?Sized, R: Rng>(
+    sampler: &mut Option<EpochSampler>,
+    corpus: &C,
+    batch_size: usize,
+    num_tokens: usize,
+    masked_lm: Option<MaskedLm>,
+    rng: &mut R,
+) -> Batch {
+    let batch = match sampler {
+        Some(sampler) => sampler.sample(corpus, batch_size),
+        None => sample_dataset(corpus, batch_size, num_tokens, rng),
+    };
+    match masked_lm {
+        Some(masked_lm) => {
+            let xs = batch.xs.clone();
+            let masked = masked_lm.masking.apply(batch, masked_lm.vocab_size, rng);
+            match masked_lm.pad {
+                Some(pad) => keep_padding(masked, &xs, pad),
+                None => masked,
+            }
+        }
+        None => batch,
+    }
+}
+
+// Restores the padding tokens of the windows `xs` that masking replaced, which are no targets
+fn keep_padding(batch: Batch, xs: &Tensor<usize>, pad: usize) -> Batch {
+    let (mut masked_xs, mut ys) = (batch.xs.blob().to_vec(), batch.ys.blob().to_vec());
+    for (i, x) in xs.blob().iter().enumerate() {
+        if *x == pad {
+            masked_xs[i] = pad;
+            ys[i] = IGNORED_TARGET;
+        }
+    }
+    Batch {
+        xs: Tensor::raw(xs.shape(), masked_xs).unwrap(),
+        ys: Tensor::raw(xs.shape(), ys).unwrap(),
+        sources: batch.sources,
+    }
+}
+
+// The window of a preference training pass on a completion of `prompt`, cut after
+// `num_tokens + 1` tokens, and the position of the first token of the completion in it
+fn preference_window(
+    prompt: &[usize],
+    completion: &[usize],
+    num_tokens: usize,
+) -> (Vec<usize>, usize) {
+    let mut window = [prompt, completion].concat();
+    window.truncate(num_tokens + 1);
+    (window, prompt.len())
+}
+
+// Log-probability of the tokens of `window` from `start` on, `logits` being the ones of the next
+// token at each position
+fn completion_logprob(logits: &[Vec<f32>], window: &[usize], start: usize) -> f32 {
+    (start - 1..window.len() - 1)
+        .map(|pos| log_softmax(&logits[pos])[window[pos + 1]])
+        .sum()
+}
+
+// Logs the outcome of a training step, `sources` being the statistics of the sources of its batch
+// (See `source_stats`)
+fn log_step(
+    step: usize,
+    loss: f32,
+    grad_stats: Option<GradStats>,
+    sources: String,
+    sampler: &Option<EpochSampler>,
+    elapsed: Duration,
+) {
+    info!(
+        step,
+        loss,
+        grad_norm = grad_stats.map(|s| s.grad_norm),
+        noise_scale = grad_stats.and_then(|s| s.noise_scale),
+        epoch = sampler.as_ref().map(|s| s.state().epoch),
+        epoch_progress = sampler.as_ref().map(|s| s.progress()),
+        elapsed_ms = elapsed.as_millis() as u64,
+        "Training step{}",
+        sources
+    );
+}
+
+// Statistics of each dataset of a training loop mixing several, printed after the loss: their
+// mean losses when the loss of every window is known, their numbers of windows otherwise
+fn source_stats(num_sources: usize, sources: &[usize], losses: Option<&[f32]>) -> String {
+    if num_sources < 2 {
+        return String::new();
+    }
+    let mut counts = vec![0; num_sources];
+    let mut sums = vec![0.; num_sources];
+    for (i, source) in sources.iter().enumerate() {
+        counts[*source] += 1;
+        sums[*source] += losses.map_or(0., |l| l[i]);
+    }
+    match losses {
+        Some(_) => format!(
+            " Losses: [{}]",
+            sums.iter()
+                .zip(counts.iter())
+                .map(|(sum, count)| match count {
+                    0 => "-".to_string(),
+                    _ => format!("{:.4}", sum / *count as f32),
+                })
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+        None => format!(" Windows: {:?}", counts),
+    }
+}
+
+/// Number of sequences the `index`-th of `num_models` data-parallel models processes in each batch.
+pub fn batch_share(batch_size: usize, num_models: usize, index: usize) -> usize {
+    batch_size / num_models + usize::from(index < batch_size % num_models)
+}
+
+/// The largest batch size up to `max` that `fits` succeeds with, doubling it from 1 and then
+/// bisecting. `fits` failing with anything but a lack of memory (Or with a single sequence) fails
+/// the search.
+pub fn find_batch_size<F: FnMut(usize) -> Result<(), GptError>>(
+    max: usize,
+    mut fits: F,
+) -> Result<usize, GptError> {
+    fits(1)?;
+    // The largest size known to fit, and the smallest one known not to
+    let (mut low, mut high) = (1, max + 1);
+    while high - low > 1 {
+        let size = if high > max {
+            (low * 2).min(max)
+        } else {
+            (low + high) / 2
+        };
+        match fits(size) {
+            Ok(()) => low = size,
+            Err(e) if e.is_out_of_memory() => high = size,
+            Err(e) => return Err(e),
+        }
+        debug!(size, fits = low == size, "Tried a batch size");
+    }
+    Ok(low)
+}
+
+fn select<R: Rng, T: TensorOps<f32>>(
+    rng: &mut R,
+    t: &T,
+    temperature: f32,
+) -> Result<usize, GptError> {
+    check_temperature(temperature)?;
+    let t = Softmax::new().run(
+        &[&GeneralTensor::Float(Tensor::<f32>::raw(
+            t.shape(),
+            t.blob().to_vec(),
+        )?)],
+        false,
+    )?;
+    let mut ts = t.blob().iter().cloned().enumerate().collect::<Vec<_>>();
+    ts.sort_by_key(|(_, b)| (b * 1000.) as usize);
+    let dice = rng.gen_range(0.0..temperature);
+    let mut accum = 0.;
+    for (id, t) in ts.iter().rev() {
+        accum += t;
+        if dice < accum {
+            return Ok(*id);
+        }
+    }
+    // Rounding left the cumulated probabilities a little short of `dice`
+    ts.first()
+        .map(|(id, _)| *id)
+        .ok_or(GptError::NoAllowedTokens)
+}
+
+fn check_temperature(temperature: f32) -> Result<(), GptError> {
+    if temperature > 0. && temperature <= 1. {
+        Ok(())
+    } else {
+        Err(GptError::InvalidConfig(format!(
+            "temperature should be in (0, 1], got {}",
+            temperature
+        )))
+    }
+}
+
+// Allocates the weight matrices of linear layers and multiplies inputs by them
+struct Linears<'a> {
+    lora: Option<LoraConfig>,
+    quantized: Option<&'a QuantizedState>,
+    adapters: Vec<LoraAdapter>,
+    weights: Vec<TensorId>,
+}
+
+impl Linears<'_> {
+    // Computes `inp * W` for a new `shape`d weight matrix called `name`, which is taken as a
+    // constant when a quantized version of it is available. When `adapt` is set and fine-tuning
+    // with LoRA, a low-rank `inp * A * B` correction is added to the result. `B` starts from zero
+    // so that the model initially behaves like the base model.
+    fn apply<G: Graph, R: Rng>(
+        &mut self,
+        rng: &mut R,
+        g: &mut G,
+        inp: TensorId,
+        shape: [usize; 2],
+        name: &str,
+        adapt: bool,
+    ) -> Result<TensorId, GraphError> {
+        if let Some(q) = self.quantized.and_then(|q| q.quantized.get(name)) {
+            if q.shape() != shape {
+                return Err(TensorError::UnexpectedShape.into());
+            }
+            return g.call(QuantizedMatMul::new(Arc::new(q.clone())), &[inp]);
+        }
+        let weights = g.alloc(Tensor::<f32>::rand(rng, &shape), true, name.into())?;
+        self.weights.push(weights);
+        let out = g.call(MatMul::new(), &[inp, weights])?;
+        match self.lora {
+            Some(lora) if adapt => {
+                let a = g.alloc(
+                    Tensor::<f32>::rand(rng, &[shape[0], lora.rank]),
+                    true,
+                    format!("{}_lora_a", name),
+                )?;
+                let b = g.alloc(
+                    Tensor::<f32>::zeros(&[lora.rank, shape[1]]),
+                    true,
+                    format!("{}_lora_b", name),
+                )?;
+                let down = g.call(MatMul::new(), &[inp, a])?;
+                let up = g.call(MatMul::new(), &[down, b])?;
+                let scaled = g.call(Coeff::new(lora.scale()), &[up])?;
+                self.adapters.push(LoraAdapter { weights, a, b });
+                g.call(Add::new(), &[out, scaled])
+            }
+            _ => Ok(out),
+        }
+    }
+}
+
+// Adds a (zero-initialized) trainable bias to the output of a computation
+fn bias<G: Graph>(
+    g: &mut G,
+    inp: TensorId,
+    size: usize,
+    name: String,
+) -> Result<TensorId, GraphError> {
+    let bias = g.alloc(Tensor::<f32>::zeros(&[size]), true, name)?;
+    g.call(Add::new(), &[inp, bias])
+}
+
+pub(crate) fn pos_encode_inter(num_tokens: usize, embedding_size: usize) -> Tensor<f32> {
+    let mut raw_new = Vec::new();
+    let cols = embedding_size;
+    let rows = num_tokens;
+    for row in 0..rows {
+        for col in 0..cols {
+            let k = row as f32;
+            let i = (col / 2) as f32;
+            let factor = 10000f32.powf(2f32 * i / embedding_size as f32);
+
+            let pos = if col % 2 == 0 {
+                (k / factor).sin()
+            } else {
+                (k / factor).cos()
+            };
+
+            raw_new.push(pos);
+        }
+    }
+
+    Tensor::raw(&[rows, cols], raw_new).unwrap()
+}
+
+impl<G: Graph> GPT<G> {
+    /// Builds the model `config` describes, initializing its parameters with `rng`.
+    pub fn new<R: Rng>(rng: &mut R, mut g: G, config: &GptBuilder<'_>) -> Result<Self, GptError> {
+        if config.vocab_size == 0 {
+            return Err(GptError::InvalidConfig("vocab size is not set".into()));
+        }
+        if let Some(class_weights) = &config.class_weights {
+            if class_weights.len() != config.vocab_size {
+                return Err(GptError::InvalidConfig(format!(
+                    "expected {} class weights, one per token, got {}",
+                    config.vocab_size,
+                    class_weights.len()
+                )));
+            }
+        }
+        if config.masked_lm.is_some() && config.distillation.is_some() {
+            return Err(GptError::InvalidConfig(
+                "masked language models can't be trained by distillation".into(),
+            ));
+        }
+        if config.dpo.is_some() && (config.masked_lm.is_some() || config.distillation.is_some()) {
+            return Err(GptError::InvalidConfig(
+                "masked language models and distillation can't be trained on preferences".into(),
+            ));
+        }
+        let head_size = config.resolved_head_size()?;
+        let GptBuilder {
+            batch_size,
+            vocab_size,
+            embedding_degree,
+            num_tokens,
+            num_layers,
+            num_heads,
+            dropout,
+            label_smoothing,
+            z_loss,
+            architecture,
+            document_separator,
+            pad_token,
+            masked_lm,
+            distillation,
+            contrastive,
+            dpo,
+            lora,
+            quantized,
+            ..
+        } = *config;
+        let class_weights = config.class_weights.clone();
+        let mut linears = Linears {
+            lora,
+            quantized,
+            adapters: Vec::new(),
+            weights: Vec::new(),
+        };
+
+        // Mapping each token to a `embedding_degree` dimension space through a lookup table
+        let token_embedding = g.alloc(
+            Tensor::<f32>::rand(rng, &[vocab_size, embedding_degree]),
+            true,
+            "token_embedding".into(),
+        )?;
+
+        // Token inputs. We will get `num_tokens` tokens as inputs and will have `num_tokens`
+        // outputs.
+        // In the case of CPU training, it's much more efficient to parallelize over instances
+        // in a single batch. (I.e. it's not very efficient to parallelize a matrix multiplication
+        // operation on CPUs. Better approach is to process a 32-instanced batch on a 32-core CPU,
+        // where each instance runs on its own core, without parallelizing operations)
+        // That's why we DO NOT specify a `batch_size` when training on a CPU.
+        let token_input = g.alloc_usize(
+            Tensor::<usize>::zeros(&if let Some(batch_size) = batch_size {
+                vec![batch_size, num_tokens]
+            } else {
+                vec![num_tokens]
+            }),
+            "token_input".into(),
+        )?;
+
+        let expected_output = g.alloc_usize(
+            Tensor::<usize>::zeros(&if let Some(batch_size) = batch_size {
+                vec![batch_size, num_tokens]
+            } else {
+                vec![num_tokens]
+            }),
+            "expected_output".into(),
+        )?;
+
+        // Document index of every token, when tokens don't attend across documents
+        let documents = (document_separator.is_some() || pad_token.is_some())
+            .then(|| {
+                let input = g.alloc_usize(
+                    Tensor::<usize>::zeros(&if let Some(batch_size) = batch_size {
+                        vec![batch_size, num_tokens]
+                    } else {
+                        vec![num_tokens]
+                    }),
+                    "document_input".into(),
+                )?;
+                Ok::<_, GptError>(Documents {
+                    input,
+                    separator: document_separator,
+                    pad: pad_token,
+                })
+            })
+            .transpose()?;
+
+        // Logits of the teacher for every token, when distilling a model into this one
+        let teacher_input = distillation
+            .map(|_| {
+                let input = g.alloc(
+                    Tensor::<f32>::zeros(&if let Some(batch_size) = batch_size {
+                        vec![batch_size, num_tokens, vocab_size]
+                    } else {
+                        vec![num_tokens, vocab_size]
+                    }),
+                    false,
+                    "teacher_logits".into(),
+                )?;
+                Ok::<_, GptError>(TeacherInput { input, vocab_size })
+            })
+            .transpose()?;
+
+        // Weight of the loss of every token, given by the weight of its target (The distillation
+        // loss isn't weighted). `train_dpo` sets them itself, the loss of a token being its exact
+        // negative log-probability.
+        let (label_smoothing, z_loss, class_weights) = match dpo {
+            Some(_) => (0., 0., Some(vec![1.; vocab_size])),
+            None => (label_smoothing, z_loss, class_weights),
+        };
+        let loss_weights = class_weights
+            .filter(|_| distillation.is_none())
+            .map(|class_weights| {
+                let input = g.alloc(
+                    Tensor::<f32>::zeros(&if let Some(batch_size) = batch_size {
+                        vec![batch_size, num_tokens]
+                    } else {
+                        vec![num_tokens]
+                    }),
+                    false,
+                    "loss_weights".into(),
+                )?;
+                Ok::<_, GptError>(LossWeights {
+                    input,
+                    class_weights,
+                })
+            })
+            .transpose()?;
+
+        // Map the token index into a `embedding_degree` dimension vector through the `token_embedding`
+        // lookup table.
+        let embedded_token_input = g.call(Embedding::new(), &[token_input, token_embedding])?;
+
+        // Map token positions into `embedding_degree` dimension vectors.
+        let is_gpt2 = architecture == Architecture::Gpt2;
+        let pos_input = g.alloc(
+            Tensor::<f32>::rand(rng, &[num_tokens, embedding_degree]),
+            is_gpt2,
+            if is_gpt2 {
+                "pos_embedding".into()
+            } else {
+                "pos_input".into()
+            },
+        )?;
+
+        // Positional+Token information will both reside in a single `embedding_degree` dimension
+        // vector.
+        let inp = g.call(Add::new(), &[embedded_token_input, pos_input])?;
+
+        // Tensors entering each layer, used for truncating the backward pass
+        let mut layer_inputs = Vec::with_capacity(num_layers);
+
+        let mut attention = Vec::with_capacity(num_layers);
+        let mut curr_inp = inp;
+        for l in 0..num_layers {
+            g.set_name(curr_inp, format!("layer_{}_input", l))?;
+            layer_inputs.push(curr_inp);
+            // Normalize input before applying multi-head attention
+            let norm_coeff = g.alloc(
+                Tensor::<f32>::rand(rng, &[embedding_degree]),
+                true,
+                format!("norm_{}_coeff", l),
+            )?;
+            let norm_bias = g.alloc(
+                Tensor::<f32>::zeros(&[embedding_degree]),
+                true,
+                format!("norm_{}_bias", l),
+            )?;
+            let norm_inp = g.call(LayerNorm::new(), &[curr_inp, norm_coeff, norm_bias])?;
+
+            let mut heads = Vec::new();
+            let mut weights = Vec::new();
+
+            // Multi-head Attention
+            for h in 0..num_heads {
+                // Key
+                let k = linears.apply(
+                    rng,
+                    &mut g,
+                    norm_inp,
+                    [embedding_degree, head_size],
+                    &format!("head_{}_{}_k", l, h),
+                    true,
+                )?;
+                let k = if is_gpt2 {
+                    bias(&mut g, k, head_size, format!("head_{}_{}_k_bias", l, h))?
+                } else {
+                    k
+                };
+
+                // Query
+                let q = linears.apply(
+                    rng,
+                    &mut g,
+                    norm_inp,
+                    [embedding_degree, head_size],
+                    &format!("head_{}_{}_q", l, h),
+                    true,
+                )?;
+                let q = if is_gpt2 {
+                    bias(&mut g, q, head_size, format!("head_{}_{}_q_bias", l, h))?
+                } else {
+                    q
+                };
+
+                // Value
+                let v = linears.apply(
+                    rng,
+                    &mut g,
+                    norm_inp,
+                    [embedding_degree, head_size],
+                    &format!("head_{}_{}_v", l, h),
+                    true,
+                )?;
+                let v = if is_gpt2 {
+                    bias(&mut g, v, head_size, format!("head_{}_{}_v_bias", l, h))?
+                } else {
+                    v
+                };
+
+                let q_t = g.call(Transpose::new(), &[q])?;
+                let kq = g.call(MatMul::new(), &[k, q_t])?;
+
+                let head_size_sqrt_inv = (head_size as f32).powf(-0.5);
+                let kq_coeff = g.call(Coeff::new(head_size_sqrt_inv), &[kq])?;
+
+                // Masked language models attend in both directions
+                let masked_kq = match (documents, masked_lm.is_some()) {
+                    (Some(documents), false) => {
+                        g.call(DocumentMask::new(num_tokens), &[kq_coeff, documents.input])?
+                    }
+                    (Some(documents), true) => g.call(
+                        DocumentMask::bidirectional(num_tokens),
+                        &[kq_coeff, documents.input],
+                    )?,
+                    (None, false) => g.call(TrilMask::new(num_tokens), &[kq_coeff])?,
+                    (None, true) => kq_coeff,
+                };
+                let soft_masked_kq = g.call(Softmax::new(), &[masked_kq])?;
+                g.set_name(soft_masked_kq, format!("head_{}_{}_weights", l, h))?;
+                weights.push(soft_masked_kq);
+                let dropped_soft_masked_kq = g.call(Dropout::new(dropout), &[soft_masked_kq])?;
+                let atten = g.call(MatMul::new(), &[dropped_soft_masked_kq, v])?;
+                g.set_name(atten, format!("head_{}_{}_atten", l, h))?;
+                heads.push(atten);
+            }
+
+            attention.push(weights);
+
+            // Concat head results and project into embedding_degree
+            let cat = g.call(Cat::new(), &heads)?;
+            let proj_bias_params = g.alloc(
+                Tensor::<f32>::zeros(&[embedding_degree]),
+                true,
+                format!("proj_{}_bias", l),
+            )?;
+            let proj_cat = linears.apply(
+                rng,
+                &mut g,
+                cat,
+                [num_heads * head_size, embedding_degree],
+                &format!("proj_{}_weights", l),
+                false,
+            )?;
+            let proj_cat_bias = g.call(Add::new(), &[proj_cat, proj_bias_params])?;
+            let dropped_proj_cat_bias = g.call(Dropout::new(dropout), &[proj_cat_bias])?;
+
+            // Add attention results to input and then normalize
+            // GPT-2 keeps the residual stream unnormalized
+            let residual = if is_gpt2 { curr_inp } else { norm_inp };
+            let add_atten = g.call(Add::new(), &[residual, dropped_proj_cat_bias])?;
+            let add_atten_norm_coeff = g.alloc(
+                Tensor::<f32>::rand(rng, &[embedding_degree]),
+                true,
+                format!("atten_norm_{}_coeff", l),
+            )?;
+            let add_atten_norm_bias = g.alloc(
+                Tensor::<f32>::zeros(&[embedding_degree]),
+                true,
+                format!("atten_norm_{}_bias", l),
+            )?;
+            let add_atten_norm = g.call(
+                LayerNorm::new(),
+                &[add_atten, add_atten_norm_coeff, add_atten_norm_bias],
+            )?;
+
+            // A feed-forward layer:
+            // Linear embedding_degree -> 4*embedding_degree
+            // Relu
+            // Linear 4*embedding_degree -> embedding_degree
+            let bias1_params = g.alloc(
+                Tensor::<f32>::zeros(&[4 * embedding_degree]),
+                true,
+                format!("feedforward1_{}_bias", l),
+            )?;
+            let lin1_result = linears.apply(
+                rng,
+                &mut g,
+                add_atten_norm,
+                [embedding_degree, 4 * embedding_degree],
+                &format!("feedforward1_{}_weights", l),
+                false,
+            )?;
+            let lin1_bias_result = g.call(Add::new(), &[lin1_result, bias1_params])?;
+            let lin1_act = g.call(Gelu::new(), &[lin1_bias_result])?;
+            let bias2_params = g.alloc(
+                Tensor::<f32>::zeros(&[embedding_degree]),
+                true,
+                format!("feedforward2_{}_bias", l),
+            )?;
+            let lin2_result = linears.apply(
+                rng,
+                &mut g,
+                lin1_act,
+                [4 * embedding_degree, embedding_degree],
+                &format!("feedforward2_{}_weights", l),
+                false,
+            )?;
+            let lin2_bias_result = g.call(Add::new(), &[lin2_result, bias2_params])?;
+
+            let residual = if is_gpt2 { add_atten } else { add_atten_norm };
+            curr_inp = g.call(Add::new(), &[residual, lin2_bias_result])?;
+        }
+
+        // Normalize the output after the last layer
+        let norm_out_coeff = g.alloc(
+            Tensor::<f32>::rand(rng, &[embedding_degree]),
+            true,
+            format!("head_norm_coeff"),
+        )?;
+        let norm_out_bias = g.alloc(
+            Tensor::<f32>::zeros(&[embedding_degree]),
+            true,
+            format!("head_norm_bias"),
+        )?;
+        let norm_out = g.call(LayerNorm::new(), &[curr_inp, norm_out_coeff, norm_out_bias])?;
+        g.set_name(norm_out, "hidden".into())?;
+
+        // Map from embedding_degree to vocab_size through a linear layer
+        let to_vocab_bias = g.alloc(
+            Tensor::<f32>::zeros(&[vocab_size]),
+            true,
+            format!("head_map_bias"),
+        )?;
+        let result_lin = linears.apply(
+            rng,
+            &mut g,
+            norm_out,
+            [embedding_degree, vocab_size],
+            "head_map_weights",
+            false,
+        )?;
+        let output = g.call(Add::new(), &[result_lin, to_vocab_bias])?;
+
+        let loss = match (distillation, teacher_input) {
+            (Some(distillation), Some(teacher)) => g.call(
+                Distillation::new(distillation.temperature, distillation.alpha),
+                &[output, expected_output, teacher.input],
+            )?,
+            _ => match &loss_weights {
+                Some(weights) => g.call(
+                    CrossEntropy::new(label_smoothing, z_loss),
+                    &[output, expected_output, weights.input],
+                )?,
+                None => g.call(
+                    CrossEntropy::new(label_smoothing, z_loss),
+                    &[output, expected_output],
+                )?,
+            },
+        };
+        // Only the masked tokens are predicted, the mean over all of them would shrink with the
+        // masking rate
+        let loss = match &masked_lm {
+            Some(masking) => g.call(Coeff::new(1. / masking.rate), &[loss])?,
+            None => loss,
+        };
+        g.set_name(output, "output".into())?;
+        g.set_name(loss, "loss".into())?;
+
+        // The gradient of the hidden states is computed on the host by `train_contrastive`, over
+        // the embeddings of a whole batch of texts
+        let contrastive = contrastive
+            .map(|config| {
+                let grad = g.alloc(
+                    Tensor::<f32>::zeros(&if let Some(batch_size) = batch_size {
+                        vec![batch_size, num_tokens, embedding_degree]
+                    } else {
+                        vec![num_tokens, embedding_degree]
+                    }),
+                    false,
+                    "hidden_grad".into(),
+                )?;
+                let probe = g.call(Dot::new(), &[norm_out, grad])?;
+                g.set_name(probe, "contrastive_probe".into())?;
+                Ok::<_, GptError>(ContrastiveInput {
+                    config,
+                    grad,
+                    probe,
+                    embedding_degree,
+                })
+            })
+            .transpose()?;
+
+        // Number of computations preceding each layer, once transposes are folded into the
+        // attention products and elementwise chains are fused
+        let keep = [norm_out, output, loss]
+            .into_iter()
+            .chain(contrastive.map(|c| c.probe))
+            .chain(attention.iter().flatten().cloned())
+            .collect::<Vec<_>>();
+        g.fold_transposes(&keep)?;
+        g.fuse_elementwise(&keep)?;
+        let nodes = g.nodes();
+        let layer_starts = layer_inputs
+            .iter()
+            .map(|inp| {
+                nodes
+                    .iter()
+                    .filter(|n| n.op.is_some() && n.id <= *inp)
+                    .count()
+            })
+            .collect();
+
+        // Only the adapters are trained in LoRA mode, everything else is frozen
+        let mut frozen = Vec::new();
+        if lora.is_some() {
+            let adapter_params = linears
+                .adapters
+                .iter()
+                .flat_map(|a| [a.a, a.b])
+                .collect::<Vec<_>>();
+            for p in g.params().to_vec() {
+                if !adapter_params.contains(&p) {
+                    g.freeze(p)?;
+                    frozen.push(p);
+                }
+            }
+        }
+
+        Ok(Self {
+            graph: g,
+            batch_size,
+            num_tokens,
+            layer_starts,
+            lora,
+            lora_adapters: linears.adapters,
+            linear_weights: linears.weights,
+            frozen,
+            loss_scaler: None,
+            sampler: None,
+            token_input,
+            documents,
+            masked_lm: masked_lm.map(|masking| MaskedLm {
+                masking,
+                vocab_size,
+                pad: pad_token,
+            }),
+            teacher_input,
+            contrastive,
+            dpo,
+            loss_weights,
+            teacher: None,
+            reference: None,
+            pos_input,
+            hidden: norm_out,
+            attention,
+            output,
+            expected_output,
+            loss,
+            pos_input_fixed: (!is_gpt2).then(|| pos_encode_inter(num_tokens, embedding_degree)),
+            training: true,
+            grad_stats_every: None,
+            grad_stats: None,
+            noise_scale: Default::default(),
+            spike_guard: None,
+            spikes: Default::default(),
+            progress: Default::default(),
+            provenance: Default::default(),
+            budget: Default::default(),
+            profiling: false,
+            profile: Default::default(),
+        })
+    }
+
+    pub fn sync(&mut self) -> Result<(), GptError> {
+        self.graph
+            .params()
+            .to_vec()
+            .into_iter()
+            .chain(self.frozen.clone())
+            .map(|p| self.graph.fetch(p, false))
+            .collect::<Result<Vec<_>, GraphError>>()?;
+        Ok(())
+    }
+
+    pub fn graph(&self) -> &G {
+        &self.graph
+    }
+
+    pub fn graph_mut(&mut self) -> &mut G {
+        &mut self.graph
+    }
+
+    /// Replaces every operation named `op_name` (See `Function::op_name`) by a function `make`
+    /// returns, applied to the same inputs, e.g. to try another attention normalization than
+    /// `Softmax`. Elementwise chains are fused when the model is built, so they appear as single
+    /// operations (e.g. the `Add+Gelu` of the feed-forward blocks). Returns the number of replaced
+    /// operations.
+    pub fn replace_ops<F: Fn() -> Box<dyn Function>>(
+        &mut self,
+        op_name: &str,
+        make: F,
+    ) -> Result<usize, GptError> {
+        let ids = self
+            .graph
+            .nodes()
+            .into_iter()
+            .filter(|n| n.op.as_deref() == Some(op_name))
+            .map(|n| n.id)
+            .collect::<Vec<_>>();
+        for id in ids.iter() {
+            self.graph.replace(*id, make())?;
+        }
+        Ok(ids.len())
+    }
+
+    /// Calls `hook` with the value of the tensor called `name` every time a forward pass computes
+    /// it, e.g. `layer_2_input`, `head_0_1_weights` (Attention weights), `hidden` (Output of the
+    /// last layer) or `output` (Logits), see `femto graph-dump` for the others. The hook may
+    /// modify the tensor in place, e.g. to add a steering vector to the residual stream, which
+    /// the rest of the pass then sees.
+    pub fn add_hook<F: Fn(&mut Tensor<f32>) + Send + Sync + 'static>(
+        &mut self,
+        name: &str,
+        hook: F,
+    ) -> Result<(), GptError> {
+        let id = self
+            .graph
+            .nodes()
+            .into_iter()
+            .find(|n| n.name == name)
+            .ok_or_else(|| GptError::UnknownTensor(name.into()))?
+            .id;
+        Ok(self.graph.add_hook(id, Arc::new(hook))?)
+    }
+
+    pub fn clear_hooks(&mut self) {
+        self.graph.clear_hooks();
+    }
+
+    /// Runs a forward and a backward pass on a batch, without updating the parameters. Returns
+    /// the loss.
+    pub fn forward_backward(
+        &mut self,
+        xs: &Tensor<usize>,
+        ys: &Tensor<usize>,
+    ) -> Result<f32, GptError> {
+        self.load_batch(xs, ys)?;
+        self.graph.forward(self.training)?;
+        self.graph.zero_grad()?;
+        Ok(self.graph.backward_all(self.loss, None, false)?)
+    }
+
+    /// Times `iterations` training steps on batches of `batch_size` windows of random tokens,
+    /// after a warm-up step (Compiling the kernels of GPU graphs). Each pass is waited for before
+    /// the next one starts. The learning rate is zero, the parameters only change through the
+    /// optimizer's state.
+    pub fn bench<R: Rng, O: Optimizer>(
+        &mut self,
+        rng: &mut R,
+        batch_size: usize,
+        iterations: usize,
+        optimizer: &O,
+    ) -> Result<BenchReport, GptError> {
+        let vocab_size = *self.graph.get(self.output)?.shape().last().unwrap();
+        let num_tokens = self.num_tokens;
+        let mut tokens = || {
+            Tensor::raw(
+                &[batch_size, num_tokens],
+                (0..batch_size * num_tokens)
+                    .map(|_| rng.gen_range(0..vocab_size))
+                    .collect(),
+            )
+        };
+        // Reading the smallest parameter back waits for the kernels of the optimizer
+        let probe = self
+            .graph
+            .params()
+            .iter()
+            .copied()
+            .min_by_key(|p| self.graph.get(*p).map_or(usize::MAX, |t| t.size()));
+        let mut times = [Duration::ZERO; 3];
+        for i in 0..=iterations {
+            let (xs, ys) = (tokens()?, tokens()?);
+            self.load_batch(&xs, &ys)?;
+            let timer = Instant::now();
+            self.graph.forward(self.training)?;
+            self.graph.fetch(self.loss, false)?;
+            let forward = timer.elapsed();
+            let timer = Instant::now();
+            self.graph.zero_grad()?;
+            self.graph.backward_all(self.loss, None, false)?;
+            let backward = timer.elapsed();
+            let timer = Instant::now();
+            self.graph.optimize(optimizer, 0.)?;
+            if let Some(probe) = probe {
+                self.graph.fetch(probe, false)?;
+            }
+            if i > 0 {
+                for (sum, t) in times.iter_mut().zip([forward, backward, timer.elapsed()]) {
+                    *sum += t;
+                }
+            }
+        }
+        let [forward, backward, optimizer] = times.map(|t| t / iterations.max(1) as u32);
+        let step = (forward + backward + optimizer).as_secs_f32();
+        Ok(BenchReport {
+            forward,
+            backward,
+            optimizer,
+            tokens_per_sec: (batch_size * self.num_tokens) as f32 / step.max(f32::MIN_POSITIVE),
+        })
+    }
+
+    // Loads the inputs of a training pass on the windows `xs`, predicting `ys`
+    fn load_batch(&mut self, xs: &Tensor<usize>, ys: &Tensor<usize>) -> Result<(), GptError> {
+        if let Some(pos) = &self.pos_input_fixed {
+            self.graph.load(self.pos_input, pos)?;
+        }
+        self.graph.load_usize(self.token_input, xs)?;
+        load_documents(&mut self.graph, self.documents, xs, false)?;
+        if let Some(logits) = self.teacher_logits(xs)? {
+            self.graph
+                .load(self.teacher_input.unwrap().input, &logits)?;
+        }
+        self.graph.load_usize(self.expected_output, ys)?;
+        load_loss_weights(&mut self.graph, self.loss_weights.as_ref(), ys)?;
+        Ok(())
+    }
+
+    /// The parameters of the model (Frozen ones included) in the order they were allocated, under
+    /// the names their checkpoints use, e.g. `head_0_1_k` for the key projection of the second
+    /// head of the first layer. Call `sync` first.
+    pub fn named_parameters(&self) -> Result<Vec<(&str, &Tensor<f32>)>, GptError> {
+        let mut params = self
+            .graph
+            .params()
+            .iter()
+            .chain(self.frozen.iter())
+            .copied()
+            .collect::<Vec<_>>();
+        params.sort();
+        params
+            .into_iter()
+            .map(|p| {
+                Ok((
+                    self.graph.name_of(p)?.as_str(),
+                    self.graph.get(p)?.as_float()?,
+                ))
+            })
+            .collect()
+    }
+
+    pub fn num_params(&self) -> usize {
+        self.graph
+            .params()
+            .to_vec()
+            .into_iter()
+            .map(|p| self.graph.get(p).unwrap().as_float().unwrap().size())
+            .sum::<usize>()
+    }
+
+    /// Loads the weights of `training_state`, and with `load_optimizer` its optimizer state and
+    /// position in the epochs. The optimizer starts over when the state has none (e.g. weights
+    /// written by `femto export --weights-only`).
+    pub fn set_training_state(
+        &mut self,
+        training_state: TrainingState,
+        load_optimizer: bool,
+    ) -> Result<(), GptError> {
+        // Frozen weights are loaded too, so that a base model can be loaded before its adapters
+        for p in self
+            .graph
+            .params()
+            .to_vec()
+            .into_iter()
+            .chain(self.frozen.clone())
+        {
+            let name = self.graph.name_of(p)?;
+            if let Some(t) = training_state.tensors.get(name) {
+                self.graph.load(p, t)?;
+            }
+        }
+        if load_optimizer {
+            self.graph.set_optimizer_state(&training_state.optimizer)?;
+            self.sampler = training_state.sampler.or(self.sampler);
+            self.progress = training_state.progress;
+            self.provenance = training_state.provenance.clone();
+        }
+        Ok(())
+    }
+
+    /// Folds the LoRA adapters into their base weights (`W + scale * A * B`), returning a
+    /// training state loadable by a regular model of the same architecture. Call `sync` first.
+    pub fn merge_lora(&self) -> Result<TrainingState, GptError> {
+        let lora = self.lora.ok_or(GptError::LoraNotEnabled)?;
+        let mut state = TrainingState {
+            tensors: Default::default(),
+            optimizer: Default::default(),
+            sampler: None,
+            progress: Default::default(),
+            provenance: Default::default(),
+        };
+        for p in self.frozen.iter() {
+            let k = self.graph.name_of(*p)?.to_string();
+            let v = self.graph.get(*p)?.as_float()?.clone();
+            state.tensors.insert(k, v);
+        }
+        for adapter in self.lora_adapters.iter() {
+            let a = self.graph.get(adapter.a)?.as_float()?;
+            let b = self.graph.get(adapter.b)?.as_float()?;
+            let w = self.graph.get(adapter.weights)?.as_float()?;
+            let delta = (a ^ b)?.map_values(|f| f * lora.scale());
+            let k = self.graph.name_of(adapter.weights)?.to_string();
+            state.tensors.insert(k, (w + &delta)?);
+        }
+        Ok(state)
+    }
+
+    /// Switches the precision of activations and gradients, master weights are kept in f32.
+    /// Training in f16 also enables dynamic loss scaling, bf16 has enough range without it.
+    pub fn set_precision(&mut self, precision: Precision) -> Result<(), GptError> {
+        self.graph.set_precision(precision)?;
+        self.loss_scaler = (precision == Precision::F16).then(LossScaler::new);
+        Ok(())
+    }
+
+    /// Makes training fail with `GraphError::NumericalAnomaly` as soon as a NaN/Inf value appears
+    /// in an activation or a gradient.
+    pub fn set_detect_anomaly(&mut self, enabled: bool) -> Result<(), GptError> {
+        Ok(self.graph.set_detect_anomaly(enabled)?)
+    }
+
+    /// Times the forward and backward passes per operation (The kernels on GPUs), except for the
+    /// ones of the callbacks of the training loops. See `take_profile`.
+    pub fn set_profiling(&mut self, enabled: bool) -> Result<(), GptError> {
+        self.profiling = enabled;
+        Ok(self.graph.set_profiling(enabled)?)
+    }
+    /// The timings recorded since profiling was enabled or the last call, summed over the
+    /// workers of data-parallel training.
+    pub fn take_profile(&mut self) -> Profile {
+        let mut profile = std::mem::take(&mut self.profile);
+        profile.merge(&self.graph.take_profile());
+        profile
+    }
+
+    /// Measures the gradients every `every` optimizer steps of the training loops, printing their
+    /// statistics along with the loss. Reading the gradients back from GPUs takes time, `None`
+    /// (The default) disables it.
+    pub fn set_grad_stats(&mut self, every: Option<usize>) {
+        self.grad_stats_every = every.filter(|n| *n > 0);
+    }
+
+    /// Gradient statistics of the last measured step (See `set_grad_stats`), e.g. for the
+    /// callbacks of the training loops.
+    pub fn grad_stats(&self) -> Option<GradStats> {
+        self.grad_stats
+    }
+
+    // Whether the gradients of the coming optimizer step are to be measured
+    fn grad_stats_due(&self) -> bool {
+        self.grad_stats_every
+            .is_some_and(|n| self.graph.optimizer_step().is_multiple_of(n))
+    }
+
+    // Records the statistics of the gradients of the coming optimizer step given their squared
+    // norm, and in data-parallel loops the mean squared norm of the gradients of the shares of the
+    // batch, of `share` sequences on average
+    fn record_grad_stats(
+        &mut self,
+        squared_norm: f32,
+        batch_size: usize,
+        shares: Option<(f32, f32)>,
+    ) -> GradStats {
+        let noise_scale = shares.and_then(|(small, share)| {
+            self.noise_scale
+                .update(small, share, squared_norm, batch_size as f32)
+        });
+        let stats = GradStats {
+            step: self.graph.optimizer_step(),
+            grad_norm: squared_norm.sqrt(),
+            noise_scale,
+        };
+        self.grad_stats = Some(stats);
+        stats
+    }
+
+    /// Watches the losses of the training loops: on a spike (See `SpikeGuard::threshold`), the
+    /// step is skipped, the model goes back to its last good state (Kept in memory when the
+    /// callbacks run, i.e. where checkpoints are saved), and the learning rate is reduced for a
+    /// while. The sampling of windows carries on, skipping the offending ones. Disabled by default.
+    pub fn set_spike_guard(&mut self, guard: Option<SpikeGuard>) {
+        self.spike_guard = guard;
+        self.spikes = Default::default();
+    }
+
+    /// Stops the training loops once the model has trained on `budget.tokens` tokens or for
+    /// `budget.duration`, counting the runs of the checkpoint it resumed from (See `progress`).
+    /// The callback of the loop gets the final state, e.g. to save it.
+    pub fn set_budget(&mut self, budget: Budget) {
+        self.budget = budget;
+    }
+
+    pub fn progress(&self) -> Progress {
+        self.progress
+    }
+
+    /// Records the hashes of the dataset and tokenizer the model is trained on, saved in its
+    /// checkpoints along with the last loss.
+    pub fn set_data_hashes(&mut self, dataset: String, tokenizer: String) {
+        self.provenance.dataset = Some(dataset);
+        self.provenance.tokenizer = Some(tokenizer);
+    }
+
+    pub fn provenance(&self) -> &Provenance {
+        &self.provenance
+    }
+
+    // Brings the progress up to date before a step on `batch_size` windows, `run` being the start
+    // of the training loop and the time trained before it. Once the budget is spent, the state is
+    // handed to the callback instead and there's no step.
+    fn begin_step<E: From<GptError> + From<GraphError>, C: Fn(&mut Self) -> Result<(), E>>(
+        &mut self,
+        run: (Instant, Duration),
+        batch_size: usize,
+        callback: &C,
+    ) -> Result<bool, E> {
+        self.progress.elapsed = run.1 + run.0.elapsed();
+        if self.budget.is_spent(&self.progress) {
+            info!(
+                tokens = self.progress.tokens,
+                elapsed_s = self.progress.elapsed.as_secs(),
+                "Training budget spent"
+            );
+            self.sync()?;
+            self.eval_callback(callback)?;
+            return Ok(false);
+        }
+        self.progress.tokens += (batch_size * self.num_tokens) as u64;
+        Ok(true)
+    }
+
+    // Keeps the current state as the one to go back to on spikes
+    fn keep_good_state(&mut self) -> Result<(), GptError> {
+        if self.spike_guard.is_some() {
+            self.sync()?;
+            self.spikes.last_good = Some(self.get_training_state()?);
+        }
+        Ok(())
+    }
+
+    // Checks the loss of a training step before the optimizer applies it, returns `false` if it
+    // was a spike, the model then having been rolled back
+    fn guard_loss(&mut self, loss: f32) -> Result<bool, GptError> {
+        let Some(guard) = self.spike_guard else {
+            return Ok(true);
+        };
+        let spike = !loss.is_finite()
+            || self.spikes.loss_ema.is_some_and(|(ema, steps)| {
+                steps >= SpikeMonitor::WARMUP && loss > ema * guard.threshold
+            });
+        if !spike {
+            self.spikes.loss_ema = Some(match self.spikes.loss_ema {
+                Some((ema, steps)) => (
+                    SpikeMonitor::DECAY * ema + (1. - SpikeMonitor::DECAY) * loss,
+                    steps + 1,
+                ),
+                None => (loss, 1),
+            });
+            return Ok(true);
+        }
+        match self.spikes.last_good.clone() {
+            Some(state) => {
+                warn!(
+                    loss,
+                    good_step = state.optimizer.step,
+                    "Loss spike, rolling back to the last good state"
+                );
+                // The progress and the sampling carry on, only the model goes back
+                self.graph.set_optimizer_state(&state.optimizer)?;
+                self.set_training_state(state, false)?;
+            }
+            None => warn!(loss, "Loss spike, skipping the step"),
+        }
+        self.spikes.cooldown = guard.cooldown;
+        Ok(false)
+    }
+
+    // Multiplier of the learning rate of the coming optimizer step
+    fn spike_lr_factor(&mut self) -> f32 {
+        match self.spike_guard {
+            Some(guard) if self.spikes.cooldown > 0 => {
+                self.spikes.cooldown -= 1;
+                guard.lr_factor
+            }
+            _ => 1.,
+        }
+    }
+
+    /// Switches between training mode (The default), where dropout is applied, and evaluation
+    /// mode, which every forward pass (Including `infer`) runs in. Training loops run their
+    /// callbacks in evaluation mode.
+    pub fn set_training(&mut self, training: bool) {
+        self.training = training;
+    }
+
+    pub fn is_training(&self) -> bool {
+        self.training
+    }
+
+    /// Whether the model is a masked language model (See `GptBuilder::masked_lm`), which can't
+    /// generate text.
+    pub fn is_masked_lm(&self) -> bool {
+        self.masked_lm.is_some()
+    }
+
+    /// Picks how the training loops sample windows of the dataset. Sampling by epochs continues
+    /// from the position saved in the last loaded training state (See `set_training_state`), if
+    /// any, and from a new shuffled epoch otherwise.
+    pub fn set_sampling(&mut self, sampling: Sampling) {
+        self.sampler = match sampling {
+            Sampling::Random => None,
+            Sampling::Epochs => Some(
+                self.sampler
+                    .unwrap_or_else(|| SamplerState::new(&mut rand::thread_rng())),
+            ),
+        };
+    }
+
+    /// Sets the model distilled into this one (Which must be built with
+    /// `GptBuilder::distillation`): the training loops run it on every batch, in evaluation mode,
+    /// and train on its logits. It must share the vocabulary, and see at least as many tokens.
+    pub fn set_teacher(&mut self, mut teacher: GPT<G>) -> Result<(), GptError> {
+        if self.teacher_input.is_none() {
+            return Err(GptError::InvalidConfig(
+                "the model is not built for distillation".into(),
+            ));
+        }
+        if teacher.num_tokens < self.num_tokens {
+            return Err(GptError::InvalidConfig(format!(
+                "the teacher sees {} tokens, fewer than the {} of the student",
+                teacher.num_tokens, self.num_tokens
+            )));
+        }
+        teacher.set_training(false);
+        self.teacher = Some(Box::new(teacher));
+        Ok(())
+    }
+
+    /// Sets the model `train_dpo` measures the log-probabilities of the completions against,
+    /// usually the model before the preference training (e.g. after its instruction fine-tuning).
+    /// It stays frozen and runs in evaluation mode. It must share the vocabulary, and see at least
+    /// as many tokens.
+    pub fn set_reference(&mut self, mut reference: GPT<G>) -> Result<(), GptError> {
+        if reference.num_tokens < self.num_tokens {
+            return Err(GptError::InvalidConfig(format!(
+                "the reference sees {} tokens, fewer than the {} of the model",
+                reference.num_tokens, self.num_tokens
+            )));
+        }
+        reference.set_training(false);
+        self.reference = Some(Box::new(reference));
+        Ok(())
+    }
+
+    // The logits of the teacher for the windows `xs` (Of shape `[.., num_tokens]`), if any
+    fn teacher_logits(&mut self, xs: &Tensor<usize>) -> Result<Option<Tensor<f32>>, GptError> {
+        let (teacher, input) = match (self.teacher.as_mut(), self.teacher_input) {
+            (Some(teacher), Some(input)) => (teacher, input),
+            _ => return Ok(None),
+        };
+        let windows = xs.blob().chunks(self.num_tokens).collect::<Vec<_>>();
+        let output = teacher.output;
+        let logits = teacher.forward_windows(&windows, output)?;
+        let mut shape = xs.shape().to_vec();
+        shape.push(input.vocab_size);
+        Ok(Some(Tensor::raw(
+            &shape,
+            logits.into_iter().flatten().flatten().collect(),
+        )?))
+    }
+
+    // Runs the callback of a training loop in evaluation mode
+    fn eval_callback<E: From<GptError> + From<GraphError>, C: Fn(&mut Self) -> Result<(), E>>(
+        &mut self,
+        callback: &C,
+    ) -> Result<(), E> {
+        let training = std::mem::replace(&mut self.training, false);
+        // The passes of the callback (e.g. generating text) would skew the profile of training
+        if self.profiling {
+            self.graph.set_profiling(false)?;
+        }
+        let result = callback(self);
+        self.training = training;
+        if self.profiling {
+            self.graph.set_profiling(true)?;
+        }
+        result
+    }
+
+    // Decides whether the gradients of this step should be applied, adjusting the loss scale
+    fn check_grads<'a>(&mut self, grads: impl IntoIterator<Item = &'a Tensor<f32>>) -> bool {
+        match self.loss_scaler.as_mut() {
+            Some(scaler) => {
+                let finite = grads
+                    .into_iter()
+                    .all(|g| g.blob().iter().all(|f| f.is_finite()));
+                if !finite {
+                    warn!(
+                        loss_scale = scaler.scale(),
+                        "Gradient overflow, skipping the step"
+                    );
+                }
+                scaler.update(finite)
+            }
+            None => true,
+        }
+    }
+
+    /// Converts the model into its quantized form, used for inference only. Call `sync` first.
+    pub fn quantize(&self, format: QuantFormat) -> Result<QuantizedState, GptError> {
+        let mut state = QuantizedState {
+            tensors: Default::default(),
+            quantized: Default::default(),
+        };
+        for p in self.graph.params().iter().chain(self.frozen.iter()) {
+            let k = self.graph.name_of(*p)?.to_string();
+            let v = self.graph.get(*p)?.as_float()?;
+            if self.linear_weights.contains(p) {
+                state
+                    .quantized
+                    .insert(k, QuantizedTensor::quantize(v, format)?);
+            } else {
+                state.tensors.insert(k, v.clone());
+            }
+        }
+        Ok(state)
+    }
+
+    // Lowers a backward scope into the computation limit and pruning flag understood by graphs
+    fn backward_params(&self, scope: BackwardScope) -> Result<(Option<usize>, bool), GptError> {
+        match scope {
+            BackwardScope::Full => Ok((None, false)),
+            BackwardScope::ParamsOnly => Ok((None, true)),
+            BackwardScope::LastLayers(n) => {
+                let num_layers = self.layer_starts.len();
+                if n == 0 || n > num_layers {
+                    return Err(GraphError::InvalidBackwardScope(format!(
+                        "cannot backpropagate through {} layers of a {}-layer model",
+                        n, num_layers
+                    ))
+                    .into());
+                }
+                let start = self.layer_starts[num_layers - n];
+                Ok((Some(self.graph.num_computations() - start), false))
+            }
+        }
+    }
+
+    pub fn get_training_state(&self) -> Result<TrainingState, GptError> {
+        let mut state = TrainingState {
+            tensors: Default::default(),
+            optimizer: self.graph.get_optimizer_state()?,
+            sampler: self.sampler,
+            progress: self.progress,
+            provenance: self.provenance.clone(),
+        };
+        for p in self.graph.params().iter() {
+            let k = self.graph.name_of(*p)?.to_string();
+            let v = self.graph.get(*p)?.as_float()?.clone();
+            state.tensors.insert(k, v);
+        }
+        Ok(state)
+    }
+
+    // Gives `replicas` the weights and optimizer state of `self`
+    fn sync_replicas(&mut self, replicas: &mut [Self]) -> Result<(), GptError> {
+        self.sync()?;
+        let optimizer_state = self.graph.get_optimizer_state()?;
+        for replica in replicas.iter_mut() {
+            for p in self.graph.params().iter().chain(self.frozen.iter()) {
+                replica
+                    .graph
+                    .load(*p, self.graph.get(*p)?.as_float().map_err(GptError::from)?)?;
+            }
+            replica.graph.set_optimizer_state(&optimizer_state)?;
+            replica.training = self.training;
+        }
+        Ok(())
+    }
+
+    /// Data-parallel training on CPUs: the batch is split among `config.num_workers` replicas of
+    /// the graph (One per rayon thread when 0), whose gradients are averaged before a single
+    /// optimizer step.
+    pub fn train_cpu<
+        D: Corpus + ?Sized,
+        O: Optimizer,
+        E: From<GptError> + From<GraphError>,
+        C: Fn(&mut Self) -> Result<(), E>,
+    >(
+        &mut self,
+        corpus: &D,
+        config: &TrainConfig,
+        optimizer: &O,
+        callback: C,
+    ) -> Result<(), E>
+    where
+        G: Clone + Send + Sync,
+    {
+        let (num_batches, batch_size, num_workers) =
+            (config.num_batches, config.batch_size, config.num_workers);
+        let _span = info_span!("train_cpu", num_batches, batch_size, num_workers).entered();
+        let (limit, params_only) = self.backward_params(config.backward_scope)?;
+        if let Some(pos) = &self.pos_input_fixed {
+            self.graph.load(self.pos_input, pos)?;
+        }
+        self.keep_good_state()?;
+
+        // Every worker owns a replica of the graph for the whole run and processes its share of
+        // each batch sequentially, accumulating the gradients of the parameters.
+        let num_workers = if num_workers == 0 {
+            rayon::current_num_threads()
+        } else {
+            num_workers
+        }
+        .clamp(1, batch_size);
+        let mut replicas = vec![self.graph.clone(); num_workers];
+        // The replicas time their own passes, not the ones of `self` so far
+        for replica in replicas.iter_mut() {
+            replica.take_profile();
+        }
+        let params = self.graph.params().to_vec();
+        let mut rng = rand::thread_rng();
+        let mut sampler = self
+            .sampler
+            .map(|s| EpochSampler::of(corpus, self.num_tokens, s));
+
+        let run = (Instant::now(), self.progress.elapsed);
+        for i in 0..num_batches {
+            if !self.begin_step(run, batch_size, &callback)? {
+                break;
+            }
+            let timer = Instant::now();
+            let loss_scale = self.loss_scaler.as_ref().map_or(1., |s| s.scale());
+            // The windows of every worker are drawn upfront, in the order of the epoch
+            let batches = (0..num_workers)
+                .map(|w| {
+                    (0..batch_share(batch_size, num_workers, w))
+                        .map(|_| {
+                            next_batch(
+                                &mut sampler,
+                                corpus,
+                                1,
+                                self.num_tokens,
+                                self.masked_lm,
+                                &mut rng,
+                            )
+                        })
+                        .collect::<Vec<_>>()
+                })
+                .collect::<Vec<_>>();
+            let sources = batches
+                .iter()
+                .flatten()
+                .flat_map(|b| b.sources.iter().copied())
+                .collect::<Vec<_>>();
+            let teacher_logits = batches
+                .iter()
+                .map(|batches| {
+                    batches
+                        .iter()
+                        .map(|b| self.teacher_logits(&b.xs))
+                        .collect::<Result<Vec<_>, GptError>>()
+                })
+                .collect::<Result<Vec<_>, GptError>>()?;
+            self.sampler = sampler.as_ref().map(EpochSampler::state);
+            let results = replicas
+                .par_iter_mut()
+                .zip(batches.into_iter().zip(teacher_logits).collect::<Vec<_>>())
+                .map(|(graph, (batches, teacher_logits))| {
+                    graph.set_loss_scale(loss_scale)?;
+                    let mut grads = Vec::with_capacity(params.len());
+                    for p in params.iter() {
+                        let param = self.graph.get(*p)?.as_float()?;
+                        graph.load(*p, param)?;
+                        grads.push(Tensor::<f32>::zeros(param.shape()));
+                    }
+
+                    let mut errs = Vec::with_capacity(batches.len());
+                    for (batch, logits) in batches.into_iter().zip(teacher_logits) {
+                        graph.load_usize(self.token_input, &batch.xs)?;
+                        load_documents(graph, self.documents, &batch.xs, false)?;
+                        if let (Some(teacher), Some(logits)) = (self.teacher_input, logits) {
+                            graph.load(teacher.input, &logits)?;
+                        }
+                        graph.load_usize(self.expected_output, &batch.ys)?;
+                        load_loss_weights(graph, self.loss_weights.as_ref(), &batch.ys)?;
+                        graph.forward(self.training)?;
+                        graph.zero_grad()?;
+                        errs.push(graph.backward_all(self.loss, limit, params_only)?);
+                        for (grad, p) in grads.iter_mut().zip(params.iter()) {
+                            *grad = (&*grad + graph.get_grad(*p)?)?;
+                        }
+                    }
+                    Ok((grads, errs))
+                })
+                .collect::<Result<Vec<_>, GraphError>>()
+                .map_err(|e| match e {
+                    // Replicas don't run the optimizer, their step count is stale
+                    GraphError::NumericalAnomaly {
+                        tensor_id,
+                        tensor_name,
+                        op_name,
+                        ..
+                    } => GraphError::NumericalAnomaly {
+                        tensor_id,
+                        tensor_name,
+                        op_name,
+                        step: self.graph.optimizer_step(),
+                    },
+                    e => e,
+                })?;
+
+            // Every worker's share of the batch gives a gradient over a smaller batch
+            let shares = self.grad_stats_due().then(|| {
+                let small = results
+                    .iter()
+                    .map(|(grads, errs)| {
+                        squared_norm(grads) / (errs.len() as f32 * loss_scale).powi(2)
+                    })
+                    .sum::<f32>()
+                    / num_workers as f32;
+                (small, batch_size as f32 / num_workers as f32)
+            });
+            let mut errs = Vec::with_capacity(batch_size);
+            let mut grad_sums = params
+                .iter()
+                .map(|p| Ok(Tensor::<f32>::zeros(self.graph.get(*p)?.shape())))
+                .collect::<Result<Vec<_>, GptError>>()?;
+            for (grads, worker_errs) in results {
+                errs.extend(worker_errs);
+                for (sum, grad) in grad_sums.iter_mut().zip(grads.iter()) {
+                    *sum = (&*sum + grad).map_err(GptError::from)?;
+                }
+            }
+            let avg_loss = errs.iter().sum::<f32>() / errs.len() as f32;
+            if !self.guard_loss(avg_loss)? || !self.check_grads(grad_sums.iter()) {
+                continue;
+            }
+            self.provenance.loss = Some(avg_loss);
+            let grad_stats = shares.map(|shares| {
+                self.record_grad_stats(
+                    squared_norm(&grad_sums) / (batch_size as f32 * loss_scale).powi(2),
+                    batch_size,
+                    Some(shares),
+                )
+            });
+            for (id, sum) in params.iter().zip(grad_sums) {
+                self.graph.load_grad(
+                    *id,
+                    &sum.map_values(|f| f / (batch_size as f32 * loss_scale)),
+                )?;
+            }
+            let lr = config.learning_rate.at(self.graph.optimizer_step()) * self.spike_lr_factor();
+            self.graph.optimize(optimizer, lr)?;
+            if i % 10 == 0 {
+                self.keep_good_state()?;
+                self.sync()?;
+                self.eval_callback(&callback)?;
+            }
+            log_step(
+                self.graph.optimizer_step(),
+                avg_loss,
+                grad_stats,
+                source_stats(corpus.num_sources(), &sources, Some(&errs)),
+                &sampler,
+                timer.elapsed(),
+            );
+        }
+        for replica in replicas.iter_mut() {
+            self.profile.merge(&replica.take_profile());
+        }
+        Ok(())
+    }
+
+    /// Data-parallel training on multiple graphs (e.g. `GpuGraph`s on different devices): the
+    /// batch is split between `self` and `replicas`, whose gradients are averaged on the host.
+    /// Every model then applies the same optimizer step, so that their parameters stay in sync.
+    /// Models should pre-allocate batches of `batch_share(batch_size, replicas.len() + 1, i)`
+    /// sequences, `self` being the first one.
+    pub fn train_data_parallel<
+        D: Corpus + ?Sized,
+        O: Optimizer,
+        E: From<GptError> + From<GraphError>,
+        C: Fn(&mut Self) -> Result<(), E>,
+    >(
+        &mut self,
+        replicas: &mut [Self],
+        corpus: &D,
+        config: &TrainConfig,
+        optimizer: &O,
+        callback: C,
+    ) -> Result<(), E>
+    where
+        G: Send,
+    {
+        let (num_batches, batch_size) = (config.num_batches, config.batch_size);
+        let _span = info_span!(
+            "train_data_parallel",
+            num_batches,
+            batch_size,
+            num_models = replicas.len() + 1
+        )
+        .entered();
+        let (limit, params_only) = self.backward_params(config.backward_scope)?;
+        if self.teacher.is_some() {
+            return Err(GptError::InvalidConfig(
+                "distillation is not supported by data-parallel training".into(),
+            )
+            .into());
+        }
+        let params = self.graph.params().to_vec();
+        let num_models = replicas.len() + 1;
+
+        // Replicas start from the weights and optimizer state of `self`
+        self.sync_replicas(replicas)?;
+        self.keep_good_state()?;
+
+        let mut rng = rand::thread_rng();
+        let mut sampler = self
+            .sampler
+            .map(|s| EpochSampler::of(corpus, self.num_tokens, s));
+        let run = (Instant::now(), self.progress.elapsed);
+        for i in 0..num_batches {
+            if !self.begin_step(run, batch_size, &callback)? {
+                break;
+            }
+            let timer = Instant::now();
+            let loss_scale = self.loss_scaler.as_ref().map_or(1., |s| s.scale());
+            let batches = (0..num_models)
+                .map(|m| {
+                    let share = batch_share(batch_size, num_models, m);
+                    next_batch(
+                        &mut sampler,
+                        corpus,
+                        share,
+                        self.num_tokens,
+                        self.masked_lm,
+                        &mut rng,
+                    )
+                })
+                .collect::<Vec<_>>();
+            let sources = batches
+                .iter()
+                .flat_map(|b| b.sources.iter().copied())
+                .collect::<Vec<_>>();
+            self.sampler = sampler.as_ref().map(EpochSampler::state);
+            let mut models = std::iter::once(&mut *self)
+                .chain(replicas.iter_mut())
+                .collect::<Vec<_>>();
+            let results = models
+                .par_iter_mut()
+                .zip(batches)
+                .map(|(model, batch)| {
+                    let share = batch.xs.shape()[0];
+                    if let Some(pos) = &model.pos_input_fixed {
+                        model.graph.load(model.pos_input, pos)?;
+                    }
+                    model.graph.load_usize(model.token_input, &batch.xs)?;
+                    load_documents(&mut model.graph, model.documents, &batch.xs, false)?;
+                    model.graph.load_usize(model.expected_output, &batch.ys)?;
+                    load_loss_weights(&mut model.graph, model.loss_weights.as_ref(), &batch.ys)?;
+                    model.graph.set_loss_scale(loss_scale)?;
+                    model.graph.forward(model.training)?;
+                    model.graph.zero_grad()?;
+                    let err = model.graph.backward_all(model.loss, limit, params_only)?;
+                    // Gradients are averaged over each model's share, weight them by its size
+                    let mut grads = Vec::with_capacity(params.len());
+                    for p in params.iter() {
+                        model.graph.fetch(*p, true)?;
+                        grads.push(model.graph.get_grad(*p)?.map_values(|f| f * share as f32));
+                    }
+                    Ok((grads, err * share as f32))
+                })
+                .collect::<Result<Vec<_>, GptError>>()?;
+
+            drop(models);
+            // Every model's share of the batch gives a gradient over a smaller batch
+            let shares = self.grad_stats_due().then(|| {
+                let small = results
+                    .iter()
+                    .enumerate()
+                    .map(|(m, (grads, _))| {
+                        let share = batch_share(batch_size, num_models, m) as f32;
+                        squared_norm(grads) / (share * share * loss_scale * loss_scale)
+                    })
+                    .sum::<f32>()
+                    / num_models as f32;
+                (small, batch_size as f32 / num_models as f32)
+            });
+            let mut loss_sum = 0.;
+            let mut grad_sums = params
+                .iter()
+                .map(|p| Ok(Tensor::<f32>::zeros(self.graph.get(*p)?.shape())))
+                .collect::<Result<Vec<_>, GptError>>()?;
+            for (grads, loss) in results {
+                loss_sum += loss;
+                for (sum, grad) in grad_sums.iter_mut().zip(grads.iter()) {
+                    *sum = (&*sum + grad).map_err(GptError::from)?;
+                }
+            }
+            if !self.guard_loss(loss_sum / batch_size as f32)? {
+                // The replicas go back to the restored state too
+                self.sync_replicas(replicas)?;
+                continue;
+            }
+            if !self.check_grads(grad_sums.iter()) {
+                continue;
+            }
+            self.provenance.loss = Some(loss_sum / batch_size as f32);
+            let grads = grad_sums
+                .into_iter()
+                .map(|sum| sum.map_values(|f| f / (batch_size as f32 * loss_scale)))
+                .collect::<Vec<_>>();
+            let grad_stats = shares.map(|shares| {
+                self.record_grad_stats(squared_norm(&grads), batch_size, Some(shares))
+            });
+            let lr = config.learning_rate.at(self.graph.optimizer_step()) * self.spike_lr_factor();
+            for model in std::iter::once(&mut *self).chain(replicas.iter_mut()) {
+                for (id, grad) in params.iter().zip(grads.iter()) {
+                    model.graph.load_grad(*id, grad)?;
+                }
+                model.graph.optimize(optimizer, lr)?;
+            }
+            if i % 50 == 0 {
+                self.keep_good_state()?;
+                self.eval_callback(&callback)?;
+            }
+            log_step(
+                self.graph.optimizer_step(),
+                loss_sum / batch_size as f32,
+                grad_stats,
+                source_stats(corpus.num_sources(), &sources, None),
+                &sampler,
+                timer.elapsed(),
+            );
+        }
+        for replica in replicas.iter_mut() {
+            let profile = replica.take_profile();
+            self.profile.merge(&profile);
+        }
+        Ok(())
+    }
+
+    pub fn train<
+        D: Corpus + ?Sized,
+        O: Optimizer,
+        E: From<GptError> + From<GraphError>,
+        C: Fn(&mut Self) -> Result<(), E>,
+    >(
+        &mut self,
+        corpus: &D,
+        config: &TrainConfig,
+        optimizer: &O,
+        callback: C,
+    ) -> Result<(), E> {
+        let (num_batches, batch_size) = (config.num_batches, config.batch_size);
+        let _span = info_span!("train", num_batches, batch_size).entered();
+        let (limit, params_only) = self.backward_params(config.backward_scope)?;
+        if let Some(pos) = &self.pos_input_fixed {
+            self.graph.load(self.pos_input, pos)?;
+        }
+        self.keep_good_state()?;
+
+        let mut rng = rand::thread_rng();
+        let mut sampler = self
+            .sampler
+            .map(|s| EpochSampler::of(corpus, self.num_tokens, s));
+        let batch = next_batch(
+            &mut sampler,
+            corpus,
+            batch_size,
+            self.num_tokens,
+            self.masked_lm,
+            &mut rng,
+        );
+        self.graph.load_usize(self.token_input, &batch.xs)?;
+        load_documents(&mut self.graph, self.documents, &batch.xs, false)?;
+        self.graph.load_usize(self.expected_output, &batch.ys)?;
+        load_loss_weights(&mut self.graph, self.loss_weights.as_ref(), &batch.ys)?;
+        if let Some(logits) = self.teacher_logits(&batch.xs)? {
+            self.graph
+                .load(self.teacher_input.unwrap().input, &logits)?;
+        }
+        let mut sources = batch.sources;
+
+        let run = (Instant::now(), self.progress.elapsed);
+        for i in 0..num_batches {
+            if !self.begin_step(run, batch_size, &callback)? {
+                break;
+            }
+            let timer = Instant::now();
+
+            let loss_scale = self.loss_scaler.as_ref().map_or(1., |s| s.scale());
+            self.graph.set_loss_scale(loss_scale)?;
+            self.graph.forward(self.training)?;
+
+            // The next batch is uploaded while this one is processed, it's not trained on yet
+            // as far as checkpoints are concerned
+            self.sampler = sampler.as_ref().map(EpochSampler::state);
+            let next = next_batch(
+                &mut sampler,
+                corpus,
+                batch_size,
+                self.num_tokens,
+                self.masked_lm,
+                &mut rng,
+            );
+            self.graph.stage_usize(self.token_input, &next.xs)?;
+            load_documents(&mut self.graph, self.documents, &next.xs, true)?;
+            self.graph.stage_usize(self.expected_output, &next.ys)?;
+            let batch_sources = std::mem::replace(&mut sources, next.sources);
+            let next_teacher_logits = self.teacher_logits(&next.xs)?;
+
+            self.graph.zero_grad()?;
+            let err = self.graph.backward_all(self.loss, limit, params_only)?;
+            // The teacher's logits and the loss weights aren't staged, the backward pass still
+            // needed the current ones
+            if let Some(logits) = &next_teacher_logits {
+                self.graph.load(self.teacher_input.unwrap().input, logits)?;
+            }
+            load_loss_weights(&mut self.graph, self.loss_weights.as_ref(), &next.ys)?;
+            if !self.guard_loss(err)? {
+                continue;
+            }
+            self.provenance.loss = Some(err);
+            if self.loss_scaler.is_some() {
+                let params = self.graph.params().to_vec();
+                let mut grads = Vec::with_capacity(params.len());
+                for p in params.iter() {
+                    self.graph.fetch(*p, true)?;
+                    grads.push(self.graph.get_grad(*p)?.clone());
+                }
+                if !self.check_grads(grads.iter()) {
+                    continue;
+                }
+                for (p, grad) in params.into_iter().zip(grads) {
+                    self.graph
+                        .load_grad(p, &grad.map_values(|f| f / loss_scale))?;
+                }
+            }
+            let grad_stats = if self.grad_stats_due() {
+                let params = self.graph.params().to_vec();
+                let mut sum = 0.;
+                for p in params {
+                    self.graph.fetch(p, true)?;
+                    sum += squared_norm([self.graph.get_grad(p)?]);
+                }
+                Some(self.record_grad_stats(sum, batch_size, None))
+            } else {
+                None
+            };
+            let lr = config.learning_rate.at(self.graph.optimizer_step()) * self.spike_lr_factor();
+            self.graph.optimize(optimizer, lr)?;
+            if i % 50 == 0 {
+                self.keep_good_state()?;
+                self.eval_callback(&callback)?;
+                // Inference replaced the inputs, along with the staged batch
+                self.graph.load_usize(self.token_input, &next.xs)?;
+                load_documents(&mut self.graph, self.documents, &next.xs, false)?;
+                self.graph.load_usize(self.expected_output, &next.ys)?;
+                load_loss_weights(&mut self.graph, self.loss_weights.as_ref(), &next.ys)?;
+                if let Some(logits) = &next_teacher_logits {
+                    self.graph.load(self.teacher_input.unwrap().input, logits)?;
+                }
+            }
+            log_step(
+                self.graph.optimizer_step(),
+                err,
+                grad_stats,
+                source_stats(corpus.num_sources(), &batch_sources, None),
+                &sampler,
+                timer.elapsed(),
+            );
+        }
+        Ok(())
+    }
+
+    /// Fine-tunes the embeddings of the model (See `embed`) on pairs of texts meant to be close,
+    /// e.g. a question and its answer, or a sentence and its paraphrase. Every step draws
+    /// `config.batch_size` pairs, and pulls each anchor towards its positive while pushing it away
+    /// from the positives of the other pairs, its in-batch negatives (See `InfoNce`). The model
+    /// must be built with `GptBuilder::contrastive`. CPU graphs process the texts one by one,
+    /// without workers. Texts are truncated to their last `num_tokens` tokens.
+    pub fn train_contrastive<
+        O: Optimizer,
+        E: From<GptError> + From<GraphError>,
+        C: Fn(&mut Self) -> Result<(), E>,
+    >(
+        &mut self,
+        pairs: &[(Vec<usize>, Vec<usize>)],
+        config: &TrainConfig,
+        optimizer: &O,
+        callback: C,
+    ) -> Result<(), E> {
+        let (num_batches, batch_size) = (config.num_batches, config.batch_size);
+        let _span = info_span!("train_contrastive", num_batches, batch_size).entered();
+        let contrastive = self.contrastive.ok_or_else(|| {
+            GptError::InvalidConfig("the model isn't built for contrastive training".into())
+        })?;
+        let batch_size = batch_size.min(pairs.len());
+        if batch_size < 2 {
+            return Err(GptError::InvalidConfig(
+                "contrastive training needs batches of at least 2 pairs".into(),
+            )
+            .into());
+        }
+        if let Some(i) = pairs
+            .iter()
+            .position(|(anchor, positive)| anchor.is_empty() || positive.is_empty())
+        {
+            return Err(GptError::EmptyPrompt(i).into());
+        }
+        let (limit, params_only) = self.backward_params(config.backward_scope)?;
+        if let Some(pos) = &self.pos_input_fixed {
+            self.graph.load(self.pos_input, pos)?;
+        }
+        self.keep_good_state()?;
+        // The gradients are computed in f32, without the loss scaling of lower precisions
+        self.graph.set_loss_scale(1.)?;
+
+        let mut rng = rand::thread_rng();
+        let run = (Instant::now(), self.progress.elapsed);
+        for i in 0..num_batches {
+            if !self.begin_step(run, 2 * batch_size, &callback)? {
+                break;
+            }
+            let timer = Instant::now();
+            let picked = rand::seq::index::sample(&mut rng, pairs.len(), batch_size);
+            let window = |t: &[usize]| t[t.len().saturating_sub(self.num_tokens)..].to_vec();
+            // The anchors, then the positives
+            let rows = picked
+                .iter()
+                .map(|p| window(&pairs[p].0))
+                .chain(picked.iter().map(|p| window(&pairs[p].1)))
+                .collect::<Vec<_>>();
+            let rows = rows.iter().map(|r| r.as_slice()).collect::<Vec<_>>();
+
+            let loss = self.contrastive_backward(
+                contrastive.config,
+                &rows,
+                batch_size,
+                limit,
+                params_only,
+            )?;
+            if !self.guard_loss(loss)? {
+                continue;
+            }
+            self.provenance.loss = Some(loss);
+
+            let lr = config.learning_rate.at(self.graph.optimizer_step()) * self.spike_lr_factor();
+            self.graph.optimize(optimizer, lr)?;
+            if i % 50 == 0 {
+                self.keep_good_state()?;
+                self.eval_callback(&callback)?;
+            }
+            log_step(
+                self.graph.optimizer_step(),
+                loss,
+                None,
+                String::new(),
+                &None,
+                timer.elapsed(),
+            );
+        }
+        Ok(())
+    }
+
+    // Forward and backward passes of a step of `train_contrastive` on `rows`, the anchors of
+    // `batch_size` pairs followed by their positives. Returns the loss, leaving the gradients of
+    // the parameters in the graph.
+    fn contrastive_backward(
+        &mut self,
+        config: ContrastiveConfig,
+        rows: &[&[usize]],
+        batch_size: usize,
+        limit: Option<usize>,
+        params_only: bool,
+    ) -> Result<f32, GptError> {
+        let ContrastiveInput {
+            grad: grad_input,
+            probe,
+            ..
+        } = self.contrastive.unwrap();
+        let params = self.graph.params().to_vec();
+        let mut embeddings = Vec::with_capacity(rows.len());
+        for states in self.forward_windows(rows, self.hidden)? {
+            embeddings.push(config.pooling.pool(states));
+        }
+        let degree = embeddings[0].len();
+        let (anchors, positives) = embeddings.split_at(batch_size);
+        let anchors = GeneralTensor::Float(Tensor::raw(&[batch_size, degree], anchors.concat())?);
+        let positives =
+            GeneralTensor::Float(Tensor::raw(&[batch_size, degree], positives.concat())?);
+        let mut loss_fn = InfoNce::new(config.temperature);
+        let loss = loss_fn.run(&[&anchors, &positives], true)?.mean();
+        let grads = loss_fn.grad(
+            &[&anchors, &positives],
+            &Tensor::constant(&[batch_size], 1. / batch_size as f32),
+        )?;
+        let embedding_grads = grads
+            .iter()
+            .flat_map(|g| g.blob().chunks(degree))
+            .collect::<Vec<_>>();
+
+        // The gradient of each embedding goes to the hidden states it pools, through the product
+        // of both. CPU graphs backpropagate a sequence at a time, GPU ones a batch of the size
+        // they were built with (Padded with empty rows).
+        let chunk_size = self.batch_size.unwrap_or(1);
+        let pad = self.documents.and_then(|d| d.pad).unwrap_or(0);
+        let chunks = rows.chunks(chunk_size).collect::<Vec<_>>();
+        let mut grad_sums = Vec::new();
+        for (c, chunk) in chunks.iter().enumerate() {
+            let mut context = vec![pad; chunk_size * self.num_tokens];
+            // The backward pass starts from the mean of the products over the positions
+            let size = context.len() as f32;
+            let mut hidden_grad = vec![0.; context.len() * degree];
+            for (r, row) in chunk.iter().enumerate() {
+                context[r * self.num_tokens..r * self.num_tokens + row.len()].copy_from_slice(row);
+                let grad = embedding_grads[c * chunk_size + r];
+                for (pos, w) in config.pooling.weights(row.len()).iter().enumerate() {
+                    let offset = (r * self.num_tokens + pos) * degree;
+                    for (h, g) in hidden_grad[offset..offset + degree].iter_mut().zip(grad) {
+                        *h = g * w * size;
+                    }
+                }
+            }
+            let shape = match self.batch_size {
+                Some(batch_size) => vec![batch_size, self.num_tokens],
+                None => vec![self.num_tokens],
+            };
+            self.load_batch(
+                &Tensor::raw(&shape, context)?,
+                &Tensor::<usize>::zeros(&shape),
+            )?;
+            let shape = [shape, vec![degree]].concat();
+            self.graph
+                .load(grad_input, &Tensor::raw(&shape, hidden_grad)?)?;
+            self.graph.forward(self.training)?;
+            self.graph.zero_grad()?;
+            self.graph.backward_all(probe, limit, params_only)?;
+            if chunks.len() > 1 {
+                self.add_param_grads(&params, &mut grad_sums)?;
+            }
+        }
+        for (p, sum) in params.iter().zip(grad_sums) {
+            self.graph.load_grad(*p, &sum)?;
+        }
+        Ok(loss)
+    }
+
+    // Adds the gradients of `params` to `sums`, for steps made of several backward passes
+    fn add_param_grads(
+        &mut self,
+        params: &[TensorId],
+        sums: &mut Vec<Tensor<f32>>,
+    ) -> Result<(), GptError> {
+        for (k, p) in params.iter().enumerate() {
+            self.graph.fetch(*p, true)?;
+            let grad = self.graph.get_grad(*p)?;
+            match sums.get_mut(k) {
+                Some(sum) => *sum = (&*sum + grad)?,
+                None => sums.push(grad.clone()),
+            }
+        }
+        Ok(())
+    }
+
+    /// Trains the model to prefer the `chosen` completions of the examples over their `rejected`
+    /// ones by direct preference optimization (DPO), which needs neither a reward model nor
+    /// sampling. Every step draws `config.batch_size` examples, and widens the gap between the
+    /// log-probability ratios of their completions to the reference model (See `set_reference`),
+    /// through a logistic loss. The model must be built with `GptBuilder::dpo`. CPU graphs
+    /// process the completions one by one, without workers. Like the windows of `Completions`,
+    /// the ones of the examples start with their prompts, and end after `num_tokens + 1` tokens.
+    pub fn train_dpo<
+        O: Optimizer,
+        E: From<GptError> + From<GraphError>,
+        C: Fn(&mut Self) -> Result<(), E>,
+    >(
+        &mut self,
+        examples: &[Preference],
+        config: &TrainConfig,
+        optimizer: &O,
+        callback: C,
+    ) -> Result<(), E> {
+        let (num_batches, batch_size) = (config.num_batches, config.batch_size);
+        let _span = info_span!("train_dpo", num_batches, batch_size).entered();
+        let dpo = self.dpo.ok_or_else(|| {
+            GptError::InvalidConfig("the model isn't built for preference training".into())
+        })?;
+        if self.reference.is_none() {
+            return Err(GptError::InvalidConfig(
+                "preference training needs a reference model (See `GPT::set_reference`)".into(),
+            )
+            .into());
+        }
+        if let Some(i) = examples.iter().position(|e| e.prompt.is_empty()) {
+            return Err(GptError::EmptyPrompt(i).into());
+        }
+        if let Some(i) = examples
+            .iter()
+            .position(|e| e.chosen.is_empty() || e.rejected.is_empty())
+        {
+            return Err(
+                GptError::InvalidConfig(format!("example {} has an empty completion", i)).into(),
+            );
+        }
+        let batch_size = batch_size.min(examples.len());
+        let (limit, params_only) = self.backward_params(config.backward_scope)?;
+        if let Some(pos) = &self.pos_input_fixed {
+            self.graph.load(self.pos_input, pos)?;
+        }
+        self.keep_good_state()?;
+        // The gradients are computed in f32, without the loss scaling of lower precisions
+        self.graph.set_loss_scale(1.)?;
+
+        let mut rng = rand::thread_rng();
+        let run = (Instant::now(), self.progress.elapsed);
+        for i in 0..num_batches {
+            if !self.begin_step(run, 2 * batch_size, &callback)? {
+                break;
+            }
+            let timer = Instant::now();
+            let picked = rand::seq::index::sample(&mut rng, examples.len(), batch_size)
+                .iter()
+                .map(|p| &examples[p])
+                .collect::<Vec<_>>();
+
+            let loss = self.dpo_backward(dpo, &picked, limit, params_only)?;
+            if !self.guard_loss(loss)? {
+                continue;
+            }
+            self.provenance.loss = Some(loss);
+
+            let lr = config.learning_rate.at(self.graph.optimizer_step()) * self.spike_lr_factor();
+            self.graph.optimize(optimizer, lr)?;
+            if i % 50 == 0 {
+                self.keep_good_state()?;
+                self.eval_callback(&callback)?;
+            }
+            log_step(
+                self.graph.optimizer_step(),
+                loss,
+                None,
+                String::new(),
+                &None,
+                timer.elapsed(),
+            );
+        }
+        Ok(())
+    }
+
+    // Forward and backward passes of a step of `train_dpo` on `examples`. Returns the loss,
+    // leaving the gradients of the parameters in the graph.
+    fn dpo_backward(
+        &mut self,
+        config: DpoConfig,
+        examples: &[&Preference],
+        limit: Option<usize>,
+        params_only: bool,
+    ) -> Result<f32, GptError> {
+        // The windows of the chosen completions, then of the rejected ones
+        let windows = examples
+            .iter()
+            .map(|e| preference_window(&e.prompt, &e.chosen, self.num_tokens))
+            .chain(
+                examples
+                    .iter()
+                    .map(|e| preference_window(&e.prompt, &e.rejected, self.num_tokens)),
+            )
+            .collect::<Vec<_>>();
+        let rows = windows
+            .iter()
+            .map(|(w, _)| &w[..w.len() - 1])
+            .collect::<Vec<_>>();
+        let logprobs = |logits: Vec<Vec<Vec<f32>>>| {
+            logits
+                .iter()
+                .zip(&windows)
+                .map(|(logits, (window, start))| completion_logprob(logits, window, *start))
+                .collect::<Vec<_>>()
+        };
+        let policy = logprobs(self.forward_windows(&rows, self.output)?);
+        let reference = self.reference.as_mut().unwrap();
+        let output = reference.output;
+        let reference = logprobs(reference.forward_windows(&rows, output)?);
+
+        // The loss of an example is `-log(sigmoid(margin))`, whose derivatives with respect to
+        // the log-probabilities of its chosen and rejected completions are `-/+ beta *
+        // sigmoid(-margin)`. The losses of the tokens being their negative log-probabilities,
+        // they get the opposite weights.
+        let n = examples.len();
+        let mut loss = 0.;
+        let mut coeffs = vec![0.; 2 * n];
+        for i in 0..n {
+            let margin =
+                config.beta * ((policy[i] - reference[i]) - (policy[n + i] - reference[n + i]));
+            loss += ((-margin).max(0.) + (-margin.abs()).exp().ln_1p()) / n as f32;
+            let coeff = config.beta / (1. + margin.exp()) / n as f32;
+            coeffs[i] = coeff;
+            coeffs[n + i] = -coeff;
+        }
+
+        // CPU graphs backpropagate a completion at a time, GPU ones a batch of the size they
+        // were built with (Padded with empty rows)
+        let weights_input = self.loss_weights.as_ref().unwrap().input;
+        let chunk_size = self.batch_size.unwrap_or(1);
+        let pad = self.documents.and_then(|d| d.pad).unwrap_or(0);
+        let params = self.graph.params().to_vec();
+        let chunks = windows.chunks(chunk_size).collect::<Vec<_>>();
+        let mut grad_sums = Vec::new();
+        for (c, chunk) in chunks.iter().enumerate() {
+            let mut xs = vec![pad; chunk_size * self.num_tokens];
+            let mut ys = vec![IGNORED_TARGET; xs.len()];
+            let mut weights = vec![0.; xs.len()];
+            // The backward pass starts from the mean of the losses over the positions
+            let size = xs.len() as f32;
+            for (r, (window, start)) in chunk.iter().enumerate() {
+                let offset = r * self.num_tokens;
+                xs[offset..offset + window.len() - 1].copy_from_slice(&window[..window.len() - 1]);
+                for pos in start - 1..window.len() - 1 {
+                    ys[offset + pos] = window[pos + 1];
+                    weights[offset + pos] = coeffs[c * chunk_size + r] * size;
+                }
+            }
+            let shape = match self.batch_size {
+                Some(batch_size) => vec![batch_size, self.num_tokens],
+                None => vec![self.num_tokens],
+            };
+            self.load_batch(&Tensor::raw(&shape, xs)?, &Tensor::raw(&shape, ys)?)?;
+            self.graph
+                .load(weights_input, &Tensor::raw(&shape, weights)?)?;
+            self.graph.forward(self.training)?;
+            self.graph.zero_grad()?;
+            self.graph.backward_all(self.loss, limit, params_only)?;
+            if chunks.len() > 1 {
+                self.add_param_grads(&params, &mut grad_sums)?;
+            }
+        }
+        for (p, sum) in params.iter().zip(grad_sums) {
+            self.graph.load_grad(*p, &sum)?;
+        }
+        Ok(loss)
+    }
+
+    /// Continues `prompt`, passing its tokens and then the generated ones to `callback`.
+    pub fn infer<R: Rng, F: Fn(usize) -> ()>(
+        &mut self,
+        rng: &mut R,
+        prompt: &[usize],
+        params: &InferParams,
+        callback: F,
+    ) -> Result<Vec<usize>, GptError> {
+        let prompt = params.fit_prompt(0, prompt, self.num_tokens)?;
+        for ch in params.heal_prompt(prompt).0 {
+            callback(*ch);
+        }
+        let mut chs = self.infer_batch(rng, &[prompt], params, |_, ch| callback(ch))?;
+        Ok(chs.remove(0))
+    }
+
+    /// Continues several prompts at once, as the rows of a batch, so that every forward pass
+    /// generates a token for all of them. GPU graphs process them by chunks of the batch size
+    /// they were built with. `callback` gets the index of the prompt and each generated token,
+    /// the results start with the prompts (Truncated as `params.context_overflow` says, and
+    /// without the last token `params.token_healing` drops). Sequences may be shorter than
+    /// `params.count` tokens when `params.constraint` or `params.stop` ends them.
+    pub fn infer_batch<R: Rng, P: AsRef<[usize]>, F: Fn(usize, usize)>(
+        &mut self,
+        rng: &mut R,
+        prompts: &[P],
+        params: &InferParams,
+        callback: F,
+    ) -> Result<Vec<Vec<usize>>, GptError> {
+        self.generate(rng, prompts, params, |i, token, _| callback(i, token))
+    }
+
+    /// Same as `infer_batch`, along with the log-probability of every token of the results and
+    /// the `top_n` most likely alternatives at its position. Generated tokens are scored with the
+    /// logits they were sampled from (Biased, masked and penalized, before the temperature and
+    /// the top-k and nucleus truncations), prompt tokens with the raw ones. A `count` of 0 only
+    /// scores the prompts.
+    pub fn infer_logprobs<R: Rng, P: AsRef<[usize]>, F: Fn(usize, usize)>(
+        &mut self,
+        rng: &mut R,
+        prompts: &[P],
+        params: &InferParams,
+        top_n: usize,
+        callback: F,
+    ) -> Result<Vec<Vec<TokenLogprob>>, GptError> {
+        let prompts = self.fit_prompts(prompts, params)?;
+        let mut results = self.prompt_logprobs(&prompts, top_n)?;
+        // The token a healed prompt drops is generated again
+        for (scored, prompt) in results.iter_mut().zip(prompts.iter()) {
+            if params.heal_prompt(prompt).1.is_some() {
+                scored.pop();
+            }
+        }
+        self.generate(rng, &prompts, params, |i, token, logits| {
+            callback(i, token);
+            results[i].push(TokenLogprob::new(token, logits, top_n));
+        })?;
+        Ok(results)
+    }
+
+    /// Same as `infer_batch`, passing the entropy (In nats) of the distribution every generated
+    /// token was sampled from to `callback` too, once the temperature and the top-k and nucleus
+    /// truncations applied: how much freedom the sampling had, e.g. to compare sampling settings
+    /// (See `diagnostics::GenerationStats`).
+    pub fn infer_entropies<R: Rng, P: AsRef<[usize]>, F: FnMut(usize, usize, f32)>(
+        &mut self,
+        rng: &mut R,
+        prompts: &[P],
+        params: &InferParams,
+        mut callback: F,
+    ) -> Result<Vec<Vec<usize>>, GptError> {
+        let mut generated = vec![0; prompts.len()];
+        self.generate(rng, prompts, params, |i, token, logits| {
+            callback(i, token, params.sampling_entropy(logits, generated[i]));
+            generated[i] += 1;
+        })
+    }
+
+    /// Generates `n` continuations of each prompt, in the same batches, and keeps the one `rerank`
+    /// scores the highest, along with its score. Sampling a few candidates and reranking them
+    /// avoids the unlucky draws of a single sample.
+    pub fn best_of<R: Rng, P: AsRef<[usize]>>(
+        &mut self,
+        rng: &mut R,
+        prompts: &[P],
+        params: &InferParams,
+        n: usize,
+        rerank: Rerank,
+    ) -> Result<Vec<(Vec<usize>, f32)>, GptError> {
+        if n == 0 {
+            return Err(GptError::InvalidConfig(
+                "best-of needs at least one candidate".into(),
+            ));
+        }
+        let prompts = self.fit_prompts(prompts, params)?;
+        let candidates = prompts
+            .iter()
+            .flat_map(|p| std::iter::repeat_n(*p, n))
+            .collect::<Vec<_>>();
+        let mut log_probs = vec![Vec::new(); candidates.len()];
+        let seqs = self.generate(rng, &candidates, params, |i, token, logits| {
+            log_probs[i].push(log_softmax(logits)[token]);
+        })?;
+        Ok(seqs
+            .chunks(n)
+            .zip(log_probs.chunks(n))
+            .zip(prompts)
+            .map(|((seqs, log_probs), prompt)| {
+                seqs.iter()
+                    .zip(log_probs)
+                    .map(|(seq, log_probs)| {
+                        let generated = &seq[params.heal_prompt(prompt).0.len()..];
+                        let score = rerank.score(generated, log_probs);
+                        (seq.clone(), score)
+                    })
+                    .max_by(|a, b| a.1.total_cmp(&b.1))
+                    .unwrap()
+            })
+            .collect())
+    }
+
+    /// Total log-likelihood of `tokens` under the model, the sum of the log-probabilities of every
+    /// token but the first given the ones before it (Dividing it by `tokens.len() - 1` gives the
+    /// average). Comparing the scores of a prompt followed by different answers picks the most
+    /// likely one, e.g. to classify texts or run cloze-style evaluations.
+    pub fn score(&mut self, tokens: &[usize]) -> Result<f32, GptError> {
+        Ok(self
+            .prompt_logprobs(&[tokens], 0)?
+            .remove(0)
+            .iter()
+            .filter_map(|t| t.log_prob)
+            .sum())
+    }
+
+    /// The hidden state of the last layer (After its normalization) for `tokens`, pooled into a
+    /// vector of `embedding_degree` values, e.g. to compare texts by the cosine similarity of
+    /// their embeddings. Only the last `num_tokens` tokens are seen.
+    pub fn embed(&mut self, tokens: &[usize], pooling: Pooling) -> Result<Vec<f32>, GptError> {
+        if tokens.is_empty() {
+            return Err(GptError::EmptyPrompt(0));
+        }
+        if let Some(pos) = &self.pos_input_fixed {
+            self.graph.load(self.pos_input, pos)?;
+        }
+        let window = &tokens[tokens.len().saturating_sub(self.num_tokens)..];
+        let states = self.forward_windows(&[window], self.hidden)?.remove(0);
+        Ok(pooling.pool(states))
+    }
+
+    /// The attention weights of every head for `tokens` (Only the last `num_tokens` ones are
+    /// seen), a `[layers, heads, n, n]` tensor where `[l, h, i, j]` is how much the `i`-th token
+    /// attends to the `j`-th one in the `h`-th head of the `l`-th layer.
+    pub fn attention(&mut self, tokens: &[usize]) -> Result<Tensor<f32>, GptError> {
+        if tokens.is_empty() {
+            return Err(GptError::EmptyPrompt(0));
+        }
+        if let Some(pos) = &self.pos_input_fixed {
+            self.graph.load(self.pos_input, pos)?;
+        }
+        let window = &tokens[tokens.len().saturating_sub(self.num_tokens)..];
+        self.forward_rows(&[window])?;
+        let n = window.len();
+        let mut blob = Vec::new();
+        for heads in self.attention.clone().iter() {
+            for head in heads.iter() {
+                for weights in self.fetch_rows(*head, &[window])?.remove(0) {
+                    blob.extend_from_slice(&weights[..n]);
+                }
+            }
+        }
+        let num_heads = self.attention.first().map_or(0, |heads| heads.len());
+        Ok(Tensor::raw(&[self.attention.len(), num_heads, n, n], blob)?)
+    }
+
+    // Scores the tokens of the prompts, by windows of `num_tokens` tokens. Each window predicts
+    // its tokens from the ones before them in the window, and its first token from the previous
+    // window
+    fn prompt_logprobs<P: AsRef<[usize]>>(
+        &mut self,
+        prompts: &[P],
+        top_n: usize,
+    ) -> Result<Vec<Vec<TokenLogprob>>, GptError> {
+        if let Some(i) = prompts.iter().position(|p| p.as_ref().is_empty()) {
+            return Err(GptError::EmptyPrompt(i));
+        }
+        if let Some(pos) = &self.pos_input_fixed {
+            self.graph.load(self.pos_input, pos)?;
+        }
+        let windows = prompts
+            .iter()
+            .flat_map(|p| p.as_ref().chunks(self.num_tokens))
+            .collect::<Vec<_>>();
+        let mut logits = self.forward_windows(&windows, self.output)?.into_iter();
+        let mut results = Vec::with_capacity(prompts.len());
+        for prompt in prompts.iter() {
+            let prompt = prompt.as_ref();
+            let mut scored = vec![TokenLogprob {
+                token: prompt[0],
+                log_prob: None,
+                top: Vec::new(),
+            }];
+            for start in (0..prompt.len()).step_by(self.num_tokens) {
+                let window = logits.next().unwrap();
+                for (pos, logits) in window.iter().enumerate() {
+                    if let Some(token) = prompt.get(start + pos + 1) {
+                        scored.push(TokenLogprob::new(*token, logits, top_n));
+                    }
+                }
+            }
+            results.push(scored);
+        }
+        Ok(results)
+    }
+
+    // Samples the continuations of `infer_batch`, passing the index of the prompt, the token and
+    // the processed logits it was sampled from to `on_token`
+    fn generate<R: Rng, P: AsRef<[usize]>, F: FnMut(usize, usize, &[f32])>(
+        &mut self,
+        rng: &mut R,
+        prompts: &[P],
+        params: &InferParams,
+        mut on_token: F,
+    ) -> Result<Vec<Vec<usize>>, GptError> {
+        if let Some(i) = prompts.iter().position(|p| p.as_ref().is_empty()) {
+            return Err(GptError::EmptyPrompt(i));
+        }
+        check_temperature(params.temperature)?;
+        for (_, temperature) in params.temperature_schedule.iter() {
+            check_temperature(*temperature)?;
+        }
+        if let Some(pos) = &self.pos_input_fixed {
+            self.graph.load(self.pos_input, pos)?;
+        }
+        let (prompts, dropped): (Vec<_>, Vec<_>) = self
+            .fit_prompts(prompts, params)?
+            .into_iter()
+            .map(|p| params.heal_prompt(p))
+            .unzip();
+
+        let mut seeded = params.seed.map(StdRng::seed_from_u64);
+        let mut seqs = prompts.iter().map(|p| p.to_vec()).collect::<Vec<_>>();
+        let mut done = vec![false; seqs.len()];
+        for step in 0..params.count {
+            let active = (0..seqs.len()).filter(|i| !done[*i]).collect::<Vec<_>>();
+            if active.is_empty() {
+                break;
+            }
+            let active_seqs = active
+                .iter()
+                .map(|i| (&seqs[*i][..], prompts[*i].len()))
+                .collect::<Vec<_>>();
+            let logits = self.guided_logits(params, &active_seqs)?;
+            for (i, mut logits) in active.into_iter().zip(logits) {
+                let prompt_len = prompts[i].len();
+                let generated = &seqs[i][prompt_len..];
+                params.constrain(&mut logits, generated);
+                if let (true, Some(dropped)) = (generated.is_empty(), dropped[i]) {
+                    params.heal(&mut logits, dropped);
+                }
+                params.penalize(&mut logits, &seqs[i]);
+                if logits.iter().all(|l| *l == f32::NEG_INFINITY) {
+                    match &params.constraint {
+                        Some(constraint) if constraint.is_complete(generated) => {
+                            done[i] = true;
+                            continue;
+                        }
+                        _ => return Err(GptError::NoAllowedTokens),
+                    }
+                }
+                let mut truncated = logits.clone();
+                params.truncate(&mut truncated);
+                let truncated = Tensor::raw(&[truncated.len()], truncated)?;
+                let temperature = params.temperature_at(step);
+                let next_ch = match seeded.as_mut() {
+                    Some(seeded) => select(seeded, &truncated, temperature)?,
+                    None => select(rng, &truncated, temperature)?,
+                };
+                on_token(i, next_ch, &logits);
+                seqs[i].push(next_ch);
+                done[i] = params.stopped(&seqs[i][prompt_len..]);
+            }
+        }
+        Ok(seqs)
+    }
+
+    /// Keeps the `beam.beam_size` most likely continuations of `prompt` at every step, returning
+    /// the best ones (Starting with the prompt) along with their scores: their log-probabilities,
+    /// divided by their lengths raised to `beam.length_penalty`. The logits are biased, masked
+    /// and penalized as in `infer_batch`, and sequences end at the stop sequences too, but the
+    /// sampling settings (Temperatures, top-k, nucleus and seed) are unused.
+    pub fn beam_search(
+        &mut self,
+        prompt: &[usize],
+        params: &InferParams,
+        beam: &BeamParams,
+    ) -> Result<Vec<(Vec<usize>, f32)>, GptError> {
+        if prompt.is_empty() {
+            return Err(GptError::EmptyPrompt(0));
+        }
+        if let Some(pos) = &self.pos_input_fixed {
+            self.graph.load(self.pos_input, pos)?;
+        }
+        let (prompt, dropped) =
+            params.heal_prompt(params.fit_prompt(0, prompt, self.num_tokens)?);
+        let score = |seq: &[usize], log_prob: f32| {
+            let len = (seq.len() - prompt.len()).max(1) as f32;
+            log_prob / len.powf(beam.length_penalty)
+        };
+
+        // Sequences along with their log-probabilities
+        let mut beams = vec![(prompt.to_vec(), 0.)];
+        let mut finished = Vec::new();
+        for _ in 0..params.count {
+            let seqs = beams
+                .iter()
+                .map(|(seq, _)| (&seq[..], prompt.len()))
+                .collect::<Vec<_>>();
+            let logits = self.guided_logits(params, &seqs)?;
+            let mut candidates = Vec::new();
+            for (b, mut logits) in logits.into_iter().enumerate() {
+                let (seq, log_prob) = &beams[b];
+                let generated = &seq[prompt.len()..];
+                params.constrain(&mut logits, generated);
+                if let (true, Some(dropped)) = (generated.is_empty(), dropped) {
+                    params.heal(&mut logits, dropped);
+                }
+                params.penalize(&mut logits, seq);
+                if logits.iter().all(|l| *l == f32::NEG_INFINITY) {
+                    match &params.constraint {
+                        Some(constraint) if constraint.is_complete(generated) => {
+                            finished.push((seq.clone(), score(seq, *log_prob)));
+                            continue;
+                        }
+                        _ => return Err(GptError::NoAllowedTokens),
+                    }
+                }
+                for (token, l) in log_softmax(&logits).into_iter().enumerate() {
+                    if l > f32::NEG_INFINITY {
+                        candidates.push((b, token, log_prob + l));
+                    }
+                }
+            }
+            candidates.sort_by(|a, b| b.2.total_cmp(&a.2));
+            candidates.truncate(beam.beam_size);
+            let (stopped, active) = candidates
+                .into_iter()
+                .map(|(b, token, log_prob)| {
+                    let mut seq = beams[b].0.clone();
+                    seq.push(token);
+                    (seq, log_prob)
+                })
+                .partition::<Vec<_>, _>(|(seq, _)| params.stopped(&seq[prompt.len()..]));
+            finished.extend(
+                stopped
+                    .into_iter()
+                    .map(|(seq, log_prob)| (score(&seq, log_prob), seq))
+                    .map(|(score, seq)| (seq, score)),
+            );
+            beams = active;
+            if beams.is_empty() {
+                break;
+            }
+        }
+        finished.extend(
+            beams
+                .into_iter()
+                .map(|(seq, log_prob)| (score(&seq, log_prob), seq))
+                .map(|(score, seq)| (seq, score)),
+        );
+        finished.sort_by(|a, b| b.1.total_cmp(&a.1));
+        finished.truncate(beam.beam_size);
+        Ok(finished)
+    }
+
+    // The parts of the prompts that are continued, see `InferParams::context_overflow`
+    fn fit_prompts<'a, P: AsRef<[usize]>>(
+        &self,
+        prompts: &'a [P],
+        params: &InferParams,
+    ) -> Result<Vec<&'a [usize]>, GptError> {
+        prompts
+            .iter()
+            .enumerate()
+            .map(|(i, p)| params.fit_prompt(i, p.as_ref(), self.num_tokens))
+            .collect()
+    }
+
+    // Logits of the tokens following each sequence
+    fn next_logits<'a, I: Iterator<Item = &'a [usize]>>(
+        &mut self,
+        seqs: I,
+    ) -> Result<Vec<Vec<f32>>, GptError> {
+        let windows = seqs
+            .map(|seq| &seq[seq.len().saturating_sub(self.num_tokens)..])
+            .collect::<Vec<_>>();
+        Ok(self
+            .forward_windows(&windows, self.output)?
+            .into_iter()
+            .map(|mut logits| logits.pop().unwrap())
+            .collect())
+    }
 
-            let mut heads = Vec::new();
+    // Logits of the tokens following each sequence, whose first tokens (As many as the number
+    // paired with it) are its prompt, combined with the unconditional ones given
+    // `params.negative_prompt` instead (See `InferParams::guidance_scale`)
+    fn guided_logits(
+        &mut self,
+        params: &InferParams,
+        seqs: &[(&[usize], usize)],
+    ) -> Result<Vec<Vec<f32>>, GptError> {
+        let conditional = seqs.iter().map(|(seq, _)| *seq);
+        if params.guidance_scale == 1.0 {
+            return self.next_logits(conditional);
+        }
+        let unconditional = seqs
+            .iter()
+            .map(|(seq, prompt_len)| {
+                let prefix = match params.negative_prompt.is_empty() {
+                    true => &seq[prompt_len - 1..*prompt_len],
+                    false => &params.negative_prompt[..],
+                };
+                [prefix, &seq[*prompt_len..]].concat()
+            })
+            .collect::<Vec<_>>();
+        // Both are computed in the same batches
+        let logits =
+            self.next_logits(conditional.chain(unconditional.iter().map(|seq| &seq[..])))?;
+        let (conditional, unconditional) = logits.split_at(seqs.len());
+        Ok(conditional
+            .iter()
+            .zip(unconditional)
+            .map(|(c, u)| {
+                c.iter()
+                    .zip(u)
+                    .map(|(c, u)| u + params.guidance_scale * (c - u))
+                    .collect()
+            })
+            .collect())
+    }
 
-            // Multi-head Attention
-            for h in 0..num_heads {
-                // Key
-                let k_params = g.alloc(
-                    Tensor::<f32>::rand(rng, &[embedding_degree, head_size]),
-                    true,
-                    format!("head_{}_{}_k", l, h),
-                )?;
-                let k = g.call(MatMul::new(), &[norm_inp, k_params])?;
+    // Values of `tensor` (The logits of the next tokens, or the hidden states) at every position
+    // of windows of at most `num_tokens` tokens, processed as the rows of batches (Of the size GPU
+    // graphs were built with, the last one padded with empty rows)
+    fn forward_windows(
+        &mut self,
+        windows: &[&[usize]],
+        tensor: TensorId,
+    ) -> Result<Vec<Vec<Vec<f32>>>, GptError> {
+        let chunk_size = self.batch_size.unwrap_or(windows.len()).max(1);
+        let mut values = Vec::with_capacity(windows.len());
+        for chunk in windows.chunks(chunk_size) {
+            self.forward_rows(chunk)?;
+            values.extend(self.fetch_rows(tensor, chunk)?);
+        }
+        Ok(values)
+    }
 
-                // Query
-                let q_params = g.alloc(
-                    Tensor::<f32>::rand(rng, &[embedding_degree, head_size]),
-                    true,
-                    format!("head_{}_{}_q", l, h),
-                )?;
-                let q = g.call(MatMul::new(), &[norm_inp, q_params])?;
+    // Runs a forward pass over windows of at most `num_tokens` tokens, as the rows of a batch (Of
+    // the size GPU graphs were built with, padded with empty rows)
+    fn forward_rows(&mut self, rows: &[&[usize]]) -> Result<(), GptError> {
+        let num_rows = self.batch_size.unwrap_or(rows.len());
+        if rows.len() > num_rows {
+            return Err(GptError::InvalidConfig(format!(
+                "{} rows don't fit in a batch of {}",
+                rows.len(),
+                num_rows
+            )));
+        }
+        // Rows are padded on the right, which the causal attention keeps from affecting them
+        // (Bidirectional models need a `pad_token`, whose positions the attention mask hides)
+        let pad = self.documents.and_then(|d| d.pad).unwrap_or(0);
+        let mut context = Vec::with_capacity(num_rows * self.num_tokens);
+        for row in rows.iter() {
+            context.extend_from_slice(row);
+            context.resize(context.len() + self.num_tokens - row.len(), pad);
+        }
+        context.resize(num_rows * self.num_tokens, pad);
+        let shape = [num_rows, self.num_tokens];
+        let context = Tensor::raw(&shape, context)?;
+        self.graph.load_usize(self.token_input, &context)?;
+        load_documents(&mut self.graph, self.documents, &context, false)?;
+        // The loss is computed too, its targets (And the teacher's logits, the loss weights or the
+        // gradient of the hidden states) need the shape of the batch
+        let targets = Tensor::<usize>::zeros(&shape);
+        self.graph.load_usize(self.expected_output, &targets)?;
+        load_loss_weights(&mut self.graph, self.loss_weights.as_ref(), &targets)?;
+        if let Some(teacher) = self.teacher_input {
+            let shape = [num_rows, self.num_tokens, teacher.vocab_size];
+            self.graph
+                .load(teacher.input, &Tensor::<f32>::zeros(&shape))?;
+        }
+        if let Some(contrastive) = self.contrastive {
+            let shape = [num_rows, self.num_tokens, contrastive.embedding_degree];
+            self.graph
+                .load(contrastive.grad, &Tensor::<f32>::zeros(&shape))?;
+        }
+        Ok(self.graph.forward(self.training)?)
+    }
 
-                // Value
-                let v_params = g.alloc(
-                    Tensor::<f32>::rand(rng, &[embedding_degree, head_size]),
-                    true,
-                    format!("head_{}_{}_v", l, h),
-                )?;
-                let v = g.call(MatMul::new(), &[norm_inp, v_params])?;
+    // Values of `tensor` at the positions of the `rows` of the last forward pass (Not of the empty
+    // rows padding its batch)
+    fn fetch_rows(
+        &mut self,
+        tensor: TensorId,
+        rows: &[&[usize]],
+    ) -> Result<Vec<Vec<Vec<f32>>>, GptError> {
+        self.graph.fetch(tensor, false)?;
+        let output = self.graph.get(tensor)?.as_float()?;
+        let mut values = Vec::with_capacity(rows.len());
+        for (r, row) in rows.iter().enumerate() {
+            let out = output.get(r)?;
+            values.push(
+                (0..row.len())
+                    .map(|pos| Ok(out.get(pos)?.blob().to_vec()))
+                    .collect::<Result<Vec<_>, TensorError>>()?,
+            );
+        }
+        Ok(values)
+    }
+}
 
-                let q_t = g.call(Transpose::new(), &[q])?;
-                let kq = g.call(MatMul::new(), &[k, q_t])?;
+impl GPT<AnyGraph> {
+    /// Trains the model the way the `femto` command line does: GPU graphs process whole
+    /// batches, CPU graphs split them among `config.num_workers` replicas.
+    pub fn fit<
+        D: Corpus + ?Sized,
+        O: Optimizer,
+        E: From<GptError> + From<GraphError>,
+        C: Fn(&mut Self) -> Result<(), E>,
+    >(
+        &mut self,
+        corpus: &D,
+        config: &TrainConfig,
+        optimizer: &O,
+        callback: C,
+    ) -> Result<(), E> {
+        if self.graph.is_gpu() {
+            self.train(corpus, config, optimizer, callback)
+        } else {
+            self.train_cpu(corpus, config, optimizer, callback)
+        }
+    }
+}
 
-                let head_size_sqrt_inv = (head_size as f32).powf(-0.5);
-                let kq_coeff = g.call(Coeff::new(head_size_sqrt_inv), &[kq])?;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graph::CpuGraph;
+    use crate::optimizer::AdamW;
+    use std::cell::RefCell;
 
-                let masked_kq = g.call(TrilMask::new(num_tokens), &[kq_coeff])?;
-                let soft_masked_kq = g.call(Softmax::new(), &[masked_kq])?;
-                let dropped_soft_masked_kq = g.call(Dropout::new(dropout), &[soft_masked_kq])?;
-                let atten = g.call(MatMul::new(), &[dropped_soft_masked_kq, v])?;
-                heads.push(atten);
-            }
+    fn builder() -> GptBuilder<'static> {
+        GptBuilder::new()
+            .vocab_size(3)
+            .embedding_degree(8)
+            .context(4)
+            .layers(1)
+            .heads(2)
+    }
 
-            // Concat head results and project into embedding_degree
-            let cat = g.call(Cat::new(), &heads)?;
-            let proj_params = g.alloc(
-                Tensor::<f32>::rand(rng, &[num_heads * head_size, embedding_degree]),
-                true,
-                format!("proj_{}_weights", l),
-            )?;
-            let proj_bias_params = g.alloc(
-                Tensor::<f32>::zeros(&[embedding_degree]),
-                true,
-                format!("proj_{}_bias", l),
-            )?;
-            let proj_cat = g.call(MatMul::new(), &[cat, proj_params])?;
-            let proj_cat_bias = g.call(Add::new(), &[proj_cat, proj_bias_params])?;
-            let dropped_proj_cat_bias = g.call(Dropout::new(dropout), &[proj_cat_bias])?;
+    fn tiny_gpt() -> GPT<CpuGraph> {
+        builder().build(CpuGraph::new()).unwrap()
+    }
 
-            // Add attention results to input and then normalize
-            let add_atten = g.call(Add::new(), &[norm_inp, dropped_proj_cat_bias])?;
-            let add_atten_norm_coeff = g.alloc(
-                Tensor::<f32>::rand(rng, &[embedding_degree]),
-                true,
-                format!("atten_norm_{}_coeff", l),
-            )?;
-            let add_atten_norm_bias = g.alloc(
-                Tensor::<f32>::zeros(&[embedding_degree]),
-                true,
-                format!("atten_norm_{}_bias", l),
-            )?;
-            let add_atten_norm = g.call(
-                LayerNorm::new(),
-                &[add_atten, add_atten_norm_coeff, add_atten_norm_bias],
-            )?;
+    // Rows of `id` for each position of `window`
+    fn rows(gpt: &mut GPT<CpuGraph>, id: TensorId, window: &[usize]) -> Vec<Vec<f32>> {
+        if let Some(pos) = &gpt.pos_input_fixed {
+            gpt.graph.load(gpt.pos_input, pos).unwrap();
+        }
+        gpt.forward_rows(&[window]).unwrap();
+        gpt.fetch_rows(id, &[window]).unwrap().remove(0)
+    }
 
-            // A feed-forward layer:
-            // Linear embedding_degree -> 4*embedding_degree
-            // Relu
-            // Linear 4*embedding_degree -> embedding_degree
-            let lin1_params = g.alloc(
-                Tensor::<f32>::rand(rng, &[embedding_degree, 4 * embedding_degree]),
-                true,
-                format!("feedforward1_{}_weights", l),
-            )?;
-            let bias1_params = g.alloc(
-                Tensor::<f32>::zeros(&[4 * embedding_degree]),
-                true,
-                format!("feedforward1_{}_bias", l),
-            )?;
-            let lin1_result = g.call(MatMul::new(), &[add_atten_norm, lin1_params])?;
-            let lin1_bias_result = g.call(Add::new(), &[lin1_result, bias1_params])?;
-            let lin1_act = g.call(Gelu::new(), &[lin1_bias_result])?;
-            let lin2_params = g.alloc(
-                Tensor::<f32>::rand(rng, &[4 * embedding_degree, embedding_degree]),
-                true,
-                format!("feedforward2_{}_weights", l),
-            )?;
-            let bias2_params = g.alloc(
-                Tensor::<f32>::zeros(&[embedding_degree]),
-                true,
-                format!("feedforward2_{}_bias", l),
-            )?;
-            let lin2_result = g.call(MatMul::new(), &[lin1_act, lin2_params])?;
-            let lin2_bias_result = g.call(Add::new(), &[lin2_result, bias2_params])?;
+    // Logits of the tokens following each position of `window`
+    fn logits(gpt: &mut GPT<CpuGraph>, window: &[usize]) -> Vec<Vec<f32>> {
+        rows(gpt, gpt.output, window)
+    }
 
-            curr_inp = g.call(Add::new(), &[add_atten_norm, lin2_bias_result])?;
-        }
+    fn train(gpt: &mut GPT<CpuGraph>, num_batches: usize) {
+        let corpus = (0..200).map(|i| i % 3).collect::<Vec<usize>>();
+        let config = TrainConfig {
+            num_batches,
+            batch_size: 2,
+            num_workers: 1,
+            learning_rate: LrSchedule {
+                base: 0.01,
+                min: 0.01,
+                warmup_steps: 0,
+                decay_steps: 1,
+            },
+            ..Default::default()
+        };
+        gpt.train_cpu(&corpus, &config, &AdamW::new(), |_| Ok::<_, GptError>(()))
+            .unwrap();
+    }
 
-        // Normalize the output after the last layer
-        let norm_out_coeff = g.alloc(
-            Tensor::<f32>::rand(rng, &[embedding_degree]),
-            true,
-            format!("head_norm_coeff"),
-        )?;
-        let norm_out_bias = g.alloc(
-            Tensor::<f32>::zeros(&[embedding_degree]),
-            true,
-            format!("head_norm_bias"),
-        )?;
-        let norm_out = g.call(LayerNorm::new(), &[curr_inp, norm_out_coeff, norm_out_bias])?;
+    #[test]
+    fn test_token_healing() {
+        let pieces = ["a", "ab", "b", "abc"].map(String::from).to_vec();
+        let params = InferParams::new().token_healing(Arc::new(pieces));
+        assert_eq!(params.heal_prompt(&[2, 0]), (&[2][..], Some(0)));
+        let mut logits = vec![0.; 5];
+        params.heal(&mut logits, 0);
+        assert_eq!(logits, [0., 0., f32::NEG_INFINITY, 0., f32::NEG_INFINITY]);
 
-        // Map from embedding_degree to vocab_size through a linear layer
-        let to_vocab = g.alloc(
-            Tensor::<f32>::rand(rng, &[embedding_degree, vocab_size]),
-            true,
-            format!("head_map_weights"),
-        )?;
-        let to_vocab_bias = g.alloc(
-            Tensor::<f32>::zeros(&[vocab_size]),
-            true,
-            format!("head_map_bias"),
-        )?;
-        let result_lin = g.call(MatMul::new(), &[norm_out, to_vocab])?;
-        let output = g.call(Add::new(), &[result_lin, to_vocab_bias])?;
+        // Tokens without a text are kept in the prompt
+        assert_eq!(params.heal_prompt(&[2, 7]), (&[2, 7][..], None));
+    }
 
-        let loss = g.call(CrossEntropy::new(), &[output, expected_output])?;
+    #[test]
+    fn test_temperature_schedule() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let logits = Tensor::raw(&[3], vec![0.5, 2., -1.]).unwrap();
+        assert!(select(&mut rng, &logits, 1.).unwrap() < 3);
+        for temperature in [0., 1.5, f32::NAN] {
+            assert!(matches!(
+                select(&mut rng, &logits, temperature),
+                Err(GptError::InvalidConfig(_))
+            ));
+        }
 
-        Ok(Self {
-            graph: g,
-            num_tokens,
-            token_input,
-            pos_input,
-            output,
-            expected_output,
-            loss,
-            pos_input_fixed: pos_encode_inter(num_tokens, embedding_degree),
+        let mut gpt = tiny_gpt();
+        let params = InferParams::new().count(3).temperature_for(2, 2.);
+        assert!(matches!(
+            gpt.infer_batch(&mut rng, &[[0, 1]], &params, |_, _| ()),
+            Err(GptError::InvalidConfig(_))
+        ));
+        let params = InferParams::new().count(3).temperature_for(2, 1.);
+        assert_eq!(
+            gpt.infer_batch(&mut rng, &[[0, 1]], &params, |_, _| ())
+                .unwrap()[0]
+                .len(),
+            5
+        );
+    }
+
+    #[test]
+    fn test_spike_rollback() {
+        let mut gpt = tiny_gpt();
+        // Every loss after the warmup of the moving average is a spike
+        gpt.set_spike_guard(Some(SpikeGuard {
+            threshold: 0.,
+            ..Default::default()
+        }));
+        gpt.set_budget(Budget {
+            tokens: Some(30 * 2 * 4),
+            duration: None,
+        });
+        let corpus = (0..200).map(|i| i % 3).collect::<Vec<usize>>();
+        let config = TrainConfig {
+            num_batches: 100,
+            batch_size: 2,
+            num_workers: 1,
+            learning_rate: LrSchedule {
+                base: 0.01,
+                min: 0.01,
+                warmup_steps: 0,
+                decay_steps: 1,
+            },
+            ..Default::default()
+        };
+        let states = RefCell::new(Vec::new());
+        gpt.train_cpu(&corpus, &config, &AdamW::new(), |gpt| {
+            states.borrow_mut().push(gpt.get_training_state()?);
+            Ok::<_, GptError>(())
         })
+        .unwrap();
+
+        // The spikes don't keep the run from stopping at its budget
+        assert_eq!(gpt.progress().tokens, 30 * 2 * 4);
+        // The state kept after the first step, which the spikes went back to
+        let weights = |state: &TrainingState| {
+            let mut tensors = state.tensors.iter().collect::<Vec<_>>();
+            tensors.sort_by_key(|(name, _)| *name);
+            tensors
+                .into_iter()
+                .flat_map(|(_, t)| t.blob().to_vec())
+                .collect::<Vec<_>>()
+        };
+        let states = states.into_inner();
+        let (first, last) = (&states[0], states.last().unwrap());
+        assert!(weights(first) == weights(last));
+        // The steps of the warmup trained the model further before the first spike
+        assert_eq!((first.optimizer.step, last.optimizer.step), (1, 1));
     }
 
-    pub fn sync(&mut self) -> Result<(), GraphError> {
-        self.graph
-            .params()
-            .to_vec()
-            .into_iter()
-            .map(|p| self.graph.fetch(p, false))
-            .collect::<Result<Vec<_>, GraphError>>()?;
-        Ok(())
+    #[test]
+    fn test_budget() {
+        let mut gpt = tiny_gpt();
+        let budget = Budget {
+            tokens: Some(3 * 2 * 4),
+            duration: None,
+        };
+        gpt.set_budget(budget);
+        train(&mut gpt, 100);
+        let state = gpt.get_training_state().unwrap();
+        assert_eq!(state.progress.tokens, 3 * 2 * 4);
+        assert_eq!(state.optimizer.step, 3);
+
+        // A run resumed from a spent budget stops before its first step
+        let mut resumed = tiny_gpt();
+        resumed.set_budget(budget);
+        resumed.set_training_state(state, true).unwrap();
+        train(&mut resumed, 100);
+        assert_eq!(resumed.progress().tokens, 3 * 2 * 4);
+        assert_eq!(resumed.get_training_state().unwrap().optimizer.step, 3);
     }
 
-    pub fn num_params(&self) -> usize {
-        self.graph
-            .params()
-            .to_vec()
-            .into_iter()
-            .map(|p| self.graph.get(p).unwrap().as_float().unwrap().size())
-            .sum::<usize>()
+    #[test]
+    fn test_attention_in_a_batch() {
+        let mut gpt = tiny_gpt();
+        let mut batched = builder().batch_size(3).build(CpuGraph::new()).unwrap();
+        batched
+            .set_training_state(gpt.get_training_state().unwrap(), false)
+            .unwrap();
+        gpt.set_training(false);
+        batched.set_training(false);
+        // The window is padded into a batch of the size the graph was built with
+        let tokens = [0, 2, 1];
+        let weights = gpt.attention(&tokens).unwrap();
+        let batched_weights = batched.attention(&tokens).unwrap();
+        assert_eq!(weights.shape(), [1, 2, 3, 3]);
+        assert_eq!(weights.shape(), batched_weights.shape());
+        for (a, b) in weights.blob().iter().zip(batched_weights.blob()) {
+            assert!((a - b).abs() < 1e-5);
+        }
     }
 
-    pub fn set_training_state(
-        &mut self,
-        training_state: TrainingState,
-        load_optimizer: bool,
-    ) -> Result<(), GraphError> {
-        for p in self.graph.params().to_vec() {
-            let name = self.graph.name_of(p)?;
-            if let Some(t) = training_state.tensors.get(name) {
-                self.graph.load(p, t)?;
+    #[test]
+    fn test_infer_logprobs() {
+        let mut gpt = builder().batch_size(2).build(CpuGraph::new()).unwrap();
+        gpt.set_training(false);
+        // More prompts than the rows of a batch, the last one is padded
+        let prompts: [&[usize]; 3] = [&[0, 1, 2], &[2, 2], &[1, 0, 0, 1]];
+        let params = InferParams::new().count(2);
+        let mut rng = StdRng::seed_from_u64(42);
+        let scored = gpt
+            .infer_logprobs(&mut rng, &prompts, &params, 3, |_, _| ())
+            .unwrap();
+        for (prompt, scored) in prompts.iter().zip(scored.iter()) {
+            assert_eq!(scored.len(), prompt.len() + 2);
+            assert_eq!(scored[0].log_prob, None);
+            let logits = logits(&mut gpt, prompt);
+            for (pos, token) in scored[1..prompt.len()].iter().enumerate() {
+                let expected = log_softmax(&logits[pos])[token.token];
+                assert!((token.log_prob.unwrap() - expected).abs() < 1e-5);
+            }
+            for token in scored.iter().skip(1) {
+                let total = token.top.iter().map(|(_, l)| l.exp()).sum::<f32>();
+                assert!((total - 1.).abs() < 1e-5);
+                assert!(token.top.windows(2).all(|w| w[0].1 >= w[1].1));
             }
         }
-        if load_optimizer {
-            self.graph.set_optimizer_state(&training_state.optimizer)?;
+    }
+
+    #[test]
+    fn test_infer_batch() {
+        let mut gpt = tiny_gpt();
+        let mut rng = StdRng::seed_from_u64(42);
+        // Nearly greedy, so that the draws of the other rows don't matter
+        let params = InferParams::new().count(2).temperature(1e-6);
+        let prompts = [vec![0], vec![1, 2], vec![2, 0]];
+        let seqs = gpt
+            .infer_batch(&mut rng, &prompts, &params, |_, _| ())
+            .unwrap();
+        for (prompt, seq) in prompts.iter().zip(seqs) {
+            assert_eq!(seq[..prompt.len()], prompt[..]);
+            assert_eq!(gpt.infer(&mut rng, prompt, &params, |_| ()).unwrap(), seq);
         }
-        Ok(())
     }
 
-    pub fn get_training_state(&self) -> Result<TrainingState, GraphError> {
-        let mut state = TrainingState {
-            tensors: Default::default(),
-            optimizer: self.graph.get_optimizer_state()?,
+    #[test]
+    fn test_beam_search() {
+        let mut gpt = tiny_gpt();
+        let beam = BeamParams {
+            beam_size: 3,
+            length_penalty: 0.,
         };
-        for p in self.graph.params().iter() {
-            let k = self.graph.name_of(*p)?.to_string();
-            let v = self.graph.get(*p)?.as_float()?.clone();
-            state.tensors.insert(k, v);
+        let prompt = [0, 1];
+        let beams = gpt
+            .beam_search(&prompt, &InferParams::new().count(2), &beam)
+            .unwrap();
+        assert_eq!(beams.len(), 3);
+        assert!(beams.windows(2).all(|w| w[0].1 >= w[1].1));
+        // Without a length penalty, the scores are the log-probabilities of the continuations
+        let prompt_score = gpt.score(&prompt).unwrap();
+        for (seq, score) in beams {
+            assert_eq!(seq.len(), 4);
+            assert!((gpt.score(&seq).unwrap() - prompt_score - score).abs() < 1e-4);
         }
-        Ok(state)
     }
 
-    pub fn train_cpu<
-        O: Optimizer,
-        F: Fn(usize) -> f32,
-        C: Fn(&mut Self) -> Result<(), GraphError>,
-    >(
-        &mut self,
-        dataset: &[usize],
-        num_batches: usize,
-        batch_size: usize,
-        limit: Option<usize>,
-        optimizer: &O,
-        learning_rate: F,
-        callback: C,
-    ) -> Result<(), GraphError>
-    where
-        G: Clone + Send + Sync,
-    {
-        self.graph.load(self.pos_input, &self.pos_input_fixed)?;
+    #[test]
+    fn test_score() {
+        let mut gpt = tiny_gpt();
+        let tokens = [0, 2, 1, 1, 0, 2, 2];
+        // The last logits of the first window predict the first token of the second one
+        let rows = [
+            logits(&mut gpt, &tokens[..4]),
+            logits(&mut gpt, &tokens[4..]),
+        ]
+        .concat();
+        let expected = rows
+            .iter()
+            .zip(&tokens[1..])
+            .map(|(logits, token)| log_softmax(logits)[*token])
+            .sum::<f32>();
+        assert!((gpt.score(&tokens).unwrap() - expected).abs() < 1e-4);
+        assert_eq!(gpt.score(&tokens[..1]).unwrap(), 0.);
+        assert!(matches!(gpt.score(&[]), Err(GptError::EmptyPrompt(0))));
+    }
 
-        for i in 0..num_batches {
-            let timer = Instant::now();
-            let (graphs, errs): (Vec<G>, Vec<f32>) = (0..batch_size)
-                .into_par_iter()
-                .map(|_| {
-                    let mut rng = rand::thread_rng();
-                    let mut graph = self.graph.clone();
-                    let (xs, ys) = sample_dataset(dataset, 1, self.num_tokens, &mut rng);
-
-                    graph.load_usize(self.token_input, &xs)?;
-                    graph.load_usize(self.expected_output, &ys)?;
-                    graph.forward(true)?;
-                    graph.zero_grad()?;
-                    let err = graph.backward_all(self.loss, limit)?;
-                    Ok((graph, err))
-                })
-                .collect::<Result<Vec<(G, f32)>, GraphError>>()?
-                .into_iter()
-                .unzip();
-            for (id, avg) in self
-                .graph
-                .params()
-                .to_vec()
-                .into_par_iter()
-                .map(|id| {
-                    let mut avg = Tensor::<f32>::scalar(0.);
-                    for g in graphs.iter() {
-                        avg = (&avg + g.get_grad(id)?)?;
-                    }
-                    avg = avg.map_values(|f| f / graphs.len() as f32);
-                    Ok((id, avg))
-                })
-                .collect::<Result<Vec<_>, GraphError>>()?
-            {
-                self.graph.load_grad(id, &avg)?;
-            }
-            let avg_loss = errs.iter().sum::<f32>() / errs.len() as f32;
-            let lr = learning_rate(self.graph.optimizer_step());
-            self.graph.optimize(optimizer, lr)?;
-            if i % 10 == 0 {
-                self.sync()?;
-                callback(self)?;
-            }
-            println!(
-                "Step: {} Loss: {} (Elapsed: {}ms)",
-                self.graph.optimizer_step(),
-                avg_loss,
-                timer.elapsed().as_millis()
-            );
+    #[test]
+    fn test_embed() {
+        let mut gpt = tiny_gpt();
+        let text = [0, 2, 1, 1, 0, 2];
+        let hidden = gpt.hidden;
+        let states = rows(&mut gpt, hidden, &text[2..]);
+        let mean = gpt.embed(&text[2..], Pooling::Mean).unwrap();
+        assert_eq!(mean.len(), 8);
+        for (d, v) in mean.iter().enumerate() {
+            let expected = states.iter().map(|s| s[d]).sum::<f32>() / 4.;
+            assert!((v - expected).abs() < 1e-5);
         }
-        Ok(())
+        assert_eq!(gpt.embed(&text[2..], Pooling::Last).unwrap(), states[3]);
+        // Only the last tokens of a longer text are seen
+        assert_eq!(gpt.embed(&text, Pooling::Mean).unwrap(), mean);
+        assert!(matches!(
+            gpt.embed(&[], Pooling::Mean),
+            Err(GptError::EmptyPrompt(0))
+        ));
     }
 
-    pub fn train<O: Optimizer, F: Fn(usize) -> f32, C: Fn(&mut Self) -> Result<(), GraphError>>(
-        &mut self,
-        dataset: &[usize],
-        num_batches: usize,
-        batch_size: usize,
-        limit: Option<usize>,
-        optimizer: &O,
-        learning_rate: F,
-        callback: C,
-    ) -> Result<(), GraphError> {
-        self.graph.load(self.pos_input, &self.pos_input_fixed)?;
+    #[test]
+    fn test_context_overflow() {
+        let mut gpt = tiny_gpt();
+        let mut rng = StdRng::seed_from_u64(42);
+        let prompt = [0, 2, 1, 1, 0, 2];
+        let mut infer = |prompt: &[usize], overflow| {
+            let params = InferParams::new()
+                .count(3)
+                .seed(7)
+                .context_overflow(overflow);
+            gpt.infer(&mut rng, prompt, &params, |_| ())
+        };
+        assert!(matches!(
+            infer(&prompt, ContextOverflow::Error),
+            Err(GptError::PromptTooLong(0, 6, 4))
+        ));
+        let left = infer(&prompt[2..], ContextOverflow::Error).unwrap();
+        let right = infer(&prompt[..4], ContextOverflow::Error).unwrap();
+        assert_eq!(infer(&prompt, ContextOverflow::TruncateLeft).unwrap(), left);
+        assert_eq!(
+            infer(&prompt, ContextOverflow::TruncateRight).unwrap(),
+            right
+        );
+        // The whole prompt is kept, the tokens are generated from the last window
+        let sliding = infer(&prompt, ContextOverflow::Sliding).unwrap();
+        assert_eq!(sliding[..6], prompt);
+        assert_eq!(sliding[2..], left);
+    }
 
-        for i in 0..num_batches {
-            let timer = Instant::now();
-            let mut rng = rand::thread_rng();
-            let (xs, ys) = sample_dataset(dataset, batch_size, self.num_tokens, &mut rng);
+    #[test]
+    fn test_find_batch_size() {
+        let mut tried = Vec::new();
+        let found = find_batch_size(12, |size| {
+            tried.push(size);
+            Ok(())
+        });
+        assert_eq!(found.unwrap(), 12);
+        assert_eq!(tried, [1, 2, 4, 8, 12]);
 
-            self.graph.load_usize(self.token_input, &xs)?;
-            self.graph.load_usize(self.expected_output, &ys)?;
+        // Errors other than a lack of memory aren't retried with a smaller size
+        let found = find_batch_size(12, |size| match size {
+            1 | 2 => Ok(()),
+            _ => Err(GptError::InvalidConfig("unsupported".into())),
+        });
+        assert!(matches!(found, Err(GptError::InvalidConfig(_))));
+    }
 
-            self.graph.forward(true)?;
-            self.graph.zero_grad()?;
-            let err = self.graph.backward_all(self.loss, limit)?;
-            let lr = learning_rate(self.graph.optimizer_step());
-            self.graph.optimize(optimizer, lr)?;
-            if i % 50 == 0 {
-                callback(self)?;
+    #[cfg(feature = "gpu")]
+    #[test]
+    fn test_find_batch_size_out_of_memory() {
+        let found = find_batch_size(100, |size| match size {
+            1..=37 => Ok(()),
+            _ => Err(GraphError::OutOfMemory {
+                requested: size,
+                in_use: 0,
+                largest_tensor: "head_map_weights".into(),
             }
-            println!(
-                "Step: {} Loss: {} (Elapsed: {}ms)",
-                self.graph.optimizer_step(),
-                err,
-                timer.elapsed().as_millis()
-            );
-        }
-        Ok(())
+            .into()),
+        });
+        assert_eq!(found.unwrap(), 37);
     }
 
-    pub fn infer<R: Rng, F: Fn(usize) -> ()>(
-        &mut self,
-        rng: &mut R,
-        prompt: &[usize],
-        count: usize,
-        temperature: f32,
-        callback: F,
-    ) -> Result<Vec<usize>, GraphError> {
-        let mut cnt = prompt.len();
-        let mut context = vec![0; self.num_tokens];
-        context[..prompt.len()].copy_from_slice(prompt);
+    #[test]
+    fn test_train_contrastive() {
+        let pairs = vec![
+            (vec![0, 0, 1], vec![0, 1, 0]),
+            (vec![2, 2, 1], vec![2, 1, 2]),
+        ];
+        let config = TrainConfig {
+            num_batches: 100,
+            batch_size: 2,
+            learning_rate: LrSchedule {
+                base: 0.01,
+                min: 0.01,
+                warmup_steps: 0,
+                decay_steps: 1,
+            },
+            ..Default::default()
+        };
+        let mut gpt = tiny_gpt();
+        assert!(matches!(
+            gpt.train_contrastive(&pairs, &config, &AdamW::new(), |_| Ok::<_, GptError>(())),
+            Err(GptError::InvalidConfig(_))
+        ));
 
-        self.graph.load(self.pos_input, &self.pos_input_fixed)?;
+        // How much closer each anchor is to its positive than to the other one
+        let margin = |gpt: &mut GPT<CpuGraph>| {
+            let mut embed = |t: &[usize]| {
+                let e = gpt.embed(t, Pooling::Mean).unwrap();
+                let norm = e.iter().map(|v| v * v).sum::<f32>().sqrt();
+                e.into_iter().map(|v| v / norm).collect::<Vec<_>>()
+            };
+            let anchors = pairs.iter().map(|p| embed(&p.0)).collect::<Vec<_>>();
+            let positives = pairs.iter().map(|p| embed(&p.1)).collect::<Vec<_>>();
+            let cos = |a: &[f32], b: &[f32]| a.iter().zip(b).map(|(a, b)| a * b).sum::<f32>();
+            cos(&anchors[0], &positives[0]) - cos(&anchors[0], &positives[1])
+                + cos(&anchors[1], &positives[1])
+                - cos(&anchors[1], &positives[0])
+        };
+        let mut gpt = builder()
+            .contrastive(ContrastiveConfig::default())
+            .build(CpuGraph::new())
+            .unwrap();
+        let before = margin(&mut gpt);
+        gpt.train_contrastive(&pairs, &config, &AdamW::new(), |_| Ok::<_, GptError>(()))
+            .unwrap();
+        assert!(margin(&mut gpt) > before + 0.5);
+    }
 
-        for ch in prompt {
-            callback(*ch);
-        }
-        let mut chs = prompt.to_vec();
-        for _ in 0..count {
-            self.graph.load_usize(
-                self.token_input,
-                &Tensor::raw(&[1, self.num_tokens], context.clone())?,
-            )?;
+    #[test]
+    fn test_train_dpo() {
+        let examples = [Preference {
+            prompt: vec![0],
+            chosen: vec![1, 1],
+            rejected: vec![2, 2],
+        }];
+        let config = TrainConfig {
+            num_batches: 20,
+            batch_size: 1,
+            learning_rate: LrSchedule {
+                base: 0.01,
+                min: 0.01,
+                warmup_steps: 0,
+                decay_steps: 1,
+            },
+            ..Default::default()
+        };
+        let mut gpt = builder()
+            .dpo(DpoConfig::default())
+            .build(CpuGraph::new())
+            .unwrap();
+        assert!(matches!(
+            gpt.train_dpo(&examples, &config, &AdamW::new(), |_| Ok::<_, GptError>(())),
+            Err(GptError::InvalidConfig(_))
+        ));
 
-            self.graph.forward(false)?;
-            self.graph.fetch(self.output, false)?;
-            let next_ch = select(
-                rng,
-                &self
-                    .graph
-                    .get(self.output)?
-                    .as_float()?
-                    .get(0)?
-                    .get(cnt - 1)?,
-                temperature,
-            )?;
+        // The model starts as its reference
+        let mut reference = tiny_gpt();
+        reference
+            .set_training_state(gpt.get_training_state().unwrap(), false)
+            .unwrap();
+        gpt.set_reference(reference).unwrap();
+        let gap = |gpt: &mut GPT<CpuGraph>| {
+            gpt.score(&[0, 1, 1]).unwrap() - gpt.score(&[0, 2, 2]).unwrap()
+        };
+        let before = gap(&mut gpt);
+        gpt.train_dpo(&examples, &config, &AdamW::new(), |_| Ok::<_, GptError>(()))
+            .unwrap();
+        assert!(gap(&mut gpt) > before + 1.);
+    }
+
+    #[test]
+    fn test_guidance() {
+        let mut gpt = tiny_gpt();
+        let last = |gpt: &mut GPT<CpuGraph>, seq: &[usize]| logits(gpt, seq).pop().unwrap();
+        // The prompt `[0, 2]` followed by a generated token
+        let seq = [0, 2, 1];
+        let conditional = last(&mut gpt, &seq);
+        let guided = |gpt: &mut GPT<CpuGraph>, params: &InferParams| {
+            gpt.guided_logits(params, &[(&seq[..], 2)])
+                .unwrap()
+                .remove(0)
+        };
+        assert_eq!(guided(&mut gpt, &InferParams::new()), conditional);
 
-            chs.push(next_ch);
-            callback(next_ch);
-            if cnt == self.num_tokens {
-                context.remove(0);
-                context.push(0);
-                cnt -= 1;
+        // The unconditional logits continue the last token of the prompt, or the negative one
+        for (params, unconditional) in [
+            (InferParams::new().guidance_scale(3.), vec![2, 1]),
+            (
+                InferParams::new()
+                    .guidance_scale(3.)
+                    .negative_prompt(vec![1, 1]),
+                vec![1, 1, 1],
+            ),
+        ] {
+            let unconditional = last(&mut gpt, &unconditional);
+            for ((g, c), u) in guided(&mut gpt, &params)
+                .iter()
+                .zip(&conditional)
+                .zip(unconditional)
+            {
+                assert!((g - (u + 3. * (c - u))).abs() < 1e-4);
             }
-            context[cnt] = next_ch;
-            cnt += 1;
         }
-        Ok(chs)
+    }
+
+    #[test]
+    fn test_best_of() {
+        let mut gpt = tiny_gpt();
+        let mut rng = StdRng::seed_from_u64(42);
+        let params = InferParams::new().count(2);
+        let prompts = [[0, 2], [1, 1]];
+        let best = gpt
+            .best_of(&mut rng, &prompts, &params, 3, Rerank::LogProb)
+            .unwrap();
+        assert_eq!(best.len(), 2);
+        // The average log-probability of the generated tokens
+        for (prompt, (seq, score)) in prompts.iter().zip(best) {
+            assert_eq!(seq[..2], prompt[..]);
+            let expected = (gpt.score(&seq).unwrap() - gpt.score(prompt).unwrap()) / 2.;
+            assert!((score - expected).abs() < 1e-4);
+        }
+        assert!(matches!(
+            gpt.best_of(&mut rng, &prompts, &params, 0, Rerank::LogProb),
+            Err(GptError::InvalidConfig(_))
+        ));
+    }
+
+    #[test]
+    fn test_merge_lora() {
+        let mut gpt = builder()
+            .lora(LoraConfig { rank: 2, alpha: 4. })
+            .build(CpuGraph::new())
+            .unwrap();
+        // The adapters start at zero, trained ones change the output
+        train(&mut gpt, 5);
+        gpt.sync().unwrap();
+        gpt.set_training(false);
+        let merged = gpt.merge_lora().unwrap();
+
+        let mut plain = tiny_gpt();
+        plain.set_training(false);
+        assert!(matches!(plain.merge_lora(), Err(GptError::LoraNotEnabled)));
+        let window = [0, 2, 1, 1];
+        let untrained = logits(&mut plain, &window);
+        plain.set_training_state(merged, false).unwrap();
+        let adapted = logits(&mut gpt, &window);
+        assert!(adapted != untrained);
+        for (a, b) in adapted
+            .iter()
+            .flatten()
+            .zip(logits(&mut plain, &window).iter().flatten())
+        {
+            assert!((a - b).abs() < 1e-4);
+        }
     }
 }
@@ -0,0 +1,250 @@
+// Runtime selection of the graph implementation. `Graph` has generic methods, so it can't be used
+// as a trait object, `AnyGraph` dispatches to the selected implementation instead.
+
+use super::*;
+
+/// Graph implementations a binary can be built with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    Cpu,
+    /// Requires the `gpu` feature
+    OpenCl,
+}
+
+impl Default for Backend {
+    /// The fastest backend compiled in.
+    fn default() -> Self {
+        if cfg!(feature = "gpu") {
+            Backend::OpenCl
+        } else {
+            Backend::Cpu
+        }
+    }
+}
+
+impl std::str::FromStr for Backend {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "cpu" => Ok(Backend::Cpu),
+            "opencl" => Ok(Backend::OpenCl),
+            _ => Err(format!("expected `cpu` or `opencl`, got `{}`", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for Backend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Backend::Cpu => write!(f, "cpu"),
+            Backend::OpenCl => write!(f, "opencl"),
+        }
+    }
+}
+
+pub enum AnyGraph {
+    Cpu(CpuGraph),
+    #[cfg(feature = "gpu")]
+    OpenCl(gpu::GpuGraph),
+}
+
+impl AnyGraph {
+    /// Creates an empty graph of the given backend, `device` selects the GPU of the OpenCL
+    /// backend (See `gpu::select_device`).
+    pub fn new(backend: Backend, device: Option<usize>) -> Result<Self, GraphError> {
+        match backend {
+            Backend::Cpu => Ok(AnyGraph::Cpu(CpuGraph::new())),
+            #[cfg(feature = "gpu")]
+            Backend::OpenCl => Ok(AnyGraph::OpenCl(gpu::GpuGraph::with_device(
+                gpu::select_device(device)?,
+            )?)),
+            #[cfg(not(feature = "gpu"))]
+            Backend::OpenCl => {
+                let _ = device;
+                Err(GraphError::BackendUnavailable(backend))
+            }
+        }
+    }
+
+    pub fn backend(&self) -> Backend {
+        match self {
+            AnyGraph::Cpu(_) => Backend::Cpu,
+            #[cfg(feature = "gpu")]
+            AnyGraph::OpenCl(_) => Backend::OpenCl,
+        }
+    }
+
+    pub fn is_gpu(&self) -> bool {
+        self.backend() != Backend::Cpu
+    }
+
+    /// The hardware the graph runs on, e.g. the name of its GPU.
+    pub fn device_name(&self) -> String {
+        match self {
+            AnyGraph::Cpu(_) => format!("{} CPU threads", rayon::current_num_threads()),
+            #[cfg(feature = "gpu")]
+            AnyGraph::OpenCl(g) => g.device().name().to_string(),
+        }
+    }
+}
+
+// Needed by `GPT::train_cpu`, which is only used with CPU graphs. GPU graphs own device buffers,
+// use one graph per device instead (See `GPT::train_data_parallel`).
+impl Clone for AnyGraph {
+    fn clone(&self) -> Self {
+        match self {
+            AnyGraph::Cpu(g) => AnyGraph::Cpu(g.clone()),
+            #[cfg(feature = "gpu")]
+            AnyGraph::OpenCl(_) => panic!("GPU graphs cannot be cloned"),
+        }
+    }
+}
+
+macro_rules! dispatch {
+    ($self:ident, $g:ident => $e:expr) => {
+        match $self {
+            AnyGraph::Cpu($g) => $e,
+            #[cfg(feature = "gpu")]
+            AnyGraph::OpenCl($g) => $e,
+        }
+    };
+}
+
+impl Graph for AnyGraph {
+    fn alloc(
+        &mut self,
+        t: Tensor<f32>,
+        is_param: bool,
+        name: String,
+    ) -> Result<TensorId, GraphError> {
+        dispatch!(self, g => g.alloc(t, is_param, name))
+    }
+    fn alloc_usize(&mut self, t: Tensor<usize>, name: String) -> Result<TensorId, GraphError> {
+        dispatch!(self, g => g.alloc_usize(t, name))
+    }
+    fn params(&self) -> &[TensorId] {
+        dispatch!(self, g => g.params())
+    }
+    fn freeze(&mut self, id: TensorId) -> Result<(), GraphError> {
+        dispatch!(self, g => g.freeze(id))
+    }
+    fn load<T: TensorOps<f32>>(
+        &mut self,
+        tensor_id: TensorId,
+        tensor: &T,
+    ) -> Result<(), GraphError> {
+        dispatch!(self, g => g.load(tensor_id, tensor))
+    }
+    fn load_usize<T: TensorOps<usize>>(
+        &mut self,
+        tensor_id: TensorId,
+        tensor: &T,
+    ) -> Result<(), GraphError> {
+        dispatch!(self, g => g.load_usize(tensor_id, tensor))
+    }
+    fn stage_usize<T: TensorOps<usize>>(
+        &mut self,
+        tensor_id: TensorId,
+        tensor: &T,
+    ) -> Result<(), GraphError> {
+        dispatch!(self, g => g.stage_usize(tensor_id, tensor))
+    }
+    fn load_grad<T: TensorOps<f32>>(
+        &mut self,
+        tensor_id: TensorId,
+        tensor: &T,
+    ) -> Result<(), GraphError> {
+        dispatch!(self, g => g.load_grad(tensor_id, tensor))
+    }
+    fn zero_grad(&mut self) -> Result<(), GraphError> {
+        dispatch!(self, g => g.zero_grad())
+    }
+    fn set_name(&mut self, id: TensorId, name: String) -> Result<(), GraphError> {
+        dispatch!(self, g => g.set_name(id, name))
+    }
+    fn name_of(&self, id: TensorId) -> Result<&String, GraphError> {
+        dispatch!(self, g => g.name_of(id))
+    }
+    fn fetch(&mut self, id: TensorId, grad: bool) -> Result<(), GraphError> {
+        dispatch!(self, g => g.fetch(id, grad))
+    }
+    fn get(&self, id: TensorId) -> Result<&GeneralTensor, GraphError> {
+        dispatch!(self, g => Graph::get(g, id))
+    }
+    fn get_grad(&self, id: TensorId) -> Result<&Tensor<f32>, GraphError> {
+        dispatch!(self, g => g.get_grad(id))
+    }
+    fn backward_all(
+        &mut self,
+        id: TensorId,
+        limit: Option<usize>,
+        params_only: bool,
+    ) -> Result<f32, GraphError> {
+        dispatch!(self, g => g.backward_all(id, limit, params_only))
+    }
+    fn forward(&mut self, training: bool) -> Result<(), GraphError> {
+        dispatch!(self, g => g.forward(training))
+    }
+    fn call(
+        &mut self,
+        f: Box<dyn Function>,
+        tensor_ids: &[TensorId],
+    ) -> Result<TensorId, GraphError> {
+        dispatch!(self, g => g.call(f, tensor_ids))
+    }
+    fn num_computations(&self) -> usize {
+        dispatch!(self, g => g.num_computations())
+    }
+    fn fuse_elementwise(&mut self, keep: &[TensorId]) -> Result<usize, GraphError> {
+        dispatch!(self, g => g.fuse_elementwise(keep))
+    }
+    fn replace(&mut self, id: TensorId, f: Box<dyn Function>) -> Result<(), GraphError> {
+        dispatch!(self, g => g.replace(id, f))
+    }
+    fn fold_transposes(&mut self, keep: &[TensorId]) -> Result<usize, GraphError> {
+        dispatch!(self, g => g.fold_transposes(keep))
+    }
+    fn add_hook(&mut self, id: TensorId, hook: Hook) -> Result<(), GraphError> {
+        dispatch!(self, g => g.add_hook(id, hook))
+    }
+    fn clear_hooks(&mut self) {
+        dispatch!(self, g => g.clear_hooks())
+    }
+    fn nodes(&self) -> Vec<NodeInfo> {
+        dispatch!(self, g => g.nodes())
+    }
+    fn set_precision(&mut self, precision: Precision) -> Result<(), GraphError> {
+        dispatch!(self, g => g.set_precision(precision))
+    }
+    fn set_loss_scale(&mut self, scale: f32) -> Result<(), GraphError> {
+        dispatch!(self, g => g.set_loss_scale(scale))
+    }
+    fn set_detect_anomaly(&mut self, enabled: bool) -> Result<(), GraphError> {
+        dispatch!(self, g => g.set_detect_anomaly(enabled))
+    }
+    fn set_buffer_reuse(&mut self, enabled: bool) -> Result<(), GraphError> {
+        dispatch!(self, g => g.set_buffer_reuse(enabled))
+    }
+    fn set_profiling(&mut self, enabled: bool) -> Result<(), GraphError> {
+        dispatch!(self, g => g.set_profiling(enabled))
+    }
+    fn take_profile(&mut self) -> Profile {
+        dispatch!(self, g => g.take_profile())
+    }
+    fn optimize<O: Optimizer>(
+        &mut self,
+        optimizer: &O,
+        learning_rate: f32,
+    ) -> Result<(), GraphError> {
+        dispatch!(self, g => g.optimize(optimizer, learning_rate))
+    }
+    fn optimizer_step(&self) -> usize {
+        dispatch!(self, g => g.optimizer_step())
+    }
+    fn get_optimizer_state(&self) -> Result<OptimizerState, GraphError> {
+        dispatch!(self, g => g.get_optimizer_state())
+    }
+    fn set_optimizer_state(&mut self, state: &OptimizerState) -> Result<(), GraphError> {
+        dispatch!(self, g => g.set_optimizer_state(state))
+    }
+}
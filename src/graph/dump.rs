@@ -0,0 +1,108 @@
+use super::TensorId;
+
+/// A tensor of a graph, along with the operation that computes it (None for inputs and
+/// parameters).
+#[derive(Debug, Clone)]
+pub struct NodeInfo {
+    pub id: TensorId,
+    pub name: String,
+    pub shape: Vec<usize>,
+    pub is_param: bool,
+    pub op: Option<String>,
+    pub inputs: Vec<TensorId>,
+}
+
+/// Formats of graph dumps.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DumpFormat {
+    /// Graphviz source, render with e.g. `dot -Tsvg`
+    Dot,
+    /// One line per tensor
+    Text,
+}
+
+impl std::str::FromStr for DumpFormat {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "dot" => Ok(DumpFormat::Dot),
+            "text" => Ok(DumpFormat::Text),
+            _ => Err(format!("expected `dot` or `text`, got `{}`", s)),
+        }
+    }
+}
+
+pub(crate) fn label(node: &NodeInfo) -> String {
+    let title = match (&node.op, node.name.is_empty()) {
+        (Some(op), true) => op.clone(),
+        (Some(op), false) => format!("{} ({})", op, node.name),
+        (None, _) => node.name.clone(),
+    };
+    format!("#{} {} {:?}", node.id, title, node.shape)
+}
+
+pub fn dump_graph(nodes: &[NodeInfo], format: DumpFormat) -> String {
+    let mut out = String::new();
+    match format {
+        DumpFormat::Dot => {
+            out += "digraph femto {\n";
+            out += "  node [shape=box, fontname=\"monospace\"];\n";
+            for node in nodes.iter() {
+                let style = if node.is_param {
+                    ", style=filled, fillcolor=lightblue"
+                } else if node.op.is_none() {
+                    ", shape=ellipse"
+                } else {
+                    ""
+                };
+                out += &format!(
+                    "  t{} [label=\"{}\"{}];\n",
+                    node.id,
+                    label(node).replace('"', "\\\""),
+                    style
+                );
+            }
+            for node in nodes.iter() {
+                for inp in node.inputs.iter() {
+                    out += &format!("  t{} -> t{};\n", inp, node.id);
+                }
+            }
+            out += "}\n";
+        }
+        DumpFormat::Text => {
+            for node in nodes.iter() {
+                out += &label(node);
+                if !node.inputs.is_empty() {
+                    let inputs = node
+                        .inputs
+                        .iter()
+                        .map(|i| format!("#{}", i))
+                        .collect::<Vec<_>>();
+                    out += &format!(" <- {}", inputs.join(", "));
+                }
+                out += "\n";
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::funcs::Add;
+    use crate::graph::{CpuGraph, Graph};
+    use crate::tensor::Tensor;
+
+    #[test]
+    fn test_dump_dot() {
+        let mut g = CpuGraph::new();
+        let a = g.alloc(Tensor::zeros(&[2, 3]), true, "a".into()).unwrap();
+        let b = g.alloc(Tensor::zeros(&[2, 3]), false, "b".into()).unwrap();
+        let c = g.call(Add::new(), &[a, b]).unwrap();
+        let dot = dump_graph(&g.nodes(), DumpFormat::Dot);
+        assert!(dot.contains(&format!("t{} -> t{};", a, c)));
+        assert!(dot.contains(&format!("t{} -> t{};", b, c)));
+        assert!(dot.contains("Add [2, 3]"));
+    }
+}
@@ -0,0 +1,295 @@
+// Fusion of elementwise computations: chains of computations that apply elementwise (See
+// `Function::elementwise`), each one only consumed by the next, are replaced by a single `Fused`
+// computation producing the output of the last one. Intermediate results never leave the
+// registers on GPUs, and are not stored on CPUs.
+//
+// Transposes are folded the same way into the computations consuming them, when these can read
+// their inputs transposed (See `Function::with_transposed_inputs`).
+
+use super::{Computation, TensorId};
+use crate::funcs::{Elementwise, Fused};
+use std::collections::HashMap;
+
+pub(super) struct Fusion {
+    pub out: TensorId,
+    // Outputs of the computations made useless by the new one, which are not computed anymore
+    pub removed: Vec<TensorId>,
+    pub computation: Computation,
+}
+
+struct Chain {
+    input: TensorId,
+    ops: Vec<Elementwise>,
+    operands: Vec<TensorId>,
+    outputs: Vec<TensorId>,
+}
+
+pub(super) fn plan_fusions<'a, I: Iterator<Item = (TensorId, &'a Computation)> + Clone>(
+    computations: I,
+    keep: &[TensorId],
+    size_of: impl Fn(TensorId) -> usize,
+) -> Vec<Fusion> {
+    let mut uses = HashMap::<TensorId, usize>::new();
+    for (_, c) in computations.clone() {
+        for inp in c.inps.iter() {
+            *uses.entry(*inp).or_default() += 1;
+        }
+    }
+
+    // Chains by the tensor they currently end with
+    let mut chains = HashMap::<TensorId, Chain>::new();
+    for (out, c) in computations {
+        let Some(op) = c.func.elementwise() else {
+            continue;
+        };
+        let arity = if op == Elementwise::Add { 2 } else { 1 };
+        if c.inps.len() != arity {
+            continue;
+        }
+        // The tensor going through the operation must have the shape of the result, the other
+        // input of an `Add` may be broadcasted
+        let through = |inp: &TensorId| size_of(*inp) == size_of(out);
+        let extended = c.inps.iter().position(|inp| {
+            through(inp) && chains.contains_key(inp) && uses[inp] == 1 && !keep.contains(inp)
+        });
+        let (mut chain, index) = match extended {
+            Some(index) => (chains.remove(&c.inps[index]).unwrap(), index),
+            None => match c.inps.iter().position(through) {
+                Some(index) => (
+                    Chain {
+                        input: c.inps[index],
+                        ops: Vec::new(),
+                        operands: Vec::new(),
+                        outputs: Vec::new(),
+                    },
+                    index,
+                ),
+                None => continue,
+            },
+        };
+        chain.ops.push(op);
+        if op == Elementwise::Add {
+            chain.operands.push(c.inps[1 - index]);
+        }
+        chain.outputs.push(out);
+        chains.insert(out, chain);
+    }
+
+    let mut fusions = chains
+        .into_values()
+        .filter(|chain| chain.ops.len() > 1)
+        .map(|mut chain| {
+            let out = chain.outputs.pop().unwrap();
+            Fusion {
+                out,
+                removed: chain.outputs,
+                computation: Computation {
+                    inps: std::iter::once(chain.input).chain(chain.operands).collect(),
+                    func: Fused::new(chain.ops),
+                },
+            }
+        })
+        .collect::<Vec<_>>();
+    fusions.sort_by_key(|f| f.out);
+    fusions
+}
+
+pub(super) fn plan_transpose_folds<'a, I: Iterator<Item = (TensorId, &'a Computation)> + Clone>(
+    computations: I,
+    keep: &[TensorId],
+) -> Vec<Fusion> {
+    // Inputs of the transposes that can be folded, by output
+    let transposes = computations
+        .clone()
+        .filter(|(out, c)| c.func.is_transpose() && c.inps.len() == 1 && !keep.contains(out))
+        .map(|(out, c)| (out, c.inps[0]))
+        .collect::<HashMap<_, _>>();
+    let mut uses = HashMap::<TensorId, usize>::new();
+    for (_, c) in computations.clone() {
+        for inp in c.inps.iter().filter(|inp| transposes.contains_key(inp)) {
+            *uses.entry(*inp).or_default() += 1;
+        }
+    }
+
+    let mut folds = Vec::new();
+    for (out, c) in computations {
+        let transposed = c
+            .inps
+            .iter()
+            .map(|inp| transposes.contains_key(inp))
+            .collect::<Vec<_>>();
+        if !transposed.contains(&true) {
+            continue;
+        }
+        let Some(func) = c.func.with_transposed_inputs(&transposed) else {
+            continue;
+        };
+        // A transpose is removed along with its last consumer
+        let mut removed = Vec::new();
+        for inp in c.inps.iter().filter(|inp| transposes.contains_key(inp)) {
+            let remaining = uses.get_mut(inp).unwrap();
+            *remaining -= 1;
+            if *remaining == 0 {
+                removed.push(*inp);
+            }
+        }
+        folds.push(Fusion {
+            out,
+            removed,
+            computation: Computation {
+                inps: c
+                    .inps
+                    .iter()
+                    .map(|inp| transposes.get(inp).copied().unwrap_or(*inp))
+                    .collect(),
+                func,
+            },
+        });
+    }
+    folds
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::funcs::{Add, Coeff, Gelu, MatMul, Softmax, Transpose};
+    use crate::graph::{CpuGraph, Graph};
+    use crate::tensor::*;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    // A feed-forward block with a residual connection: the bias, activation and residual
+    // additions can be fused, the attention-like softmax output is used twice
+    fn build(rng: &mut StdRng) -> (CpuGraph, Vec<TensorId>) {
+        let mut g = CpuGraph::new();
+        let x = g
+            .alloc(Tensor::<f32>::rand(rng, &[4, 3]), false, "x".into())
+            .unwrap();
+        let w = g
+            .alloc(Tensor::<f32>::rand(rng, &[3, 3]), true, "w".into())
+            .unwrap();
+        let b = g
+            .alloc(Tensor::<f32>::rand(rng, &[3]), true, "b".into())
+            .unwrap();
+        let lin = g.call(MatMul::new(), &[x, w]).unwrap();
+        let biased = g.call(Add::new(), &[lin, b]).unwrap();
+        let act = g.call(Gelu::new(), &[biased]).unwrap();
+        let scaled = g.call(Coeff::new(0.5), &[act]).unwrap();
+        let out = g.call(Add::new(), &[x, scaled]).unwrap();
+        let soft = g.call(Softmax::new(), &[out]).unwrap();
+        let twice = g.call(Add::new(), &[soft, soft]).unwrap();
+        (g, vec![x, w, b, biased, act, scaled, out, twice])
+    }
+
+    #[test]
+    fn test_fuse_elementwise() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let (mut unfused, _) = build(&mut rng);
+        let mut rng = StdRng::seed_from_u64(1);
+        let (mut fused, ids) = build(&mut rng);
+        let [_, w, b, biased, act, scaled, out, twice] = ids[..] else {
+            unreachable!()
+        };
+
+        assert_eq!(fused.fuse_elementwise(&[]).unwrap(), 3);
+        let nodes = fused.nodes();
+        assert!(nodes.iter().all(|n| ![biased, act, scaled].contains(&n.id)));
+        let node = nodes.iter().find(|n| n.id == out).unwrap();
+        assert_eq!(node.op.as_deref(), Some("Add+Gelu+Coeff+Add"));
+        // Inputs used twice by a single computation are left alone
+        let node = nodes.iter().find(|n| n.id == twice).unwrap();
+        assert_eq!(node.op.as_deref(), Some("Add"));
+
+        for g in [&mut unfused, &mut fused] {
+            g.forward(true).unwrap();
+            g.zero_grad().unwrap();
+            g.backward_all(twice, None, false).unwrap();
+        }
+        let close = |a: &Tensor<f32>, b: &Tensor<f32>| {
+            a.blob()
+                .iter()
+                .zip(b.blob().iter())
+                .all(|(x, y)| (x - y).abs() < 1e-5)
+        };
+        for id in [out, twice] {
+            assert!(close(
+                unfused.get(id).unwrap().as_float().unwrap(),
+                fused.get(id).unwrap().as_float().unwrap()
+            ));
+        }
+        for id in [w, b] {
+            assert!(close(
+                unfused.get_grad(id).unwrap(),
+                fused.get_grad(id).unwrap()
+            ));
+        }
+    }
+
+    #[test]
+    fn test_fuse_elementwise_keep() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let (mut g, ids) = build(&mut rng);
+        // Keeping the activation splits the chain in two
+        assert_eq!(g.fuse_elementwise(&[ids[4]]).unwrap(), 2);
+    }
+
+    #[test]
+    fn test_fold_transposes() {
+        // Attention-like products, the transpose of `k` is also consumed by a softmax
+        let build = |rng: &mut StdRng| {
+            let mut g = CpuGraph::new();
+            let mut alloc = |g: &mut CpuGraph, name: &str| {
+                g.alloc(Tensor::<f32>::rand(rng, &[2, 3, 4]), true, name.into())
+                    .unwrap()
+            };
+            let q = alloc(&mut g, "q");
+            let k = alloc(&mut g, "k");
+            let v = alloc(&mut g, "v");
+            let q_t = g.call(Transpose::new(), &[q]).unwrap();
+            let k_t = g.call(Transpose::new(), &[k]).unwrap();
+            let qk = g.call(MatMul::new(), &[v, q_t]).unwrap();
+            let soft = g.call(Softmax::new(), &[k_t]).unwrap();
+            let kv = g.call(MatMul::new(), &[k_t, v]).unwrap();
+            let out = g.call(MatMul::new(), &[qk, v]).unwrap();
+            let out = g.call(MatMul::new(), &[out, kv]).unwrap();
+            let out = g.call(MatMul::new(), &[out, soft]).unwrap();
+            (g, vec![q, k, v, q_t, k_t, out])
+        };
+        let mut rng = StdRng::seed_from_u64(1);
+        let (mut unfolded, _) = build(&mut rng);
+        let mut rng = StdRng::seed_from_u64(1);
+        let (mut folded, ids) = build(&mut rng);
+        let [q, k, v, q_t, k_t, out] = ids[..] else {
+            unreachable!()
+        };
+
+        assert_eq!(folded.fold_transposes(&[]).unwrap(), 1);
+        let nodes = folded.nodes();
+        assert!(nodes.iter().all(|n| n.id != q_t));
+        assert!(nodes.iter().any(|n| n.id == k_t));
+        assert!(nodes
+            .iter()
+            .any(|n| n.op.as_deref() == Some("MatMul(a^T)") && n.inputs == vec![k, v]));
+
+        for g in [&mut unfolded, &mut folded] {
+            g.forward(true).unwrap();
+            g.zero_grad().unwrap();
+            g.backward_all(out, None, false).unwrap();
+        }
+        let close = |a: &Tensor<f32>, b: &Tensor<f32>| {
+            a.blob()
+                .iter()
+                .zip(b.blob().iter())
+                .all(|(x, y)| (x - y).abs() < 1e-4)
+        };
+        assert!(close(
+            unfolded.get(out).unwrap().as_float().unwrap(),
+            folded.get(out).unwrap().as_float().unwrap()
+        ));
+        for id in [q, k, v] {
+            assert!(close(
+                unfolded.get_grad(id).unwrap(),
+                folded.get_grad(id).unwrap()
+            ));
+        }
+    }
+}
@@ -0,0 +1,107 @@
+// Online tuning of the work-group sizes of generated kernels. The generated kernels accept any
+// of the candidate work-group sizes (Row reductions size their local memory for the largest one,
+// see `ROW_GROUP`), but the fastest one differs a lot between devices. The first runs of a
+// kernel are timed with each candidate size in turn (These are real runs, so tuning has no side
+// effects besides blocking on the queue), then the fastest size is used and persisted in
+// `cache_dir()`, per device and driver.
+
+use super::program::{cache_dir, Device};
+use crate::checkpoint::fnv1a;
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+use std::time::Duration;
+
+pub const WORK_GROUP_SIZES: [usize; 5] = [16, 32, 64, 128, 256];
+
+#[derive(Debug, Default)]
+pub struct Autotuner {
+    path: Option<PathBuf>,
+    candidates: Vec<usize>,
+    best: BTreeMap<String, usize>,
+    trials: HashMap<String, Vec<(usize, Option<Duration>)>>,
+    dirty: bool,
+}
+
+/// Identifies a kernel across runs, by its source and global work size.
+pub fn kernel_key(source_code: &str, global_work_size: usize) -> String {
+    format!(
+        "{:016x}-{}",
+        fnv1a(source_code.as_bytes()),
+        global_work_size
+    )
+}
+
+impl Autotuner {
+    /// Loads the sizes previously tuned on `device`, if any.
+    pub fn load(device: &Device) -> Self {
+        let max_size = device.max_work_group_size().unwrap_or(256);
+        let path = device.driver_version().ok().and_then(|driver| {
+            Some(cache_dir()?.join(format!(
+                "worksizes-{:016x}-{:016x}.json",
+                fnv1a(device.name().as_bytes()),
+                fnv1a(driver.as_bytes())
+            )))
+        });
+        let best = path
+            .as_ref()
+            .and_then(|p| std::fs::read(p).ok())
+            .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+            .unwrap_or_default();
+        Self {
+            path,
+            candidates: WORK_GROUP_SIZES
+                .into_iter()
+                .filter(|s| *s <= max_size)
+                .collect(),
+            best,
+            trials: Default::default(),
+            dirty: false,
+        }
+    }
+
+    pub fn best(&self, key: &str) -> Option<usize> {
+        self.best.get(key).copied()
+    }
+
+    /// The size to time the next run of a kernel with, None once it's tuned.
+    pub fn trial(&self, key: &str) -> Option<usize> {
+        if self.best.contains_key(key) {
+            return None;
+        }
+        let tried = self.trials.get(key).map_or(0, |t| t.len());
+        self.candidates.get(tried).copied()
+    }
+
+    /// Records the duration of a timed run (None if the size was rejected by the device). After
+    /// the last candidate, the fastest size is picked, `default` if none of them worked.
+    pub fn record(&mut self, key: &str, size: usize, elapsed: Option<Duration>, default: usize) {
+        let trials = self.trials.entry(key.to_string()).or_default();
+        trials.push((size, elapsed));
+        if trials.len() < self.candidates.len() {
+            return;
+        }
+        let best = trials
+            .iter()
+            .filter_map(|(size, elapsed)| Some((*size, (*elapsed)?)))
+            .min_by_key(|(_, elapsed)| *elapsed)
+            .map_or(default, |(size, _)| size);
+        self.trials.remove(key);
+        self.best.insert(key.to_string(), best);
+        self.dirty = true;
+    }
+
+    /// Persists the newly tuned sizes. Best-effort, tuning is simply redone when it fails.
+    pub fn save(&mut self) {
+        let Some(path) = self.path.as_ref().filter(|_| self.dirty) else {
+            return;
+        };
+        self.dirty = false;
+        let Ok(json) = serde_json::to_vec(&self.best) else {
+            return;
+        };
+        let tmp = path.with_extension(format!("{}.tmp", std::process::id()));
+        let _ = std::fs::create_dir_all(path.parent().unwrap())
+            .and_then(|_| std::fs::write(&tmp, json))
+            .and_then(|_| std::fs::rename(&tmp, path));
+    }
+}
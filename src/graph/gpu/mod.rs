@@ -1,12 +1,17 @@
+pub mod autotune;
 pub mod program;
 use super::*;
-use crate::funcs::{GpuFunction, SharedBuffer};
-use program::{Brand, Buffer, Device, Program, ProgramError};
+use crate::funcs::{GpuFunction, KernelCall, SharedBuffer, PHILOX_SOURCE};
+use crate::optimizer::{ParamSettings, QuantizedMoments, MOMENT_BLOCK_SIZE};
+use autotune::Autotuner;
+use program::{Brand, Buffer, Device, Kernel, Program, ProgramError};
 use std::collections::HashMap;
+use tracing::{info, info_span};
 
 pub enum GeneralBuffer {
     Float(Buffer<f32>),
     Usize(Buffer<usize>),
+    Bytes(Buffer<u8>),
 }
 
 pub struct GpuTensor {
@@ -15,6 +20,130 @@ pub struct GpuTensor {
     is_sync: bool,
 }
 
+impl GpuTensor {
+    fn allocated_bytes(&self) -> usize {
+        self.buffer.as_ref().map_or(0, |b| b.size_in_bytes())
+    }
+}
+
+// Where the moments of the optimizer are kept, depending on the optimizer (See `Optimizer`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MomentStorage {
+    F32,
+    Int8,
+    // The optimizer has no kernels, its steps run on the host
+    Host,
+}
+
+// 8-bit Adam moments of a parameter, see `QuantizedMoments`
+struct GpuQuantizedMoments {
+    shape: Vec<usize>,
+    m: Buffer<u8>, // `char`s in the kernel
+    v: Buffer<u8>,
+    m_scale: Buffer<f32>,
+    v_scale: Buffer<f32>,
+}
+
+impl GpuQuantizedMoments {
+    fn allocated_bytes(&self) -> usize {
+        self.m.size_in_bytes()
+            + self.v.size_in_bytes()
+            + self.m_scale.size_in_bytes()
+            + self.v_scale.size_in_bytes()
+    }
+    fn read(&self) -> Result<QuantizedMoments, GraphError> {
+        let mut m = vec![0u8; self.m.length()];
+        let mut q = QuantizedMoments {
+            shape: self.shape.clone(),
+            m: Vec::new(),
+            m_scale: vec![0.; self.m_scale.length()],
+            v: vec![0; self.v.length()],
+            v_scale: vec![0.; self.v_scale.length()],
+        };
+        self.m.read_into(&mut m)?;
+        self.v.read_into(&mut q.v)?;
+        self.m_scale.read_into(&mut q.m_scale)?;
+        self.v_scale.read_into(&mut q.v_scale)?;
+        q.m = m.into_iter().map(|m| m as i8).collect();
+        Ok(q)
+    }
+    fn write(&mut self, q: &QuantizedMoments) -> Result<(), GraphError> {
+        self.m
+            .write_from(&q.m.iter().map(|m| *m as u8).collect::<Vec<_>>())?;
+        self.v.write_from(&q.v)?;
+        self.m_scale.write_from(&q.m_scale)?;
+        self.v_scale.write_from(&q.v_scale)?;
+        Ok(())
+    }
+}
+
+fn mirror_bytes(t: &GeneralTensor) -> usize {
+    match t {
+        GeneralTensor::Float(t) => t.size() * std::mem::size_of::<f32>(),
+        GeneralTensor::Usize(t) => t.size() * std::mem::size_of::<usize>(),
+    }
+}
+
+// Kernels of a computation producing tensor `out_id`
+fn gpu_function(
+    f: &dyn Function,
+    out_id: TensorId,
+    shapes: &[Vec<usize>],
+) -> Result<GpuFunction, GraphError> {
+    f.gpu_impl(out_id, shapes)
+        .ok_or_else(|| GraphError::NoGpuImpl(f.op_name()))
+}
+
+// Counts the bytes allocated so far, so that allocation failures can be reported along with the
+// memory already in use and the tensor most likely responsible for it.
+struct AllocationTracker {
+    in_use: usize,
+    largest_tensor: String,
+}
+
+impl AllocationTracker {
+    fn track<T>(
+        &mut self,
+        requested: usize,
+        buffer: Result<T, GraphError>,
+    ) -> Result<T, GraphError> {
+        match buffer {
+            Ok(b) => {
+                self.in_use += requested;
+                Ok(b)
+            }
+            Err(GraphError::GpuError(e)) if e.is_out_of_memory() => Err(GraphError::OutOfMemory {
+                requested,
+                in_use: self.in_use,
+                largest_tensor: self.largest_tensor.clone(),
+            }),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+// Inputs loaded with `stage_usize` are double-buffered: the next value is uploaded to a spare
+// buffer on the transfer queue, while the kernels of the current step still use the other one,
+// and the buffers are swapped by the next forward pass.
+struct StagedUpload {
+    buffer: GeneralBuffer,
+    mirror: GeneralTensor, // Source of the upload, must outlive it
+    done: ocl::Event,
+}
+
+impl StagedUpload {
+    fn wait(&self) -> Result<(), ProgramError> {
+        self.done.wait_for()?;
+        Ok(())
+    }
+}
+
+struct SpareBuffer {
+    buffer: GeneralBuffer,
+    // Completes once the kernels that used the buffer are done
+    released: Option<ocl::Event>,
+}
+
 impl GeneralBuffer {
     fn new(prog: &Program, t: &GeneralTensor) -> Result<Self, GraphError> {
         let mut buff = match t {
@@ -28,6 +157,14 @@ impl GeneralBuffer {
         match self {
             GeneralBuffer::Float(b) => b.length(),
             GeneralBuffer::Usize(b) => b.length(),
+            GeneralBuffer::Bytes(b) => b.length(),
+        }
+    }
+    fn size_in_bytes(&self) -> usize {
+        match self {
+            GeneralBuffer::Float(b) => b.size_in_bytes(),
+            GeneralBuffer::Usize(b) => b.size_in_bytes(),
+            GeneralBuffer::Bytes(b) => b.size_in_bytes(),
         }
     }
     fn write_from(&mut self, t: &GeneralTensor) -> Result<(), GraphError> {
@@ -48,6 +185,9 @@ impl GeneralBuffer {
                     b.write_from(t.blob())?;
                 }
             },
+            GeneralBuffer::Bytes(_) => {
+                return Err(GraphError::IncompatibleTypes);
+            }
         }
         Ok(())
     }
@@ -73,6 +213,9 @@ impl GeneralBuffer {
                     *t = Tensor::raw(t.shape(), blob)?;
                 }
             },
+            GeneralBuffer::Bytes(_) => {
+                return Err(GraphError::IncompatibleTypes);
+            }
         }
         Ok(())
     }
@@ -87,6 +230,30 @@ impl<'a> program::KernelArgument<'a> for &'a GeneralBuffer {
             GeneralBuffer::Usize(b) => {
                 b.push(kernel);
             }
+            GeneralBuffer::Bytes(b) => {
+                b.push(kernel);
+            }
+        }
+    }
+}
+
+// Arguments of the kernels of a computation between its output and its inputs (See `SharedBuffer`)
+enum SharedArg {
+    Buffer(GeneralBuffer),
+    Random,
+}
+
+impl SharedArg {
+    fn size_in_bytes(&self) -> usize {
+        match self {
+            SharedArg::Buffer(b) => b.size_in_bytes(),
+            SharedArg::Random => 0,
+        }
+    }
+    fn push<'a>(&'a self, kern: Kernel<'a>, rng_key: u64, rng_pass: u32) -> Kernel<'a> {
+        match self {
+            SharedArg::Buffer(b) => kern.arg(b),
+            SharedArg::Random => kern.arg(rng_key).arg(rng_pass),
         }
     }
 }
@@ -99,7 +266,26 @@ pub struct GpuComputation {
 
 pub struct CompiledGraph {
     program: Program,
-    comp_buffers: HashMap<TensorId, Vec<GeneralBuffer>>,
+    comp_buffers: HashMap<TensorId, Vec<SharedArg>>,
+    grad_plan: GradPlan,
+    grad_pool: Vec<GeneralBuffer>,
+}
+
+// The gradient buffer of a tensor, shared with other tensors when planned so
+fn grad_buffer<'a>(
+    grads: &'a [GpuTensor],
+    program: &'a CompiledGraph,
+    id: TensorId,
+) -> Result<&'a GeneralBuffer, GraphError> {
+    match program.grad_plan.slots.get(&id) {
+        Some(slot) => Ok(&program.grad_pool[*slot]),
+        None => grads
+            .get(id)
+            .ok_or(GraphError::TensorNotFound(id))?
+            .buffer
+            .as_ref()
+            .ok_or(GraphError::NotReady),
+    }
 }
 
 pub struct GpuGraph {
@@ -111,13 +297,135 @@ pub struct GpuGraph {
     names: Vec<String>,
     computations: BTreeMap<TensorId, GpuComputation>,
     optimizer_state: HashMap<String, GpuTensor>,
+    // Moments of the parameters by name when the optimizer wants them in 8 bits, replacing
+    // `optimizer_state`
+    quantized_moments: HashMap<String, GpuQuantizedMoments>,
+    host_optimizer_state: OptimizerState,
+    moments: MomentStorage,
     optimizer_step: usize,
+    precision: Precision,
+    loss_scale: f32,
+    detect_anomaly: bool,
+    profiling: bool,
+    profile: Profile,
+    buffer_reuse: bool,
+    autotuner: Autotuner,
+    staged: HashMap<TensorId, StagedUpload>,
+    spare: HashMap<TensorId, SpareBuffer>,
+    fused: HashSet<TensorId>,
+    hooks: HashMap<TensorId, Vec<Hook>>,
+    // Key of the random numbers of the kernels, and counter of the current pass (0 in inference
+    // mode) out of the training passes so far
+    rng_key: u64,
+    rng_pass: u32,
+    training_passes: u32,
+}
+
+// Runs a generated kernel with the work-group size picked by the autotuner, timing it while the
+// kernel is being tuned. Sizes the device rejects are retried with the kernel's default size.
+// When profiling, the event of the kernel is added to `events`.
+fn run_kernel<'a, A: Fn(Kernel<'a>) -> Result<Kernel<'a>, GraphError>>(
+    program: &'a Program,
+    autotuner: &mut Autotuner,
+    call: &KernelCall,
+    global_work_size: usize,
+    events: Option<&mut Vec<ocl::Event>>,
+    args: A,
+) -> Result<(), GraphError> {
+    let timed = events.is_some();
+    let launch = |local_work_size: usize| -> Result<Option<ocl::Event>, GraphError> {
+        let padded = global_work_size
+            + (local_work_size - (global_work_size % local_work_size)) % local_work_size;
+        let kern = args(program.create_kernel(&call.kernel_name, padded, local_work_size))?;
+        if timed {
+            return Ok(Some(kern.run_with_event()?));
+        }
+        kern.run()?;
+        Ok(None)
+    };
+    let key = autotune::kernel_key(&call.source_code, global_work_size);
+    let event = match autotuner.trial(&key) {
+        Some(size) => {
+            program.finish()?;
+            let timer = std::time::Instant::now();
+            let result = launch(size).and_then(|event| {
+                program.finish()?;
+                Ok(event)
+            });
+            let elapsed = result.is_ok().then(|| timer.elapsed());
+            autotuner.record(&key, size, elapsed, call.local_work_size);
+            match result {
+                Ok(event) => event,
+                Err(_) => launch(call.local_work_size)?,
+            }
+        }
+        None => launch(autotuner.best(&key).unwrap_or(call.local_work_size))?,
+    };
+    if let (Some(events), Some(event)) = (events, event) {
+        events.push(event);
+    }
+    Ok(())
+}
+
+// Adds the time the kernels of `events` took to the operations they were run for
+fn record_kernel_times(
+    program: &Program,
+    profile: &mut Profile,
+    events: Vec<(String, bool, Vec<ocl::Event>)>,
+) -> Result<(), GraphError> {
+    program.finish()?;
+    for (op, forward, events) in events {
+        let mut elapsed = std::time::Duration::ZERO;
+        for event in events.iter() {
+            elapsed += program::kernel_time(event)?;
+        }
+        if forward {
+            profile.record_forward(op, elapsed);
+        } else {
+            profile.record_backward(op, elapsed);
+        }
+    }
+    Ok(())
+}
+
+/// Environment variable holding the index (Among `Device::all()`) of the device to use.
+pub const DEVICE_ENV_VAR: &str = "FEMTO_DEVICE";
+
+/// Picks the device at the given index of `Device::all()`, falling back to `FEMTO_DEVICE` when
+/// no index is given. By default, the first NVIDIA device (Or the first device at all) is used.
+pub fn select_device(index: Option<usize>) -> Result<Device, GraphError> {
+    let index = match index {
+        Some(index) => Some(index),
+        None => match std::env::var(DEVICE_ENV_VAR) {
+            Ok(v) => Some(
+                v.trim()
+                    .parse()
+                    .map_err(|_| GraphError::InvalidDevice(v.clone()))?,
+            ),
+            Err(_) => None,
+        },
+    };
+    let devices = Device::all()?;
+    let device = match index {
+        Some(index) => devices.get(index),
+        None => devices
+            .iter()
+            .find(|d| d.brand() == Brand::Nvidia)
+            .or(devices.first()),
+    };
+    device.cloned().ok_or(GraphError::DeviceNotFound {
+        index: index.unwrap_or(0),
+        available: devices.len(),
+    })
 }
 
 impl GpuGraph {
     pub fn new() -> Result<Self, GraphError> {
-        let device = Device::by_brand(Brand::Nvidia)?[0].clone();
+        Self::with_device(select_device(None)?)
+    }
+    pub fn with_device(device: Device) -> Result<Self, GraphError> {
         Ok(Self {
+            autotuner: Autotuner::load(&device),
             device,
             tensors: Default::default(),
             grads: Default::default(),
@@ -125,18 +433,174 @@ impl GpuGraph {
             names: Default::default(),
             params: Default::default(),
             optimizer_state: Default::default(),
+            quantized_moments: Default::default(),
+            host_optimizer_state: Default::default(),
+            moments: MomentStorage::F32,
             optimizer_step: 0,
             program: None,
+            precision: Default::default(),
+            loss_scale: 1.,
+            detect_anomaly: false,
+            profiling: false,
+            profile: Default::default(),
+            buffer_reuse: true,
+            staged: Default::default(),
+            spare: Default::default(),
+            fused: Default::default(),
+            hooks: Default::default(),
+            rng_key: rand::random(),
+            rng_pass: 0,
+            training_passes: 0,
         })
     }
+    pub fn device(&self) -> &Device {
+        &self.device
+    }
+    /// Seeds the random numbers of the kernels (e.g. dropout masks), which are seeded randomly
+    /// otherwise.
+    pub fn set_seed(&mut self, seed: u64) {
+        self.rng_key = seed;
+        self.training_passes = 0;
+    }
     pub fn get(&self, id: TensorId) -> Result<&GpuTensor, GraphError> {
         self.tensors.get(id).ok_or(GraphError::TensorNotFound(id))
     }
+    // Replaces computations by the planned ones, returning the number of removed computations
+    fn apply_fusions(&mut self, fusions: Vec<Fusion>) -> Result<usize, GraphError> {
+        let mut removed = 0;
+        for fusion in fusions {
+            for id in fusion.removed {
+                self.computations.remove(&id);
+                self.tensors[id].mirror = GeneralTensor::Float(Tensor::scalar(0.));
+                self.grads[id].mirror = GeneralTensor::Float(Tensor::scalar(0.));
+                self.fused.insert(id);
+                removed += 1;
+            }
+            let shapes = fusion
+                .computation
+                .inps
+                .iter()
+                .map(|id| self.tensors[*id].mirror.shape().to_vec())
+                .collect::<Vec<_>>();
+            let gpu_function = gpu_function(&*fusion.computation.func, fusion.out, &shapes)?;
+            self.computations.insert(
+                fusion.out,
+                GpuComputation {
+                    computation: fusion.computation,
+                    gpu_function,
+                },
+            );
+        }
+        self.program = None; // Needs recompile
+        Ok(removed)
+    }
+    fn check_grad_kept(&self, id: TensorId) -> Result<(), GraphError> {
+        match &self.program {
+            Some(p) if p.grad_plan.slots.contains_key(&id) => Err(GraphError::GradientNotKept(id)),
+            _ => Ok(()),
+        }
+    }
+    // The tensor taking the most memory, along with its size
+    fn largest_tensor(&self) -> String {
+        self.nodes()
+            .iter()
+            .map(|node| (node, mirror_bytes(&self.tensors[node.id].mirror)))
+            .max_by_key(|(_, bytes)| *bytes)
+            .map(|(node, bytes)| format!("{}, {} bytes", label(node), bytes))
+            .unwrap_or_default()
+    }
+    /// Total size of the buffers allocated on the device (Zero before the graph is compiled).
+    pub fn allocated_bytes(&self) -> usize {
+        let tensors = self
+            .tensors
+            .iter()
+            .chain(self.grads.iter())
+            .chain(self.optimizer_state.values())
+            .map(|t| t.allocated_bytes())
+            .chain(self.quantized_moments.values().map(|q| q.allocated_bytes()))
+            .sum::<usize>();
+        let shared = self.program.as_ref().map_or(0, |p| {
+            p.comp_buffers
+                .values()
+                .flatten()
+                .map(|b| b.size_in_bytes())
+                .chain(p.grad_pool.iter().map(|b| b.size_in_bytes()))
+                .sum()
+        });
+        tensors + shared + self.staging_bytes()
+    }
+    fn staging_bytes(&self) -> usize {
+        self.staged
+            .values()
+            .map(|s| s.buffer.size_in_bytes())
+            .chain(self.spare.values().map(|s| s.buffer.size_in_bytes()))
+            .sum()
+    }
+    /// Device memory used by each tensor (Its value, gradient and the scratch buffers of the
+    /// operation computing it), largest first, followed by the totals. Gradients sharing buffers
+    /// (See `Graph::set_buffer_reuse`) are only counted in the totals.
+    pub fn memory_report(&self) -> String {
+        const MIB: f64 = (1 << 20) as f64;
+        let mut rows = self
+            .nodes()
+            .iter()
+            .map(|node| {
+                let scratch = self
+                    .program
+                    .as_ref()
+                    .and_then(|p| p.comp_buffers.get(&node.id))
+                    .map_or(0, |buffs| buffs.iter().map(|b| b.size_in_bytes()).sum());
+                (
+                    self.tensors[node.id].allocated_bytes(),
+                    self.grads[node.id].allocated_bytes(),
+                    scratch,
+                    label(node),
+                )
+            })
+            .collect::<Vec<_>>();
+        rows.sort_by_key(|(value, grad, scratch, _)| std::cmp::Reverse(value + grad + scratch));
+
+        let mut out = format!("{:>10} {:>10} {:>10}  tensor\n", "value", "grad", "scratch");
+        for (value, grad, scratch, label) in rows {
+            out += &format!(
+                "{:>10.2} {:>10.2} {:>10.2}  {}\n",
+                value as f64 / MIB,
+                grad as f64 / MIB,
+                scratch as f64 / MIB,
+                label
+            );
+        }
+        let optimizer_state = self
+            .optimizer_state
+            .values()
+            .map(|t| t.allocated_bytes())
+            .chain(self.quantized_moments.values().map(|q| q.allocated_bytes()))
+            .sum::<usize>();
+        out += &format!("optimizer state: {:.2} MiB\n", optimizer_state as f64 / MIB);
+        let shared_grads = self.program.as_ref().map_or(0, |p| {
+            p.grad_pool.iter().map(|b| b.size_in_bytes()).sum::<usize>()
+        });
+        out += &format!("shared gradients: {:.2} MiB\n", shared_grads as f64 / MIB);
+        out += &format!(
+            "staged inputs: {:.2} MiB\n",
+            self.staging_bytes() as f64 / MIB
+        );
+        out += &format!("total: {:.2} MiB\n", self.allocated_bytes() as f64 / MIB);
+        out
+    }
     pub fn compile(&mut self) -> Result<(), GraphError> {
         if self.program.is_some() {
             return Ok(());
         }
-        let mut src = String::new();
+        let _span = info_span!("compile", device = self.device.name()).entered();
+        let timer = std::time::Instant::now();
+        // Staging buffers belong to the previous program, staged values are applied right away
+        self.spare.clear();
+        for (id, staged) in self.staged.drain() {
+            staged.wait()?;
+            self.tensors[id].mirror = staged.mirror;
+        }
+        let mut src = PHILOX_SOURCE.to_string();
         src += "
         __kernel void zeroize(__global float *buff, uint n) {
             uint id = get_global_id(0);
@@ -144,6 +608,26 @@ impl GpuGraph {
                 buff[id] = 0;
             }}
         }
+        __kernel void fill(__global float *buff, uint n, float value) {
+            uint id = get_global_id(0);
+            if(id < n) {
+                buff[id] = value;
+            }
+        }
+        __kernel void round_precision(__global float *buff, uint n, uint mode) {
+            uint id = get_global_id(0);
+            if(id < n) {
+                if(mode == 1) {
+                    half h;
+                    vstore_half_rte(buff[id], 0, &h);
+                    buff[id] = vload_half(0, &h);
+                } else if(mode == 2) {
+                    uint bits = as_uint(buff[id]);
+                    bits += 0x7fff + ((bits >> 16) & 1);
+                    buff[id] = as_float(bits & 0xffff0000);
+                }
+            }
+        }
         ";
         for comp in self.computations.values() {
             for func in comp.gpu_function.forward_funcs.iter() {
@@ -154,7 +638,7 @@ impl GpuGraph {
             }
         }
         src += "
-        __kernel void optimizer(__global float *param, __global float *grad, __global float *m, __global float *v,  float learning_rate, ulong step, ulong n) {
+        __kernel void optimizer(__global float *param, __global float *grad, __global float *m, __global float *v,  float learning_rate, float weight_decay, ulong step, ulong n) {
             uint id = get_global_id(0);
             param += id;
             grad += id;
@@ -162,7 +646,6 @@ impl GpuGraph {
             v += id;
             float beta1 = 0.9;
             float beta2 = 0.999;
-            float weight_decay = 0.01;
             if(id < n) {
                 *param = *param - *param * learning_rate * weight_decay;
                 *m = beta1 * (*m) + (1 - beta1) * (*grad);
@@ -174,63 +657,328 @@ impl GpuGraph {
             }
         }
         ";
-        let prog = Program::from_opencl(&self.device, &src)?;
+        src += &format!("#define MOMENT_BLOCK_SIZE {}\n", MOMENT_BLOCK_SIZE);
+        src += "
+        // Same as `optimizer` with 8-bit moments (See `QuantizedMoments`), a work-item per block.
+        // The new scales of a block are only known once all of its moments are updated, so they
+        // are computed twice.
+        __kernel void optimizer_8bit(__global float *param, __global float *grad, __global char *m, __global uchar *v, __global float *m_scale, __global float *v_scale, float learning_rate, float weight_decay, ulong step, ulong n) {
+            ulong block = get_global_id(0);
+            ulong start = block * MOMENT_BLOCK_SIZE;
+            if(start >= n) {
+                return;
+            }
+            ulong end = min(start + MOMENT_BLOCK_SIZE, n);
+            float beta1 = 0.9f;
+            float beta2 = 0.999f;
+            float old_m_scale = m_scale[block];
+            float old_v_scale = v_scale[block];
+            float new_m_scale = 0.0f;
+            float new_v_scale = 0.0f;
+            for(ulong i = start; i < end; i++) {
+                float q_m = m[i] / 127.0f;
+                float q_v = v[i] / 255.0f;
+                float m_i = beta1 * q_m * fabs(q_m) * old_m_scale + (1 - beta1) * grad[i];
+                float v_i = beta2 * q_v * q_v * q_v * q_v * old_v_scale + (1 - beta2) * grad[i] * grad[i];
+                new_m_scale = max(new_m_scale, fabs(m_i));
+                new_v_scale = max(new_v_scale, v_i);
+                param[i] = param[i] - param[i] * learning_rate * weight_decay;
+                float m_hat = m_i / (1.0f - pow(beta1, step + 1));
+                float v_hat = v_i / (1.0f - pow(beta2, step + 1));
+                param[i] = param[i] - m_hat * learning_rate / (sqrt(v_hat) + 1e-8f);
+            }
+            for(ulong i = start; i < end; i++) {
+                float q_m = m[i] / 127.0f;
+                float q_v = v[i] / 255.0f;
+                float m_i = beta1 * q_m * fabs(q_m) * old_m_scale + (1 - beta1) * grad[i];
+                float v_i = beta2 * q_v * q_v * q_v * q_v * old_v_scale + (1 - beta2) * grad[i] * grad[i];
+                m[i] = new_m_scale == 0.0f ? 0 : (char)(sign(m_i) * round(sqrt(fabs(m_i) / new_m_scale) * 127.0f));
+                v[i] = new_v_scale == 0.0f ? 0 : (uchar)min(ceil(sqrt(sqrt(v_i / new_v_scale)) * 255.0f), 255.0f);
+            }
+            m_scale[block] = new_m_scale;
+            v_scale[block] = new_v_scale;
+        }
+        ";
+        let prog = Program::from_opencl_cached(&self.device, &src)?;
+        info!(
+            elapsed_ms = timer.elapsed().as_millis() as u64,
+            "Compiled the kernels"
+        );
 
+        let mut tracker = AllocationTracker {
+            in_use: 0,
+            largest_tensor: self.largest_tensor(),
+        };
         let mut comp_buffers = HashMap::new();
 
         for (id, comp) in self.computations.iter() {
-            comp_buffers.entry(*id).or_insert_with(|| {
-                comp.gpu_function
-                    .shared_buffers
+            let buffs = comp
+                .gpu_function
+                .shared_buffers
+                .iter()
+                .map(|sb| {
+                    let (requested, buffer) = match sb {
+                        SharedBuffer::Random => return Ok(SharedArg::Random),
+                        SharedBuffer::Float(sz) => (
+                            sz * std::mem::size_of::<f32>(),
+                            prog.create_buffer::<f32>(*sz).map(GeneralBuffer::Float),
+                        ),
+                        SharedBuffer::Usize(sz) => (
+                            sz * std::mem::size_of::<usize>(),
+                            prog.create_buffer::<usize>(*sz).map(GeneralBuffer::Usize),
+                        ),
+                        SharedBuffer::Constant(vals) => (
+                            std::mem::size_of_val(vals.as_slice()),
+                            prog.create_buffer_from_slice::<f32>(vals)
+                                .map(GeneralBuffer::Float),
+                        ),
+                        SharedBuffer::ConstantBytes(vals) => (
+                            vals.len(),
+                            prog.create_buffer_from_slice::<u8>(vals)
+                                .map(GeneralBuffer::Bytes),
+                        ),
+                    };
+                    tracker
+                        .track(requested, buffer.map_err(GraphError::from))
+                        .map(SharedArg::Buffer)
+                })
+                .collect::<Result<Vec<_>, GraphError>>()?;
+            comp_buffers.insert(*id, buffs);
+        }
+
+        let (optimizer_state, quantized_moments) = self.alloc_moments(&prog, &mut tracker)?;
+
+        // Only the gradients of computed tensors are planned, the ones of parameters and inputs
+        // outlive backward passes
+        let grad_plan = if self.buffer_reuse {
+            plan_grad_buffers(
+                self.computations
                     .iter()
-                    .map(|sb| match sb {
-                        SharedBuffer::Float(sz) => prog
-                            .create_buffer::<f32>(*sz)
-                            .map(|b| GeneralBuffer::Float(b)),
-                        SharedBuffer::Usize(sz) => prog
-                            .create_buffer::<usize>(*sz)
-                            .map(|b| GeneralBuffer::Usize(b)),
-                    })
-                    .collect::<Result<Vec<_>, ProgramError>>()
-                    .unwrap()
-            });
+                    .map(|(id, c)| (*id, c.computation.inps.as_slice())),
+                &self
+                    .computations
+                    .keys()
+                    .map(|id| (*id, self.grads[*id].mirror.size()))
+                    .collect::<Vec<_>>(),
+            )
+        } else {
+            GradPlan::default()
+        };
+        let grad_pool = grad_plan
+            .slot_sizes
+            .iter()
+            .map(|sz| {
+                tracker.track(
+                    sz * std::mem::size_of::<f32>(),
+                    prog.create_buffer::<f32>(*sz)
+                        .map(GeneralBuffer::Float)
+                        .map_err(GraphError::from),
+                )
+            })
+            .collect::<Result<Vec<_>, GraphError>>()?;
+
+        for (id, (v, g)) in self
+            .tensors
+            .iter_mut()
+            .zip(self.grads.iter_mut())
+            .enumerate()
+        {
+            // Results of fused computations are never stored
+            if self.fused.contains(&id) {
+                continue;
+            }
+            v.buffer = Some(tracker.track(
+                mirror_bytes(&v.mirror),
+                GeneralBuffer::new(&prog, &v.mirror),
+            )?);
+            if !grad_plan.slots.contains_key(&id) {
+                g.buffer = Some(tracker.track(
+                    mirror_bytes(&g.mirror),
+                    GeneralBuffer::new(&prog, &g.mirror),
+                )?);
+            }
+            v.is_sync = true;
+            g.is_sync = true;
         }
+        self.program = Some(CompiledGraph {
+            program: prog,
+            comp_buffers,
+            grad_plan,
+            grad_pool,
+        });
+        self.optimizer_state = optimizer_state;
+        self.quantized_moments = quantized_moments;
+        info!(allocated_bytes = tracker.in_use, "Allocated the buffers");
+        Ok(())
+    }
 
-        let mut optimizer_state = HashMap::new();
+    // Steps of the optimizers without kernels, the parameters and their gradients being read back
+    fn optimize_on_host<O: Optimizer>(
+        &mut self,
+        optimizer: &O,
+        learning_rate: f32,
+    ) -> Result<(), GraphError> {
         for p in self.params.to_vec() {
+            self.fetch(p, true)?;
+        }
+        let pg = self
+            .tensors
+            .iter_mut()
+            .zip(self.grads.iter())
+            .enumerate()
+            .filter(|(id, _)| self.params.contains(id))
+            .map(|(id, (param, grad))| {
+                let name = self.names.get(id).ok_or(GraphError::TensorNotFound(id))?;
+                Ok((
+                    name.clone(),
+                    (param.mirror.as_float_mut()?, grad.mirror.as_float()?),
+                ))
+            })
+            .collect::<Result<HashMap<_, _>, GraphError>>()?;
+        self.host_optimizer_state.step = self.optimizer_step;
+        optimizer.step(pg, &mut self.host_optimizer_state, learning_rate)?;
+        for p in self.params.iter() {
+            let param = &mut self.tensors[*p];
+            param
+                .buffer
+                .as_mut()
+                .ok_or(GraphError::NotReady)?
+                .write_from(&param.mirror)?;
+        }
+        self.optimizer_step += 1;
+        Ok(())
+    }
+
+    // Zeroed moments of the parameters, in the format of `moments`
+    #[allow(clippy::type_complexity)]
+    fn alloc_moments(
+        &self,
+        prog: &Program,
+        tracker: &mut AllocationTracker,
+    ) -> Result<
+        (
+            HashMap<String, GpuTensor>,
+            HashMap<String, GpuQuantizedMoments>,
+        ),
+        GraphError,
+    > {
+        let mut optimizer_state = HashMap::new();
+        let mut quantized_moments = HashMap::new();
+        if self.moments == MomentStorage::Host {
+            return Ok((optimizer_state, quantized_moments));
+        }
+        for p in self.params.iter().copied() {
             let t = self.tensors.get(p).unwrap().mirror.shape().to_vec();
+            if self.moments == MomentStorage::Int8 {
+                let zeros = Tensor::<f32>::zeros(&t);
+                let q = QuantizedMoments::quantize(&zeros, &zeros);
+                let mut moments = GpuQuantizedMoments {
+                    shape: t,
+                    m: tracker.track(
+                        q.m.len(),
+                        prog.create_buffer(q.m.len()).map_err(GraphError::from),
+                    )?,
+                    v: tracker.track(
+                        q.v.len(),
+                        prog.create_buffer(q.v.len()).map_err(GraphError::from),
+                    )?,
+                    m_scale: tracker.track(
+                        std::mem::size_of_val(q.m_scale.as_slice()),
+                        prog.create_buffer(q.m_scale.len())
+                            .map_err(GraphError::from),
+                    )?,
+                    v_scale: tracker.track(
+                        std::mem::size_of_val(q.v_scale.as_slice()),
+                        prog.create_buffer(q.v_scale.len())
+                            .map_err(GraphError::from),
+                    )?,
+                };
+                moments.write(&q)?;
+                quantized_moments.insert(self.name_of(p)?.to_string(), moments);
+                continue;
+            }
             let m_val = GeneralTensor::Float(Tensor::zeros(&t));
             let v_val = GeneralTensor::Float(Tensor::zeros(&t));
             let m = GpuTensor {
-                buffer: Some(GeneralBuffer::new(&prog, &m_val)?),
+                buffer: Some(
+                    tracker.track(mirror_bytes(&m_val), GeneralBuffer::new(prog, &m_val))?,
+                ),
                 mirror: m_val,
                 is_sync: true,
             };
             let v = GpuTensor {
-                buffer: Some(GeneralBuffer::new(&prog, &v_val)?),
+                buffer: Some(
+                    tracker.track(mirror_bytes(&v_val), GeneralBuffer::new(prog, &v_val))?,
+                ),
                 mirror: v_val,
                 is_sync: true,
             };
             optimizer_state.insert(format!("{}_m", self.name_of(p)?), m);
             optimizer_state.insert(format!("{}_v", self.name_of(p)?), v);
         }
-        for (v, g) in self.tensors.iter_mut().zip(self.grads.iter_mut()) {
-            v.buffer = Some(GeneralBuffer::new(&prog, &v.mirror)?);
-            g.buffer = Some(GeneralBuffer::new(&prog, &g.mirror)?);
-            v.is_sync = true;
-            g.is_sync = true;
+        Ok((optimizer_state, quantized_moments))
+    }
+}
+
+// Reads a float buffer back to the host, telling whether it contains NaN/Inf values
+fn zero_buffer(
+    program: &CompiledGraph,
+    buffer: &GeneralBuffer,
+    size: usize,
+) -> Result<(), GraphError> {
+    let local_work_size = 32;
+    let global_work_size = size + (local_work_size - (size % local_work_size)) % local_work_size;
+    let mut kern = program
+        .program
+        .create_kernel("zeroize", global_work_size, local_work_size);
+    kern = kern.arg(buffer);
+    kern = kern.arg(size as u32);
+    kern.run()?;
+    Ok(())
+}
+
+fn has_anomaly(buffer: &GeneralBuffer, size: usize) -> Result<bool, GraphError> {
+    match buffer {
+        GeneralBuffer::Float(b) => {
+            let mut blob = vec![0.; size];
+            b.read_into(&mut blob)?;
+            Ok(blob.iter().any(|f| !f.is_finite()))
         }
-        self.program = Some(CompiledGraph {
-            program: prog,
-            comp_buffers,
-        });
-        self.optimizer_state = optimizer_state;
-        Ok(())
+        _ => Ok(false),
     }
 }
 
+// Rounds the values of a buffer to the given precision, weights are not rounded on GPUs and the
+// rounding only applies to activations and gradients.
+fn round_buffer(
+    program: &CompiledGraph,
+    buffer: &GeneralBuffer,
+    size: usize,
+    precision: Precision,
+) -> Result<(), GraphError> {
+    let mode: u32 = match precision {
+        Precision::F32 => {
+            return Ok(());
+        }
+        Precision::F16 => 1,
+        Precision::Bf16 => 2,
+    };
+    let local_work_size = 32;
+    let global_work_size = size + (local_work_size - (size % local_work_size)) % local_work_size;
+    let mut kern =
+        program
+            .program
+            .create_kernel("round_precision", global_work_size, local_work_size);
+    kern = kern.arg(buffer);
+    kern = kern.arg(size as u32);
+    kern = kern.arg(mode);
+    kern.run()?;
+    Ok(())
+}
+
 impl GpuGraph {
     pub fn fetch_grad(&mut self, tensor_id: TensorId) -> Result<&Tensor<f32>, GraphError> {
+        self.check_grad_kept(tensor_id)?;
         let gt = self.grads.get_mut(tensor_id).unwrap();
         if !gt.is_sync {
             gt.buffer
@@ -265,6 +1013,17 @@ impl Graph for GpuGraph {
         tensor: &T,
     ) -> Result<(), GraphError> {
         self.compile()?;
+        // A staged value not swapped in yet is superseded
+        if let Some(staged) = self.staged.remove(&tensor_id) {
+            staged.wait()?;
+            self.spare.insert(
+                tensor_id,
+                SpareBuffer {
+                    buffer: staged.buffer,
+                    released: None,
+                },
+            );
+        }
         let gt = self.tensors.get_mut(tensor_id).unwrap();
         gt.mirror = GeneralTensor::Usize(tensor.view().into());
         gt.buffer
@@ -312,12 +1071,62 @@ impl Graph for GpuGraph {
         gt.is_sync = true;
         Ok(())
     }
+    fn stage_usize<T: TensorOps<usize>>(
+        &mut self,
+        tensor_id: TensorId,
+        tensor: &T,
+    ) -> Result<(), GraphError> {
+        self.compile()?;
+        let program = &self.program.as_ref().ok_or(GraphError::NotReady)?.program;
+        let gt = self
+            .tensors
+            .get(tensor_id)
+            .ok_or(GraphError::TensorNotFound(tensor_id))?;
+        let (mut buffer, released) = match self.staged.remove(&tensor_id) {
+            Some(staged) => {
+                staged.wait()?;
+                (staged.buffer, None)
+            }
+            None => match self.spare.remove(&tensor_id) {
+                Some(spare) => (spare.buffer, spare.released),
+                None => {
+                    let mut tracker = AllocationTracker {
+                        in_use: self.allocated_bytes(),
+                        largest_tensor: self.largest_tensor(),
+                    };
+                    let buffer = tracker.track(
+                        mirror_bytes(&gt.mirror),
+                        GeneralBuffer::new(program, &gt.mirror),
+                    )?;
+                    (buffer, None)
+                }
+            },
+        };
+        let mirror = GeneralTensor::Usize(tensor.view().into());
+        let done = match (&mut buffer, &mirror) {
+            // Safe since `mirror` is kept along with the event, until the upload is done
+            (GeneralBuffer::Usize(b), GeneralTensor::Usize(t)) => unsafe {
+                b.write_async(t.blob(), program.transfer_queue(), released.as_ref())?
+            },
+            _ => return Err(GraphError::IncompatibleTypes),
+        };
+        self.staged.insert(
+            tensor_id,
+            StagedUpload {
+                buffer,
+                mirror,
+                done,
+            },
+        );
+        Ok(())
+    }
     fn load_grad<T: TensorOps<f32>>(
         &mut self,
         tensor_id: TensorId,
         tensor: &T,
     ) -> Result<(), GraphError> {
         self.compile()?;
+        self.check_grad_kept(tensor_id)?;
         let gt = self.grads.get_mut(tensor_id).unwrap();
         gt.mirror = GeneralTensor::Float(tensor.view().into());
         gt.buffer
@@ -329,24 +1138,25 @@ impl Graph for GpuGraph {
     }
     fn zero_grad(&mut self) -> Result<(), GraphError> {
         self.compile()?;
-        let program = self.program.as_mut().ok_or(GraphError::NotReady)?;
-        for gt in self.grads.iter_mut() {
-            let buffer = gt.buffer.as_ref().ok_or(GraphError::NotReady)?;
+        let program = self.program.as_ref().ok_or(GraphError::NotReady)?;
+        for (id, gt) in self.grads.iter_mut().enumerate() {
             gt.is_sync = false;
-            let local_work_size = 32;
-            let mut global_work_size = gt.mirror.size();
-            global_work_size +=
-                (local_work_size - (global_work_size % local_work_size)) % local_work_size;
-            let mut kern =
-                program
-                    .program
-                    .create_kernel("zeroize", global_work_size, local_work_size);
-            kern = kern.arg(buffer);
-            kern = kern.arg(gt.mirror.size() as u32);
-            kern.run()?;
+            // Shared gradients are cleared by backward passes, when their lifetimes start
+            if program.grad_plan.slots.contains_key(&id) || self.fused.contains(&id) {
+                continue;
+            }
+            let buffer = gt.buffer.as_ref().ok_or(GraphError::NotReady)?;
+            zero_buffer(program, buffer, gt.mirror.size())?;
         }
         Ok(())
     }
+    fn set_name(&mut self, id: TensorId, name: String) -> Result<(), GraphError> {
+        *self
+            .names
+            .get_mut(id)
+            .ok_or(GraphError::TensorNotFound(id))? = name;
+        Ok(())
+    }
     fn name_of(&self, id: TensorId) -> Result<&String, GraphError> {
         self.names.get(id).ok_or(GraphError::TensorNotFound(id))
     }
@@ -358,23 +1168,85 @@ impl Graph for GpuGraph {
         Ok(&gt.mirror)
     }
     fn get_grad(&self, id: TensorId) -> Result<&Tensor<f32>, GraphError> {
+        self.check_grad_kept(id)?;
         let gt = self.grads.get(id).unwrap();
         if !gt.is_sync {
             return Err(GraphError::NotReady);
         }
         Ok(gt.mirror.as_float()?)
     }
-    fn backward_all(&mut self, id: TensorId, _limit: Option<usize>) -> Result<f32, GraphError> {
+    fn backward_all(
+        &mut self,
+        id: TensorId,
+        limit: Option<usize>,
+        params_only: bool,
+    ) -> Result<f32, GraphError> {
         self.compile()?;
+        let (rng_key, rng_pass) = (self.rng_key, self.rng_pass);
 
-        self.fetch(id, false)?;
-        let output = self.get(id)?.mirror.as_float()?.clone();
-        let mean_coeff = 1. / output.size() as f32;
-        self.load_grad(id, &Tensor::constant(output.shape(), mean_coeff))?;
+        // The whole pass is enqueued before blocking on the loss, which is only read at the end
+        let size = self.get(id)?.mirror.size();
+        let mean_coeff = self.loss_scale / size as f32;
+        {
+            let program = self.program.as_ref().ok_or(GraphError::NotReady)?;
+            let live_from_start = program.grad_plan.clear_before.get(&None);
+            // A shared gradient is only alive from the start of the pass if nothing consumes it
+            if program.grad_plan.slots.contains_key(&id)
+                && !live_from_start.is_some_and(|ids| ids.contains(&id))
+            {
+                return Err(GraphError::GradientNotKept(id));
+            }
+            for other in live_from_start.into_iter().flatten().filter(|i| **i != id) {
+                let buffer = grad_buffer(&self.grads, program, *other)?;
+                zero_buffer(program, buffer, self.grads[*other].mirror.size())?;
+            }
+
+            let local_work_size = 32;
+            let global_work_size =
+                size + (local_work_size - (size % local_work_size)) % local_work_size;
+            let mut kern = program
+                .program
+                .create_kernel("fill", global_work_size, local_work_size);
+            kern = kern.arg(grad_buffer(&self.grads, program, id)?);
+            kern = kern.arg(size as u32);
+            kern = kern.arg(mean_coeff);
+            kern.run()?;
+            self.grads
+                .get_mut(id)
+                .ok_or(GraphError::TensorNotFound(id))?
+                .is_sync = false;
+        }
+
+        let dependents = params_only.then(|| {
+            param_dependents(
+                &self.params,
+                self.computations
+                    .iter()
+                    .map(|(id, c)| (*id, c.computation.inps.as_slice())),
+            )
+        });
 
         let program = self.program.as_mut().ok_or(GraphError::NotReady)?;
+        let mut timings = Vec::new();
 
-        for (id, c) in self.computations.clone().iter().rev() {
+        for (i, (id, c)) in self.computations.clone().iter().rev().enumerate() {
+            if let Some(limit) = limit {
+                if i >= limit {
+                    break;
+                }
+            }
+            // Even if the computation is skipped, the gradients it would write must be cleared
+            if let Some(starting) = program.grad_plan.clear_before.get(&Some(*id)) {
+                for inp in starting.iter() {
+                    let buffer = grad_buffer(&self.grads, program, *inp)?;
+                    zero_buffer(program, buffer, self.grads[*inp].mirror.size())?;
+                }
+            }
+            if let Some(dependents) = &dependents {
+                if !dependents.contains(id) {
+                    continue;
+                }
+            }
             let inps = c
                 .computation
                 .inps
@@ -390,38 +1262,59 @@ impl Graph for GpuGraph {
                 .computation
                 .inps
                 .iter()
-                .map(|id| self.grads[*id].buffer.as_ref().ok_or(GraphError::NotReady))
+                .map(|id| grad_buffer(&self.grads, program, *id))
                 .collect::<Result<Vec<_>, GraphError>>()?;
             let out = self.tensors[*id]
                 .buffer
                 .as_ref()
                 .ok_or(GraphError::NotReady)?;
-            let out_grad = self.grads[*id]
-                .buffer
-                .as_ref()
-                .ok_or(GraphError::NotReady)?;
+            let out_grad = grad_buffer(&self.grads, program, *id)?;
 
             let buffs = program.comp_buffers.get(id).ok_or(GraphError::NotReady)?;
 
+            let mut events = Vec::new();
             for k in c.gpu_function.backward_funcs.iter() {
-                let mut global_work_size = k.global_work_size;
-                global_work_size += (k.local_work_size - (global_work_size % k.local_work_size))
-                    % k.local_work_size;
-                let mut kern = program.program.create_kernel(
-                    &k.kernel_name,
-                    global_work_size,
-                    k.local_work_size,
-                );
-                kern = kern.arg(out);
-                kern = kern.arg(out_grad);
-                for buff in buffs.iter() {
-                    kern = kern.arg(buff);
-                }
-                for (inp, grad) in inps.iter().cloned().zip(inp_grads.iter().cloned()) {
-                    kern = kern.arg(inp);
-                    kern = kern.arg(grad);
+                run_kernel(
+                    &program.program,
+                    &mut self.autotuner,
+                    k,
+                    k.global_work_size,
+                    self.profiling.then_some(&mut events),
+                    |mut kern| {
+                        kern = kern.arg(out);
+                        kern = kern.arg(out_grad);
+                        for buff in buffs.iter() {
+                            kern = buff.push(kern, rng_key, rng_pass);
+                        }
+                        for (inp, grad) in inps.iter().cloned().zip(inp_grads.iter().cloned()) {
+                            kern = kern.arg(inp);
+                            kern = kern.arg(grad);
+                        }
+                        Ok(kern)
+                    },
+                )?;
+            }
+            if self.profiling {
+                timings.push((c.computation.func.op_name(), false, events));
+            }
+
+            for (inp, grad) in c.computation.inps.iter().zip(inp_grads.iter()) {
+                if self.tensors[*inp].mirror.as_float().is_ok() {
+                    round_buffer(
+                        program,
+                        grad,
+                        self.grads[*inp].mirror.size(),
+                        self.precision,
+                    )?;
+                    if self.detect_anomaly && has_anomaly(grad, self.grads[*inp].mirror.size())? {
+                        return Err(GraphError::NumericalAnomaly {
+                            tensor_id: *inp,
+                            tensor_name: display_name(&self.names, *inp),
+                            op_name: format!("{} (backward)", c.computation.func.op_name()),
+                            step: self.optimizer_step,
+                        });
+                    }
                 }
-                kern.run()?;
             }
 
             for inp in c.computation.inps.iter() {
@@ -429,11 +1322,40 @@ impl Graph for GpuGraph {
             }
         }
 
-        Ok(output.mean())
+        if self.profiling {
+            record_kernel_times(&program.program, &mut self.profile, timings)?;
+        }
+        self.autotuner.save();
+        self.fetch(id, false)?;
+        Ok(self.get(id)?.mirror.as_float()?.mean())
     }
     fn forward(&mut self, training: bool) -> Result<(), GraphError> {
         self.compile()?;
+        // Every training pass draws new random numbers, backward passes reuse them
+        self.rng_pass = if training {
+            self.training_passes = self.training_passes.wrapping_add(1).max(1);
+            self.training_passes
+        } else {
+            0
+        };
+        let (rng_key, rng_pass) = (self.rng_key, self.rng_pass);
         let program = self.program.as_mut().ok_or(GraphError::NotReady)?;
+        // Uploads were started during the previous step, they are usually done by now
+        for (id, staged) in std::mem::take(&mut self.staged) {
+            staged.wait()?;
+            let gt = self
+                .tensors
+                .get_mut(id)
+                .ok_or(GraphError::TensorNotFound(id))?;
+            let previous = gt.buffer.replace(staged.buffer);
+            gt.mirror = staged.mirror;
+            gt.is_sync = true;
+            if let Some(buffer) = previous {
+                let released = Some(program.program.marker()?);
+                self.spare.insert(id, SpareBuffer { buffer, released });
+            }
+        }
+        let mut timings = Vec::new();
         for (out, c) in self.computations.iter() {
             let inps = c
                 .computation
@@ -449,39 +1371,78 @@ impl Graph for GpuGraph {
 
             let buffs = program.comp_buffers.get(out).ok_or(GraphError::NotReady)?;
 
+            let mut events = Vec::new();
             for func in c.gpu_function.forward_funcs.iter() {
-                let local_work_size = func.local_work_size;
-                let mut global_work_size = if training {
+                let global_work_size = if training {
                     func.global_work_size
                 } else {
                     func.global_work_size / batches
                 };
-                global_work_size +=
-                    (local_work_size - (global_work_size % local_work_size)) % local_work_size;
-
-                let mut kern = program.program.create_kernel(
-                    &func.kernel_name,
+                run_kernel(
+                    &program.program,
+                    &mut self.autotuner,
+                    func,
                     global_work_size,
-                    local_work_size,
-                );
-                kern = kern.arg(out_tensor.buffer.as_ref().ok_or(GraphError::NotReady)?);
-                for buff in buffs.iter() {
-                    kern = kern.arg(buff);
-                }
-                for inp in inps.iter() {
-                    kern = kern.arg(inp.buffer.as_ref().ok_or(GraphError::NotReady)?);
-                }
-                kern.run()?;
+                    self.profiling.then_some(&mut events),
+                    |mut kern| {
+                        kern = kern.arg(out_tensor.buffer.as_ref().ok_or(GraphError::NotReady)?);
+                        for buff in buffs.iter() {
+                            kern = buff.push(kern, rng_key, rng_pass);
+                        }
+                        for inp in inps.iter() {
+                            kern = kern.arg(inp.buffer.as_ref().ok_or(GraphError::NotReady)?);
+                        }
+                        Ok(kern)
+                    },
+                )?;
+            }
+            if self.profiling {
+                timings.push((c.computation.func.op_name(), true, events));
+            }
+
+            if out_tensor.mirror.as_float().is_ok() {
+                round_buffer(
+                    program,
+                    out_tensor.buffer.as_ref().ok_or(GraphError::NotReady)?,
+                    out_tensor.mirror.size(),
+                    self.precision,
+                )?;
+            }
+            if self.detect_anomaly
+                && has_anomaly(
+                    out_tensor.buffer.as_ref().ok_or(GraphError::NotReady)?,
+                    out_tensor.mirror.size(),
+                )?
+            {
+                return Err(GraphError::NumericalAnomaly {
+                    tensor_id: *out,
+                    tensor_name: display_name(&self.names, *out),
+                    op_name: c.computation.func.op_name(),
+                    step: self.optimizer_step,
+                });
             }
 
             let gt = self.tensors.get_mut(*out).unwrap();
             gt.is_sync = false;
+            if let Some(hooks) = self.hooks.get(out) {
+                let buffer = gt.buffer.as_mut().ok_or(GraphError::NotReady)?;
+                buffer.read_into(&mut gt.mirror)?;
+                for hook in hooks.iter() {
+                    hook(gt.mirror.as_float_mut()?);
+                }
+                buffer.write_from(&gt.mirror)?;
+                gt.is_sync = true;
+            }
             /*gt.buffer
                 .as_mut()
                 .ok_or(GraphError::NotReady)?
                 .read_into(&mut gt.mirror)?;
             println!("{}: {} ({})", &format!("{:?}", c.computation.func)[..3], beg.elapsed().as_millis(), c.forward.global_work_size);*/
         }
+        if self.profiling {
+            record_kernel_times(&program.program, &mut self.profile, timings)?;
+        }
+        self.autotuner.save();
         Ok(())
     }
     fn call(
@@ -499,8 +1460,8 @@ impl Graph for GpuGraph {
             .into_iter()
             .unzip();
         let out = f.run(&tensors, false)?;
+        let gpu_function = gpu_function(&*f, self.tensors.len(), &shapes)?;
         let child = self.alloc(out, false, "".into())?;
-        let gpu_function = f.gpu_impl(child, &shapes);
 
         self.computations.insert(
             child,
@@ -515,25 +1476,159 @@ impl Graph for GpuGraph {
         self.program = None; // Needs recompile
         Ok(child)
     }
+    fn num_computations(&self) -> usize {
+        self.computations.len()
+    }
+    fn add_hook(&mut self, id: TensorId, hook: Hook) -> Result<(), GraphError> {
+        if !self.computations.contains_key(&id) {
+            return Err(GraphError::NotComputed(id));
+        }
+        self.hooks.entry(id).or_default().push(hook);
+        Ok(())
+    }
+    fn clear_hooks(&mut self) {
+        self.hooks.clear();
+    }
+    fn replace(&mut self, id: TensorId, mut f: Box<dyn Function>) -> Result<(), GraphError> {
+        let inps = self
+            .computations
+            .get(&id)
+            .ok_or(GraphError::NotComputed(id))?
+            .computation
+            .inps
+            .clone();
+        let (tensors, shapes): (Vec<&GeneralTensor>, Vec<Vec<usize>>) = inps
+            .iter()
+            .map(|id| {
+                self.get(*id)
+                    .map(|gt| (&gt.mirror, gt.mirror.shape().to_vec()))
+            })
+            .collect::<Result<Vec<_>, GraphError>>()?
+            .into_iter()
+            .unzip();
+        let out = f.run(&tensors, false)?;
+        if out.shape() != self.tensors[id].mirror.shape() {
+            return Err(TensorError::UnexpectedShape.into());
+        }
+        let gpu_function = gpu_function(&*f, id, &shapes)?;
+        self.tensors[id].mirror = GeneralTensor::Float(out);
+        self.computations.insert(
+            id,
+            GpuComputation {
+                computation: Computation { func: f, inps },
+                gpu_function,
+            },
+        );
+        self.program = None; // Needs recompile
+        Ok(())
+    }
+    fn fuse_elementwise(&mut self, keep: &[TensorId]) -> Result<usize, GraphError> {
+        let fusions = plan_fusions(
+            self.computations
+                .iter()
+                .map(|(id, c)| (*id, &c.computation)),
+            keep,
+            |id| self.tensors[id].mirror.size(),
+        );
+        self.apply_fusions(fusions)
+    }
+    fn fold_transposes(&mut self, keep: &[TensorId]) -> Result<usize, GraphError> {
+        let folds = plan_transpose_folds(
+            self.computations
+                .iter()
+                .map(|(id, c)| (*id, &c.computation)),
+            keep,
+        );
+        self.apply_fusions(folds)
+    }
+    fn nodes(&self) -> Vec<NodeInfo> {
+        self.tensors
+            .iter()
+            .enumerate()
+            .filter(|(id, _)| !self.fused.contains(id))
+            .map(|(id, t)| {
+                let comp = self.computations.get(&id).map(|c| &c.computation);
+                NodeInfo {
+                    id,
+                    name: self.names[id].clone(),
+                    shape: t.mirror.shape().to_vec(),
+                    is_param: self.params.contains(&id),
+                    op: comp.map(|c| c.func.op_name()),
+                    inputs: comp.map(|c| c.inps.clone()).unwrap_or_default(),
+                }
+            })
+            .collect()
+    }
+    fn set_precision(&mut self, precision: Precision) -> Result<(), GraphError> {
+        self.precision = precision;
+        Ok(())
+    }
+    fn set_loss_scale(&mut self, scale: f32) -> Result<(), GraphError> {
+        self.loss_scale = scale;
+        Ok(())
+    }
+    fn set_detect_anomaly(&mut self, enabled: bool) -> Result<(), GraphError> {
+        self.detect_anomaly = enabled;
+        Ok(())
+    }
+    fn set_profiling(&mut self, enabled: bool) -> Result<(), GraphError> {
+        self.profiling = enabled;
+        Ok(())
+    }
+    fn take_profile(&mut self) -> Profile {
+        std::mem::take(&mut self.profile)
+    }
+    fn set_buffer_reuse(&mut self, enabled: bool) -> Result<(), GraphError> {
+        if self.program.is_some() && enabled != self.buffer_reuse {
+            return Err(GraphError::AlreadyCompiled);
+        }
+        self.buffer_reuse = enabled;
+        Ok(())
+    }
     fn optimize<O: Optimizer>(
         &mut self,
-        _optimizer: &O, // TODO: Generate OpenCL code with this
+        optimizer: &O, // TODO: Generate OpenCL code with this
         learning_rate: f32,
     ) -> Result<(), GraphError> {
         self.compile()?;
 
+        // Only whether the optimizer has kernels matters here
+        let moments = if optimizer.gpu_impl(&HashMap::new()).is_none() {
+            MomentStorage::Host
+        } else if optimizer.moments_8bit() {
+            MomentStorage::Int8
+        } else {
+            MomentStorage::F32
+        };
+        if moments != self.moments {
+            // The moments change format, keeping their values
+            let state = self.get_optimizer_state()?;
+            self.optimizer_state.clear();
+            self.quantized_moments.clear();
+            self.host_optimizer_state = Default::default();
+            self.moments = moments;
+            let mut tracker = AllocationTracker {
+                in_use: self.allocated_bytes(),
+                largest_tensor: self.largest_tensor(),
+            };
+            let program = self.program.as_ref().ok_or(GraphError::NotReady)?;
+            let (optimizer_state, quantized_moments) =
+                self.alloc_moments(&program.program, &mut tracker)?;
+            self.optimizer_state = optimizer_state;
+            self.quantized_moments = quantized_moments;
+            self.set_optimizer_state(&state)?;
+        }
+        if self.moments == MomentStorage::Host {
+            return self.optimize_on_host(optimizer, learning_rate);
+        }
+
         for p in self.params.iter() {
             self.tensors.get_mut(*p).unwrap().is_sync = false;
         }
 
         let program = self.program.as_mut().ok_or(GraphError::NotReady)?;
 
-        let tens: Vec<(
-            &GeneralBuffer,
-            &GeneralBuffer,
-            &GeneralBuffer,
-            &GeneralBuffer,
-        )> = self
+        let tens: Vec<(ParamSettings, &GeneralBuffer, &GeneralBuffer, &String)> = self
             .tensors
             .iter_mut()
             .enumerate()
@@ -541,41 +1636,52 @@ impl Graph for GpuGraph {
             .map(|(id, params)| {
                 let name = self.names.get(id).ok_or(GraphError::TensorNotFound(id))?;
                 let grad = self.grads.get(id).ok_or(GraphError::TensorNotFound(id))?;
-                let m = self
-                    .optimizer_state
-                    .get(&format!("{}_m", name))
-                    .unwrap()
-                    .buffer
-                    .as_ref()
-                    .unwrap();
-                let v = self
-                    .optimizer_state
-                    .get(&format!("{}_v", name))
-                    .unwrap()
-                    .buffer
-                    .as_ref()
-                    .unwrap();
                 Ok((
+                    optimizer.param_settings(name),
                     params.buffer.as_ref().ok_or(GraphError::NotReady)?,
                     grad.buffer.as_ref().ok_or(GraphError::NotReady)?,
-                    m,
-                    v,
+                    name,
                 ))
             })
             .collect::<Result<Vec<_>, GraphError>>()?;
-        for (param, grad, m, v) in tens {
+        for (settings, param, grad, name) in tens {
             let works = param.length();
             let local_work_size = 32;
-            let global_work_size =
-                works + ((local_work_size - (works % local_work_size)) % local_work_size);
-            let mut kern = program
-                .program
-                .create_kernel("optimizer", global_work_size, 32);
-            kern = kern.arg(param);
-            kern = kern.arg(grad);
-            kern = kern.arg(m);
-            kern = kern.arg(v);
-            kern = kern.arg(learning_rate);
+            let mut kern = match self.quantized_moments.get(name) {
+                Some(moments) => {
+                    let blocks = works.div_ceil(MOMENT_BLOCK_SIZE);
+                    let global_work_size =
+                        blocks + ((local_work_size - (blocks % local_work_size)) % local_work_size);
+                    program
+                        .program
+                        .create_kernel("optimizer_8bit", global_work_size, 32)
+                        .arg(param)
+                        .arg(grad)
+                        .arg(&moments.m)
+                        .arg(&moments.v)
+                        .arg(&moments.m_scale)
+                        .arg(&moments.v_scale)
+                }
+                None => {
+                    let moment = |key: String| {
+                        self.optimizer_state
+                            .get(&key)
+                            .and_then(|m| m.buffer.as_ref())
+                            .ok_or(GraphError::NotReady)
+                    };
+                    let global_work_size =
+                        works + ((local_work_size - (works % local_work_size)) % local_work_size);
+                    program
+                        .program
+                        .create_kernel("optimizer", global_work_size, 32)
+                        .arg(param)
+                        .arg(grad)
+                        .arg(moment(format!("{}_m", name))?)
+                        .arg(moment(format!("{}_v", name))?)
+                }
+            };
+            kern = kern.arg(learning_rate * settings.lr_scale);
+            kern = kern.arg(settings.weight_decay);
             kern = kern.arg(self.optimizer_step);
             kern = kern.arg(works);
             kern.run()?;
@@ -594,6 +1700,7 @@ impl Graph for GpuGraph {
             gt.is_sync = true;
         }
         if grad {
+            self.check_grad_kept(tensor_id)?;
             let gg = self.grads.get_mut(tensor_id).unwrap();
             if !gg.is_sync {
                 gg.buffer
@@ -608,13 +1715,31 @@ impl Graph for GpuGraph {
     fn params(&self) -> &[TensorId] {
         &self.params
     }
+    fn freeze(&mut self, id: TensorId) -> Result<(), GraphError> {
+        self.name_of(id)?;
+        self.params.retain(|p| *p != id);
+        self.program = None; // Optimizer buffers need to be rebuilt
+        Ok(())
+    }
     fn optimizer_step(&self) -> usize {
         self.optimizer_step
     }
     fn get_optimizer_state(&self) -> Result<OptimizerState, GraphError> {
+        if self.moments == MomentStorage::Host {
+            return Ok(OptimizerState {
+                step: self.optimizer_step,
+                ..self.host_optimizer_state.dequantized()?
+            });
+        }
         let mut result = HashMap::new();
         for p in self.params.iter() {
             let name = self.name_of(*p)?;
+            if let Some(moments) = self.quantized_moments.get(name) {
+                let (m, v) = moments.read()?.dequantize()?;
+                result.insert(format!("{}_m", name), m);
+                result.insert(format!("{}_v", name), v);
+                continue;
+            }
             let key_m = format!("{}_m", name);
             let key_v = format!("{}_v", name);
             let m = self.optimizer_state.get(&key_m).unwrap();
@@ -629,38 +1754,47 @@ impl Graph for GpuGraph {
         Ok(OptimizerState {
             step: self.optimizer_step,
             state: result,
+            ..Default::default()
         })
     }
     fn set_optimizer_state(&mut self, state: &OptimizerState) -> Result<(), GraphError> {
+        let dequantized;
+        let state = if state.quantized.is_empty() {
+            state
+        } else {
+            dequantized = state.dequantized()?;
+            &dequantized
+        };
         self.optimizer_step = state.step;
+        if self.moments == MomentStorage::Host {
+            self.host_optimizer_state = state.clone();
+            return Ok(());
+        }
         for p in self.params.iter() {
-            let name = self.name_of(*p)?;
-            let key_m = format!("{}_m", name);
-            let key_v = format!("{}_v", name);
-
-            if let Some(m_content) = state.state.get(&key_m).cloned() {
-                let m = self
-                    .optimizer_state
-                    .get_mut(&key_m)
-                    .unwrap()
-                    .buffer
-                    .as_mut()
-                    .unwrap();
-                let m_val = GeneralTensor::Float(m_content);
-                m.write_from(&m_val)?;
-                drop(m);
+            let name = self.name_of(*p)?.to_string();
+            if let Some(moments) = self.quantized_moments.get_mut(&name) {
+                let zeros = Tensor::zeros(&moments.shape);
+                let m = state.state.get(&format!("{}_m", name)).unwrap_or(&zeros);
+                let v = state.state.get(&format!("{}_v", name)).unwrap_or(&zeros);
+                moments.write(&QuantizedMoments::quantize(m, v))?;
+                continue;
             }
-
-            if let Some(v_content) = state.state.get(&key_v).cloned() {
-                let v = self
-                    .optimizer_state
-                    .get_mut(&key_v)
-                    .unwrap()
+            // Moments missing from the state (e.g. of a weights-only checkpoint) start over at
+            // zero, as they do on the CPU
+            for key in [format!("{}_m", name), format!("{}_v", name)] {
+                let Some(moment) = self.optimizer_state.get_mut(&key) else {
+                    continue;
+                };
+                let content = state
+                    .state
+                    .get(&key)
+                    .cloned()
+                    .unwrap_or_else(|| Tensor::zeros(moment.mirror.shape()));
+                moment
                     .buffer
                     .as_mut()
-                    .unwrap();
-                let v_val = GeneralTensor::Float(v_content);
-                v.write_from(&v_val)?;
+                    .unwrap()
+                    .write_from(&GeneralTensor::Float(content))?;
             }
         }
         Ok(())
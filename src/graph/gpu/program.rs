@@ -1,7 +1,12 @@
+use crate::checkpoint::fnv1a;
+use tracing::debug;
+
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Brand {
     Amd,
     Nvidia,
+    /// Any other OpenCL platform (Intel, POCL, Apple...)
+    Other,
 }
 
 impl Brand {
@@ -9,9 +14,18 @@ impl Brand {
         match self {
             Brand::Nvidia => "NVIDIA CUDA",
             Brand::Amd => "AMD Accelerated Parallel Processing",
+            Brand::Other => "",
         }
     }
 
+    pub fn of_platform(platform_name: &str) -> Brand {
+        [Brand::Nvidia, Brand::Amd]
+            .into_iter()
+            .find(|b| b.platform_name() == platform_name)
+            .unwrap_or(Brand::Other)
+    }
+
+    // PCI bus ids are only available through NVIDIA's extension, other devices get 0
     pub fn get_bus_id(&self, d: ocl::Device) -> ocl::Result<u32> {
         match self {
             Brand::Nvidia => {
@@ -19,7 +33,7 @@ impl Brand {
                 let result = d.info_raw(CL_DEVICE_PCI_BUS_ID_NV)?;
                 Ok(u32::from_le_bytes(result[..].try_into().unwrap()))
             }
-            Brand::Amd => panic!("Not supported!"),
+            Brand::Amd | Brand::Other => Ok(0),
         }
     }
 }
@@ -55,31 +69,54 @@ impl Device {
     pub fn brand(&self) -> Brand {
         self.brand
     }
+    pub fn platform_name(&self) -> ocl::Result<String> {
+        self.platform.name()
+    }
+    pub fn driver_version(&self) -> ocl::Result<String> {
+        Ok(self
+            .device
+            .info(ocl::enums::DeviceInfo::DriverVersion)?
+            .to_string())
+    }
+    pub fn max_work_group_size(&self) -> ocl::Result<usize> {
+        self.device.max_wg_size()
+    }
+    fn list(brand: Brand, plat: ocl::Platform) -> ocl::Result<Vec<Device>> {
+        ocl::Device::list_all(plat)?
+            .into_iter()
+            .map(|d| {
+                Ok(Device {
+                    brand,
+                    name: d.name()?,
+                    bus_id: brand.get_bus_id(d)?,
+                    platform: plat,
+                    device: d,
+                })
+            })
+            .collect()
+    }
     pub fn by_brand(brand: Brand) -> ocl::Result<Vec<Device>> {
         match find_platform(brand.platform_name())? {
-            Some(plat) => ocl::Device::list_all(plat)?
-                .into_iter()
-                .map(|d| {
-                    (|| -> ocl::Result<Device> {
-                        Ok(Device {
-                            brand,
-                            name: d.name()?,
-                            bus_id: brand.get_bus_id(d)?,
-                            platform: plat,
-                            device: d,
-                        })
-                    })()
-                })
-                .collect(),
+            Some(plat) => Self::list(brand, plat),
             None => Ok(Vec::new()),
         }
     }
+    /// Devices of all OpenCL platforms, in a stable order (Platforms first, then devices).
+    pub fn all() -> ocl::Result<Vec<Device>> {
+        let mut devices = Vec::new();
+        for plat in ocl::Platform::list() {
+            devices.extend(Self::list(Brand::of_platform(&plat.name()?), plat)?);
+        }
+        Ok(devices)
+    }
 }
 
 pub struct Program {
     device: Device,
     program: ocl::Program,
     queue: ocl::Queue,
+    // Host to device copies that may overlap with the kernels of `queue`
+    transfer_queue: ocl::Queue,
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -94,15 +131,70 @@ pub enum ProgramError {
     IO(#[from] std::io::Error),
 }
 
+impl ProgramError {
+    /// Whether the device ran out of memory. Drivers report it through different codes, NVIDIA's
+    /// only fails with CL_OUT_OF_RESOURCES once the buffer is first used.
+    pub fn is_out_of_memory(&self) -> bool {
+        use ocl::core::Status;
+        let status = match self {
+            ProgramError::OclCoreError(e) => e.api_status(),
+            ProgramError::OclError(e) => e.api_status(),
+            _ => None,
+        };
+        matches!(
+            status,
+            Some(
+                Status::CL_MEM_OBJECT_ALLOCATION_FAILURE
+                    | Status::CL_OUT_OF_RESOURCES
+                    | Status::CL_INVALID_BUFFER_SIZE
+            )
+        )
+    }
+}
+
+/// `~/.cache/femto` (Or `$XDG_CACHE_HOME/femto`), None when it can't be determined.
+pub fn cache_dir() -> Option<std::path::PathBuf> {
+    let dir = match std::env::var_os("XDG_CACHE_HOME") {
+        Some(dir) if !dir.is_empty() => std::path::PathBuf::from(dir),
+        _ => std::path::PathBuf::from(std::env::var_os("HOME")?).join(".cache"),
+    };
+    Some(dir.join("femto"))
+}
+
+/// Where the compiled binary of `src` for `device` is cached, in `cache_dir()`, named after the
+/// hashes of the source, the device name and the driver version.
+pub fn cache_path(device: &Device, src: &str) -> Option<std::path::PathBuf> {
+    let driver = device.driver_version().ok()?;
+    Some(cache_dir()?.join(format!(
+        "{:016x}-{:016x}-{:016x}.bin",
+        fnv1a(src.as_bytes()),
+        fnv1a(device.name.as_bytes()),
+        fnv1a(driver.as_bytes())
+    )))
+}
+
+// Kernels are timed through their events when profiling, which needs a queue created for it
+fn kernel_queue(context: &ocl::Context, device: &Device) -> Result<ocl::Queue, ProgramError> {
+    Ok(ocl::Queue::new(
+        context,
+        device.device,
+        Some(ocl::flags::QUEUE_PROFILING_ENABLE),
+    )?)
+}
+
+/// Time a finished kernel (See `Kernel::run_with_event`) took on the device.
+pub fn kernel_time(event: &ocl::Event) -> Result<std::time::Duration, ProgramError> {
+    use ocl::enums::ProfilingInfo;
+    let start = event.profiling_info(ProfilingInfo::Start)?.time()?;
+    let end = event.profiling_info(ProfilingInfo::End)?.time()?;
+    Ok(std::time::Duration::from_nanos(end.saturating_sub(start)))
+}
+
 impl Program {
     pub fn device(&self) -> &Device {
         &self.device
     }
     pub fn from_opencl(device: &Device, src: &str) -> Result<Program, ProgramError> {
-        //if std::path::Path::exists(&cached) {
-        //    let bin = std::fs::read(cached)?;
-        //    Program::from_binary(device, bin)
-        //} else {
         let context = ocl::Context::builder()
             .platform(device.platform)
             .devices(device.device)
@@ -111,15 +203,38 @@ impl Program {
             .src(src)
             .devices(ocl::builders::DeviceSpecifier::Single(device.device))
             .build(&context)?;
-        let queue = ocl::Queue::new(&context, device.device, None)?;
-        let prog = Program {
+        let queue = kernel_queue(&context, device)?;
+        let transfer_queue = ocl::Queue::new(&context, device.device, None)?;
+        Ok(Program {
             program,
             queue,
+            transfer_queue,
             device: device.clone(),
+        })
+    }
+    /// Like `from_opencl`, but reuses the binary of a previous compilation of the same source on
+    /// the same device and driver, when found in the kernel cache (See `cache_path`).
+    pub fn from_opencl_cached(device: &Device, src: &str) -> Result<Program, ProgramError> {
+        let Some(path) = cache_path(device, src) else {
+            return Self::from_opencl(device, src);
         };
-        //std::fs::write(cached, prog.to_binary()?)?;
+        if let Ok(bin) = std::fs::read(&path) {
+            // Unreadable binaries (e.g. written by a crashed run) are simply rebuilt
+            if let Ok(prog) = Self::from_binary(device, bin) {
+                debug!(path = %path.display(), "Loaded the kernels from the cache");
+                return Ok(prog);
+            }
+        }
+        debug!(path = %path.display(), "Building the kernels");
+        let prog = Self::from_opencl(device, src)?;
+        // Caching is best-effort, a read-only home shouldn't prevent running
+        if let Ok(bin) = prog.to_binary() {
+            let tmp = path.with_extension("tmp");
+            let _ = std::fs::create_dir_all(path.parent().unwrap())
+                .and_then(|_| std::fs::write(&tmp, bin))
+                .and_then(|_| std::fs::rename(&tmp, &path));
+        }
         Ok(prog)
-        //}
     }
     pub fn to_binary(&self) -> Result<Vec<u8>, ProgramError> {
         match self.program.info(ocl::enums::ProgramInfo::Binaries)? {
@@ -139,11 +254,13 @@ impl Program {
             .binaries(&bins)
             .devices(ocl::builders::DeviceSpecifier::Single(device.device))
             .build(&context)?;
-        let queue = ocl::Queue::new(&context, device.device, None)?;
+        let queue = kernel_queue(&context, device)?;
+        let transfer_queue = ocl::Queue::new(&context, device.device, None)?;
         Ok(Program {
             device: device.clone(),
             program,
             queue,
+            transfer_queue,
         })
     }
     pub fn create_buffer<T>(&self, length: usize) -> Result<Buffer<T>, ProgramError> {
@@ -166,6 +283,18 @@ impl Program {
         Ok(buf)
     }
 
+    /// Blocks until all the enqueued kernels are done.
+    pub fn finish(&self) -> Result<(), ProgramError> {
+        self.queue.finish()?;
+        Ok(())
+    }
+    pub fn transfer_queue(&self) -> &ocl::Queue {
+        &self.transfer_queue
+    }
+    /// An event completing once all the kernels enqueued so far are done.
+    pub fn marker(&self) -> Result<ocl::Event, ProgramError> {
+        Ok(self.queue.enqueue_marker(None::<&ocl::Event>)?)
+    }
     pub fn create_kernel(&self, name: &str, gws: usize, lws: usize) -> Kernel<'_> {
         let mut builder = ocl::Kernel::builder();
         builder.name(name);
@@ -231,6 +360,15 @@ impl<'a> Kernel<'a> {
         }
         Ok(())
     }
+    /// Like `run`, along with the event of the kernel, e.g. to time it.
+    pub fn run_with_event(self) -> Result<ocl::Event, ProgramError> {
+        let kern = self.builder.build()?;
+        let mut event = ocl::Event::empty();
+        unsafe {
+            kern.cmd().enew(&mut event).enq()?;
+        }
+        Ok(event)
+    }
 }
 
 #[macro_export]
@@ -247,6 +385,10 @@ impl<T> Buffer<T> {
         self.buffer.len() / std::mem::size_of::<T>()
     }
 
+    pub fn size_in_bytes(&self) -> usize {
+        self.buffer.len()
+    }
+
     pub fn write_from(&mut self, data: &[T]) -> Result<(), ProgramError> {
         assert!(data.len() <= self.length());
         self.buffer
@@ -260,6 +402,35 @@ impl<T> Buffer<T> {
         Ok(())
     }
 
+    /// Enqueues a copy of `data` on `queue`, once `after` completes, without waiting for it.
+    ///
+    /// # Safety
+    ///
+    /// `data` must stay alive and unchanged until the returned event completes.
+    pub unsafe fn write_async(
+        &mut self,
+        data: &[T],
+        queue: &ocl::Queue,
+        after: Option<&ocl::Event>,
+    ) -> Result<ocl::Event, ProgramError> {
+        assert!(data.len() <= self.length());
+        let mut done = ocl::Event::empty();
+        let mut cmd = self
+            .buffer
+            .write(std::slice::from_raw_parts(
+                data.as_ptr() as *const u8,
+                std::mem::size_of_val(data),
+            ))
+            .queue(queue)
+            .block(false)
+            .enew(&mut done);
+        if let Some(after) = after {
+            cmd = cmd.ewait(after);
+        }
+        cmd.enq()?;
+        Ok(done)
+    }
+
     pub fn read_into(&self, data: &mut [T]) -> Result<(), ProgramError> {
         assert!(data.len() <= self.length());
         self.buffer
@@ -0,0 +1,310 @@
+// Gradient checking: the analytic gradients of a `Function` are compared against central finite
+// differences of `sum(w * f(inputs))`, `w` being random weights (So that errors in different
+// output elements can't cancel out). Outputs that are not finite, like the masked values of
+// `TrilMask`, are left out of the sum.
+
+use crate::funcs::*;
+use crate::tensor::*;
+use rand::Rng;
+use std::sync::Arc;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum GradCheckError {
+    #[error("tensor error: {0}")]
+    TensorError(#[from] TensorError),
+    #[error(
+        "gradient mismatch in {op_name}, input {input} element {index}: \
+         analytic {analytic}, numerical {numerical}"
+    )]
+    Mismatch {
+        op_name: String,
+        input: usize,
+        index: usize,
+        analytic: f32,
+        numerical: f32,
+    },
+}
+
+// Sums the gradient of a broadcasted input, the same way `CpuGraph` accumulates gradients
+fn reduce_to(grad: &Tensor<f32>, shape: &[usize]) -> Result<Tensor<f32>, TensorError> {
+    let mut sum = Tensor::<f32>::zeros(shape);
+    if grad.dim() >= shape.len() {
+        for t in grad.keep_right(shape.len())?.inners().iter() {
+            sum = (&sum + t)?;
+        }
+    } else {
+        sum = (&sum + &grad.view())?;
+    }
+    Ok(sum)
+}
+
+fn weighted_sum(out: &Tensor<f32>, weights: &Tensor<f32>) -> f64 {
+    out.blob()
+        .iter()
+        .zip(weights.blob().iter())
+        .filter(|(o, _)| o.is_finite())
+        .map(|(o, w)| *o as f64 * *w as f64)
+        .sum()
+}
+
+/// Checks the gradients of `f` with respect to all of its float inputs, evaluating `f` in
+/// inference mode. Gradients match when their difference is within `tolerance`, relative to
+/// their magnitudes (Plus one, for gradients close to zero).
+pub fn gradcheck<R: Rng>(
+    rng: &mut R,
+    f: &dyn Function,
+    inps: &[GeneralTensor],
+    eps: f32,
+    tolerance: f32,
+) -> Result<(), GradCheckError> {
+    let refs = inps.iter().collect::<Vec<_>>();
+    let mut func = f.clone_box();
+    let out = func.run(&refs, false)?;
+    let weights = Tensor::raw(
+        out.shape(),
+        out.blob()
+            .iter()
+            .map(|o| {
+                if o.is_finite() {
+                    rng.gen_range(-1.0..1.0)
+                } else {
+                    0.
+                }
+            })
+            .collect(),
+    )?;
+    let grads = func.grad(&refs, &weights)?;
+
+    for (input, (inp, grad)) in inps.iter().zip(grads.iter()).enumerate() {
+        let inp = match inp {
+            GeneralTensor::Float(t) => t,
+            GeneralTensor::Usize(_) => continue,
+        };
+        let grad = reduce_to(grad, inp.shape())?;
+        for index in 0..inp.size() {
+            let loss_at = |delta: f32| -> Result<f64, TensorError> {
+                let mut perturbed = inp.clone();
+                perturbed.blob_mut()[index] += delta;
+                let mut inps = inps.to_vec();
+                inps[input] = GeneralTensor::Float(perturbed);
+                let out = f.clone_box().run(&inps.iter().collect::<Vec<_>>(), false)?;
+                Ok(weighted_sum(&out, &weights))
+            };
+            let numerical = ((loss_at(eps)? - loss_at(-eps)?) / (2. * eps as f64)) as f32;
+            let analytic = grad.blob()[index];
+            if (analytic - numerical).abs() > tolerance * (1. + analytic.abs().max(numerical.abs()))
+            {
+                return Err(GradCheckError::Mismatch {
+                    op_name: f.op_name(),
+                    input,
+                    index,
+                    analytic,
+                    numerical,
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+// Inputs uniformly sampled from [-1, 1)
+fn float<R: Rng>(rng: &mut R, shape: &[usize]) -> GeneralTensor {
+    GeneralTensor::Float(Tensor::<f32>::rand_range(rng, -1., 1., shape))
+}
+
+fn indices<R: Rng>(rng: &mut R, shape: &[usize], max: usize) -> GeneralTensor {
+    let size = shape.iter().product();
+    GeneralTensor::Usize(
+        Tensor::raw(shape, (0..size).map(|_| rng.gen_range(0..max)).collect()).unwrap(),
+    )
+}
+
+/// Small random inputs for every `Function` implementation, used for checking them.
+pub fn op_cases<R: Rng>(rng: &mut R) -> Vec<(Box<dyn Function>, Vec<GeneralTensor>)> {
+    let quantized_weights = Tensor::<f32>::rand_range(rng, -1., 1., &[8, 3]);
+    // Keep the inputs of ReLU away from its kink at zero
+    let relu_inp = Tensor::raw(
+        &[3, 4],
+        (0..12)
+            .map(|_| {
+                let f = rng.gen_range(0.1..1.);
+                if rng.gen() {
+                    f
+                } else {
+                    -f
+                }
+            })
+            .collect(),
+    )
+    .unwrap();
+
+    let mut cases = vec![
+        (Add::new(), vec![float(rng, &[3, 4]), float(rng, &[3, 4])]),
+        (Add::new(), vec![float(rng, &[2, 3, 4]), float(rng, &[4])]),
+        (
+            Cat::new(),
+            vec![
+                float(rng, &[2, 3]),
+                float(rng, &[2, 3]),
+                float(rng, &[2, 3]),
+            ],
+        ),
+        (Coeff::new(0.7), vec![float(rng, &[3, 4])]),
+        (
+            CrossEntropy::new(0.0, 0.0),
+            vec![float(rng, &[2, 3, 5]), indices(rng, &[2, 3], 5)],
+        ),
+        (
+            CrossEntropy::new(0.1, 0.01),
+            vec![float(rng, &[2, 3, 5]), indices(rng, &[2, 3], 5)],
+        ),
+        (
+            CrossEntropy::new(0.1, 0.01),
+            vec![
+                float(rng, &[2, 3, 5]),
+                indices(rng, &[2, 3], 5),
+                float(rng, &[2, 3]),
+            ],
+        ),
+        (
+            Distillation::new(2.0, 0.5),
+            vec![
+                float(rng, &[2, 3, 5]),
+                indices(rng, &[2, 3], 5),
+                float(rng, &[2, 3, 5]),
+            ],
+        ),
+        (
+            DocumentMask::new(4),
+            vec![float(rng, &[2, 4, 4]), indices(rng, &[2, 4], 2)],
+        ),
+        (
+            DocumentMask::bidirectional(4),
+            vec![float(rng, &[2, 4, 4]), indices(rng, &[2, 4], 2)],
+        ),
+        (
+            Dot::new(),
+            vec![float(rng, &[2, 3, 4]), float(rng, &[2, 3, 4])],
+        ),
+        (Dropout::new(0.5), vec![float(rng, &[3, 4])]),
+        (
+            Embedding::new(),
+            vec![indices(rng, &[2, 3], 5), float(rng, &[5, 4])],
+        ),
+        (
+            Fused::new(vec![
+                Elementwise::Add,
+                Elementwise::Gelu,
+                Elementwise::Coeff(0.7),
+                Elementwise::Add,
+                Elementwise::Dropout(0.5),
+            ]),
+            vec![
+                float(rng, &[2, 3, 4]),
+                float(rng, &[4]),
+                float(rng, &[2, 3, 4]),
+            ],
+        ),
+        (Gelu::new(), vec![float(rng, &[3, 4])]),
+        (
+            InfoNce::new(0.5),
+            vec![float(rng, &[3, 4]), float(rng, &[3, 4])],
+        ),
+        (
+            LayerNorm::new(),
+            vec![float(rng, &[3, 8]), float(rng, &[8]), float(rng, &[8])],
+        ),
+        (
+            MatMul::new(),
+            vec![float(rng, &[3, 4]), float(rng, &[4, 2])],
+        ),
+        (
+            MatMul::new(),
+            vec![float(rng, &[2, 3, 4]), float(rng, &[4, 5])],
+        ),
+        (
+            MatMul::transposed(true, false),
+            vec![float(rng, &[2, 4, 3]), float(rng, &[4, 5])],
+        ),
+        (
+            MatMul::transposed(false, true),
+            vec![float(rng, &[2, 3, 4]), float(rng, &[2, 5, 4])],
+        ),
+        (
+            MatMul::transposed(true, true),
+            vec![float(rng, &[4, 3]), float(rng, &[5, 4])],
+        ),
+        (Relu::new(), vec![GeneralTensor::Float(relu_inp)]),
+        (Softmax::new(), vec![float(rng, &[3, 4])]),
+        (Transpose::new(), vec![float(rng, &[2, 3, 4])]),
+        (TrilMask::new(4), vec![float(rng, &[2, 4, 4])]),
+    ];
+    for format in [QuantFormat::Q8, QuantFormat::Q4] {
+        let q = QuantizedTensor::quantize(&quantized_weights, format).unwrap();
+        cases.push((QuantizedMatMul::new(Arc::new(q)), vec![float(rng, &[2, 8])]));
+    }
+    cases
+}
+
+pub const GRADCHECK_EPS: f32 = 1e-2;
+pub const GRADCHECK_TOLERANCE: f32 = 1e-2;
+
+/// Checks the gradients of all ops on the inputs of `op_cases`, returning the number of checked
+/// cases.
+pub fn gradcheck_ops<R: Rng>(rng: &mut R) -> Result<usize, GradCheckError> {
+    let cases = op_cases(rng);
+    for (f, inps) in cases.iter() {
+        gradcheck(rng, f.as_ref(), inps, GRADCHECK_EPS, GRADCHECK_TOLERANCE)?;
+    }
+    Ok(cases.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gradcheck_ops() {
+        gradcheck_ops(&mut rand::thread_rng()).unwrap();
+    }
+
+    // Doubles its input, but claims a gradient of 2.2
+    #[derive(Debug, Clone)]
+    struct WrongGrad;
+    impl Function for WrongGrad {
+        fn run(
+            &mut self,
+            inps: &[&GeneralTensor],
+            _training: bool,
+        ) -> Result<Tensor<f32>, TensorError> {
+            Ok(inps[0].as_float()?.map_values(|f| f * 2.))
+        }
+        fn grad(
+            &self,
+            _inps: &[&GeneralTensor],
+            out_grad: &Tensor<f32>,
+        ) -> Result<Vec<Tensor<f32>>, TensorError> {
+            Ok(vec![out_grad.map_values(|f| f * 2.2)])
+        }
+        fn clone_box(&self) -> Box<dyn Function> {
+            Box::new(self.clone())
+        }
+    }
+
+    #[test]
+    fn test_gradcheck_detects_mismatch() {
+        let mut rng = rand::thread_rng();
+        let inp = float(&mut rng, &[3, 4]);
+        assert!(matches!(
+            gradcheck(
+                &mut rng,
+                &WrongGrad,
+                &[inp],
+                GRADCHECK_EPS,
+                GRADCHECK_TOLERANCE
+            ),
+            Err(GradCheckError::Mismatch { .. })
+        ));
+    }
+}
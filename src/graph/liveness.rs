@@ -0,0 +1,134 @@
+// Liveness analysis of the gradients of intermediate tensors. During a backward pass, the gradient
+// of a tensor is first written by the computation consuming it last (Computations are visited in
+// reverse order) and last read by the computation producing it. Gradients whose lifetimes don't
+// overlap can share a buffer, as long as it's cleared when a new lifetime starts.
+
+use super::TensorId;
+use std::collections::HashMap;
+
+/// Assignment of gradients to shared buffers.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GradPlan {
+    /// Number of elements of each shared buffer
+    pub slot_sizes: Vec<usize>,
+    /// The shared buffer of each planned gradient
+    pub slots: HashMap<TensorId, usize>,
+    /// Gradients to clear before backpropagating through a computation, gradients of tensors
+    /// that are not consumed by any computation (e.g. the loss) live from the start of the pass
+    /// and are under `None`.
+    pub clear_before: HashMap<Option<TensorId>, Vec<TensorId>>,
+}
+
+impl GradPlan {
+    pub fn total_size(&self) -> usize {
+        self.slot_sizes.iter().sum()
+    }
+}
+
+// Rounds sizes up to one of four classes per power of two, so that tensors of similar sizes can
+// share a buffer while wasting at most a quarter of it.
+fn bucket(size: usize) -> usize {
+    let bits = usize::BITS - size.leading_zeros();
+    let step = 1 << bits.saturating_sub(3);
+    size.div_ceil(step) * step
+}
+
+/// Plans the sharing of the gradients of `candidates` (Tensor ids and sizes) among as few
+/// buffers as possible. `computations` are the outputs and inputs of every computation of the
+/// graph, in order.
+pub fn plan_grad_buffers<'a, I: Iterator<Item = (TensorId, &'a [TensorId])>>(
+    computations: I,
+    candidates: &[(TensorId, usize)],
+) -> GradPlan {
+    let mut last_consumer = HashMap::<TensorId, TensorId>::new();
+    for (out, inps) in computations {
+        for inp in inps {
+            let consumer = last_consumer.entry(*inp).or_insert(out);
+            *consumer = (*consumer).max(out);
+        }
+    }
+
+    // In the order their lifetimes start during a backward pass
+    let mut order = candidates
+        .iter()
+        .map(|(id, size)| (*id, *size, last_consumer.get(id).copied()))
+        .collect::<Vec<_>>();
+    order.sort_by_key(|(id, _, first_use)| (first_use.map(std::cmp::Reverse), *id));
+
+    let mut plan = GradPlan::default();
+    let mut active = Vec::<(TensorId, usize)>::new();
+    let mut free = HashMap::<usize, Vec<usize>>::new();
+    for (id, size, first_use) in order {
+        // Lifetimes ending (At the computation producing the tensor) before this one starts
+        if let Some(first_use) = first_use {
+            active.retain(|(last_use, slot)| {
+                if *last_use > first_use {
+                    free.entry(plan.slot_sizes[*slot]).or_default().push(*slot);
+                    false
+                } else {
+                    true
+                }
+            });
+        }
+        let size = bucket(size);
+        let slot = free
+            .get_mut(&size)
+            .and_then(|slots| slots.pop())
+            .unwrap_or_else(|| {
+                plan.slot_sizes.push(size);
+                plan.slot_sizes.len() - 1
+            });
+        active.push((id, slot));
+        plan.slots.insert(id, slot);
+        plan.clear_before.entry(first_use).or_default().push(id);
+    }
+    plan
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bucket() {
+        assert_eq!(bucket(1), 1);
+        assert_eq!(bucket(8), 8);
+        assert_eq!(bucket(9), 10);
+        assert_eq!(bucket(100), 112);
+        assert_eq!(bucket(1024), 1024);
+        assert_eq!(bucket(1025), 1280);
+    }
+
+    #[test]
+    fn test_plan_grad_buffers() {
+        // A chain 0 -> 2 -> ... -> 7 with a residual connection 2 -> 4, 1 being a parameter
+        let computations: Vec<(TensorId, Vec<TensorId>)> = vec![
+            (2, vec![0, 1]),
+            (3, vec![2]),
+            (4, vec![3, 2]),
+            (5, vec![4]),
+            (6, vec![5]),
+            (7, vec![6]),
+        ];
+        let candidates = [(2, 100), (3, 100), (4, 100), (5, 100), (6, 100), (7, 1)];
+        let plan = plan_grad_buffers(
+            computations
+                .iter()
+                .map(|(out, inps)| (*out, inps.as_slice())),
+            &candidates,
+        );
+
+        // Gradients used by the same computation never share a buffer
+        for (out, inps) in computations.iter() {
+            for inp in inps.iter().filter(|id| plan.slots.contains_key(id)) {
+                assert_ne!(plan.slots[out], plan.slots[inp]);
+            }
+        }
+        // The gradient of 2 is used from the backward pass of 4 on, after the one of 5 is dead
+        assert_eq!(plan.slots[&2], plan.slots[&5]);
+        assert_eq!(plan.slots[&4], plan.slots[&6]);
+        assert_eq!(plan.slot_sizes, vec![1, 112, 112, 112]);
+        assert_eq!(plan.clear_before[&None], vec![7]);
+        assert_eq!(plan.clear_before[&Some(4)], vec![2, 3]);
+    }
+}
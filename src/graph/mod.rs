@@ -1,14 +1,41 @@
 #[cfg(feature = "gpu")]
 pub mod gpu;
 
+mod backend;
+pub use backend::*;
+
+mod dump;
+pub use dump::*;
+
+mod fusion;
+use fusion::{plan_fusions, plan_transpose_folds, Fusion};
+
+mod gradcheck;
+pub use gradcheck::*;
+
+mod liveness;
+pub use liveness::*;
+
+mod parity;
+pub use parity::*;
+
+mod profile;
+pub use profile::*;
+
 use crate::funcs::Function;
 use crate::optimizer::{Optimizer, OptimizerState};
 use crate::tensor::*;
-use std::collections::{BTreeMap, HashMap};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Instant;
 use thiserror::Error;
 
 pub type TensorId = usize;
 
+/// Observes, or modifies in place, the value of a tensor during forward passes (See
+/// `Graph::add_hook`).
+pub type Hook = Arc<dyn Fn(&mut Tensor<f32>) + Send + Sync>;
+
 pub trait Graph {
     fn alloc(
         &mut self,
@@ -18,6 +45,8 @@ pub trait Graph {
     ) -> Result<TensorId, GraphError>;
     fn alloc_usize(&mut self, t: Tensor<usize>, name: String) -> Result<TensorId, GraphError>;
     fn params(&self) -> &[TensorId];
+    /// Stops treating a parameter as trainable, the optimizer will no longer update it.
+    fn freeze(&mut self, id: TensorId) -> Result<(), GraphError>;
     fn load<T: TensorOps<f32>>(
         &mut self,
         tensor_id: TensorId,
@@ -28,6 +57,13 @@ pub trait Graph {
         tensor_id: TensorId,
         tensor: &T,
     ) -> Result<(), GraphError>;
+    /// Loads a tensor for the next forward pass, the current value stays in place until then
+    /// (e.g. for the backward pass of the current step). GPU graphs upload it in the background.
+    fn stage_usize<T: TensorOps<usize>>(
+        &mut self,
+        tensor_id: TensorId,
+        tensor: &T,
+    ) -> Result<(), GraphError>;
     fn load_grad<T: TensorOps<f32>>(
         &mut self,
         tensor_id: TensorId,
@@ -35,16 +71,64 @@ pub trait Graph {
     ) -> Result<(), GraphError>;
     fn zero_grad(&mut self) -> Result<(), GraphError>;
     fn name_of(&self, id: TensorId) -> Result<&String, GraphError>;
+    /// Names a tensor, e.g. an intermediate result, so that it's recognizable in graph dumps and
+    /// error messages. Parameters are saved in checkpoints under their names.
+    fn set_name(&mut self, id: TensorId, name: String) -> Result<(), GraphError>;
     fn fetch(&mut self, id: TensorId, grad: bool) -> Result<(), GraphError>;
     fn get(&self, id: TensorId) -> Result<&GeneralTensor, GraphError>;
     fn get_grad(&self, id: TensorId) -> Result<&Tensor<f32>, GraphError>;
-    fn backward_all(&mut self, id: TensorId, limit: Option<usize>) -> Result<f32, GraphError>;
+    /// Backpropagates from `id`. `limit` restricts the process to the last n computations of the
+    /// graph and `params_only` skips the computations that do not depend on any trainable
+    /// parameter.
+    fn backward_all(
+        &mut self,
+        id: TensorId,
+        limit: Option<usize>,
+        params_only: bool,
+    ) -> Result<f32, GraphError>;
     fn forward(&mut self, training: bool) -> Result<(), GraphError>;
     fn call(
         &mut self,
         f: Box<dyn Function>,
         tensor_ids: &[TensorId],
     ) -> Result<TensorId, GraphError>;
+    fn num_computations(&self) -> usize;
+    /// Replaces the operation computing tensor `id` by `f`, applied to the same inputs. The output
+    /// of `f` must have the shape of the tensor.
+    fn replace(&mut self, id: TensorId, f: Box<dyn Function>) -> Result<(), GraphError>;
+    /// Calls `hook` with the value of tensor `id` every time a forward pass computes it, before
+    /// the computations reading it. Changes made by the hook are seen by them, while the
+    /// backward pass takes them as constants. GPU graphs copy the tensor to the host and back.
+    fn add_hook(&mut self, id: TensorId, hook: Hook) -> Result<(), GraphError>;
+    fn clear_hooks(&mut self);
+    /// Replaces chains of elementwise computations, each one only consumed by the next, by single
+    /// `Fused` computations. The intermediate results of the chains are not computed anymore and
+    /// disappear from `nodes`, except for the tensors in `keep`. Returns the number of removed
+    /// computations.
+    fn fuse_elementwise(&mut self, keep: &[TensorId]) -> Result<usize, GraphError>;
+    /// Removes the `Transpose` computations whose consumers can all read their input transposed
+    /// instead (e.g. `MatMul`), except for the tensors in `keep`. Returns the number of removed
+    /// computations.
+    fn fold_transposes(&mut self, keep: &[TensorId]) -> Result<usize, GraphError>;
+    /// Describes every tensor of the graph and the computation producing it.
+    fn nodes(&self) -> Vec<NodeInfo>;
+    /// Rounds weights, activations and gradients to the given format during forward/backward
+    /// passes, while the parameters updated by the optimizer stay in f32.
+    fn set_precision(&mut self, precision: Precision) -> Result<(), GraphError>;
+    /// Multiplies the gradient the backward pass starts from, so that small gradients do not
+    /// underflow in low precision formats.
+    fn set_loss_scale(&mut self, scale: f32) -> Result<(), GraphError>;
+    /// Checks the output and input gradients of every computation for NaN/Inf values, failing
+    /// with `GraphError::NumericalAnomaly` on the first one found. Slow, meant for debugging.
+    fn set_detect_anomaly(&mut self, enabled: bool) -> Result<(), GraphError>;
+    /// Lets the gradients of intermediate tensors share memory during backward passes (See
+    /// `plan_grad_buffers`), they can't be fetched anymore. Must be set before the first pass,
+    /// GPU graphs reuse buffers by default and CPU graphs ignore it.
+    fn set_buffer_reuse(&mut self, enabled: bool) -> Result<(), GraphError>;
+    /// Times the computations of the following passes per operation, see `take_profile`.
+    fn set_profiling(&mut self, enabled: bool) -> Result<(), GraphError>;
+    /// The timings recorded since profiling was enabled or the last call, which resets them.
+    fn take_profile(&mut self) -> Profile;
     fn optimize<O: Optimizer>(
         &mut self,
         optimizer: &O,
@@ -83,6 +167,15 @@ pub struct CpuGraph {
     params: Vec<TensorId>,
     computations: BTreeMap<TensorId, Computation>,
     optimizer_state: OptimizerState,
+    precision: Precision,
+    loss_scale: f32,
+    rounded_params: HashMap<TensorId, GeneralTensor>,
+    detect_anomaly: bool,
+    profiling: bool,
+    profile: Profile,
+    staged: HashMap<TensorId, Tensor<usize>>,
+    fused: HashSet<TensorId>,
+    hooks: HashMap<TensorId, Vec<Hook>>,
 }
 
 #[derive(Error, Debug)]
@@ -93,12 +186,64 @@ pub enum GraphError {
     TensorNotFound(usize),
     #[error("graph is not ready!")]
     NotReady,
+    #[error("graph is already compiled!")]
+    AlreadyCompiled,
+    #[error("gradient of tensor {0} is not kept (see `Graph::set_buffer_reuse`)")]
+    GradientNotKept(TensorId),
     #[error("tensor types incompatible!")]
     IncompatibleTypes,
+    #[error("invalid backward scope: {0}")]
+    InvalidBackwardScope(String),
+    #[error("tensor {0} is not computed by an operation")]
+    NotComputed(TensorId),
+    #[error("NaN/Inf in tensor {tensor_id} ({tensor_name}), computed by {op_name} at step {step}")]
+    NumericalAnomaly {
+        tensor_id: TensorId,
+        tensor_name: String,
+        op_name: String,
+        step: usize,
+    },
+
+    #[error("backend `{0}` is not available, rebuild with `--features gpu`")]
+    BackendUnavailable(Backend),
 
     #[cfg(feature = "gpu")]
     #[error("gpu error: {0}")]
     GpuError(#[from] gpu::program::ProgramError),
+    #[cfg(feature = "gpu")]
+    #[error("gpu device {index} not found, {available} devices available (see `femto devices`)")]
+    DeviceNotFound { index: usize, available: usize },
+    #[cfg(feature = "gpu")]
+    #[error("{0} has no gpu implementation (see `Function::gpu_impl`)")]
+    NoGpuImpl(String),
+    #[cfg(feature = "gpu")]
+    #[error("invalid device index: {0}")]
+    InvalidDevice(String),
+    #[cfg(feature = "gpu")]
+    #[error(
+        "out of gpu memory allocating {requested} bytes, {in_use} bytes already in use \
+         (largest tensor: {largest_tensor}), try a smaller batch size or model"
+    )]
+    OutOfMemory {
+        requested: usize,
+        in_use: usize,
+        largest_tensor: String,
+    },
+}
+
+impl GraphError {
+    /// Whether a device ran out of memory, e.g. allocating the buffers of a graph.
+    pub fn is_out_of_memory(&self) -> bool {
+        #[cfg(feature = "gpu")]
+        if let GraphError::OutOfMemory { .. } = self {
+            return true;
+        }
+        #[cfg(feature = "gpu")]
+        if let GraphError::GpuError(e) = self {
+            return e.is_out_of_memory();
+        }
+        false
+    }
 }
 
 #[cfg(feature = "gpu")]
@@ -108,7 +253,49 @@ impl From<ocl::Error> for GraphError {
     }
 }
 
+// Finds the tensors whose values depend on at least one trainable parameter. Since a computation
+// always comes after its inputs, a single pass over the computations is enough.
+fn param_dependents<'a, I: Iterator<Item = (TensorId, &'a [TensorId])>>(
+    params: &[TensorId],
+    computations: I,
+) -> HashSet<TensorId> {
+    let mut dependents = params.iter().cloned().collect::<HashSet<_>>();
+    for (out, inps) in computations {
+        if inps.iter().any(|id| dependents.contains(id)) {
+            dependents.insert(out);
+        }
+    }
+    dependents
+}
+
+// Name of a tensor in error messages
+fn display_name(names: &[String], id: TensorId) -> String {
+    match names.get(id) {
+        Some(name) if !name.is_empty() => name.clone(),
+        _ => "unnamed".into(),
+    }
+}
+
+fn is_finite(blob: &[f32]) -> bool {
+    blob.iter().all(|f| f.is_finite())
+}
+
 impl CpuGraph {
+    // Replaces computations by the planned ones, returning the number of removed computations
+    fn apply_fusions(&mut self, fusions: Vec<Fusion>) -> usize {
+        let mut removed = 0;
+        for fusion in fusions {
+            for id in fusion.removed {
+                self.computations.remove(&id);
+                self.tensors[id] = GeneralTensor::Float(Tensor::scalar(0.));
+                self.grads[id] = Tensor::scalar(0.);
+                self.fused.insert(id);
+                removed += 1;
+            }
+            self.computations.insert(fusion.out, fusion.computation);
+        }
+        removed
+    }
     fn add_grad<T: TensorOps<f32>>(&mut self, id: TensorId, add: T) -> Result<(), GraphError> {
         // Usize tensors do not have gradient
         if self.get(id)?.as_float().is_err() {
@@ -166,9 +353,18 @@ impl Graph for CpuGraph {
         tensor_id: TensorId,
         tensor: &T,
     ) -> Result<(), GraphError> {
+        self.staged.remove(&tensor_id);
         self.tensors[tensor_id] = GeneralTensor::Usize(tensor.view().into());
         Ok(())
     }
+    fn stage_usize<T: TensorOps<usize>>(
+        &mut self,
+        tensor_id: TensorId,
+        tensor: &T,
+    ) -> Result<(), GraphError> {
+        self.staged.insert(tensor_id, tensor.view().into());
+        Ok(())
+    }
     fn load_grad<T: TensorOps<f32>>(
         &mut self,
         tensor_id: TensorId,
@@ -184,6 +380,13 @@ impl Graph for CpuGraph {
         Ok(())
     }
 
+    fn set_name(&mut self, id: TensorId, name: String) -> Result<(), GraphError> {
+        *self
+            .names
+            .get_mut(id)
+            .ok_or(GraphError::TensorNotFound(id))? = name;
+        Ok(())
+    }
     fn name_of(&self, id: TensorId) -> Result<&String, GraphError> {
         self.names.get(id).ok_or(GraphError::TensorNotFound(id))
     }
@@ -193,25 +396,60 @@ impl Graph for CpuGraph {
     fn get_grad(&self, id: TensorId) -> Result<&Tensor<f32>, GraphError> {
         self.grads.get(id).ok_or(GraphError::TensorNotFound(id))
     }
-    fn backward_all(&mut self, id: TensorId, limit: Option<usize>) -> Result<f32, GraphError> {
+    fn backward_all(
+        &mut self,
+        id: TensorId,
+        limit: Option<usize>,
+        params_only: bool,
+    ) -> Result<f32, GraphError> {
         let output = self.get(id)?.as_float()?.clone();
-        let mean_coeff = 1. / output.size() as f32;
+        let mean_coeff = self.loss_scale / output.size() as f32;
         self.add_grad(id, Tensor::constant(output.shape(), mean_coeff))?;
 
+        let dependents = params_only.then(|| {
+            param_dependents(
+                &self.params,
+                self.computations
+                    .iter()
+                    .map(|(id, c)| (*id, c.inps.as_slice())),
+            )
+        });
+
         for (i, (id, comp)) in self.computations.clone().iter().rev().enumerate() {
             if let Some(limit) = limit {
                 if i >= limit {
                     break;
                 }
             }
+            if let Some(dependents) = &dependents {
+                if !dependents.contains(id) {
+                    continue;
+                }
+            }
             let inps = comp
                 .inps
                 .iter()
-                .map(|id| &self.tensors[*id])
+                .map(|id| self.rounded_params.get(id).unwrap_or(&self.tensors[*id]))
                 .collect::<Vec<_>>();
             let grad_out = &self.grads[*id];
+            let timer = Instant::now();
             let grads = comp.func.grad(&inps, grad_out)?;
-            for (id, grad) in comp.inps.clone().into_iter().zip(grads.into_iter()) {
+            if self.profiling {
+                self.profile
+                    .record_backward(comp.func.op_name(), timer.elapsed());
+            }
+            for (id, mut grad) in comp.inps.clone().into_iter().zip(grads.into_iter()) {
+                if self.precision != Precision::F32 {
+                    grad = self.precision.round_tensor(&grad);
+                }
+                if self.detect_anomaly && !is_finite(grad.blob()) {
+                    return Err(GraphError::NumericalAnomaly {
+                        tensor_id: id,
+                        tensor_name: display_name(&self.names, id),
+                        op_name: format!("{} (backward)", comp.func.op_name()),
+                        step: self.optimizer_state.step,
+                    });
+                }
                 self.add_grad(id, grad)?;
             }
         }
@@ -219,13 +457,49 @@ impl Graph for CpuGraph {
         Ok(output.mean())
     }
     fn forward(&mut self, training: bool) -> Result<(), GraphError> {
+        for (id, t) in std::mem::take(&mut self.staged) {
+            self.tensors[id] = GeneralTensor::Usize(t);
+        }
+        // Low precision copies of the weights, used by both forward and backward passes
+        self.rounded_params.clear();
+        if self.precision != Precision::F32 {
+            for p in self.params.iter() {
+                let rounded = self.precision.round_tensor(self.get(*p)?.as_float()?);
+                self.rounded_params
+                    .insert(*p, GeneralTensor::Float(rounded));
+            }
+        }
         for (out, c) in self.computations.iter_mut() {
             let tensors = c
                 .inps
                 .iter()
-                .map(|id| self.tensors.get(*id).ok_or(GraphError::TensorNotFound(*id)))
+                .map(|id| {
+                    self.rounded_params
+                        .get(id)
+                        .or(self.tensors.get(*id))
+                        .ok_or(GraphError::TensorNotFound(*id))
+                })
                 .collect::<Result<Vec<_>, GraphError>>()?;
-            let result = c.func.run(&tensors, training)?;
+            let timer = Instant::now();
+            let mut result = c.func.run(&tensors, training)?;
+            if self.profiling {
+                self.profile
+                    .record_forward(c.func.op_name(), timer.elapsed());
+            }
+            if self.precision != Precision::F32 {
+                result = self.precision.round_tensor(&result);
+            }
+            if self.detect_anomaly && !is_finite(result.blob()) {
+                return Err(GraphError::NumericalAnomaly {
+                    tensor_id: *out,
+                    tensor_name: display_name(&self.names, *out),
+                    op_name: c.func.op_name(),
+                    step: self.optimizer_state.step,
+                });
+            }
+            for hook in self.hooks.get(out).into_iter().flatten() {
+                hook(&mut result);
+            }
             self.tensors[*out] = GeneralTensor::Float(result);
         }
         Ok(())
@@ -250,6 +524,92 @@ impl Graph for CpuGraph {
         );
         Ok(child)
     }
+    fn num_computations(&self) -> usize {
+        self.computations.len()
+    }
+    fn replace(&mut self, id: TensorId, mut f: Box<dyn Function>) -> Result<(), GraphError> {
+        let inps = self
+            .computations
+            .get(&id)
+            .ok_or(GraphError::NotComputed(id))?
+            .inps
+            .clone();
+        let tensors = inps
+            .iter()
+            .map(|id| self.get(*id))
+            .collect::<Result<Vec<_>, GraphError>>()?;
+        let out = f.run(&tensors, false)?;
+        if out.shape() != self.tensors[id].shape() {
+            return Err(TensorError::UnexpectedShape.into());
+        }
+        self.tensors[id] = GeneralTensor::Float(out);
+        self.computations.insert(id, Computation { inps, func: f });
+        Ok(())
+    }
+    fn add_hook(&mut self, id: TensorId, hook: Hook) -> Result<(), GraphError> {
+        if !self.computations.contains_key(&id) {
+            return Err(GraphError::NotComputed(id));
+        }
+        self.hooks.entry(id).or_default().push(hook);
+        Ok(())
+    }
+    fn clear_hooks(&mut self) {
+        self.hooks.clear();
+    }
+    fn fuse_elementwise(&mut self, keep: &[TensorId]) -> Result<usize, GraphError> {
+        let fusions = plan_fusions(
+            self.computations.iter().map(|(id, c)| (*id, c)),
+            keep,
+            |id| self.tensors[id].size(),
+        );
+        Ok(self.apply_fusions(fusions))
+    }
+    fn fold_transposes(&mut self, keep: &[TensorId]) -> Result<usize, GraphError> {
+        let folds = plan_transpose_folds(self.computations.iter().map(|(id, c)| (*id, c)), keep);
+        Ok(self.apply_fusions(folds))
+    }
+    fn nodes(&self) -> Vec<NodeInfo> {
+        self.tensors
+            .iter()
+            .enumerate()
+            .filter(|(id, _)| !self.fused.contains(id))
+            .map(|(id, t)| NodeInfo {
+                id,
+                name: self.names[id].clone(),
+                shape: t.shape().to_vec(),
+                is_param: self.params.contains(&id),
+                op: self.computations.get(&id).map(|c| c.func.op_name()),
+                inputs: self
+                    .computations
+                    .get(&id)
+                    .map(|c| c.inps.clone())
+                    .unwrap_or_default(),
+            })
+            .collect()
+    }
+    fn set_precision(&mut self, precision: Precision) -> Result<(), GraphError> {
+        self.precision = precision;
+        self.rounded_params.clear();
+        Ok(())
+    }
+    fn set_loss_scale(&mut self, scale: f32) -> Result<(), GraphError> {
+        self.loss_scale = scale;
+        Ok(())
+    }
+    fn set_detect_anomaly(&mut self, enabled: bool) -> Result<(), GraphError> {
+        self.detect_anomaly = enabled;
+        Ok(())
+    }
+    fn set_profiling(&mut self, enabled: bool) -> Result<(), GraphError> {
+        self.profiling = enabled;
+        Ok(())
+    }
+    fn take_profile(&mut self) -> Profile {
+        std::mem::take(&mut self.profile)
+    }
+    fn set_buffer_reuse(&mut self, _enabled: bool) -> Result<(), GraphError> {
+        Ok(())
+    }
     fn optimize<O: Optimizer>(
         &mut self,
         optimizer: &O,
@@ -280,11 +640,16 @@ impl Graph for CpuGraph {
     fn params(&self) -> &[TensorId] {
         &self.params
     }
+    fn freeze(&mut self, id: TensorId) -> Result<(), GraphError> {
+        self.name_of(id)?;
+        self.params.retain(|p| *p != id);
+        Ok(())
+    }
     fn optimizer_step(&self) -> usize {
         self.optimizer_state.step
     }
     fn get_optimizer_state(&self) -> Result<OptimizerState, GraphError> {
-        Ok(self.optimizer_state.clone())
+        Ok(self.optimizer_state.dequantized()?)
     }
     fn set_optimizer_state(&mut self, state: &OptimizerState) -> Result<(), GraphError> {
         self.optimizer_state = state.clone();
@@ -301,6 +666,171 @@ impl CpuGraph {
             params: Default::default(),
             names: Default::default(),
             optimizer_state: Default::default(),
+            precision: Default::default(),
+            loss_scale: 1.,
+            rounded_params: Default::default(),
+            detect_anomaly: false,
+            profiling: false,
+            profile: Default::default(),
+            staged: Default::default(),
+            fused: Default::default(),
+            hooks: Default::default(),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::funcs::{Add, Coeff};
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn test_detect_anomaly() {
+        let mut g = CpuGraph::new();
+        let a = g
+            .alloc(Tensor::constant(&[2, 3], 1.), true, "a".into())
+            .unwrap();
+        let b = g.call(Add::new(), &[a, a]).unwrap();
+        let c = g.call(Coeff::new(f32::INFINITY), &[b]).unwrap();
+        g.set_name(c, "scaled".into()).unwrap();
+        g.forward(true).unwrap();
+
+        g.set_detect_anomaly(true).unwrap();
+        match g.forward(true) {
+            Err(GraphError::NumericalAnomaly {
+                tensor_id,
+                tensor_name,
+                op_name,
+                ..
+            }) => {
+                assert_eq!(tensor_id, c);
+                assert_eq!(tensor_name, "scaled");
+                assert_eq!(op_name, "Coeff");
+            }
+            _ => panic!("anomaly not detected"),
+        }
+    }
+
+    // An operation defined outside of the funcs module
+    #[derive(Debug, Clone)]
+    struct Square;
+    impl Function for Square {
+        fn run(
+            &mut self,
+            inps: &[&GeneralTensor],
+            _training: bool,
+        ) -> Result<Tensor<f32>, TensorError> {
+            Ok(inps[0].as_float()?.map_values(|f| f * f))
+        }
+        fn grad(
+            &self,
+            inps: &[&GeneralTensor],
+            out_grad: &Tensor<f32>,
+        ) -> Result<Vec<Tensor<f32>>, TensorError> {
+            let inp = inps[0].as_float()?;
+            let grad = inp
+                .blob()
+                .iter()
+                .zip(out_grad.blob().iter())
+                .map(|(f, d)| 2. * f * d)
+                .collect();
+            Ok(vec![Tensor::raw(inp.shape(), grad)?])
+        }
+        fn clone_box(&self) -> Box<dyn Function> {
+            Box::new(self.clone())
+        }
+    }
+
+    #[test]
+    fn test_replace() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let inp = GeneralTensor::Float(Tensor::<f32>::rand(&mut rng, &[2, 3]));
+        gradcheck(&mut rng, &Square, &[inp], 1e-3, 1e-2).unwrap();
+
+        let mut g = CpuGraph::new();
+        let a = g
+            .alloc(Tensor::constant(&[2, 3], 3.), true, "a".into())
+            .unwrap();
+        let b = g.call(Coeff::new(2.), &[a]).unwrap();
+        g.replace(b, Box::new(Square)).unwrap();
+        assert_eq!(
+            g.nodes().iter().find(|n| n.id == b).unwrap().op.as_deref(),
+            Some("Square")
+        );
+        g.forward(true).unwrap();
+        g.zero_grad().unwrap();
+        g.backward_all(b, None, false).unwrap();
+        assert!(g
+            .get(b)
+            .unwrap()
+            .as_float()
+            .unwrap()
+            .blob()
+            .iter()
+            .all(|f| *f == 9.));
+        assert!(g.get_grad(a).unwrap().blob().iter().all(|f| *f == 1.));
+
+        // The output must keep its shape, and only computed tensors can be replaced
+        let c = g.call(Add::new(), &[b, a]).unwrap();
+        assert!(g.replace(c, Coeff::new(1.)).is_ok());
+        assert!(matches!(
+            g.replace(c, crate::funcs::Transpose::new()),
+            Err(GraphError::TensorError(TensorError::UnexpectedShape))
+        ));
+        assert!(matches!(
+            g.replace(a, Coeff::new(1.)),
+            Err(GraphError::NotComputed(_))
+        ));
+    }
+
+    #[test]
+    fn test_hooks() {
+        let mut g = CpuGraph::new();
+        let a = g
+            .alloc(Tensor::constant(&[2, 3], 1.), true, "a".into())
+            .unwrap();
+        let b = g.call(Coeff::new(2.), &[a]).unwrap();
+        let c = g.call(Add::new(), &[b, a]).unwrap();
+        let seen = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let observed = seen.clone();
+        g.add_hook(
+            b,
+            Arc::new(move |t: &mut Tensor<f32>| {
+                observed.lock().unwrap().extend_from_slice(t.blob());
+                *t = t.map_values(|f| f * 10.);
+            }),
+        )
+        .unwrap();
+        g.forward(true).unwrap();
+        assert_eq!(seen.lock().unwrap().as_slice(), &[2.; 6]);
+        // Later computations see the modified tensor
+        assert_eq!(g.get(c).unwrap().as_float().unwrap().blob(), &[21.; 6]);
+
+        g.clear_hooks();
+        g.forward(true).unwrap();
+        assert_eq!(g.get(c).unwrap().as_float().unwrap().blob(), &[3.; 6]);
+        assert!(matches!(
+            g.add_hook(a, Arc::new(|_: &mut Tensor<f32>| {})),
+            Err(GraphError::NotComputed(_))
+        ));
+    }
+
+    #[test]
+    fn test_stage_usize() {
+        let mut g = CpuGraph::new();
+        let x = g.alloc_usize(Tensor::zeros(&[3]), "x".into()).unwrap();
+        let next = Tensor::raw(&[3], vec![1, 2, 3]).unwrap();
+        g.stage_usize(x, &next).unwrap();
+        assert_eq!(g.get(x).unwrap().as_usize().unwrap().blob(), &[0, 0, 0]);
+        g.forward(true).unwrap();
+        assert_eq!(g.get(x).unwrap().as_usize().unwrap().blob(), &[1, 2, 3]);
+
+        // Loading directly supersedes a staged value
+        g.stage_usize(x, &Tensor::zeros(&[3])).unwrap();
+        g.load_usize(x, &next).unwrap();
+        g.forward(true).unwrap();
+        assert_eq!(g.get(x).unwrap().as_usize().unwrap().blob(), &[1, 2, 3]);
+    }
+}
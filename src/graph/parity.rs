@@ -0,0 +1,104 @@
+use super::{Graph, GraphError, TensorId};
+use crate::tensor::*;
+
+/// A tensor (or gradient) whose values differ between two graphs.
+#[derive(Debug, Clone)]
+pub struct ParityMismatch {
+    pub tensor_id: TensorId,
+    pub name: String,
+    pub op_name: Option<String>,
+    pub grad: bool,
+    pub max_diff: f32,
+}
+
+// Largest difference between two tensors, relative to the magnitude of the first one
+fn max_diff(a: &Tensor<f32>, b: &Tensor<f32>) -> f32 {
+    if a.shape() != b.shape() {
+        return f32::INFINITY;
+    }
+    let scale = 1. + a.blob().iter().fold(0f32, |m, f| m.max(f.abs()));
+    a.blob()
+        .iter()
+        .zip(b.blob().iter())
+        .map(|(x, y)| if x == y { 0. } else { (x - y).abs() / scale })
+        .fold(0., f32::max)
+}
+
+/// Compares the values and gradients of every float tensor of two graphs built the same way
+/// (e.g. the same model on `CpuGraph` and `GpuGraph`), after both ran the same passes. Returns
+/// the tensors whose relative difference exceeds `tolerance`, in computation order.
+pub fn compare_graphs<A: Graph, B: Graph>(
+    a: &mut A,
+    b: &mut B,
+    tolerance: f32,
+) -> Result<Vec<ParityMismatch>, GraphError> {
+    let nodes = a.nodes();
+    if nodes.len() != b.nodes().len() {
+        return Err(GraphError::IncompatibleTypes);
+    }
+    let mut mismatches = Vec::new();
+    for node in nodes {
+        a.fetch(node.id, true)?;
+        b.fetch(node.id, true)?;
+        let (value_a, value_b) = match (a.get(node.id)?, b.get(node.id)?) {
+            (GeneralTensor::Float(x), GeneralTensor::Float(y)) => (x, y),
+            (GeneralTensor::Usize(_), GeneralTensor::Usize(_)) => continue,
+            _ => return Err(GraphError::IncompatibleTypes),
+        };
+        for (grad, diff) in [
+            (false, max_diff(value_a, value_b)),
+            (true, max_diff(a.get_grad(node.id)?, b.get_grad(node.id)?)),
+        ] {
+            if diff.is_nan() || diff > tolerance {
+                mismatches.push(ParityMismatch {
+                    tensor_id: node.id,
+                    name: node.name.clone(),
+                    op_name: node.op.clone(),
+                    grad,
+                    max_diff: diff,
+                });
+            }
+        }
+    }
+    Ok(mismatches)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::funcs::{CrossEntropy, MatMul, Softmax};
+    use crate::graph::CpuGraph;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    fn build(seed: u64) -> (CpuGraph, TensorId, TensorId) {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut g = CpuGraph::new();
+        let x = g
+            .alloc(Tensor::<f32>::rand(&mut rng, &[4, 3]), true, "x".into())
+            .unwrap();
+        let w = g
+            .alloc(Tensor::<f32>::rand(&mut rng, &[3, 5]), true, "w".into())
+            .unwrap();
+        let ys = g
+            .alloc_usize(Tensor::raw(&[4], vec![0, 1, 2, 3]).unwrap(), "y".into())
+            .unwrap();
+        let out = g.call(MatMul::new(), &[x, w]).unwrap();
+        let out = g.call(Softmax::new(), &[out]).unwrap();
+        let loss = g.call(CrossEntropy::new(0.0, 0.0), &[out, ys]).unwrap();
+        g.forward(true).unwrap();
+        g.zero_grad().unwrap();
+        g.backward_all(loss, None, false).unwrap();
+        (g, w, loss)
+    }
+
+    #[test]
+    fn test_compare_graphs() {
+        let (mut a, _, _) = build(1);
+        let (mut b, _, _) = build(1);
+        assert!(compare_graphs(&mut a, &mut b, 1e-6).unwrap().is_empty());
+
+        let (mut c, w, _) = build(2);
+        let mismatches = compare_graphs(&mut a, &mut c, 1e-6).unwrap();
+        assert!(mismatches.iter().any(|m| m.tensor_id == w && !m.grad));
+    }
+}
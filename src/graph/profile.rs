@@ -0,0 +1,109 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// Time spent computing the tensors of one kind of operation (See `Function::op_name`).
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct OpTime {
+    pub forward: Duration,
+    pub backward: Duration,
+    /// Number of forward runs
+    pub calls: usize,
+}
+
+impl OpTime {
+    pub fn total(&self) -> Duration {
+        self.forward + self.backward
+    }
+}
+
+/// Per-operation timings of the passes of a graph, recorded once `Graph::set_profiling` is
+/// enabled. CPU graphs measure the wall time of the functions, GPU graphs the time their kernels
+/// took on the device.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Profile {
+    ops: HashMap<String, OpTime>,
+}
+
+impl Profile {
+    pub fn record_forward(&mut self, op: String, elapsed: Duration) {
+        let time = self.ops.entry(op).or_default();
+        time.forward += elapsed;
+        time.calls += 1;
+    }
+    pub fn record_backward(&mut self, op: String, elapsed: Duration) {
+        self.ops.entry(op).or_default().backward += elapsed;
+    }
+    /// Adds the timings of `other`, e.g. the ones of another worker.
+    pub fn merge(&mut self, other: &Profile) {
+        for (op, time) in other.ops.iter() {
+            let sum = self.ops.entry(op.clone()).or_default();
+            sum.forward += time.forward;
+            sum.backward += time.backward;
+            sum.calls += time.calls;
+        }
+    }
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+    pub fn total(&self) -> Duration {
+        self.ops.values().map(OpTime::total).sum()
+    }
+    /// The operations, the most expensive first.
+    pub fn sorted(&self) -> Vec<(&str, OpTime)> {
+        let mut ops = self
+            .ops
+            .iter()
+            .map(|(op, time)| (op.as_str(), *time))
+            .collect::<Vec<_>>();
+        ops.sort_by(|a, b| b.1.total().cmp(&a.1.total()).then(a.0.cmp(b.0)));
+        ops
+    }
+}
+
+impl std::fmt::Display for Profile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let total = self.total().as_secs_f64().max(f64::MIN_POSITIVE);
+        writeln!(
+            f,
+            "{:<20} {:>7} {:>12} {:>12} {:>8}",
+            "Operation", "Share", "Forward", "Backward", "Calls"
+        )?;
+        for (op, time) in self.sorted() {
+            writeln!(
+                f,
+                "{:<20} {:>6.1}% {:>10.1}ms {:>10.1}ms {:>8}",
+                op,
+                time.total().as_secs_f64() / total * 100.,
+                time.forward.as_secs_f64() * 1000.,
+                time.backward.as_secs_f64() * 1000.,
+                time.calls
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_profile() {
+        let ms = Duration::from_millis;
+        let mut profile = Profile::default();
+        profile.record_forward("Softmax".into(), ms(10));
+        profile.record_forward("MatMul".into(), ms(20));
+        profile.record_backward("MatMul".into(), ms(40));
+        let mut other = Profile::default();
+        other.record_forward("MatMul".into(), ms(20));
+        profile.merge(&other);
+
+        let sorted = profile.sorted();
+        assert_eq!(sorted[0].0, "MatMul");
+        assert_eq!(sorted[0].1.calls, 2);
+        assert_eq!(sorted[0].1.total(), ms(80));
+        assert_eq!(profile.total(), ms(90));
+        let table = profile.to_string();
+        assert!(table.lines().nth(1).unwrap().contains("88.9%"));
+    }
+}
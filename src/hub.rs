@@ -0,0 +1,333 @@
+// Sharing bundles on the Hugging Face Hub. A femto model repository holds a bundle (See
+// `bundle`) under `BUNDLE_FILE`, uploaded through git LFS as the Hub wants for large files:
+// the bytes go to the storage the LFS batch API points to, then a commit adds the file by its
+// SHA-256. Pulling downloads it back through the `resolve` endpoint.
+
+use serde::Deserialize;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::io::Read;
+use std::path::PathBuf;
+use thiserror::Error;
+
+pub const HUB_ENDPOINT: &str = "https://huggingface.co";
+/// Name of the bundle in the repositories
+pub const BUNDLE_FILE: &str = "model.femto";
+
+#[derive(Error, Debug)]
+pub enum HubError {
+    #[error("invalid repository `{0}`, expected `user/model`")]
+    InvalidRepo(String),
+    #[error("pushing needs a Hugging Face token with write access")]
+    MissingToken,
+    #[error("{url} failed: {message}")]
+    Request {
+        url: String,
+        status: Option<u16>,
+        message: String,
+    },
+    #[error("unexpected response from {url}: {message}")]
+    InvalidResponse { url: String, message: String },
+}
+
+impl HubError {
+    /// Whether the Hub refused the token, or found no repository it gives access to.
+    pub fn is_unauthorized(&self) -> bool {
+        matches!(
+            self,
+            Self::Request {
+                status: Some(401 | 403 | 404),
+                ..
+            }
+        )
+    }
+}
+
+/// A client of the Hub, at `HF_ENDPOINT` (Or `HUB_ENDPOINT`) with the token of `HF_TOKEN` (Or
+/// the one `huggingface-cli login` saved).
+#[derive(Debug, Clone)]
+pub struct Hub {
+    endpoint: String,
+    token: Option<String>,
+    agent: ureq::Agent,
+}
+
+// Objects of the responses of the LFS batch API
+#[derive(Deserialize)]
+struct LfsBatch {
+    objects: Vec<LfsObject>,
+}
+
+#[derive(Deserialize)]
+struct LfsObject {
+    #[serde(default)]
+    actions: Option<LfsActions>,
+    #[serde(default)]
+    error: Option<LfsObjectError>,
+}
+
+#[derive(Deserialize)]
+struct LfsActions {
+    upload: Option<LfsAction>,
+    verify: Option<LfsAction>,
+}
+
+#[derive(Deserialize)]
+struct LfsAction {
+    href: String,
+    #[serde(default)]
+    header: std::collections::HashMap<String, String>,
+}
+
+#[derive(Deserialize)]
+struct LfsObjectError {
+    message: String,
+}
+
+/// Checks `repo` is a `namespace/name` id, returning both.
+pub fn split_repo(repo: &str) -> Result<(&str, &str), HubError> {
+    let valid = |part: &str| {
+        !part.is_empty()
+            && part
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c))
+    };
+    match repo.split_once('/') {
+        Some((namespace, name)) if valid(namespace) && valid(name) => Ok((namespace, name)),
+        _ => Err(HubError::InvalidRepo(repo.into())),
+    }
+}
+
+// The header and file lines of a commit adding the LFS object `oid` as `path`
+fn commit_payload(path: &str, oid: &str, size: usize, message: &str) -> String {
+    let lines = [
+        json!({"key": "header", "value": {"summary": message, "description": ""}}),
+        json!({"key": "lfsFile", "value": {"path": path, "algo": "sha256", "oid": oid, "size": size}}),
+    ];
+    lines.iter().map(|l| l.to_string() + "\n").collect()
+}
+
+// Where `huggingface-cli login` saves the token
+fn token_path() -> Option<PathBuf> {
+    match std::env::var_os("HF_HOME") {
+        Some(dir) if !dir.is_empty() => Some(PathBuf::from(dir).join("token")),
+        _ => Some(
+            PathBuf::from(std::env::var_os("HOME")?)
+                .join(".cache")
+                .join("huggingface")
+                .join("token"),
+        ),
+    }
+}
+
+impl Hub {
+    pub fn new(endpoint: &str, token: Option<String>) -> Self {
+        Self {
+            endpoint: endpoint.trim_end_matches('/').into(),
+            token,
+            agent: ureq::AgentBuilder::new()
+                .user_agent(concat!("femto/", env!("CARGO_PKG_VERSION")))
+                .build(),
+        }
+    }
+
+    pub fn from_env() -> Self {
+        let endpoint = std::env::var("HF_ENDPOINT").unwrap_or_else(|_| HUB_ENDPOINT.into());
+        let token = std::env::var("HF_TOKEN")
+            .ok()
+            .or_else(|| std::fs::read_to_string(token_path()?).ok())
+            .map(|t| t.trim().to_string())
+            .filter(|t| !t.is_empty());
+        Self::new(&endpoint, token)
+    }
+
+    // Writes to the Hub need a token, reads only for private repositories
+    fn require_token(&self) -> Result<(), HubError> {
+        match self.token {
+            Some(_) => Ok(()),
+            None => Err(HubError::MissingToken),
+        }
+    }
+
+    fn request(&self, method: &str, url: &str) -> ureq::Request {
+        let request = self.agent.request(method, url);
+        match &self.token {
+            Some(token) => request.set("Authorization", &format!("Bearer {}", token)),
+            None => request,
+        }
+    }
+
+    fn post_json(&self, url: &str, body: &serde_json::Value) -> Result<ureq::Response, HubError> {
+        let request = self
+            .request("POST", url)
+            .set("Content-Type", "application/json");
+        Self::send(url, request.send_string(&body.to_string()))
+    }
+
+    // Turns the error statuses of a request into `HubError`s, with the message of the Hub
+    fn send(
+        url: &str,
+        result: Result<ureq::Response, ureq::Error>,
+    ) -> Result<ureq::Response, HubError> {
+        result.map_err(|e| match e {
+            ureq::Error::Status(status, response) => {
+                let body = response.into_string().unwrap_or_default();
+                // The Hub explains its errors as `{"error": "..."}`
+                let message = serde_json::from_str::<serde_json::Value>(&body)
+                    .ok()
+                    .and_then(|v| v["error"].as_str().map(String::from))
+                    .unwrap_or(body);
+                HubError::Request {
+                    url: url.into(),
+                    status: Some(status),
+                    message: format!("{} {}", status, message.trim()),
+                }
+            }
+            // Their message would repeat the URL
+            ureq::Error::Transport(e) => HubError::Request {
+                url: url.into(),
+                status: None,
+                message: e
+                    .message()
+                    .map_or_else(|| e.kind().to_string(), String::from),
+            },
+        })
+    }
+
+    fn read_json<T: serde::de::DeserializeOwned>(
+        url: &str,
+        response: ureq::Response,
+    ) -> Result<T, HubError> {
+        serde_json::from_reader(response.into_reader()).map_err(|e| HubError::InvalidResponse {
+            url: url.into(),
+            message: e.to_string(),
+        })
+    }
+
+    /// Creates the model repository `repo`, if it doesn't exist yet.
+    pub fn create_repo(&self, repo: &str, private: bool) -> Result<(), HubError> {
+        let (namespace, name) = split_repo(repo)?;
+        self.require_token()?;
+        let url = format!("{}/api/repos/create", self.endpoint);
+        let body = json!({"name": name, "organization": namespace, "private": private});
+        match self.post_json(&url, &body) {
+            // The repository exists already
+            Err(HubError::Request {
+                status: Some(409), ..
+            }) => Ok(()),
+            result => result.map(|_| ()),
+        }
+    }
+
+    /// Uploads `bundle` to `repo` as `BUNDLE_FILE`, in a commit of `message` on the main branch.
+    pub fn push(&self, repo: &str, bundle: &[u8], message: &str) -> Result<(), HubError> {
+        split_repo(repo)?;
+        self.require_token()?;
+        let oid = format!("{:x}", Sha256::digest(bundle));
+
+        let url = format!("{}/{}.git/info/lfs/objects/batch", self.endpoint, repo);
+        let body = json!({
+            "operation": "upload",
+            "transfers": ["basic"],
+            "objects": [{"oid": oid, "size": bundle.len()}],
+            "hash_algo": "sha256",
+        });
+        let response = Self::send(
+            &url,
+            self.request("POST", &url)
+                .set("Accept", "application/vnd.git-lfs+json")
+                .set("Content-Type", "application/vnd.git-lfs+json")
+                .send_string(&body.to_string()),
+        )?;
+        let batch: LfsBatch = Self::read_json(&url, response)?;
+        let object = batch
+            .objects
+            .into_iter()
+            .next()
+            .ok_or_else(|| HubError::InvalidResponse {
+                url: url.clone(),
+                message: "no LFS object".into(),
+            })?;
+        if let Some(error) = object.error {
+            return Err(HubError::Request {
+                url,
+                status: None,
+                message: error.message,
+            });
+        }
+        // Without actions, the Hub has the object already
+        if let Some(actions) = object.actions {
+            if let Some(upload) = actions.upload {
+                // The storage authenticates the upload by its own headers
+                let mut request = self.agent.put(&upload.href);
+                for (k, v) in upload.header.iter() {
+                    request = request.set(k, v);
+                }
+                Self::send(&upload.href, request.send_bytes(bundle))?;
+            }
+            if let Some(verify) = actions.verify {
+                let mut request = self
+                    .request("POST", &verify.href)
+                    .set("Content-Type", "application/json");
+                for (k, v) in verify.header.iter() {
+                    request = request.set(k, v);
+                }
+                let body = json!({"oid": oid, "size": bundle.len()});
+                Self::send(&verify.href, request.send_string(&body.to_string()))?;
+            }
+        }
+
+        let url = format!("{}/api/models/{}/commit/main", self.endpoint, repo);
+        Self::send(
+            &url,
+            self.request("POST", &url)
+                .set("Content-Type", "application/x-ndjson")
+                .send_string(&commit_payload(BUNDLE_FILE, &oid, bundle.len(), message)),
+        )?;
+        Ok(())
+    }
+
+    /// Downloads the bundle of `repo`, at `revision` (A branch, tag or commit).
+    pub fn pull(&self, repo: &str, revision: &str) -> Result<Vec<u8>, HubError> {
+        split_repo(repo)?;
+        let url = format!(
+            "{}/{}/resolve/{}/{}",
+            self.endpoint, repo, revision, BUNDLE_FILE
+        );
+        let response = Self::send(&url, self.request("GET", &url).call())?;
+        let mut bytes = Vec::new();
+        response
+            .into_reader()
+            .read_to_end(&mut bytes)
+            .map_err(|e| HubError::Request {
+                url,
+                status: None,
+                message: e.to_string(),
+            })?;
+        Ok(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_repo_and_commit() {
+        assert_eq!(split_repo("user/tiny-gpt").unwrap(), ("user", "tiny-gpt"));
+        for repo in ["tiny-gpt", "user/", "/tiny-gpt", "a/b/c", "user/tiny gpt"] {
+            assert!(split_repo(repo).is_err(), "{}", repo);
+        }
+
+        let payload = commit_payload(BUNDLE_FILE, "ab12", 42, "Upload");
+        let lines = payload
+            .lines()
+            .map(|l| serde_json::from_str::<serde_json::Value>(l).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["value"]["summary"], "Upload");
+        assert_eq!(lines[1]["key"], "lfsFile");
+        assert_eq!(lines[1]["value"]["path"], BUNDLE_FILE);
+        assert_eq!(lines[1]["value"]["size"], 42);
+    }
+}
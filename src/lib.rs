@@ -1,6 +1,18 @@
+pub mod bundle;
+pub mod checkpoint;
+pub mod classifier;
+pub mod constraint;
+pub mod datasets;
+pub mod diagnostics;
+pub mod error;
+pub mod export;
 pub mod funcs;
 pub mod gpt;
 pub mod graph;
+#[cfg(feature = "hub")]
+pub mod hub;
 pub mod optimizer;
+pub mod prelude;
+pub mod sampler;
 pub mod tensor;
 pub mod tokenizer;
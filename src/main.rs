@@ -1,22 +1,174 @@
-use femto_gpt::gpt::{TrainingState, GPT};
-use femto_gpt::graph::GraphError;
-use femto_gpt::optimizer::AdamW;
-use femto_gpt::tokenizer::{SentencePieceTokenizer, Tokenizer};
+use femto_gpt::bundle::{
+    load_bundle, save_bundle, Bundle, BundleConfig, BundledTokenizer, BUNDLE_TOKENIZER_KEY,
+};
+use femto_gpt::checkpoint::{
+    average_training_states, checkpoint_version, decompress, fnv1a, load_training_state,
+    save_compressed_training_state, save_training_state, write_atomic, Checkpoint, CheckpointError,
+    ZSTD_MAGIC,
+};
+use femto_gpt::classifier::{
+    load_classifier, parse_labeled_csv, parse_labeled_jsonl, save_classifier, Classifier,
+};
+use femto_gpt::constraint::{token_pieces, Constraint, RegexConstraint};
+use femto_gpt::datasets::ExampleDataset;
+use femto_gpt::diagnostics::GenerationStats;
+use femto_gpt::error::FemtoError;
+use femto_gpt::export::{
+    gpt2_training_state, read_npz, read_safetensors, write_gguf, write_npy, write_safetensors,
+    ExportError, ExportFormat, ModelShape,
+};
+use femto_gpt::gpt::{
+    find_batch_size, Architecture, BackwardScope, BeamParams, Budget, ContextOverflow,
+    ContextStage, ContrastiveConfig, DistillConfig, DpoConfig, GptBuilder, GptError, InferParams,
+    LoraConfig, LrSchedule, MemoryEstimate, Pooling, Preference, Progress, QuantizedState, Rerank,
+    SpikeGuard, TrainConfig, TrainingState, GPT,
+};
+use femto_gpt::graph::{dump_graph, gradcheck_ops, AnyGraph, Backend, DumpFormat, Graph};
+use femto_gpt::optimizer::{AdamW, AnyOptimizer, ParamGroup};
+use femto_gpt::sampler::{Completions, Corpus, Masking, Mixture, Padded, Sampling};
+use femto_gpt::tensor::{set_matmul_backend, MatMulBackend, Precision, QuantFormat, TensorOps};
+use femto_gpt::tokenizer::{DatasetStats, HuggingFaceTokenizer, SentencePieceTokenizer, Tokenizer};
+use serde::Deserialize;
+use std::collections::HashMap;
 use std::fs;
-use std::io::prelude::*;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
 use structopt::StructOpt;
+use tracing::{info, warn};
+
+#[derive(StructOpt, Debug)]
+struct Opts {
+    /// Graph implementation: `cpu` or `opencl` (Defaults to the GPU when compiled in, falling
+    /// back to the CPU if it can't be initialized)
+    #[structopt(long, global = true)]
+    backend: Option<Backend>,
+    /// Index of the GPU to use, as listed by `devices` (Defaults to `FEMTO_DEVICE`)
+    #[structopt(long, global = true)]
+    device: Option<usize>,
+    /// Verbosity of the logs (e.g. of the training steps): `error`, `warn`, `info`, `debug` or
+    /// `trace`
+    #[structopt(long, global = true, default_value = "info")]
+    log_level: tracing::Level,
+    /// Print the logs as JSON lines, e.g. for log collectors
+    #[structopt(long, global = true)]
+    log_json: bool,
+    #[structopt(subcommand)]
+    cli: Cli,
+}
 
 #[derive(StructOpt, Debug)]
 enum Cli {
     Train {
+        /// Repeat it to mix several datasets, weighting them like `a.txt:0.7` (Default weight 1).
+        /// A `.jsonl` dataset of `{"prompt": .., "completion": ..}` lines only trains on the
+        /// completions, and can't be mixed
         #[structopt(long, default_value = "dataset.txt")]
-        dataset: PathBuf,
+        dataset: Vec<WeightedDataset>,
         #[structopt(long, default_value = "vocab_file.vocab")]
         vocab: PathBuf,
         #[structopt(long, default_value = "training_state.dat")]
         model: PathBuf,
+        /// Label-smoothing epsilon of the cross-entropy loss
+        #[structopt(long, default_value = "0.0")]
+        label_smoothing: f32,
+        /// Coefficient of the auxiliary z-loss term (0 disables it)
+        #[structopt(long, default_value = "0.0")]
+        z_loss: f32,
+        /// How far the backward pass goes: `full`, `params-only` or `last-layers:<n>`
+        #[structopt(long, default_value = "full")]
+        backward_scope: BackwardScope,
+        /// Number of data-parallel CPU workers (0 uses all available cores)
+        #[structopt(long, default_value = "0")]
+        threads: usize,
+        /// CPU matrix multiplication implementation: `native` or `blas`
+        #[structopt(long)]
+        matmul_backend: Option<MatMulBackend>,
+        /// Precision of activations and gradients: `f32`, `f16` or `bf16`
+        #[structopt(long, default_value = "f32")]
+        precision: Precision,
+        /// Stop with an error naming the first tensor that becomes NaN/Inf (Slow)
+        #[structopt(long)]
+        detect_anomaly: bool,
+        /// Split each batch across these GPUs, e.g. `0,1` (Overrides `--device`)
+        #[structopt(long, use_delimiter = true)]
+        devices: Vec<usize>,
+        /// How windows are drawn from the dataset: `epochs` or `random`
+        #[structopt(long, default_value = "epochs")]
+        sampling: Sampling,
+        /// Text of the token separating the documents of the dataset, e.g. `<|endoftext|>`; tokens
+        /// then don't attend to the ones of other documents
+        #[structopt(long)]
+        document_separator: Option<String>,
+        /// Text of a padding token: documents are then trained on one per window, padded rather
+        /// than packed (Requires `--document-separator`)
+        #[structopt(long)]
+        pad_token: Option<String>,
+        /// Train with shorter contexts first, e.g. `16:2000,32:2000` for 2000 batches of
+        /// 16-token windows, then 2000 of 32-token ones, before the full context
+        #[structopt(long, use_delimiter = true)]
+        curriculum: Vec<ContextStage>,
+        /// What the model learns: `causal` (Predicting the next token) or `masked-lm` (Predicting
+        /// hidden tokens from both sides, like BERT)
+        #[structopt(long, default_value = "causal")]
+        objective: Objective,
+        /// Text of the token hiding the masked tokens, with `--objective masked-lm`
+        #[structopt(long, default_value = "<mask>")]
+        mask_token: String,
+        /// Fraction of the tokens masked language models predict
+        #[structopt(long, default_value = "0.15")]
+        mask_rate: f32,
+        /// Checkpoint of a (Larger) model to distill into this one, trained on its logits too
+        #[structopt(long)]
+        teacher: Option<PathBuf>,
+        /// Temperature softening the distributions of the teacher and the student
+        #[structopt(long, default_value = "2.0")]
+        distill_temperature: f32,
+        /// Weight of the KL divergence from the teacher in the loss, the cross-entropy getting
+        /// the rest
+        #[structopt(long, default_value = "0.5")]
+        distill_alpha: f32,
+        /// Training config file (JSON), see `ConfigFile`
+        #[structopt(long)]
+        config: Option<PathBuf>,
+        /// Compress the saved checkpoints with zstd at this level (1 to 22), loading detects it
+        #[structopt(long)]
+        compress_level: Option<i32>,
+        /// `adamw`, or `adafactor` which keeps much less state for larger models
+        #[structopt(long, default_value = "adamw")]
+        optimizer: AnyOptimizer,
+        /// Decoupled weight decay of the parameters
+        #[structopt(long, default_value = "0.01")]
+        weight_decay: f32,
+        /// Weight decay the biases and normalization coefficients too, which are exempted by
+        /// default
+        #[structopt(long)]
+        decay_all_params: bool,
+        /// Print the norm of the gradients (And their noise scale, when training on several
+        /// workers or devices) every this many steps
+        #[structopt(long)]
+        grad_stats_every: Option<usize>,
+        /// Roll back to the last good state when the loss goes above this many times its moving
+        /// average (Or NaN/Inf), halving the learning rate for 100 steps
+        #[structopt(long)]
+        spike_threshold: Option<f32>,
+        /// Train for this many batches only, then print the time spent in each operation (Kernels
+        /// on GPUs), forward and backward
+        #[structopt(long)]
+        profile: Option<usize>,
+        /// Sequences per batch, or `auto` for the most that fit in the memory of the GPU, found
+        /// by trial training steps
+        #[structopt(long, default_value = "32")]
+        batch_size: BatchSize,
+        /// Stop once the model has trained on this many tokens, e.g. `50M`, counting the runs
+        /// whose checkpoint it resumed
+        #[structopt(long)]
+        train_tokens: Option<TokenCount>,
+        /// Stop once the model has trained for this many hours, counting the runs whose checkpoint
+        /// it resumed
+        #[structopt(long)]
+        train_hours: Option<f32>,
     },
     Infer {
         #[structopt(long, default_value = "dataset.txt")]
@@ -25,215 +177,3092 @@ enum Cli {
         vocab: PathBuf,
         #[structopt(long, default_value = "training_state.dat")]
         model: PathBuf,
+        /// Text to continue, repeat it to continue several prompts as a batch. `-` reads it from
+        /// the standard input
+        #[structopt(long, required_unless = "prompt-file")]
+        prompt: Vec<String>,
+        /// File holding a prompt, continued after the ones of `--prompt`. Repeat it for several
         #[structopt(long)]
-        prompt: String,
+        prompt_file: Vec<PathBuf>,
         #[structopt(long, default_value = "100")]
         count: usize,
         #[structopt(long, default_value = "0.5")]
         temperature: f32,
+        /// Temperatures of the first generated tokens, before `--temperature`, e.g. `10:1.0,20:0.7`
+        /// for 10 tokens at 1.0 then 20 at 0.7
+        #[structopt(long, use_delimiter = true)]
+        temperature_schedule: Vec<TemperatureStage>,
+        /// Sample among this many of the most likely tokens only
+        #[structopt(long)]
+        top_k: Option<usize>,
+        /// Sample among the most likely tokens whose probabilities add up to this only
+        #[structopt(long)]
+        top_p: Option<f32>,
+        /// Above 1, discourages repeating the tokens already in the text
+        #[structopt(long, default_value = "1.0")]
+        repetition_penalty: f32,
+        /// Text ending the generation once generated, repeat it for several
+        #[structopt(long)]
+        stop: Vec<String>,
+        /// Seed of the sampling, the same prompts, model and seed generating the same text
+        #[structopt(long)]
+        sample_seed: Option<u64>,
+        /// What to do with prompts longer than the context: `error`, `truncate-left` or
+        /// `truncate-right` to drop their first or last tokens, or `sliding` to keep them whole,
+        /// the model only seeing their last tokens
+        #[structopt(long, default_value = "error")]
+        context_overflow: ContextOverflow,
+        /// Regular expression the generated text (After the prompt) has to match
+        #[structopt(long)]
+        regex: Option<String>,
+        /// Classifier-free guidance, above 1 following the prompt more closely by comparing the
+        /// model's predictions with and without it (Which takes two passes per token)
+        #[structopt(long, default_value = "1.0")]
+        guidance_scale: f32,
+        /// Text the guidance steers away from, in place of the prompt (Defaults to its last token)
+        #[structopt(long)]
+        negative_prompt: Option<String>,
+        /// Generate the last token of the prompt again, among the ones starting with its text, so
+        /// that prompts ending in the middle of a word are continued as the model would
+        #[structopt(long, conflicts_with = "regex")]
+        token_healing: bool,
+        /// Decode with a beam search keeping this many sequences, instead of sampling, and print
+        /// the best ones with their scores
+        #[structopt(long)]
+        beam_size: Option<usize>,
+        /// Exponent of the length normalization of beam search scores
+        #[structopt(long, default_value = "1.0")]
+        length_penalty: f32,
+        /// Sample this many continuations of each prompt, in the same batches, and only print
+        /// the best one according to `--rerank`
+        #[structopt(long, conflicts_with_all = &["beam-size", "logprobs"])]
+        best_of: Option<usize>,
+        /// How `--best-of` picks a continuation: `logprob` for the highest average
+        /// log-probability, or `repetition` for the fewest repeated trigrams
+        #[structopt(long, default_value = "logprob")]
+        rerank: Rerank,
+        /// Print the log-probability of every token, and of this many alternatives at its position
+        #[structopt(long)]
+        logprobs: Option<usize>,
+        /// LoRA adapter checkpoint to load on top of the base model
+        #[structopt(long)]
+        adapter: Option<PathBuf>,
+        /// The model is a quantized checkpoint produced by `quantize`
+        #[structopt(long, conflicts_with = "adapter")]
+        quantized: bool,
+        #[structopt(long, default_value = "8")]
+        lora_rank: usize,
+        #[structopt(long, default_value = "16")]
+        lora_alpha: f32,
+        /// CPU matrix multiplication implementation: `native` or `blas`
+        #[structopt(long)]
+        matmul_backend: Option<MatMulBackend>,
+        /// Model layout: `femto`, or `gpt2` for checkpoints produced by `import-gpt2`
+        #[structopt(long, default_value = "femto", conflicts_with = "quantized")]
+        architecture: Architecture,
+        /// Hugging Face `tokenizer.json` to use instead of the SentencePiece vocabulary
+        #[structopt(long)]
+        hf_tokenizer: Option<PathBuf>,
+        /// `text`, or `json` to print a JSON object per prompt (With the token ids, and the
+        /// perplexity along with `--logprobs`), any other message going to the standard error
+        #[structopt(long, default_value = "text")]
+        output: OutputFormat,
+    },
+    /// Sample continuations of prompts and report how varied they are (Distinct n-grams,
+    /// repetitions and self-BLEU) and the entropy of the sampling, to compare sampling settings
+    EvalGen {
+        /// Prompts to continue, one per line
+        #[structopt(long)]
+        prompts: PathBuf,
+        #[structopt(long, default_value = "vocab_file.vocab")]
+        vocab: PathBuf,
+        #[structopt(long, default_value = "training_state.dat")]
+        model: PathBuf,
+        /// Continuations sampled for each prompt
+        #[structopt(long, default_value = "1")]
+        samples: usize,
+        #[structopt(long, default_value = "100")]
+        count: usize,
+        #[structopt(long, default_value = "0.5")]
+        temperature: f32,
+        /// Sample among this many of the most likely tokens only
+        #[structopt(long)]
+        top_k: Option<usize>,
+        /// Sample among the most likely tokens whose probabilities add up to this only
+        #[structopt(long)]
+        top_p: Option<f32>,
+        /// Above 1, discourages repeating the tokens already in the text
+        #[structopt(long, default_value = "1.0")]
+        repetition_penalty: f32,
+        /// Seed of the sampling, the same prompts, model and seed generating the same text
+        #[structopt(long)]
+        sample_seed: Option<u64>,
+        /// Model layout: `femto`, or `gpt2` for checkpoints produced by `import-gpt2`
+        #[structopt(long, default_value = "femto")]
+        architecture: Architecture,
+        /// Hugging Face `tokenizer.json` to use instead of the SentencePiece vocabulary
+        #[structopt(long)]
+        hf_tokenizer: Option<PathBuf>,
+        /// `text`, or `json` to print the statistics as a JSON object
+        #[structopt(long, default_value = "text")]
+        output: OutputFormat,
+    },
+    /// Print the embedding of each prompt (The pooled hidden state of the last layer)
+    Embed {
+        #[structopt(long, default_value = "vocab_file.vocab")]
+        vocab: PathBuf,
+        #[structopt(long, default_value = "training_state.dat")]
+        model: PathBuf,
+        /// Text to embed, repeat it to embed several ones. `-` reads it from the standard input
+        #[structopt(long, required_unless = "prompt-file")]
+        prompt: Vec<String>,
+        /// File holding a text to embed after the ones of `--prompt`. Repeat it for several
+        #[structopt(long)]
+        prompt_file: Vec<PathBuf>,
+        /// How the states of the tokens are combined: `mean` or `last`
+        #[structopt(long, default_value = "mean")]
+        pooling: Pooling,
+        /// Model layout: `femto`, or `gpt2` for checkpoints produced by `import-gpt2`
+        #[structopt(long, default_value = "femto")]
+        architecture: Architecture,
+        /// Hugging Face `tokenizer.json` to use instead of the SentencePiece vocabulary
+        #[structopt(long)]
+        hf_tokenizer: Option<PathBuf>,
     },
+    /// Fine-tune a model for `embed` on `{"anchor", "positive"}` JSONL lines, pairs of texts whose
+    /// embeddings are pulled together while the ones of the other pairs are pushed apart
+    EmbedTrain {
+        #[structopt(long)]
+        dataset: PathBuf,
+        #[structopt(long, default_value = "vocab_file.vocab")]
+        vocab: PathBuf,
+        #[structopt(long, default_value = "training_state.dat")]
+        model: PathBuf,
+        /// Where the fine-tuned model is saved (Defaults to overwriting `--model`), as a bundle
+        /// when `--model` is one
+        #[structopt(long)]
+        out: Option<PathBuf>,
+        /// How the states of the tokens are combined: `mean` or `last`
+        #[structopt(long, default_value = "mean")]
+        pooling: Pooling,
+        /// Temperature dividing the cosine similarities of the embeddings
+        #[structopt(long, default_value = "0.05")]
+        temperature: f32,
+        /// Pairs per step, each anchor having the positives of the others as negatives
+        #[structopt(long, default_value = "16")]
+        batch_size: usize,
+        #[structopt(long, default_value = "1000")]
+        steps: usize,
+        #[structopt(long, default_value = "0.0001")]
+        learning_rate: f32,
+        /// How far the backward pass goes: `full`, `params-only` or `last-layers:<n>`
+        #[structopt(long, default_value = "full")]
+        backward_scope: BackwardScope,
+        /// Model layout: `femto`, or `gpt2` for checkpoints produced by `import-gpt2`
+        #[structopt(long, default_value = "femto")]
+        architecture: Architecture,
+        /// Hugging Face `tokenizer.json` to use instead of the SentencePiece vocabulary
+        #[structopt(long)]
+        hf_tokenizer: Option<PathBuf>,
+    },
+    /// Dump the attention weights of every head for a prompt, as JSON or as a `.npy` array of
+    /// shape `[layers, heads, tokens, tokens]` (`[heads, tokens, tokens]` with `--layer`)
+    Attention {
+        #[structopt(long, default_value = "vocab_file.vocab")]
+        vocab: PathBuf,
+        #[structopt(long, default_value = "training_state.dat")]
+        model: PathBuf,
+        #[structopt(long)]
+        prompt: String,
+        /// Only dump the weights of this layer (Starting from 0)
+        #[structopt(long)]
+        layer: Option<usize>,
+        /// Output file, NumPy's format when it ends with `.npy` and JSON otherwise (Printed when
+        /// not set)
+        #[structopt(long)]
+        out: Option<PathBuf>,
+        /// Model layout: `femto`, or `gpt2` for checkpoints produced by `import-gpt2`
+        #[structopt(long, default_value = "femto")]
+        architecture: Architecture,
+        /// Hugging Face `tokenizer.json` to use instead of the SentencePiece vocabulary
+        #[structopt(long)]
+        hf_tokenizer: Option<PathBuf>,
+    },
+    /// Train a classification head on the embeddings of a frozen model, from `text,label` CSV
+    /// rows or `{"text", "label"}` JSONL lines
+    ClassifyTrain {
+        /// Labeled texts, JSONL if the file ends with `.jsonl` and CSV otherwise
+        #[structopt(long)]
+        dataset: PathBuf,
+        #[structopt(long, default_value = "vocab_file.vocab")]
+        vocab: PathBuf,
+        #[structopt(long, default_value = "training_state.dat")]
+        model: PathBuf,
+        #[structopt(long, default_value = "classifier.dat")]
+        out: PathBuf,
+        /// How the states of the tokens are combined: `mean` or `last`
+        #[structopt(long, default_value = "mean")]
+        pooling: Pooling,
+        /// Optimizer steps, each on all the examples
+        #[structopt(long, default_value = "200")]
+        epochs: usize,
+        #[structopt(long, default_value = "0.01")]
+        learning_rate: f32,
+        /// Share of the examples held out to measure the accuracy on
+        #[structopt(long, default_value = "0.1")]
+        holdout: f32,
+        /// Model layout: `femto`, or `gpt2` for checkpoints produced by `import-gpt2`
+        #[structopt(long, default_value = "femto")]
+        architecture: Architecture,
+        /// Hugging Face `tokenizer.json` to use instead of the SentencePiece vocabulary
+        #[structopt(long)]
+        hf_tokenizer: Option<PathBuf>,
+    },
+    /// Label texts with a head trained by `classify-train`, printing the label and its probability
+    Classify {
+        #[structopt(long, default_value = "vocab_file.vocab")]
+        vocab: PathBuf,
+        #[structopt(long, default_value = "training_state.dat")]
+        model: PathBuf,
+        #[structopt(long, default_value = "classifier.dat")]
+        classifier: PathBuf,
+        /// Text to classify, repeat it to classify several ones. `-` reads it from the standard
+        /// input
+        #[structopt(long, required_unless = "prompt-file")]
+        prompt: Vec<String>,
+        /// File holding a text to classify after the ones of `--prompt`. Repeat it for several
+        #[structopt(long)]
+        prompt_file: Vec<PathBuf>,
+        /// Model layout: `femto`, or `gpt2` for checkpoints produced by `import-gpt2`
+        #[structopt(long, default_value = "femto")]
+        architecture: Architecture,
+        /// Hugging Face `tokenizer.json` to use instead of the SentencePiece vocabulary
+        #[structopt(long)]
+        hf_tokenizer: Option<PathBuf>,
+    },
+    /// Fine-tune low-rank adapters on top of a frozen base model
+    Finetune {
+        /// Text, or `.jsonl` prompt/completion pairs whose completions (Ending with a stop token
+        /// of their own) are the only targets
+        #[structopt(long, default_value = "dataset.txt")]
+        dataset: PathBuf,
+        #[structopt(long, default_value = "vocab_file.vocab")]
+        vocab: PathBuf,
+        #[structopt(long, default_value = "training_state.dat")]
+        model: PathBuf,
+        #[structopt(long, default_value = "lora_adapter.dat")]
+        adapter: PathBuf,
+        #[structopt(long, default_value = "8")]
+        lora_rank: usize,
+        #[structopt(long, default_value = "16")]
+        lora_alpha: f32,
+        /// Model layout: `femto`, or `gpt2` for checkpoints produced by `import-gpt2`
+        #[structopt(long, default_value = "femto")]
+        architecture: Architecture,
+        /// Hugging Face `tokenizer.json` to use instead of the SentencePiece vocabulary
+        #[structopt(long)]
+        hf_tokenizer: Option<PathBuf>,
+        /// Compress the saved adapters with zstd at this level (1 to 22), loading detects it
+        #[structopt(long)]
+        compress_level: Option<i32>,
+    },
+    /// Align a model with preferences by direct preference optimization (DPO), from
+    /// `{"prompt", "chosen", "rejected"}` JSONL lines: the model learns to make the chosen
+    /// completions more likely than the rejected ones, relative to a frozen reference model
+    Dpo {
+        #[structopt(long)]
+        dataset: PathBuf,
+        #[structopt(long, default_value = "vocab_file.vocab")]
+        vocab: PathBuf,
+        #[structopt(long, default_value = "training_state.dat")]
+        model: PathBuf,
+        /// Where the trained model is saved (Defaults to overwriting `--model`), as a bundle when
+        /// `--model` is one
+        #[structopt(long)]
+        out: Option<PathBuf>,
+        /// Model the log-probabilities are compared to (Defaults to `--model`, before training)
+        #[structopt(long)]
+        reference: Option<PathBuf>,
+        /// How far the model may drift from the reference, lower values letting it go further
+        #[structopt(long, default_value = "0.1")]
+        beta: f32,
+        /// Examples per step
+        #[structopt(long, default_value = "8")]
+        batch_size: usize,
+        #[structopt(long, default_value = "1000")]
+        steps: usize,
+        #[structopt(long, default_value = "0.0001")]
+        learning_rate: f32,
+        /// How far the backward pass goes: `full`, `params-only` or `last-layers:<n>`
+        #[structopt(long, default_value = "full")]
+        backward_scope: BackwardScope,
+        /// Model layout: `femto`, or `gpt2` for checkpoints produced by `import-gpt2`
+        #[structopt(long, default_value = "femto")]
+        architecture: Architecture,
+        /// Hugging Face `tokenizer.json` to use instead of the SentencePiece vocabulary
+        #[structopt(long)]
+        hf_tokenizer: Option<PathBuf>,
+    },
+    /// Fold a LoRA adapter into its base model, producing a regular checkpoint
+    MergeLora {
+        #[structopt(long, default_value = "vocab_file.vocab")]
+        vocab: PathBuf,
+        #[structopt(long, default_value = "training_state.dat")]
+        model: PathBuf,
+        #[structopt(long, default_value = "lora_adapter.dat")]
+        adapter: PathBuf,
+        #[structopt(long, default_value = "8")]
+        lora_rank: usize,
+        #[structopt(long, default_value = "16")]
+        lora_alpha: f32,
+        #[structopt(long)]
+        out: PathBuf,
+    },
+    /// Convert a trained model into a quantized checkpoint for inference
+    Quantize {
+        #[structopt(long, default_value = "vocab_file.vocab")]
+        vocab: PathBuf,
+        #[structopt(long, default_value = "training_state.dat")]
+        model: PathBuf,
+        #[structopt(long, default_value = "model_q8.dat")]
+        out: PathBuf,
+        /// Quantization format: `q8` (8-bit, per-row scales) or `q4` (4-bit, blocks of 32)
+        #[structopt(long, default_value = "q8")]
+        format: QuantFormat,
+    },
+    /// Print what a checkpoint holds (Format version, metadata, model dimensions, tensors and
+    /// optimizer state), without building the model
+    Inspect {
+        #[structopt(long, default_value = "training_state.dat")]
+        model: PathBuf,
+    },
+    /// Add or remove transformer layers of a checkpoint, the optimizer state is reset. Layers
+    /// are removed from the end, added ones initialized at random or as copies of the last layer
+    Resize {
+        #[structopt(long, default_value = "training_state.dat")]
+        model: PathBuf,
+        #[structopt(long)]
+        layers: usize,
+        /// `random` or `copy-last`
+        #[structopt(long, default_value = "random")]
+        init_new: LayerInit,
+        /// Defaults to overwriting the checkpoint
+        #[structopt(long)]
+        out: Option<PathBuf>,
+    },
+    /// Extend a trained model to a larger vocabulary, whose first tokens must be those of the
+    /// model's. Embeddings and output weights of the new tokens start as the mean of the existing
+    /// ones, the optimizer state is reset
+    ExtendVocab {
+        #[structopt(long, default_value = "training_state.dat")]
+        model: PathBuf,
+        /// The new vocabulary
+        #[structopt(long, default_value = "vocab_file.vocab")]
+        vocab: PathBuf,
+        /// Hugging Face `tokenizer.json` to use instead of the SentencePiece vocabulary
+        #[structopt(long)]
+        hf_tokenizer: Option<PathBuf>,
+        /// Defaults to overwriting the checkpoint
+        #[structopt(long)]
+        out: Option<PathBuf>,
+    },
+    /// Average the weights of checkpoints of the same model ("Model soup"), which often samples
+    /// better than each of them
+    Merge {
+        #[structopt(long, required = true)]
+        models: Vec<PathBuf>,
+        /// Weight of each model, e.g. `0.7,0.3` (Normalized, defaults to a plain average)
+        #[structopt(long, use_delimiter = true)]
+        weights: Vec<f32>,
+        #[structopt(long)]
+        out: PathBuf,
+    },
+    /// Export a trained model for use by other runtimes
+    Export {
+        #[structopt(long, default_value = "vocab_file.vocab")]
+        vocab: PathBuf,
+        #[structopt(long, default_value = "training_state.dat")]
+        model: PathBuf,
+        #[structopt(long)]
+        out: PathBuf,
+        /// Output file format: `gguf` or `safetensors`
+        #[structopt(long, default_value = "gguf")]
+        format: ExportFormat,
+        /// Write a femto checkpoint of the weights only, without the optimizer state (About a
+        /// third of the size), instead of a `--format` file
+        #[structopt(long)]
+        weights_only: bool,
+    },
+    /// Package the weights of a model with its tokenizer and dimensions into a single file, which
+    /// is accepted wherever a model is, without `--vocab` or `--hf-tokenizer`
+    ExportBundle {
+        #[structopt(long, default_value = "vocab_file.vocab")]
+        vocab: PathBuf,
+        /// Hugging Face `tokenizer.json` to bundle instead of the SentencePiece vocabulary
+        #[structopt(long)]
+        hf_tokenizer: Option<PathBuf>,
+        #[structopt(long, default_value = "training_state.dat")]
+        model: PathBuf,
+        #[structopt(long, default_value = "model.femto")]
+        out: PathBuf,
+    },
+    /// Convert pretrained GPT-2 weights (`.safetensors` or `.npz`) into a femto checkpoint
+    ImportGpt2 {
+        #[structopt(long)]
+        weights: PathBuf,
+        #[structopt(long, default_value = "gpt2.dat")]
+        out: PathBuf,
+        #[structopt(long, default_value = "12")]
+        num_heads: usize,
+        /// Context length, at most 1024
+        #[structopt(long, default_value = "1024")]
+        num_tokens: usize,
+    },
+    /// Report how a dataset tokenizes: token counts, coverage of the vocabulary, characters the
+    /// tokenizer can't represent and the most frequent tokens
+    DatasetStats {
+        #[structopt(long, default_value = "dataset.txt")]
+        dataset: PathBuf,
+        #[structopt(long, default_value = "vocab_file.vocab")]
+        vocab: PathBuf,
+        /// Hugging Face `tokenizer.json` to use instead of the SentencePiece vocabulary
+        #[structopt(long)]
+        hf_tokenizer: Option<PathBuf>,
+        /// Number of most frequent tokens listed
+        #[structopt(long, default_value = "20")]
+        top: usize,
+    },
+    /// Print the computation graph of the model (tensor shapes, operations and their inputs)
+    GraphDump {
+        #[structopt(long, default_value = "vocab_file.vocab")]
+        vocab: PathBuf,
+        /// Output format: `dot` (Graphviz) or `text`
+        #[structopt(long, default_value = "dot")]
+        format: DumpFormat,
+        #[structopt(long, default_value = "femto")]
+        architecture: Architecture,
+    },
+    /// Check the gradients of all ops against finite differences, and with `--gpu` that a GPU
+    /// training step agrees with the same step on the CPU
+    Selftest {
+        #[structopt(long)]
+        gpu: bool,
+        /// Largest relative difference allowed between CPU and GPU tensors
+        #[structopt(long, default_value = "1e-3")]
+        tolerance: f32,
+        #[structopt(long, default_value = "0")]
+        seed: u64,
+    },
+    /// Time the training steps of a randomly initialized model on the selected backend
+    Bench {
+        #[structopt(long, default_value = "64")]
+        vocab_size: usize,
+        #[structopt(long, default_value = "64")]
+        embedding_degree: usize,
+        #[structopt(long, default_value = "64")]
+        context: usize,
+        #[structopt(long, default_value = "4")]
+        layers: usize,
+        #[structopt(long, default_value = "4")]
+        heads: usize,
+        #[structopt(long, default_value = "32")]
+        batch_size: usize,
+        /// Timed training steps, after a warm-up one
+        #[structopt(long, default_value = "10")]
+        iterations: usize,
+        /// Precision of activations and gradients: `f32`, `f16` or `bf16`
+        #[structopt(long, default_value = "f32")]
+        precision: Precision,
+    },
+    /// Estimate the parameters and the memory training a model takes on each backend, without
+    /// building it
+    Estimate {
+        #[structopt(long, default_value = "64")]
+        vocab_size: usize,
+        #[structopt(long, default_value = "64")]
+        embedding_degree: usize,
+        #[structopt(long, default_value = "64")]
+        context: usize,
+        #[structopt(long, default_value = "4")]
+        layers: usize,
+        #[structopt(long, default_value = "4")]
+        heads: usize,
+        #[structopt(long, default_value = "32")]
+        batch_size: usize,
+        /// Number of data-parallel CPU workers (0 uses all available cores)
+        #[structopt(long, default_value = "0")]
+        threads: usize,
+        #[structopt(long, default_value = "adamw")]
+        optimizer: AnyOptimizer,
+        #[structopt(long, default_value = "femto")]
+        architecture: Architecture,
+    },
+    /// List the OpenCL devices available for GPU training and inference
+    Devices,
+    /// Upload a bundle (See `export-bundle`) to a Hugging Face Hub repository, created if needed.
+    /// The token is read from `HF_TOKEN`, or the one `huggingface-cli login` saved
+    #[cfg_attr(not(feature = "hub"), allow(dead_code))]
+    Push {
+        /// Repository to push to, as `user/model`
+        #[structopt(long)]
+        repo: String,
+        #[structopt(long, default_value = "model.femto")]
+        model: PathBuf,
+        /// Make the repository private, when it's created
+        #[structopt(long)]
+        private: bool,
+        #[structopt(long, default_value = "Upload the femto bundle")]
+        message: String,
+    },
+    /// Download a small standard corpus (`tinyshakespeare` or `wikitext2-tiny`), checking its
+    /// SHA-256, to train a first model on
+    #[cfg_attr(not(feature = "download"), allow(dead_code))]
+    FetchDataset {
+        name: ExampleDataset,
+        #[structopt(long, default_value = "dataset.txt")]
+        out: PathBuf,
+        /// Also write a character-level vocabulary of the corpus, e.g. `vocab_file.vocab`
+        #[structopt(long)]
+        vocab: Option<PathBuf>,
+    },
+    /// Download the bundle of a Hugging Face Hub repository
+    #[cfg_attr(not(feature = "hub"), allow(dead_code))]
+    Pull {
+        /// Repository to pull from, as `user/model`
+        repo: String,
+        /// Branch, tag or commit to download
+        #[structopt(long, default_value = "main")]
+        revision: String,
+        #[structopt(long, default_value = "model.femto")]
+        out: PathBuf,
+    },
+}
+
+// A dataset of `train --dataset`, the windows of a mixture are drawn from it in proportion to
+// its weight (See `Mixture`)
+#[derive(Debug, Clone)]
+struct WeightedDataset {
+    path: PathBuf,
+    weight: f32,
+}
+
+impl FromStr for WeightedDataset {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // Paths may contain colons themselves, only a trailing number is a weight
+        match s.rsplit_once(':').map(|(p, w)| (p, w.parse::<f32>())) {
+            Some((path, Ok(weight))) if weight > 0. && weight.is_finite() => Ok(Self {
+                path: path.into(),
+                weight,
+            }),
+            Some((_, Ok(weight))) => Err(format!("expected a positive weight, got `{}`", weight)),
+            _ => Ok(Self {
+                path: s.into(),
+                weight: 1.,
+            }),
+        }
+    }
+}
+
+// A stage of `infer --temperature-schedule`: a number of tokens and their temperature
+#[derive(Debug, Clone, Copy)]
+struct TemperatureStage {
+    tokens: usize,
+    temperature: f32,
+}
+
+impl FromStr for TemperatureStage {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let stage = s
+            .split_once(':')
+            .and_then(|(tokens, temperature)| {
+                Some(TemperatureStage {
+                    tokens: tokens.parse().ok()?,
+                    temperature: temperature.parse().ok()?,
+                })
+            })
+            .ok_or(format!("expected `<tokens>:<temperature>`, got `{}`", s))?;
+        if !(stage.temperature > 0. && stage.temperature <= 1.) {
+            return Err(format!(
+                "temperature should be in (0, 1], got {}",
+                stage.temperature
+            ));
+        }
+        Ok(stage)
+    }
+}
+
+// What `train` teaches the model
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Objective {
+    /// Predicting the next token
+    Causal,
+    /// Predicting the masked tokens of windows, with bidirectional attention (See `Masking`)
+    MaskedLm,
+}
+
+impl FromStr for Objective {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "causal" => Ok(Objective::Causal),
+            "masked-lm" => Ok(Objective::MaskedLm),
+            _ => Err(format!("expected `causal` or `masked-lm`, got `{}`", s)),
+        }
+    }
+}
+
+// Largest batch size `--batch-size auto` tries
+const MAX_AUTO_BATCH_SIZE: usize = 1024;
+
+// Batch size of `train`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BatchSize {
+    Fixed(usize),
+    Auto,
+}
+
+impl FromStr for BatchSize {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match (s, s.parse::<usize>()) {
+            ("auto", _) => Ok(BatchSize::Auto),
+            (_, Ok(size)) if size > 0 => Ok(BatchSize::Fixed(size)),
+            _ => Err(format!(
+                "expected a positive number of sequences or `auto`, got `{}`",
+                s
+            )),
+        }
+    }
+}
+
+// A number of tokens, with an optional `K`, `M` or `B` suffix, e.g. `50M`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct TokenCount(u64);
+
+impl FromStr for TokenCount {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (number, scale) = match s.char_indices().last() {
+            Some((i, 'K' | 'k')) => (&s[..i], 1e3),
+            Some((i, 'M' | 'm')) => (&s[..i], 1e6),
+            Some((i, 'B' | 'b')) => (&s[..i], 1e9),
+            _ => (s, 1.),
+        };
+        match number.parse::<f64>() {
+            Ok(n) if n > 0. && n.is_finite() => Ok(TokenCount((n * scale).round() as u64)),
+            _ => Err(format!(
+                "expected a positive number of tokens like `50M`, got `{}`",
+                s
+            )),
+        }
+    }
+}
+
+// How `infer` and `eval-gen` print their results
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum OutputFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(OutputFormat::Text),
+            "json" => Ok(OutputFormat::Json),
+            _ => Err(format!("expected `text` or `json`, got `{}`", s)),
+        }
+    }
+}
+
+// The token whose text is `text`, e.g. a special token like `<|endoftext|>`
+fn token_of<T: Tokenizer + ?Sized>(tokenizer: &T, text: &str) -> Result<usize, FemtoError> {
+    (0..tokenizer.vocab_size())
+        .find(|t| tokenizer.untokenize(&[*t]) == text)
+        .ok_or_else(|| FemtoError::Config(format!("{:?} is not a token of the vocabulary", text)))
+}
+
+// How `resize` initializes the layers it adds
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LayerInit {
+    Random,
+    /// Copies of the last layer of the checkpoint
+    CopyLast,
+}
+
+impl FromStr for LayerInit {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "random" => Ok(LayerInit::Random),
+            "copy-last" => Ok(LayerInit::CopyLast),
+            _ => Err(format!("expected `random` or `copy-last`, got `{}`", s)),
+        }
+    }
+}
+
+// The name of a layer tensor with its layer index (The first number between underscores, e.g.
+// `head_2_0_k` or `feedforward1_2_bias`) replaced by `layer`
+fn with_layer(name: &str, layer: usize) -> Option<String> {
+    let mut parts = name.split('_').collect::<Vec<_>>();
+    let index = parts
+        .iter()
+        .position(|p| !p.is_empty() && p.chars().all(|c| c.is_ascii_digit()))?;
+    let layer = layer.to_string();
+    parts[index] = &layer;
+    Some(parts.join("_"))
+}
+
+// `t` with its dimension `axis` grown to `size`, the added entries being the mean of the existing
+// ones along it (e.g. new rows of the token embedding are the mean embedding)
+fn extend_with_mean(
+    t: &femto_gpt::tensor::Tensor<f32>,
+    axis: usize,
+    size: usize,
+) -> Result<femto_gpt::tensor::Tensor<f32>, FemtoError> {
+    let shape = t.shape();
+    let n = shape[axis];
+    let inner = shape[axis + 1..].iter().product::<usize>();
+    let mut blob = Vec::with_capacity(t.size() / n.max(1) * size);
+    for block in t.blob().chunks(n * inner) {
+        blob.extend_from_slice(block);
+        let mut mean = vec![0.; inner];
+        for row in block.chunks(inner) {
+            for (m, v) in mean.iter_mut().zip(row) {
+                *m += v / n as f32;
+            }
+        }
+        for _ in n..size {
+            blob.extend_from_slice(&mean);
+        }
+    }
+    let mut shape = shape.to_vec();
+    shape[axis] = size;
+    Ok(femto_gpt::tensor::Tensor::raw(&shape, blob)?)
+}
+
+// Settings of `train --config`, e.g.
+// `{"param_groups": [{"pattern": "*_bias", "weight_decay": 0},
+//                    {"pattern": "token_embedding", "lr_scale": 0.1}]}`
+#[derive(Debug, Default, Deserialize)]
+struct ConfigFile {
+    /// Learning rate multipliers and weight decays of the parameters matching name patterns, the
+    /// first matching group applies
+    #[serde(default)]
+    param_groups: Vec<ParamGroup>,
+    /// Weights of the loss of the positions whose target is each of these tokens, by text (The
+    /// others weigh 1), e.g. `{"<|endoftext|>": 0.1}` (See `GptBuilder::class_weights`)
+    #[serde(default)]
+    class_weights: HashMap<String, f32>,
+    /// Keep the moments of the optimizer in 8 bits, taking a quarter of the memory (See
+    /// `AdamW::with_8bit_moments`)
+    #[serde(default)]
+    optimizer_8bit: bool,
+}
+
+// Quantized models are stored bincode-encoded
+fn load_state<S: serde::de::DeserializeOwned>(path: &Path) -> Result<S, FemtoError> {
+    let bytes = fs::read(path).map_err(FemtoError::io(path))?;
+    bincode::deserialize(&bytes)
+        .map_err(CheckpointError::from)
+        .map_err(FemtoError::checkpoint(path))
 }
 
-fn main() -> Result<(), GraphError> {
-    #[cfg(not(feature = "gpu"))]
-    let graph = femto_gpt::graph::CpuGraph::new();
-    #[cfg(not(feature = "gpu"))]
-    let is_gpu = false;
+fn read_text(path: &Path) -> Result<String, FemtoError> {
+    fs::read_to_string(path).map_err(FemtoError::io(path))
+}
+
+// Hash of the contents of a file, recorded in the provenance of the models trained on it
+fn hash_file(path: &Path) -> Result<String, FemtoError> {
+    let bytes = fs::read(path).map_err(FemtoError::io(path))?;
+    Ok(format!("{:016x}", fnv1a(&bytes)))
+}
+
+// The texts of `--prompt` (`-` standing for the standard input) followed by the ones of
+// `--prompt-file`, newlines included
+fn read_prompts(prompts: Vec<String>, files: &[PathBuf]) -> Result<Vec<String>, FemtoError> {
+    let mut stdin = None;
+    let mut texts = Vec::with_capacity(prompts.len() + files.len());
+    for prompt in prompts {
+        if prompt != "-" {
+            texts.push(prompt);
+            continue;
+        }
+        if stdin.is_some() {
+            return Err(FemtoError::Config(
+                "the standard input can only be read once, `--prompt -` is repeated".into(),
+            ));
+        }
+        let mut text = String::new();
+        std::io::Read::read_to_string(&mut std::io::stdin(), &mut text)
+            .map_err(FemtoError::io(Path::new("<stdin>")))?;
+        stdin = Some(());
+        texts.push(text);
+    }
+    for path in files.iter() {
+        texts.push(read_text(path)?);
+    }
+    Ok(texts)
+}
+
+fn read_config(path: &Path) -> Result<ConfigFile, FemtoError> {
+    serde_json::from_str(&read_text(path)?)
+        .map_err(|e| FemtoError::Config(format!("{}: {}", path.display(), e)))
+}
+
+// A line of a `.jsonl` dataset, e.g. `{"prompt": "Translate: chat", "completion": "cat"}`
+#[derive(Debug, Deserialize)]
+struct CompletionExample {
+    prompt: String,
+    completion: String,
+}
+
+// A line of the dataset of `embed-train`, e.g. `{"anchor": "a cat", "positive": "a kitten"}`
+#[derive(Debug, Deserialize)]
+struct PairExample {
+    anchor: String,
+    positive: String,
+}
+
+// Tokens of the anchor and the positive of every pair
+type TokenPairs = Vec<(Vec<usize>, Vec<usize>)>;
+
+// The pairs of texts of the dataset of `embed-train`
+fn read_pairs<T: Tokenizer + ?Sized>(tokenizer: &T, path: &Path) -> Result<TokenPairs, FemtoError> {
+    read_text(path)?
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| {
+            let invalid = |e: String| {
+                FemtoError::Config(format!("{}, line {}: {}", path.display(), i + 1, e))
+            };
+            let pair: PairExample =
+                serde_json::from_str(line).map_err(|e| invalid(e.to_string()))?;
+            let (anchor, positive) = (
+                tokenizer.tokenize(&pair.anchor),
+                tokenizer.tokenize(&pair.positive),
+            );
+            if anchor.is_empty() || positive.is_empty() {
+                return Err(invalid("empty text".into()));
+            }
+            Ok((anchor, positive))
+        })
+        .collect()
+}
+
+// A line of the dataset of `dpo`, e.g.
+// `{"prompt": "2 + 2 =", "chosen": " 4", "rejected": " 5"}`
+#[derive(Debug, Deserialize)]
+struct PreferenceExample {
+    prompt: String,
+    chosen: String,
+    rejected: String,
+}
+
+// The examples of the dataset of `dpo`
+fn read_preferences<T: Tokenizer + ?Sized>(
+    tokenizer: &T,
+    path: &Path,
+) -> Result<Vec<Preference>, FemtoError> {
+    read_text(path)?
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| {
+            let invalid = |e: String| {
+                FemtoError::Config(format!("{}, line {}: {}", path.display(), i + 1, e))
+            };
+            let example: PreferenceExample =
+                serde_json::from_str(line).map_err(|e| invalid(e.to_string()))?;
+            let preference = Preference {
+                prompt: tokenizer.tokenize(&example.prompt),
+                chosen: tokenizer.tokenize(&example.chosen),
+                rejected: tokenizer.tokenize(&example.rejected),
+            };
+            if preference.prompt.is_empty()
+                || preference.chosen.is_empty()
+                || preference.rejected.is_empty()
+            {
+                return Err(invalid("empty text".into()));
+            }
+            Ok(preference)
+        })
+        .collect()
+}
+
+// Datasets of prompt/completion pairs, which only train on the completions (See `Completions`)
+fn is_completions(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == "jsonl")
+}
+
+// The examples of a `.jsonl` dataset, `stop` (e.g. the document separator) being appended to
+// their completions
+fn read_completions<T: Tokenizer + ?Sized>(
+    tokenizer: &T,
+    path: &Path,
+    stop: Option<usize>,
+) -> Result<Completions, FemtoError> {
+    let examples = read_text(path)?
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| {
+            let example: CompletionExample = serde_json::from_str(line).map_err(|e| {
+                FemtoError::Config(format!("{}, line {}: {}", path.display(), i + 1, e))
+            })?;
+            let mut completion = tokenizer.tokenize(&example.completion);
+            completion.extend(stop);
+            Ok((tokenizer.tokenize(&example.prompt), completion))
+        })
+        .collect::<Result<Vec<_>, FemtoError>>()?;
+    let completions = Completions::new(examples);
+    if completions.is_empty() {
+        return Err(FemtoError::Config(format!(
+            "dataset {} has no examples",
+            path.display()
+        )));
+    }
+    Ok(completions)
+}
+
+// Dimensions of a checkpoint with learned positional embeddings, whose size can't be changed
+// once trained
+fn checkpoint_dims<'a>(
+    model: GptBuilder<'a>,
+    state: &TrainingState,
+) -> Result<GptBuilder<'a>, FemtoError> {
+    let shape = ModelShape::of(state)?;
+    let context = state
+        .tensors
+        .get("pos_embedding")
+        .ok_or_else(|| ExportError::TensorNotFound("pos_embedding".into()))?
+        .shape()[0];
+    Ok(model
+        .embedding_degree(shape.embedding_degree)
+        .context(context)
+        .layers(shape.num_layers)
+        .heads(shape.num_heads)
+        .head_size(shape.head_size))
+}
+
+// A model of the architecture and dimensions of a checkpoint, GPT-2 ones being recognized by
+// their learned positional embeddings
+fn checkpoint_builder<'a>(
+    model: GptBuilder<'a>,
+    state: &TrainingState,
+) -> Result<GptBuilder<'a>, FemtoError> {
+    let shape = ModelShape::of(state)?;
+    let model = match state.tensors.contains_key("pos_embedding") {
+        true => checkpoint_dims(model, state)?.architecture(Architecture::Gpt2),
+        false => model
+            .embedding_degree(shape.embedding_degree)
+            .layers(shape.num_layers)
+            .heads(shape.num_heads)
+            .head_size(shape.head_size),
+    };
+    Ok(model.vocab_size(shape.vocab_size))
+}
+
+fn load_vocab(vocab: &Path) -> Result<SentencePieceTokenizer, FemtoError> {
+    SentencePieceTokenizer::load(vocab).map_err(FemtoError::tokenizer(vocab))
+}
+
+fn load_tokenizer(
+    vocab: &Path,
+    hf_tokenizer: Option<&Path>,
+) -> Result<Box<dyn Tokenizer>, FemtoError> {
+    Ok(match hf_tokenizer {
+        Some(path) => {
+            Box::new(HuggingFaceTokenizer::load(path).map_err(FemtoError::tokenizer(path))?)
+        }
+        None => Box::new(load_vocab(vocab)?),
+    })
+}
+
+// The bundle at `model`, if it's one (See `export-bundle`). Missing models are left to the
+// loading of their weights
+fn bundle_of(model: &Path) -> Result<Option<Bundle>, FemtoError> {
+    match model.is_file() {
+        true => load_bundle(model),
+        false => Ok(None),
+    }
+}
+
+// The tokenizer of `model` along with its dimensions when it's a bundle, else the tokenizer of
+// the `--vocab` or `--hf-tokenizer` file
+fn load_model_tokenizer(
+    model: &Path,
+    vocab: &Path,
+    hf_tokenizer: Option<&Path>,
+) -> Result<(Box<dyn Tokenizer>, Option<BundleConfig>), FemtoError> {
+    match bundle_of(model)? {
+        Some(bundle) => Ok((
+            bundle
+                .tokenizer
+                .load()
+                .map_err(FemtoError::checkpoint(model))?,
+            Some(bundle.config),
+        )),
+        None => Ok((load_tokenizer(vocab, hf_tokenizer)?, None)),
+    }
+}
+
+// Same as `load_model_tokenizer`, for the commands that need a SentencePiece vocabulary
+fn load_model_vocab(
+    model: &Path,
+    vocab: &Path,
+) -> Result<(SentencePieceTokenizer, Option<BundleConfig>), FemtoError> {
+    match bundle_of(model)? {
+        Some(bundle) => Ok((
+            bundle
+                .tokenizer
+                .sentencepiece()
+                .map_err(FemtoError::checkpoint(model))?,
+            Some(bundle.config),
+        )),
+        None => Ok((load_vocab(vocab)?, None)),
+    }
+}
+
+// A model (Or bundle) and its tokenizer, for the commands only running forward passes
+fn load_frozen_model(
+    builder: GptBuilder<'_>,
+    graph: AnyGraph,
+    model: &Path,
+    vocab: &Path,
+    hf_tokenizer: Option<&Path>,
+    architecture: Architecture,
+) -> Result<(GPT<AnyGraph>, Box<dyn Tokenizer>), FemtoError> {
+    let (tokenizer, bundled) = load_model_tokenizer(model, vocab, hf_tokenizer)?;
+    let architecture = bundled.map_or(architecture, |c| c.architecture);
+    let state = load_training_state(model)?;
+    let builder = match (bundled, architecture) {
+        (Some(config), _) => config.apply(builder),
+        (None, Architecture::Gpt2) => checkpoint_dims(builder, &state)?,
+        (None, Architecture::Femto) => builder,
+    };
+    let mut gpt = builder
+        .vocab_size(tokenizer.vocab_size())
+        .architecture(architecture)
+        .build(graph)?;
+    gpt.set_training_state(state, false)?;
+    gpt.set_training(false);
+    Ok((gpt, tokenizer))
+}
+
+// The dimensions of a bundled model, or the ones of `builder`
+fn bundle_dims(builder: GptBuilder<'_>, bundled: Option<BundleConfig>) -> GptBuilder<'_> {
+    match bundled {
+        Some(config) => config.apply(builder),
+        None => builder,
+    }
+}
+
+// Runs the same training step, on a small model, with a GPU and a CPU graph and lists the tensors
+// whose values or gradients disagree
+fn gpu_parity<G: Graph>(
+    mut gpu_graph: G,
+    seed: u64,
+    tolerance: f32,
+) -> Result<Vec<femto_gpt::graph::ParityMismatch>, GptError> {
+    use rand::{Rng, SeedableRng};
+    const BATCH_SIZE: usize = 2;
+    const VOCAB_SIZE: usize = 32;
+    const NUM_TOKENS: usize = 8;
+    fn model<G: Graph>(graph: G, seed: u64) -> Result<GPT<G>, GptError> {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(seed);
+        GptBuilder::new()
+            .batch_size(BATCH_SIZE)
+            .vocab_size(VOCAB_SIZE)
+            .embedding_degree(16)
+            .context(NUM_TOKENS)
+            .layers(2)
+            .heads(2)
+            .build_with_rng(&mut rng, graph)
+    }
+    // Gradients of intermediate tensors are compared too
+    gpu_graph.set_buffer_reuse(false)?;
+    let mut cpu = model(femto_gpt::graph::CpuGraph::new(), seed)?;
+    let mut gpu = model(gpu_graph, seed)?;
+
+    let mut rng = rand::rngs::StdRng::seed_from_u64(seed + 1);
+    let mut tokens = || {
+        let blob = (0..BATCH_SIZE * NUM_TOKENS)
+            .map(|_| rng.gen_range(0..VOCAB_SIZE))
+            .collect();
+        femto_gpt::tensor::Tensor::raw(&[BATCH_SIZE, NUM_TOKENS], blob)
+    };
+    let (xs, ys) = (tokens()?, tokens()?);
+    cpu.forward_backward(&xs, &ys)?;
+    gpu.forward_backward(&xs, &ys)?;
+    Ok(femto_gpt::graph::compare_graphs(
+        cpu.graph_mut(),
+        gpu.graph_mut(),
+        tolerance,
+    )?)
+}
+
+fn train_model<T: Tokenizer + ?Sized, D: Corpus + ?Sized>(
+    gpt: &mut GPT<AnyGraph>,
+    replicas: &mut [GPT<AnyGraph>],
+    tokenizer: &T,
+    dataset: &D,
+    config: &TrainConfig,
+    optimizer: &AnyOptimizer,
+    save: &dyn Fn(&TrainingState) -> Result<(), FemtoError>,
+) -> Result<(), FemtoError> {
+    info!("Starting the training loop... (This make take hours to converge! be patient!)");
+
+    let callback = |gpt: &mut GPT<AnyGraph>| {
+        let mut rng = rand::thread_rng();
+        let inference_temperature = 0.5; // How creative? 0.0 min 1.0 max
+
+        // Masked language models attend to both sides, they can't continue a text
+        if !gpt.is_masked_lm() {
+            let inference = gpt.infer(
+                &mut rng,
+                &tokenizer.tokenize("\n"),
+                &InferParams::new()
+                    .count(100)
+                    .temperature(inference_temperature),
+                |_ch| {},
+            )?;
+
+            // Generate 100 character with the currently trained model before
+            // starting the training loop.
+            info!(text = %tokenizer.untokenize(&inference), "Generated text");
+        }
+
+        info!(step = gpt.graph().optimizer_step(), "Saving the model");
+        gpt.sync()?;
+        let ts = gpt.get_training_state()?;
+        save(&ts)
+    };
+
+    // Training loop!
+    if !replicas.is_empty() {
+        return gpt.train_data_parallel(replicas, dataset, config, optimizer, callback);
+    }
+
+    // GPU data-parallelism is across devices (See `--devices`), not worker threads
+    gpt.fit(dataset, config, optimizer, callback)
+}
+
+#[cfg(feature = "gpu")]
+fn list_devices() -> Result<(), femto_gpt::graph::GraphError> {
+    let devices = femto_gpt::graph::gpu::program::Device::all()?;
+    if devices.is_empty() {
+        println!("No OpenCL devices found");
+    }
+    for (i, device) in devices.iter().enumerate() {
+        println!(
+            "{}: {} ({})",
+            i,
+            device.name(),
+            device.platform_name().unwrap_or_default()
+        );
+    }
+    Ok(())
+}
+
+// The corpus is written as is, its vocabulary (When asked for) being the one `train` expects
+#[cfg(feature = "download")]
+fn fetch_dataset(cli: Cli) -> Result<(), FemtoError> {
+    let Cli::FetchDataset { name, out, vocab } = cli else {
+        unreachable!()
+    };
+    info!(url = name.url(), "Downloading {}", name.name());
+    let text = name.fetch()?;
+    info!(sha256 = name.sha256(), "Checked the digest");
+    write_atomic(&out, text.as_bytes())?;
+    println!(
+        "Dataset written to {} ({} bytes)",
+        out.display(),
+        text.len()
+    );
+    if let Some(path) = vocab {
+        let contents = femto_gpt::tokenizer::char_vocab(&text);
+        write_atomic(&path, contents.as_bytes())?;
+        let tokenizer = load_vocab(&path)?;
+        println!(
+            "Vocabulary of {} characters written to {}, the dataset is {} tokens",
+            tokenizer.vocab_size(),
+            path.display(),
+            tokenizer.tokenize(&text).len()
+        );
+    }
+    Ok(())
+}
+
+// Bundles are checked before being pushed and once pulled, so that the repositories only hold
+// models loadable with their tokenizer
+#[cfg(feature = "hub")]
+fn hub_command(cli: Cli) -> Result<(), FemtoError> {
+    let hub = femto_gpt::hub::Hub::from_env();
+    match cli {
+        Cli::Push {
+            repo,
+            model,
+            private,
+            message,
+        } => {
+            let bytes = fs::read(&model).map_err(FemtoError::io(&model))?;
+            if Bundle::read(&bytes)
+                .map_err(FemtoError::checkpoint(&model))?
+                .is_none()
+            {
+                return Err(FemtoError::Config(format!(
+                    "{} isn't a bundle, make one with `export-bundle`",
+                    model.display()
+                )));
+            }
+            hub.create_repo(&repo, private)?;
+            hub.push(&repo, &bytes, &message)?;
+            println!("Pushed {} to {}", model.display(), repo);
+        }
+        Cli::Pull {
+            repo,
+            revision,
+            out,
+        } => {
+            let bytes = hub.pull(&repo, &revision)?;
+            let bundle = Bundle::read(&bytes)
+                .map_err(FemtoError::checkpoint(&out))?
+                .ok_or_else(|| {
+                    FemtoError::Config(format!("{} doesn't hold a femto bundle", repo))
+                })?;
+            write_atomic(&out, &bytes)?;
+            println!(
+                "Bundle written to {} ({} layers, context of {})",
+                out.display(),
+                bundle.config.layers,
+                bundle.config.context
+            );
+        }
+        _ => unreachable!(),
+    }
+    Ok(())
+}
+
+fn format_bytes(bytes: usize) -> String {
+    let units = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024. && unit + 1 < units.len() {
+        size /= 1024.;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, units[unit])
+}
+
+fn print_estimate(estimate: &MemoryEstimate, batch_size: usize, workers: usize) {
+    println!("Parameters:  {}", estimate.params);
+    println!("Weights:     {:>10}", format_bytes(estimate.param_bytes()));
+    println!("Gradients:   {:>10}", format_bytes(estimate.param_bytes()));
+    println!(
+        "Optimizer:   {:>10}",
+        format_bytes(estimate.optimizer_bytes)
+    );
+    println!(
+        "Activations: {:>10} per sequence, {} per batch of {} (With their gradients)",
+        format_bytes(estimate.activation_bytes(1)),
+        format_bytes(estimate.activation_bytes(batch_size)),
+        batch_size
+    );
+    println!(
+        "CPU RAM:     {:>10} (Workers: {})",
+        format_bytes(estimate.cpu_bytes(workers)),
+        workers
+    );
+    println!(
+        "GPU VRAM:    {:>10} (At most)",
+        format_bytes(estimate.gpu_bytes(batch_size))
+    );
+}
+
+// Prints the events of the library (Training steps, kernel compilation...) as text or JSON lines,
+// on the standard error when the standard output is reserved to results
+fn init_logging(level: tracing::Level, json: bool, stderr: bool) {
+    use tracing_subscriber::fmt::writer::BoxMakeWriter;
+    let writer = if stderr {
+        BoxMakeWriter::new(std::io::stderr)
+    } else {
+        BoxMakeWriter::new(std::io::stdout)
+    };
+    let logs = tracing_subscriber::fmt()
+        .with_max_level(level)
+        .with_writer(writer);
+    if json {
+        logs.json().init();
+    } else {
+        logs.init();
+    }
+}
+
+fn main() {
+    let opts = Opts::from_args();
+    let json_output = matches!(
+        opts.cli,
+        Cli::Infer {
+            output: OutputFormat::Json,
+            ..
+        }
+    );
+    init_logging(opts.log_level, opts.log_json, json_output);
+    if let Err(e) = run(opts) {
+        eprintln!("Error: {}", e);
+        if let Some(hint) = e.hint() {
+            eprintln!("Hint: {}", hint);
+        }
+        std::process::exit(1);
+    }
+}
+
+fn run(opts: Opts) -> Result<(), FemtoError> {
+    if let Cli::Devices = opts.cli {
+        #[cfg(not(feature = "gpu"))]
+        println!("GPU support is not compiled in, build with `--features gpu`");
+        #[cfg(feature = "gpu")]
+        list_devices()?;
+        return Ok(());
+    }
+    if let Cli::FetchDataset { .. } = opts.cli {
+        #[cfg(not(feature = "download"))]
+        return Err(FemtoError::Config(
+            "downloading is not compiled in, build with `--features download`".into(),
+        ));
+        #[cfg(feature = "download")]
+        return fetch_dataset(opts.cli);
+    }
+    if let Cli::Push { .. } | Cli::Pull { .. } = opts.cli {
+        #[cfg(not(feature = "hub"))]
+        return Err(FemtoError::Config(
+            "Hub support is not compiled in, build with `--features hub`".into(),
+        ));
+        #[cfg(feature = "hub")]
+        return hub_command(opts.cli);
+    }
+    // Nothing is built, the backend doesn't matter
+    if let Cli::Estimate {
+        vocab_size,
+        embedding_degree,
+        context,
+        layers,
+        heads,
+        batch_size,
+        threads,
+        optimizer,
+        architecture,
+    } = opts.cli
+    {
+        let estimate = GptBuilder::new()
+            .vocab_size(vocab_size)
+            .embedding_degree(embedding_degree)
+            .context(context)
+            .layers(layers)
+            .heads(heads)
+            .architecture(architecture)
+            .estimate(&optimizer)?;
+        // As `GPT::train_cpu` picks them
+        let workers = if threads == 0 {
+            rayon::current_num_threads()
+        } else {
+            threads
+        }
+        .clamp(1, batch_size.max(1));
+        print_estimate(&estimate, batch_size, workers);
+        return Ok(());
+    }
+
+    // Without an explicit backend or device, a GPU that fails to initialize isn't fatal
+    let graph = match (opts.backend, opts.device) {
+        (None, None) => AnyGraph::new(Backend::default(), None).or_else(|e| {
+            if Backend::default() != Backend::Cpu {
+                warn!(error = %e, "GPU initialization failed, falling back to the CPU");
+            }
+            AnyGraph::new(Backend::Cpu, None)
+        })?,
+        // Picking a device implies the GPU backend
+        (backend, device) => AnyGraph::new(backend.unwrap_or(Backend::OpenCl), device)?,
+    };
+    let is_gpu = graph.is_gpu();
+    let device = opts.device;
+
+    let batch_size = 32;
+    let num_tokens = 64;
+    // The default model (See `GptBuilder`), GPU graphs need pre-allocated batches
+    let model_builder = GptBuilder::new()
+        .context(num_tokens)
+        .batch_size(is_gpu.then_some(batch_size));
+
+    match opts.cli {
+        Cli::Infer {
+            tokenizer_dataset: _tokenizer_dataset,
+            vocab,
+            model,
+            prompt,
+            prompt_file,
+            count,
+            temperature,
+            temperature_schedule,
+            top_k,
+            top_p,
+            repetition_penalty,
+            stop,
+            sample_seed,
+            context_overflow,
+            regex,
+            guidance_scale,
+            negative_prompt,
+            token_healing,
+            beam_size,
+            length_penalty,
+            best_of,
+            rerank,
+            logprobs,
+            adapter,
+            quantized,
+            lora_rank,
+            lora_alpha,
+            matmul_backend,
+            architecture,
+            hf_tokenizer,
+            output,
+        } => {
+            let prompt = read_prompts(prompt, &prompt_file)?;
+            if let Some(backend) = matmul_backend {
+                set_matmul_backend(backend)?;
+            }
+            let training_state_path = &model.clone();
+            // Messages for humans, kept out of the way of JSON results
+            let json = output == OutputFormat::Json;
+            let say = |message: String| {
+                if json {
+                    eprintln!("{}", message);
+                } else {
+                    println!("{}", message);
+                }
+            };
+
+            let mut rng = rand::thread_rng();
+
+            // Create a unique char-to-int mapping for all unique characters inside our dataset
+            //let dataset_char = fs::read_to_string(tokenizer_dataset.clone())
+            //.expect("Should have been able to read the file");
+            // Use the vocab file for the tokenizer instead of the dataset
+            let (tokenizer, bundled) =
+                load_model_tokenizer(&model, &vocab, hf_tokenizer.as_deref())?;
+            let architecture = bundled.map_or(architecture, |c| c.architecture);
+
+            let vocab_size = tokenizer.vocab_size();
+            say(format!("Vocab-size: {} unique characters", vocab_size));
+
+            // Quantized matrices are baked into the graph, the rest is loaded as usual
+            let quantized_state = quantized
+                .then(|| load_state::<QuantizedState>(&model))
+                .transpose()?;
+            let base_state = (!quantized)
+                .then(|| load_training_state(training_state_path))
+                .transpose()?;
+
+            let model_builder = match (bundled, &base_state, architecture) {
+                (Some(config), _, _) => config.apply(model_builder),
+                (None, Some(state), Architecture::Gpt2) => checkpoint_dims(model_builder, state)?,
+                _ => model_builder,
+            };
+
+            let mut gpt = model_builder
+                .vocab_size(vocab_size)
+                .architecture(architecture)
+                .lora(adapter.as_ref().map(|_| LoraConfig {
+                    rank: lora_rank,
+                    alpha: lora_alpha,
+                }))
+                .quantized(quantized_state.as_ref())
+                .build_with_rng(&mut rng, graph)?;
+
+            gpt.sync()?;
+
+            if let Some(quantized_state) = quantized_state {
+                let ts = TrainingState {
+                    tensors: quantized_state.tensors,
+                    optimizer: Default::default(),
+                    sampler: None,
+                    progress: Default::default(),
+                    provenance: Default::default(),
+                };
+                gpt.set_training_state(ts, false)?;
+            }
+            if let Some(base_state) = base_state {
+                gpt.set_training_state(base_state, true)?;
+            }
+            if let Some(adapter) = adapter {
+                gpt.set_training_state(load_training_state(&adapter)?, false)?;
+            }
+
+            gpt.set_training(false);
+            say("Generating text:".into());
+
+            let prompts = prompt
+                .iter()
+                .map(|p| tokenizer.tokenize(p))
+                .collect::<Vec<_>>();
+            let constraint = regex
+                .map(|pattern| RegexConstraint::new(&pattern, &token_pieces(tokenizer.as_ref())))
+                .transpose()?;
+            let mut params = InferParams::new()
+                .count(count)
+                .temperature(temperature)
+                .top_k(top_k)
+                .top_p(top_p)
+                .repetition_penalty(repetition_penalty)
+                .seed(sample_seed)
+                .context_overflow(context_overflow)
+                .constraint(constraint.map(|c| Arc::new(c) as Arc<dyn Constraint>))
+                .guidance_scale(guidance_scale)
+                .negative_prompt(
+                    negative_prompt.map_or_else(Vec::new, |text| tokenizer.tokenize(&text)),
+                )
+                .token_healing(token_healing.then(|| Arc::new(token_pieces(tokenizer.as_ref()))));
+            for stage in temperature_schedule {
+                params = params.temperature_for(stage.tokens, stage.temperature);
+            }
+            for text in stop {
+                params = params.stop(tokenizer.tokenize(&text));
+            }
+
+            if let Some(beam_size) = beam_size {
+                let beam = BeamParams {
+                    beam_size,
+                    length_penalty,
+                };
+                for (text, prompt) in prompt.iter().zip(prompts.iter()) {
+                    let beams = gpt.beam_search(prompt, &params, &beam)?;
+                    if json {
+                        let beams = beams
+                            .iter()
+                            .map(|(seq, score)| {
+                                serde_json::json!({
+                                    "text": tokenizer.untokenize(seq),
+                                    "tokens": seq,
+                                    "score": score,
+                                })
+                            })
+                            .collect::<Vec<_>>();
+                        println!("{}", serde_json::json!({"prompt": text, "beams": beams}));
+                        continue;
+                    }
+                    for (seq, score) in beams {
+                        println!("{:.4}\t{}", score, tokenizer.untokenize(&seq));
+                    }
+                }
+                return Ok(());
+            }
+
+            if let Some(top_n) = logprobs {
+                let inferences =
+                    gpt.infer_logprobs(&mut rng, &prompts, &params, top_n, |_, _| {})?;
+                for (text, inference) in prompt.iter().zip(inferences.iter()) {
+                    let tokens = inference.iter().map(|t| t.token).collect::<Vec<_>>();
+                    if json {
+                        let piece = |token: usize| tokenizer.untokenize(&[token]);
+                        let scored = inference
+                            .iter()
+                            .filter_map(|t| t.log_prob)
+                            .collect::<Vec<_>>();
+                        // Of all the tokens predicted, i.e. every one but the first
+                        let perplexity = (!scored.is_empty())
+                            .then(|| (-scored.iter().sum::<f32>() / scored.len() as f32).exp());
+                        let entries = inference
+                            .iter()
+                            .map(|t| {
+                                let top = t
+                                    .top
+                                    .iter()
+                                    .map(|(token, l)| {
+                                        serde_json::json!({
+                                            "token": token,
+                                            "text": piece(*token),
+                                            "log_prob": l,
+                                        })
+                                    })
+                                    .collect::<Vec<_>>();
+                                serde_json::json!({
+                                    "token": t.token,
+                                    "text": piece(t.token),
+                                    "log_prob": t.log_prob,
+                                    "top": top,
+                                })
+                            })
+                            .collect::<Vec<_>>();
+                        println!(
+                            "{}",
+                            serde_json::json!({
+                                "prompt": text,
+                                "text": tokenizer.untokenize(&tokens),
+                                "tokens": entries,
+                                "perplexity": perplexity,
+                            })
+                        );
+                        continue;
+                    }
+                    println!("{}", tokenizer.untokenize(&tokens));
+                    for t in inference.iter() {
+                        let log_prob = t.log_prob.map_or("-".into(), |l| format!("{:.4}", l));
+                        let top = t
+                            .top
+                            .iter()
+                            .map(|(token, l)| {
+                                format!("{:?} {:.4}", tokenizer.untokenize(&[*token]), l)
+                            })
+                            .collect::<Vec<_>>();
+                        println!(
+                            "{:?}\t{}\t{}",
+                            tokenizer.untokenize(&[t.token]),
+                            log_prob,
+                            top.join(", ")
+                        );
+                    }
+                }
+                return Ok(());
+            }
+
+            if let Some(n) = best_of {
+                let best = gpt.best_of(&mut rng, &prompts, &params, n, rerank)?;
+                for (text, (inference, score)) in prompt.iter().zip(best.iter()) {
+                    if json {
+                        println!(
+                            "{}",
+                            serde_json::json!({
+                                "prompt": text,
+                                "text": tokenizer.untokenize(inference),
+                                "tokens": inference,
+                                "score": score,
+                            })
+                        );
+                    } else {
+                        println!("{:.4}\t{}", score, tokenizer.untokenize(inference));
+                    }
+                }
+                return Ok(());
+            }
+
+            let inferences = gpt.infer_batch(&mut rng, &prompts, &params, |_, _| {})?;
+            for (text, inference) in prompt.iter().zip(inferences.iter()) {
+                if json {
+                    println!(
+                        "{}",
+                        serde_json::json!({
+                            "prompt": text,
+                            "text": tokenizer.untokenize(inference),
+                            "tokens": inference,
+                        })
+                    );
+                } else {
+                    println!("{}", tokenizer.untokenize(inference));
+                }
+            }
+
+            Ok(())
+        }
+        Cli::Train {
+            vocab,
+            dataset,
+            model,
+            label_smoothing,
+            z_loss,
+            backward_scope,
+            threads,
+            matmul_backend,
+            precision,
+            detect_anomaly,
+            devices,
+            sampling,
+            document_separator,
+            pad_token,
+            curriculum,
+            objective,
+            mask_token,
+            mask_rate,
+            teacher,
+            distill_temperature,
+            distill_alpha,
+            config,
+            compress_level,
+            optimizer,
+            weight_decay,
+            decay_all_params,
+            grad_stats_every,
+            spike_threshold,
+            profile,
+            batch_size,
+            train_tokens,
+            train_hours,
+        } => {
+            let config = config
+                .map(|path| read_config(&path))
+                .transpose()?
+                .unwrap_or_default();
+            let mut optimizer = optimizer
+                .with_groups(config.param_groups)
+                .with_weight_decay(weight_decay);
+            if decay_all_params {
+                optimizer = optimizer.with_decay_all_params();
+            }
+            let optimizer = match optimizer {
+                AnyOptimizer::AdamW(adam) if config.optimizer_8bit => {
+                    AnyOptimizer::AdamW(adam.with_8bit_moments())
+                }
+                AnyOptimizer::Adafactor(_) if config.optimizer_8bit => {
+                    return Err(FemtoError::Config(
+                        "`optimizer_8bit` only applies to `--optimizer adamw`".into(),
+                    ));
+                }
+                optimizer => optimizer,
+            };
+            if let Some(backend) = matmul_backend {
+                set_matmul_backend(backend)?;
+            }
+            let training_state_path = &model.clone();
+            let save = |ts: &TrainingState| {
+                save_compressed_training_state(training_state_path, ts, compress_level)
+            };
+
+            let mut rng = rand::thread_rng();
+
+            // Create a unique char-to-int mapping for all unique characters inside our dataset
+            let tokenizer = load_vocab(&vocab)?;
+
+            let document_separator = document_separator
+                .map(|text| token_of(&tokenizer, &text))
+                .transpose()?;
+            let pad_token = pad_token
+                .map(|text| token_of(&tokenizer, &text))
+                .transpose()?;
+            if pad_token.is_some() && document_separator.is_none() {
+                return Err(FemtoError::Config(
+                    "`--pad-token` needs a `--document-separator` to split the documents".into(),
+                ));
+            }
+
+            // The datasets of a mixture are hashed one by one, in their order
+            let dataset_hash = dataset
+                .iter()
+                .map(|d| hash_file(&d.path))
+                .collect::<Result<Vec<_>, _>>()?
+                .join(",");
+            let tokenizer_hash = hash_file(&vocab)?;
+
+            // The examples of prompt/completion pairs end with the document separator, so that
+            // the model learns where completions stop
+            let dataset: Box<dyn Corpus> = match dataset.as_slice() {
+                [d] if is_completions(&d.path) => {
+                    if objective == Objective::MaskedLm || pad_token.is_some() {
+                        return Err(FemtoError::Config(
+                            "masked language models and padding need text datasets, not `.jsonl` \
+                             ones"
+                                .into(),
+                        ));
+                    }
+                    let completions = read_completions(&tokenizer, &d.path, document_separator)?;
+                    println!("Examples: {}", completions.num_examples());
+                    Box::new(completions)
+                }
+                _ => {
+                    let datasets = dataset
+                        .iter()
+                        .map(|d| {
+                            if is_completions(&d.path) {
+                                return Err(FemtoError::Config(format!(
+                                    "dataset {} of prompt/completion pairs can't be mixed",
+                                    d.path.display()
+                                )));
+                            }
+                            let tokens = tokenizer.tokenize(&read_text(&d.path)?);
+                            if tokens.is_empty() {
+                                return Err(FemtoError::Config(format!(
+                                    "dataset {} has no tokens",
+                                    d.path.display()
+                                )));
+                            }
+                            Ok((tokens, d.weight))
+                        })
+                        .collect::<Result<Vec<_>, FemtoError>>()?;
+                    match (pad_token, document_separator) {
+                        (Some(pad), Some(separator)) => {
+                            let padded = datasets
+                                .into_iter()
+                                .map(|(tokens, weight)| {
+                                    let documents = tokens
+                                        .split_inclusive(|t| *t == separator)
+                                        .map(<[usize]>::to_vec)
+                                        .collect();
+                                    (Padded::new(documents, pad), weight)
+                                })
+                                .collect();
+                            Box::new(Mixture::new(padded))
+                        }
+                        _ => Box::new(Mixture::new(datasets)),
+                    }
+                }
+            };
+
+            let vocab_size = tokenizer.vocab_size();
+            println!("Vocab-size: {} unique characters", vocab_size);
+            let masked_lm = match objective {
+                Objective::Causal => None,
+                Objective::MaskedLm => Some(Masking {
+                    mask_token: token_of(&tokenizer, &mask_token)?,
+                    rate: mask_rate,
+                }),
+            };
+            let class_weights = if config.class_weights.is_empty() {
+                None
+            } else {
+                let mut weights = vec![1.; vocab_size];
+                for (text, weight) in config.class_weights.iter() {
+                    weights[token_of(&tokenizer, text)?] = *weight;
+                }
+                Some(weights)
+            };
+            let distillation = teacher.as_ref().map(|_| DistillConfig {
+                temperature: distill_temperature,
+                alpha: distill_alpha,
+            });
+            let budget = Budget {
+                tokens: train_tokens.map(|TokenCount(tokens)| tokens),
+                duration: train_hours
+                    .map(|hours| {
+                        Duration::try_from_secs_f32(hours * 3600.)
+                            .map_err(|_| FemtoError::Config(format!("invalid hours: {}", hours)))
+                    })
+                    .transpose()?,
+            };
+            let mut build = |graph, batch_size, context| -> Result<GPT<AnyGraph>, GptError> {
+                let mut gpt = model_builder
+                    .clone()
+                    .batch_size(is_gpu.then_some(batch_size))
+                    .context(context)
+                    .vocab_size(vocab_size)
+                    .document_separator(document_separator)
+                    .pad_token(pad_token)
+                    .masked_lm(masked_lm)
+                    .distillation(distillation)
+                    .label_smoothing(label_smoothing)
+                    .z_loss(z_loss)
+                    .class_weights(class_weights.clone())
+                    .build_with_rng(&mut rng, graph)?;
+                gpt.set_precision(precision)?;
+                gpt.set_detect_anomaly(detect_anomaly)?;
+                gpt.set_grad_stats(grad_stats_every);
+                gpt.set_spike_guard(spike_threshold.map(|threshold| SpikeGuard {
+                    threshold,
+                    ..Default::default()
+                }));
+                gpt.set_profiling(profile.is_some())?;
+                gpt.set_budget(budget);
+                Ok(gpt)
+            };
+
+            // Trial models train a step on random windows, larger and larger until the device is
+            // out of memory
+            let batch_size = match batch_size {
+                BatchSize::Fixed(size) => size,
+                BatchSize::Auto if !is_gpu => {
+                    return Err(FemtoError::Config(
+                        "`--batch-size auto` needs a GPU, CPU workers process a sequence at a time"
+                            .into(),
+                    ));
+                }
+                BatchSize::Auto if !devices.is_empty() => {
+                    return Err(FemtoError::Config(
+                        "`--batch-size auto` can't be combined with `--devices`".into(),
+                    ));
+                }
+                BatchSize::Auto => {
+                    let size = find_batch_size(MAX_AUTO_BATCH_SIZE, |size| {
+                        let graph = AnyGraph::new(Backend::OpenCl, device)?;
+                        build(graph, size, num_tokens)?
+                            .bench(&mut rand::thread_rng(), size, 0, &optimizer)
+                            .map(|_| ())
+                    })?;
+                    info!(batch_size = size, "Found the largest batch size that fits");
+                    size
+                }
+            };
+
+            // Every device gets its own replica of the model, processing a share of each batch
+            let (mut gpt, mut replicas) = if devices.is_empty() {
+                (build(graph, batch_size, num_tokens)?, Vec::new())
+            } else {
+                drop(graph);
+                if devices.len() > batch_size {
+                    return Err(FemtoError::Config(format!(
+                        "cannot split a batch of {} among {} devices",
+                        batch_size,
+                        devices.len()
+                    )));
+                }
+                let mut models = devices
+                    .iter()
+                    .enumerate()
+                    .map(|(i, device)| {
+                        build(
+                            AnyGraph::new(Backend::OpenCl, Some(*device))?,
+                            femto_gpt::gpt::batch_share(batch_size, devices.len(), i),
+                            num_tokens,
+                        )
+                    })
+                    .collect::<Result<Vec<_>, GptError>>()?;
+                (models.remove(0), models)
+            };
+
+            gpt.sync()?;
+
+            info!(num_params = gpt.num_params(), "Built the model");
+
+            // Load training data from train_data directory (If exists)
+            // To change the number of layers of a trained model, use `femto resize` first.
+            // IT'S NOT POSSIBLE TO CHANGE OTHER PROPERTIES ONCE THE MODEL IS TRAINED!
+            if training_state_path.is_file() {
+                gpt.set_training_state(load_training_state(training_state_path)?, true)?;
+            }
+            gpt.set_sampling(sampling);
+            gpt.set_data_hashes(dataset_hash, tokenizer_hash);
+
+            // The teacher only runs forward passes, on the device of the student
+            if let Some(path) = teacher {
+                if !curriculum.is_empty() || !replicas.is_empty() {
+                    return Err(FemtoError::Config(
+                        "`--teacher` can't be combined with `--curriculum` or `--devices`".into(),
+                    ));
+                }
+                let state = load_training_state(&path)?;
+                let shape = ModelShape::of(&state)?;
+                if shape.vocab_size != vocab_size {
+                    return Err(FemtoError::Config(format!(
+                        "the teacher has a vocabulary of {} tokens, the student {}",
+                        shape.vocab_size, vocab_size
+                    )));
+                }
+                let graph = if is_gpu {
+                    AnyGraph::new(Backend::OpenCl, device)?
+                } else {
+                    AnyGraph::new(Backend::Cpu, None)?
+                };
+                let mut teacher =
+                    checkpoint_builder(model_builder.clone(), &state)?.build(graph)?;
+                teacher.set_training_state(state, false)?;
+                info!(num_params = teacher.num_params(), "Loaded the teacher");
+                gpt.set_teacher(teacher)?;
+            }
+
+            let mut train_config = TrainConfig {
+                batch_size,
+                backward_scope,
+                num_workers: threads,
+                ..Default::default()
+            };
+            if let Some(num_batches) = profile {
+                if !curriculum.is_empty() {
+                    return Err(FemtoError::Config(
+                        "`--profile` can't be combined with `--curriculum`".into(),
+                    ));
+                }
+                train_config.num_batches = num_batches;
+            }
+
+            // The stages of the curriculum not reached yet by the checkpoint are trained on
+            // models of shorter contexts, whose state is then handed back to the full model
+            if !curriculum.is_empty() && !replicas.is_empty() {
+                return Err(FemtoError::Config(
+                    "`--curriculum` can't be combined with `--devices`".into(),
+                ));
+            }
+            if let Some(stage) = curriculum
+                .iter()
+                .find(|s| s.context == 0 || s.context > num_tokens)
+            {
+                return Err(FemtoError::Config(format!(
+                    "curriculum contexts must be in 1..={}, got {}",
+                    num_tokens, stage.context
+                )));
+            }
+            for stage in ContextStage::remaining(&curriculum, gpt.graph().optimizer_step()) {
+                info!(
+                    context = stage.context,
+                    num_batches = stage.num_batches,
+                    "Training with a shorter context"
+                );
+                let graph = if is_gpu {
+                    AnyGraph::new(Backend::OpenCl, device)?
+                } else {
+                    AnyGraph::new(Backend::Cpu, None)?
+                };
+                let mut short = build(graph, batch_size, stage.context)?;
+                gpt.sync()?;
+                short.set_training_state(gpt.get_training_state()?, true)?;
+                train_model(
+                    &mut short,
+                    &mut [],
+                    &tokenizer,
+                    dataset.as_ref(),
+                    &TrainConfig {
+                        num_batches: stage.num_batches,
+                        ..train_config.clone()
+                    },
+                    &optimizer,
+                    &save,
+                )?;
+                short.sync()?;
+                gpt.set_training_state(short.get_training_state()?, true)?;
+            }
 
-    #[cfg(feature = "gpu")]
-    let graph = femto_gpt::graph::gpu::GpuGraph::new()?;
-    #[cfg(feature = "gpu")]
-    let is_gpu = true;
+            train_model(
+                &mut gpt,
+                &mut replicas,
+                &tokenizer,
+                dataset.as_ref(),
+                &train_config,
+                &optimizer,
+                &save,
+            )?;
 
-    let batch_size = 32;
-    let num_tokens = 64;
-    let embedding_degree = 64;
-    let num_layers = 4;
-    let num_heads = 4;
-    let head_size = embedding_degree / num_heads;
-    let dropout = 0.0;
-    assert_eq!(num_heads * head_size, embedding_degree);
-
-    let cli = Cli::from_args();
-    match cli {
-        Cli::Infer {
-            tokenizer_dataset: _tokenizer_dataset,
+            if profile.is_some() {
+                println!();
+                print!("{}", gpt.take_profile());
+            }
+            Ok(())
+        }
+        Cli::Finetune {
+            dataset,
             vocab,
             model,
-            prompt,
-            count,
-            temperature,
+            adapter,
+            lora_rank,
+            lora_alpha,
+            architecture,
+            hf_tokenizer,
+            compress_level,
         } => {
-            let training_state_path = &model.clone();
-
             let mut rng = rand::thread_rng();
 
-            // Create a unique char-to-int mapping for all unique characters inside our dataset
-            //let dataset_char = fs::read_to_string(tokenizer_dataset.clone())
-                //.expect("Should have been able to read the file");
-            // Use the vocab file for the tokenizer instead of the dataset
-            let tokenizer = SentencePieceTokenizer::load(&vocab).unwrap();
+            let (tokenizer, bundled) =
+                load_model_tokenizer(&model, &vocab, hf_tokenizer.as_deref())?;
+            let architecture = bundled.map_or(architecture, |c| c.architecture);
 
-            assert_eq!(num_heads * head_size, embedding_degree);
+            let dataset: Box<dyn Corpus> = if is_completions(&dataset) {
+                let completions = read_completions(tokenizer.as_ref(), &dataset, None)?;
+                println!("Examples: {}", completions.num_examples());
+                Box::new(completions)
+            } else {
+                Box::new(tokenizer.tokenize(&read_text(&dataset)?))
+            };
 
             let vocab_size = tokenizer.vocab_size();
             println!("Vocab-size: {} unique characters", vocab_size);
-            let mut gpt = GPT::new(
-                &mut rng,
-                graph,
-                is_gpu.then(|| batch_size), // Pre-allocate batches only when using GPUs
-                vocab_size,
-                embedding_degree,
-                num_tokens,
-                num_layers,
-                num_heads,
-                head_size,
-                dropout,
-            )?;
+
+            let base_state = load_training_state(&model)?;
+            let model_builder = match (bundled, architecture) {
+                (Some(config), _) => config.apply(model_builder),
+                (None, Architecture::Gpt2) => checkpoint_dims(model_builder, &base_state)?,
+                (None, Architecture::Femto) => model_builder,
+            };
+
+            let mut gpt = model_builder
+                .vocab_size(vocab_size)
+                .architecture(architecture)
+                .lora(LoraConfig {
+                    rank: lora_rank,
+                    alpha: lora_alpha,
+                })
+                .build_with_rng(&mut rng, graph)?;
 
             gpt.sync()?;
 
-            let mut ts_file = fs::File::open(&training_state_path).unwrap();
-            let mut bytes = Vec::new();
-            ts_file.read_to_end(&mut bytes).unwrap();
-            let ts: TrainingState = bincode::deserialize(&bytes).unwrap();
-            gpt.set_training_state(ts, true)?;
+            println!("Number of trainable parameters: {}", gpt.num_params());
 
-            println!("Generating text:");
+            // The base model is frozen, only the adapters (and their optimizer state) are trained
+            // and saved.
+            gpt.set_training_state(base_state, false)?;
+            if adapter.is_file() {
+                gpt.set_training_state(load_training_state(&adapter)?, true)?;
+            }
 
-            let inference = gpt.infer(
-                &mut rng,
-                &tokenizer.tokenize(&prompt),
-                count,
-                temperature,
-                |_ch| {},
+            train_model(
+                &mut gpt,
+                &mut [],
+                tokenizer.as_ref(),
+                dataset.as_ref(),
+                &TrainConfig {
+                    batch_size,
+                    backward_scope: BackwardScope::ParamsOnly,
+                    ..Default::default()
+                },
+                &AnyOptimizer::AdamW(AdamW::new()),
+                &|ts| save_compressed_training_state(&adapter, ts, compress_level),
             )?;
 
-            // Generate 100 character with the currently trained model
-            println!("{}", tokenizer.untokenize(&inference));
-
             Ok(())
         }
-        Cli::Train { vocab, dataset, model } => {
-            let training_state_path = &model.clone();
+        Cli::Dpo {
+            dataset,
+            vocab,
+            model,
+            out,
+            reference,
+            beta,
+            batch_size,
+            steps,
+            learning_rate,
+            backward_scope,
+            architecture,
+            hf_tokenizer,
+        } => {
+            let bundle = bundle_of(&model)?;
+            let (tokenizer, bundled) =
+                load_model_tokenizer(&model, &vocab, hf_tokenizer.as_deref())?;
+            let examples = read_preferences(tokenizer.as_ref(), &dataset)?;
+            if examples.is_empty() {
+                return Err(FemtoError::Config(format!(
+                    "dataset {} has no examples",
+                    dataset.display()
+                )));
+            }
+            println!("Examples: {}", examples.len());
+            let batch_size = batch_size.min(examples.len());
+
+            let architecture = bundled.map_or(architecture, |c| c.architecture);
+            let state = load_training_state(&model)?;
+            let model_builder = match (bundled, architecture) {
+                (Some(config), _) => config.apply(model_builder),
+                (None, Architecture::Gpt2) => checkpoint_dims(model_builder, &state)?,
+                (None, Architecture::Femto) => model_builder,
+            }
+            // GPU graphs process the chosen and the rejected completions of a step at once
+            .batch_size(is_gpu.then_some(2 * batch_size))
+            .vocab_size(tokenizer.vocab_size())
+            .architecture(architecture);
+
+            // The reference only runs forward passes, on the device of the model
+            let reference_state = match &reference {
+                Some(path) => load_training_state(path)?,
+                None => state.clone(),
+            };
+            let shape = ModelShape::of(&reference_state)?;
+            if shape.vocab_size != tokenizer.vocab_size() {
+                return Err(FemtoError::Config(format!(
+                    "the reference has a vocabulary of {} tokens, the model {}",
+                    shape.vocab_size,
+                    tokenizer.vocab_size()
+                )));
+            }
+            let reference_graph = if is_gpu {
+                AnyGraph::new(Backend::OpenCl, device)?
+            } else {
+                AnyGraph::new(Backend::Cpu, None)?
+            };
+            let mut reference = checkpoint_builder(model_builder.clone(), &reference_state)?
+                .build(reference_graph)?;
+            reference.set_training_state(reference_state, false)?;
+
+            let mut gpt = model_builder.dpo(DpoConfig { beta }).build(graph)?;
+            // The optimizer starts over, it's another objective
+            gpt.set_training_state(state, false)?;
+            gpt.set_reference(reference)?;
 
+            let out = out.unwrap_or(model);
+            let save = |ts: TrainingState| match &bundle {
+                Some(bundle) => save_bundle(
+                    &out,
+                    &Bundle {
+                        state: ts,
+                        ..bundle.clone()
+                    },
+                ),
+                None => save_training_state(&out, &ts),
+            };
+            let config = TrainConfig {
+                num_batches: steps,
+                batch_size,
+                backward_scope,
+                learning_rate: LrSchedule {
+                    base: learning_rate,
+                    min: learning_rate,
+                    warmup_steps: 0,
+                    decay_steps: 1,
+                },
+                ..Default::default()
+            };
+            gpt.train_dpo(
+                &examples,
+                &config,
+                &AdamW::new(),
+                |gpt: &mut GPT<AnyGraph>| {
+                    info!(step = gpt.graph().optimizer_step(), "Saving the model");
+                    gpt.sync()?;
+                    save(gpt.get_training_state()?)
+                },
+            )?;
+            gpt.sync()?;
+            save(gpt.get_training_state()?)?;
+            println!("Model written to {}", out.display());
+            Ok(())
+        }
+        Cli::MergeLora {
+            vocab,
+            model,
+            adapter,
+            lora_rank,
+            lora_alpha,
+            out,
+        } => {
             let mut rng = rand::thread_rng();
+            let (tokenizer, bundled) = load_model_tokenizer(&model, &vocab, None)?;
 
-            // Create a unique char-to-int mapping for all unique characters inside our dataset
-            let dataset_char =
-                fs::read_to_string(dataset.clone()).expect("Should have been able to read the file");
-            let tokenizer = SentencePieceTokenizer::load(&vocab).unwrap();
+            let mut gpt = bundle_dims(model_builder, bundled)
+                .vocab_size(tokenizer.vocab_size())
+                .lora(LoraConfig {
+                    rank: lora_rank,
+                    alpha: lora_alpha,
+                })
+                .build_with_rng(&mut rng, graph)?;
 
-            let dataset = tokenizer.tokenize(&dataset_char);
+            gpt.set_training_state(load_training_state(&model)?, false)?;
+            gpt.set_training_state(load_training_state(&adapter)?, false)?;
+            gpt.sync()?;
 
-            let vocab_size = tokenizer.vocab_size();
-            println!("Vocab-size: {} unique characters", vocab_size);
-            let mut gpt = GPT::new(
-                &mut rng,
+            let ts = gpt.merge_lora()?;
+            save_training_state(&out, &ts)?;
+
+            Ok(())
+        }
+        Cli::EvalGen {
+            prompts,
+            vocab,
+            model,
+            samples,
+            count,
+            temperature,
+            top_k,
+            top_p,
+            repetition_penalty,
+            sample_seed,
+            architecture,
+            hf_tokenizer,
+            output,
+        } => {
+            let mut rng = rand::thread_rng();
+            let (mut gpt, tokenizer) = load_frozen_model(
+                model_builder,
+                graph,
+                &model,
+                &vocab,
+                hf_tokenizer.as_deref(),
+                architecture,
+            )?;
+            let texts = read_text(&prompts)?;
+            let texts = texts
+                .lines()
+                .filter(|line| !line.trim().is_empty())
+                .collect::<Vec<_>>();
+            if texts.is_empty() {
+                return Err(FemtoError::Config(format!(
+                    "{} has no prompts",
+                    prompts.display()
+                )));
+            }
+            let prompts = texts
+                .iter()
+                .map(|text| tokenizer.tokenize(text))
+                .collect::<Vec<_>>();
+
+            let params = InferParams::new()
+                .count(count)
+                .temperature(temperature)
+                .top_k(top_k)
+                .top_p(top_p)
+                .repetition_penalty(repetition_penalty)
+                .seed(sample_seed);
+            // The samples of a prompt are continued in the same batches
+            let prompts = prompts
+                .iter()
+                .flat_map(|p| std::iter::repeat_n(p, samples))
+                .collect::<Vec<_>>();
+            let mut entropies = Vec::new();
+            let seqs = gpt.infer_entropies(&mut rng, &prompts, &params, |_, _, entropy| {
+                entropies.push(entropy)
+            })?;
+            let generations = seqs
+                .iter()
+                .zip(prompts.iter())
+                .map(|(seq, prompt)| seq[prompt.len()..].to_vec())
+                .collect::<Vec<_>>();
+            let stats = GenerationStats::new(&generations, &entropies);
+            match output {
+                OutputFormat::Json => println!("{}", serde_json::json!(stats)),
+                OutputFormat::Text => print!("{}", stats),
+            }
+            Ok(())
+        }
+        Cli::Embed {
+            vocab,
+            model,
+            prompt,
+            prompt_file,
+            pooling,
+            architecture,
+            hf_tokenizer,
+        } => {
+            let prompt = read_prompts(prompt, &prompt_file)?;
+            let (mut gpt, tokenizer) = load_frozen_model(
+                model_builder,
                 graph,
-                is_gpu.then(|| batch_size), // Pre-allocate batches only when using GPUs
-                vocab_size,
-                embedding_degree,
-                num_tokens,
-                num_layers,
-                num_heads,
-                head_size,
-                dropout,
+                &model,
+                &vocab,
+                hf_tokenizer.as_deref(),
+                architecture,
             )?;
 
+            for prompt in prompt.iter() {
+                let embedding = gpt.embed(&tokenizer.tokenize(prompt), pooling)?;
+                let values = embedding.iter().map(|v| v.to_string()).collect::<Vec<_>>();
+                println!("{}", values.join(" "));
+            }
+
+            Ok(())
+        }
+        Cli::EmbedTrain {
+            dataset,
+            vocab,
+            model,
+            out,
+            pooling,
+            temperature,
+            batch_size,
+            steps,
+            learning_rate,
+            backward_scope,
+            architecture,
+            hf_tokenizer,
+        } => {
+            let bundle = bundle_of(&model)?;
+            let (tokenizer, bundled) =
+                load_model_tokenizer(&model, &vocab, hf_tokenizer.as_deref())?;
+            let pairs = read_pairs(tokenizer.as_ref(), &dataset)?;
+            if pairs.len() < 2 {
+                return Err(FemtoError::Config(format!(
+                    "dataset {} needs at least 2 pairs, each one's negatives being the others",
+                    dataset.display()
+                )));
+            }
+            println!("Pairs: {}", pairs.len());
+            let batch_size = batch_size.min(pairs.len());
+
+            let architecture = bundled.map_or(architecture, |c| c.architecture);
+            let state = load_training_state(&model)?;
+            let model_builder = match (bundled, architecture) {
+                (Some(config), _) => config.apply(model_builder),
+                (None, Architecture::Gpt2) => checkpoint_dims(model_builder, &state)?,
+                (None, Architecture::Femto) => model_builder,
+            };
+            // GPU graphs process the anchors and the positives of a step at once
+            let mut gpt = model_builder
+                .batch_size(is_gpu.then_some(2 * batch_size))
+                .vocab_size(tokenizer.vocab_size())
+                .architecture(architecture)
+                .contrastive(ContrastiveConfig {
+                    temperature,
+                    pooling,
+                })
+                .build(graph)?;
+            // The optimizer starts over, it's another objective
+            gpt.set_training_state(state, false)?;
+
+            let out = out.unwrap_or(model);
+            let save = |ts: TrainingState| match &bundle {
+                Some(bundle) => save_bundle(
+                    &out,
+                    &Bundle {
+                        state: ts,
+                        ..bundle.clone()
+                    },
+                ),
+                None => save_training_state(&out, &ts),
+            };
+            let config = TrainConfig {
+                num_batches: steps,
+                batch_size,
+                backward_scope,
+                learning_rate: LrSchedule {
+                    base: learning_rate,
+                    min: learning_rate,
+                    warmup_steps: 0,
+                    decay_steps: 1,
+                },
+                ..Default::default()
+            };
+            gpt.train_contrastive(&pairs, &config, &AdamW::new(), |gpt: &mut GPT<AnyGraph>| {
+                info!(step = gpt.graph().optimizer_step(), "Saving the model");
+                gpt.sync()?;
+                save(gpt.get_training_state()?)
+            })?;
             gpt.sync()?;
+            save(gpt.get_training_state()?)?;
+            println!("Model written to {}", out.display());
+            Ok(())
+        }
+        Cli::ClassifyTrain {
+            dataset,
+            vocab,
+            model,
+            out,
+            pooling,
+            epochs,
+            learning_rate,
+            holdout,
+            architecture,
+            hf_tokenizer,
+        } => {
+            use rand::seq::SliceRandom;
+            let contents = read_text(&dataset)?;
+            let examples = if dataset.extension().is_some_and(|e| e == "jsonl") {
+                parse_labeled_jsonl(&contents)
+            } else {
+                parse_labeled_csv(&contents)
+            }
+            .map_err(|e| FemtoError::Config(format!("{}, {}", dataset.display(), e)))?;
+            let labels = examples
+                .iter()
+                .map(|e| e.label.clone())
+                .collect::<std::collections::BTreeSet<_>>()
+                .into_iter()
+                .collect::<Vec<_>>();
+            if labels.len() < 2 {
+                return Err(FemtoError::Config(format!(
+                    "dataset {} needs at least 2 labels, it has {}",
+                    dataset.display(),
+                    labels.len()
+                )));
+            }
+            let (mut gpt, tokenizer) = load_frozen_model(
+                model_builder,
+                graph,
+                &model,
+                &vocab,
+                hf_tokenizer.as_deref(),
+                architecture,
+            )?;
 
-            println!("Number of parameters: {}", gpt.num_params());
+            // The model is frozen, its embeddings are computed once
+            let mut embedded = examples
+                .iter()
+                .map(|e| {
+                    let label = labels.binary_search(&e.label).unwrap();
+                    Ok((gpt.embed(&tokenizer.tokenize(&e.text), pooling)?, label))
+                })
+                .collect::<Result<Vec<_>, GptError>>()?;
+            embedded.shuffle(&mut rand::thread_rng());
+            let num_holdout = ((embedded.len() as f32 * holdout).round() as usize)
+                .min(embedded.len().saturating_sub(1));
+            let held_out = embedded.split_off(embedded.len() - num_holdout);
 
-            // Load training data from train_data directory (If exists)
-            // If you want to reuse training_data of a smaller model in a bigger model, you may
-            // first start again with a new optimizer by setting load_optimizer=false
-            // WARN: YOU CAN ONLY REUSE THE WEIGHTS OF A MODEL WITH DIFFERENT NUM-LAYERS!
-            // IT'S NOT POSSIBLE TO CHANGE OTHER PROPERTIES ONCE THE MODEL IS TRAINED!
-            if training_state_path.is_file() {
-                let mut ts_file = fs::File::open(&training_state_path).unwrap();
+            let degree = embedded[0].0.len();
+            let mut classifier = Classifier::new(labels, degree, pooling);
+            let (optimizer, mut state) = (AdamW::new(), Default::default());
+            for epoch in 0..epochs {
+                let loss = classifier.fit_step(&embedded, &optimizer, &mut state, learning_rate)?;
+                if epoch % 10 == 0 || epoch + 1 == epochs {
+                    info!(epoch, loss, "Trained the classifier");
+                }
+            }
+            println!(
+                "Accuracy: {:.2}% on {} training examples",
+                classifier.accuracy(&embedded) * 100.,
+                embedded.len()
+            );
+            if !held_out.is_empty() {
+                println!(
+                    "Accuracy: {:.2}% on {} held-out examples",
+                    classifier.accuracy(&held_out) * 100.,
+                    held_out.len()
+                );
+            }
+            save_classifier(&out, &classifier)?;
+            println!(
+                "Classifier of {} labels written to {}",
+                classifier.labels.len(),
+                out.display()
+            );
+            Ok(())
+        }
+        Cli::Classify {
+            vocab,
+            model,
+            classifier,
+            prompt,
+            prompt_file,
+            architecture,
+            hf_tokenizer,
+        } => {
+            let prompt = read_prompts(prompt, &prompt_file)?;
+            let head = load_classifier(&classifier)?;
+            let (mut gpt, tokenizer) = load_frozen_model(
+                model_builder,
+                graph,
+                &model,
+                &vocab,
+                hf_tokenizer.as_deref(),
+                architecture,
+            )?;
+            for prompt in prompt.iter() {
+                let embedding = gpt.embed(&tokenizer.tokenize(prompt), head.pooling)?;
+                if embedding.len() != head.embedding_degree() {
+                    return Err(FemtoError::Config(format!(
+                        "the classifier takes embeddings of {} values, the model gives {}",
+                        head.embedding_degree(),
+                        embedding.len()
+                    )));
+                }
+                let (label, probability) = head.classify(&embedding);
+                println!("{}\t{:.4}", head.labels[label], probability);
+            }
+            Ok(())
+        }
+        Cli::Attention {
+            vocab,
+            model,
+            prompt,
+            layer,
+            out,
+            architecture,
+            hf_tokenizer,
+        } => {
+            let (mut gpt, tokenizer) = load_frozen_model(
+                model_builder,
+                graph,
+                &model,
+                &vocab,
+                hf_tokenizer.as_deref(),
+                architecture,
+            )?;
+
+            let tokens = tokenizer.tokenize(&prompt);
+            let mut weights = gpt.attention(&tokens)?;
+            if let Some(layer) = layer {
+                let num_layers = weights.shape()[0];
+                if layer >= num_layers {
+                    return Err(FemtoError::Config(format!(
+                        "layer {} is out of range, the model has {} layers",
+                        layer, num_layers
+                    )));
+                }
+                weights = weights.get(layer)?.into();
+            }
+
+            if let Some(out) = out
+                .as_ref()
+                .filter(|p| p.extension() == Some("npy".as_ref()))
+            {
                 let mut bytes = Vec::new();
-                ts_file.read_to_end(&mut bytes).unwrap();
-                let ts: TrainingState = bincode::deserialize(&bytes).unwrap();
-                gpt.set_training_state(ts, true)?;
+                write_npy(&mut bytes, &weights)?;
+                write_atomic(out, &bytes)?;
+                println!("Attention weights written to {}", out.display());
+                return Ok(());
+            }
+
+            // Nested arrays of the weights, along with the text of the tokens they relate
+            fn nest(t: &[f32], shape: &[usize]) -> serde_json::Value {
+                match shape {
+                    [] => t[0].into(),
+                    [n, rest @ ..] => t
+                        .chunks(t.len() / n)
+                        .map(|c| nest(c, rest))
+                        .collect::<Vec<_>>()
+                        .into(),
+                }
+            }
+            let json = serde_json::json!({
+                "tokens": tokens
+                    .iter()
+                    .skip(tokens.len().saturating_sub(weights.shape()[weights.dim() - 1]))
+                    .map(|t| tokenizer.untokenize(&[*t]))
+                    .collect::<Vec<_>>(),
+                "layer": layer,
+                "weights": nest(weights.blob(), weights.shape()),
+            });
+            match out {
+                Some(out) => {
+                    write_atomic(&out, json.to_string().as_bytes())?;
+                    println!("Attention weights written to {}", out.display());
+                }
+                None => println!("{}", json),
             }
 
-            println!();
+            Ok(())
+        }
+        Cli::Inspect { model } => {
+            let compressed = fs::read(&model).map_err(FemtoError::io(&model))?;
+            let bytes = decompress(&compressed).map_err(FemtoError::checkpoint(&model))?;
+            let checkpoint = Checkpoint::read(&bytes).map_err(FemtoError::checkpoint(&model))?;
             println!(
-                "Starting the training loop... (This make take hours to converge! be patient!)"
+                "Format version: {}{}",
+                checkpoint_version(&bytes),
+                if compressed.starts_with(ZSTD_MAGIC) {
+                    format!(" (zstd-compressed, {} bytes)", compressed.len())
+                } else {
+                    String::new()
+                }
             );
-            println!();
+            for (key, value) in checkpoint.metadata.iter() {
+                // The tokenizer of a bundle is the contents of a whole file
+                match key.as_str() {
+                    BUNDLE_TOKENIZER_KEY => println!("{}: ({} bytes)", key, value.len()),
+                    _ => println!("{}: {}", key, value),
+                }
+            }
+            let state = checkpoint
+                .into_training_state()
+                .map_err(FemtoError::checkpoint(&model))?;
 
-            let base_lr = 0.001;
-            let min_lr = 0.00001;
-            let warmup_steps = 100;
-            let decay_steps = 50000;
+            // Only GPT-2 checkpoints learn their positional embeddings, which fix the context
+            match (ModelShape::of(&state), state.tensors.get("pos_embedding")) {
+                (Ok(shape), pos_embedding) => println!(
+                    "Architecture: {} ({} layers of {} heads of size {}, {}-dimensional \
+                     embeddings, vocabulary of {}{})",
+                    if pos_embedding.is_some() {
+                        "gpt2"
+                    } else {
+                        "femto"
+                    },
+                    shape.num_layers,
+                    shape.num_heads,
+                    shape.head_size,
+                    shape.embedding_degree,
+                    shape.vocab_size,
+                    pos_embedding
+                        .map_or(String::new(), |p| format!(", context of {}", p.shape()[0]))
+                ),
+                // e.g. LoRA adapters, saved without their base model
+                (Err(e), _) => println!("Architecture: unknown ({})", e),
+            }
+            println!(
+                "Parameters: {}",
+                state.tensors.values().map(|t| t.size()).sum::<usize>()
+            );
+            match state.optimizer.state.len() {
+                0 => println!("Optimizer: step {}, no state", state.optimizer.step),
+                n => println!(
+                    "Optimizer: step {}, state of {} tensors",
+                    state.optimizer.step, n
+                ),
+            }
+            if let Some(sampler) = state.sampler {
+                println!(
+                    "Sampler: epoch {}, {} windows sampled (Seed {})",
+                    sampler.epoch, sampler.cursor, sampler.seed
+                );
+            }
+            if state.progress != Progress::default() {
+                println!(
+                    "Trained on: {} tokens in {:.1} hours{}",
+                    state.progress.tokens,
+                    state.progress.elapsed.as_secs_f64() / 3600.,
+                    state
+                        .provenance
+                        .loss
+                        .map_or(String::new(), |loss| format!(", last loss {:.4}", loss))
+                );
+            }
+            if let (Some(dataset), Some(tokenizer)) =
+                (&state.provenance.dataset, &state.provenance.tokenizer)
+            {
+                println!("Data: dataset {}, tokenizer {}", dataset, tokenizer);
+            }
 
-            let learning_rate = |step| {
-                if step < warmup_steps {
-                    (base_lr / warmup_steps as f32) * step as f32
-                } else {
-                    // Fancy LR tuning, thanks to https://github.com/cutoken!
-                    f32::max(
-                        min_lr,
-                        base_lr
-                            - (base_lr - min_lr) * (step - warmup_steps) as f32
-                            / decay_steps as f32,
-                    )
+            println!("Tensors:");
+            let mut names = state.tensors.keys().collect::<Vec<_>>();
+            names.sort();
+            for name in names {
+                let t = &state.tensors[name];
+                let norm = t.blob().iter().map(|v| v * v).sum::<f32>().sqrt();
+                println!(
+                    "  {:<32} {:<16} norm {:.4}",
+                    name,
+                    format!("{:?}", t.shape()),
+                    norm
+                );
+            }
+            Ok(())
+        }
+        Cli::Resize {
+            model,
+            layers,
+            init_new,
+            out,
+        } => {
+            let mut rng = rand::thread_rng();
+            let state = load_training_state(&model)?;
+            let shape = ModelShape::of(&state)?;
+            if layers == 0 {
+                return Err(FemtoError::Config(
+                    "a model needs at least one layer".into(),
+                ));
+            }
+
+            // The tensors of the kept layers are loaded, those of added ones keep their random
+            // initialization
+            let mut gpt = checkpoint_builder(model_builder, &state)?
+                .layers(layers)
+                .build_with_rng(&mut rng, graph)?;
+            gpt.set_training_state(state, false)?;
+            gpt.sync()?;
+            let mut ts = gpt.get_training_state()?;
+            ts.optimizer = Default::default();
+            ts.sampler = None;
+
+            if init_new == LayerInit::CopyLast {
+                let names = ts.tensors.keys().cloned().collect::<Vec<_>>();
+                for l in shape.num_layers..layers {
+                    for name in names.iter() {
+                        // Tensors of the `l`-th layer are left unchanged by the renaming
+                        if with_layer(name, l).as_ref() != Some(name) {
+                            continue;
+                        }
+                        let last = with_layer(name, shape.num_layers - 1).unwrap();
+                        let t = ts.tensors[&last].clone();
+                        ts.tensors.insert(name.clone(), t);
+                    }
+                }
+            }
+            save_training_state(out.as_ref().unwrap_or(&model), &ts)?;
+            println!(
+                "Resized from {} to {} layers ({} parameters)",
+                shape.num_layers,
+                layers,
+                ts.tensors.values().map(|t| t.size()).sum::<usize>()
+            );
+            Ok(())
+        }
+        Cli::ExtendVocab {
+            model,
+            vocab,
+            hf_tokenizer,
+            out,
+        } => {
+            let tokenizer = load_tokenizer(&vocab, hf_tokenizer.as_deref())?;
+            let mut state = load_training_state(&model)?;
+            let old_size = ModelShape::of(&state)?.vocab_size;
+            let new_size = tokenizer.vocab_size();
+            if new_size < old_size {
+                return Err(FemtoError::Config(format!(
+                    "the vocabulary has {} tokens, fewer than the {} of the model",
+                    new_size, old_size
+                )));
+            }
+            // The vocabulary-sized dimension of each tensor depending on it
+            for (name, axis) in [
+                ("token_embedding", 0),
+                ("head_map_weights", 1),
+                ("head_map_bias", 0),
+            ] {
+                if let Some(t) = state.tensors.get(name) {
+                    let extended = extend_with_mean(t, axis, new_size)?;
+                    state.tensors.insert(name.into(), extended);
+                }
+            }
+            state.optimizer = Default::default();
+            state.sampler = None;
+            save_training_state(out.as_ref().unwrap_or(&model), &state)?;
+            println!(
+                "Extended the vocabulary from {} to {} tokens",
+                old_size, new_size
+            );
+            Ok(())
+        }
+        Cli::Merge {
+            models,
+            weights,
+            out,
+        } => {
+            let weights = match weights.len() {
+                0 => vec![1.; models.len()],
+                n if n != models.len() => {
+                    return Err(FemtoError::Config(format!(
+                        "got {} weights for {} models",
+                        n,
+                        models.len()
+                    )))
                 }
+                _ => weights,
             };
+            if weights.iter().any(|w| *w < 0. || !w.is_finite())
+                || weights.iter().sum::<f32>() <= 0.
+            {
+                return Err(FemtoError::Config(format!(
+                    "expected non-negative weights with a positive sum, got {:?}",
+                    weights
+                )));
+            }
+            let states = models
+                .iter()
+                .map(|path| load_training_state(path))
+                .collect::<Result<Vec<_>, _>>()?;
+            let merged = average_training_states(&states, &weights).map_err(|e| match e {
+                CheckpointError::Mismatch { index, .. } => {
+                    FemtoError::checkpoint(&models[index])(e)
+                }
+                e => FemtoError::checkpoint(&out)(e),
+            })?;
+            save_training_state(&out, &merged)?;
+            println!("Merged {} models", models.len());
+            Ok(())
+        }
+        Cli::Quantize {
+            vocab,
+            model,
+            out,
+            format,
+        } => {
+            let mut rng = rand::thread_rng();
+            let (tokenizer, bundled) = load_model_tokenizer(&model, &vocab, None)?;
 
-            let callback = |gpt: &mut GPT<_>| {
-                let mut rng = rand::thread_rng();
-                let inference_temperature = 0.5; // How creative? 0.0 min 1.0 max
+            let mut gpt = bundle_dims(model_builder, bundled)
+                .vocab_size(tokenizer.vocab_size())
+                .build_with_rng(&mut rng, graph)?;
 
-                println!("Generating text:");
+            gpt.set_training_state(load_training_state(&model)?, false)?;
+            gpt.sync()?;
 
-                let inference = gpt.infer(
-                    &mut rng,
-                    &tokenizer.tokenize("\n"),
-                    100,
-                    inference_temperature,
-                    |_ch| {},
-                )?;
+            let qs = gpt.quantize(format)?;
+            let bytes = bincode::serialize(&qs)
+                .map_err(CheckpointError::from)
+                .map_err(FemtoError::checkpoint(&out))?;
+            write_atomic(&out, &bytes)?;
+            println!(
+                "Quantized model written to {} ({} bytes)",
+                out.display(),
+                bytes.len()
+            );
+
+            Ok(())
+        }
+        Cli::Export {
+            vocab,
+            model,
+            out,
+            format,
+            weights_only,
+        } => {
+            if weights_only {
+                let ts = TrainingState {
+                    optimizer: Default::default(),
+                    sampler: None,
+                    progress: Default::default(),
+                    ..load_training_state(&model)?
+                };
+                save_training_state(&out, &ts)?;
+                println!("Weights written to {}", out.display());
+                return Ok(());
+            }
 
-                // Generate 100 character with the currently trained model before
-                // starting the training loop.
-                println!("{}", tokenizer.untokenize(&inference));
+            let (tokenizer, _) = load_model_vocab(&model, &vocab)?;
+            let ts = load_training_state(&model)?;
 
-                println!("Saving the model...");
-                gpt.sync().unwrap();
-                let ts = gpt.get_training_state().unwrap();
-                let bytes = bincode::serialize(&ts).unwrap();
-                fs::write(training_state_path, &bytes).expect("Unable to write file");
+            let mut bytes = Vec::new();
+            match format {
+                ExportFormat::Gguf => {
+                    write_gguf(&mut bytes, &ts, tokenizer.vocab(), tokenizer.scores())
+                }
+                ExportFormat::Safetensors => write_safetensors(
+                    &mut bytes,
+                    &ts.tensors,
+                    &[("format".to_string(), "femto".to_string())].into(),
+                ),
+            }?;
+            write_atomic(&out, &bytes)?;
+            println!("Model exported to {}", out.display());
 
-                Ok(())
+            Ok(())
+        }
+        Cli::ExportBundle {
+            vocab,
+            hf_tokenizer,
+            model,
+            out,
+        } => {
+            // Loading the tokenizer checks its file, bundled as is
+            let vocab_size = load_tokenizer(&vocab, hf_tokenizer.as_deref())?.vocab_size();
+            let tokenizer = match &hf_tokenizer {
+                Some(path) => BundledTokenizer::HuggingFace(read_text(path)?),
+                None => BundledTokenizer::SentencePiece(read_text(&vocab)?),
             };
+            let mut state = load_training_state(&model)?;
+            let config =
+                BundleConfig::of(&state, num_tokens).map_err(FemtoError::checkpoint(&model))?;
+            if config.vocab_size != vocab_size {
+                return Err(FemtoError::Config(format!(
+                    "the tokenizer has {} tokens, the model a vocabulary of {}",
+                    vocab_size, config.vocab_size
+                )));
+            }
+            // Only the weights are needed for inference
+            state.optimizer = Default::default();
+            state.sampler = None;
 
-            // Training loop!
-            #[cfg(not(feature = "gpu"))]
-            gpt.train_cpu(
-                &dataset,
-                100000,
-                batch_size,
-                None, // or Some(n), limit backward process to last n computations
-                &AdamW::new(),
-                learning_rate,
-                callback,
+            save_bundle(
+                &out,
+                &Bundle {
+                    state,
+                    tokenizer,
+                    config,
+                },
             )?;
+            println!(
+                "Bundle written to {} ({}, {} layers, context of {})",
+                out.display(),
+                match config.architecture {
+                    Architecture::Femto => "femto",
+                    Architecture::Gpt2 => "gpt2",
+                },
+                config.layers,
+                config.context
+            );
+
+            Ok(())
+        }
+        Cli::ImportGpt2 {
+            weights,
+            out,
+            num_heads,
+            num_tokens,
+        } => {
+            let bytes = fs::read(&weights).map_err(FemtoError::io(&weights))?;
+            let tensors = if weights.extension().is_some_and(|ext| ext == "npz") {
+                read_npz(&bytes)
+            } else {
+                read_safetensors(&bytes)
+            }?;
+            let ts = gpt2_training_state(tensors, num_heads, num_tokens)?;
+            let shape = ModelShape::of(&ts)?;
+            println!(
+                "Imported GPT-2 with {} layers, {} heads and {} embedding degree",
+                shape.num_layers, shape.num_heads, shape.embedding_degree
+            );
+
+            save_training_state(&out, &ts)?;
+            println!("Model saved to {}", out.display());
 
-            #[cfg(feature = "gpu")]
-            gpt.train(
-                &dataset,
-                100000,
+            Ok(())
+        }
+        Cli::DatasetStats {
+            dataset,
+            vocab,
+            hf_tokenizer,
+            top,
+        } => {
+            let tokenizer = load_tokenizer(&vocab, hf_tokenizer.as_deref())?;
+            let stats = DatasetStats::new(tokenizer.as_ref(), &read_text(&dataset)?);
+            print!("{}", stats.report(tokenizer.as_ref(), top));
+            Ok(())
+        }
+        Cli::GraphDump {
+            vocab,
+            format,
+            architecture,
+        } => {
+            let mut rng = rand::thread_rng();
+            let tokenizer = load_vocab(&vocab)?;
+
+            let gpt = model_builder
+                .batch_size(None)
+                .vocab_size(tokenizer.vocab_size())
+                .architecture(architecture)
+                .build_with_rng(&mut rng, graph)?;
+            print!("{}", dump_graph(&gpt.graph().nodes(), format));
+
+            Ok(())
+        }
+        Cli::Selftest {
+            gpu,
+            tolerance,
+            seed,
+        } => {
+            use rand::SeedableRng;
+            let mut rng = rand::rngs::StdRng::seed_from_u64(seed);
+            match gradcheck_ops(&mut rng) {
+                Ok(cases) => println!("Gradient check: {} cases passed", cases),
+                Err(e) => {
+                    println!("Gradient check failed: {}", e);
+                    std::process::exit(1);
+                }
+            }
+
+            if gpu {
+                if !graph.is_gpu() {
+                    println!("The GPU parity check requires a GPU backend");
+                    std::process::exit(1);
+                }
+                let mismatches = gpu_parity(graph, seed, tolerance)?;
+                for m in mismatches.iter() {
+                    println!(
+                        "Mismatch in {} of tensor {} ({}): {}",
+                        if m.grad { "gradient" } else { "value" },
+                        m.tensor_id,
+                        m.op_name.as_deref().unwrap_or(&m.name),
+                        m.max_diff
+                    );
+                }
+                if !mismatches.is_empty() {
+                    std::process::exit(1);
+                }
+                println!("GPU parity check passed");
+            }
+
+            Ok(())
+        }
+        Cli::Bench {
+            vocab_size,
+            embedding_degree,
+            context,
+            layers,
+            heads,
+            batch_size,
+            iterations,
+            precision,
+        } => {
+            let mut rng = rand::thread_rng();
+            println!("Backend: {} ({})", graph.backend(), graph.device_name());
+            let mut gpt = model_builder
+                .batch_size(is_gpu.then_some(batch_size))
+                .vocab_size(vocab_size)
+                .embedding_degree(embedding_degree)
+                .context(context)
+                .layers(layers)
+                .heads(heads)
+                .build_with_rng(&mut rng, graph)?;
+            gpt.set_precision(precision)?;
+            println!(
+                "Model: {} parameters, batches of {} x {} tokens",
+                gpt.num_params(),
                 batch_size,
-                None, // or Some(n), limit backward process to last n computations
-                &AdamW::new(),
-                learning_rate,
-                callback,
-            )?;
+                context
+            );
+            print!(
+                "{}",
+                gpt.bench(&mut rng, batch_size, iterations, &AdamW::new())?
+            );
 
             Ok(())
         }
+        Cli::Devices
+        | Cli::Estimate { .. }
+        | Cli::FetchDataset { .. }
+        | Cli::Push { .. }
+        | Cli::Pull { .. } => unreachable!(),
     }
-}
\ No newline at end of file
+}
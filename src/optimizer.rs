@@ -1,6 +1,6 @@
 use serde::{Deserialize, Serialize};
 
-use crate::tensor::{Tensor, TensorError, TensorOps};
+use crate::tensor::{Tensor, TensorError, TensorMutOps, TensorOps};
 use rayon::prelude::*;
 use std::collections::HashMap;
 
@@ -8,6 +8,109 @@ use std::collections::HashMap;
 pub struct OptimizerState {
     pub step: usize,
     pub state: HashMap<String, Tensor<f32>>,
+    /// 8-bit moments of the parameters, by name (See `AdamW::with_8bit_moments`). They are
+    /// dequantized into `state` before being saved.
+    #[serde(skip)]
+    pub quantized: HashMap<String, QuantizedMoments>,
+}
+
+impl OptimizerState {
+    /// The state with its 8-bit moments turned back into f32 ones.
+    pub fn dequantized(&self) -> Result<Self, TensorError> {
+        let mut state = self.state.clone();
+        for (name, moments) in self.quantized.iter() {
+            let (m, v) = moments.dequantize()?;
+            state.insert(format!("{}_m", name), m);
+            state.insert(format!("{}_v", name), v);
+        }
+        Ok(Self {
+            step: self.step,
+            state,
+            quantized: Default::default(),
+        })
+    }
+}
+
+/// Number of consecutive elements of 8-bit moments sharing a scale.
+pub const MOMENT_BLOCK_SIZE: usize = 256;
+
+/// Adam moments of a parameter in 8 bits per element, a quarter of their f32 size. Each block of
+/// `MOMENT_BLOCK_SIZE` elements is scaled by its largest value. The first moment keeps the square
+/// root of its magnitude, and the second one its fourth root, so that the small values of a block
+/// aren't all rounded to zero. The second moment is rounded up, which can only shorten the steps.
+#[derive(Debug, Clone, PartialEq)]
+pub struct QuantizedMoments {
+    pub shape: Vec<usize>,
+    pub m: Vec<i8>,
+    pub m_scale: Vec<f32>,
+    pub v: Vec<u8>,
+    pub v_scale: Vec<f32>,
+}
+
+impl QuantizedMoments {
+    pub fn quantize(m: &Tensor<f32>, v: &Tensor<f32>) -> Self {
+        let mut quantized = Self {
+            shape: m.shape().to_vec(),
+            m: Vec::with_capacity(m.size()),
+            m_scale: Vec::new(),
+            v: Vec::with_capacity(v.size()),
+            v_scale: Vec::new(),
+        };
+        for (m, v) in m
+            .blob()
+            .chunks(MOMENT_BLOCK_SIZE)
+            .zip(v.blob().chunks(MOMENT_BLOCK_SIZE))
+        {
+            let m_scale = m.iter().fold(0f32, |a, b| a.max(b.abs()));
+            let v_scale = v.iter().fold(0f32, |a, b| a.max(*b));
+            quantized
+                .m
+                .extend(m.iter().map(|m| quantize_m(*m, m_scale)));
+            quantized
+                .v
+                .extend(v.iter().map(|v| quantize_v(*v, v_scale)));
+            quantized.m_scale.push(m_scale);
+            quantized.v_scale.push(v_scale);
+        }
+        quantized
+    }
+    pub fn dequantize(&self) -> Result<(Tensor<f32>, Tensor<f32>), TensorError> {
+        let m = self
+            .m
+            .chunks(MOMENT_BLOCK_SIZE)
+            .zip(self.m_scale.iter())
+            .flat_map(|(m, scale)| m.iter().map(move |m| dequantize_m(*m, *scale)))
+            .collect();
+        let v = self
+            .v
+            .chunks(MOMENT_BLOCK_SIZE)
+            .zip(self.v_scale.iter())
+            .flat_map(|(v, scale)| v.iter().map(move |v| dequantize_v(*v, *scale)))
+            .collect();
+        Ok((Tensor::raw(&self.shape, m)?, Tensor::raw(&self.shape, v)?))
+    }
+}
+
+// The kernel of `GpuGraph` quantizes the same way
+fn quantize_m(m: f32, scale: f32) -> i8 {
+    if scale == 0. {
+        return 0;
+    }
+    ((m.abs() / scale).sqrt() * 127.).round().copysign(m) as i8
+}
+fn dequantize_m(q: i8, scale: f32) -> f32 {
+    let q = q as f32 / 127.;
+    q * q.abs() * scale
+}
+fn quantize_v(v: f32, scale: f32) -> u8 {
+    if scale == 0. {
+        return 0;
+    }
+    ((v / scale).sqrt().sqrt() * 255.).ceil().min(255.) as u8
+}
+fn dequantize_v(q: u8, scale: f32) -> f32 {
+    let q = q as f32 / 255.;
+    q * q * q * q * scale
 }
 
 #[cfg(feature = "gpu")]
@@ -18,7 +121,73 @@ pub struct GpuOptimizer {
     pub kernel_name: String,
 }
 
+/// Learning rate multiplier and weight decay of a parameter.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ParamSettings {
+    pub lr_scale: f32,
+    pub weight_decay: f32,
+}
+
+/// Settings applied to the parameters whose names match `pattern`, where `*` matches any
+/// sequence of characters (e.g. `*_bias`, or `token_embedding`).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ParamGroup {
+    pub pattern: String,
+    #[serde(default = "default_lr_scale")]
+    pub lr_scale: f32,
+    /// Overrides the weight decay of the optimizer
+    #[serde(default)]
+    pub weight_decay: Option<f32>,
+}
+
+fn default_lr_scale() -> f32 {
+    1.
+}
+
+impl ParamGroup {
+    pub fn matches(&self, name: &str) -> bool {
+        glob_match(self.pattern.as_bytes(), name.as_bytes())
+    }
+}
+
+/// Parameters that aren't weight decayed unless asked to (See `AdamW::with_decay_all_params`):
+/// biases, and the coefficients of the normalization layers, going by the names `GPT` gives them.
+pub const NO_DECAY_PATTERNS: &[&str] = &["*_bias", "*norm_*_coeff", "head_norm_coeff"];
+
+// Settings of the first group matching `name`, the weight decay of the parameters the groups
+// don't override being `weight_decay`, or zero for the ones of `NO_DECAY_PATTERNS`
+fn settings_of(
+    groups: &[ParamGroup],
+    weight_decay: f32,
+    decay_all_params: bool,
+    name: &str,
+) -> ParamSettings {
+    let group = groups.iter().find(|g| g.matches(name));
+    let exempt = !decay_all_params
+        && NO_DECAY_PATTERNS
+            .iter()
+            .any(|p| glob_match(p.as_bytes(), name.as_bytes()));
+    ParamSettings {
+        lr_scale: group.map_or(1., |g| g.lr_scale),
+        weight_decay: group.and_then(|g| g.weight_decay).unwrap_or(if exempt {
+            0.
+        } else {
+            weight_decay
+        }),
+    }
+}
+
+fn glob_match(pattern: &[u8], name: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some((b'*', rest)) => (0..=name.len()).any(|i| glob_match(rest, &name[i..])),
+        Some((c, rest)) => name.first() == Some(c) && glob_match(rest, &name[1..]),
+    }
+}
+
 pub trait Optimizer: Clone + Serialize + serde::de::DeserializeOwned {
+    /// Settings of the parameter named `name`, graphs scale the learning rate with them.
+    fn param_settings(&self, name: &str) -> ParamSettings;
     fn step(
         &self,
         params: HashMap<String, (&mut Tensor<f32>, &Tensor<f32>)>,
@@ -26,17 +195,33 @@ pub trait Optimizer: Clone + Serialize + serde::de::DeserializeOwned {
         learning_rate: f32,
     ) -> Result<(), TensorError>;
 
+    /// Whether graphs should keep the moments of the optimizer in 8 bits (See
+    /// `QuantizedMoments`).
+    fn moments_8bit(&self) -> bool {
+        false
+    }
+
+    /// Bytes of state the optimizer keeps for a parameter of this shape.
+    fn state_size(&self, shape: &[usize]) -> usize;
+
+    /// `None` for the optimizers without kernels, whose steps `GpuGraph` runs on the host.
     #[cfg(feature = "gpu")]
-    fn gpu_impl(&self, params: &HashMap<String, Vec<usize>>) -> GpuOptimizer;
+    fn gpu_impl(&self, params: &HashMap<String, Vec<usize>>) -> Option<GpuOptimizer>;
 }
 
 const EPSILON: f32 = 1e-8;
 
-#[derive(Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AdamW {
     beta1: f32,
     beta2: f32,
     weight_decay: f32,
+    #[serde(default)]
+    groups: Vec<ParamGroup>,
+    #[serde(default)]
+    decay_all_params: bool,
+    #[serde(default)]
+    moments_8bit: bool,
 }
 
 impl AdamW {
@@ -45,13 +230,54 @@ impl AdamW {
             beta1: 0.9,
             beta2: 0.999,
             weight_decay: 0.01,
+            groups: Vec::new(),
+            decay_all_params: false,
+            moments_8bit: false,
         }
     }
+    pub fn with_weight_decay(mut self, weight_decay: f32) -> Self {
+        self.weight_decay = weight_decay;
+        self
+    }
+    /// Weight decays the parameters of `NO_DECAY_PATTERNS` too.
+    pub fn with_decay_all_params(mut self) -> Self {
+        self.decay_all_params = true;
+        self
+    }
+    /// Applies the settings of the first matching group to each parameter, parameters matching
+    /// none of them get the defaults.
+    pub fn with_groups(mut self, groups: Vec<ParamGroup>) -> Self {
+        self.groups = groups;
+        self
+    }
+    /// Keeps the moments in 8 bits instead of 32, cutting the memory taken by the optimizer by
+    /// three quarters at the cost of some precision in the updates.
+    pub fn with_8bit_moments(mut self) -> Self {
+        self.moments_8bit = true;
+        self
+    }
 }
 
-// Adam optimizer with weight decay!
+// Adam optimizer with weight decay! The decay is decoupled from the gradients: parameters shrink
+// by `learning_rate * weight_decay` of themselves, instead of the decay going through the moments
+// as an L2 penalty would.
 // https://pytorch.org/docs/stable/generated/torch.optim.AdamW.html
 impl Optimizer for AdamW {
+    fn param_settings(&self, name: &str) -> ParamSettings {
+        settings_of(&self.groups, self.weight_decay, self.decay_all_params, name)
+    }
+    fn moments_8bit(&self) -> bool {
+        self.moments_8bit
+    }
+    fn state_size(&self, shape: &[usize]) -> usize {
+        let size = shape.iter().product::<usize>();
+        if self.moments_8bit {
+            // A byte per element of each moment, and a scale per block of each
+            2 * size + 2 * size.div_ceil(MOMENT_BLOCK_SIZE) * std::mem::size_of::<f32>()
+        } else {
+            2 * size * std::mem::size_of::<f32>()
+        }
+    }
     fn step(
         &self,
         params: HashMap<String, (&mut Tensor<f32>, &Tensor<f32>)>,
@@ -61,22 +287,24 @@ impl Optimizer for AdamW {
         for (name, m, v) in params
             .into_par_iter()
             .map(|(name, (param, grad))| {
+                let settings = self.param_settings(&name);
+                let learning_rate = learning_rate * settings.lr_scale;
                 let m_key = format!("{}_m", name);
                 let v_key = format!("{}_v", name);
-                let mut m = optimizer_state
-                    .state
-                    .get(&m_key)
-                    .cloned()
-                    .unwrap_or(Tensor::zeros(param.shape()));
-                let mut v = optimizer_state
-                    .state
-                    .get(&v_key)
-                    .cloned()
-                    .unwrap_or(Tensor::zeros(param.shape()));
+                // f32 moments (e.g. of a loaded checkpoint) take precedence over 8-bit ones
+                let (mut m, mut v) = match (
+                    optimizer_state.state.get(&m_key),
+                    optimizer_state.state.get(&v_key),
+                    optimizer_state.quantized.get(&name),
+                ) {
+                    (Some(m), Some(v), _) => (m.clone(), v.clone()),
+                    (_, _, Some(quantized)) => quantized.dequantize()?,
+                    _ => (Tensor::zeros(param.shape()), Tensor::zeros(param.shape())),
+                };
 
                 // Weight decay
-                *param =
-                    (&*param - &(&*param * &Tensor::scalar(learning_rate * self.weight_decay))?)?;
+                *param = (&*param
+                    - &(&*param * &Tensor::scalar(learning_rate * settings.weight_decay))?)?;
 
                 m = (&(&Tensor::scalar(self.beta1) * &m)?
                     + &(&Tensor::scalar(1. - self.beta1) * grad)?)?;
@@ -101,21 +329,31 @@ impl Optimizer for AdamW {
         {
             let m_key = format!("{}_m", name);
             let v_key = format!("{}_v", name);
-            optimizer_state.state.insert(m_key, m);
-            optimizer_state.state.insert(v_key, v);
+            if self.moments_8bit {
+                optimizer_state.state.remove(&m_key);
+                optimizer_state.state.remove(&v_key);
+                optimizer_state
+                    .quantized
+                    .insert(name, QuantizedMoments::quantize(&m, &v));
+            } else {
+                optimizer_state.quantized.remove(&name);
+                optimizer_state.state.insert(m_key, m);
+                optimizer_state.state.insert(v_key, v);
+            }
         }
         optimizer_state.step += 1;
         Ok(())
     }
 
     #[cfg(feature = "gpu")]
-    fn gpu_impl(&self, params: &HashMap<String, Vec<usize>>) -> GpuOptimizer {
+    fn gpu_impl(&self, params: &HashMap<String, Vec<usize>>) -> Option<GpuOptimizer> {
         let source_code = "
         __kernel void optimizer(__global float *param,
                                 __global float *grad,
                                 __global float *m,
                                 __global float *v,
                                 float learning_rate,
+                                float weight_decay,
                                 ulong step,
                                 ulong n) {
             uint id = get_global_id(0);
@@ -125,7 +363,6 @@ impl Optimizer for AdamW {
             v += id;
             float beta1 = 0.9;
             float beta2 = 0.999;
-            float weight_decay = 0.01;
             if(id < n) {
                 *param = *param - *param * learning_rate * weight_decay;
                 *m = beta1 * (*m) + (1 - beta1) * (*grad);
@@ -137,7 +374,7 @@ impl Optimizer for AdamW {
             }
         }"
         .into();
-        GpuOptimizer {
+        Some(GpuOptimizer {
             source_code,
             extra_buffers: params
                 .iter()
@@ -148,6 +385,424 @@ impl Optimizer for AdamW {
                 .flatten()
                 .collect(),
             kernel_name: "optimizer".into(),
+        })
+    }
+}
+
+const ADAFACTOR_EPSILON: f32 = 1e-30;
+
+/// Adafactor, which keeps no first moments, and for matrices only the sums of the rows and columns
+/// of the second moments, making its memory negligible next to the one of `AdamW`. Parameters of
+/// more than two dimensions are treated as matrices of rows of their last dimension.
+/// https://arxiv.org/abs/1804.04235
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Adafactor {
+    // The second moments decay with `1 - step^-decay_rate`
+    decay_rate: f32,
+    // Largest root mean square of the updates before they are scaled by the learning rate
+    clip_threshold: f32,
+    weight_decay: f32,
+    #[serde(default)]
+    groups: Vec<ParamGroup>,
+    #[serde(default)]
+    decay_all_params: bool,
+}
+
+impl Adafactor {
+    pub fn new() -> Self {
+        Self {
+            decay_rate: 0.8,
+            clip_threshold: 1.,
+            weight_decay: 0.01,
+            groups: Vec::new(),
+            decay_all_params: false,
         }
     }
+    /// See `AdamW::with_groups`.
+    pub fn with_groups(mut self, groups: Vec<ParamGroup>) -> Self {
+        self.groups = groups;
+        self
+    }
+    pub fn with_weight_decay(mut self, weight_decay: f32) -> Self {
+        self.weight_decay = weight_decay;
+        self
+    }
+    /// See `AdamW::with_decay_all_params`.
+    pub fn with_decay_all_params(mut self) -> Self {
+        self.decay_all_params = true;
+        self
+    }
+}
+
+impl Default for Adafactor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Optimizer for Adafactor {
+    fn param_settings(&self, name: &str) -> ParamSettings {
+        settings_of(&self.groups, self.weight_decay, self.decay_all_params, name)
+    }
+    fn state_size(&self, shape: &[usize]) -> usize {
+        let size = shape.iter().product::<usize>();
+        let floats = match shape.last() {
+            Some(&cols) if shape.len() >= 2 => size / cols + cols,
+            _ => size,
+        };
+        floats * std::mem::size_of::<f32>()
+    }
+    fn step(
+        &self,
+        params: HashMap<String, (&mut Tensor<f32>, &Tensor<f32>)>,
+        optimizer_state: &mut OptimizerState,
+        learning_rate: f32,
+    ) -> Result<(), TensorError> {
+        let t = (optimizer_state.step + 1) as f32;
+        let beta2 = 1. - t.powf(-self.decay_rate);
+        let moment = |key: &str, len: usize| {
+            optimizer_state
+                .state
+                .get(key)
+                .map_or_else(|| vec![0.; len], |v| v.blob().to_vec())
+        };
+        for (name, moments) in params
+            .into_par_iter()
+            .map(|(name, (param, grad))| {
+                let settings = self.param_settings(&name);
+                let learning_rate = learning_rate * settings.lr_scale;
+                let g = grad.blob();
+                let g2 = g.iter().map(|g| g * g + ADAFACTOR_EPSILON);
+
+                let (update, moments) = if param.dim() >= 2 {
+                    let cols = param.shape()[param.dim() - 1];
+                    let rows = param.size() / cols;
+                    let r_key = format!("{}_vr", name);
+                    let c_key = format!("{}_vc", name);
+                    let mut r = moment(&r_key, rows);
+                    let mut c = moment(&c_key, cols);
+                    let mut row_sums = vec![0.; rows];
+                    let mut col_sums = vec![0.; cols];
+                    for (i, g2) in g2.enumerate() {
+                        row_sums[i / cols] += g2;
+                        col_sums[i % cols] += g2;
+                    }
+                    for (r, sum) in r.iter_mut().zip(row_sums) {
+                        *r = beta2 * *r + (1. - beta2) * sum / cols as f32;
+                    }
+                    for (c, sum) in c.iter_mut().zip(col_sums) {
+                        *c = beta2 * *c + (1. - beta2) * sum / rows as f32;
+                    }
+                    // The second moments are approximated by `r * c / mean(r)`
+                    let r_mean = r.iter().sum::<f32>() / rows as f32;
+                    let update = g
+                        .iter()
+                        .enumerate()
+                        .map(|(i, g)| g / (r[i / cols] * c[i % cols] / r_mean).sqrt())
+                        .collect::<Vec<_>>();
+                    let moments = vec![
+                        (r_key, Tensor::raw(&[rows], r)?),
+                        (c_key, Tensor::raw(&[cols], c)?),
+                    ];
+                    (update, moments)
+                } else {
+                    let v_key = format!("{}_v", name);
+                    let mut v = moment(&v_key, param.size());
+                    for (v, g2) in v.iter_mut().zip(g2) {
+                        *v = beta2 * *v + (1. - beta2) * g2;
+                    }
+                    let update = g.iter().zip(&v).map(|(g, v)| g / v.sqrt()).collect();
+                    (update, vec![(v_key, Tensor::raw(param.shape(), v)?)])
+                };
+
+                let rms = (update.iter().map(|u| u * u).sum::<f32>() / update.len() as f32).sqrt();
+                let step_size = learning_rate / (rms / self.clip_threshold).max(1.);
+                for (p, u) in param.blob_mut().iter_mut().zip(update) {
+                    *p -= *p * learning_rate * settings.weight_decay + u * step_size;
+                }
+                Ok((name, moments))
+            })
+            .collect::<Result<Vec<_>, TensorError>>()?
+        {
+            // Moments of another optimizer (e.g. of a checkpoint trained with `AdamW`) would only
+            // take memory
+            optimizer_state.state.remove(&format!("{}_m", name));
+            optimizer_state.quantized.remove(&name);
+            if moments.len() > 1 {
+                optimizer_state.state.remove(&format!("{}_v", name));
+            }
+            optimizer_state.state.extend(moments);
+        }
+        optimizer_state.step += 1;
+        Ok(())
+    }
+
+    #[cfg(feature = "gpu")]
+    fn gpu_impl(&self, _params: &HashMap<String, Vec<usize>>) -> Option<GpuOptimizer> {
+        None
+    }
+}
+
+/// Optimizer selected at runtime, see `AnyGraph` for the same with graphs.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum AnyOptimizer {
+    AdamW(AdamW),
+    Adafactor(Adafactor),
+}
+
+impl AnyOptimizer {
+    pub fn with_groups(self, groups: Vec<ParamGroup>) -> Self {
+        match self {
+            Self::AdamW(o) => Self::AdamW(o.with_groups(groups)),
+            Self::Adafactor(o) => Self::Adafactor(o.with_groups(groups)),
+        }
+    }
+    pub fn with_weight_decay(self, weight_decay: f32) -> Self {
+        match self {
+            Self::AdamW(o) => Self::AdamW(o.with_weight_decay(weight_decay)),
+            Self::Adafactor(o) => Self::Adafactor(o.with_weight_decay(weight_decay)),
+        }
+    }
+    pub fn with_decay_all_params(self) -> Self {
+        match self {
+            Self::AdamW(o) => Self::AdamW(o.with_decay_all_params()),
+            Self::Adafactor(o) => Self::Adafactor(o.with_decay_all_params()),
+        }
+    }
+}
+
+impl std::str::FromStr for AnyOptimizer {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "adamw" => Ok(Self::AdamW(AdamW::new())),
+            "adafactor" => Ok(Self::Adafactor(Adafactor::new())),
+            _ => Err(format!("expected `adamw` or `adafactor`, got `{}`", s)),
+        }
+    }
+}
+
+impl Optimizer for AnyOptimizer {
+    fn param_settings(&self, name: &str) -> ParamSettings {
+        match self {
+            Self::AdamW(o) => o.param_settings(name),
+            Self::Adafactor(o) => o.param_settings(name),
+        }
+    }
+    fn step(
+        &self,
+        params: HashMap<String, (&mut Tensor<f32>, &Tensor<f32>)>,
+        optimizer_state: &mut OptimizerState,
+        learning_rate: f32,
+    ) -> Result<(), TensorError> {
+        match self {
+            Self::AdamW(o) => o.step(params, optimizer_state, learning_rate),
+            Self::Adafactor(o) => o.step(params, optimizer_state, learning_rate),
+        }
+    }
+    fn moments_8bit(&self) -> bool {
+        match self {
+            Self::AdamW(o) => o.moments_8bit(),
+            Self::Adafactor(o) => o.moments_8bit(),
+        }
+    }
+    fn state_size(&self, shape: &[usize]) -> usize {
+        match self {
+            Self::AdamW(o) => o.state_size(shape),
+            Self::Adafactor(o) => o.state_size(shape),
+        }
+    }
+
+    #[cfg(feature = "gpu")]
+    fn gpu_impl(&self, params: &HashMap<String, Vec<usize>>) -> Option<GpuOptimizer> {
+        match self {
+            Self::AdamW(o) => o.gpu_impl(params),
+            Self::Adafactor(o) => o.gpu_impl(params),
+        }
+    }
+}
+
+// Dynamic loss scaling for half-precision training: the loss is multiplied by a large factor so
+// that small gradients don't underflow, and the factor is halved whenever the gradients overflow.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LossScaler {
+    scale: f32,
+    growth_interval: usize,
+    good_steps: usize,
+}
+
+impl LossScaler {
+    pub fn new() -> Self {
+        Self {
+            scale: 65536.,
+            growth_interval: 2000,
+            good_steps: 0,
+        }
+    }
+    pub fn scale(&self) -> f32 {
+        self.scale
+    }
+    /// Updates the scale given whether the scaled gradients were all finite, returns `false` if
+    /// the optimizer step should be skipped.
+    pub fn update(&mut self, finite: bool) -> bool {
+        if !finite {
+            self.scale = f32::max(self.scale / 2., 1.);
+            self.good_steps = 0;
+            return false;
+        }
+        self.good_steps += 1;
+        if self.good_steps == self.growth_interval {
+            self.scale *= 2.;
+            self.good_steps = 0;
+        }
+        true
+    }
+}
+
+impl Default for LossScaler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_param_groups() {
+        let groups: Vec<ParamGroup> = serde_json::from_str(
+            r#"[{"pattern": "*_bias", "weight_decay": 0}, {"pattern": "head_*_k", "lr_scale": 0.5}]"#,
+        )
+        .unwrap();
+        let opt = AdamW::new().with_groups(groups);
+        let settings = |name| {
+            let s = opt.param_settings(name);
+            (s.lr_scale, s.weight_decay)
+        };
+        assert_eq!(settings("proj_0_bias"), (1., 0.));
+        assert_eq!(settings("head_0_1_k"), (0.5, 0.01));
+        assert_eq!(settings("head_0_1_k_bias"), (1., 0.));
+        assert_eq!(settings("token_embedding"), (1., 0.01));
+
+        // Without gradients, only the weight decay moves the parameters
+        let mut bias = Tensor::constant(&[2], 1.);
+        let mut weights = Tensor::constant(&[2], 1.);
+        let zeros = Tensor::zeros(&[2]);
+        let params = HashMap::from([
+            ("proj_0_bias".to_string(), (&mut bias, &zeros)),
+            ("proj_0_weights".to_string(), (&mut weights, &zeros)),
+        ]);
+        opt.step(params, &mut OptimizerState::default(), 0.1)
+            .unwrap();
+        assert_eq!(bias.blob(), &[1., 1.]);
+        assert_eq!(weights.blob(), &[0.999, 0.999]);
+    }
+
+    #[test]
+    fn test_decay_exclusions() {
+        let decay = |opt: &AdamW, name| opt.param_settings(name).weight_decay;
+        let opt = AdamW::new().with_weight_decay(0.1);
+        assert_eq!(decay(&opt, "proj_0_weights"), 0.1);
+        assert_eq!(decay(&opt, "token_embedding"), 0.1);
+        assert_eq!(decay(&opt, "proj_0_bias"), 0.);
+        assert_eq!(decay(&opt, "atten_norm_0_coeff"), 0.);
+        assert_eq!(decay(&opt, "norm_1_coeff"), 0.);
+        assert_eq!(decay(&opt, "head_norm_coeff"), 0.);
+
+        let opt = opt.with_decay_all_params();
+        assert_eq!(decay(&opt, "proj_0_bias"), 0.1);
+        assert_eq!(decay(&opt, "head_norm_coeff"), 0.1);
+
+        // Groups still take precedence
+        let opt = AdamW::new().with_groups(vec![ParamGroup {
+            pattern: "norm_*".into(),
+            lr_scale: 1.,
+            weight_decay: Some(0.5),
+        }]);
+        assert_eq!(decay(&opt, "norm_0_coeff"), 0.5);
+    }
+
+    // Fits `w` to a target, along directions whose curvatures span four orders of magnitude
+    fn fit<O: Optimizer>(opt: &O, shape: &[usize], steps: usize) -> (f32, OptimizerState) {
+        let n = shape.iter().product::<usize>();
+        let curvature = (0..n)
+            .map(|i| 10f32.powf(i as f32 % 5. - 2.))
+            .collect::<Vec<_>>();
+        let target = (0..n).map(|i| (i as f32 * 0.37).sin()).collect::<Vec<_>>();
+        let loss = |w: &Tensor<f32>| {
+            w.blob()
+                .iter()
+                .zip(&target)
+                .zip(&curvature)
+                .map(|((w, t), a)| a * (w - t) * (w - t) / 2.)
+                .sum::<f32>()
+        };
+        let mut w = Tensor::zeros(shape);
+        let mut state = OptimizerState::default();
+        for _ in 0..steps {
+            let grad = Tensor::raw(
+                shape,
+                w.blob()
+                    .iter()
+                    .zip(&target)
+                    .zip(&curvature)
+                    .map(|((w, t), a)| a * (w - t))
+                    .collect(),
+            )
+            .unwrap();
+            let params = HashMap::from([("w".to_string(), (&mut w, &grad))]);
+            opt.step(params, &mut state, 0.01).unwrap();
+        }
+        (loss(&w), state)
+    }
+
+    #[test]
+    fn test_8bit_moments() {
+        let m = Tensor::raw(&[3], vec![0.5, -0.001, 0.]).unwrap();
+        let v = Tensor::raw(&[3], vec![0.25, 1e-6, 0.]).unwrap();
+        let quantized = QuantizedMoments::quantize(&m, &v);
+        let (m_deq, v_deq) = quantized.dequantize().unwrap();
+        assert_eq!(m_deq.blob()[0], 0.5);
+        assert!(m_deq.blob()[1] < 0.);
+        assert_eq!(m_deq.blob()[2], 0.);
+        assert!((v_deq.blob()[0] - 0.25).abs() < 1e-6);
+        assert!(v_deq.blob()[1] >= 1e-6);
+
+        let opt = AdamW::new().with_8bit_moments();
+        let (loss_f32, _) = fit(&AdamW::new(), &[1000], 300);
+        let (loss_8bit, state) = fit(&opt, &[1000], 300);
+        let (initial, _) = fit(&opt, &[1000], 0);
+        assert!(loss_f32 < initial * 1e-4);
+        assert!(loss_8bit < initial * 1e-3);
+
+        // The moments take a quarter of the memory, and are saved as f32 ones
+        assert!(state.state.is_empty());
+        let moments = &state.quantized["w"];
+        assert_eq!((moments.m.len(), moments.v.len()), (1000, 1000));
+        assert_eq!(moments.m_scale.len(), 4);
+        assert_eq!(opt.state_size(&[1000]), 2 * 1000 + 2 * 4 * 4);
+        assert_eq!(AdamW::new().state_size(&[1000]), 2 * 1000 * 4);
+        let saved = state.dequantized().unwrap();
+        assert!(saved.quantized.is_empty());
+        assert_eq!(saved.state["w_m"].shape(), &[1000]);
+    }
+
+    #[test]
+    fn test_adafactor() {
+        let opt = AnyOptimizer::from_str("adafactor").unwrap();
+        let (initial, _) = fit(&opt, &[40, 25], 0);
+        let (loss, state) = fit(&opt, &[40, 25], 300);
+        assert!(loss < initial * 1e-3);
+
+        // Matrices only keep the second moments of their rows and columns
+        assert_eq!(state.state.len(), 2);
+        assert_eq!(state.state["w_vr"].shape(), &[40]);
+        assert_eq!(state.state["w_vc"].shape(), &[25]);
+        assert_eq!(opt.state_size(&[40, 25]), (40 + 25) * 4);
+        let (loss, state) = fit(&opt, &[1000], 300);
+        assert!(loss < initial * 1e-3);
+        assert_eq!(state.state["w_v"].shape(), &[1000]);
+    }
 }
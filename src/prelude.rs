@@ -0,0 +1,16 @@
+// What most programs training or running a model need: `use femto_gpt::prelude::*;`
+
+#[cfg(feature = "fs")]
+pub use crate::checkpoint::{load_training_state, save_training_state};
+pub use crate::checkpoint::{read_training_state, write_training_state};
+pub use crate::error::FemtoError;
+pub use crate::gpt::{
+    Architecture, BackwardScope, BeamParams, ContextOverflow, GptBuilder, GptError, InferParams,
+    LoraConfig, LrSchedule, Pooling, SpikeGuard, TokenLogprob, TrainConfig, TrainingState, GPT,
+};
+pub use crate::graph::{AnyGraph, Backend, CpuGraph, Graph, GraphError};
+pub use crate::optimizer::{AdamW, Optimizer, ParamGroup};
+pub use crate::tensor::{Tensor, TensorOps};
+#[cfg(feature = "huggingface")]
+pub use crate::tokenizer::HuggingFaceTokenizer;
+pub use crate::tokenizer::{SentencePieceTokenizer, Tokenizer};
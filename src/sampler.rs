@@ -0,0 +1,634 @@
+// Sampling of the training windows. Windows are either drawn at random positions of the dataset,
+// or by epochs: the dataset is cut into consecutive windows whose order is shuffled at the start
+// of every epoch, so that each one is seen once per epoch. The position in an epoch is saved in
+// checkpoints (See `TrainingState::sampler`), resumed runs continue where they stopped.
+// Windows come from a `Corpus`: a single dataset, a `Mixture` of weighted ones, the
+// prompt/completion pairs of `Completions` for instruction fine-tuning, or `Padded` documents
+// batched one per window. Masked language models train on windows hidden by `Masking` instead of
+// predicting the next tokens.
+
+use crate::tensor::{Tensor, TensorOps};
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+
+/// How training windows are picked from the dataset.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Sampling {
+    /// Random positions, with no notion of epochs
+    #[default]
+    Random,
+    /// Every window once per epoch, in a shuffled order
+    Epochs,
+}
+
+impl std::str::FromStr for Sampling {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "random" => Ok(Sampling::Random),
+            "epochs" => Ok(Sampling::Epochs),
+            _ => Err(format!("expected `random` or `epochs`, got `{}`", s)),
+        }
+    }
+}
+
+/// Tokens training windows are drawn from (See `GPT::train`): a tokenized dataset, a `Mixture`
+/// of several, prompt/completion pairs (`Completions`) or `Padded` documents.
+pub trait Corpus: Sync {
+    /// Number of positions windows can start at.
+    fn len(&self) -> usize;
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    /// Number of datasets windows are drawn from.
+    fn num_sources(&self) -> usize {
+        1
+    }
+    /// Positions the windows of an epoch start at (See `EpochSampler`), consecutive windows by
+    /// default.
+    fn window_starts(&self, num_tokens: usize) -> Vec<usize> {
+        (0..self.len()).step_by(num_tokens.max(1)).collect()
+    }
+    /// Appends the `num_tokens` tokens starting at `start` to `xs`, and the tokens following them
+    /// to `ys`. Returns the dataset they come from.
+    fn window(
+        &self,
+        start: usize,
+        num_tokens: usize,
+        xs: &mut Vec<usize>,
+        ys: &mut Vec<usize>,
+    ) -> usize;
+}
+
+// Windows wrap around the end of the dataset
+impl Corpus for [usize] {
+    fn len(&self) -> usize {
+        <[usize]>::len(self)
+    }
+    fn window(
+        &self,
+        start: usize,
+        num_tokens: usize,
+        xs: &mut Vec<usize>,
+        ys: &mut Vec<usize>,
+    ) -> usize {
+        let all = self
+            .iter()
+            .cycle()
+            .skip(start)
+            .take(num_tokens + 1)
+            .cloned()
+            .collect::<Vec<_>>();
+        xs.extend(&all[0..num_tokens]);
+        ys.extend(&all[1..num_tokens + 1]);
+        0
+    }
+}
+
+impl Corpus for Vec<usize> {
+    fn len(&self) -> usize {
+        self.as_slice().len()
+    }
+    fn window(
+        &self,
+        start: usize,
+        num_tokens: usize,
+        xs: &mut Vec<usize>,
+        ys: &mut Vec<usize>,
+    ) -> usize {
+        self.as_slice().window(start, num_tokens, xs, ys)
+    }
+}
+
+/// Datasets mixed with weights: windows are drawn from the `i`-th one with probability
+/// `weights[i] / sum(weights)`. The mixture is laid out as a dataset as long as all of them
+/// together, each one taking a share of the positions proportional to its weight (Small datasets
+/// are repeated, parts of large ones left out), so that epochs keep the proportions too.
+#[derive(Debug, Clone)]
+pub struct Mixture<C = Vec<usize>> {
+    datasets: Vec<C>,
+    // End of the positions of each dataset
+    ends: Vec<usize>,
+}
+
+impl<C: Corpus> Mixture<C> {
+    /// Datasets along with their (Positive) weights.
+    pub fn new(datasets: Vec<(C, f32)>) -> Self {
+        let len = datasets.iter().map(|(d, _)| d.len()).sum::<usize>();
+        let total = datasets.iter().map(|(_, w)| w).sum::<f32>();
+        let mut weight = 0.;
+        let ends = datasets
+            .iter()
+            .map(|(_, w)| {
+                weight += w;
+                (len as f32 * weight / total).round() as usize
+            })
+            .collect();
+        Self {
+            datasets: datasets.into_iter().map(|(d, _)| d).collect(),
+            ends,
+        }
+    }
+}
+
+impl<C: Corpus> Corpus for Mixture<C> {
+    fn len(&self) -> usize {
+        self.ends.last().copied().unwrap_or(0)
+    }
+    fn num_sources(&self) -> usize {
+        self.datasets.len()
+    }
+    // The windows of each dataset, repeated or cut to its share of the positions
+    fn window_starts(&self, num_tokens: usize) -> Vec<usize> {
+        let mut starts = Vec::new();
+        let mut begin = 0;
+        for (dataset, end) in self.datasets.iter().zip(self.ends.iter()) {
+            let own = dataset.window_starts(num_tokens);
+            if !own.is_empty() {
+                let repeated = (0..).flat_map(|i| own.iter().map(move |s| i * dataset.len() + s));
+                starts.extend(repeated.map(|s| begin + s).take_while(|s| s < end));
+            }
+            begin = *end;
+        }
+        starts
+    }
+    fn window(
+        &self,
+        start: usize,
+        num_tokens: usize,
+        xs: &mut Vec<usize>,
+        ys: &mut Vec<usize>,
+    ) -> usize {
+        let source = self.ends.partition_point(|end| *end <= start);
+        let offset = start - source.checked_sub(1).map_or(0, |s| self.ends[s]);
+        let dataset = &self.datasets[source];
+        dataset.window(offset % dataset.len(), num_tokens, xs, ys);
+        source
+    }
+}
+
+/// Prompt/completion pairs for instruction fine-tuning, the model only learning to predict the
+/// completions. The examples are packed one after the other like a dataset, but windows start at
+/// the beginning of an example, so that the completions they train on follow their whole prompt
+/// (Unless it doesn't fit in the window).
+#[derive(Debug, Clone)]
+pub struct Completions {
+    tokens: Vec<usize>,
+    // Whether each token belongs to a completion, the tokens of the prompts are never targets
+    completion: Vec<bool>,
+    // Position of the first token of each example
+    starts: Vec<usize>,
+}
+
+impl Completions {
+    /// Tokens of the prompt and of the completion of each example (Which should end with a token
+    /// telling the model to stop, e.g. a document separator).
+    pub fn new(examples: Vec<(Vec<usize>, Vec<usize>)>) -> Self {
+        let mut corpus = Self {
+            tokens: Vec::new(),
+            completion: Vec::new(),
+            starts: Vec::new(),
+        };
+        for (prompt, completion) in examples {
+            if prompt.is_empty() && completion.is_empty() {
+                continue;
+            }
+            corpus.starts.push(corpus.tokens.len());
+            corpus
+                .completion
+                .resize(corpus.tokens.len() + prompt.len(), false);
+            corpus
+                .completion
+                .resize(corpus.tokens.len() + prompt.len() + completion.len(), true);
+            corpus.tokens.extend(prompt);
+            corpus.tokens.extend(completion);
+        }
+        corpus
+    }
+
+    pub fn num_examples(&self) -> usize {
+        self.starts.len()
+    }
+}
+
+// Windows wrap around the end of the examples
+impl Corpus for Completions {
+    fn len(&self) -> usize {
+        self.tokens.len()
+    }
+    fn window(
+        &self,
+        start: usize,
+        num_tokens: usize,
+        xs: &mut Vec<usize>,
+        ys: &mut Vec<usize>,
+    ) -> usize {
+        let start = self.starts[self.starts.partition_point(|s| *s <= start) - 1];
+        let positions = (0..self.tokens.len())
+            .cycle()
+            .skip(start)
+            .take(num_tokens + 1)
+            .collect::<Vec<_>>();
+        xs.extend(positions[..num_tokens].iter().map(|p| self.tokens[*p]));
+        ys.extend(positions[1..].iter().map(|p| {
+            if self.completion[*p] {
+                self.tokens[*p]
+            } else {
+                IGNORED_TARGET
+            }
+        }));
+        0
+    }
+}
+
+/// Documents batched one per window, padded with `pad` up to the size of the window rather than
+/// packed one after the other, so that windows neither mix documents nor wrap around. Documents
+/// longer than a window are split into several ones. The padding tokens are never targets, and
+/// models built with `GptBuilder::pad_token` don't attend to them.
+#[derive(Debug, Clone)]
+pub struct Padded {
+    tokens: Vec<usize>,
+    // Position of the first token of each document
+    starts: Vec<usize>,
+    pad: usize,
+}
+
+impl Padded {
+    pub fn new(documents: Vec<Vec<usize>>, pad: usize) -> Self {
+        let mut corpus = Self {
+            tokens: Vec::new(),
+            starts: Vec::new(),
+            pad,
+        };
+        for document in documents.into_iter().filter(|d| !d.is_empty()) {
+            corpus.starts.push(corpus.tokens.len());
+            corpus.tokens.extend(document);
+        }
+        corpus
+    }
+
+    pub fn num_documents(&self) -> usize {
+        self.starts.len()
+    }
+
+    // Start and end of the `i`-th document
+    fn document(&self, i: usize) -> (usize, usize) {
+        let end = self.starts.get(i + 1).copied().unwrap_or(self.tokens.len());
+        (self.starts[i], end)
+    }
+}
+
+impl Corpus for Padded {
+    fn len(&self) -> usize {
+        self.tokens.len()
+    }
+    fn window_starts(&self, num_tokens: usize) -> Vec<usize> {
+        (0..self.starts.len())
+            .flat_map(|i| {
+                let (start, end) = self.document(i);
+                (start..end).step_by(num_tokens.max(1))
+            })
+            .collect()
+    }
+    fn window(
+        &self,
+        start: usize,
+        num_tokens: usize,
+        xs: &mut Vec<usize>,
+        ys: &mut Vec<usize>,
+    ) -> usize {
+        // The window of the document containing `start`
+        let (doc_start, doc_end) = self.document(self.starts.partition_point(|s| *s <= start) - 1);
+        let start = doc_start + (start - doc_start) / num_tokens.max(1) * num_tokens;
+        let end = doc_end.min(start + num_tokens);
+        xs.extend(&self.tokens[start..end]);
+        xs.resize(xs.len() + num_tokens - (end - start), self.pad);
+        ys.extend((start + 1..start + num_tokens + 1).map(|p| {
+            if p < doc_end {
+                self.tokens[p]
+            } else {
+                IGNORED_TARGET
+            }
+        }));
+        0
+    }
+}
+
+/// Target of the positions left out of the loss (See `CrossEntropy`), e.g. the tokens `Masking`
+/// doesn't hide, the prompts of `Completions` or the padding of `Padded` documents.
+pub const IGNORED_TARGET: usize = usize::MAX;
+
+/// Masking of the tokens of training windows for masked language modeling (As in BERT): each
+/// token is picked with probability `rate` to be predicted, 80% of the picked ones are replaced
+/// by `mask_token`, 10% by a random token and 10% left unchanged.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Masking {
+    pub mask_token: usize,
+    pub rate: f32,
+}
+
+impl Masking {
+    /// Masks the windows of `batch`, whose targets become the original tokens at the picked
+    /// positions and `IGNORED_TARGET` elsewhere.
+    pub fn apply<R: Rng>(&self, batch: Batch, vocab_size: usize, rng: &mut R) -> Batch {
+        let mut xs = batch.xs.blob().to_vec();
+        let mut ys = vec![IGNORED_TARGET; xs.len()];
+        for (x, y) in xs.iter_mut().zip(ys.iter_mut()) {
+            if rng.gen::<f32>() >= self.rate {
+                continue;
+            }
+            *y = *x;
+            match rng.gen::<f32>() {
+                r if r < 0.8 => *x = self.mask_token,
+                r if r < 0.9 => *x = rng.gen_range(0..vocab_size),
+                _ => {}
+            }
+        }
+        Batch {
+            xs: Tensor::raw(batch.xs.shape(), xs).unwrap(),
+            ys: Tensor::raw(batch.xs.shape(), ys).unwrap(),
+            sources: batch.sources,
+        }
+    }
+}
+
+/// Windows of a training batch, as `[batch_size, num_tokens]` tensors of tokens and targets.
+#[derive(Debug, Clone)]
+pub struct Batch {
+    pub xs: Tensor<usize>,
+    pub ys: Tensor<usize>,
+    /// The dataset each window comes from (See `Corpus::num_sources`)
+    pub sources: Vec<usize>,
+}
+
+/// Position of an `EpochSampler`, the order of the windows of an epoch is derived from `seed`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SamplerState {
+    pub seed: u64,
+    pub epoch: usize,
+    /// Number of windows of the epoch already sampled
+    pub cursor: usize,
+}
+
+impl SamplerState {
+    /// The start of a run, with a random seed.
+    pub fn new<R: Rng>(rng: &mut R) -> Self {
+        Self {
+            seed: rng.gen(),
+            epoch: 0,
+            cursor: 0,
+        }
+    }
+}
+
+/// Samples the windows of `num_tokens` tokens (And their targets, shifted by one token) of a
+/// dataset epoch by epoch.
+#[derive(Debug, Clone)]
+pub struct EpochSampler {
+    num_tokens: usize,
+    // Start positions of the windows, in the order of the current epoch
+    starts: Vec<usize>,
+    state: SamplerState,
+}
+
+impl EpochSampler {
+    pub fn new(dataset_len: usize, num_tokens: usize, state: SamplerState) -> Self {
+        Self::with_starts(
+            (0..dataset_len).step_by(num_tokens.max(1)).collect(),
+            num_tokens,
+            state,
+        )
+    }
+
+    /// Samples the windows of `corpus`, starting where it tells (See `Corpus::window_starts`).
+    pub fn of<C: Corpus + ?Sized>(corpus: &C, num_tokens: usize, state: SamplerState) -> Self {
+        Self::with_starts(corpus.window_starts(num_tokens), num_tokens, state)
+    }
+
+    fn with_starts(starts: Vec<usize>, num_tokens: usize, state: SamplerState) -> Self {
+        let mut sampler = Self {
+            num_tokens,
+            starts,
+            state,
+        };
+        sampler.shuffle();
+        // The dataset may have shrunk since the state was saved
+        if sampler.state.cursor >= sampler.starts.len() {
+            sampler.next_epoch();
+        }
+        sampler
+    }
+
+    fn shuffle(&mut self) {
+        self.starts.sort_unstable();
+        let seed = self.state.seed.wrapping_add(self.state.epoch as u64);
+        self.starts.shuffle(&mut StdRng::seed_from_u64(seed));
+    }
+
+    fn next_epoch(&mut self) {
+        self.state.epoch += 1;
+        self.state.cursor = 0;
+        self.shuffle();
+    }
+
+    pub fn state(&self) -> SamplerState {
+        self.state
+    }
+
+    /// Number of windows of an epoch.
+    pub fn num_windows(&self) -> usize {
+        self.starts.len()
+    }
+
+    /// Fraction of the current epoch already sampled, in [0, 1).
+    pub fn progress(&self) -> f32 {
+        self.state.cursor as f32 / self.starts.len().max(1) as f32
+    }
+
+    /// The next `batch_size` windows of `corpus`. Batches overlapping the end of an epoch
+    /// continue with the next one.
+    pub fn sample<C: Corpus + ?Sized>(&mut self, corpus: &C, batch_size: usize) -> Batch {
+        let mut starts = Vec::with_capacity(batch_size);
+        for _ in 0..batch_size {
+            starts.push(self.starts[self.state.cursor]);
+            self.state.cursor += 1;
+            if self.state.cursor == self.starts.len() {
+                self.next_epoch();
+            }
+        }
+        windows(corpus, &starts, self.num_tokens)
+    }
+}
+
+/// Windows starting at random positions of the corpus.
+pub fn sample_dataset<C: Corpus + ?Sized, R: Rng>(
+    corpus: &C,
+    batch_size: usize,
+    context_size: usize,
+    rng: &mut R,
+) -> Batch {
+    let starts = (0..batch_size)
+        .map(|_| rng.gen_range(0..corpus.len()))
+        .collect::<Vec<_>>();
+    windows(corpus, &starts, context_size)
+}
+
+/// Document indices `docs` of the tokens `xs` (Of shape `[.., num_tokens]`) with every `pad`
+/// token in a document of its own, so that no other token attends to the padding.
+pub fn isolate_padding(xs: &Tensor<usize>, docs: &Tensor<usize>, pad: usize) -> Tensor<usize> {
+    let num_tokens = xs.shape().last().copied().unwrap_or(1).max(1);
+    let docs = xs
+        .blob()
+        .iter()
+        .zip(docs.blob())
+        .enumerate()
+        .map(|(i, (x, doc))| {
+            if *x == pad {
+                // Beyond the indices of the documents, which are less than `num_tokens`
+                num_tokens + i % num_tokens
+            } else {
+                *doc
+            }
+        })
+        .collect();
+    Tensor::raw(xs.shape(), docs).unwrap()
+}
+
+/// The document of every token of the windows `xs` (Of shape `[.., num_tokens]`): the number of
+/// separators before it in its window, a separator belonging to the document it ends.
+pub fn document_indices(xs: &Tensor<usize>, separator: usize) -> Tensor<usize> {
+    let num_tokens = xs.shape().last().copied().unwrap_or(1).max(1);
+    let mut docs = Vec::with_capacity(xs.size());
+    for window in xs.blob().chunks(num_tokens) {
+        let mut doc = 0;
+        for &token in window {
+            docs.push(doc);
+            if token == separator {
+                doc += 1;
+            }
+        }
+    }
+    Tensor::raw(xs.shape(), docs).unwrap()
+}
+
+// Windows of `context_size` tokens at the given positions and their targets
+fn windows<C: Corpus + ?Sized>(corpus: &C, starts: &[usize], context_size: usize) -> Batch {
+    let mut xs: Vec<usize> = Vec::with_capacity(starts.len() * context_size);
+    let mut ys: Vec<usize> = Vec::with_capacity(starts.len() * context_size);
+    let sources = starts
+        .iter()
+        .map(|start| corpus.window(*start, context_size, &mut xs, &mut ys))
+        .collect();
+    Batch {
+        xs: Tensor::raw(&[starts.len(), context_size], xs).unwrap(),
+        ys: Tensor::raw(&[starts.len(), context_size], ys).unwrap(),
+        sources,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_epoch_sampler() {
+        let dataset = (0..20).collect::<Vec<_>>();
+        let state = SamplerState::new(&mut rand::thread_rng());
+        let mut sampler = EpochSampler::new(dataset.len(), 4, state);
+        assert_eq!(sampler.num_windows(), 5);
+
+        // Every window is seen once per epoch
+        let batch = sampler.sample(&dataset[..], 5);
+        let mut firsts = batch.xs.blob().chunks(4).map(|w| w[0]).collect::<Vec<_>>();
+        firsts.sort();
+        assert_eq!(firsts, vec![0, 4, 8, 12, 16]);
+        assert_eq!(batch.ys.blob()[0], batch.xs.blob()[1]);
+        assert_eq!(sampler.state().epoch, 1);
+
+        // Resuming from a saved state gives the same windows
+        sampler.sample(&dataset[..], 2);
+        assert!((sampler.progress() - 0.4).abs() < 1e-6);
+        let mut resumed = EpochSampler::new(dataset.len(), 4, sampler.state());
+        assert_eq!(
+            sampler.sample(&dataset[..], 4).xs.blob(),
+            resumed.sample(&dataset[..], 4).xs.blob()
+        );
+    }
+
+    #[test]
+    fn test_mixture() {
+        // The small dataset takes 3/4 of the 16 positions, repeating itself
+        let mixture = Mixture::new(vec![(vec![0; 4], 3.), (vec![1; 12], 1.)]);
+        assert_eq!(mixture.len(), 16);
+        let batch = windows(&mixture, &[0, 11, 12, 15], 2);
+        assert_eq!(batch.sources, vec![0, 0, 1, 1]);
+        assert_eq!(batch.xs.blob(), &[0, 0, 0, 0, 1, 1, 1, 1]);
+    }
+
+    #[test]
+    fn test_completions() {
+        let completions = Completions::new(vec![
+            (vec![1, 2], vec![3]),
+            (vec![], vec![]),
+            (vec![4], vec![5, 6]),
+        ]);
+        assert_eq!(completions.num_examples(), 2);
+        assert_eq!(completions.len(), 6);
+        // Windows start at their example, only the completions are targets
+        let batch = windows(&completions, &[1, 3], 4);
+        assert_eq!(batch.xs.blob(), &[1, 2, 3, 4, 4, 5, 6, 1]);
+        let i = IGNORED_TARGET;
+        assert_eq!(batch.ys.blob(), &[i, 3, i, 5, 5, 6, i, i]);
+    }
+
+    #[test]
+    fn test_masking() {
+        let dataset = (0..64).collect::<Vec<_>>();
+        let batch = windows(&dataset, &[0, 32], 32);
+        let masking = Masking {
+            mask_token: 100,
+            rate: 1.,
+        };
+        let masked = masking.apply(batch.clone(), 64, &mut rand::thread_rng());
+        // Every token is picked, most of them hidden
+        assert_eq!(masked.ys.blob(), batch.xs.blob());
+        let hidden = masked.xs.blob().iter().filter(|x| **x == 100).count();
+        assert!(hidden > 32 && hidden < 64);
+
+        let masking = Masking {
+            rate: 0.,
+            ..masking
+        };
+        let masked = masking.apply(batch.clone(), 64, &mut rand::thread_rng());
+        assert_eq!(masked.xs.blob(), batch.xs.blob());
+        assert!(masked.ys.blob().iter().all(|y| *y == IGNORED_TARGET));
+    }
+
+    #[test]
+    fn test_document_indices() {
+        let xs = Tensor::raw(&[2, 4], vec![1, 0, 2, 0, 0, 3, 3, 3]).unwrap();
+        assert_eq!(document_indices(&xs, 0).blob(), &[0, 0, 1, 1, 0, 1, 1, 1]);
+        // Every padding token is alone in its document
+        let docs = isolate_padding(&xs, &document_indices(&xs, 0), 3);
+        assert_eq!(docs.blob(), &[0, 0, 1, 1, 0, 5, 6, 7]);
+    }
+
+    #[test]
+    fn test_padded() {
+        let padded = Padded::new(vec![vec![1, 2], vec![], vec![3, 4, 5, 6, 7]], 0);
+        assert_eq!(padded.num_documents(), 2);
+        // Every document starts a window, long ones several
+        assert_eq!(padded.window_starts(3), vec![0, 2, 5]);
+        let batch = windows(&padded, &[1, 4, 6], 3);
+        assert_eq!(batch.xs.blob(), &[1, 2, 0, 3, 4, 5, 6, 7, 0]);
+        let i = IGNORED_TARGET;
+        assert_eq!(batch.ys.blob(), &[2, i, i, 4, 5, 6, 7, i, i]);
+
+        // Mixtures keep the windows of their datasets
+        let mixture = Mixture::new(vec![(padded.clone(), 2.), (padded, 1.)]);
+        assert_eq!(mixture.len(), 14);
+        assert_eq!(mixture.window_starts(3), vec![0, 2, 5, 7, 9, 11]);
+    }
+}
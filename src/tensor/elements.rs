@@ -1,6 +1,45 @@
+use half::{bf16, f16};
+
 pub trait TensorElement: Clone + Copy + Sized + Send + Sync {
     fn zero() -> Self;
     fn one() -> Self;
+    /// Computes `c += a * b`, where `a` is a `m x n` and `b` is a `n x p` row-major matrix.
+    fn matmul(a: &[Self], b: &[Self], c: &mut [Self], m: usize, n: usize, p: usize);
+}
+
+/// Floating-point elements, which can be converted into each other (See `Tensor::cast`), e.g. f64
+/// for precise numerical checks or f16/bf16 for storing tensors in half the memory.
+pub trait FloatElement: TensorElement {
+    fn from_f64(v: f64) -> Self;
+    fn to_f64(self) -> f64;
+}
+
+fn naive_matmul<V: TensorElement + std::ops::Mul<Output = V> + std::ops::AddAssign>(
+    a: &[V],
+    b: &[V],
+    c: &mut [V],
+    m: usize,
+    n: usize,
+    p: usize,
+) {
+    for i in 0..m {
+        for k in 0..n {
+            for j in 0..p {
+                c[i * p + j] += a[i * n + k] * b[k * p + j];
+            }
+        }
+    }
+}
+
+// Half-precision products are accumulated in f32, and only rounded once per element
+fn half_matmul<V: FloatElement>(a: &[V], b: &[V], c: &mut [V], m: usize, n: usize, p: usize) {
+    let a = a.iter().map(|v| v.to_f64() as f32).collect::<Vec<_>>();
+    let b = b.iter().map(|v| v.to_f64() as f32).collect::<Vec<_>>();
+    let mut result = c.iter().map(|v| v.to_f64() as f32).collect::<Vec<_>>();
+    f32::matmul(&a, &b, &mut result, m, n, p);
+    for (c, r) in c.iter_mut().zip(result) {
+        *c = V::from_f64(r as f64);
+    }
 }
 
 impl TensorElement for f32 {
@@ -10,6 +49,45 @@ impl TensorElement for f32 {
     fn one() -> Self {
         1.
     }
+    fn matmul(a: &[Self], b: &[Self], c: &mut [Self], m: usize, n: usize, p: usize) {
+        super::gemm::sgemm(a, b, c, m, n, p);
+    }
+}
+
+impl TensorElement for f64 {
+    fn zero() -> Self {
+        0.
+    }
+    fn one() -> Self {
+        1.
+    }
+    fn matmul(a: &[Self], b: &[Self], c: &mut [Self], m: usize, n: usize, p: usize) {
+        naive_matmul(a, b, c, m, n, p);
+    }
+}
+
+impl TensorElement for f16 {
+    fn zero() -> Self {
+        f16::ZERO
+    }
+    fn one() -> Self {
+        f16::ONE
+    }
+    fn matmul(a: &[Self], b: &[Self], c: &mut [Self], m: usize, n: usize, p: usize) {
+        half_matmul(a, b, c, m, n, p);
+    }
+}
+
+impl TensorElement for bf16 {
+    fn zero() -> Self {
+        bf16::ZERO
+    }
+    fn one() -> Self {
+        bf16::ONE
+    }
+    fn matmul(a: &[Self], b: &[Self], c: &mut [Self], m: usize, n: usize, p: usize) {
+        half_matmul(a, b, c, m, n, p);
+    }
 }
 
 impl TensorElement for usize {
@@ -19,4 +97,84 @@ impl TensorElement for usize {
     fn one() -> Self {
         1
     }
+    fn matmul(a: &[Self], b: &[Self], c: &mut [Self], m: usize, n: usize, p: usize) {
+        naive_matmul(a, b, c, m, n, p);
+    }
+}
+
+impl FloatElement for f32 {
+    fn from_f64(v: f64) -> Self {
+        v as f32
+    }
+    fn to_f64(self) -> f64 {
+        self as f64
+    }
+}
+
+impl FloatElement for f64 {
+    fn from_f64(v: f64) -> Self {
+        v
+    }
+    fn to_f64(self) -> f64 {
+        self
+    }
+}
+
+impl FloatElement for f16 {
+    fn from_f64(v: f64) -> Self {
+        f16::from_f64(v)
+    }
+    fn to_f64(self) -> f64 {
+        f16::to_f64(self)
+    }
+}
+
+impl FloatElement for bf16 {
+    fn from_f64(v: f64) -> Self {
+        bf16::from_f64(v)
+    }
+    fn to_f64(self) -> f64 {
+        bf16::to_f64(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::*;
+    use half::{bf16, f16};
+
+    #[test]
+    fn test_float_elements() {
+        let mut rng = rand::thread_rng();
+        let a = Tensor::<f32>::rand(&mut rng, &[3, 5, 4]);
+        let b = Tensor::<f32>::rand(&mut rng, &[3, 4, 2]);
+        let expected = (&a ^ &b).unwrap();
+
+        fn check<
+            V: FloatElement
+                + std::ops::Mul<Output = V>
+                + std::ops::Add<Output = V>
+                + std::ops::AddAssign,
+        >(
+            a: &Tensor<f32>,
+            b: &Tensor<f32>,
+            expected: &Tensor<f32>,
+            tolerance: f32,
+        ) {
+            let result = (&a.cast::<V>() ^ &b.cast::<V>()).unwrap().cast::<f32>();
+            assert_eq!(result.shape(), expected.shape());
+            for (r, e) in result.blob().iter().zip(expected.blob().iter()) {
+                assert!((r - e).abs() <= tolerance * e.abs().max(1e-3));
+            }
+        }
+        check::<f64>(&a, &b, &expected, 1e-5);
+        check::<f16>(&a, &b, &expected, 1e-2);
+        check::<bf16>(&a, &b, &expected, 1e-1);
+
+        // f64 holds f32 values exactly, halves round them
+        assert_eq!(a.cast::<f64>().cast::<f32>().blob(), a.blob());
+        let t = Tensor::raw(&[2], vec![1.0f32 / 3., 65504.]).unwrap();
+        assert_eq!(t.cast::<f16>().cast::<f32>().blob(), &[0.33325195, 65504.]);
+        assert_eq!(t.cast::<bf16>().cast::<f32>().blob(), &[0.33398438, 65536.]);
+    }
 }
@@ -8,4 +8,6 @@ pub enum TensorError {
     UnexpectedShape,
     #[error("invalid index!")]
     InvalidIndex,
+    #[error("{0} backend is not available in this build!")]
+    BackendUnavailable(&'static str),
 }
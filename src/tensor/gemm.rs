@@ -0,0 +1,281 @@
+// Blocked single-precision matrix multiplication, `c += a * b` where `a` is `m x n`, `b` is
+// `n x p` and all matrices are row-major. The fastest kernel supported by the running CPU is
+// picked at runtime (AVX2+FMA on x86_64, NEON on aarch64), falling back to a portable loop.
+
+use super::TensorError;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// Number of `k` values processed per block, so that the touched rows of `b` stay in cache
+const KC: usize = 256;
+
+/// Implementation used for multiplying `f32` matrices on CPUs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MatMulBackend {
+    /// femto's own SIMD kernels
+    Native,
+    /// An external BLAS-like implementation, requires the `blas` feature
+    Blas,
+}
+
+impl std::str::FromStr for MatMulBackend {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "native" => Ok(MatMulBackend::Native),
+            "blas" => Ok(MatMulBackend::Blas),
+            _ => Err(format!("expected `native` or `blas`, got `{}`", s)),
+        }
+    }
+}
+
+// BLAS is preferred whenever it is compiled in
+static USE_BLAS: AtomicBool = AtomicBool::new(cfg!(feature = "blas"));
+
+pub fn set_matmul_backend(backend: MatMulBackend) -> Result<(), TensorError> {
+    if backend == MatMulBackend::Blas && !cfg!(feature = "blas") {
+        return Err(TensorError::BackendUnavailable("blas"));
+    }
+    USE_BLAS.store(backend == MatMulBackend::Blas, Ordering::Relaxed);
+    Ok(())
+}
+
+pub fn matmul_backend() -> MatMulBackend {
+    if USE_BLAS.load(Ordering::Relaxed) {
+        MatMulBackend::Blas
+    } else {
+        MatMulBackend::Native
+    }
+}
+
+pub fn sgemm(a: &[f32], b: &[f32], c: &mut [f32], m: usize, n: usize, p: usize) {
+    assert!(a.len() >= m * n && b.len() >= n * p && c.len() >= m * p);
+
+    #[cfg(feature = "blas")]
+    {
+        if USE_BLAS.load(Ordering::Relaxed) {
+            // Safety: the slice sizes are checked above, all matrices are dense and row-major
+            unsafe {
+                matrixmultiply::sgemm(
+                    m,
+                    n,
+                    p,
+                    1.,
+                    a.as_ptr(),
+                    n as isize,
+                    1,
+                    b.as_ptr(),
+                    p as isize,
+                    1,
+                    1.,
+                    c.as_mut_ptr(),
+                    p as isize,
+                    1,
+                )
+            };
+            return;
+        }
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    {
+        if is_x86_feature_detected!("avx2") && is_x86_feature_detected!("fma") {
+            // Safety: required CPU features are detected and the slice sizes are checked above
+            unsafe { sgemm_avx2(a, b, c, m, n, p) };
+            return;
+        }
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    {
+        // Safety: NEON is mandatory on aarch64 and the slice sizes are checked above
+        unsafe { sgemm_neon(a, b, c, m, n, p) };
+        return;
+    }
+
+    #[allow(unreachable_code)]
+    sgemm_portable(a, b, c, m, n, p);
+}
+
+// Plain (cache-friendly) i-k-j loop order, also used for handling the leftover columns of the
+// vectorized kernels.
+#[allow(clippy::too_many_arguments)]
+fn sgemm_cols(
+    a: &[f32],
+    b: &[f32],
+    c: &mut [f32],
+    rows: std::ops::Range<usize>,
+    ks: std::ops::Range<usize>,
+    cols: std::ops::Range<usize>,
+    n: usize,
+    p: usize,
+) {
+    for i in rows {
+        for k in ks.clone() {
+            let a_ik = a[i * n + k];
+            for j in cols.clone() {
+                c[i * p + j] += a_ik * b[k * p + j];
+            }
+        }
+    }
+}
+
+fn sgemm_portable(a: &[f32], b: &[f32], c: &mut [f32], m: usize, n: usize, p: usize) {
+    for k0 in (0..n).step_by(KC) {
+        let k1 = usize::min(k0 + KC, n);
+        sgemm_cols(a, b, c, 0..m, k0..k1, 0..p, n, p);
+    }
+}
+
+// Computes blocks of 4 rows and 8 columns at a time, keeping the accumulators in registers.
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx2,fma")]
+unsafe fn sgemm_avx2(a: &[f32], b: &[f32], c: &mut [f32], m: usize, n: usize, p: usize) {
+    use std::arch::x86_64::*;
+
+    const LANES: usize = 8;
+    let p_vec = p - p % LANES;
+    let (a_ptr, b_ptr) = (a.as_ptr(), b.as_ptr());
+
+    for k0 in (0..n).step_by(KC) {
+        let k1 = usize::min(k0 + KC, n);
+        let c_ptr = c.as_mut_ptr();
+
+        let mut i = 0;
+        while i + 4 <= m {
+            for j in (0..p_vec).step_by(LANES) {
+                let c0 = c_ptr.add(i * p + j);
+                let c1 = c0.add(p);
+                let c2 = c1.add(p);
+                let c3 = c2.add(p);
+                let mut acc0 = _mm256_loadu_ps(c0);
+                let mut acc1 = _mm256_loadu_ps(c1);
+                let mut acc2 = _mm256_loadu_ps(c2);
+                let mut acc3 = _mm256_loadu_ps(c3);
+                for k in k0..k1 {
+                    let bv = _mm256_loadu_ps(b_ptr.add(k * p + j));
+                    let a0 = a_ptr.add(i * n + k);
+                    acc0 = _mm256_fmadd_ps(_mm256_set1_ps(*a0), bv, acc0);
+                    acc1 = _mm256_fmadd_ps(_mm256_set1_ps(*a0.add(n)), bv, acc1);
+                    acc2 = _mm256_fmadd_ps(_mm256_set1_ps(*a0.add(2 * n)), bv, acc2);
+                    acc3 = _mm256_fmadd_ps(_mm256_set1_ps(*a0.add(3 * n)), bv, acc3);
+                }
+                _mm256_storeu_ps(c0, acc0);
+                _mm256_storeu_ps(c1, acc1);
+                _mm256_storeu_ps(c2, acc2);
+                _mm256_storeu_ps(c3, acc3);
+            }
+            i += 4;
+        }
+        while i < m {
+            for j in (0..p_vec).step_by(LANES) {
+                let c0 = c_ptr.add(i * p + j);
+                let mut acc = _mm256_loadu_ps(c0);
+                for k in k0..k1 {
+                    let bv = _mm256_loadu_ps(b_ptr.add(k * p + j));
+                    acc = _mm256_fmadd_ps(_mm256_set1_ps(*a_ptr.add(i * n + k)), bv, acc);
+                }
+                _mm256_storeu_ps(c0, acc);
+            }
+            i += 1;
+        }
+
+        if p_vec < p {
+            sgemm_cols(a, b, c, 0..m, k0..k1, p_vec..p, n, p);
+        }
+    }
+}
+
+// Same structure as the AVX2 kernel, with 4-lane NEON vectors.
+#[cfg(target_arch = "aarch64")]
+unsafe fn sgemm_neon(a: &[f32], b: &[f32], c: &mut [f32], m: usize, n: usize, p: usize) {
+    use std::arch::aarch64::*;
+
+    const LANES: usize = 4;
+    let p_vec = p - p % LANES;
+    let (a_ptr, b_ptr) = (a.as_ptr(), b.as_ptr());
+
+    for k0 in (0..n).step_by(KC) {
+        let k1 = usize::min(k0 + KC, n);
+        let c_ptr = c.as_mut_ptr();
+
+        let mut i = 0;
+        while i + 4 <= m {
+            for j in (0..p_vec).step_by(LANES) {
+                let c0 = c_ptr.add(i * p + j);
+                let c1 = c0.add(p);
+                let c2 = c1.add(p);
+                let c3 = c2.add(p);
+                let mut acc0 = vld1q_f32(c0);
+                let mut acc1 = vld1q_f32(c1);
+                let mut acc2 = vld1q_f32(c2);
+                let mut acc3 = vld1q_f32(c3);
+                for k in k0..k1 {
+                    let bv = vld1q_f32(b_ptr.add(k * p + j));
+                    let a0 = a_ptr.add(i * n + k);
+                    acc0 = vfmaq_n_f32(acc0, bv, *a0);
+                    acc1 = vfmaq_n_f32(acc1, bv, *a0.add(n));
+                    acc2 = vfmaq_n_f32(acc2, bv, *a0.add(2 * n));
+                    acc3 = vfmaq_n_f32(acc3, bv, *a0.add(3 * n));
+                }
+                vst1q_f32(c0, acc0);
+                vst1q_f32(c1, acc1);
+                vst1q_f32(c2, acc2);
+                vst1q_f32(c3, acc3);
+            }
+            i += 4;
+        }
+        while i < m {
+            for j in (0..p_vec).step_by(LANES) {
+                let c0 = c_ptr.add(i * p + j);
+                let mut acc = vld1q_f32(c0);
+                for k in k0..k1 {
+                    let bv = vld1q_f32(b_ptr.add(k * p + j));
+                    acc = vfmaq_n_f32(acc, bv, *a_ptr.add(i * n + k));
+                }
+                vst1q_f32(c0, acc);
+            }
+            i += 1;
+        }
+
+        if p_vec < p {
+            sgemm_cols(a, b, c, 0..m, k0..k1, p_vec..p, n, p);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::Rng;
+
+    #[test]
+    fn test_sgemm_matches_naive() {
+        let mut rng = rand::thread_rng();
+        for &(m, n, p) in &[(1, 1, 1), (4, 8, 8), (7, 3, 13), (64, 64, 16), (5, 300, 33)] {
+            let a = (0..m * n)
+                .map(|_| rng.gen_range(-1.0..1.0))
+                .collect::<Vec<f32>>();
+            let b = (0..n * p)
+                .map(|_| rng.gen_range(-1.0..1.0))
+                .collect::<Vec<f32>>();
+            let mut expected = vec![0.; m * p];
+            for i in 0..m {
+                for j in 0..p {
+                    for k in 0..n {
+                        expected[i * p + j] += a[i * n + k] * b[k * p + j];
+                    }
+                }
+            }
+            for backend in [MatMulBackend::Native, MatMulBackend::Blas] {
+                if set_matmul_backend(backend).is_err() {
+                    continue;
+                }
+                let mut c = vec![0.; m * p];
+                sgemm(&a, &b, &mut c, m, n, p);
+                for (x, y) in c.iter().zip(expected.iter()) {
+                    assert!((x - y).abs() < 1e-4);
+                }
+            }
+        }
+    }
+}
@@ -1,12 +1,18 @@
 mod elements;
 mod error;
+mod gemm;
 mod helper;
 mod ops;
+mod precision;
+mod quant;
 mod view;
 pub use elements::*;
 pub use error::*;
+pub use gemm::{matmul_backend, set_matmul_backend, sgemm, MatMulBackend};
 pub use helper::*;
 pub use ops::*;
+pub use precision::*;
+pub use quant::*;
 pub use view::*;
 
 use rand::prelude::*;
@@ -140,6 +146,15 @@ pub trait TensorMutOps<V: TensorElement>: TensorOps<V> {
     }
 }
 
+impl<V: FloatElement> Tensor<V> {
+    /// Converts the elements to another floating-point type, rounding them to the nearest
+    /// representable values. Graphs compute in f32, other types are converted at their
+    /// boundaries (`Graph::load`, `Graph::get`).
+    pub fn cast<W: FloatElement>(&self) -> Tensor<W> {
+        self.map_values(|v| W::from_f64(v.to_f64()))
+    }
+}
+
 impl Tensor<f32> {
     pub fn mean(&self) -> f32 {
         self.blob().iter().cloned().sum::<f32>() / self.size() as f32
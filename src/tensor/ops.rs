@@ -80,19 +80,8 @@ impl<
                     let m = a.shape()[0];
                     let n = a.shape()[1];
                     let p = b.shape()[1];
-                    let mut result = vec![
-                            <<V as std::ops::Mul>::Output as std::ops::Add>::Output::zero();
-                            m * p
-                        ];
-                    let a_blob = a.blob();
-                    let b_blob = b.blob();
-                    for i in 0..m {
-                        for k in 0..n {
-                            for j in 0..p {
-                                result[i * p + j] += a_blob[i * n + k] * b_blob[k * p + j];
-                            }
-                        }
-                    }
+                    let mut result = vec![V::zero(); m * p];
+                    V::matmul(a.blob(), b.blob(), &mut result, m, n, p);
                     result
                 })
                 .flatten()
@@ -0,0 +1,42 @@
+use super::*;
+use half::{bf16, f16};
+
+/// Floating-point format in which activations, weights and gradients are kept during the
+/// forward/backward passes. Master weights (updated by the optimizer) always stay in f32.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum Precision {
+    #[default]
+    F32,
+    F16,
+    Bf16,
+}
+
+impl Precision {
+    pub fn round(&self, v: f32) -> f32 {
+        match self {
+            Precision::F32 => v,
+            Precision::F16 => f16::from_f32(v).to_f32(),
+            Precision::Bf16 => bf16::from_f32(v).to_f32(),
+        }
+    }
+
+    pub fn round_tensor(&self, t: &Tensor<f32>) -> Tensor<f32> {
+        match self {
+            Precision::F32 => t.clone(),
+            Precision::F16 => t.cast::<f16>().cast(),
+            Precision::Bf16 => t.cast::<bf16>().cast(),
+        }
+    }
+}
+
+impl std::str::FromStr for Precision {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "f32" => Ok(Precision::F32),
+            "f16" => Ok(Precision::F16),
+            "bf16" => Ok(Precision::Bf16),
+            _ => Err(format!("expected `f32`, `f16` or `bf16`, got `{}`", s)),
+        }
+    }
+}
@@ -0,0 +1,355 @@
+use super::*;
+use half::f16;
+use rayon::prelude::*;
+
+// Number of consecutive weights of a row sharing a scale in the 4-bit format
+pub const Q4_BLOCK_SIZE: usize = 32;
+
+/// Storage format of quantized weight matrices.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuantFormat {
+    /// 8-bit integers with a f32 scale per row
+    Q8,
+    /// 4-bit integers in blocks of 32, each block with its own f16 scale
+    Q4,
+}
+
+impl std::str::FromStr for QuantFormat {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "q8" => Ok(QuantFormat::Q8),
+            "q4" => Ok(QuantFormat::Q4),
+            _ => Err(format!("expected `q8` or `q4`, got `{}`", s)),
+        }
+    }
+}
+
+/// A 2D weight matrix in one of the quantized formats.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum QuantizedTensor {
+    Q8(Q8Tensor),
+    Q4(Q4Tensor),
+}
+
+impl QuantizedTensor {
+    pub fn quantize(t: &Tensor<f32>, format: QuantFormat) -> Result<Self, TensorError> {
+        Ok(match format {
+            QuantFormat::Q8 => QuantizedTensor::Q8(Q8Tensor::quantize(t)?),
+            QuantFormat::Q4 => QuantizedTensor::Q4(Q4Tensor::quantize(t)?),
+        })
+    }
+    pub fn dequantize(&self) -> Tensor<f32> {
+        match self {
+            QuantizedTensor::Q8(t) => t.dequantize(),
+            QuantizedTensor::Q4(t) => t.dequantize(),
+        }
+    }
+    pub fn shape(&self) -> &[usize] {
+        match self {
+            QuantizedTensor::Q8(t) => t.shape(),
+            QuantizedTensor::Q4(t) => t.shape(),
+        }
+    }
+    pub fn matmul(&self, a: &Tensor<f32>) -> Result<Tensor<f32>, TensorError> {
+        match self {
+            QuantizedTensor::Q8(t) => t.matmul(a),
+            QuantizedTensor::Q4(t) => t.matmul(a),
+        }
+    }
+    pub fn matmul_transposed(&self, a: &Tensor<f32>) -> Result<Tensor<f32>, TensorError> {
+        match self {
+            QuantizedTensor::Q8(t) => t.matmul_transposed(a),
+            QuantizedTensor::Q4(t) => t.matmul_transposed(a),
+        }
+    }
+}
+
+/// A 2D weight matrix quantized to 8-bit integers, with a single f32 scale per row
+/// (`w[k][j] ~= scales[k] * data[k][j]`).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Q8Tensor {
+    shape: Vec<usize>,
+    scales: Vec<f32>,
+    data: Vec<i8>,
+}
+
+impl Q8Tensor {
+    pub fn quantize(t: &Tensor<f32>) -> Result<Self, TensorError> {
+        if t.dim() != 2 {
+            return Err(TensorError::UnexpectedShape);
+        }
+        let cols = t.shape()[1];
+        let mut scales = Vec::with_capacity(t.shape()[0]);
+        let mut data = Vec::with_capacity(t.size());
+        for row in t.blob().chunks(cols) {
+            let max = row.iter().fold(0f32, |m, v| m.max(v.abs()));
+            let scale = if max > 0. { max / 127. } else { 1. };
+            scales.push(scale);
+            data.extend(row.iter().map(|v| (v / scale).round() as i8));
+        }
+        Ok(Self {
+            shape: t.shape().to_vec(),
+            scales,
+            data,
+        })
+    }
+
+    pub fn dequantize(&self) -> Tensor<f32> {
+        let cols = self.shape[1];
+        let blob = self
+            .data
+            .chunks(cols)
+            .zip(self.scales.iter())
+            .flat_map(|(row, scale)| row.iter().map(move |v| *v as f32 * scale))
+            .collect();
+        Tensor::raw(&self.shape, blob).unwrap()
+    }
+
+    pub fn shape(&self) -> &[usize] {
+        &self.shape
+    }
+
+    pub fn scales(&self) -> &[f32] {
+        &self.scales
+    }
+
+    pub fn data(&self) -> &[i8] {
+        &self.data
+    }
+
+    /// Computes `a * W`, where the last dimension of `a` matches the rows of `W`. The weights
+    /// are dequantized on the fly, one row at a time.
+    pub fn matmul(&self, a: &Tensor<f32>) -> Result<Tensor<f32>, TensorError> {
+        let (n, p) = (self.shape[0], self.shape[1]);
+        if a.dim() == 0 || a.shape()[a.dim() - 1] != n {
+            return Err(TensorError::UnexpectedShape);
+        }
+        let mut out = vec![0.; a.size() / n * p];
+        out.par_chunks_mut(p)
+            .zip(a.blob().par_chunks(n))
+            .for_each(|(c, a)| {
+                for (k, a_k) in a.iter().enumerate() {
+                    let coeff = a_k * self.scales[k];
+                    for (c_j, q_kj) in c.iter_mut().zip(self.data[k * p..(k + 1) * p].iter()) {
+                        *c_j += coeff * *q_kj as f32;
+                    }
+                }
+            });
+        let mut shape = a.shape().to_vec();
+        *shape.last_mut().unwrap() = p;
+        Tensor::raw(&shape, out)
+    }
+
+    /// Computes `a * W^T`, used for backpropagating through a quantized matrix multiplication.
+    pub fn matmul_transposed(&self, a: &Tensor<f32>) -> Result<Tensor<f32>, TensorError> {
+        let (n, p) = (self.shape[0], self.shape[1]);
+        if a.dim() == 0 || a.shape()[a.dim() - 1] != p {
+            return Err(TensorError::UnexpectedShape);
+        }
+        let mut out = vec![0.; a.size() / p * n];
+        out.par_chunks_mut(n)
+            .zip(a.blob().par_chunks(p))
+            .for_each(|(c, a)| {
+                for (k, c_k) in c.iter_mut().enumerate() {
+                    let dot = a
+                        .iter()
+                        .zip(self.data[k * p..(k + 1) * p].iter())
+                        .map(|(a_j, q_kj)| a_j * *q_kj as f32)
+                        .sum::<f32>();
+                    *c_k = dot * self.scales[k];
+                }
+            });
+        let mut shape = a.shape().to_vec();
+        *shape.last_mut().unwrap() = n;
+        Tensor::raw(&shape, out)
+    }
+}
+
+/// A 2D weight matrix quantized to 4-bit integers. Every row is split into blocks of
+/// `Q4_BLOCK_SIZE` weights with a f16 scale each (`w ~= scale * (q - 8)`), two weights are packed
+/// in a byte (lower nibble first). Rows are padded to a whole number of blocks.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Q4Tensor {
+    shape: Vec<usize>,
+    scales: Vec<f16>,
+    data: Vec<u8>,
+}
+
+impl Q4Tensor {
+    pub fn quantize(t: &Tensor<f32>) -> Result<Self, TensorError> {
+        if t.dim() != 2 {
+            return Err(TensorError::UnexpectedShape);
+        }
+        let cols = t.shape()[1];
+        let blocks = Self::blocks_per_row(cols);
+        let mut scales = Vec::with_capacity(t.shape()[0] * blocks);
+        let mut data = Vec::with_capacity(t.shape()[0] * blocks * Q4_BLOCK_SIZE / 2);
+        for row in t.blob().chunks(cols) {
+            for block in row.chunks(Q4_BLOCK_SIZE) {
+                // The value with the largest magnitude is mapped to -8, so that the full range
+                // of the 4-bit integers is used
+                let max = block
+                    .iter()
+                    .fold(0f32, |m, v| if v.abs() > m.abs() { *v } else { m });
+                let scale = f16::from_f32(if max != 0. { max / -8. } else { 1. });
+                let inv_scale = 1. / scale.to_f32();
+                let mut quants = [8u8; Q4_BLOCK_SIZE];
+                for (q, v) in quants.iter_mut().zip(block.iter()) {
+                    *q = ((v * inv_scale).round() + 8.).clamp(0., 15.) as u8;
+                }
+                scales.push(scale);
+                data.extend(quants.chunks(2).map(|q| q[0] | (q[1] << 4)));
+            }
+        }
+        Ok(Self {
+            shape: t.shape().to_vec(),
+            scales,
+            data,
+        })
+    }
+
+    fn blocks_per_row(cols: usize) -> usize {
+        cols.div_ceil(Q4_BLOCK_SIZE)
+    }
+
+    // Unpacks a (padded) row of weights
+    fn dequantize_row(&self, k: usize, out: &mut [f32]) {
+        let blocks = Self::blocks_per_row(self.shape[1]);
+        let row = &self.data[k * blocks * Q4_BLOCK_SIZE / 2..(k + 1) * blocks * Q4_BLOCK_SIZE / 2];
+        for (b, (bytes, out)) in row
+            .chunks(Q4_BLOCK_SIZE / 2)
+            .zip(out.chunks_mut(Q4_BLOCK_SIZE))
+            .enumerate()
+        {
+            let scale = self.scales[k * blocks + b].to_f32();
+            for (pair, byte) in out.chunks_mut(2).zip(bytes.iter()) {
+                pair[0] = ((byte & 0xf) as f32 - 8.) * scale;
+                pair[1] = ((byte >> 4) as f32 - 8.) * scale;
+            }
+        }
+    }
+
+    pub fn dequantize(&self) -> Tensor<f32> {
+        let (n, p) = (self.shape[0], self.shape[1]);
+        let mut row = vec![0.; Self::blocks_per_row(p) * Q4_BLOCK_SIZE];
+        let mut blob = Vec::with_capacity(n * p);
+        for k in 0..n {
+            self.dequantize_row(k, &mut row);
+            blob.extend(&row[..p]);
+        }
+        Tensor::raw(&self.shape, blob).unwrap()
+    }
+
+    pub fn shape(&self) -> &[usize] {
+        &self.shape
+    }
+
+    pub fn scales(&self) -> &[f16] {
+        &self.scales
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Computes `a * W`, where the last dimension of `a` matches the rows of `W`. The weights
+    /// are dequantized on the fly, one row at a time.
+    pub fn matmul(&self, a: &Tensor<f32>) -> Result<Tensor<f32>, TensorError> {
+        let (n, p) = (self.shape[0], self.shape[1]);
+        if a.dim() == 0 || a.shape()[a.dim() - 1] != n {
+            return Err(TensorError::UnexpectedShape);
+        }
+        let padded = Self::blocks_per_row(p) * Q4_BLOCK_SIZE;
+        let mut out = vec![0.; a.size() / n * p];
+        out.par_chunks_mut(p)
+            .zip(a.blob().par_chunks(n))
+            .for_each_init(
+                || vec![0.; padded],
+                |row, (c, a)| {
+                    for (k, a_k) in a.iter().enumerate() {
+                        self.dequantize_row(k, row);
+                        for (c_j, w_kj) in c.iter_mut().zip(row.iter()) {
+                            *c_j += a_k * w_kj;
+                        }
+                    }
+                },
+            );
+        let mut shape = a.shape().to_vec();
+        *shape.last_mut().unwrap() = p;
+        Tensor::raw(&shape, out)
+    }
+
+    /// Computes `a * W^T`, used for backpropagating through a quantized matrix multiplication.
+    pub fn matmul_transposed(&self, a: &Tensor<f32>) -> Result<Tensor<f32>, TensorError> {
+        let (n, p) = (self.shape[0], self.shape[1]);
+        if a.dim() == 0 || a.shape()[a.dim() - 1] != p {
+            return Err(TensorError::UnexpectedShape);
+        }
+        let padded = Self::blocks_per_row(p) * Q4_BLOCK_SIZE;
+        let mut out = vec![0.; a.size() / p * n];
+        out.par_chunks_mut(n)
+            .zip(a.blob().par_chunks(p))
+            .for_each_init(
+                || vec![0.; padded],
+                |row, (c, a)| {
+                    for (k, c_k) in c.iter_mut().enumerate() {
+                        self.dequantize_row(k, row);
+                        *c_k = a.iter().zip(row.iter()).map(|(a_j, w_kj)| a_j * w_kj).sum();
+                    }
+                },
+            );
+        let mut shape = a.shape().to_vec();
+        *shape.last_mut().unwrap() = n;
+        Tensor::raw(&shape, out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_q8_matmul_close_to_f32() {
+        let mut rng = rand::thread_rng();
+        let a = Tensor::<f32>::rand_range(&mut rng, -1., 1., &[3, 5, 16]);
+        let w = Tensor::<f32>::rand_range(&mut rng, -1., 1., &[16, 24]);
+        let q = Q8Tensor::quantize(&w).unwrap();
+        let expected = (&a ^ &w).unwrap();
+        let out = q.matmul(&a).unwrap();
+        assert_eq!(out.shape(), expected.shape());
+        for (x, y) in out.blob().iter().zip(expected.blob().iter()) {
+            assert!((x - y).abs() < 0.1);
+        }
+        let g = Tensor::<f32>::rand_range(&mut rng, -1., 1., &[3, 5, 24]);
+        let expected = (&g ^ &w.transpose().unwrap()).unwrap();
+        let out = q.matmul_transposed(&g).unwrap();
+        for (x, y) in out.blob().iter().zip(expected.blob().iter()) {
+            assert!((x - y).abs() < 0.1);
+        }
+    }
+
+    #[test]
+    fn test_q4_matmul_close_to_f32() {
+        let mut rng = rand::thread_rng();
+        let a = Tensor::<f32>::rand_range(&mut rng, -1., 1., &[3, 5, 16]);
+        // Rows of 40 weights, the last block is padded
+        let w = Tensor::<f32>::rand_range(&mut rng, -1., 1., &[16, 40]);
+        let q = QuantizedTensor::quantize(&w, QuantFormat::Q4).unwrap();
+        for (x, y) in q.dequantize().blob().iter().zip(w.blob().iter()) {
+            assert!((x - y).abs() < 0.13);
+        }
+        let expected = (&a ^ &q.dequantize()).unwrap();
+        let out = q.matmul(&a).unwrap();
+        assert_eq!(out.shape(), expected.shape());
+        for (x, y) in out.blob().iter().zip(expected.blob().iter()) {
+            assert!((x - y).abs() < 1e-4);
+        }
+        let g = Tensor::<f32>::rand_range(&mut rng, -1., 1., &[3, 5, 40]);
+        let expected = (&g ^ &q.dequantize().transpose().unwrap()).unwrap();
+        let out = q.matmul_transposed(&g).unwrap();
+        for (x, y) in out.blob().iter().zip(expected.blob().iter()) {
+            assert!((x - y).abs() < 1e-4);
+        }
+    }
+}
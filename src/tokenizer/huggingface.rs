@@ -0,0 +1,52 @@
+use super::Tokenizer;
+
+use std::path::Path;
+use tokenizers::decoders::DecoderWrapper;
+use tokenizers::pre_tokenizers::PreTokenizerWrapper;
+
+/// Tokenizer described by a Hugging Face `tokenizer.json` file, e.g. the byte-level BPE of GPT-2.
+pub struct HuggingFaceTokenizer {
+    inner: tokenizers::Tokenizer,
+}
+
+impl HuggingFaceTokenizer {
+    pub fn load(path: &Path) -> Result<Self, tokenizers::Error> {
+        Ok(Self {
+            inner: tokenizers::Tokenizer::from_file(path)?,
+        })
+    }
+
+    /// Reads the contents of a `tokenizer.json` file.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, tokenizers::Error> {
+        Ok(Self {
+            inner: tokenizers::Tokenizer::from_bytes(bytes)?,
+        })
+    }
+}
+
+impl Tokenizer for HuggingFaceTokenizer {
+    fn vocab_size(&self) -> usize {
+        self.inner.get_vocab_size(true)
+    }
+    fn tokenize(&self, string: &str) -> Vec<usize> {
+        self.inner
+            .encode(string, false)
+            .map(|enc| enc.get_ids().iter().map(|id| *id as usize).collect())
+            .unwrap_or_default()
+    }
+    fn untokenize(&self, tokens: &[usize]) -> String {
+        let ids = tokens.iter().map(|t| *t as u32).collect::<Vec<_>>();
+        self.inner.decode(&ids, false).unwrap_or_default()
+    }
+    // Byte-level tokenizers (e.g. GPT-2's) encode the bytes of any text, unless they normalize it
+    // or add a space before it. Other pre-tokenizers are assumed to lose something
+    fn is_lossless(&self) -> bool {
+        let byte_level = matches!(
+            self.inner.get_pre_tokenizer(),
+            Some(PreTokenizerWrapper::ByteLevel(b)) if !b.add_prefix_space
+        );
+        byte_level
+            && self.inner.get_normalizer().is_none()
+            && matches!(self.inner.get_decoder(), Some(DecoderWrapper::ByteLevel(_)))
+    }
+}
@@ -4,8 +4,121 @@ pub use simple::*;
 mod sentencepiece;
 pub use sentencepiece::*;
 
+mod stats;
+pub use stats::*;
+
+mod stream;
+pub use stream::*;
+
+#[cfg(feature = "huggingface")]
+mod huggingface;
+#[cfg(feature = "huggingface")]
+pub use huggingface::*;
+
 pub trait Tokenizer {
     fn vocab_size(&self) -> usize;
     fn tokenize(&self, string: &str) -> Vec<usize>;
     fn untokenize(&self, tokens: &[usize]) -> String;
+    /// Whether every text survives a round trip through the tokenizer, i.e.
+    /// `untokenize(tokenize(s))` is always `s`. Lossy tokenizers replace, drop or reject the
+    /// characters out of their vocabulary, or normalize the texts (See `DatasetStats::oov_chars`
+    /// for the characters of a dataset they lose).
+    fn is_lossless(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    // Texts mixing ASCII, whitespace, multi-byte characters and any other Unicode scalar value
+    fn arbitrary_texts(rng: &mut StdRng, count: usize) -> Vec<String> {
+        let common = "abcABC 019,.!?\n\t\r\u{2581}é€中😀"
+            .chars()
+            .collect::<Vec<_>>();
+        (0..count)
+            .map(|_| {
+                (0..rng.gen_range(0..32))
+                    .map(|_| match rng.gen_bool(0.8) {
+                        true => common[rng.gen_range(0..common.len())],
+                        false => rng.gen::<char>(),
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    // Lossless tokenizers round-trip every text, lossy ones fail on some
+    fn check_round_trips<T: Tokenizer>(tokenizer: &T) {
+        let texts = arbitrary_texts(&mut StdRng::seed_from_u64(0), 500);
+        let failing = texts
+            .iter()
+            .find(|text| tokenizer.untokenize(&tokenizer.tokenize(text)) != **text);
+        match tokenizer.is_lossless() {
+            true => assert_eq!(failing, None),
+            false => assert!(failing.is_some()),
+        }
+    }
+
+    #[test]
+    fn test_round_trips() {
+        // Texts made of the characters of its dataset are kept, it can't tokenize others
+        let texts = arbitrary_texts(&mut StdRng::seed_from_u64(0), 500);
+        let simple = SimpleTokenizer::new(&texts.concat());
+        assert!(!simple.is_lossless());
+        for text in texts.iter() {
+            assert_eq!(&simple.untokenize(&simple.tokenize(text)), text);
+        }
+
+        let vocab = char_vocab("abc xyz");
+        let sentencepiece = SentencePieceTokenizer::from_reader(vocab.as_bytes()).unwrap();
+        check_round_trips(&sentencepiece);
+        // Lines start with a space and are joined, unknown characters become `<unk>`
+        let tokens = sentencepiece.tokenize("ab\nzé");
+        assert_eq!(sentencepiece.untokenize(&tokens), " ab z<unk>");
+    }
+
+    #[cfg(feature = "huggingface")]
+    #[test]
+    fn test_huggingface_round_trips() {
+        use tokenizers::pre_tokenizers::byte_level::ByteLevel;
+
+        // A byte-level BPE without merges: one token per byte
+        let tokenizer = |normalizer: serde_json::Value| {
+            let vocab = ByteLevel::alphabet()
+                .into_iter()
+                .enumerate()
+                .map(|(i, c)| (c.to_string(), i))
+                .collect::<std::collections::HashMap<_, _>>();
+            let byte_level = serde_json::json!({
+                "type": "ByteLevel",
+                "add_prefix_space": false,
+                "trim_offsets": true,
+                "use_regex": true
+            });
+            let json = serde_json::json!({
+                "version": "1.0",
+                "truncation": null,
+                "padding": null,
+                "added_tokens": [],
+                "normalizer": normalizer,
+                "pre_tokenizer": byte_level,
+                "post_processor": null,
+                "decoder": byte_level,
+                "model": { "type": "BPE", "vocab": vocab, "merges": [] }
+            });
+            HuggingFaceTokenizer::from_bytes(json.to_string().as_bytes()).unwrap()
+        };
+        let bytes = tokenizer(serde_json::Value::Null);
+        assert!(bytes.is_lossless());
+        check_round_trips(&bytes);
+        assert_eq!(bytes.tokenize("é").len(), 2);
+
+        let lowercase = tokenizer(serde_json::json!({ "type": "Lowercase" }));
+        assert!(!lowercase.is_lossless());
+        check_round_trips(&lowercase);
+    }
 }
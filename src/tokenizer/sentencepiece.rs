@@ -15,13 +15,31 @@ use super::Tokenizer;
 
 use rayon::prelude::*;
 use std::collections::HashMap;
-use std::fs::File;
 use std::io;
-use std::io::{BufRead, BufReader};
+use std::io::BufRead;
+#[cfg(feature = "fs")]
 use std::path::Path;
 
 pub const PREFIXED_UNDERSCORE: char = '\u{2581}';
 
+/// The `.vocab` file of a character-level tokenizer of `text`: an `<unk>` piece (Which unknown
+/// characters fall back to), then every character of `text` but the newlines, sorted. Spaces are
+/// written as `PREFIXED_UNDERSCORE`, which also starts every line.
+pub fn char_vocab(text: &str) -> String {
+    let mut chars = text
+        .chars()
+        .filter(|c| *c != '\n')
+        .map(|c| if c == ' ' { PREFIXED_UNDERSCORE } else { c })
+        .chain([PREFIXED_UNDERSCORE])
+        .collect::<Vec<_>>();
+    chars.sort();
+    chars.dedup();
+    std::iter::once("<unk>".to_string())
+        .chain(chars.into_iter().map(String::from))
+        .map(|piece| piece + "\t0\n")
+        .collect()
+}
+
 #[derive(Clone, Copy)]
 struct Node {
     index: usize,
@@ -54,17 +72,24 @@ impl DagNode {
 pub struct SentencePieceTokenizer {
     root: DagNode,
     vocab: Vec<String>,
+    scores: Vec<f32>,
 }
 
 impl SentencePieceTokenizer {
+    #[cfg(feature = "fs")]
     pub fn load<P: AsRef<Path>>(vocab_file: P) -> io::Result<SentencePieceTokenizer> {
+        Self::from_reader(io::BufReader::new(std::fs::File::open(vocab_file)?))
+    }
+
+    /// Reads a `.vocab` file (One tab-separated piece and score per line), e.g. from the bytes
+    /// of one fetched by a browser.
+    pub fn from_reader<R: BufRead>(reader: R) -> io::Result<SentencePieceTokenizer> {
         let mut model = SentencePieceTokenizer {
             root: DagNode::new("".to_string()),
             vocab: Default::default(),
+            scores: Default::default(),
         };
 
-        let f = File::open(vocab_file)?;
-        let reader = BufReader::new(f);
         for (idx, line) in reader.lines().enumerate() {
             let line = line?;
             let split = line.splitn(2, "\t").collect::<Vec<_>>();
@@ -82,8 +107,19 @@ impl SentencePieceTokenizer {
         Ok(model)
     }
 
+    /// The pieces of the vocabulary, indexed by token id.
+    pub fn vocab(&self) -> &[String] {
+        &self.vocab
+    }
+
+    /// The log-probability scores of the pieces, indexed by token id.
+    pub fn scores(&self) -> &[f32] {
+        &self.scores
+    }
+
     fn insert(&mut self, word: &str, score: f32, index: usize) {
         self.vocab.insert(index as usize, word.into());
+        self.scores.insert(index, score);
         let char_count = word.chars().count();
         let mut node = &mut self.root;
 
@@ -205,3 +241,24 @@ impl Tokenizer for SentencePieceTokenizer {
         out.replace(PREFIXED_UNDERSCORE, " ")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_char_vocab() {
+        let text = "First Citizen:\nSpeak, speak.\n";
+        let vocab = char_vocab(text);
+        assert!(vocab.starts_with("<unk>\t0\n"));
+        let tokenizer = SentencePieceTokenizer::from_reader(vocab.as_bytes()).unwrap();
+        // `<unk>`, the space and the 16 other distinct characters
+        assert_eq!(tokenizer.vocab_size(), 18);
+        let tokens = tokenizer.tokenize(text);
+        assert!(!tokens.contains(&0));
+        assert_eq!(
+            tokenizer.untokenize(&tokens),
+            " First Citizen: Speak, speak. "
+        );
+    }
+}
@@ -0,0 +1,172 @@
+// Statistics of a dataset under a tokenizer (See `femto dataset-stats`), to check that they fit
+// each other before spending hours training on them.
+
+use super::Tokenizer;
+use std::collections::HashMap;
+
+/// Token and character counts of a dataset.
+#[derive(Debug, Clone)]
+pub struct DatasetStats {
+    /// Occurrences of each token of the vocabulary
+    pub counts: Vec<usize>,
+    /// Number of characters, line breaks excluded
+    pub num_chars: usize,
+    /// Characters the tokenizer can't represent, along with their occurrences (Most frequent
+    /// first)
+    pub oov_chars: Vec<(char, usize)>,
+}
+
+impl DatasetStats {
+    pub fn new<T: Tokenizer + ?Sized>(tokenizer: &T, text: &str) -> Self {
+        let mut counts = vec![0; tokenizer.vocab_size()];
+        for token in tokenizer.tokenize(text) {
+            counts[token] += 1;
+        }
+        let mut chars = HashMap::<char, usize>::new();
+        for c in text.chars().filter(|c| *c != '\n') {
+            *chars.entry(c).or_default() += 1;
+        }
+        // Characters that don't survive a round trip through the tokenizer, unknown ones being
+        // replaced by another token
+        let mut oov_chars = chars
+            .iter()
+            .filter(|(c, _)| {
+                !tokenizer
+                    .untokenize(&tokenizer.tokenize(&c.to_string()))
+                    .contains(**c)
+            })
+            .map(|(c, n)| (*c, *n))
+            .collect::<Vec<_>>();
+        oov_chars.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        Self {
+            counts,
+            num_chars: chars.values().sum(),
+            oov_chars,
+        }
+    }
+
+    pub fn num_tokens(&self) -> usize {
+        self.counts.iter().sum()
+    }
+
+    /// Number of tokens of the vocabulary occurring in the dataset.
+    pub fn unique_tokens(&self) -> usize {
+        self.counts.iter().filter(|n| **n > 0).count()
+    }
+
+    /// Fraction of the vocabulary occurring in the dataset.
+    pub fn vocab_coverage(&self) -> f32 {
+        self.unique_tokens() as f32 / self.counts.len().max(1) as f32
+    }
+
+    /// Fraction of the characters the tokenizer can't represent.
+    pub fn oov_rate(&self) -> f32 {
+        let oov = self.oov_chars.iter().map(|(_, n)| n).sum::<usize>();
+        oov as f32 / self.num_chars.max(1) as f32
+    }
+
+    /// The `n` most frequent tokens and their occurrences, most frequent first.
+    pub fn most_frequent(&self, n: usize) -> Vec<(usize, usize)> {
+        let mut tokens = self
+            .counts
+            .iter()
+            .copied()
+            .enumerate()
+            .filter(|(_, count)| *count > 0)
+            .collect::<Vec<_>>();
+        tokens.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        tokens.truncate(n);
+        tokens
+    }
+
+    /// Number of tokens of the vocabulary by order of magnitude of their occurrences: the first
+    /// bucket counts the unused tokens, the `k`-th one those occurring in `[10^(k-1), 10^k)`
+    /// times.
+    pub fn frequency_histogram(&self) -> Vec<usize> {
+        let mut buckets = vec![0];
+        for count in self.counts.iter() {
+            let bucket = match count {
+                0 => 0,
+                n => n.ilog10() as usize + 1,
+            };
+            if bucket >= buckets.len() {
+                buckets.resize(bucket + 1, 0);
+            }
+            buckets[bucket] += 1;
+        }
+        buckets
+    }
+
+    /// A human-readable report of the statistics, listing the `top` most frequent tokens.
+    pub fn report<T: Tokenizer + ?Sized>(&self, tokenizer: &T, top: usize) -> String {
+        let mut out = String::new();
+        out += &format!("Tokens: {}\n", self.num_tokens());
+        out += &format!(
+            "Unique tokens: {} of {} ({:.1}% of the vocabulary)\n",
+            self.unique_tokens(),
+            self.counts.len(),
+            self.vocab_coverage() * 100.
+        );
+        out += &format!(
+            "Out-of-vocabulary characters: {:.3}%",
+            self.oov_rate() * 100.
+        );
+        for (c, n) in self.oov_chars.iter().take(10) {
+            out += &format!(" {:?} ({})", c, n);
+        }
+        out += "\n";
+        out += &format!(
+            "Lossless round trips: {}\n",
+            if tokenizer.is_lossless() { "yes" } else { "no" }
+        );
+
+        out += "Most frequent tokens:\n";
+        let most_frequent = self.most_frequent(top);
+        let max = most_frequent.first().map_or(1, |(_, n)| *n);
+        let num_tokens = self.num_tokens().max(1);
+        for (token, n) in most_frequent {
+            out += &format!(
+                "  {:<12} {:>10} {:>6.2}% {}\n",
+                format!("{:?}", tokenizer.untokenize(&[token])),
+                n,
+                n as f32 / num_tokens as f32 * 100.,
+                "#".repeat((n * 40).div_ceil(max))
+            );
+        }
+
+        out += "Tokens by number of occurrences:\n";
+        for (bucket, n) in self.frequency_histogram().iter().enumerate() {
+            let range = match bucket {
+                0 => "0".to_string(),
+                b => format!(
+                    "{}-{}",
+                    10usize.pow(b as u32 - 1),
+                    10usize.pow(b as u32) - 1
+                ),
+            };
+            out += &format!("  {:<12} {:>10}\n", range, n);
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tokenizer::SentencePieceTokenizer;
+
+    #[test]
+    fn test_dataset_stats() {
+        let vocab = "<unk>\t0\n\u{2581}\t-1\na\t-1\nb\t-1\nc\t-1\n";
+        let tokenizer = SentencePieceTokenizer::from_reader(vocab.as_bytes()).unwrap();
+        let stats = DatasetStats::new(&tokenizer, "aab\naza");
+        // Every line starts with a space, the unknown `z` becomes `<unk>`
+        assert_eq!(stats.counts, vec![1, 2, 4, 1, 0]);
+        assert_eq!(stats.num_tokens(), 8);
+        assert_eq!(stats.unique_tokens(), 4);
+        assert_eq!(stats.oov_chars, vec![('z', 1)]);
+        assert!((stats.oov_rate() - 1. / 6.).abs() < 1e-6);
+        assert_eq!(stats.most_frequent(1), vec![(2, 4)]);
+        assert_eq!(stats.frequency_histogram(), vec![1, 4]);
+    }
+}
@@ -0,0 +1,90 @@
+// Incremental detokenization of generated tokens, for streaming their text as it's sampled.
+// Untokenizing tokens one at a time breaks characters spanning several of them (e.g. byte-level
+// tokens of a multi-byte character, which decode to U+FFFD on their own), and loses the spaces
+// some tokenizers only add between tokens.
+
+use super::Tokenizer;
+
+/// Turns tokens pushed one at a time into pieces of text made of whole characters: the text of a
+/// token is held back until the tokens after it complete its last character.
+#[derive(Debug, Clone, Default)]
+pub struct StreamDecoder {
+    tokens: Vec<usize>,
+    // Start of the tokens decoded again with the new ones, for their context
+    prefix: usize,
+    // End of the tokens whose text has been emitted
+    read: usize,
+}
+
+impl StreamDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds `token`, returning the text it completes, if any.
+    pub fn push<T: Tokenizer + ?Sized>(&mut self, tokenizer: &T, token: usize) -> Option<String> {
+        self.tokens.push(token);
+        let emitted = tokenizer.untokenize(&self.tokens[self.prefix..self.read]);
+        let text = tokenizer.untokenize(&self.tokens[self.prefix..]);
+        // A trailing replacement character is an incomplete one, unless the tokens end there
+        if text.len() <= emitted.len() || text.ends_with(char::REPLACEMENT_CHARACTER) {
+            return None;
+        }
+        let piece = text.get(emitted.len()..)?.to_string();
+        self.tokens.drain(..self.prefix);
+        self.prefix = self.read - self.prefix;
+        self.read = self.tokens.len();
+        Some(piece)
+    }
+
+    /// The text of the tokens held back, if any, incomplete characters included.
+    pub fn finish<T: Tokenizer + ?Sized>(&mut self, tokenizer: &T) -> Option<String> {
+        let emitted = tokenizer.untokenize(&self.tokens[self.prefix..self.read]);
+        let text = tokenizer.untokenize(&self.tokens[self.prefix..]);
+        *self = Self::default();
+        text.get(emitted.len()..)
+            .filter(|piece| !piece.is_empty())
+            .map(|piece| piece.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // One token per byte
+    struct ByteTokenizer;
+
+    impl Tokenizer for ByteTokenizer {
+        fn vocab_size(&self) -> usize {
+            256
+        }
+        fn tokenize(&self, string: &str) -> Vec<usize> {
+            string.bytes().map(|b| b as usize).collect()
+        }
+        fn untokenize(&self, tokens: &[usize]) -> String {
+            String::from_utf8_lossy(&tokens.iter().map(|t| *t as u8).collect::<Vec<_>>()).into()
+        }
+    }
+
+    #[test]
+    fn test_stream_decoder() {
+        let text = "aé€b😀";
+        let mut decoder = StreamDecoder::new();
+        let mut pieces = ByteTokenizer
+            .tokenize(text)
+            .into_iter()
+            .filter_map(|t| decoder.push(&ByteTokenizer, t))
+            .collect::<Vec<_>>();
+        assert_eq!(pieces, ["a", "é", "€", "b", "😀"]);
+        assert_eq!(decoder.finish(&ByteTokenizer), None);
+
+        // The start of a character the generation stopped in
+        pieces.clear();
+        for t in ByteTokenizer.tokenize("a€").into_iter().take(3) {
+            pieces.extend(decoder.push(&ByteTokenizer, t));
+        }
+        assert_eq!(pieces, ["a"]);
+        assert_eq!(decoder.finish(&ByteTokenizer).unwrap(), "\u{FFFD}");
+    }
+}